@@ -0,0 +1,104 @@
+//! The Brainfuck compiler itself, shared by the example and its tests.
+//!
+//! The tape holds 64-bit cells, since the interpreter only makes 64-bit
+//! accesses, and each cell wraps at 256 like the byte it stands for. There
+//! is no input: `,` reads end-of-file and stores 0.
+
+use cataclysm::{
+    consts::{RAX, RBX},
+    instr::{self, CondCode},
+    syscall::{emit_syscall, Syscall},
+    AsmExpr, Data, Label, Mem, Program, Section,
+};
+
+const CELLS: usize = 30_000;
+
+/// One Brainfuck command, with runs of `+-<>` folded together.
+enum Op {
+    Add(i64),
+    Move(i64),
+    Output,
+    Input,
+    Open(usize),
+    Close(usize),
+}
+
+fn parse(source: &str) -> Result<Vec<Op>, String> {
+    let mut ops: Vec<Op> = Vec::new();
+    let mut open = Vec::new();
+    let mut loops = 0;
+    for c in source.chars() {
+        match (c, ops.last_mut()) {
+            ('+' | '-', Some(Op::Add(n))) => *n += if c == '+' { 1 } else { -1 },
+            ('>' | '<', Some(Op::Move(n))) => *n += if c == '>' { 1 } else { -1 },
+            ('+', _) => ops.push(Op::Add(1)),
+            ('-', _) => ops.push(Op::Add(-1)),
+            ('>', _) => ops.push(Op::Move(1)),
+            ('<', _) => ops.push(Op::Move(-1)),
+            ('.', _) => ops.push(Op::Output),
+            (',', _) => ops.push(Op::Input),
+            ('[', _) => {
+                open.push(loops);
+                ops.push(Op::Open(loops));
+                loops += 1;
+            }
+            (']', _) => ops.push(Op::Close(open.pop().ok_or("unmatched `]`")?)),
+            _ => {}
+        }
+    }
+    if !open.is_empty() {
+        return Err("unmatched `[`".to_string());
+    }
+    Ok(ops)
+}
+
+/// A `_start` that runs `source` with rbx pointing at the current cell.
+pub fn compile(source: &str) -> Result<Program, String> {
+    let cell = || Mem::base(RBX);
+    let mut text = vec![
+        AsmExpr::Label(Label::plain("_start")),
+        instr::mov(RBX, Label::plain("tape")),
+    ];
+
+    for op in parse(source)? {
+        match op {
+            Op::Add(n) => text.extend([
+                instr::mov(RAX, cell()),
+                instr::add(RAX, n),
+                instr::and(RAX, 0xff),
+                instr::mov(cell(), RAX),
+            ]),
+            Op::Move(n) => text.push(instr::add(RBX, n * 8)),
+            Op::Output => text.extend(
+                emit_syscall(Syscall::Write, &[1.into(), RBX.into(), 1.into()])
+                    .map_err(|e| e.to_string())?,
+            ),
+            Op::Input => text.extend([instr::xor(RAX, RAX), instr::mov(cell(), RAX)]),
+            Op::Open(n) => text.extend([
+                instr::mov(RAX, cell()),
+                instr::test(RAX, RAX),
+                instr::jcc(CondCode::E, Label::plain(&format!(".end{}", n))),
+                AsmExpr::Label(Label::plain(&format!(".loop{}", n))),
+            ]),
+            Op::Close(n) => text.extend([
+                instr::mov(RAX, cell()),
+                instr::test(RAX, RAX),
+                instr::jcc(CondCode::Ne, Label::plain(&format!(".loop{}", n))),
+                AsmExpr::Label(Label::plain(&format!(".end{}", n))),
+            ]),
+        }
+    }
+    text.extend(emit_syscall(Syscall::Exit, &[0.into()]).map_err(|e| e.to_string())?);
+
+    let tape = vec![
+        AsmExpr::Label(Label::plain("tape")),
+        AsmExpr::Data(Data::Fill {
+            count: CELLS * 8,
+            byte: 0,
+        }),
+    ];
+    Ok(Program::default()
+        .with_global("_start")
+        .with_section(Section::new("text", text))
+        .with_section(Section::new("data", tape)))
+}
@@ -0,0 +1,56 @@
+//! A Brainfuck compiler built on the public API.
+//!
+//! Given a file it compiles the program, runs it in the interpreter and
+//! checks that it encodes to machine code; `--emit` prints the NASM source
+//! rather than running it. With no file it runs a built-in hello world.
+//! The programs `cargo test` checks are in `tests/brainfuck.rs`.
+//!
+//! ```text
+//! cargo run --example brainfuck
+//! cargo run --example brainfuck -- hello.bf --emit
+//! ```
+
+mod compiler;
+
+use std::{env, fs, process};
+
+use cataclysm::{encode::EncodeOptions, highlight::ColorMode, interp, Program};
+
+use compiler::compile;
+
+const HELLO: &str = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let emit = args.iter().any(|a| a == "--emit");
+
+    let (name, source) = match args.iter().find(|a| !a.starts_with("--")) {
+        Some(path) => (
+            path.as_str(),
+            fs::read_to_string(path).unwrap_or_else(|e| fail(path, &e.to_string())),
+        ),
+        None => ("hello", HELLO.to_string()),
+    };
+    let program = compile(&source).unwrap_or_else(|e| fail(name, &e));
+    if emit {
+        program.print(ColorMode::default());
+    } else {
+        print!("{}", run(&program));
+    }
+}
+
+fn fail(name: &str, message: &str) -> ! {
+    eprintln!("{}: {}", name, message);
+    process::exit(1)
+}
+
+/// Runs `program` in the interpreter and checks that it also encodes.
+fn run(program: &Program) -> String {
+    let outcome = interp::run(program, "_start", 10_000_000)
+        .unwrap_or_else(|e| fail("interpreter", &e.to_string()));
+    program
+        .clone()
+        .encode(&EncodeOptions::new())
+        .unwrap_or_else(|errors| fail("encoder", &errors[0].to_string()));
+    String::from_utf8_lossy(&outcome.output).into_owned()
+}
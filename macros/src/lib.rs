@@ -0,0 +1,510 @@
+//! Procedural macros for building cataclysm programs from assembly-like
+//! syntax.
+
+use proc_macro::{Delimiter, Group, Spacing, TokenStream, TokenTree};
+
+/// Builds a `Vec<AsmExpr>` from NASM-flavoured statements separated by `;`.
+///
+/// ```ignore
+/// let body = asm_dsl! {
+///     mov rax, {count};
+///     lea rsi, [rel {message}];
+///     top:
+///     dec rax;
+///     jnz top;
+/// };
+/// ```
+///
+/// Registers are written by name, bare identifiers are label references,
+/// integers become immediates and `{expr}` interpolates any Rust value that
+/// converts into an `Operand` (or a `Label`, inside memory operands and
+/// label definitions). Memory operands are `[rel label]`, or any sum of a
+/// base register, an index register scaled as in `rcx*8`, a label and
+/// displacements, such as `[rbp - 8]` or `[table + rcx*8 + 16]`; either
+/// can be sized as in `qword [rel label]`.
+#[proc_macro]
+pub fn asm_dsl(input: TokenStream) -> TokenStream {
+    match expand(input) {
+        Ok(code) => code.parse().unwrap(),
+        Err(message) => format!("compile_error!({:?})", message).parse().unwrap(),
+    }
+}
+
+const PREFIXES: &[&str] = &["rep", "repe", "repz", "repne", "repnz", "lock"];
+
+fn register(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "rax" => "RAX",
+        "rbx" => "RBX",
+        "rcx" => "RCX",
+        "rdx" => "RDX",
+        "rdi" => "RDI",
+        "rsi" => "RSI",
+        "rip" => "RIP",
+        "rsp" => "RSP",
+        "rbp" => "RBP",
+        "r8" => "R8",
+        "r9" => "R9",
+        "r10" => "R10",
+        "r11" => "R11",
+        "r12" => "R12",
+        "r13" => "R13",
+        "r14" => "R14",
+        "r15" => "R15",
+        "r16" => "R16",
+        "r17" => "R17",
+        "r18" => "R18",
+        "r19" => "R19",
+        "r20" => "R20",
+        "r21" => "R21",
+        "r22" => "R22",
+        "r23" => "R23",
+        "r24" => "R24",
+        "r25" => "R25",
+        "r26" => "R26",
+        "r27" => "R27",
+        "r28" => "R28",
+        "r29" => "R29",
+        "r30" => "R30",
+        "r31" => "R31",
+        "tmm0" => "TMM0",
+        "tmm1" => "TMM1",
+        "tmm2" => "TMM2",
+        "tmm3" => "TMM3",
+        "tmm4" => "TMM4",
+        "tmm5" => "TMM5",
+        "tmm6" => "TMM6",
+        "tmm7" => "TMM7",
+        "xmm0" => "XMM0",
+        "xmm1" => "XMM1",
+        "xmm2" => "XMM2",
+        "xmm3" => "XMM3",
+        "xmm4" => "XMM4",
+        "xmm5" => "XMM5",
+        "xmm6" => "XMM6",
+        "xmm7" => "XMM7",
+        "xmm8" => "XMM8",
+        "xmm9" => "XMM9",
+        "xmm10" => "XMM10",
+        "xmm11" => "XMM11",
+        "xmm12" => "XMM12",
+        "xmm13" => "XMM13",
+        "xmm14" => "XMM14",
+        "xmm15" => "XMM15",
+        "ymm0" => "YMM0",
+        "ymm1" => "YMM1",
+        "ymm2" => "YMM2",
+        "ymm3" => "YMM3",
+        "ymm4" => "YMM4",
+        "ymm5" => "YMM5",
+        "ymm6" => "YMM6",
+        "ymm7" => "YMM7",
+        "ymm8" => "YMM8",
+        "ymm9" => "YMM9",
+        "ymm10" => "YMM10",
+        "ymm11" => "YMM11",
+        "ymm12" => "YMM12",
+        "ymm13" => "YMM13",
+        "ymm14" => "YMM14",
+        "ymm15" => "YMM15",
+        "zmm0" => "ZMM0",
+        "zmm1" => "ZMM1",
+        "zmm2" => "ZMM2",
+        "zmm3" => "ZMM3",
+        "zmm4" => "ZMM4",
+        "zmm5" => "ZMM5",
+        "zmm6" => "ZMM6",
+        "zmm7" => "ZMM7",
+        "zmm8" => "ZMM8",
+        "zmm9" => "ZMM9",
+        "zmm10" => "ZMM10",
+        "zmm11" => "ZMM11",
+        "zmm12" => "ZMM12",
+        "zmm13" => "ZMM13",
+        "zmm14" => "ZMM14",
+        "zmm15" => "ZMM15",
+        "zmm16" => "ZMM16",
+        "zmm17" => "ZMM17",
+        "zmm18" => "ZMM18",
+        "zmm19" => "ZMM19",
+        "zmm20" => "ZMM20",
+        "zmm21" => "ZMM21",
+        "zmm22" => "ZMM22",
+        "zmm23" => "ZMM23",
+        "zmm24" => "ZMM24",
+        "zmm25" => "ZMM25",
+        "zmm26" => "ZMM26",
+        "zmm27" => "ZMM27",
+        "zmm28" => "ZMM28",
+        "zmm29" => "ZMM29",
+        "zmm30" => "ZMM30",
+        "zmm31" => "ZMM31",
+        "k0" => "K0",
+        "k1" => "K1",
+        "k2" => "K2",
+        "k3" => "K3",
+        "k4" => "K4",
+        "k5" => "K5",
+        "k6" => "K6",
+        "k7" => "K7",
+        _ => return None,
+    })
+}
+
+/// The low 32, 16 and 8 bits of each baseline register, whose constants
+/// are their names in capitals. The 32-bit ones come first.
+const PARTIAL_REGISTERS: &[&str] = &[
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d",
+    "r13d", "r14d", "r15d", "ax", "cx", "dx", "bx", "sp", "bp", "si", "di", "r8w", "r9w", "r10w",
+    "r11w", "r12w", "r13w", "r14w", "r15w", "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil",
+    "r8b", "r9b", "r10b", "r11b", "r12b", "r13b", "r14b", "r15b",
+];
+
+fn register_path(name: &str) -> Option<String> {
+    let constant = match register(name) {
+        Some(constant) => constant.to_string(),
+        None if PARTIAL_REGISTERS.contains(&name) => name.to_uppercase(),
+        None => return None,
+    };
+    Some(format!("::cataclysm::consts::{}", constant))
+}
+
+fn expand(input: TokenStream) -> Result<String, String> {
+    let mut statements = vec![Vec::new()];
+    for token in input {
+        match &token {
+            TokenTree::Punct(p) if p.as_char() == ';' => statements.push(Vec::new()),
+            _ => statements.last_mut().unwrap().push(token),
+        }
+    }
+
+    let mut exprs = Vec::new();
+    for tokens in statements {
+        statement(&tokens, &mut exprs)?;
+    }
+
+    Ok(format!("::std::vec![{}]", exprs.join(", ")))
+}
+
+fn is_punct(token: &TokenTree, c: char) -> bool {
+    matches!(token, TokenTree::Punct(p) if p.as_char() == c)
+}
+
+/// Length of the `name:` label definition at the start of `tokens`, if any.
+fn label_definition(tokens: &[TokenTree]) -> Option<usize> {
+    let len = match tokens {
+        [TokenTree::Punct(dot), TokenTree::Ident(_), ..] if dot.as_char() == '.' => 2,
+        [TokenTree::Ident(_), ..] => 1,
+        [TokenTree::Group(g), ..] if g.delimiter() == Delimiter::Brace => 1,
+        _ => return None,
+    };
+
+    tokens.get(len).filter(|t| is_punct(t, ':')).map(|_| len)
+}
+
+/// Expands one `;`-terminated statement: any number of label definitions
+/// followed by at most one instruction.
+fn statement(mut tokens: &[TokenTree], out: &mut Vec<String>) -> Result<(), String> {
+    while let Some(len) = label_definition(tokens) {
+        out.push(format!(
+            "::cataclysm::AsmExpr::Label({})",
+            label(&tokens[..len])?
+        ));
+        tokens = &tokens[len + 1..];
+    }
+
+    if tokens.is_empty() {
+        return Ok(());
+    }
+
+    let mut rest = tokens;
+    let mut mnemonic = Vec::new();
+    while let Some((TokenTree::Ident(ident), tail)) = rest.split_first() {
+        let word = ident.to_string();
+        let is_prefix = PREFIXES.contains(&word.as_str());
+        mnemonic.push(word);
+        rest = tail;
+        if !is_prefix {
+            break;
+        }
+    }
+
+    if mnemonic.is_empty() {
+        return Err(format!(
+            "expected an instruction or label, found `{}`",
+            tokens_to_string(tokens)
+        ));
+    }
+
+    let operands = if rest.is_empty() {
+        Vec::new()
+    } else {
+        rest.split(|t| is_punct(t, ','))
+            .map(operand)
+            .collect::<Result<Vec<_>, _>>()?
+    };
+
+    out.push(format!(
+        "::cataclysm::AsmExpr::Instruction(::cataclysm::Amd64Instruction::new(::cataclysm::mnemonic::Amd64Mnemonic::parse({:?}), ::std::vec![{}]))",
+        mnemonic.join(" "),
+        operands.join(", ")
+    ));
+    Ok(())
+}
+
+fn tokens_to_string(tokens: &[TokenTree]) -> String {
+    tokens.iter().cloned().collect::<TokenStream>().to_string()
+}
+
+fn interpolated(group: &Group) -> String {
+    format!("({})", group.stream())
+}
+
+/// A label name (`top`, `.top`) or an interpolated `Label` value.
+fn label(tokens: &[TokenTree]) -> Result<String, String> {
+    match tokens {
+        [TokenTree::Ident(name)] => {
+            Ok(format!("::cataclysm::Label::plain({:?})", name.to_string()))
+        }
+        [TokenTree::Punct(dot), TokenTree::Ident(name)] if dot.as_char() == '.' => Ok(format!(
+            "::cataclysm::Label::plain({:?})",
+            format!(".{}", name)
+        )),
+        [TokenTree::Group(g)] if g.delimiter() == Delimiter::Brace => Ok(interpolated(g)),
+        _ => Err(format!(
+            "expected a label, found `{}`",
+            tokens_to_string(tokens)
+        )),
+    }
+}
+
+fn operand(tokens: &[TokenTree]) -> Result<String, String> {
+    match tokens {
+        [TokenTree::Ident(name)] => Ok(match register_path(&name.to_string()) {
+            Some(reg) => format!("::cataclysm::Operand::Register({})", reg),
+            None => label_operand(&label(tokens)?),
+        }),
+        [TokenTree::Punct(dot), TokenTree::Ident(_)] if dot.as_char() == '.' => {
+            Ok(label_operand(&label(tokens)?))
+        }
+        [TokenTree::Literal(lit)] => integer(&lit.to_string(), false),
+        [TokenTree::Punct(minus), TokenTree::Literal(lit)]
+            if minus.as_char() == '-' && minus.spacing() == Spacing::Alone =>
+        {
+            integer(&lit.to_string(), true)
+        }
+        [TokenTree::Group(g)] if g.delimiter() == Delimiter::Brace => {
+            Ok(format!("::cataclysm::Operand::from({})", interpolated(g)))
+        }
+        [TokenTree::Group(g)] if g.delimiter() == Delimiter::Bracket => Ok(format!(
+            "::cataclysm::Operand::Memory({})",
+            memory(&g.stream().into_iter().collect::<Vec<_>>())?
+        )),
+        [TokenTree::Ident(size), TokenTree::Group(g)] if g.delimiter() == Delimiter::Bracket => {
+            let width = match size.to_string().as_str() {
+                "byte" => "Byte",
+                "word" => "Word",
+                "dword" => "Dword",
+                "qword" => "Qword",
+                other => return Err(format!("`{}` is not an operand size", other)),
+            };
+            Ok(format!(
+                "::cataclysm::Operand::Memory({}.with_size(::cataclysm::register::GprWidth::{}))",
+                memory(&g.stream().into_iter().collect::<Vec<_>>())?,
+                width
+            ))
+        }
+        _ => Err(format!(
+            "unsupported operand `{}`",
+            tokens_to_string(tokens)
+        )),
+    }
+}
+
+fn label_operand(label: &str) -> String {
+    format!(
+        "::cataclysm::Operand::Immediate(::cataclysm::ImmediateValue::Label({}))",
+        label
+    )
+}
+
+/// `[0]`, for addresses with neither a base nor a label.
+const ABSOLUTE: &str = "::cataclysm::Mem { base: ::std::option::Option::None, \
+    index: ::std::option::Option::None, scale: 1, label: ::std::option::Option::None, \
+    displacement: 0, size: ::std::option::Option::None }";
+
+/// The `Mem` a bracketed memory operand describes: `rel` and a label, or
+/// a sum of a base register, an index register optionally scaled as
+/// `reg*4`, a label and integer displacements, in any order. Only
+/// displacements may be subtracted.
+fn memory(tokens: &[TokenTree]) -> Result<String, String> {
+    let found = || format!("`[{}]`", tokens_to_string(tokens));
+    if tokens.is_empty() {
+        return Err("empty memory operand `[]`".to_string());
+    }
+    let (rel, tokens) = match tokens {
+        [TokenTree::Ident(kw), rest @ ..] if kw.to_string() == "rel" => (true, rest),
+        _ => (false, tokens),
+    };
+
+    let mut base: Option<String> = None;
+    let mut index: Option<(String, u32)> = None;
+    let mut target = None;
+    let mut displacement = 0i128;
+
+    for (negative, term) in terms(tokens)? {
+        match term {
+            [TokenTree::Literal(lit)] => {
+                let value = integer_value(&lit.to_string())?;
+                displacement += if negative { -value } else { value };
+            }
+            _ if negative => {
+                return Err(format!(
+                    "only displacements can be subtracted in {}",
+                    found()
+                ))
+            }
+            [TokenTree::Ident(reg), star, TokenTree::Literal(scale)]
+            | [TokenTree::Literal(scale), star, TokenTree::Ident(reg)]
+                if is_punct(star, '*') =>
+            {
+                let reg = address_register(&reg.to_string())?;
+                let scale = match integer_value(&scale.to_string())? {
+                    scale @ (1 | 2 | 4 | 8) => scale as u32,
+                    scale => return Err(format!("scale {} is not 1, 2, 4 or 8", scale)),
+                };
+                if index.replace((reg, scale)).is_some() {
+                    return Err(format!("more than one index register in {}", found()));
+                }
+            }
+            [TokenTree::Ident(name)] if register_path(&name.to_string()).is_some() => {
+                let reg = address_register(&name.to_string())?;
+                if base.is_none() {
+                    base = Some(reg);
+                } else if index.is_none() {
+                    index = Some((reg, 1));
+                } else {
+                    return Err(format!("too many registers in {}", found()));
+                }
+            }
+            _ => {
+                if target.replace(label(term)?).is_some() {
+                    return Err(format!("more than one label in {}", found()));
+                }
+            }
+        }
+    }
+
+    if rel && (target.is_none() || base.is_some() || index.is_some()) {
+        return Err(format!(
+            "expected `[rel label]`, optionally with a displacement, found {}",
+            found()
+        ));
+    }
+    match &index {
+        Some((index, _)) if index == "rsp" || index == "esp" || index == "rip" => {
+            return Err(format!("`{}` cannot be an index register", index))
+        }
+        Some(_) if base.as_deref() == Some("rip") || (base.is_none() && target.is_some()) => {
+            return Err(format!(
+                "a rip-relative address cannot have an index, in {}",
+                found()
+            ))
+        }
+        _ => {}
+    }
+    let path = |name: String| register_path(&name).unwrap();
+    let base = base.map(path);
+    let index = index.map(|(index, scale)| (path(index), scale));
+    let displacement = i64::try_from(displacement)
+        .map_err(|_| format!("the displacement in {} does not fit in 64 bits", found()))?;
+
+    let mut mem = match (target, base) {
+        (Some(target), Some(base)) => {
+            format!("::cataclysm::Mem::label({}).with_base({})", target, base)
+        }
+        (Some(target), None) => format!("::cataclysm::Mem::label({})", target),
+        (None, Some(base)) => format!("::cataclysm::Mem::base({})", base),
+        (None, None) => ABSOLUTE.to_string(),
+    };
+    if let Some((index, scale)) = index {
+        mem.push_str(&format!(".with_index({}, {})", index, scale));
+    }
+    if displacement != 0 {
+        mem.push_str(&format!(".with_displacement({}i64)", displacement));
+    }
+    Ok(mem)
+}
+
+/// `name`, if it can be a base or index register: a 64-bit or 32-bit
+/// general-purpose register, or rip.
+fn address_register(name: &str) -> Result<String, String> {
+    let full = matches!(register(name), Some(constant) if constant.starts_with('R'));
+    if full || PARTIAL_REGISTERS[..16].contains(&name) {
+        Ok(name.to_string())
+    } else {
+        Err(format!("`{}` cannot address memory", name))
+    }
+}
+
+/// The terms of a sum, split at top-level `+` and `-`, each with whether
+/// it is subtracted.
+fn terms(tokens: &[TokenTree]) -> Result<Vec<(bool, &[TokenTree])>, String> {
+    let mut terms = Vec::new();
+    let mut negative = false;
+    let mut start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        if is_punct(token, '+') || is_punct(token, '-') {
+            if i == start && !(i == 0 && is_punct(token, '-')) {
+                return Err(format!(
+                    "expected a term before `{}` in `[{}]`",
+                    token,
+                    tokens_to_string(tokens)
+                ));
+            }
+            if i > start {
+                terms.push((negative, &tokens[start..i]));
+            }
+            negative = is_punct(token, '-');
+            start = i + 1;
+        }
+    }
+    if start == tokens.len() && !tokens.is_empty() {
+        return Err(format!(
+            "expected a term after the last operator in `[{}]`",
+            tokens_to_string(tokens)
+        ));
+    }
+    if start < tokens.len() {
+        terms.push((negative, &tokens[start..]));
+    }
+    Ok(terms)
+}
+
+fn integer(text: &str, negative: bool) -> Result<String, String> {
+    let magnitude = integer_value(text)?;
+    let value = if negative { -magnitude } else { magnitude };
+
+    let imm = if let Ok(v) = i64::try_from(value) {
+        format!("::cataclysm::ImmediateValue::I64({}i64)", v)
+    } else if let Ok(v) = u64::try_from(value) {
+        format!("::cataclysm::ImmediateValue::U64({}u64)", v)
+    } else {
+        return Err(format!("`{}` does not fit in 64 bits", text));
+    };
+
+    Ok(format!("::cataclysm::Operand::Immediate({})", imm))
+}
+
+/// The value of an integer literal, in any of Rust's radixes.
+fn integer_value(text: &str) -> Result<i128, String> {
+    let digits = text.replace('_', "");
+    let (radix, digits) = match digits.get(..2) {
+        Some("0x") | Some("0X") => (16, &digits[2..]),
+        Some("0b") | Some("0B") => (2, &digits[2..]),
+        Some("0o") | Some("0O") => (8, &digits[2..]),
+        _ => (10, digits.as_str()),
+    };
+
+    i128::from_str_radix(digits, radix).map_err(|_| format!("`{}` is not an integer", text))
+}
@@ -0,0 +1,615 @@
+//! Calling-convention compliance of the functions in a program.
+//!
+//! Every function that returns is checked against a [`CallingConvention`]:
+//! callee-saved registers must hold their entry values at each `ret`, and
+//! no register may be read before it is written unless the convention
+//! passes an argument in it. Functions with a declared [`Signature`] are
+//! held to their argument count, and to setting the return register on
+//! every path when they return a value.
+//!
+//! Register values are tracked through moves and the stack (`push`, `pop`,
+//! `rsp`-relative loads and stores, and `rbp` frames), so saving and
+//! restoring a register through any of them is recognised.
+
+use std::{collections::HashMap, error, fmt};
+
+use crate::{
+    cfg::{Cfg, Function},
+    dataflow::{self, constant, operand_registers, RegSet},
+    expr::ConstExpr,
+    program::Program,
+    register::Gpr,
+    Amd64Instruction, Amd64Register, Operand,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CallingConvention {
+    pub name: &'static str,
+    /// Integer argument registers, in order.
+    pub arguments: &'static [Gpr],
+    /// Registers a function must restore before returning, besides rsp.
+    pub callee_saved: &'static [Gpr],
+    pub return_register: Gpr,
+    /// Bytes the caller reserves just above the return address for the
+    /// callee to spill its register arguments to.
+    pub shadow_space: u32,
+}
+
+impl CallingConvention {
+    pub const SYSTEM_V: CallingConvention = CallingConvention {
+        name: "System V",
+        arguments: &dataflow::SYSV_ARGUMENTS,
+        callee_saved: &dataflow::SYSV_CALLEE_SAVED,
+        return_register: Gpr::RAX,
+        shadow_space: 0,
+    };
+
+    pub const WIN64: CallingConvention = CallingConvention {
+        name: "Microsoft x64",
+        arguments: &[Gpr::RCX, Gpr::RDX, Gpr::R8, Gpr::R9],
+        callee_saved: &[
+            Gpr::RBX,
+            Gpr::RBP,
+            Gpr::RDI,
+            Gpr::RSI,
+            Gpr::R12,
+            Gpr::R13,
+            Gpr::R14,
+            Gpr::R15,
+        ],
+        return_register: Gpr::RAX,
+        shadow_space: 32,
+    };
+
+    /// Registers a call may leave holding anything.
+    fn caller_saved(&self) -> RegSet {
+        let mut preserved = RegSet::of(self.callee_saved);
+        preserved.insert(Gpr::RSP);
+        RegSet::ALL.difference(preserved)
+    }
+}
+
+/// What a function takes and gives back, as far as registers go.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Signature {
+    /// How many of the convention's argument registers carry arguments.
+    pub arguments: usize,
+    /// Whether the return register holds a result.
+    pub returns: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AbiError {
+    /// A callee-saved register may not hold its entry value at a `ret`.
+    CalleeSavedClobbered { function: String, register: Gpr },
+    /// A register is read on some path before anything sets it.
+    UndefinedRead {
+        function: String,
+        register: Gpr,
+        instruction: String,
+    },
+    /// Some path reaches `ret` without setting the return register.
+    ReturnValueUnset { function: String, register: Gpr },
+}
+
+impl fmt::Display for AbiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AbiError::CalleeSavedClobbered { function, register } => write!(
+                f,
+                "{} may return without restoring callee-saved {}",
+                function, register
+            ),
+            AbiError::UndefinedRead {
+                function,
+                register,
+                instruction,
+            } => write!(
+                f,
+                "{} reads {} in `{}` before it is set",
+                function,
+                register,
+                instruction.replace('\t', " ")
+            ),
+            AbiError::ReturnValueUnset { function, register } => write!(
+                f,
+                "{} may return without setting its return value in {}",
+                function, register
+            ),
+        }
+    }
+}
+
+impl error::Error for AbiError {}
+
+/// Checks functions against a calling convention, with optional
+/// per-function signatures.
+#[derive(Clone, Debug)]
+pub struct AbiCheck {
+    convention: CallingConvention,
+    signatures: HashMap<String, Signature>,
+}
+
+impl Default for AbiCheck {
+    fn default() -> Self {
+        AbiCheck::new(CallingConvention::SYSTEM_V)
+    }
+}
+
+impl AbiCheck {
+    pub fn new(convention: CallingConvention) -> Self {
+        AbiCheck {
+            convention,
+            signatures: HashMap::new(),
+        }
+    }
+
+    /// Declares the signature of `function`. Undeclared functions may read
+    /// every argument register and are not checked for a return value.
+    pub fn declare(mut self, function: &str, signature: Signature) -> Self {
+        self.signatures.insert(function.to_string(), signature);
+        self
+    }
+
+    pub fn check(&self, program: &Program) -> Result<(), Vec<AbiError>> {
+        let defines = program.define_map();
+        let mut errors = Vec::new();
+
+        for function in &Cfg::build(program).functions {
+            let signature = self.signatures.get(&function.name);
+            if signature.is_some() || returns(function) {
+                let checker = Checker {
+                    convention: &self.convention,
+                    signature,
+                    function,
+                    defines: &defines,
+                };
+                checker.run(&mut errors);
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+fn returns(function: &Function) -> bool {
+    let reachable = function.reachable();
+    function
+        .blocks
+        .iter()
+        .zip(reachable)
+        .any(|(b, r)| r && b.instructions.last().is_some_and(|i| i.mnemonic == "ret"))
+}
+
+/// What a register or stack slot is known to hold.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Value {
+    /// The value the register had on entry to the function.
+    Entry(Gpr),
+    /// The stack pointer when the stack held this many slots.
+    Frame(usize),
+    Unknown,
+}
+
+impl Value {
+    fn meet(self, other: Value) -> Value {
+        if self == other {
+            self
+        } else {
+            Value::Unknown
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+struct State {
+    regs: [Value; 32],
+    /// Slots pushed since entry, innermost last; `None` once rsp has been
+    /// changed in a way the checker cannot follow.
+    stack: Option<Vec<Value>>,
+    /// Registers set on every path so far, counting arguments and
+    /// preserved registers as set on entry.
+    defined: RegSet,
+}
+
+impl State {
+    fn meet(&self, other: &State) -> State {
+        let mut regs = self.regs;
+        for (r, o) in regs.iter_mut().zip(other.regs) {
+            *r = r.meet(o);
+        }
+        let stack = match (&self.stack, &other.stack) {
+            (Some(a), Some(b)) if a.len() == b.len() => {
+                Some(a.iter().zip(b).map(|(x, y)| x.meet(*y)).collect())
+            }
+            _ => None,
+        };
+        State {
+            regs,
+            stack,
+            defined: self.defined.intersection(other.defined),
+        }
+    }
+
+    fn get(&self, reg: Gpr) -> Value {
+        self.regs[reg.index() as usize]
+    }
+
+    fn set(&mut self, reg: Gpr, value: Value) {
+        self.regs[reg.index() as usize] = value;
+        self.defined.insert(reg);
+    }
+
+    /// The stack slot an `[rsp + disp]` operand names, counted from the
+    /// bottom of the tracked stack.
+    fn slot(&self, operand: &Operand) -> Option<usize> {
+        let Operand::Memory(mem) = operand else {
+            return None;
+        };
+        let depth = self.stack.as_ref()?.len();
+        let disp = usize::try_from(mem.displacement).ok()?;
+        let aligned = disp % 8 == 0 && mem.index.is_none() && mem.label.is_none();
+        let base = mem.base.as_ref().and_then(Amd64Register::gpr);
+        (base == Some(Gpr::RSP) && aligned && disp / 8 < depth).then(|| depth - 1 - disp / 8)
+    }
+
+    fn value_of(&self, operand: &Operand) -> Value {
+        match operand {
+            Operand::Register(reg) => reg.gpr().map_or(Value::Unknown, |r| self.get(r)),
+            _ => match (self.slot(operand), &self.stack) {
+                (Some(slot), Some(stack)) => stack[slot],
+                _ => Value::Unknown,
+            },
+        }
+    }
+
+    fn adjust_stack(&mut self, slots: i64) {
+        let Some(stack) = &mut self.stack else {
+            return;
+        };
+        if slots >= 0 {
+            stack.extend((0..slots).map(|_| Value::Unknown));
+        } else if stack.len() as i64 + slots >= 0 {
+            stack.truncate((stack.len() as i64 + slots) as usize);
+        } else {
+            self.stack = None;
+        }
+    }
+}
+
+struct Checker<'a> {
+    convention: &'a CallingConvention,
+    signature: Option<&'a Signature>,
+    function: &'a Function,
+    defines: &'a HashMap<String, ConstExpr>,
+}
+
+impl Checker<'_> {
+    fn entry_state(&self) -> State {
+        let arguments = match self.signature {
+            Some(sig) => {
+                &self.convention.arguments[..sig.arguments.min(self.convention.arguments.len())]
+            }
+            None => self.convention.arguments,
+        };
+        let mut defined = RegSet::of(arguments).union(RegSet::of(self.convention.callee_saved));
+        defined.insert(Gpr::RSP);
+
+        let mut regs = [Value::Unknown; 32];
+        for (i, value) in regs.iter_mut().enumerate() {
+            *value = Value::Entry(Gpr::extended(i as u32).unwrap());
+        }
+        State {
+            regs,
+            stack: Some(Vec::new()),
+            defined,
+        }
+    }
+
+    fn run(&self, errors: &mut Vec<AbiError>) {
+        let blocks = &self.function.blocks;
+        let mut state_in: Vec<Option<State>> = vec![None; blocks.len()];
+        state_in[0] = Some(self.entry_state());
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (i, block) in blocks.iter().enumerate() {
+                let Some(mut state) = state_in[i].clone() else {
+                    continue;
+                };
+                for inst in &block.instructions {
+                    self.step(&mut state, inst, &mut Vec::new());
+                }
+                for &(to, _) in &block.successors {
+                    let merged = match &state_in[to] {
+                        Some(old) => old.meet(&state),
+                        None => state.clone(),
+                    };
+                    if state_in[to].as_ref() != Some(&merged) {
+                        state_in[to] = Some(merged);
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        let mut found = Vec::new();
+        for (i, block) in blocks.iter().enumerate() {
+            if let Some(mut state) = state_in[i].clone() {
+                for inst in &block.instructions {
+                    self.step(&mut state, inst, &mut found);
+                }
+            }
+        }
+        for error in found {
+            if !errors.contains(&error) {
+                errors.push(error);
+            }
+        }
+    }
+
+    /// Applies `inst` to `state`, noting any violation it commits.
+    fn step(&self, state: &mut State, inst: &Amd64Instruction, errors: &mut Vec<AbiError>) {
+        let name = &self.function.name;
+        let fx = dataflow::effects(inst);
+        let ops = &inst.operands;
+
+        let read = operand_registers(inst).intersection(fx.uses);
+        for register in read.difference(state.defined).iter() {
+            errors.push(AbiError::UndefinedRead {
+                function: name.clone(),
+                register,
+                instruction: inst.to_string(),
+            });
+            // Report each undefined register once per path, not at every
+            // use that follows.
+            state.defined.insert(register);
+        }
+
+        let dst = match ops.first() {
+            Some(Operand::Register(reg)) => reg.gpr(),
+            _ => None,
+        };
+        let rsp_immediate = || {
+            (dst == Some(Gpr::RSP))
+                .then(|| constant(ops.get(1)?, self.defines))
+                .flatten()
+                .filter(|n| n % 8 == 0)
+        };
+
+        match inst.mnemonic.as_str() {
+            "push" => {
+                let value = state.value_of(&ops[0]);
+                if let Some(stack) = &mut state.stack {
+                    stack.push(value);
+                }
+            }
+            "pop" => {
+                let value = state
+                    .stack
+                    .as_mut()
+                    .and_then(|s| s.pop())
+                    .unwrap_or(Value::Unknown);
+                if let Some(dst) = dst {
+                    state.set(dst, value);
+                }
+            }
+            "mov" if dst == Some(Gpr::RSP) => match state.value_of(&ops[1]) {
+                Value::Frame(depth) if state.stack.as_ref().is_some_and(|s| s.len() >= depth) => {
+                    state.stack.as_mut().unwrap().truncate(depth)
+                }
+                _ => state.stack = None,
+            },
+            "mov" => {
+                let value = match &ops[1] {
+                    Operand::Register(reg) if reg.gpr() == Some(Gpr::RSP) => state
+                        .stack
+                        .as_ref()
+                        .map_or(Value::Unknown, |s| Value::Frame(s.len())),
+                    src => state.value_of(src),
+                };
+                match dst {
+                    Some(dst) => state.set(dst, value),
+                    None => {
+                        if let (Some(slot), Some(stack)) = (state.slot(&ops[0]), &mut state.stack) {
+                            stack[slot] = value;
+                        }
+                    }
+                }
+            }
+            "xchg" => {
+                let (a, b) = (state.value_of(&ops[0]), state.value_of(&ops[1]));
+                for (op, value) in [(&ops[0], b), (&ops[1], a)] {
+                    if let Operand::Register(reg) = op {
+                        if let Some(reg) = reg.gpr() {
+                            state.set(reg, value);
+                        }
+                    }
+                }
+            }
+            "sub" if rsp_immediate().is_some() => state.adjust_stack(rsp_immediate().unwrap() / 8),
+            "add" if rsp_immediate().is_some() => state.adjust_stack(-rsp_immediate().unwrap() / 8),
+            "leave" => {
+                match state.get(Gpr::RBP) {
+                    Value::Frame(depth)
+                        if state.stack.as_ref().is_some_and(|s| s.len() >= depth) =>
+                    {
+                        state.stack.as_mut().unwrap().truncate(depth)
+                    }
+                    _ => state.stack = None,
+                }
+                let value = state
+                    .stack
+                    .as_mut()
+                    .and_then(|s| s.pop())
+                    .unwrap_or(Value::Unknown);
+                state.set(Gpr::RBP, value);
+            }
+            "call" => {
+                for reg in self.convention.caller_saved().iter() {
+                    state.regs[reg.index() as usize] = Value::Unknown;
+                }
+                state.defined.insert(self.convention.return_register);
+            }
+            "ret" => self.check_return(state, errors),
+            _ => {
+                for reg in fx.defs.iter() {
+                    if reg == Gpr::RSP {
+                        state.stack = None;
+                    } else {
+                        state.set(reg, Value::Unknown);
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_return(&self, state: &State, errors: &mut Vec<AbiError>) {
+        for &register in self.convention.callee_saved {
+            if state.get(register) != Value::Entry(register) {
+                errors.push(AbiError::CalleeSavedClobbered {
+                    function: self.function.name.clone(),
+                    register,
+                });
+            }
+        }
+
+        let register = self.convention.return_register;
+        let returns = self.signature.is_some_and(|s| s.returns);
+        if returns && !state.defined.contains(register) {
+            errors.push(AbiError::ReturnValueUnset {
+                function: self.function.name.clone(),
+                register,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm_dsl, AsmExpr, Label, Section};
+
+    fn function(body: Vec<AsmExpr>) -> Program {
+        let text = [vec![AsmExpr::Label(Label::plain("f"))], body].concat();
+        Program::default().with_section(Section::new("text", text))
+    }
+
+    fn errors(check: &AbiCheck, body: Vec<AsmExpr>) -> Vec<AbiError> {
+        check.check(&function(body)).err().unwrap_or_default()
+    }
+
+    #[test]
+    fn saving_a_register_through_the_stack_or_a_frame_counts() {
+        let check = AbiCheck::default();
+        let pushed = asm_dsl! {
+            push rbx;
+            mov rbx, rdi;
+            mov rax, rbx;
+            pop rbx;
+            ret;
+        };
+        assert_eq!(errors(&check, pushed), []);
+        let framed = asm_dsl! {
+            push rbp;
+            mov rbp, rsp;
+            sub rsp, 16;
+            mov [rsp + 8], r12;
+            mov r12, 1;
+            mov r12, [rsp + 8];
+            leave;
+            ret;
+        };
+        assert_eq!(errors(&check, framed), []);
+        let moved = asm_dsl! {
+            mov r8, r13;
+            xor r13, r13;
+            mov r13, r8;
+            ret;
+        };
+        assert_eq!(errors(&check, moved), []);
+    }
+
+    #[test]
+    fn callee_saved_registers_must_be_restored() {
+        let body = asm_dsl! { mov rbx, 1; ret; };
+        assert_eq!(
+            errors(&AbiCheck::default(), body),
+            [AbiError::CalleeSavedClobbered {
+                function: "f".to_string(),
+                register: Gpr::RBX,
+            }]
+        );
+        // rsi belongs to the caller under System V, but not under Win64.
+        let body = asm_dsl! { mov rsi, 1; ret; };
+        assert_eq!(errors(&AbiCheck::default(), body.clone()), []);
+        assert_eq!(
+            errors(&AbiCheck::new(CallingConvention::WIN64), body),
+            [AbiError::CalleeSavedClobbered {
+                function: "f".to_string(),
+                register: Gpr::RSI,
+            }]
+        );
+    }
+
+    #[test]
+    fn only_argument_registers_are_read_unset() {
+        let body = asm_dsl! { mov rax, rdi; add rax, r10; ret; };
+        let check = AbiCheck::default();
+        let found: Vec<String> = errors(&check, body).iter().map(|e| e.to_string()).collect();
+        assert_eq!(found, ["f reads r10 in `add rax, r10` before it is set"]);
+
+        // A declared signature narrows the arguments to those it takes.
+        let body = asm_dsl! { mov rax, rsi; ret; };
+        let declared = check.declare(
+            "f",
+            Signature {
+                arguments: 1,
+                returns: true,
+            },
+        );
+        assert!(matches!(
+            errors(&declared, body).as_slice(),
+            [AbiError::UndefinedRead {
+                register: Gpr::RSI,
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn declared_results_are_set_on_every_path() {
+        let check = AbiCheck::default().declare(
+            "f",
+            Signature {
+                arguments: 1,
+                returns: true,
+            },
+        );
+        let body = asm_dsl! {
+            test rdi, rdi;
+            je done;
+            mov rax, 1;
+            done:
+            ret;
+        };
+        assert_eq!(
+            errors(&check, body),
+            [AbiError::ReturnValueUnset {
+                function: "f".to_string(),
+                register: Gpr::RAX,
+            }]
+        );
+    }
+
+    #[test]
+    fn functions_that_never_return_are_not_checked() {
+        let body = asm_dsl! { mov rbx, 1; mov rax, 60; syscall; };
+        assert_eq!(errors(&AbiCheck::default(), body), []);
+    }
+}
@@ -0,0 +1,161 @@
+//! Data placed off its natural alignment.
+//!
+//! Nothing in a section pads a data item to suit its type: a `dq` after a
+//! `db` lands wherever the byte leaves off. Most loads only slow down for
+//! that, but the aligned SSE loads tables are often read with fault.
+//! [`check`] lays the program out and reports every item whose address is
+//! not a multiple of its natural alignment, either because of where it
+//! lands in its section or because the section itself is not aligned that
+//! far:
+//!
+//! ```
+//! use cataclysm::{encode::EncodeOptions, AsmExpr, Data, Label, Program, Section};
+//!
+//! let program = Program::default().with_section(Section::new(
+//!     "data",
+//!     vec![
+//!         AsmExpr::Label(Label::plain("flag")),
+//!         AsmExpr::Data(Data::Byte(1)),
+//!         AsmExpr::Label(Label::plain("table")),
+//!         AsmExpr::Data(Data::Int(5)),
+//!         AsmExpr::Data(Data::Int(6)),
+//!     ],
+//! ));
+//! let misaligned = program.check_alignment(&EncodeOptions::default()).unwrap();
+//! assert_eq!(misaligned.len(), 2);
+//! assert_eq!(
+//!     misaligned[0].to_string(),
+//!     "`dq 5` at table in section .data needs 8-byte alignment but has 1; \
+//!      7 bytes of padding before it would give it that"
+//! );
+//! ```
+//!
+//! Sections start as the [`EncodeOptions`] align them, or at their fixed
+//! base. Text output leaves that to the assembler: NASM aligns ELF data
+//! sections to 4 bytes unless told otherwise, so checking the text output
+//! wants a `section_alignment` of 4.
+
+use std::fmt;
+
+use crate::{
+    encode::{EncodeError, EncodeOptions},
+    layout::EntryKind,
+    program::Program,
+    Data,
+};
+
+/// A data item [`check`] found misaligned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Misalignment {
+    pub section: String,
+    /// The item, as emitted.
+    pub item: String,
+    /// Bytes from the start of the section.
+    pub offset: u64,
+    /// The last label at or before the item, and how far past it the item
+    /// is.
+    pub label: Option<(String, u64)>,
+    /// The item's natural alignment.
+    pub alignment: u64,
+    /// The largest power of two its address is known to be a multiple of.
+    pub placed: u64,
+    /// The bytes of padding before the item that would align it, unless
+    /// the section is not aligned enough for any to.
+    pub padding: Option<u64>,
+}
+
+impl fmt::Display for Misalignment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "`{}` at ", self.item.replace('\t', " "))?;
+        match &self.label {
+            Some((label, 0)) => write!(f, "{}", label)?,
+            Some((label, past)) => write!(f, "{} + {}", label, past)?,
+            None => write!(f, "{:#x}", self.offset)?,
+        }
+        write!(
+            f,
+            " in section .{} needs {}-byte alignment but has {}",
+            self.section, self.alignment, self.placed
+        )?;
+        match self.padding {
+            Some(padding) => write!(
+                f,
+                "; {} bytes of padding before it would give it that",
+                padding
+            ),
+            None => write!(f, "; the section is not aligned that far"),
+        }
+    }
+}
+
+/// Every data item of `program`, laid out as `options` asks, whose address
+/// is not known to be a multiple of its natural alignment, in program
+/// order.
+pub fn check(
+    program: &Program,
+    options: &EncodeOptions,
+) -> Result<Vec<Misalignment>, Vec<EncodeError>> {
+    let layout = program.layout(options)?;
+    let mut misaligned = Vec::new();
+
+    for section in &layout.sections {
+        let start = match options.section_bases.get(&section.name) {
+            Some(0) => u64::MAX,
+            Some(&base) => 1 << base.trailing_zeros(),
+            None => options.section_alignment.max(1),
+        };
+        let mut label = None;
+        for entry in &section.entries {
+            let data = match &entry.kind {
+                EntryKind::Label(name) => {
+                    label = Some((name.clone(), entry.offset));
+                    continue;
+                }
+                EntryKind::Data(data) => data,
+                _ => continue,
+            };
+            let alignment = natural(data, entry.size);
+            let placed = match entry.offset {
+                0 => start,
+                offset => start.min(1 << offset.trailing_zeros()),
+            };
+            if placed >= alignment {
+                continue;
+            }
+            misaligned.push(Misalignment {
+                section: section.name.clone(),
+                item: data.to_string(),
+                offset: entry.offset,
+                label: label
+                    .as_ref()
+                    .map(|(name, at)| (name.clone(), entry.offset - at)),
+                alignment,
+                placed,
+                padding: (alignment <= start)
+                    .then(|| entry.offset.next_multiple_of(alignment) - entry.offset),
+            });
+        }
+    }
+
+    Ok(misaligned)
+}
+
+/// The alignment `data`, taking `size` bytes, is best read at: that of
+/// its type, or of one element for arrays and repeats.
+pub(crate) fn natural(data: &Data, size: u64) -> u64 {
+    match data {
+        Data::Byte(_) | Data::Bytes(_) | Data::Fill { .. } | Data::SkipTo { .. } => 1,
+        Data::Word(_) => 2,
+        Data::Dword(_) | Data::Offset { .. } | Data::ImageOffset(_) => 4,
+        Data::Int(_) | Data::UInt(_) | Data::USize(_) | Data::Float(_) => 8,
+        // As the System V ABI aligns `long double`.
+        Data::Tword(_) => 16,
+        Data::I128(_) | Data::U128(_) => 16,
+        Data::Str(_, encoding) => encoding.unit_width() as u64,
+        Data::Address(_) => size,
+        Data::Array(array) => array.element.width() as u64,
+        Data::Reserve { size, .. } => size.bits() as u64 / 8,
+        Data::Endian(_, data) => natural(data, size),
+        Data::Times(count, data) => natural(data, size / (*count).max(1) as u64),
+    }
+}
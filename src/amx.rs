@@ -0,0 +1,182 @@
+//! Intel AMX: the tile configuration block and typed constructors for the
+//! tile instructions, for generating matrix kernels.
+
+use std::{error, fmt};
+
+use crate::{
+    mnemonic::Amd64Mnemonic,
+    register::{Tmm, TILE_REGISTERS},
+    Amd64Instruction, Amd64Register, AsmExpr, Data, Mem, Operand,
+};
+
+/// Largest tile palette 1 supports: 16 rows of 64 bytes.
+const MAX_ROWS: u8 = 16;
+const MAX_BYTES_PER_ROW: u16 = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TileConfigError {
+    /// More rows than the palette allows.
+    TooManyRows { tile: Tmm, rows: u8 },
+    /// A row wider than the palette allows.
+    RowTooWide { tile: Tmm, bytes: u16 },
+    /// The tile was already configured.
+    Duplicate(Tmm),
+}
+
+impl fmt::Display for TileConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TileConfigError::TooManyRows { tile, rows } => write!(
+                f,
+                "{} is configured with {} rows, more than the {} allowed",
+                tile, rows, MAX_ROWS
+            ),
+            TileConfigError::RowTooWide { tile, bytes } => write!(
+                f,
+                "{} is configured with {}-byte rows, wider than the {} allowed",
+                tile, bytes, MAX_BYTES_PER_ROW
+            ),
+            TileConfigError::Duplicate(tile) => write!(f, "{} is configured twice", tile),
+        }
+    }
+}
+
+impl error::Error for TileConfigError {}
+
+/// Builder for the 64-byte block `ldtilecfg` loads, describing the shape
+/// of each tile under palette 1. Tiles left unconfigured are unusable.
+#[derive(Clone)]
+pub struct TileConfig {
+    start_row: u8,
+    /// Rows and bytes per row of each tile, if configured.
+    tiles: [Option<(u8, u16)>; TILE_REGISTERS as usize],
+}
+
+impl TileConfig {
+    pub fn new() -> Self {
+        TileConfig {
+            start_row: 0,
+            tiles: [None; TILE_REGISTERS as usize],
+        }
+    }
+
+    /// Gives `tile` `rows` rows of `bytes_per_row` bytes each.
+    pub fn tile(
+        mut self,
+        tile: Tmm,
+        rows: u8,
+        bytes_per_row: u16,
+    ) -> Result<Self, TileConfigError> {
+        if rows > MAX_ROWS {
+            return Err(TileConfigError::TooManyRows { tile, rows });
+        }
+        if bytes_per_row > MAX_BYTES_PER_ROW {
+            return Err(TileConfigError::RowTooWide {
+                tile,
+                bytes: bytes_per_row,
+            });
+        }
+
+        let slot = &mut self.tiles[tile.index() as usize];
+        if slot.is_some() {
+            return Err(TileConfigError::Duplicate(tile));
+        }
+        *slot = Some((rows, bytes_per_row));
+        Ok(self)
+    }
+
+    /// Row to resume from after an interrupted tile load or store. Only
+    /// needed when restoring saved state.
+    pub fn start_row(mut self, row: u8) -> Self {
+        self.start_row = row;
+        self
+    }
+
+    /// The configuration as laid out in memory: palette id, start row,
+    /// reserved bytes, then the bytes-per-row of all sixteen tile slots and
+    /// finally their row counts.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0; 64];
+        bytes[0] = 1;
+        bytes[1] = self.start_row;
+
+        for (i, tile) in self.tiles.iter().enumerate() {
+            if let Some((rows, bytes_per_row)) = tile {
+                bytes[16 + 2 * i..18 + 2 * i].copy_from_slice(&bytes_per_row.to_le_bytes());
+                bytes[48 + i] = *rows;
+            }
+        }
+
+        bytes
+    }
+
+    pub fn to_data(&self) -> Data {
+        Data::Bytes(self.to_bytes())
+    }
+}
+
+impl Default for TileConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn inst(mnemonic: &str, operands: Vec<Operand>) -> AsmExpr {
+    AsmExpr::Instruction(Amd64Instruction::new(
+        Amd64Mnemonic::parse(mnemonic),
+        operands,
+    ))
+}
+
+fn tile(reg: Tmm) -> Operand {
+    Operand::Register(Amd64Register::Tile(reg))
+}
+
+/// Loads the tile configuration stored at `config`.
+pub fn ldtilecfg(config: Mem) -> AsmExpr {
+    inst("ldtilecfg", vec![Operand::Memory(config)])
+}
+
+/// Saves the current tile configuration to `config`.
+pub fn sttilecfg(config: Mem) -> AsmExpr {
+    inst("sttilecfg", vec![Operand::Memory(config)])
+}
+
+/// Loads `dst` row by row from `[base + stride]`, where the index register
+/// of `src` holds the stride between rows.
+pub fn tileloadd(dst: Tmm, src: Mem) -> AsmExpr {
+    inst("tileloadd", vec![tile(dst), Operand::Memory(src)])
+}
+
+/// Stores `src` row by row, with the index register of `dst` as stride.
+pub fn tilestored(dst: Mem, src: Tmm) -> AsmExpr {
+    inst("tilestored", vec![Operand::Memory(dst), tile(src)])
+}
+
+pub fn tilezero(dst: Tmm) -> AsmExpr {
+    inst("tilezero", vec![tile(dst)])
+}
+
+/// Returns the tile unit to its initial, unconfigured state.
+pub fn tilerelease() -> AsmExpr {
+    inst("tilerelease", vec![])
+}
+
+macro_rules! dot_product {
+    ($($name:ident => $mnemonic:literal),* $(,)?) => {
+        $(
+            /// Accumulates the dot products of `a` and `b` into `acc`.
+            pub fn $name(acc: Tmm, a: Tmm, b: Tmm) -> AsmExpr {
+                inst($mnemonic, vec![tile(acc), tile(a), tile(b)])
+            }
+        )*
+    };
+}
+
+dot_product! {
+    tdpbf16ps => "tdpbf16ps",
+    tdpbssd => "tdpbssd",
+    tdpbsud => "tdpbsud",
+    tdpbusd => "tdpbusd",
+    tdpbuud => "tdpbuud",
+}
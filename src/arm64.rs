@@ -0,0 +1,496 @@
+//! AArch64 instructions, and the GNU and Apple assembler syntax a program
+//! targeting [`Arch::Arm64`] is written in.
+//!
+//! An [`Arm64Instruction`] sits in a section as [`AsmExpr::Arm64`], next to
+//! the same labels, data, blocks and conditionals x86-64 code uses. When
+//! the program's target is AArch64 its text output switches from NASM to
+//! `.globl`, `.section` and `.quad`, local labels become assembler-local
+//! symbols, and the `adrp`/`add` pair that loads a label's address is
+//! spelled for the target's [`Os`]:
+//!
+//! ```text
+//! adrp    x0, msg             ; Linux
+//! add     x0, x0, :lo12:msg
+//! adrp    x0, msg@PAGE        ; macOS
+//! add     x0, x0, msg@PAGEOFF
+//! ```
+//!
+//! AArch64 code is only ever emitted as text; the encoder, interpreter and
+//! analyses are x86-64 only and reject or skip it.
+
+use std::fmt;
+
+use crate::{gnu::Syntax, instr::CondCode, target::Os, AsmExpr, Label};
+
+/// A 64-bit `x` register, its 32-bit `w` half, the stack pointer or the
+/// zero register of either width.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Arm64Register {
+    X(u8),
+    W(u8),
+    Sp,
+    Xzr,
+    Wzr,
+}
+
+impl fmt::Display for Arm64Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Arm64Register::X(n) => write!(f, "x{}", n),
+            Arm64Register::W(n) => write!(f, "w{}", n),
+            Arm64Register::Sp => write!(f, "sp"),
+            Arm64Register::Xzr => write!(f, "xzr"),
+            Arm64Register::Wzr => write!(f, "wzr"),
+        }
+    }
+}
+
+pub const X0: Arm64Register = Arm64Register::X(0);
+pub const X1: Arm64Register = Arm64Register::X(1);
+pub const X2: Arm64Register = Arm64Register::X(2);
+pub const X3: Arm64Register = Arm64Register::X(3);
+pub const X4: Arm64Register = Arm64Register::X(4);
+pub const X5: Arm64Register = Arm64Register::X(5);
+pub const X6: Arm64Register = Arm64Register::X(6);
+pub const X7: Arm64Register = Arm64Register::X(7);
+pub const X8: Arm64Register = Arm64Register::X(8);
+pub const X9: Arm64Register = Arm64Register::X(9);
+pub const X10: Arm64Register = Arm64Register::X(10);
+pub const X11: Arm64Register = Arm64Register::X(11);
+pub const X12: Arm64Register = Arm64Register::X(12);
+pub const X13: Arm64Register = Arm64Register::X(13);
+pub const X14: Arm64Register = Arm64Register::X(14);
+pub const X15: Arm64Register = Arm64Register::X(15);
+pub const X16: Arm64Register = Arm64Register::X(16);
+pub const X17: Arm64Register = Arm64Register::X(17);
+pub const X18: Arm64Register = Arm64Register::X(18);
+pub const X19: Arm64Register = Arm64Register::X(19);
+pub const X20: Arm64Register = Arm64Register::X(20);
+pub const X21: Arm64Register = Arm64Register::X(21);
+pub const X22: Arm64Register = Arm64Register::X(22);
+pub const X23: Arm64Register = Arm64Register::X(23);
+pub const X24: Arm64Register = Arm64Register::X(24);
+pub const X25: Arm64Register = Arm64Register::X(25);
+pub const X26: Arm64Register = Arm64Register::X(26);
+pub const X27: Arm64Register = Arm64Register::X(27);
+pub const X28: Arm64Register = Arm64Register::X(28);
+pub const X29: Arm64Register = Arm64Register::X(29);
+pub const X30: Arm64Register = Arm64Register::X(30);
+pub const W0: Arm64Register = Arm64Register::W(0);
+pub const W1: Arm64Register = Arm64Register::W(1);
+pub const W2: Arm64Register = Arm64Register::W(2);
+pub const W3: Arm64Register = Arm64Register::W(3);
+pub const W4: Arm64Register = Arm64Register::W(4);
+pub const W5: Arm64Register = Arm64Register::W(5);
+pub const W6: Arm64Register = Arm64Register::W(6);
+pub const W7: Arm64Register = Arm64Register::W(7);
+pub const W8: Arm64Register = Arm64Register::W(8);
+pub const W9: Arm64Register = Arm64Register::W(9);
+pub const W10: Arm64Register = Arm64Register::W(10);
+pub const W11: Arm64Register = Arm64Register::W(11);
+pub const W12: Arm64Register = Arm64Register::W(12);
+pub const W13: Arm64Register = Arm64Register::W(13);
+pub const W14: Arm64Register = Arm64Register::W(14);
+pub const W15: Arm64Register = Arm64Register::W(15);
+pub const W16: Arm64Register = Arm64Register::W(16);
+pub const W17: Arm64Register = Arm64Register::W(17);
+pub const W18: Arm64Register = Arm64Register::W(18);
+pub const W19: Arm64Register = Arm64Register::W(19);
+pub const W20: Arm64Register = Arm64Register::W(20);
+pub const W21: Arm64Register = Arm64Register::W(21);
+pub const W22: Arm64Register = Arm64Register::W(22);
+pub const W23: Arm64Register = Arm64Register::W(23);
+pub const W24: Arm64Register = Arm64Register::W(24);
+pub const W25: Arm64Register = Arm64Register::W(25);
+pub const W26: Arm64Register = Arm64Register::W(26);
+pub const W27: Arm64Register = Arm64Register::W(27);
+pub const W28: Arm64Register = Arm64Register::W(28);
+pub const W29: Arm64Register = Arm64Register::W(29);
+pub const W30: Arm64Register = Arm64Register::W(30);
+pub const SP: Arm64Register = Arm64Register::Sp;
+pub const XZR: Arm64Register = Arm64Register::Xzr;
+pub const WZR: Arm64Register = Arm64Register::Wzr;
+/// The frame pointer, x29.
+pub const FP: Arm64Register = X29;
+/// The link register `bl` writes the return address to, x30.
+pub const LR: Arm64Register = X30;
+
+/// How a memory operand's offset applies to its base register.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Indexing {
+    /// `[base, #offset]`: the base is left alone.
+    Offset,
+    /// `[base, #offset]!`: the base is advanced before the access.
+    Pre,
+    /// `[base], #offset`: the base is advanced after the access.
+    Post,
+}
+
+#[derive(Clone)]
+pub enum Arm64Operand {
+    Register(Arm64Register),
+    /// `#n`.
+    Immediate(i64),
+    /// A base register plus a constant offset.
+    Memory {
+        base: Arm64Register,
+        offset: i64,
+        indexing: Indexing,
+    },
+    /// `[base, index, lsl #shift]`.
+    Indexed {
+        base: Arm64Register,
+        index: Arm64Register,
+        shift: u8,
+    },
+    /// A branch target, or a label `adr` and literal loads reach directly.
+    Label(Label),
+    /// The 4 KiB page holding a label, for `adrp`.
+    Page(Label),
+    /// A label's offset within its page, for the `add` or load that
+    /// follows an `adrp`.
+    PageOffset(Label),
+}
+
+impl Arm64Operand {
+    /// `[base, #offset]`.
+    pub fn mem(base: Arm64Register, offset: i64) -> Self {
+        Arm64Operand::Memory {
+            base,
+            offset,
+            indexing: Indexing::Offset,
+        }
+    }
+
+    /// `[base, #offset]!`, as in the `stp` that opens a frame.
+    pub fn pre(base: Arm64Register, offset: i64) -> Self {
+        Arm64Operand::Memory {
+            base,
+            offset,
+            indexing: Indexing::Pre,
+        }
+    }
+
+    /// `[base], #offset`, as in the `ldp` that closes a frame.
+    pub fn post(base: Arm64Register, offset: i64) -> Self {
+        Arm64Operand::Memory {
+            base,
+            offset,
+            indexing: Indexing::Post,
+        }
+    }
+
+    /// The label the operand refers to, if any.
+    pub fn label(&self) -> Option<&Label> {
+        match self {
+            Arm64Operand::Label(l) | Arm64Operand::Page(l) | Arm64Operand::PageOffset(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn label_mut(&mut self) -> Option<&mut Label> {
+        match self {
+            Arm64Operand::Label(l) | Arm64Operand::Page(l) | Arm64Operand::PageOffset(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    fn fmt_in(&self, f: &mut fmt::Formatter, syntax: &Syntax) -> fmt::Result {
+        match self {
+            Arm64Operand::Register(reg) => write!(f, "{}", reg),
+            Arm64Operand::Immediate(n) => write!(f, "#{}", n),
+            Arm64Operand::Memory {
+                base,
+                offset,
+                indexing,
+            } => match (indexing, offset) {
+                (Indexing::Offset, 0) => write!(f, "[{}]", base),
+                (Indexing::Offset, n) => write!(f, "[{}, #{}]", base, n),
+                (Indexing::Pre, n) => write!(f, "[{}, #{}]!", base, n),
+                (Indexing::Post, n) => write!(f, "[{}], #{}", base, n),
+            },
+            Arm64Operand::Indexed { base, index, shift } => match shift {
+                0 => write!(f, "[{}, {}]", base, index),
+                n => write!(f, "[{}, {}, lsl #{}]", base, index, n),
+            },
+            Arm64Operand::Label(label) => write!(f, "{}", syntax.symbol(&label.label)),
+            Arm64Operand::Page(label) => match syntax.os {
+                Os::Linux => write!(f, "{}", syntax.symbol(&label.label)),
+                Os::MacOs => write!(f, "{}@PAGE", syntax.symbol(&label.label)),
+            },
+            Arm64Operand::PageOffset(label) => match syntax.os {
+                Os::Linux => write!(f, ":lo12:{}", syntax.symbol(&label.label)),
+                Os::MacOs => write!(f, "{}@PAGEOFF", syntax.symbol(&label.label)),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Arm64Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_in(f, &Syntax::default())
+    }
+}
+
+impl From<Arm64Register> for Arm64Operand {
+    fn from(reg: Arm64Register) -> Self {
+        Arm64Operand::Register(reg)
+    }
+}
+
+impl From<i64> for Arm64Operand {
+    fn from(value: i64) -> Self {
+        Arm64Operand::Immediate(value)
+    }
+}
+
+impl From<i32> for Arm64Operand {
+    fn from(value: i32) -> Self {
+        Arm64Operand::Immediate(value as i64)
+    }
+}
+
+impl From<Label> for Arm64Operand {
+    fn from(label: Label) -> Self {
+        Arm64Operand::Label(label)
+    }
+}
+
+#[derive(Clone)]
+pub struct Arm64Instruction {
+    pub mnemonic: String,
+    pub operands: Vec<Arm64Operand>,
+}
+
+impl Arm64Instruction {
+    pub fn new(mnemonic: &str, operands: Vec<Arm64Operand>) -> Self {
+        Arm64Instruction {
+            mnemonic: mnemonic.to_string(),
+            operands,
+        }
+    }
+
+    pub(crate) fn fmt_in(&self, f: &mut fmt::Formatter, syntax: &Syntax) -> fmt::Result {
+        write!(f, "{}", self.mnemonic)?;
+        for (index, operand) in self.operands.iter().enumerate() {
+            write!(f, "{}", if index == 0 { "\t" } else { ", " })?;
+            operand.fmt_in(f, syntax)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Arm64Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_in(f, &Syntax::default())
+    }
+}
+
+/// AArch64 condition codes, as used by `b.cond` and `csel`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Condition {
+    Eq,
+    Ne,
+    Hs,
+    Lo,
+    Mi,
+    Pl,
+    Vs,
+    Vc,
+    Hi,
+    Ls,
+    Ge,
+    Lt,
+    Gt,
+    Le,
+}
+
+impl Condition {
+    /// The condition that, after `cmp`, holds when `cc` would after the
+    /// x86-64 `cmp` of the same operands. AArch64 has no parity flag, so
+    /// [`CondCode::P`] and [`CondCode::Np`] have none.
+    pub fn from_x86(cc: CondCode) -> Option<Self> {
+        Some(match cc {
+            CondCode::O => Condition::Vs,
+            CondCode::No => Condition::Vc,
+            // AArch64 sets the carry flag when a subtraction does not
+            // borrow, the opposite of x86-64.
+            CondCode::B => Condition::Lo,
+            CondCode::Ae => Condition::Hs,
+            CondCode::E => Condition::Eq,
+            CondCode::Ne => Condition::Ne,
+            CondCode::Be => Condition::Ls,
+            CondCode::A => Condition::Hi,
+            CondCode::S => Condition::Mi,
+            CondCode::Ns => Condition::Pl,
+            CondCode::P | CondCode::Np => return None,
+            CondCode::L => Condition::Lt,
+            CondCode::Ge => Condition::Ge,
+            CondCode::Le => Condition::Le,
+            CondCode::G => Condition::Gt,
+        })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Condition::Eq => "eq",
+            Condition::Ne => "ne",
+            Condition::Hs => "hs",
+            Condition::Lo => "lo",
+            Condition::Mi => "mi",
+            Condition::Pl => "pl",
+            Condition::Vs => "vs",
+            Condition::Vc => "vc",
+            Condition::Hi => "hi",
+            Condition::Ls => "ls",
+            Condition::Ge => "ge",
+            Condition::Lt => "lt",
+            Condition::Gt => "gt",
+            Condition::Le => "le",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+fn inst(mnemonic: &str, operands: Vec<Arm64Operand>) -> AsmExpr {
+    AsmExpr::Arm64(Arm64Instruction::new(mnemonic, operands))
+}
+
+macro_rules! ternary {
+    ($($name:ident => $mnemonic:literal),* $(,)?) => {
+        $(
+            pub fn $name(
+                dst: Arm64Register,
+                a: Arm64Register,
+                b: impl Into<Arm64Operand>,
+            ) -> AsmExpr {
+                inst($mnemonic, vec![dst.into(), a.into(), b.into()])
+            }
+        )*
+    };
+}
+
+ternary! {
+    add => "add",
+    adds => "adds",
+    sub => "sub",
+    subs => "subs",
+    and => "and",
+    orr => "orr",
+    eor => "eor",
+    lsl => "lsl",
+    lsr => "lsr",
+    asr => "asr",
+    mul => "mul",
+    udiv => "udiv",
+    sdiv => "sdiv",
+}
+
+/// `mov dst, src` from a register or an immediate the assembler can
+/// materialize in one instruction.
+pub fn mov(dst: Arm64Register, src: impl Into<Arm64Operand>) -> AsmExpr {
+    inst("mov", vec![dst.into(), src.into()])
+}
+
+pub fn cmp(a: Arm64Register, b: impl Into<Arm64Operand>) -> AsmExpr {
+    inst("cmp", vec![a.into(), b.into()])
+}
+
+/// Loads `dst` from `mem`, a memory operand or a label within 1 MiB.
+pub fn ldr(dst: Arm64Register, mem: impl Into<Arm64Operand>) -> AsmExpr {
+    inst("ldr", vec![dst.into(), mem.into()])
+}
+
+pub fn str(src: Arm64Register, mem: Arm64Operand) -> AsmExpr {
+    inst("str", vec![src.into(), mem])
+}
+
+pub fn ldrb(dst: Arm64Register, mem: Arm64Operand) -> AsmExpr {
+    inst("ldrb", vec![dst.into(), mem])
+}
+
+pub fn strb(src: Arm64Register, mem: Arm64Operand) -> AsmExpr {
+    inst("strb", vec![src.into(), mem])
+}
+
+pub fn ldp(a: Arm64Register, b: Arm64Register, mem: Arm64Operand) -> AsmExpr {
+    inst("ldp", vec![a.into(), b.into(), mem])
+}
+
+pub fn stp(a: Arm64Register, b: Arm64Register, mem: Arm64Operand) -> AsmExpr {
+    inst("stp", vec![a.into(), b.into(), mem])
+}
+
+/// The address of the page holding `label`.
+pub fn adrp(dst: Arm64Register, label: Label) -> AsmExpr {
+    inst("adrp", vec![dst.into(), Arm64Operand::Page(label)])
+}
+
+/// The address of `label`, which must be within 1 MiB.
+pub fn adr(dst: Arm64Register, label: Label) -> AsmExpr {
+    inst("adr", vec![dst.into(), label.into()])
+}
+
+/// The address of `label` anywhere within 4 GiB: `adrp` for its page,
+/// then `add` for its offset in the page.
+///
+/// ```
+/// use cataclysm::{arm64, program::Program, target::{Os, Target}, Label, Section};
+///
+/// let text = Section::new("text", vec![arm64::load_address(arm64::X0, Label::plain("msg"))]);
+/// let program = Program::new(vec![], vec![text]).with_target(Target::arm64(Os::MacOs));
+/// assert!(program.to_string().contains("add\tx0, x0, msg@PAGEOFF"));
+/// ```
+pub fn load_address(dst: Arm64Register, label: Label) -> AsmExpr {
+    AsmExpr::Block(vec![
+        adrp(dst, label.clone()),
+        inst(
+            "add",
+            vec![dst.into(), dst.into(), Arm64Operand::PageOffset(label)],
+        ),
+    ])
+}
+
+pub fn b(target: Label) -> AsmExpr {
+    inst("b", vec![target.into()])
+}
+
+pub fn bl(target: Label) -> AsmExpr {
+    inst("bl", vec![target.into()])
+}
+
+pub fn b_cond(cond: Condition, target: Label) -> AsmExpr {
+    inst(&format!("b.{}", cond), vec![target.into()])
+}
+
+pub fn cbz(reg: Arm64Register, target: Label) -> AsmExpr {
+    inst("cbz", vec![reg.into(), target.into()])
+}
+
+pub fn cbnz(reg: Arm64Register, target: Label) -> AsmExpr {
+    inst("cbnz", vec![reg.into(), target.into()])
+}
+
+pub fn br(target: Arm64Register) -> AsmExpr {
+    inst("br", vec![target.into()])
+}
+
+pub fn blr(target: Arm64Register) -> AsmExpr {
+    inst("blr", vec![target.into()])
+}
+
+pub fn ret() -> AsmExpr {
+    inst("ret", vec![])
+}
+
+pub fn nop() -> AsmExpr {
+    inst("nop", vec![])
+}
+
+/// A system call; Linux takes `#0` with the number in x8, macOS `#0x80`
+/// with it in x16.
+pub fn svc(imm: u16) -> AsmExpr {
+    inst("svc", vec![Arm64Operand::Immediate(imm as i64)])
+}
@@ -0,0 +1,156 @@
+//! Arrays of plain numbers emitted as a single data directive, for lookup
+//! tables built from Rust data.
+
+use std::fmt;
+
+use crate::{float_literal, Data, Endian};
+
+/// The type of each element of an [`Array`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Element {
+    U8,
+    U16,
+    U32,
+    U64,
+    I8,
+    I16,
+    I32,
+    I64,
+    F32,
+    F64,
+}
+
+impl Element {
+    /// Size of one element in bytes.
+    pub fn width(self) -> usize {
+        match self {
+            Element::U8 | Element::I8 => 1,
+            Element::U16 | Element::I16 => 2,
+            Element::U32 | Element::I32 | Element::F32 => 4,
+            Element::U64 | Element::I64 | Element::F64 => 8,
+        }
+    }
+
+    pub fn directive(self) -> &'static str {
+        match self.width() {
+            1 => "db",
+            2 => "dw",
+            4 => "dd",
+            _ => "dq",
+        }
+    }
+
+    /// An element's literal, from its bits zero-extended to 64.
+    fn literal(self, bits: u64) -> String {
+        match self {
+            Element::U8 | Element::U16 | Element::U32 | Element::U64 => bits.to_string(),
+            Element::I8 => (bits as i8).to_string(),
+            Element::I16 => (bits as i16).to_string(),
+            Element::I32 => (bits as i32).to_string(),
+            Element::I64 => (bits as i64).to_string(),
+            Element::F32 => float_literal(f32::from_bits(bits as u32)),
+            Element::F64 => float_literal(f64::from_bits(bits)),
+        }
+    }
+}
+
+/// Number types that can be laid out directly as array elements.
+pub trait Pod: Copy {
+    const ELEMENT: Element;
+
+    /// The value's bits, zero-extended to 64.
+    fn to_bits(self) -> u64;
+}
+
+macro_rules! pod {
+    ($($ty:ty => $element:ident as $unsigned:ty),* $(,)?) => {
+        $(
+            impl Pod for $ty {
+                const ELEMENT: Element = Element::$element;
+
+                fn to_bits(self) -> u64 {
+                    self as $unsigned as u64
+                }
+            }
+        )*
+    };
+}
+
+pod! {
+    u8 => U8 as u8,
+    u16 => U16 as u16,
+    u32 => U32 as u32,
+    u64 => U64 as u64,
+    i8 => I8 as u8,
+    i16 => I16 as u16,
+    i32 => I32 as u32,
+    i64 => I64 as u64,
+}
+
+impl Pod for f32 {
+    const ELEMENT: Element = Element::F32;
+
+    fn to_bits(self) -> u64 {
+        self.to_bits() as u64
+    }
+}
+
+impl Pod for f64 {
+    const ELEMENT: Element = Element::F64;
+
+    fn to_bits(self) -> u64 {
+        self.to_bits()
+    }
+}
+
+/// A sequence of numbers of one type.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Array {
+    pub element: Element,
+    /// Each element's bits, zero-extended to 64.
+    pub values: Vec<u64>,
+}
+
+impl Array {
+    /// The bytes of the array, each element laid out in `endian` order.
+    pub fn to_bytes_with(&self, endian: Endian) -> Vec<u8> {
+        let width = self.element.width();
+        let mut bytes = Vec::with_capacity(self.values.len() * width);
+        for value in &self.values {
+            match endian {
+                Endian::Little => bytes.extend_from_slice(&value.to_le_bytes()[..width]),
+                Endian::Big => bytes.extend_from_slice(&value.to_be_bytes()[8 - width..]),
+            }
+        }
+        bytes
+    }
+}
+
+impl fmt::Display for Array {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let directive = self.element.directive();
+        if self.values.is_empty() {
+            // A directive needs at least one operand.
+            return write!(f, "times 0 {} 0", directive);
+        }
+
+        write!(f, "{} ", directive)?;
+        for (i, &value) in self.values.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", self.element.literal(value))?;
+        }
+        Ok(())
+    }
+}
+
+impl Data {
+    /// `values` as one data item of the matching width.
+    pub fn from_slice<T: Pod>(values: &[T]) -> Data {
+        Data::Array(Array {
+            element: T::ELEMENT,
+            values: values.iter().map(|v| v.to_bits()).collect(),
+        })
+    }
+}
@@ -0,0 +1,184 @@
+//! x86-64 instructions in the AT&T syntax of the GNU assembler, for
+//! programs emitted with [`SyntaxFlavor::Gas`](crate::SyntaxFlavor::Gas).
+//!
+//! Operands are written source first, registers with `%` and immediates
+//! with `$`, memory as `disp(base, index, scale)`, and the operand size
+//! NASM spells as `qword` on the memory operand becomes a suffix on the
+//! mnemonic. Instructions whose Intel and AT&T names differ, such as `cqo`
+//! and `cqto`, are renamed.
+
+use std::fmt;
+
+use crate::{
+    gnu::Syntax, register::GprWidth, Amd64Instruction, ConstExpr, ImmediateValue, Mem, Operand,
+};
+
+/// Intel mnemonics with a different AT&T spelling.
+const RENAMED: &[(&str, &str)] = &[
+    ("cbw", "cbtw"),
+    ("cwde", "cwtl"),
+    ("cdqe", "cltq"),
+    ("cwd", "cwtd"),
+    ("cdq", "cltd"),
+    ("cqo", "cqto"),
+    ("movsxd", "movslq"),
+];
+
+/// The AT&T mnemonic suffix for an operand this wide.
+fn suffix(width: GprWidth) -> char {
+    match width {
+        GprWidth::Byte => 'b',
+        GprWidth::Word => 'w',
+        GprWidth::Dword => 'l',
+        GprWidth::Qword => 'q',
+    }
+}
+
+fn width_of(operand: &Operand) -> Option<GprWidth> {
+    match operand.width()? {
+        8 => Some(GprWidth::Byte),
+        16 => Some(GprWidth::Word),
+        32 => Some(GprWidth::Dword),
+        64 => Some(GprWidth::Qword),
+        _ => None,
+    }
+}
+
+/// Whether `mnemonic` transfers control to its operand, which AT&T writes
+/// without `$` when it is a label and with `*` when it is not.
+fn is_jump(mnemonic: &str) -> bool {
+    mnemonic == "call"
+        || mnemonic.starts_with('j')
+        || mnemonic.starts_with("loop")
+        || mnemonic == "xbegin"
+}
+
+/// The mnemonic as AT&T spells it, given the size NASM would write on an
+/// otherwise unsized memory operand.
+fn mnemonic(inst: &Amd64Instruction, size: Option<GprWidth>) -> String {
+    let (prefixes, name) = match inst.mnemonic.as_str().rsplit_once(' ') {
+        Some((prefixes, name)) => (format!("{} ", prefixes), name),
+        None => (String::new(), inst.mnemonic.as_str()),
+    };
+    if let Some((_, renamed)) = RENAMED.iter().find(|(intel, _)| *intel == name) {
+        return format!("{}{}", prefixes, renamed);
+    }
+
+    // `movzx` and `movsx` take the source and destination sizes as a
+    // pair of suffixes, `movzbl` and the like.
+    let extended = match name {
+        "movzx" => Some("movz"),
+        "movsx" => Some("movs"),
+        _ => None,
+    };
+    if let (Some(stem), [dst, src]) = (extended, inst.operands.as_slice()) {
+        if let (Some(to), Some(from)) = (width_of(dst), width_of(src).or(size)) {
+            return format!("{}{}{}{}", prefixes, stem, suffix(from), suffix(to));
+        }
+    }
+
+    match size {
+        Some(width) => format!("{}{}{}", prefixes, name, suffix(width)),
+        None => inst.mnemonic.to_string(),
+    }
+}
+
+fn fmt_expr(f: &mut fmt::Formatter, expr: &ConstExpr, syntax: &Syntax) -> fmt::Result {
+    let mut expr = expr.clone();
+    expr.rename_symbols(&|name| name.starts_with('.').then(|| syntax.symbol(name)));
+    write!(f, "{}", expr)
+}
+
+/// `label+disp(%base, %index, scale)`, with whichever parts `mem` has;
+/// a label without a base register is reached through `%rip`.
+fn fmt_memory(f: &mut fmt::Formatter, mem: &Mem, syntax: &Syntax) -> fmt::Result {
+    if let Some(label) = &mem.label {
+        write!(f, "{}", syntax.symbol(&label.label))?;
+        match mem.displacement {
+            0 => {}
+            d if d < 0 => write!(f, "{}", d)?,
+            d => write!(f, "+{}", d)?,
+        }
+    } else if mem.displacement != 0 || (mem.base.is_none() && mem.index.is_none()) {
+        write!(f, "{}", mem.displacement)?;
+    }
+    let base = match (&mem.base, &mem.label) {
+        (Some(base), _) => Some(base.to_string()),
+        (None, Some(_)) => Some("rip".to_string()),
+        (None, None) => None,
+    };
+    if base.is_none() && mem.index.is_none() {
+        return Ok(());
+    }
+    write!(f, "(")?;
+    if let Some(base) = base {
+        write!(f, "%{}", base)?;
+    }
+    if let Some(index) = &mem.index {
+        write!(f, ", %{}, {}", index, mem.scale)?;
+    }
+    write!(f, ")")
+}
+
+fn fmt_operand(
+    f: &mut fmt::Formatter,
+    operand: &Operand,
+    jump: bool,
+    syntax: &Syntax,
+) -> fmt::Result {
+    match operand {
+        Operand::Register(reg) if jump => write!(f, "*%{}", reg),
+        Operand::Register(reg) => write!(f, "%{}", reg),
+        Operand::Immediate(ImmediateValue::Label(label)) if jump => {
+            write!(f, "{}", syntax.symbol(&label.label))
+        }
+        Operand::Immediate(ImmediateValue::Label(label)) => {
+            write!(f, "${}", syntax.symbol(&label.label))
+        }
+        Operand::Immediate(ImmediateValue::Expr(expr)) => {
+            write!(f, "$")?;
+            fmt_expr(f, expr, syntax)
+        }
+        Operand::Immediate(ImmediateValue::F64(v)) => write!(f, "$0x{:x}", v.to_bits()),
+        Operand::Immediate(imm) => write!(f, "${}", imm),
+        Operand::Memory(mem) => {
+            if jump {
+                write!(f, "*")?;
+            }
+            fmt_memory(f, mem, syntax)
+        }
+        Operand::Param(name) => write!(f, "\\{}", name),
+    }
+}
+
+impl Amd64Instruction {
+    /// Writes the instruction in AT&T syntax, under the defaults and label
+    /// scope of `syntax`.
+    pub(crate) fn fmt_att(&self, f: &mut fmt::Formatter, syntax: &Syntax) -> fmt::Result {
+        let own = self.operands.iter().find_map(|operand| match operand {
+            Operand::Memory(mem) => mem.size,
+            _ => None,
+        });
+        let size = own.or(syntax
+            .defaults
+            .operand_size
+            .filter(|_| self.unsized_memory().is_some()));
+        write!(f, "{}", mnemonic(self, size))?;
+
+        let jump = is_jump(
+            self.mnemonic
+                .as_str()
+                .rsplit(' ')
+                .next()
+                .unwrap_or_default(),
+        );
+        for (index, operand) in self.operands.iter().rev().enumerate() {
+            write!(f, "{}", if index == 0 { "\t" } else { ", " })?;
+            fmt_operand(f, operand, jump, syntax)?;
+        }
+        match self.hint {
+            Some(hint) if syntax.hint_comments => write!(f, "\t# {}", hint),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! Benchmark loops built on [`TimingHarness`]: repeated, warmed-up
+//! timing of a snippet reduced to min and median cycle counts.
+
+use crate::{
+    consts::{R12, R13, R14, RAX, RCX, RDI, RDX},
+    instr::{cmp, dec, inc, jcc, jmp, lea, mov, sub, syscall, test, xor, CondCode},
+    program::Program,
+    register::Reg64,
+    timing::TimingHarness,
+    AsmExpr, Data, Global, Label, Mem, Section,
+};
+
+/// Generates code that runs a snippet `warmup` times untimed, then times
+/// it `samples` times and stores the minimum and median cycle counts, less
+/// the harness's own overhead, in `{name}_results`.
+///
+/// The loop keeps its state in r12-r14, which the snippet must preserve;
+/// the timing itself clobbers rax, rbx, rcx, rdx and the flags.
+pub struct BenchmarkLoop {
+    name: String,
+    warmup: u32,
+    samples: u32,
+}
+
+/// Size of the results buffer: min, median and overhead, 8 bytes each.
+const RESULTS_LEN: u32 = 24;
+
+impl BenchmarkLoop {
+    /// A loop taking `samples` timed runs, at least one.
+    pub fn new(name: &str, samples: u32) -> Self {
+        BenchmarkLoop {
+            name: name.to_string(),
+            warmup: 0,
+            samples: samples.max(1),
+        }
+    }
+
+    pub fn with_warmup(mut self, iterations: u32) -> Self {
+        self.warmup = iterations;
+        self
+    }
+
+    fn label(&self, suffix: &str) -> Label {
+        Label::plain(&format!("{}_{}", self.name, suffix))
+    }
+
+    /// A label in the loop's code, hashed so that it neither collides
+    /// with another copy of the loop nor ends the local-label scope of the
+    /// code around it.
+    fn code_label(&self, suffix: &str) -> Label {
+        Label::unique(&format!("{}_{}", self.name, suffix))
+    }
+
+    fn at(&self, suffix: &str) -> Mem {
+        Mem::label(self.label(suffix))
+    }
+
+    /// The results buffer, laid out as the `{name}_min`, `{name}_median`
+    /// and `{name}_overhead` slots in that order. The overhead is the
+    /// fastest an empty region can be timed, and is already subtracted
+    /// from the other two.
+    pub fn results(&self) -> Label {
+        self.label("results")
+    }
+
+    /// Sample storage and the results buffer, for a writable data section.
+    pub fn data(&self) -> Vec<AsmExpr> {
+        let mut data = self.harness("calibrate").slots();
+        data.extend(self.harness("time").slots());
+        data.extend([
+            AsmExpr::Label(self.label("samples")),
+            AsmExpr::Data(Data::Times(self.samples as usize, Box::new(Data::UInt(0)))),
+            AsmExpr::Label(self.results()),
+        ]);
+        for slot in ["min", "median", "overhead"] {
+            data.extend([
+                AsmExpr::Label(self.label(slot)),
+                AsmExpr::Data(Data::UInt(0)),
+            ]);
+        }
+        data
+    }
+
+    fn harness(&self, phase: &str) -> TimingHarness {
+        TimingHarness::new(&format!("{}_{}", self.name, phase))
+    }
+
+    /// The benchmark around `snippet`, including warm-up and calibration.
+    pub fn wrap(&self, snippet: Vec<AsmExpr>) -> AsmExpr {
+        let mut body = Vec::new();
+
+        if self.warmup > 0 {
+            let warmup = self.code_label("warmup");
+            body.extend([mov(R12, self.warmup), AsmExpr::Label(warmup.clone())]);
+            body.extend(snippet.iter().cloned());
+            body.extend([dec(R12), jcc(CondCode::Ne, warmup)]);
+        }
+
+        body.extend(self.sample("calibrate", Vec::new()));
+        body.extend([mov(RAX, self.sample_at(0)), mov(self.at("overhead"), RAX)]);
+
+        body.extend(self.sample("time", snippet));
+        body.extend([
+            mov(RAX, self.sample_at(0)),
+            sub(RAX, self.at("overhead")),
+            mov(self.at("min"), RAX),
+            mov(RAX, self.sample_at(self.samples as i64 / 2)),
+            sub(RAX, self.at("overhead")),
+            mov(self.at("median"), RAX),
+        ]);
+
+        AsmExpr::Block(body)
+    }
+
+    /// `[r14 + index*8]`, an entry of the sorted samples.
+    fn sample_at(&self, index: i64) -> Mem {
+        Mem::base(R14).with_displacement(index * 8)
+    }
+
+    /// Times `region` into each sample slot, then sorts the slots
+    /// ascending, leaving r14 pointing at them.
+    fn sample(&self, phase: &str, region: Vec<AsmExpr>) -> Vec<AsmExpr> {
+        let harness = self.harness(phase);
+        let [top, outer, inner, place, sorted] = ["loop", "outer", "inner", "place", "sorted"]
+            .map(|s| self.code_label(&format!("{}_{}", phase, s)));
+        let entry = |index| Mem::base(R14).with_index(index, 8);
+
+        let mut body = vec![
+            lea(Reg64::R14, self.at("samples")),
+            xor(R13, R13),
+            AsmExpr::Label(top.clone()),
+            harness.reset(),
+            harness.wrap(region),
+            mov(RAX, Mem::label(harness.cycles().clone())),
+            mov(entry(R13), RAX),
+            inc(R13),
+            cmp(R13, self.samples),
+            jcc(CondCode::B, top.clone()),
+        ];
+
+        // Insertion sort; sample counts are small.
+        body.extend([
+            mov(R13, 1u32),
+            AsmExpr::Label(outer.clone()),
+            cmp(R13, self.samples),
+            jcc(CondCode::Ae, sorted.clone()),
+            mov(RAX, entry(R13)),
+            mov(RCX, R13),
+            AsmExpr::Label(inner.clone()),
+            test(RCX, RCX),
+            jcc(CondCode::E, place.clone()),
+            mov(RDX, entry(RCX).with_displacement(-8)),
+            cmp(RDX, RAX),
+            jcc(CondCode::Be, place.clone()),
+            mov(entry(RCX), RDX),
+            dec(RCX),
+            jmp(inner.clone()),
+            AsmExpr::Label(place.clone()),
+            mov(entry(RCX), RAX),
+            inc(R13),
+            jmp(outer.clone()),
+            AsmExpr::Label(sorted.clone()),
+        ]);
+
+        body
+    }
+
+    /// A complete Linux program that runs the benchmark once, writes the
+    /// raw results buffer to stdout and exits.
+    pub fn program(&self, snippet: Vec<AsmExpr>) -> Program {
+        let text = vec![
+            AsmExpr::Label(Label::plain("_start")),
+            self.wrap(snippet),
+            mov(RAX, 1u32),
+            mov(RDI, 1u32),
+            lea(Reg64::Rsi, self.at("results")),
+            mov(RDX, RESULTS_LEN),
+            syscall(),
+            mov(RAX, 60u32),
+            xor(RDI, RDI),
+            syscall(),
+        ];
+
+        Program::new(
+            vec![Global::new("_start")],
+            vec![
+                Section::new("text", text),
+                Section::new("data", self.data()),
+            ],
+        )
+    }
+}
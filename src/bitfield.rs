@@ -0,0 +1,170 @@
+use std::{error, fmt};
+
+use crate::{Data, Endian};
+
+/// Storage width of a packed bitfield constant.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BitWidth {
+    U8,
+    U16,
+    U32,
+    U64,
+}
+
+impl BitWidth {
+    pub fn bits(self) -> u32 {
+        match self {
+            BitWidth::U8 => 8,
+            BitWidth::U16 => 16,
+            BitWidth::U32 => 32,
+            BitWidth::U64 => 64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BitfieldError {
+    /// The field does not fit inside the constant's storage width.
+    OutOfRange {
+        field: String,
+        offset: u32,
+        bits: u32,
+    },
+    /// The field shares bits with a previously added field.
+    Overlap { field: String, other: String },
+    /// The value needs more bits than the field provides.
+    ValueTooWide {
+        field: String,
+        value: u64,
+        bits: u32,
+    },
+}
+
+impl fmt::Display for BitfieldError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BitfieldError::OutOfRange {
+                field,
+                offset,
+                bits,
+            } => write!(
+                f,
+                "field `{}` (bits {}..{}) does not fit the bitfield",
+                field,
+                offset,
+                offset + bits
+            ),
+            BitfieldError::Overlap { field, other } => {
+                write!(f, "field `{}` overlaps field `{}`", field, other)
+            }
+            BitfieldError::ValueTooWide { field, value, bits } => write!(
+                f,
+                "value {:#x} does not fit the {}-bit field `{}`",
+                value, bits, field
+            ),
+        }
+    }
+}
+
+impl error::Error for BitfieldError {}
+
+struct Field {
+    name: String,
+    mask: u64,
+}
+
+/// Builder that packs named bitfields into a single integer constant, e.g.
+/// page-table entry flags or descriptor attribute bytes.
+pub struct Bitfield {
+    width: BitWidth,
+    fields: Vec<Field>,
+    value: u64,
+}
+
+impl Bitfield {
+    pub fn new(width: BitWidth) -> Self {
+        Bitfield {
+            width,
+            fields: Vec::new(),
+            value: 0,
+        }
+    }
+
+    /// Places `value` in the `bits`-wide field starting at bit `offset`.
+    pub fn field(
+        mut self,
+        name: &str,
+        offset: u32,
+        bits: u32,
+        value: u64,
+    ) -> Result<Self, BitfieldError> {
+        if bits == 0
+            || offset
+                .checked_add(bits)
+                .is_none_or(|end| end > self.width.bits())
+        {
+            return Err(BitfieldError::OutOfRange {
+                field: name.to_string(),
+                offset,
+                bits,
+            });
+        }
+
+        let field_mask = if bits == 64 {
+            u64::MAX
+        } else {
+            (1u64 << bits) - 1
+        };
+        if value & !field_mask != 0 {
+            return Err(BitfieldError::ValueTooWide {
+                field: name.to_string(),
+                value,
+                bits,
+            });
+        }
+
+        let mask = field_mask << offset;
+        if let Some(other) = self.fields.iter().find(|f| f.mask & mask != 0) {
+            return Err(BitfieldError::Overlap {
+                field: name.to_string(),
+                other: other.name.clone(),
+            });
+        }
+
+        self.fields.push(Field {
+            name: name.to_string(),
+            mask,
+        });
+        self.value |= value << offset;
+        Ok(self)
+    }
+
+    /// Single-bit field at `bit`.
+    pub fn flag(self, name: &str, bit: u32, set: bool) -> Result<Self, BitfieldError> {
+        self.field(name, bit, 1, set as u64)
+    }
+
+    pub fn width(&self) -> BitWidth {
+        self.width
+    }
+
+    /// The packed value, with every unassigned bit clear.
+    pub fn value(&self) -> u64 {
+        self.value
+    }
+
+    /// The packed value as a data item of the bitfield's storage width.
+    pub fn to_data(&self, endian: Endian) -> Data {
+        if self.width == BitWidth::U64 {
+            return Data::Endian(endian, Box::new(Data::UInt(self.value)));
+        }
+
+        let len = (self.width.bits() / 8) as usize;
+        let bytes = match endian {
+            Endian::Little => self.value.to_le_bytes()[..len].to_vec(),
+            Endian::Big => self.value.to_be_bytes()[8 - len..].to_vec(),
+        };
+
+        Data::Bytes(bytes)
+    }
+}
@@ -0,0 +1,69 @@
+//! Typed constructors for the bit-manipulation extensions (BMI1, BMI2,
+//! LZCNT and POPCNT), each gated on its [`CpuFeature`](crate::target::CpuFeature).
+
+use crate::{mnemonic::Amd64Mnemonic, Amd64Instruction, Amd64Register, AsmExpr, Operand};
+
+fn inst(mnemonic: &str, operands: Vec<Operand>) -> AsmExpr {
+    AsmExpr::Instruction(Amd64Instruction::new(
+        Amd64Mnemonic::parse(mnemonic),
+        operands,
+    ))
+}
+
+macro_rules! count {
+    ($($name:ident => $mnemonic:literal),* $(,)?) => {
+        $(
+            pub fn $name(dst: Amd64Register, src: impl Into<Operand>) -> AsmExpr {
+                inst($mnemonic, vec![Operand::Register(dst), src.into()])
+            }
+        )*
+    };
+}
+
+macro_rules! three_operand {
+    ($($(#[$doc:meta])* $name:ident => $mnemonic:literal),* $(,)?) => {
+        $(
+            $(#[$doc])*
+            pub fn $name(
+                dst: Amd64Register,
+                src1: Amd64Register,
+                src2: impl Into<Operand>,
+            ) -> AsmExpr {
+                inst(
+                    $mnemonic,
+                    vec![Operand::Register(dst), Operand::Register(src1), src2.into()],
+                )
+            }
+        )*
+    };
+}
+
+count! {
+    lzcnt => "lzcnt",
+    tzcnt => "tzcnt",
+    popcnt => "popcnt",
+}
+
+three_operand! {
+    /// `dst = !src1 & src2`.
+    andn => "andn",
+    /// Deposits the low bits of `src1` at the positions set in the mask
+    /// `src2`.
+    pdep => "pdep",
+    /// Gathers the bits of `src1` selected by the mask `src2` into the low
+    /// bits of `dst`.
+    pext => "pext",
+}
+
+/// Extracts a field of `src`: bits 0-7 of `control` give the start bit and
+/// bits 8-15 the length.
+pub fn bextr(dst: Amd64Register, src: impl Into<Operand>, control: Amd64Register) -> AsmExpr {
+    inst(
+        "bextr",
+        vec![
+            Operand::Register(dst),
+            src.into(),
+            Operand::Register(control),
+        ],
+    )
+}
@@ -0,0 +1,203 @@
+//! Self-contained, position-independent code blobs, for loaders and
+//! injectors that copy bytes to wherever there is room and jump in.
+//!
+//! Every section is packed into one buffer, so code reaches its data
+//! RIP-relatively and the blob runs at any address. Anything that would
+//! need relocating, such as `mov rax, label`, `dq label` or a reference to
+//! an extern at a fixed address, is rejected.
+
+use std::{collections::HashMap, error, fmt};
+
+use crate::{
+    encode::{self, EncodeError, EncodeOptions},
+    program::Program,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlobError {
+    Encode(EncodeError),
+    NoEntry(String),
+    /// An item whose bytes depend on where the blob is loaded.
+    NotPositionIndependent {
+        section: String,
+        item: String,
+    },
+}
+
+impl fmt::Display for BlobError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlobError::Encode(e) => write!(f, "{}", e),
+            BlobError::NoEntry(name) => write!(f, "entry point `{}` is not defined", name),
+            BlobError::NotPositionIndependent { section, item } => write!(
+                f,
+                "`{}` in section .{} depends on the load address",
+                item.replace('\t', " "),
+                section
+            ),
+        }
+    }
+}
+
+impl error::Error for BlobError {}
+
+impl From<EncodeError> for BlobError {
+    fn from(e: EncodeError) -> Self {
+        BlobError::Encode(e)
+    }
+}
+
+/// A program packed into one position-independent buffer.
+#[derive(Clone, Debug)]
+pub struct Blob {
+    pub bytes: Vec<u8>,
+    /// Offset of the entry point from the start of the blob.
+    pub entry: u64,
+    /// Offset of every label from the start of the blob.
+    pub symbols: HashMap<String, u64>,
+}
+
+/// Packs `program` into a blob entered at `entry`. Section placement in
+/// `options` is ignored; sections follow one another from offset zero.
+pub fn blob(
+    program: &Program,
+    entry: &str,
+    options: &EncodeOptions,
+) -> Result<Blob, Vec<BlobError>> {
+    let errors = |errors: Vec<EncodeError>| -> Vec<BlobError> {
+        errors.into_iter().map(BlobError::from).collect()
+    };
+    let mut options = options.clone().with_base(0);
+    options.section_bases.clear();
+
+    let at_zero = encode::encode(program, &options).map_err(errors)?;
+    let moving = encode::position_dependent(program, &options).map_err(errors)?;
+    if !moving.is_empty() {
+        return Err(moving
+            .into_iter()
+            .map(|(section, item)| BlobError::NotPositionIndependent { section, item })
+            .collect());
+    }
+
+    let &entry = at_zero
+        .symbols
+        .get(entry)
+        .ok_or_else(|| vec![BlobError::NoEntry(entry.to_string())])?;
+
+    let mut bytes = Vec::new();
+    for section in &at_zero.sections {
+        bytes.resize(section.address as usize, 0);
+        bytes.extend_from_slice(&section.bytes);
+    }
+
+    let mut symbols = at_zero.symbols;
+    symbols.retain(|name, _| !options.symbols.contains_key(name));
+    Ok(Blob {
+        bytes,
+        entry,
+        symbols,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm_dsl, AsmExpr, Data, Label, Section};
+
+    fn program(text: Vec<AsmExpr>, data: Vec<AsmExpr>) -> Program {
+        Program::default()
+            .with_section(Section::new("text", text))
+            .with_section(Section::new("data", data))
+    }
+
+    fn labelled(label: &str, body: Vec<AsmExpr>) -> Vec<AsmExpr> {
+        [vec![AsmExpr::Label(Label::plain(label))], body].concat()
+    }
+
+    fn rejected(errors: Vec<BlobError>) -> Vec<String> {
+        errors
+            .into_iter()
+            .map(|e| match e {
+                BlobError::NotPositionIndependent { item, .. } => item.replace('\t', " "),
+                other => panic!("{}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sections_are_packed_from_offset_zero() {
+        let text = [
+            asm_dsl! { nop; },
+            labelled("start", asm_dsl! { mov rax, [rel value]; ret; }),
+        ]
+        .concat();
+        let data = labelled("value", vec![AsmExpr::Data(Data::UInt(42))]);
+        let blob = blob(&program(text, data), "start", &EncodeOptions::new()).unwrap();
+        assert_eq!(blob.entry, 1);
+        assert_eq!(blob.symbols["value"], 16);
+        // The displacement is the distance from the end of the load, at 8,
+        // to the value.
+        let code = [0x90, 0x48, 0x8b, 0x05, 0x08, 0, 0, 0, 0xc3];
+        let mut expected = code.to_vec();
+        expected.resize(16, 0);
+        expected.extend(42u64.to_le_bytes());
+        assert_eq!(blob.bytes, expected);
+    }
+
+    #[test]
+    fn pinned_sections_are_packed_too() {
+        let text = labelled("start", asm_dsl! { ret; });
+        let data = labelled("value", vec![AsmExpr::Data(Data::UInt(1))]);
+        let options = EncodeOptions::new().with_section_base("data", 0x40_0000);
+        let blob = blob(&program(text, data), "start", &options).unwrap();
+        assert_eq!(blob.symbols["value"], 16);
+        assert_eq!(blob.bytes.len(), 24);
+    }
+
+    #[test]
+    fn absolute_addresses_are_rejected() {
+        let text = labelled("start", asm_dsl! { mov rax, value; ret; });
+        let data = [
+            labelled("value", vec![AsmExpr::Data(Data::UInt(1))]),
+            vec![AsmExpr::Data(Data::Address(Label::plain("start")))],
+        ]
+        .concat();
+        let errors = blob(&program(text, data), "start", &EncodeOptions::new()).unwrap_err();
+        assert_eq!(rejected(errors), ["mov rax, value", "dq start"]);
+    }
+
+    #[test]
+    fn distances_within_the_blob_are_kept() {
+        let text = labelled("start", asm_dsl! { ret; });
+        let offset = Data::Offset {
+            label: Label::plain("start"),
+            base: Label::plain("table"),
+        };
+        let data = labelled("table", vec![AsmExpr::Data(offset)]);
+        let blob = blob(&program(text, data), "start", &EncodeOptions::new()).unwrap();
+        assert_eq!(blob.bytes[16..], (-16i32).to_le_bytes());
+    }
+
+    #[test]
+    fn externs_at_fixed_addresses_are_rejected() {
+        let text = labelled("start", asm_dsl! { call puts; ret; });
+        let options = EncodeOptions::new().with_symbol("puts", 0x1000);
+        let errors = blob(&program(text, Vec::new()), "start", &options).unwrap_err();
+        assert_eq!(rejected(errors), ["call puts"]);
+    }
+
+    #[test]
+    fn externs_are_not_blob_symbols() {
+        let text = labelled("start", asm_dsl! { ret; });
+        let options = EncodeOptions::new().with_symbol("base", 0x1000);
+        let blob = blob(&program(text, Vec::new()), "start", &options).unwrap();
+        assert_eq!(blob.symbols.keys().collect::<Vec<_>>(), ["start"]);
+    }
+
+    #[test]
+    fn the_entry_point_must_exist() {
+        let text = labelled("start", asm_dsl! { ret; });
+        let errors = blob(&program(text, Vec::new()), "main", &EncodeOptions::new()).unwrap_err();
+        assert_eq!(errors, [BlobError::NoEntry("main".to_string())]);
+    }
+}
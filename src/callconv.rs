@@ -0,0 +1,217 @@
+//! Calling functions by a calling convention.
+//!
+//! A [`Call`] writes the code around a `call`: arguments past the
+//! convention's registers are pushed right to left, the rest moved into
+//! their registers, the stack kept 16-byte aligned at the call and the
+//! shadow space Windows requires reserved, and all of it released after.
+//! Arguments may be read from the argument registers themselves; the
+//! moves are those of [`fix_registers`](crate::fixed::fix_registers),
+//! ordered so that none is overwritten before it is read, and a cycle
+//! such as passing rsi and rdi swapped is broken by exchanging them:
+//!
+//! ```
+//! use cataclysm::{abi::CallingConvention, callconv::emit_call, consts::{RDI, RSI}, Label};
+//!
+//! let code = emit_call(
+//!     &CallingConvention::SYSTEM_V,
+//!     Label::plain("compare"),
+//!     &[RSI.into(), RDI.into()],
+//! )
+//! .unwrap();
+//! let lines: Vec<String> = code.iter().map(|line| line.to_string().trim().replace('\t', " ")).collect();
+//! assert_eq!(lines, ["xchg rdi, rsi", "call compare"]);
+//! ```
+
+use std::{error, fmt};
+
+use crate::{
+    abi::CallingConvention,
+    consts::RSP,
+    fixed::{load, parallel_move, reads, register},
+    instr::{add, call, push, sub},
+    register::{Gpr, GprWidth},
+    Amd64Register, AsmExpr, ImmediateValue, Label, Operand,
+};
+
+/// Caller-saved registers neither convention passes arguments in, tried in
+/// order when a stack argument has to be staged.
+const SCRATCH: [Gpr; 3] = [Gpr::R11, Gpr::R10, Gpr::RAX];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CallError {
+    /// Argument `index` is not a 64-bit general-purpose register, memory
+    /// or a plain immediate.
+    Argument { index: usize, operand: String },
+    /// The arguments read every scratch register, and one is needed.
+    NoScratch,
+    /// A misalignment that is not a multiple of 8.
+    Misalignment(u32),
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CallError::Argument { index, operand } => {
+                write!(f, "argument {} (`{}`) cannot be passed", index, operand)
+            }
+            CallError::NoScratch => write!(f, "the arguments leave no scratch register free"),
+            CallError::Misalignment(bytes) => {
+                write!(f, "rsp cannot be {} bytes off alignment", bytes)
+            }
+        }
+    }
+}
+
+impl error::Error for CallError {}
+
+/// A call of `function` with arguments given as operands, evaluated as if
+/// all were read before any was passed.
+#[derive(Clone, Debug)]
+pub struct Call {
+    convention: CallingConvention,
+    function: Label,
+    args: Vec<Operand>,
+    misalignment: u32,
+}
+
+impl Call {
+    pub fn new(convention: CallingConvention, function: Label) -> Self {
+        Call {
+            convention,
+            function,
+            args: Vec::new(),
+            misalignment: 0,
+        }
+    }
+
+    pub fn with_arg(mut self, arg: impl Into<Operand>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn with_args(mut self, args: &[Operand]) -> Self {
+        self.args.extend_from_slice(args);
+        self
+    }
+
+    /// How many bytes rsp is below a 16-byte boundary where the call is
+    /// made: 8 straight after entering a function, before anything is
+    /// pushed. Zero by default, as in the body of a function whose
+    /// prologue aligned the stack.
+    pub fn with_misalignment(mut self, bytes: u32) -> Self {
+        self.misalignment = bytes;
+        self
+    }
+
+    /// The code making the call, which leaves rsp as it found it. The
+    /// flags and any scratch register used are clobbered, besides what
+    /// the call itself clobbers.
+    pub fn emit(&self) -> Result<Vec<AsmExpr>, CallError> {
+        if !self.misalignment.is_multiple_of(8) {
+            return Err(CallError::Misalignment(self.misalignment));
+        }
+        for (index, arg) in self.args.iter().enumerate() {
+            if !passable(arg) {
+                return Err(CallError::Argument {
+                    index,
+                    operand: arg.to_string(),
+                });
+            }
+        }
+        let scratch = SCRATCH
+            .into_iter()
+            .find(|&reg| !self.args.iter().any(|arg| reads(arg).contains(reg)))
+            .ok_or(CallError::NoScratch);
+
+        let registers = self.convention.arguments;
+        let (in_registers, on_stack) = self.args.split_at(self.args.len().min(registers.len()));
+
+        // Stack arguments sit right above the shadow space, so padding
+        // goes below the return address's slot only before they are
+        // pushed.
+        let mut shadow = self.convention.shadow_space;
+        let pushed = 8 * on_stack.len() as u32;
+        let mut padding = (16 - (self.misalignment + pushed + shadow) % 16) % 16;
+        if on_stack.is_empty() {
+            padding += shadow;
+            shadow = 0;
+        }
+
+        let mut code = Vec::new();
+        let mut lowered = 0;
+        if padding > 0 {
+            code.push(sub(RSP, padding));
+            lowered += padding;
+        }
+        for arg in on_stack.iter().rev() {
+            let arg = below(arg, lowered);
+            match &arg {
+                Operand::Register(_) => code.push(push(arg)),
+                Operand::Memory(mem) => code.push(push(mem.clone().with_size(GprWidth::Qword))),
+                Operand::Immediate(imm) if fits_i32(imm) => code.push(push(arg)),
+                _ => {
+                    let scratch = scratch.clone()?;
+                    code.push(load(scratch, arg));
+                    code.push(push(register(scratch)));
+                }
+            }
+            lowered += 8;
+        }
+        if shadow > 0 {
+            code.push(sub(RSP, shadow));
+            lowered += shadow;
+        }
+
+        code.extend(parallel_move(
+            registers
+                .iter()
+                .zip(in_registers)
+                .map(|(&reg, arg)| (reg, below(arg, lowered)))
+                .collect(),
+        ));
+
+        code.push(call(self.function.clone()));
+        if lowered > 0 {
+            code.push(add(RSP, lowered));
+        }
+        Ok(code)
+    }
+}
+
+/// A call of `function` with `args`, made where rsp is 16-byte aligned.
+pub fn emit_call(
+    convention: &CallingConvention,
+    function: Label,
+    args: &[Operand],
+) -> Result<Vec<AsmExpr>, CallError> {
+    Call::new(*convention, function).with_args(args).emit()
+}
+
+fn passable(arg: &Operand) -> bool {
+    match arg {
+        Operand::Register(reg) => reg.gpr().is_some(),
+        Operand::Memory(_) => true,
+        Operand::Immediate(imm) => !matches!(imm, ImmediateValue::Bytes(_)),
+        Operand::Param(_) => false,
+    }
+}
+
+fn fits_i32(imm: &ImmediateValue) -> bool {
+    match imm {
+        ImmediateValue::I64(v) => i32::try_from(*v).is_ok(),
+        ImmediateValue::U64(v) => i32::try_from(*v).is_ok(),
+        ImmediateValue::USize(v) => i32::try_from(*v).is_ok(),
+        _ => false,
+    }
+}
+
+/// `arg` as read once rsp has been lowered by `bytes`.
+pub(crate) fn below(arg: &Operand, bytes: u32) -> Operand {
+    let mut arg = arg.clone();
+    if let Operand::Memory(mem) = &mut arg {
+        if mem.base.as_ref().and_then(Amd64Register::gpr) == Some(Gpr::RSP) {
+            mem.displacement += bytes as i64;
+        }
+    }
+    arg
+}
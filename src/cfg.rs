@@ -0,0 +1,382 @@
+//! Control-flow graphs of the functions in a program's text sections.
+//!
+//! A function starts at each exported label, each call target, the first
+//! label of every text section and every non-local label that follows a
+//! return, and runs up to the start of the next one. Conditionals are
+//! resolved under the program's build configuration, so the graph matches
+//! what is emitted.
+//!
+//! Besides returns and jumps, a `syscall` the block has just loaded with
+//! the `exit` or `exit_group` number ends a block with no successors.
+
+use std::{collections::HashMap, fmt::Write};
+
+use crate::{
+    cond::BuildConfig,
+    dataflow::{self, constant},
+    expr::ConstExpr,
+    program::Program,
+    qualify_label,
+    register::Gpr,
+    Amd64Instruction, AsmExpr, ImmediateValue, Operand,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EdgeKind {
+    /// Execution runs off the end of the block.
+    Fallthrough,
+    /// An unconditional jump.
+    Jump,
+    /// The taken side of a conditional branch.
+    Branch,
+}
+
+#[derive(Clone, Default)]
+pub struct BasicBlock {
+    /// Labels defined at the top of the block, NASM-qualified.
+    pub labels: Vec<String>,
+    pub instructions: Vec<Amd64Instruction>,
+    /// Blocks of the same function control may pass to.
+    pub successors: Vec<(usize, EdgeKind)>,
+    /// Whether the block may also leave the function other than by
+    /// returning: an indirect jump, or a jump or fall-through into another
+    /// function.
+    pub exits: bool,
+}
+
+#[derive(Clone)]
+pub struct Function {
+    pub name: String,
+    /// The entry block first, then the rest in program order.
+    pub blocks: Vec<BasicBlock>,
+}
+
+#[derive(Clone, Default)]
+pub struct Cfg {
+    pub functions: Vec<Function>,
+}
+
+/// Why a block ends.
+enum Terminator<'a> {
+    None,
+    Jump(Option<&'a str>),
+    Branch(Option<&'a str>),
+    Return,
+}
+
+/// The label a direct jump or call goes to.
+fn direct_target(inst: &Amd64Instruction) -> Option<&str> {
+    match inst.operands.first() {
+        Some(Operand::Immediate(ImmediateValue::Label(label))) => Some(&label.label),
+        _ => None,
+    }
+}
+
+/// Whether the `syscall` ending `instructions` is `exit` or `exit_group`,
+/// judging by the last value the block put in rax.
+fn is_exit(instructions: &[Amd64Instruction], defines: &HashMap<String, ConstExpr>) -> bool {
+    let Some((syscall, before)) = instructions.split_last() else {
+        return false;
+    };
+    if syscall.mnemonic != "syscall" {
+        return false;
+    }
+
+    let number = before
+        .iter()
+        .rev()
+        .find(|inst| dataflow::effects(inst).defs.contains(Gpr::RAX))
+        .filter(|inst| inst.mnemonic == "mov")
+        .and_then(|inst| constant(inst.operands.get(1)?, defines));
+    // The x32 ABI sets bit 30 of every system call number.
+    matches!(number.map(|n| n & !0x4000_0000), Some(60 | 231))
+}
+
+fn terminator(inst: &Amd64Instruction) -> Terminator<'_> {
+    let target = direct_target(inst);
+    match inst.mnemonic.as_str() {
+        "jmp" => Terminator::Jump(target),
+        "ret" | "iretq" | "sysret" | "sysretq" | "hlt" | "ud2" => Terminator::Return,
+        m if m.starts_with('j') => Terminator::Branch(target),
+        _ => Terminator::None,
+    }
+}
+
+/// One instruction or label of a text section, with local labels already
+/// qualified by their scope.
+enum Item {
+    Label(String),
+    Instruction(Amd64Instruction, String),
+}
+
+fn flatten(body: &[AsmExpr], config: &BuildConfig, scope: &mut String, out: &mut Vec<Item>) {
+    for expr in body {
+        match expr {
+            AsmExpr::Label(label) => {
+                if !label.label.starts_with('.') {
+                    *scope = label.label.clone();
+                }
+                out.push(Item::Label(qualify_label(scope, &label.label)));
+            }
+            AsmExpr::Instruction(inst) => out.push(Item::Instruction(inst.clone(), scope.clone())),
+            AsmExpr::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let arm = if cond.eval(config) { then } else { otherwise };
+                flatten(arm, config, scope, out);
+            }
+            AsmExpr::Block(inner)
+            | AsmExpr::Region { body: inner, .. }
+            | AsmExpr::Macro { body: inner, .. } => flatten(inner, config, scope, out),
+            _ => {}
+        }
+    }
+}
+
+impl Cfg {
+    pub fn build(program: &Program) -> Self {
+        let mut items = Vec::new();
+        let mut entries = Vec::new();
+        let mut scope = String::new();
+
+        for section in program
+            .sections
+            .iter()
+            .filter(|s| s.name.starts_with("text"))
+        {
+            let start = items.len();
+            flatten(&section.body, &program.config, &mut scope, &mut items);
+            if let Some(first) = items[start..]
+                .iter()
+                .position(|i| matches!(i, Item::Label(_)))
+            {
+                entries.push(start + first);
+            }
+        }
+
+        let labels: HashMap<&str, usize> = items
+            .iter()
+            .enumerate()
+            .filter_map(|(i, item)| match item {
+                Item::Label(name) => Some((name.as_str(), i)),
+                _ => None,
+            })
+            .collect();
+
+        for (i, item) in items.iter().enumerate() {
+            match item {
+                Item::Label(name) if program.globals.iter().any(|g| g.value == *name) => {
+                    entries.push(i)
+                }
+                Item::Label(name) if !name.contains('.') && i > 0 => {
+                    if let Item::Instruction(prev, _) = &items[i - 1] {
+                        if matches!(terminator(prev), Terminator::Return) {
+                            entries.push(i);
+                        }
+                    }
+                }
+                Item::Instruction(inst, scope) if inst.mnemonic == "call" => {
+                    if let Some(target) = direct_target(inst) {
+                        if let Some(&at) = labels.get(qualify_label(scope, target).as_str()) {
+                            entries.push(at);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        entries.sort_unstable();
+        entries.dedup();
+
+        let defines = program.define_map();
+        let mut functions = Vec::new();
+        for (n, &start) in entries.iter().enumerate() {
+            let end = entries.get(n + 1).copied().unwrap_or(items.len());
+            functions.push(build_function(&items[start..end], &defines));
+        }
+
+        Cfg { functions }
+    }
+
+    pub fn function(&self, name: &str) -> Option<&Function> {
+        self.functions.iter().find(|f| f.name == name)
+    }
+
+    /// Every function as a cluster of one Graphviz DOT graph.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph cfg {\n  node [shape=box, fontname=monospace];\n");
+        for (i, function) in self.functions.iter().enumerate() {
+            writeln!(dot, "  subgraph cluster_{} {{", i).unwrap();
+            writeln!(dot, "    label={};", quote(&function.name)).unwrap();
+            function.write_dot(&mut dot, "    ");
+            dot.push_str("  }\n");
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+fn build_function(items: &[Item], defines: &HashMap<String, ConstExpr>) -> Function {
+    let name = match &items[0] {
+        Item::Label(name) => name.clone(),
+        Item::Instruction(..) => unreachable!("functions start at a label"),
+    };
+
+    // Split into blocks: a label starts one unless the block so far holds
+    // only labels, and a control transfer ends one.
+    let mut blocks = vec![BasicBlock::default()];
+    let mut scopes = vec![Vec::new()];
+    let mut halts = vec![false];
+    for item in items {
+        let current = blocks.last_mut().unwrap();
+        match item {
+            Item::Label(label) => {
+                if !current.instructions.is_empty() {
+                    blocks.push(BasicBlock::default());
+                    scopes.push(Vec::new());
+                    halts.push(false);
+                }
+                blocks.last_mut().unwrap().labels.push(label.clone());
+            }
+            Item::Instruction(inst, scope) => {
+                current.instructions.push(inst.clone());
+                scopes.last_mut().unwrap().push(scope.clone());
+                let exit = is_exit(&current.instructions, defines);
+                if exit || !matches!(terminator(inst), Terminator::None) {
+                    *halts.last_mut().unwrap() = exit;
+                    blocks.push(BasicBlock::default());
+                    scopes.push(Vec::new());
+                    halts.push(false);
+                }
+            }
+        }
+    }
+    if blocks.len() > 1
+        && blocks
+            .last()
+            .is_some_and(|b| b.labels.is_empty() && b.instructions.is_empty())
+    {
+        blocks.pop();
+    }
+
+    let index: HashMap<String, usize> = blocks
+        .iter()
+        .enumerate()
+        .flat_map(|(i, b)| b.labels.iter().map(move |l| (l.clone(), i)))
+        .collect();
+    let count = blocks.len();
+
+    for (i, block) in blocks.iter_mut().enumerate() {
+        let last = block.instructions.last().map(|inst| {
+            let scope = scopes[i].last().unwrap();
+            (terminator(inst), scope)
+        });
+        let (falls_through, target) = match last {
+            _ if halts[i] => (false, None),
+            Some((Terminator::Return, _)) => (false, None),
+            Some((Terminator::Jump(target), scope)) => {
+                (false, Some((target, scope, EdgeKind::Jump)))
+            }
+            Some((Terminator::Branch(target), scope)) => {
+                (true, Some((target, scope, EdgeKind::Branch)))
+            }
+            _ => (true, None),
+        };
+
+        if let Some((target, scope, kind)) = target {
+            match target.and_then(|t| index.get(&qualify_label(scope, t))) {
+                Some(&to) => block.successors.push((to, kind)),
+                None => block.exits = true,
+            }
+        }
+        if falls_through {
+            if i + 1 < count {
+                block.successors.push((i + 1, EdgeKind::Fallthrough));
+            } else {
+                block.exits = true;
+            }
+        }
+    }
+
+    Function { name, blocks }
+}
+
+fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl Function {
+    /// Which blocks control can reach from the entry block.
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut seen = vec![false; self.blocks.len()];
+        let mut stack = vec![0];
+        while let Some(i) = stack.pop() {
+            if i < seen.len() && !seen[i] {
+                seen[i] = true;
+                stack.extend(self.blocks[i].successors.iter().map(|(to, _)| *to));
+            }
+        }
+        seen
+    }
+
+    /// Blocks that may pass control to block `index`.
+    pub fn predecessors(&self, index: usize) -> impl Iterator<Item = usize> + '_ {
+        self.blocks
+            .iter()
+            .enumerate()
+            .filter(move |(_, b)| b.successors.iter().any(|(to, _)| *to == index))
+            .map(|(i, _)| i)
+    }
+
+    /// The function's blocks, each listing its labels and instructions, in
+    /// Graphviz DOT syntax. Taken branches are drawn solid, fall-through
+    /// dashed.
+    pub fn to_dot(&self) -> String {
+        let mut dot = format!(
+            "digraph {} {{\n  node [shape=box, fontname=monospace];\n",
+            quote(&self.name)
+        );
+        self.write_dot(&mut dot, "  ");
+        dot.push_str("}\n");
+        dot
+    }
+
+    fn write_dot(&self, dot: &mut String, indent: &str) {
+        let node = |i: usize| quote(&format!("{}#{}", self.name, i));
+
+        for (i, block) in self.blocks.iter().enumerate() {
+            let mut text = String::new();
+            for label in &block.labels {
+                text.push_str(&format!("{}:\n", label));
+            }
+            for inst in &block.instructions {
+                text.push_str(&format!("    {}\n", inst.to_string().replace('\t', " ")));
+            }
+            // `\l` ends a left-aligned line in a DOT label.
+            let label = quote(&text).replace('\n', "\\l");
+            let peripheries = if block.exits { ", peripheries=2" } else { "" };
+            writeln!(
+                dot,
+                "{}{} [label={}{}];",
+                indent,
+                node(i),
+                label,
+                peripheries
+            )
+            .unwrap();
+        }
+
+        for (i, block) in self.blocks.iter().enumerate() {
+            for (to, kind) in &block.successors {
+                let style = match kind {
+                    EdgeKind::Fallthrough => " [style=dashed]",
+                    EdgeKind::Jump => "",
+                    EdgeKind::Branch => " [color=darkgreen]",
+                };
+                writeln!(dot, "{}{} -> {}{};", indent, node(i), node(*to), style).unwrap();
+            }
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+
+/// Key/value build settings that conditional assembly is evaluated against,
+/// in the spirit of Rust's `cfg`: a key may be present as a bare flag or
+/// carry a value.
+#[derive(Clone, Default)]
+pub struct BuildConfig {
+    values: HashMap<String, Option<String>>,
+}
+
+impl BuildConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn enable(&mut self, flag: &str) -> &mut Self {
+        self.values.insert(flag.to_string(), None);
+        self
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> &mut Self {
+        self.values.insert(key.to_string(), Some(value.to_string()));
+        self
+    }
+
+    pub fn is_set(&self, key: &str) -> bool {
+        self.values.contains_key(key)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).and_then(|v| v.as_deref())
+    }
+}
+
+/// Condition attached to an [`AsmExpr::If`](crate::AsmExpr::If).
+#[derive(Clone, PartialEq)]
+pub enum Cond {
+    /// The key is present in the configuration, with or without a value.
+    Set(String),
+    /// The key is present with exactly this value.
+    Eq(String, String),
+    Not(Box<Cond>),
+    All(Vec<Cond>),
+    Any(Vec<Cond>),
+}
+
+impl Cond {
+    pub fn set(key: &str) -> Self {
+        Cond::Set(key.to_string())
+    }
+
+    pub fn eq(key: &str, value: &str) -> Self {
+        Cond::Eq(key.to_string(), value.to_string())
+    }
+
+    // Reads as `Cond::not(..)` alongside `Cond::eq`, which an `ops::Not`
+    // impl would not.
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(cond: Cond) -> Self {
+        Cond::Not(Box::new(cond))
+    }
+
+    pub fn eval(&self, config: &BuildConfig) -> bool {
+        match self {
+            Cond::Set(key) => config.is_set(key),
+            Cond::Eq(key, value) => config.get(key) == Some(value.as_str()),
+            Cond::Not(inner) => !inner.eval(config),
+            Cond::All(conds) => conds.iter().all(|c| c.eval(config)),
+            Cond::Any(conds) => conds.iter().any(|c| c.eval(config)),
+        }
+    }
+}
@@ -0,0 +1,169 @@
+//! Register constants, so operands can be written as `RAX` rather than
+//! `Amd64Register::GeneralPurpose(Gpr::RAX)`.
+
+use crate::{
+    register::{Gpr, GprWidth, Opmask, Tmm, Xmm, Ymm, Zmm},
+    Amd64Register, Amd64SpecialRegister,
+};
+
+pub const RAX: Amd64Register = Amd64Register::GeneralPurpose(Gpr::RAX);
+pub const RBX: Amd64Register = Amd64Register::GeneralPurpose(Gpr::RBX);
+pub const RCX: Amd64Register = Amd64Register::GeneralPurpose(Gpr::RCX);
+pub const RDX: Amd64Register = Amd64Register::GeneralPurpose(Gpr::RDX);
+pub const RDI: Amd64Register = Amd64Register::GeneralPurpose(Gpr::RDI);
+pub const RSI: Amd64Register = Amd64Register::GeneralPurpose(Gpr::RSI);
+pub const RIP: Amd64Register = Amd64Register::Special(Amd64SpecialRegister::RIP);
+pub const RSP: Amd64Register = Amd64Register::GeneralPurpose(Gpr::RSP);
+pub const RBP: Amd64Register = Amd64Register::GeneralPurpose(Gpr::RBP);
+pub const R8: Amd64Register = Amd64Register::GeneralPurpose(Gpr::R8);
+pub const R9: Amd64Register = Amd64Register::GeneralPurpose(Gpr::R9);
+pub const R10: Amd64Register = Amd64Register::GeneralPurpose(Gpr::R10);
+pub const R11: Amd64Register = Amd64Register::GeneralPurpose(Gpr::R11);
+pub const R12: Amd64Register = Amd64Register::GeneralPurpose(Gpr::R12);
+pub const R13: Amd64Register = Amd64Register::GeneralPurpose(Gpr::R13);
+pub const R14: Amd64Register = Amd64Register::GeneralPurpose(Gpr::R14);
+pub const R15: Amd64Register = Amd64Register::GeneralPurpose(Gpr::R15);
+pub const R16: Amd64Register = Amd64Register::GeneralPurpose(Gpr::R16);
+pub const R17: Amd64Register = Amd64Register::GeneralPurpose(Gpr::R17);
+pub const R18: Amd64Register = Amd64Register::GeneralPurpose(Gpr::R18);
+pub const R19: Amd64Register = Amd64Register::GeneralPurpose(Gpr::R19);
+pub const R20: Amd64Register = Amd64Register::GeneralPurpose(Gpr::R20);
+pub const R21: Amd64Register = Amd64Register::GeneralPurpose(Gpr::R21);
+pub const R22: Amd64Register = Amd64Register::GeneralPurpose(Gpr::R22);
+pub const R23: Amd64Register = Amd64Register::GeneralPurpose(Gpr::R23);
+pub const R24: Amd64Register = Amd64Register::GeneralPurpose(Gpr::R24);
+pub const R25: Amd64Register = Amd64Register::GeneralPurpose(Gpr::R25);
+pub const R26: Amd64Register = Amd64Register::GeneralPurpose(Gpr::R26);
+pub const R27: Amd64Register = Amd64Register::GeneralPurpose(Gpr::R27);
+pub const R28: Amd64Register = Amd64Register::GeneralPurpose(Gpr::R28);
+pub const R29: Amd64Register = Amd64Register::GeneralPurpose(Gpr::R29);
+pub const R30: Amd64Register = Amd64Register::GeneralPurpose(Gpr::R30);
+pub const R31: Amd64Register = Amd64Register::GeneralPurpose(Gpr::R31);
+pub const EAX: Amd64Register = Amd64Register::Partial(Gpr::RAX, GprWidth::Dword);
+pub const ECX: Amd64Register = Amd64Register::Partial(Gpr::RCX, GprWidth::Dword);
+pub const EDX: Amd64Register = Amd64Register::Partial(Gpr::RDX, GprWidth::Dword);
+pub const EBX: Amd64Register = Amd64Register::Partial(Gpr::RBX, GprWidth::Dword);
+pub const ESP: Amd64Register = Amd64Register::Partial(Gpr::RSP, GprWidth::Dword);
+pub const EBP: Amd64Register = Amd64Register::Partial(Gpr::RBP, GprWidth::Dword);
+pub const ESI: Amd64Register = Amd64Register::Partial(Gpr::RSI, GprWidth::Dword);
+pub const EDI: Amd64Register = Amd64Register::Partial(Gpr::RDI, GprWidth::Dword);
+pub const R8D: Amd64Register = Amd64Register::Partial(Gpr::R8, GprWidth::Dword);
+pub const R9D: Amd64Register = Amd64Register::Partial(Gpr::R9, GprWidth::Dword);
+pub const R10D: Amd64Register = Amd64Register::Partial(Gpr::R10, GprWidth::Dword);
+pub const R11D: Amd64Register = Amd64Register::Partial(Gpr::R11, GprWidth::Dword);
+pub const R12D: Amd64Register = Amd64Register::Partial(Gpr::R12, GprWidth::Dword);
+pub const R13D: Amd64Register = Amd64Register::Partial(Gpr::R13, GprWidth::Dword);
+pub const R14D: Amd64Register = Amd64Register::Partial(Gpr::R14, GprWidth::Dword);
+pub const R15D: Amd64Register = Amd64Register::Partial(Gpr::R15, GprWidth::Dword);
+pub const AX: Amd64Register = Amd64Register::Partial(Gpr::RAX, GprWidth::Word);
+pub const CX: Amd64Register = Amd64Register::Partial(Gpr::RCX, GprWidth::Word);
+pub const DX: Amd64Register = Amd64Register::Partial(Gpr::RDX, GprWidth::Word);
+pub const BX: Amd64Register = Amd64Register::Partial(Gpr::RBX, GprWidth::Word);
+pub const SP: Amd64Register = Amd64Register::Partial(Gpr::RSP, GprWidth::Word);
+pub const BP: Amd64Register = Amd64Register::Partial(Gpr::RBP, GprWidth::Word);
+pub const SI: Amd64Register = Amd64Register::Partial(Gpr::RSI, GprWidth::Word);
+pub const DI: Amd64Register = Amd64Register::Partial(Gpr::RDI, GprWidth::Word);
+pub const R8W: Amd64Register = Amd64Register::Partial(Gpr::R8, GprWidth::Word);
+pub const R9W: Amd64Register = Amd64Register::Partial(Gpr::R9, GprWidth::Word);
+pub const R10W: Amd64Register = Amd64Register::Partial(Gpr::R10, GprWidth::Word);
+pub const R11W: Amd64Register = Amd64Register::Partial(Gpr::R11, GprWidth::Word);
+pub const R12W: Amd64Register = Amd64Register::Partial(Gpr::R12, GprWidth::Word);
+pub const R13W: Amd64Register = Amd64Register::Partial(Gpr::R13, GprWidth::Word);
+pub const R14W: Amd64Register = Amd64Register::Partial(Gpr::R14, GprWidth::Word);
+pub const R15W: Amd64Register = Amd64Register::Partial(Gpr::R15, GprWidth::Word);
+pub const AL: Amd64Register = Amd64Register::Partial(Gpr::RAX, GprWidth::Byte);
+pub const CL: Amd64Register = Amd64Register::Partial(Gpr::RCX, GprWidth::Byte);
+pub const DL: Amd64Register = Amd64Register::Partial(Gpr::RDX, GprWidth::Byte);
+pub const BL: Amd64Register = Amd64Register::Partial(Gpr::RBX, GprWidth::Byte);
+pub const SPL: Amd64Register = Amd64Register::Partial(Gpr::RSP, GprWidth::Byte);
+pub const BPL: Amd64Register = Amd64Register::Partial(Gpr::RBP, GprWidth::Byte);
+pub const SIL: Amd64Register = Amd64Register::Partial(Gpr::RSI, GprWidth::Byte);
+pub const DIL: Amd64Register = Amd64Register::Partial(Gpr::RDI, GprWidth::Byte);
+pub const R8B: Amd64Register = Amd64Register::Partial(Gpr::R8, GprWidth::Byte);
+pub const R9B: Amd64Register = Amd64Register::Partial(Gpr::R9, GprWidth::Byte);
+pub const R10B: Amd64Register = Amd64Register::Partial(Gpr::R10, GprWidth::Byte);
+pub const R11B: Amd64Register = Amd64Register::Partial(Gpr::R11, GprWidth::Byte);
+pub const R12B: Amd64Register = Amd64Register::Partial(Gpr::R12, GprWidth::Byte);
+pub const R13B: Amd64Register = Amd64Register::Partial(Gpr::R13, GprWidth::Byte);
+pub const R14B: Amd64Register = Amd64Register::Partial(Gpr::R14, GprWidth::Byte);
+pub const R15B: Amd64Register = Amd64Register::Partial(Gpr::R15, GprWidth::Byte);
+pub const TMM0: Amd64Register = Amd64Register::Tile(Tmm::TMM0);
+pub const TMM1: Amd64Register = Amd64Register::Tile(Tmm::TMM1);
+pub const TMM2: Amd64Register = Amd64Register::Tile(Tmm::TMM2);
+pub const TMM3: Amd64Register = Amd64Register::Tile(Tmm::TMM3);
+pub const TMM4: Amd64Register = Amd64Register::Tile(Tmm::TMM4);
+pub const TMM5: Amd64Register = Amd64Register::Tile(Tmm::TMM5);
+pub const TMM6: Amd64Register = Amd64Register::Tile(Tmm::TMM6);
+pub const TMM7: Amd64Register = Amd64Register::Tile(Tmm::TMM7);
+pub const XMM0: Amd64Register = Amd64Register::Vector(Xmm::XMM0);
+pub const XMM1: Amd64Register = Amd64Register::Vector(Xmm::XMM1);
+pub const XMM2: Amd64Register = Amd64Register::Vector(Xmm::XMM2);
+pub const XMM3: Amd64Register = Amd64Register::Vector(Xmm::XMM3);
+pub const XMM4: Amd64Register = Amd64Register::Vector(Xmm::XMM4);
+pub const XMM5: Amd64Register = Amd64Register::Vector(Xmm::XMM5);
+pub const XMM6: Amd64Register = Amd64Register::Vector(Xmm::XMM6);
+pub const XMM7: Amd64Register = Amd64Register::Vector(Xmm::XMM7);
+pub const XMM8: Amd64Register = Amd64Register::Vector(Xmm::XMM8);
+pub const XMM9: Amd64Register = Amd64Register::Vector(Xmm::XMM9);
+pub const XMM10: Amd64Register = Amd64Register::Vector(Xmm::XMM10);
+pub const XMM11: Amd64Register = Amd64Register::Vector(Xmm::XMM11);
+pub const XMM12: Amd64Register = Amd64Register::Vector(Xmm::XMM12);
+pub const XMM13: Amd64Register = Amd64Register::Vector(Xmm::XMM13);
+pub const XMM14: Amd64Register = Amd64Register::Vector(Xmm::XMM14);
+pub const XMM15: Amd64Register = Amd64Register::Vector(Xmm::XMM15);
+pub const YMM0: Amd64Register = Amd64Register::Ymm(Ymm::YMM0);
+pub const YMM1: Amd64Register = Amd64Register::Ymm(Ymm::YMM1);
+pub const YMM2: Amd64Register = Amd64Register::Ymm(Ymm::YMM2);
+pub const YMM3: Amd64Register = Amd64Register::Ymm(Ymm::YMM3);
+pub const YMM4: Amd64Register = Amd64Register::Ymm(Ymm::YMM4);
+pub const YMM5: Amd64Register = Amd64Register::Ymm(Ymm::YMM5);
+pub const YMM6: Amd64Register = Amd64Register::Ymm(Ymm::YMM6);
+pub const YMM7: Amd64Register = Amd64Register::Ymm(Ymm::YMM7);
+pub const YMM8: Amd64Register = Amd64Register::Ymm(Ymm::YMM8);
+pub const YMM9: Amd64Register = Amd64Register::Ymm(Ymm::YMM9);
+pub const YMM10: Amd64Register = Amd64Register::Ymm(Ymm::YMM10);
+pub const YMM11: Amd64Register = Amd64Register::Ymm(Ymm::YMM11);
+pub const YMM12: Amd64Register = Amd64Register::Ymm(Ymm::YMM12);
+pub const YMM13: Amd64Register = Amd64Register::Ymm(Ymm::YMM13);
+pub const YMM14: Amd64Register = Amd64Register::Ymm(Ymm::YMM14);
+pub const YMM15: Amd64Register = Amd64Register::Ymm(Ymm::YMM15);
+pub const ZMM0: Amd64Register = Amd64Register::Zmm(Zmm::ZMM0);
+pub const ZMM1: Amd64Register = Amd64Register::Zmm(Zmm::ZMM1);
+pub const ZMM2: Amd64Register = Amd64Register::Zmm(Zmm::ZMM2);
+pub const ZMM3: Amd64Register = Amd64Register::Zmm(Zmm::ZMM3);
+pub const ZMM4: Amd64Register = Amd64Register::Zmm(Zmm::ZMM4);
+pub const ZMM5: Amd64Register = Amd64Register::Zmm(Zmm::ZMM5);
+pub const ZMM6: Amd64Register = Amd64Register::Zmm(Zmm::ZMM6);
+pub const ZMM7: Amd64Register = Amd64Register::Zmm(Zmm::ZMM7);
+pub const ZMM8: Amd64Register = Amd64Register::Zmm(Zmm::ZMM8);
+pub const ZMM9: Amd64Register = Amd64Register::Zmm(Zmm::ZMM9);
+pub const ZMM10: Amd64Register = Amd64Register::Zmm(Zmm::ZMM10);
+pub const ZMM11: Amd64Register = Amd64Register::Zmm(Zmm::ZMM11);
+pub const ZMM12: Amd64Register = Amd64Register::Zmm(Zmm::ZMM12);
+pub const ZMM13: Amd64Register = Amd64Register::Zmm(Zmm::ZMM13);
+pub const ZMM14: Amd64Register = Amd64Register::Zmm(Zmm::ZMM14);
+pub const ZMM15: Amd64Register = Amd64Register::Zmm(Zmm::ZMM15);
+pub const ZMM16: Amd64Register = Amd64Register::Zmm(Zmm::ZMM16);
+pub const ZMM17: Amd64Register = Amd64Register::Zmm(Zmm::ZMM17);
+pub const ZMM18: Amd64Register = Amd64Register::Zmm(Zmm::ZMM18);
+pub const ZMM19: Amd64Register = Amd64Register::Zmm(Zmm::ZMM19);
+pub const ZMM20: Amd64Register = Amd64Register::Zmm(Zmm::ZMM20);
+pub const ZMM21: Amd64Register = Amd64Register::Zmm(Zmm::ZMM21);
+pub const ZMM22: Amd64Register = Amd64Register::Zmm(Zmm::ZMM22);
+pub const ZMM23: Amd64Register = Amd64Register::Zmm(Zmm::ZMM23);
+pub const ZMM24: Amd64Register = Amd64Register::Zmm(Zmm::ZMM24);
+pub const ZMM25: Amd64Register = Amd64Register::Zmm(Zmm::ZMM25);
+pub const ZMM26: Amd64Register = Amd64Register::Zmm(Zmm::ZMM26);
+pub const ZMM27: Amd64Register = Amd64Register::Zmm(Zmm::ZMM27);
+pub const ZMM28: Amd64Register = Amd64Register::Zmm(Zmm::ZMM28);
+pub const ZMM29: Amd64Register = Amd64Register::Zmm(Zmm::ZMM29);
+pub const ZMM30: Amd64Register = Amd64Register::Zmm(Zmm::ZMM30);
+pub const ZMM31: Amd64Register = Amd64Register::Zmm(Zmm::ZMM31);
+pub const K0: Amd64Register = Amd64Register::Mask(Opmask::K0);
+pub const K1: Amd64Register = Amd64Register::Mask(Opmask::K1);
+pub const K2: Amd64Register = Amd64Register::Mask(Opmask::K2);
+pub const K3: Amd64Register = Amd64Register::Mask(Opmask::K3);
+pub const K4: Amd64Register = Amd64Register::Mask(Opmask::K4);
+pub const K5: Amd64Register = Amd64Register::Mask(Opmask::K5);
+pub const K6: Amd64Register = Amd64Register::Mask(Opmask::K6);
+pub const K7: Amd64Register = Amd64Register::Mask(Opmask::K7);
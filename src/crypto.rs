@@ -0,0 +1,70 @@
+//! Typed constructors for the hardware crypto and checksum extensions:
+//! AES-NI, SHA, CRC32 and carry-less multiplication.
+//!
+//! Sources that may come from memory accept `impl Into<Operand>`;
+//! destinations and the remaining sources are xmm registers.
+
+use crate::{
+    mnemonic::Amd64Mnemonic, register::Xmm, Amd64Instruction, Amd64Register, AsmExpr, Operand,
+};
+
+fn inst(mnemonic: &str, operands: Vec<Operand>) -> AsmExpr {
+    AsmExpr::Instruction(Amd64Instruction::new(
+        Amd64Mnemonic::parse(mnemonic),
+        operands,
+    ))
+}
+
+fn xmm(reg: Xmm) -> Operand {
+    Operand::Register(Amd64Register::Vector(reg))
+}
+
+macro_rules! vector {
+    ($($name:ident => $mnemonic:literal),* $(,)?) => {
+        $(
+            pub fn $name(dst: Xmm, src: impl Into<Operand>) -> AsmExpr {
+                inst($mnemonic, vec![xmm(dst), src.into()])
+            }
+        )*
+    };
+}
+
+macro_rules! vector_imm {
+    ($($name:ident => $mnemonic:literal),* $(,)?) => {
+        $(
+            pub fn $name(dst: Xmm, src: impl Into<Operand>, imm: u8) -> AsmExpr {
+                inst($mnemonic, vec![xmm(dst), src.into(), Operand::from(imm as u32)])
+            }
+        )*
+    };
+}
+
+vector! {
+    aesenc => "aesenc",
+    aesenclast => "aesenclast",
+    aesdec => "aesdec",
+    aesdeclast => "aesdeclast",
+    aesimc => "aesimc",
+    sha1nexte => "sha1nexte",
+    sha1msg1 => "sha1msg1",
+    sha1msg2 => "sha1msg2",
+    sha256msg1 => "sha256msg1",
+    sha256msg2 => "sha256msg2",
+}
+
+vector_imm! {
+    aeskeygenassist => "aeskeygenassist",
+    sha1rnds4 => "sha1rnds4",
+    pclmulqdq => "pclmulqdq",
+}
+
+/// Two SHA-256 rounds; the round constants plus message words are read
+/// implicitly from xmm0.
+pub fn sha256rnds2(dst: Xmm, src: impl Into<Operand>) -> AsmExpr {
+    inst("sha256rnds2", vec![xmm(dst), src.into(), xmm(Xmm::XMM0)])
+}
+
+/// Accumulates the CRC-32C of `src` into `crc`.
+pub fn crc32(crc: Amd64Register, src: impl Into<Operand>) -> AsmExpr {
+    inst("crc32", vec![Operand::Register(crc), src.into()])
+}
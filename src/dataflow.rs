@@ -0,0 +1,491 @@
+//! Register and flag effects of instructions, and liveness over a
+//! function's control-flow graph, shared by the analyses built on
+//! [`cfg`](crate::cfg).
+//!
+//! Effects are approximate in the safe direction: an instruction this
+//! module does not know is taken to read every register it names and the
+//! flags, and to write nothing.
+
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    cfg::Function, expr::ConstExpr, register::Gpr, Amd64Instruction, Amd64Register, AsmExpr,
+    ImmediateValue, Operand,
+};
+
+/// A set of general-purpose registers.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct RegSet(u32);
+
+impl RegSet {
+    pub const EMPTY: RegSet = RegSet(0);
+    pub const ALL: RegSet = RegSet(u32::MAX);
+
+    pub fn of(regs: &[Gpr]) -> Self {
+        let mut set = RegSet::EMPTY;
+        for &reg in regs {
+            set.insert(reg);
+        }
+        set
+    }
+
+    pub fn insert(&mut self, reg: Gpr) {
+        self.0 |= 1 << reg.index();
+    }
+
+    pub fn remove(&mut self, reg: Gpr) {
+        self.0 &= !(1 << reg.index());
+    }
+
+    pub fn contains(self, reg: Gpr) -> bool {
+        self.0 & (1 << reg.index()) != 0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn union(self, other: RegSet) -> RegSet {
+        RegSet(self.0 | other.0)
+    }
+
+    pub fn intersection(self, other: RegSet) -> RegSet {
+        RegSet(self.0 & other.0)
+    }
+
+    pub fn difference(self, other: RegSet) -> RegSet {
+        RegSet(self.0 & !other.0)
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = Gpr> {
+        (0..32)
+            .filter(move |i| self.0 & (1 << i) != 0)
+            .map(|i| Gpr::extended(i).unwrap())
+    }
+}
+
+impl fmt::Debug for RegSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.iter().map(Gpr::name)).finish()
+    }
+}
+
+/// Argument registers of the System V AMD64 calling convention, in order.
+pub const SYSV_ARGUMENTS: [Gpr; 6] = [Gpr::RDI, Gpr::RSI, Gpr::RDX, Gpr::RCX, Gpr::R8, Gpr::R9];
+
+/// Registers a System V function must preserve, besides rsp.
+pub const SYSV_CALLEE_SAVED: [Gpr; 6] =
+    [Gpr::RBX, Gpr::RBP, Gpr::R12, Gpr::R13, Gpr::R14, Gpr::R15];
+
+/// Registers a System V call may overwrite.
+pub const SYSV_CALLER_SAVED: [Gpr; 9] = [
+    Gpr::RAX,
+    Gpr::RCX,
+    Gpr::RDX,
+    Gpr::RSI,
+    Gpr::RDI,
+    Gpr::R8,
+    Gpr::R9,
+    Gpr::R10,
+    Gpr::R11,
+];
+
+/// Registers the Linux `syscall` instruction reads (number and arguments).
+pub const SYSCALL_ARGUMENTS: [Gpr; 7] = [
+    Gpr::RAX,
+    Gpr::RDI,
+    Gpr::RSI,
+    Gpr::RDX,
+    Gpr::R10,
+    Gpr::R8,
+    Gpr::R9,
+];
+
+/// Registers `syscall` overwrites: rax with the result, rcx and r11 with
+/// the return address and saved flags.
+pub const SYSCALL_CLOBBERS: [Gpr; 3] = [Gpr::RAX, Gpr::RCX, Gpr::R11];
+
+/// What one instruction reads and writes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Effects {
+    pub uses: RegSet,
+    pub defs: RegSet,
+    pub reads_flags: bool,
+    pub writes_flags: bool,
+    /// Whether the mnemonic is one this module models; unknown ones get
+    /// the conservative effects described in the module docs.
+    pub known: bool,
+}
+
+/// The register an operand names directly, in full or in part.
+fn register(operand: &Operand) -> Option<Gpr> {
+    match operand {
+        Operand::Register(reg) => reg.containing_gpr(),
+        _ => None,
+    }
+}
+
+/// Registers named in the operands of `inst`, as opposed to those it reads
+/// or writes implicitly.
+pub fn operand_registers(inst: &Amd64Instruction) -> RegSet {
+    let mut set = RegSet::EMPTY;
+    for op in &inst.operands {
+        set.extend(register(op));
+        set = set.union(address(op));
+    }
+    set
+}
+
+/// Registers an operand reads to form an address.
+fn address(operand: &Operand) -> RegSet {
+    let mut set = RegSet::EMPTY;
+    if let Operand::Memory(mem) = operand {
+        for reg in mem.registers() {
+            set.extend(reg.containing_gpr());
+        }
+    }
+    set
+}
+
+impl Extend<Gpr> for RegSet {
+    fn extend<I: IntoIterator<Item = Gpr>>(&mut self, regs: I) {
+        for reg in regs {
+            self.insert(reg);
+        }
+    }
+}
+
+/// Condition-code suffixes of `jcc`, `setcc` and `cmovcc`, with aliases.
+const CONDITIONS: &[&str] = &[
+    "o", "no", "b", "c", "nae", "ae", "nb", "nc", "e", "z", "ne", "nz", "be", "na", "a", "nbe",
+    "s", "ns", "p", "pe", "np", "po", "l", "nge", "ge", "nl", "le", "ng", "g", "nle",
+];
+
+fn is_condition(suffix: &str) -> bool {
+    CONDITIONS.contains(&suffix)
+}
+
+/// Splits a `rep`-family prefix off a mnemonic such as `rep movsb`.
+pub(crate) fn split_prefix(mnemonic: &str) -> (Option<&str>, &str) {
+    match mnemonic.split_once(' ') {
+        Some((prefix, rest)) if matches!(prefix, "rep" | "repe" | "repz" | "repne" | "repnz") => {
+            (Some(prefix), rest.trim_start())
+        }
+        _ => (None, mnemonic),
+    }
+}
+
+/// The string instruction family of an operandless mnemonic such as
+/// `stosb`, without its size suffix.
+fn string_op(mnemonic: &str) -> Option<&str> {
+    let (family, size) = mnemonic.split_at_checked(4)?;
+    let families = ["movs", "stos", "lods", "scas", "cmps"];
+    (families.contains(&family) && matches!(size, "b" | "w" | "d" | "q")).then_some(family)
+}
+
+/// Registers an instruction reads or writes without naming them as
+/// operands, from the architecture alone rather than any convention.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Implicit {
+    pub uses: RegSet,
+    pub defs: RegSet,
+}
+
+/// The implicit register operands of `inst`: `mul` reading rax and
+/// writing rdx:rax, `syscall` overwriting rcx and r11, `rep` counting
+/// down rcx, the stack pointer of `push` and `call`, and so on.
+pub fn implicit(inst: &Amd64Instruction) -> Implicit {
+    let (prefix, mnemonic) = split_prefix(inst.mnemonic.as_str());
+    let both = |regs: &[Gpr]| Implicit {
+        uses: RegSet::of(regs),
+        defs: RegSet::of(regs),
+    };
+    let mut implicit = match mnemonic {
+        "mul" | "imul" if inst.operands.len() == 1 => Implicit {
+            uses: RegSet::of(&[Gpr::RAX]),
+            defs: RegSet::of(&[Gpr::RAX, Gpr::RDX]),
+        },
+        "div" | "idiv" => both(&[Gpr::RAX, Gpr::RDX]),
+        "cqo" => Implicit {
+            uses: RegSet::of(&[Gpr::RAX]),
+            defs: RegSet::of(&[Gpr::RDX]),
+        },
+        "push" | "pop" | "call" | "ret" => both(&[Gpr::RSP]),
+        "leave" => Implicit {
+            uses: RegSet::of(&[Gpr::RBP]),
+            defs: RegSet::of(&[Gpr::RSP, Gpr::RBP]),
+        },
+        "syscall" => Implicit {
+            uses: RegSet::EMPTY,
+            defs: RegSet::of(&[Gpr::RCX, Gpr::R11]),
+        },
+        "cpuid" => Implicit {
+            uses: RegSet::of(&[Gpr::RAX, Gpr::RCX]),
+            defs: RegSet::of(&[Gpr::RAX, Gpr::RBX, Gpr::RCX, Gpr::RDX]),
+        },
+        "rdtsc" => Implicit {
+            uses: RegSet::EMPTY,
+            defs: RegSet::of(&[Gpr::RAX, Gpr::RDX]),
+        },
+        "rdtscp" => Implicit {
+            uses: RegSet::EMPTY,
+            defs: RegSet::of(&[Gpr::RAX, Gpr::RCX, Gpr::RDX]),
+        },
+        "loop" | "loope" | "loopz" | "loopne" | "loopnz" => both(&[Gpr::RCX]),
+        "jrcxz" => Implicit {
+            uses: RegSet::of(&[Gpr::RCX]),
+            defs: RegSet::EMPTY,
+        },
+        m if inst.operands.is_empty() => match string_op(m) {
+            Some("movs" | "cmps") => both(&[Gpr::RSI, Gpr::RDI]),
+            Some("stos" | "scas") => Implicit {
+                uses: RegSet::of(&[Gpr::RAX, Gpr::RDI]),
+                defs: RegSet::of(&[Gpr::RDI]),
+            },
+            Some("lods") => Implicit {
+                uses: RegSet::of(&[Gpr::RSI]),
+                defs: RegSet::of(&[Gpr::RAX, Gpr::RSI]),
+            },
+            _ => Implicit::default(),
+        },
+        _ => Implicit::default(),
+    };
+    if prefix.is_some() && string_op(mnemonic).is_some() {
+        implicit.uses.insert(Gpr::RCX);
+        implicit.defs.insert(Gpr::RCX);
+    }
+    implicit
+}
+
+/// The register and flag effects of `inst`, its [`implicit`] operands
+/// included, taking calls to follow the System V convention and `syscall`
+/// the Linux one.
+pub fn effects(inst: &Amd64Instruction) -> Effects {
+    let ops = &inst.operands;
+    let mut fx = Effects {
+        known: true,
+        ..Effects::default()
+    };
+    for op in ops {
+        fx.uses = fx.uses.union(address(op));
+    }
+    let dst = ops.first().and_then(register);
+    let sources = || ops.iter().skip(1).filter_map(register);
+    let all = || ops.iter().filter_map(register);
+
+    let (_, mnemonic) = split_prefix(inst.mnemonic.as_str());
+    match mnemonic {
+        "mov" | "movabs" | "movzx" | "movsx" | "movsxd" | "lea" | "rdfsbase" | "rdgsbase"
+        | "pdep" | "pext" => {
+            fx.uses.extend(sources());
+            fx.defs.extend(dst);
+        }
+        "lzcnt" | "tzcnt" | "popcnt" | "andn" | "bextr" => {
+            fx.uses.extend(sources());
+            fx.defs.extend(dst);
+            fx.writes_flags = true;
+        }
+        // Zeroing idioms depend on nothing.
+        "xor" | "sub" if dst.is_some() && ops.len() == 2 && register(&ops[1]) == dst => {
+            fx.defs.extend(dst);
+            fx.writes_flags = true;
+        }
+        "add" | "sub" | "and" | "or" | "xor" | "shl" | "shr" | "sar" | "rol" | "ror" | "inc"
+        | "dec" | "neg" | "adc" | "sbb" | "rcl" | "rcr" => {
+            fx.uses.extend(all());
+            fx.defs.extend(dst);
+            fx.writes_flags = true;
+            fx.reads_flags = matches!(mnemonic, "adc" | "sbb" | "rcl" | "rcr");
+        }
+        "not" => {
+            fx.uses.extend(all());
+            fx.defs.extend(dst);
+        }
+        "cmp" | "test" | "bt" => {
+            fx.uses.extend(all());
+            fx.writes_flags = true;
+        }
+        "xchg" | "xadd" => {
+            fx.uses.extend(all());
+            fx.defs.extend(all());
+            fx.writes_flags = mnemonic == "xadd";
+        }
+        "imul" if ops.len() == 3 => {
+            fx.uses.extend(sources());
+            fx.defs.extend(dst);
+            fx.writes_flags = true;
+        }
+        "imul" if ops.len() == 2 => {
+            fx.uses.extend(all());
+            fx.defs.extend(dst);
+            fx.writes_flags = true;
+        }
+        "mul" | "imul" | "div" | "idiv" => {
+            fx.uses.extend(all());
+            fx.writes_flags = true;
+        }
+        "push" => fx.uses.extend(all()),
+        // Memory-only destinations: every register named is read.
+        "prefetcht0" | "prefetcht1" | "prefetcht2" | "prefetchnta" | "movnti" | "movntdq"
+        | "movntps" | "movntpd" | "clflush" | "clflushopt" | "clwb" | "invlpg" => {
+            fx.uses.extend(all())
+        }
+        "pop" => fx.defs.extend(dst),
+        "call" => {
+            fx.uses.extend(all());
+            fx.uses = fx.uses.union(RegSet::of(&SYSV_ARGUMENTS));
+            fx.defs = RegSet::of(&SYSV_CALLER_SAVED);
+            fx.writes_flags = true;
+        }
+        "ret" => {
+            fx.uses = RegSet::of(&SYSV_CALLEE_SAVED);
+            fx.uses.extend([Gpr::RAX, Gpr::RDX]);
+        }
+        "syscall" => {
+            fx.uses = RegSet::of(&SYSCALL_ARGUMENTS);
+            fx.defs = RegSet::of(&SYSCALL_CLOBBERS);
+        }
+        "jmp" => fx.uses.extend(all()),
+        "loop" | "loope" | "loopz" | "loopne" | "loopnz" => {
+            fx.reads_flags = mnemonic != "loop";
+        }
+        "jrcxz" => {}
+        m if ops.is_empty() && string_op(m).is_some() => {
+            let compares = matches!(string_op(m), Some("scas" | "cmps"));
+            fx.writes_flags = compares;
+            // The direction flag, and the zero flag for repe and repne.
+            fx.reads_flags = true;
+        }
+        "cqo" | "leave" | "cpuid" | "rdtsc" | "rdtscp" | "nop" | "lfence" | "mfence" | "sfence"
+        | "pause" | "hlt" | "ud2" | "vzeroupper" | "wbinvd" => {}
+        m if m.starts_with('j') && is_condition(&m[1..]) => fx.reads_flags = true,
+        m if m.starts_with("set") && is_condition(&m[3..]) => {
+            fx.defs.extend(dst);
+            fx.reads_flags = true;
+        }
+        m if m.starts_with("cmov") && is_condition(&m[4..]) => {
+            fx.uses.extend(all());
+            fx.defs.extend(dst);
+            fx.reads_flags = true;
+        }
+        _ => {
+            fx.uses.extend(all());
+            fx.reads_flags = true;
+            fx.known = false;
+        }
+    }
+
+    // Writing the low byte or word keeps the rest of the register.
+    if let Some(Operand::Register(Amd64Register::Partial(gpr, width))) = ops.first() {
+        if width.merges() && fx.defs.contains(*gpr) {
+            fx.uses.extend([*gpr]);
+        }
+    }
+
+    let implicit = implicit(inst);
+    fx.uses = fx.uses.union(implicit.uses);
+    fx.defs = fx.defs.union(implicit.defs);
+    fx
+}
+
+/// The value of an immediate operand, if it is a number or an expression
+/// over `defines`.
+pub fn constant(operand: &Operand, defines: &HashMap<String, ConstExpr>) -> Option<i64> {
+    match operand {
+        Operand::Immediate(imm) => match imm {
+            ImmediateValue::I64(v) => Some(*v),
+            ImmediateValue::U64(v) => Some(*v as i64),
+            ImmediateValue::USize(v) => Some(*v as i64),
+            ImmediateValue::F64(v) => Some(v.to_bits() as i64),
+            ImmediateValue::Expr(expr) => expr.eval(defines).ok(),
+            ImmediateValue::Label(_) | ImmediateValue::Bytes(_) => None,
+        },
+        _ => None,
+    }
+}
+
+/// Registers and flags live on entry to and exit from each block of a
+/// function.
+///
+/// Control leaving the function other than by returning (see
+/// [`BasicBlock::exits`](crate::cfg::BasicBlock::exits)) keeps everything
+/// live, since nothing is known about where it goes.
+#[derive(Clone, Debug)]
+pub struct Liveness {
+    pub live_in: Vec<RegSet>,
+    pub live_out: Vec<RegSet>,
+    pub flags_in: Vec<bool>,
+    pub flags_out: Vec<bool>,
+}
+
+impl Liveness {
+    pub fn compute(function: &Function) -> Self {
+        let n = function.blocks.len();
+        let mut live = Liveness {
+            live_in: vec![RegSet::EMPTY; n],
+            live_out: vec![RegSet::EMPTY; n],
+            flags_in: vec![false; n],
+            flags_out: vec![false; n],
+        };
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (i, block) in function.blocks.iter().enumerate().rev() {
+                let (mut regs, mut flags) = if block.exits {
+                    (RegSet::ALL, true)
+                } else {
+                    (RegSet::EMPTY, false)
+                };
+                for &(to, _) in &block.successors {
+                    regs = regs.union(live.live_in[to]);
+                    flags |= live.flags_in[to];
+                }
+                live.live_out[i] = regs;
+                live.flags_out[i] = flags;
+
+                for inst in block.instructions.iter().rev() {
+                    (regs, flags) = step_back(inst, regs, flags);
+                }
+                if regs != live.live_in[i] || flags != live.flags_in[i] {
+                    live.live_in[i] = regs;
+                    live.flags_in[i] = flags;
+                    changed = true;
+                }
+            }
+        }
+
+        live
+    }
+}
+
+/// Registers and flags that may be live before straight-line `code` runs
+/// to its end. Control leaving the line, at a jump, a label or the end of
+/// `code`, keeps everything live, and `ret` only what the System V
+/// convention returns and preserves.
+pub fn straight_line_liveness(code: &[AsmExpr]) -> (RegSet, bool) {
+    live_before(code, RegSet::ALL, true)
+}
+
+fn live_before(code: &[AsmExpr], mut regs: RegSet, mut flags: bool) -> (RegSet, bool) {
+    for expr in code.iter().rev() {
+        (regs, flags) = match expr {
+            AsmExpr::Instruction(inst) => match split_prefix(inst.mnemonic.as_str()).1 {
+                "ret" => step_back(inst, RegSet::EMPTY, false),
+                m if m.starts_with('j') || m.starts_with("loop") => (RegSet::ALL, true),
+                _ => step_back(inst, regs, flags),
+            },
+            AsmExpr::Block(body) => live_before(body, regs, flags),
+            _ => (RegSet::ALL, true),
+        };
+    }
+    (regs, flags)
+}
+
+/// Liveness before `inst` given liveness after it.
+pub fn step_back(inst: &Amd64Instruction, regs: RegSet, flags: bool) -> (RegSet, bool) {
+    let fx = effects(inst);
+    let regs = regs.difference(fx.defs).union(fx.uses);
+    let flags = fx.reads_flags || (flags && !fx.writes_flags);
+    (regs, flags)
+}
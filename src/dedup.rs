@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+
+use crate::{program::Program, symbol_words, AsmExpr, Endian};
+
+/// Merges byte-identical data items within each read-only data section and
+/// rewrites every reference to a removed item so it points at the surviving
+/// label.
+///
+/// A data item is a label immediately followed by one or more `Data`
+/// expressions in the same block. Items whose label is exported, or whose
+/// label appears in a `Raw` line (where references cannot be rewritten),
+/// are left alone, as are items inside conditionals (run
+/// [`Program::resolve_conditions`] first to include them). Only read-only
+/// sections, `rodata` and those named `rodata.` and a suffix, are touched:
+/// in any other a store through one label would show through the other.
+/// Items are only merged with other items of the same section.
+///
+/// Returns the number of items removed.
+///
+/// ```
+/// use cataclysm::{dedup::dedup_data, AsmExpr, Data, Label, Program, Section};
+///
+/// let items = |a: &str, b: &str| {
+///     vec![
+///         AsmExpr::Label(Label::plain(a)),
+///         AsmExpr::Data(Data::UInt(7)),
+///         AsmExpr::Label(Label::plain(b)),
+///         AsmExpr::Data(Data::UInt(7)),
+///     ]
+/// };
+/// let mut program = Program::default()
+///     .with_section(Section::new("rodata", items("seven", "also_seven")))
+///     .with_section(Section::new("data", items("counter", "other_counter")));
+/// assert_eq!(dedup_data(&mut program), 1);
+/// assert_eq!(program.sections[0].body.len(), 2);
+/// assert_eq!(program.sections[1].body.len(), 4);
+/// ```
+pub fn dedup_data(program: &mut Program) -> usize {
+    let mut pinned: Vec<String> = program.globals.iter().map(|g| g.value.clone()).collect();
+    for section in &program.sections {
+        collect_raw(&section.body, &mut pinned);
+    }
+
+    let mut renames: HashMap<String, String> = HashMap::new();
+
+    for section in program.sections.iter_mut() {
+        if !is_read_only(&section.name) {
+            continue;
+        }
+
+        let mut seen: HashMap<ItemKey, String> = HashMap::new();
+        let endian = section.endian;
+        dedup_block(section.body_mut(), endian, &pinned, &mut seen, &mut renames);
+    }
+
+    if !renames.is_empty() {
+        let rename = |name: &str| renames.get(name).cloned();
+        for section in program.sections.iter_mut() {
+            AsmExpr::rename_labels(section.body_mut(), &rename);
+        }
+    }
+
+    renames.len()
+}
+
+fn is_read_only(name: &str) -> bool {
+    name == "rodata" || name.starts_with("rodata.")
+}
+
+fn collect_raw(body: &[AsmExpr], out: &mut Vec<String>) {
+    for expr in body {
+        match expr {
+            AsmExpr::Raw(text) => out.push(text.clone()),
+            _ => {
+                for inner in expr.bodies() {
+                    collect_raw(inner, out);
+                }
+            }
+        }
+    }
+}
+
+fn is_pinned(label: &str, pinned: &[String]) -> bool {
+    pinned
+        .iter()
+        .any(|text| symbol_words(text).any(|word| word == label))
+}
+
+/// Assembled bytes of an item, plus the labels whose addresses it holds
+/// (which only have placeholder bytes until link time).
+type ItemKey = (Vec<u8>, Vec<String>);
+
+fn dedup_block(
+    body: &mut Vec<AsmExpr>,
+    endian: Endian,
+    pinned: &[String],
+    seen: &mut HashMap<ItemKey, String>,
+    renames: &mut HashMap<String, String>,
+) {
+    let mut i = 0;
+    while i < body.len() {
+        if let AsmExpr::Block(inner)
+        | AsmExpr::Region { body: inner, .. }
+        | AsmExpr::Macro { body: inner, .. } = &mut body[i]
+        {
+            dedup_block(inner, endian, pinned, seen, renames);
+            i += 1;
+            continue;
+        }
+
+        let AsmExpr::Label(label) = &body[i] else {
+            i += 1;
+            continue;
+        };
+        let name = label.label.clone();
+
+        let mut end = i + 1;
+        let mut bytes = Vec::new();
+        let mut addresses = Vec::new();
+        // What a positional item holds depends on where it lands, so runs
+        // stop short of one.
+        while let Some(AsmExpr::Data(data)) = body.get(end) {
+            if data.is_positional() {
+                break;
+            }
+            bytes.extend(data.to_bytes_with(endian));
+            addresses.extend(data.labels().into_iter().map(|l| l.label.clone()));
+            end += 1;
+        }
+        let key = (bytes, addresses);
+
+        if end == i + 1 || is_pinned(&name, pinned) {
+            i = end;
+            continue;
+        }
+
+        match seen.get(&key) {
+            Some(survivor) => {
+                renames.insert(name, survivor.clone());
+                body.drain(i..end);
+            }
+            None => {
+                seen.insert(key, name);
+                i = end;
+            }
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Descriptions of instructions and operands for error messages and logs.
+//!
+//! `Display` renders NASM, which says what to assemble but not what the
+//! pieces are: `[rbx]` and `msg` do not say which operand is memory, or
+//! how wide a register is. The `Debug` impls here spell out the structure,
+//! and `to_diagnostic_string` reads as a sentence fragment:
+//!
+//! ```text
+//! mov with 2 operands: 32-bit register eax, memory [rbp - 8]
+//! ```
+
+use std::fmt;
+
+use crate::{Amd64Instruction, Amd64Register, ImmediateValue, Label, Mem, Operand};
+
+impl fmt::Debug for Label {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Label").field(&self.label).finish()
+    }
+}
+
+impl fmt::Debug for Amd64Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut s = f.debug_struct("Register");
+        s.field("name", &format_args!("{}", self));
+        if let Some(bits) = self.width() {
+            s.field("bits", &bits);
+        }
+        s.finish()
+    }
+}
+
+impl fmt::Debug for ImmediateValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImmediateValue::Label(label) => f.debug_tuple("Label").field(&label.label).finish(),
+            ImmediateValue::U64(n) => f.debug_tuple("U64").field(n).finish(),
+            ImmediateValue::USize(n) => f.debug_tuple("USize").field(n).finish(),
+            ImmediateValue::I64(n) => f.debug_tuple("I64").field(n).finish(),
+            ImmediateValue::F64(v) => f.debug_tuple("F64").field(v).finish(),
+            ImmediateValue::Bytes(bytes) => f.debug_tuple("Bytes").field(bytes).finish(),
+            ImmediateValue::Expr(expr) => f
+                .debug_tuple("Expr")
+                .field(&format_args!("{}", expr))
+                .finish(),
+        }
+    }
+}
+
+impl fmt::Debug for Mem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut s = f.debug_struct("Memory");
+        match (&self.base, &self.label) {
+            (Some(base), _) => s.field("base", base),
+            (None, Some(_)) => s.field("base", &format_args!("rip")),
+            (None, None) => &mut s,
+        };
+        if let Some(index) = &self.index {
+            s.field("index", index).field("scale", &self.scale);
+        }
+        if let Some(label) = &self.label {
+            s.field("label", &label.label);
+        }
+        s.field("displacement", &self.displacement);
+        if let Some(size) = self.size {
+            s.field("size", &format_args!("{}", size.keyword()));
+        }
+        s.finish()
+    }
+}
+
+impl fmt::Debug for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Register(reg) => reg.fmt(f),
+            Operand::Immediate(imm) => f.debug_tuple("Immediate").field(imm).finish(),
+            Operand::Memory(mem) => mem.fmt(f),
+            Operand::Param(name) => f.debug_tuple("Param").field(name).finish(),
+        }
+    }
+}
+
+impl fmt::Debug for Amd64Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut s = f.debug_struct("Instruction");
+        s.field("mnemonic", &self.mnemonic)
+            .field("operands", &self.operands);
+        if let Some(hint) = &self.hint {
+            s.field("hint", hint);
+        }
+        if let Some(fixup) = &self.fixup {
+            s.field("fixup", &fixup.label);
+        }
+        if !self.allow.is_empty() {
+            s.field("allow", &self.allow);
+        }
+        s.finish()
+    }
+}
+
+impl Amd64Register {
+    /// What kind of register this is, with its width, such as
+    /// `32-bit register eax`.
+    pub fn to_diagnostic_string(&self) -> String {
+        let kind = match self {
+            Amd64Register::Vector(_) | Amd64Register::Ymm(_) | Amd64Register::Zmm(_) => {
+                "vector register"
+            }
+            Amd64Register::Tile(_) => "tile register",
+            Amd64Register::Mask(_) => "opmask register",
+            Amd64Register::Virtual(..) => "virtual register",
+            _ => "register",
+        };
+        match self.width() {
+            Some(bits) => format!("{}-bit {} {}", bits, kind, self),
+            None => format!("{} {}", kind, self),
+        }
+    }
+}
+
+impl Operand {
+    /// What kind of operand this is: `register`, `immediate`, `memory` or
+    /// `parameter`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Operand::Register(_) => "register",
+            Operand::Immediate(_) => "immediate",
+            Operand::Memory(_) => "memory",
+            Operand::Param(_) => "parameter",
+        }
+    }
+
+    /// The operand's kind and, for registers, width, followed by how it is
+    /// written, such as `64-bit register rax` or `memory [rel msg]`.
+    pub fn to_diagnostic_string(&self) -> String {
+        match self {
+            Operand::Register(reg) => reg.to_diagnostic_string(),
+            Operand::Immediate(ImmediateValue::Label(label)) => {
+                format!("immediate address of {}", label.label)
+            }
+            Operand::Immediate(ImmediateValue::Expr(expr)) => {
+                format!("immediate expression {}", expr)
+            }
+            Operand::Immediate(ImmediateValue::Bytes(bytes)) => {
+                format!("immediate of {} bytes", bytes.len())
+            }
+            Operand::Param(name) => format!("parameter %{}", name),
+            _ => format!("{} {}", self.kind(), self),
+        }
+    }
+}
+
+impl Amd64Instruction {
+    /// The mnemonic and a description of each operand, plus any branch
+    /// hint and fault fixup, all on one line.
+    pub fn to_diagnostic_string(&self) -> String {
+        let mut out = match self.operands.len() {
+            0 => format!("{} with no operands", self.mnemonic),
+            1 => format!("{} with 1 operand: ", self.mnemonic),
+            n => format!("{} with {} operands: ", self.mnemonic, n),
+        };
+        let operands: Vec<String> = self
+            .operands
+            .iter()
+            .map(Operand::to_diagnostic_string)
+            .collect();
+        out.push_str(&operands.join(", "));
+        if let Some(hint) = &self.hint {
+            out.push_str(&format!("; hinted {:?}", hint).to_lowercase());
+        }
+        if let Some(fixup) = &self.fixup {
+            out.push_str(&format!("; resumes at {} on a fault", fixup.label));
+        }
+        out
+    }
+}
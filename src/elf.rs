@@ -0,0 +1,362 @@
+//! ELF64 relocatable objects, so a program can be handed straight to `ld`
+//! (or a C compiler driver) without going through NASM.
+//!
+//! Each program section becomes the ELF section of the same name with a
+//! dot in front, as in the emitted assembly. Labels become symbols, global
+//! if the program exports them and local otherwise, and referenced externs
+//! become undefined symbols. Values that depend on where a section is
+//! placed are written as `RELA` relocations against the section or extern;
+//! the rest are encoded exactly as [`encode`](crate::encode::encode) would.
+
+use std::collections::HashMap;
+
+use crate::{
+    encode::{self, EncodeError, Relocatable, RelocationTarget},
+    object::{
+        EM_X86_64, ET_REL, SHF_ALLOC, SHT_NOBITS, SHT_PROGBITS, SHT_RELA, SHT_SYMTAB, STB_LOCAL,
+        STT_SECTION,
+    },
+    program::Program,
+};
+
+const SHT_STRTAB: u32 = 3;
+const SHF_WRITE: u64 = 1;
+const SHF_EXECINSTR: u64 = 4;
+const SHF_INFO_LINK: u64 = 0x40;
+const STB_GLOBAL: u8 = 1;
+const SHN_UNDEF: u16 = 0;
+const HEADER_SIZE: usize = 64;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+const RELA_SIZE: usize = 24;
+
+/// `program` as the contents of a `.o` file.
+pub fn write(program: &Program) -> Result<Vec<u8>, Vec<EncodeError>> {
+    Ok(serialize(program, &encode::relocatable(program)?))
+}
+
+/// Section-header fields, in the order of an `Elf64_Shdr`.
+#[derive(Default)]
+struct Header {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+/// A NUL-separated string table; offset 0 is the empty string.
+struct Strings(Vec<u8>);
+
+impl Strings {
+    fn new() -> Self {
+        Strings(vec![0])
+    }
+
+    fn add(&mut self, s: &str) -> u32 {
+        let offset = self.0.len() as u32;
+        self.0.extend(s.as_bytes());
+        self.0.push(0);
+        offset
+    }
+}
+
+fn flags(section: &str) -> u64 {
+    if section.starts_with("text") {
+        SHF_ALLOC | SHF_EXECINSTR
+    } else if section.starts_with("data") || section.starts_with("bss") {
+        SHF_ALLOC | SHF_WRITE
+    } else {
+        SHF_ALLOC
+    }
+}
+
+fn serialize(program: &Program, relocatable: &Relocatable) -> Vec<u8> {
+    let sections = &relocatable.sections;
+    let exported = |name: &str| program.globals.iter().any(|g| g.value == name);
+
+    // Locals must come before globals. Section symbols follow the null
+    // symbol, so section `i` has symbol `i + 1` as well as header `i + 1`.
+    let mut strtab = Strings::new();
+    let mut symbols = vec![[0u8; SYMBOL_SIZE]];
+    for i in 0..sections.len() {
+        symbols.push(symbol(0, STB_LOCAL, STT_SECTION, i as u16 + 1, 0));
+    }
+    let mut first_global = 0;
+    for global in [false, true] {
+        if global {
+            first_global = symbols.len();
+        }
+        for (i, section) in sections.iter().enumerate() {
+            for (name, offset) in &section.labels {
+                if exported(name) == global {
+                    let binding = if global { STB_GLOBAL } else { STB_LOCAL };
+                    let name = strtab.add(name);
+                    symbols.push(symbol(name, binding, 0, i as u16 + 1, *offset));
+                }
+            }
+        }
+    }
+    let mut externs = HashMap::new();
+    for name in &relocatable.externs {
+        externs.insert(name.as_str(), symbols.len() as u64);
+        let name = strtab.add(name);
+        symbols.push(symbol(name, STB_GLOBAL, 0, SHN_UNDEF, 0));
+    }
+
+    let mut shstrtab = Strings::new();
+    let mut headers = vec![Header::default()];
+    let mut body = Vec::new();
+    let place = |body: &mut Vec<u8>, bytes: &[u8], align: u64| {
+        body.resize(body.len().next_multiple_of(align as usize), 0);
+        let offset = (HEADER_SIZE + body.len()) as u64;
+        body.extend(bytes);
+        offset
+    };
+
+    for section in sections {
+        let nobits = section.name.starts_with("bss")
+            && section.relocations.is_empty()
+            && section.bytes.iter().all(|&b| b == 0);
+        let offset = if nobits {
+            (HEADER_SIZE + body.len()) as u64
+        } else {
+            place(&mut body, &section.bytes, section.align)
+        };
+        headers.push(Header {
+            name: shstrtab.add(&format!(".{}", section.name)),
+            kind: if nobits { SHT_NOBITS } else { SHT_PROGBITS },
+            flags: flags(&section.name),
+            offset,
+            size: section.bytes.len() as u64,
+            align: section.align,
+            ..Header::default()
+        });
+    }
+
+    // Headers for the tables come after every program section, so their
+    // indices are known before the relocation sections refer to them.
+    let symtab_index = (headers.len()
+        + sections
+            .iter()
+            .filter(|s| !s.relocations.is_empty())
+            .count()) as u32;
+    for (i, section) in sections.iter().enumerate() {
+        if section.relocations.is_empty() {
+            continue;
+        }
+        let mut bytes = Vec::with_capacity(section.relocations.len() * RELA_SIZE);
+        for r in &section.relocations {
+            let symbol = match &r.target {
+                RelocationTarget::Section(s) => *s as u64 + 1,
+                RelocationTarget::Symbol(name) => externs[name.as_str()],
+            };
+            bytes.extend(r.offset.to_le_bytes());
+            bytes.extend((symbol << 32 | r.kind.to_elf() as u64).to_le_bytes());
+            bytes.extend(r.addend.to_le_bytes());
+        }
+        headers.push(Header {
+            name: shstrtab.add(&format!(".rela.{}", section.name)),
+            kind: SHT_RELA,
+            flags: SHF_INFO_LINK,
+            offset: place(&mut body, &bytes, 8),
+            size: bytes.len() as u64,
+            link: symtab_index,
+            info: i as u32 + 1,
+            align: 8,
+            entsize: RELA_SIZE as u64,
+        });
+    }
+
+    let symbol_bytes = symbols.concat();
+    headers.push(Header {
+        name: shstrtab.add(".symtab"),
+        kind: SHT_SYMTAB,
+        offset: place(&mut body, &symbol_bytes, 8),
+        size: symbol_bytes.len() as u64,
+        link: symtab_index + 1,
+        info: first_global as u32,
+        align: 8,
+        entsize: SYMBOL_SIZE as u64,
+        ..Header::default()
+    });
+    headers.push(Header {
+        name: shstrtab.add(".strtab"),
+        kind: SHT_STRTAB,
+        offset: place(&mut body, &strtab.0, 1),
+        size: strtab.0.len() as u64,
+        align: 1,
+        ..Header::default()
+    });
+    // Without this note, linkers assume the code needs an executable stack.
+    headers.push(Header {
+        name: shstrtab.add(".note.GNU-stack"),
+        kind: SHT_PROGBITS,
+        offset: (HEADER_SIZE + body.len()) as u64,
+        align: 1,
+        ..Header::default()
+    });
+    headers.push(Header {
+        name: shstrtab.add(".shstrtab"),
+        kind: SHT_STRTAB,
+        offset: place(&mut body, &shstrtab.0, 1),
+        size: shstrtab.0.len() as u64,
+        align: 1,
+        ..Header::default()
+    });
+    body.resize(body.len().next_multiple_of(8), 0);
+
+    let mut out =
+        Vec::with_capacity(HEADER_SIZE + body.len() + headers.len() * SECTION_HEADER_SIZE);
+    out.extend(b"\x7fELF");
+    // 64-bit, little-endian, version 1, System V ABI.
+    out.extend([2, 1, 1, 0]);
+    out.extend([0; 8]);
+    out.extend(ET_REL.to_le_bytes());
+    out.extend(EM_X86_64.to_le_bytes());
+    out.extend(1u32.to_le_bytes());
+    // No entry point or program headers.
+    out.extend(0u64.to_le_bytes());
+    out.extend(0u64.to_le_bytes());
+    out.extend(((HEADER_SIZE + body.len()) as u64).to_le_bytes());
+    out.extend(0u32.to_le_bytes());
+    out.extend((HEADER_SIZE as u16).to_le_bytes());
+    out.extend(0u16.to_le_bytes());
+    out.extend(0u16.to_le_bytes());
+    out.extend((SECTION_HEADER_SIZE as u16).to_le_bytes());
+    out.extend((headers.len() as u16).to_le_bytes());
+    out.extend((headers.len() as u16 - 1).to_le_bytes());
+    out.extend(body);
+
+    for h in &headers {
+        out.extend(h.name.to_le_bytes());
+        out.extend(h.kind.to_le_bytes());
+        out.extend(h.flags.to_le_bytes());
+        out.extend(0u64.to_le_bytes());
+        out.extend(h.offset.to_le_bytes());
+        out.extend(h.size.to_le_bytes());
+        out.extend(h.link.to_le_bytes());
+        out.extend(h.info.to_le_bytes());
+        out.extend(h.align.to_le_bytes());
+        out.extend(h.entsize.to_le_bytes());
+    }
+    out
+}
+
+/// An `Elf64_Sym` of no particular type or size.
+fn symbol(name: u32, binding: u8, kind: u8, section: u16, value: u64) -> [u8; SYMBOL_SIZE] {
+    let mut out = [0; SYMBOL_SIZE];
+    out[..4].copy_from_slice(&name.to_le_bytes());
+    out[4] = binding << 4 | kind;
+    out[6..8].copy_from_slice(&section.to_le_bytes());
+    out[8..16].copy_from_slice(&value.to_le_bytes());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use object::{
+        elf::R_X86_64_PC32, Object, ObjectSection, ObjectSymbol, RelocationFlags, RelocationTarget,
+        SectionKind, SymbolKind,
+    };
+
+    use super::*;
+    use crate::{asm_dsl, AsmExpr, Data, Label, Section};
+
+    /// `main` reads a counter and passes a message to `puts`, both in the
+    /// data section.
+    fn program() -> Program {
+        let mut text = vec![AsmExpr::Label(Label::plain("main"))];
+        text.extend(asm_dsl! {
+            lea rdi, [rel message];
+            mov rax, [rel counter];
+            call puts;
+            ret;
+        });
+        let data = vec![
+            AsmExpr::Label(Label::plain("counter")),
+            AsmExpr::Data(Data::UInt(0)),
+            AsmExpr::Label(Label::plain("message")),
+            AsmExpr::Data(Data::Bytes(b"hi\0".to_vec())),
+        ];
+        Program::default()
+            .with_global("main")
+            .with_extern("puts")
+            .with_section(Section::new("text", text))
+            .with_section(Section::new("data", data))
+    }
+
+    #[test]
+    fn sections_and_symbols_are_what_a_linker_reads() {
+        let bytes = write(&program()).unwrap();
+        let file = object::File::parse(&*bytes).unwrap();
+        let text = file.section_by_name(".text").unwrap();
+        let data = file.section_by_name(".data").unwrap();
+        assert_eq!(
+            (text.kind(), data.kind()),
+            (SectionKind::Text, SectionKind::Data)
+        );
+        assert_eq!(data.data().unwrap(), b"\0\0\0\0\0\0\0\0hi\0");
+
+        let symbol = |name| file.symbol_by_name(name).unwrap();
+        let main = symbol("main");
+        assert!(main.is_global());
+        assert_eq!(
+            (main.section_index(), main.address()),
+            (Some(text.index()), 0)
+        );
+        let message = symbol("message");
+        assert!(message.is_local());
+        assert_eq!(
+            (message.section_index(), message.address()),
+            (Some(data.index()), 8)
+        );
+        assert!(symbol("puts").is_undefined());
+        assert!(symbol("puts").is_global());
+    }
+
+    #[test]
+    fn references_across_sections_are_relocated() {
+        let bytes = write(&program()).unwrap();
+        let file = object::File::parse(&*bytes).unwrap();
+        let text = file.section_by_name(".text").unwrap();
+        let target = |target| match target {
+            RelocationTarget::Symbol(index) => {
+                let symbol = file.symbol_by_index(index).unwrap();
+                match symbol.kind() {
+                    SymbolKind::Section => {
+                        let section = file.section_by_index(symbol.section_index().unwrap());
+                        section.unwrap().name().unwrap().to_string()
+                    }
+                    _ => symbol.name().unwrap().to_string(),
+                }
+            }
+            other => panic!("relocation against {:?}", other),
+        };
+        let relocations: Vec<_> = text
+            .relocations()
+            .map(|(offset, r)| {
+                let RelocationFlags::Elf { r_type } = r.flags() else {
+                    panic!("{:?}", r.flags());
+                };
+                (offset, r_type, target(r.target()), r.addend())
+            })
+            .collect();
+        // Each field is four bytes before the end of its instruction,
+        // which is where the CPU counts from.
+        assert_eq!(
+            relocations,
+            [
+                (3, R_X86_64_PC32, ".data".to_string(), 8 - 4),
+                (10, R_X86_64_PC32, ".data".to_string(), -4),
+                (15, R_X86_64_PC32, "puts".to_string(), -4),
+            ]
+        );
+        // The fields themselves are left zero for the addends to fill.
+        assert!(text.data().unwrap()[3..7].iter().all(|&b| b == 0));
+    }
+}
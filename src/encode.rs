@@ -0,0 +1,2319 @@
+//! Machine-code encoding, for producing binary images and JIT code without
+//! an external assembler.
+//!
+//! Encoding covers the general-purpose integer instructions the rest of the
+//! crate generates and rejects anything else with
+//! [`EncodeErrorKind::Unsupported`]. That includes the APX registers
+//! r16-r31, which take a REX2 prefix; no VEX or EVEX instruction is
+//! encoded, so neither are the EVEX forms of them. Sections are laid out one after
+//! another from [`EncodeOptions::base`] unless pinned to an address, and
+//! branches to labels start short and grow to their 32-bit forms until
+//! every distance fits.
+//!
+//! Once addresses are final every label reference is range-checked, so a
+//! branch, displacement or immediate that does not fit its field is
+//! reported with the instruction and the distance involved instead of
+//! being silently truncated.
+
+use std::{collections::HashMap, error, fmt};
+
+use crate::{
+    dataflow::constant,
+    expr::{ConstExpr, ExprError},
+    layout::{Entry, EntryKind, Layout, SectionLayout},
+    object::{ObjectSection, RelocationKind},
+    program::Program,
+    qualify_label, region_symbols,
+    register::{Gpr, GprWidth},
+    Amd64Instruction, Amd64Register, Amd64SpecialRegister, AsmExpr, Data, Endian, ImmediateValue,
+    Operand, Section,
+};
+
+/// Which encodings branches to labels may use.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum BranchSize {
+    /// The 8-bit form wherever the distance allows, the 32-bit one
+    /// otherwise.
+    #[default]
+    Auto,
+    /// Always the 8-bit form; distances that do not fit are errors.
+    Short,
+    /// Always the 32-bit form.
+    Near,
+}
+
+#[derive(Clone, Debug)]
+pub struct EncodeOptions {
+    /// Address of the first section.
+    pub base: u64,
+    /// Alignment of each section's start address.
+    pub section_alignment: u64,
+    /// Sections placed at fixed addresses rather than after the previous
+    /// one.
+    pub section_bases: HashMap<String, u64>,
+    /// Addresses of symbols defined outside the program, such as externs
+    /// resolved by a JIT.
+    pub symbols: HashMap<String, u64>,
+    pub branches: BranchSize,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        EncodeOptions {
+            base: 0,
+            section_alignment: 16,
+            section_bases: HashMap::new(),
+            symbols: HashMap::new(),
+            branches: BranchSize::Auto,
+        }
+    }
+}
+
+impl EncodeOptions {
+    pub fn new() -> Self {
+        EncodeOptions::default()
+    }
+
+    pub fn with_base(mut self, base: u64) -> Self {
+        self.base = base;
+        self
+    }
+
+    pub fn with_section_base(mut self, section: &str, address: u64) -> Self {
+        self.section_bases.insert(section.to_string(), address);
+        self
+    }
+
+    pub fn with_symbol(mut self, name: &str, address: u64) -> Self {
+        self.symbols.insert(name.to_string(), address);
+        self
+    }
+
+    pub fn with_branches(mut self, branches: BranchSize) -> Self {
+        self.branches = branches;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncodeErrorKind {
+    /// A branch target is `distance` bytes away, too far for a `bits`-bit
+    /// relative offset.
+    BranchOutOfRange {
+        bits: u32,
+        distance: i64,
+    },
+    /// A memory operand's displacement (or, RIP-relative, its distance to
+    /// the target) does not fit in 32 signed bits.
+    DisplacementOutOfRange {
+        value: i64,
+    },
+    /// An immediate or data field of `bits` bits cannot hold `value`.
+    ValueOutOfRange {
+        bits: u32,
+        value: i128,
+    },
+    /// A [`Data::SkipTo`] is placed at `position`, already past `offset`.
+    PastOffset {
+        offset: u64,
+        position: u64,
+    },
+    UndefinedSymbol(String),
+    /// Defines could not be evaluated.
+    Expr(String),
+    /// The instruction, operand combination or item has no encoding here.
+    Unsupported(String),
+}
+
+impl fmt::Display for EncodeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodeErrorKind::BranchOutOfRange { bits, distance } => write!(
+                f,
+                "branch distance {} does not fit in a rel{} offset",
+                distance, bits
+            ),
+            EncodeErrorKind::DisplacementOutOfRange { value } => {
+                write!(f, "displacement {} does not fit in disp32", value)
+            }
+            EncodeErrorKind::ValueOutOfRange { bits, value } => {
+                write!(f, "value {:#x} does not fit in {} bits", value, bits)
+            }
+            EncodeErrorKind::PastOffset { offset, position } => write!(
+                f,
+                "section is already {:#x} bytes long, past offset {:#x}",
+                position, offset
+            ),
+            EncodeErrorKind::UndefinedSymbol(name) => write!(f, "undefined symbol `{}`", name),
+            EncodeErrorKind::Expr(message) => write!(f, "{}", message),
+            EncodeErrorKind::Unsupported(what) => write!(f, "cannot encode {}", what),
+        }
+    }
+}
+
+/// Something that could not be encoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodeError {
+    pub section: String,
+    /// The offending instruction or data item, as emitted.
+    pub item: String,
+    pub kind: EncodeErrorKind,
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} in `{}` in section .{}",
+            self.kind,
+            self.item.replace('\t', " "),
+            self.section
+        )
+    }
+}
+
+impl error::Error for EncodeError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EncodedSection {
+    pub name: String,
+    pub address: u64,
+    pub bytes: Vec<u8>,
+}
+
+/// An encoded program: section contents at their addresses, and the
+/// address of every label.
+#[derive(Clone, Debug)]
+pub struct Image {
+    pub sections: Vec<EncodedSection>,
+    pub symbols: HashMap<String, u64>,
+}
+
+impl Image {
+    pub fn symbol(&self, name: &str) -> Option<u64> {
+        self.symbols.get(name).copied()
+    }
+
+    pub fn section(&self, name: &str) -> Option<&EncodedSection> {
+        self.sections.iter().find(|s| s.name == name)
+    }
+}
+
+/// What a fixup field holds, which decides the error it reports.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Field {
+    Branch,
+    Displacement,
+    Immediate,
+    Data,
+}
+
+/// A field of an encoded item whose value depends on the layout, such as
+/// a label's address or distance, filled in once addresses are known.
+#[derive(Clone)]
+struct Fixup {
+    /// Offset of the field within the item's bytes.
+    offset: usize,
+    width: u8,
+    /// Whether the field holds the distance from the end of the item
+    /// rather than the address itself.
+    relative: bool,
+    /// Whether the field is sign-extended when used.
+    signed: bool,
+    field: Field,
+    value: ConstExpr,
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct Encoded {
+    pub(crate) bytes: Vec<u8>,
+    fixups: Vec<Fixup>,
+}
+
+impl Encoded {
+    /// Bytes that depend on nothing.
+    pub(crate) fn plain(bytes: Vec<u8>) -> Self {
+        Encoded {
+            bytes,
+            fixups: Vec::new(),
+        }
+    }
+
+    /// Fills in every fixup of an item ending at `end` that `env` gives a
+    /// value, keeping the rest. Returns a symbol one of those is waiting
+    /// on, if any remain.
+    pub(crate) fn resolve(
+        &mut self,
+        end: u64,
+        env: &HashMap<String, ConstExpr>,
+    ) -> Result<Option<String>, EncodeErrorKind> {
+        let mut waiting = None;
+        let mut failed = None;
+        let bytes = &mut self.bytes;
+        self.fixups
+            .retain(|fixup| match apply(fixup, bytes, end, env) {
+                Ok(()) => false,
+                Err(EncodeErrorKind::UndefinedSymbol(name)) => {
+                    waiting.get_or_insert(name);
+                    true
+                }
+                Err(kind) => {
+                    failed.get_or_insert(kind);
+                    false
+                }
+            });
+        match failed {
+            Some(kind) => Err(kind),
+            None => Ok(waiting),
+        }
+    }
+
+    pub(crate) fn is_resolved(&self) -> bool {
+        self.fixups.is_empty()
+    }
+
+    /// Whether a branch uses the 8-bit form.
+    pub(crate) fn has_short_branch(&self) -> bool {
+        self.fixups
+            .iter()
+            .any(|f| f.field == Field::Branch && f.width == 1)
+    }
+}
+
+/// One label or encoded item of a section.
+enum Item {
+    Label(String),
+    Code {
+        inst: Amd64Instruction,
+        scope: String,
+        long: bool,
+        encoded: Encoded,
+    },
+    Data {
+        data: Data,
+        endian: Endian,
+        encoded: Encoded,
+    },
+    /// A constant defined by an `equ` line, whose `$` and `$$` stand for
+    /// its own position and the start of its section.
+    Equ {
+        name: String,
+        expr: ConstExpr,
+    },
+    /// Zeros up to the next multiple of `to`.
+    Align {
+        to: u64,
+        encoded: Encoded,
+    },
+    /// A section of a linked object, with the symbols it defines.
+    Object {
+        name: String,
+        symbols: Vec<(String, u64)>,
+        encoded: Encoded,
+    },
+}
+
+impl Item {
+    fn size(&self) -> u64 {
+        match self {
+            Item::Label(_) | Item::Equ { .. } => 0,
+            Item::Code { encoded, .. }
+            | Item::Data { encoded, .. }
+            | Item::Align { encoded, .. }
+            | Item::Object { encoded, .. } => encoded.bytes.len() as u64,
+        }
+    }
+}
+
+/// Everything assembled into one output section. Like NASM, sections
+/// sharing a name are laid out as one, in program order.
+struct Placement {
+    name: String,
+    items: Vec<Item>,
+    address: u64,
+    size: u64,
+}
+
+struct Context<'a> {
+    defines: HashMap<String, ConstExpr>,
+    pointer_width: u32,
+    errors: Vec<EncodeError>,
+    options: &'a EncodeOptions,
+}
+
+impl Context<'_> {
+    fn error(&mut self, section: &str, item: String, kind: EncodeErrorKind) {
+        self.errors.push(EncodeError {
+            section: section.to_string(),
+            item,
+            kind,
+        });
+    }
+}
+
+/// The program laid out: every section at its address, with the address
+/// of each label and the value of each `equ` and define.
+struct Assembly {
+    layouts: Vec<Placement>,
+    addresses: HashMap<String, u64>,
+    env: HashMap<String, ConstExpr>,
+}
+
+/// Flattens and places every section of `program`, growing branches until
+/// their encodings are final.
+fn assemble<'a>(program: &Program, options: &'a EncodeOptions) -> (Assembly, Context<'a>) {
+    let mut cx = Context {
+        defines: program.define_map(),
+        pointer_width: program.target.abi.pointer_width(),
+        errors: Vec::new(),
+        options,
+    };
+
+    let pool = (!program.pool.is_empty()).then(|| program.pool.to_section());
+    let mut layouts: Vec<Placement> = Vec::new();
+    let mut scope = String::new();
+    for section in program.sections.iter().chain(&pool) {
+        let mut items = Vec::new();
+        flatten(
+            section,
+            &section.body,
+            program,
+            &mut scope,
+            &mut items,
+            &mut cx,
+        );
+        add_items(&mut layouts, &section.name, items);
+    }
+    for object in &program.objects {
+        for section in &object.sections {
+            let items = vec![
+                Item::Align {
+                    to: section.align,
+                    encoded: Encoded::default(),
+                },
+                Item::Object {
+                    name: object.name.clone(),
+                    symbols: section.symbols.clone(),
+                    encoded: relocate(section),
+                },
+            ];
+            add_items(&mut layouts, &section.target, items);
+        }
+    }
+
+    // Values that depend on the layout always get full-width fields, so
+    // only branches change size: grow the short ones that cannot reach
+    // until none change. Growing only ever lengthens code, so this settles.
+    let mut assembly = place(layouts, &cx);
+    while relax(&mut assembly, &mut cx) {
+        assembly = place(assembly.layouts, &cx);
+    }
+
+    (assembly, cx)
+}
+
+fn add_items(layouts: &mut Vec<Placement>, section: &str, mut items: Vec<Item>) {
+    match layouts.iter_mut().find(|l| l.name == section) {
+        Some(layout) => layout.items.append(&mut items),
+        None => layouts.push(Placement {
+            name: section.to_string(),
+            items,
+            address: 0,
+            size: 0,
+        }),
+    }
+}
+
+/// The bytes of an object section, with a fixup for each relocation.
+fn relocate(section: &ObjectSection) -> Encoded {
+    let len = section.bytes.len() as u64;
+    let fixups = section
+        .relocations
+        .iter()
+        .map(|r| {
+            let mut value = ConstExpr::sym(&r.symbol) + r.addend;
+            // Relative fixups are measured from the end of the item, and
+            // relocations from the field itself.
+            if r.kind.is_relative() {
+                value = value + (len - r.offset) as i64;
+            }
+            Fixup {
+                offset: r.offset as usize,
+                width: r.kind.width(),
+                relative: r.kind.is_relative(),
+                signed: r.kind != RelocationKind::Absolute32,
+                field: Field::Data,
+                value,
+            }
+        })
+        .collect();
+    Encoded {
+        bytes: section.bytes.clone(),
+        fixups,
+    }
+}
+
+/// Lays `program` out as `options` asks, without encoding it. Unlike
+/// [`encode`], values that do not fit their fields are not errors here.
+pub fn layout(program: &Program, options: &EncodeOptions) -> Result<Layout, Vec<EncodeError>> {
+    let (assembly, mut cx) = assemble(program, options);
+
+    let mut sections = Vec::new();
+    for layout in &assembly.layouts {
+        let mut entries = Vec::new();
+        let mut address = layout.address;
+        for item in &layout.items {
+            let offset = address - layout.address;
+            let (size, kind) = match item {
+                Item::Label(name) => (0, EntryKind::Label(name.clone())),
+                Item::Code { inst, encoded, .. } => {
+                    (encoded.bytes.len(), EntryKind::Instruction(inst.clone()))
+                }
+                Item::Data { data, encoded, .. } => {
+                    (encoded.bytes.len(), EntryKind::Data(data.clone()))
+                }
+                Item::Align { encoded, .. } if encoded.bytes.is_empty() => continue,
+                Item::Align { encoded, .. } => {
+                    let count = encoded.bytes.len();
+                    (count, EntryKind::Data(Data::Fill { count, byte: 0 }))
+                }
+                Item::Object {
+                    name,
+                    symbols,
+                    encoded,
+                } => {
+                    entries.extend(symbols.iter().map(|(symbol, at)| Entry {
+                        offset: offset + at,
+                        size: 0,
+                        kind: EntryKind::Label(symbol.clone()),
+                    }));
+                    (encoded.bytes.len(), EntryKind::Object(name.clone()))
+                }
+                Item::Equ { name, .. } => match evaluate(&ConstExpr::sym(name), &assembly.env) {
+                    Ok(value) => (
+                        0,
+                        EntryKind::Equ {
+                            name: name.clone(),
+                            value,
+                        },
+                    ),
+                    Err(kind) => {
+                        cx.error(&layout.name, format!("{} equ", name), kind);
+                        continue;
+                    }
+                },
+            };
+            entries.push(Entry {
+                offset,
+                size: size as u64,
+                kind,
+            });
+            address += size as u64;
+        }
+        sections.push(SectionLayout {
+            name: layout.name.clone(),
+            address: layout.address,
+            size: layout.size,
+            entries,
+        });
+    }
+
+    if cx.errors.is_empty() {
+        Ok(Layout {
+            sections,
+            symbols: assembly.addresses,
+        })
+    } else {
+        Err(cx.errors)
+    }
+}
+
+/// Encodes `program` into machine code laid out as `options` asks.
+pub fn encode(program: &Program, options: &EncodeOptions) -> Result<Image, Vec<EncodeError>> {
+    let (assembly, mut cx) = assemble(program, options);
+
+    let mut sections = Vec::new();
+    for layout in &assembly.layouts {
+        let mut bytes = Vec::with_capacity(layout.size as usize);
+        let mut address = layout.address;
+        for item in &layout.items {
+            let (encoded, text) = match item {
+                Item::Label(_) => continue,
+                Item::Equ { name, .. } => {
+                    if let Err(kind) = evaluate(&ConstExpr::sym(name), &assembly.env) {
+                        cx.error(&layout.name, format!("{} equ", name), kind);
+                    }
+                    continue;
+                }
+                Item::Code { inst, encoded, .. } => (encoded, inst.to_string()),
+                Item::Align { encoded, .. } => (encoded, "alignment padding".to_string()),
+                Item::Object { name, encoded, .. } => (encoded, format!("object {}", name)),
+                Item::Data { data, encoded, .. } => {
+                    let position = address - layout.address;
+                    match skip_offset(data) {
+                        Some(offset) if offset < position => cx.error(
+                            &layout.name,
+                            data.to_string(),
+                            EncodeErrorKind::PastOffset { offset, position },
+                        ),
+                        _ => {}
+                    }
+                    (encoded, data.to_string())
+                }
+            };
+            let mut item_bytes = encoded.bytes.clone();
+            let end = address + item_bytes.len() as u64;
+            for fixup in &encoded.fixups {
+                if let Err(kind) = apply(fixup, &mut item_bytes, end, &assembly.env) {
+                    cx.error(&layout.name, text.clone(), kind);
+                }
+            }
+            address = end;
+            bytes.extend(item_bytes);
+        }
+        sections.push(EncodedSection {
+            name: layout.name.clone(),
+            address: layout.address,
+            bytes,
+        });
+    }
+
+    if cx.errors.is_empty() {
+        Ok(Image {
+            sections,
+            symbols: assembly.addresses,
+        })
+    } else {
+        Err(cx.errors)
+    }
+}
+
+/// Where an object-file relocation points.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum RelocationTarget {
+    /// The start of a section, by its index in
+    /// [`Relocatable::sections`].
+    Section(usize),
+    /// A symbol defined outside the program.
+    Symbol(String),
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct SectionRelocation {
+    pub(crate) offset: u64,
+    pub(crate) kind: RelocationKind,
+    pub(crate) target: RelocationTarget,
+    pub(crate) addend: i64,
+}
+
+/// A section encoded as if it started at address 0, with every value
+/// that depends on where it ends up left to the linker.
+pub(crate) struct RelocatableSection {
+    pub(crate) name: String,
+    pub(crate) bytes: Vec<u8>,
+    pub(crate) align: u64,
+    /// Every label of the section, with its offset.
+    pub(crate) labels: Vec<(String, u64)>,
+    pub(crate) relocations: Vec<SectionRelocation>,
+}
+
+pub(crate) struct Relocatable {
+    pub(crate) sections: Vec<RelocatableSection>,
+    /// The externs something refers to.
+    pub(crate) externs: Vec<String>,
+}
+
+/// Sections placed this far apart never reach each other with a short
+/// branch, so branches between them are always long enough to relocate.
+const RELOCATABLE_SPACING: u64 = 1 << 32;
+
+/// Encodes `program` for an object file. Fields whose value is a label
+/// of the same section, or a constant, are filled in; fields holding an
+/// address plus a constant become relocations; anything else is reported
+/// as unsupported.
+pub(crate) fn relocatable(program: &Program) -> Result<Relocatable, Vec<EncodeError>> {
+    let names = section_names(program);
+    let mut options = EncodeOptions::new();
+    for (i, name) in names.iter().enumerate() {
+        options = options.with_section_base(name, i as u64 * RELOCATABLE_SPACING);
+    }
+    let mut bases: HashMap<String, usize> = HashMap::new();
+    for (j, external) in program.externs.iter().enumerate() {
+        let base = names.len() + j;
+        options = options.with_symbol(&external.value, base as u64 * RELOCATABLE_SPACING);
+        bases.insert(external.value.clone(), base);
+    }
+
+    let (assembly, mut cx) = assemble(program, &options);
+    for (i, layout) in assembly.layouts.iter().enumerate() {
+        for item in &layout.items {
+            match item {
+                Item::Label(name) => {
+                    bases.insert(name.clone(), i);
+                }
+                Item::Object { symbols, .. } => {
+                    bases.extend(symbols.iter().map(|(name, _)| (name.clone(), i)));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut sections = Vec::new();
+    let mut referenced = vec![false; program.externs.len()];
+    for (i, layout) in assembly.layouts.iter().enumerate() {
+        let mut bytes = Vec::with_capacity(layout.size as usize);
+        let mut labels = Vec::new();
+        let mut relocations = Vec::new();
+        let mut align = options.section_alignment;
+        let mut address = layout.address;
+        for item in &layout.items {
+            let offset = address - layout.address;
+            let (encoded, text) = match item {
+                Item::Label(name) => {
+                    labels.push((name.clone(), offset));
+                    continue;
+                }
+                Item::Equ { name, .. } => {
+                    if let Err(kind) = evaluate(&ConstExpr::sym(name), &assembly.env) {
+                        cx.error(&layout.name, format!("{} equ", name), kind);
+                    }
+                    continue;
+                }
+                Item::Code { inst, encoded, .. } => (encoded, inst.to_string()),
+                Item::Align { to, encoded } => {
+                    align = align.max(*to);
+                    (encoded, "alignment padding".to_string())
+                }
+                Item::Object {
+                    name,
+                    symbols,
+                    encoded,
+                } => {
+                    labels.extend(symbols.iter().map(|(s, at)| (s.clone(), offset + at)));
+                    (encoded, format!("object {}", name))
+                }
+                Item::Data { data, encoded, .. } => {
+                    match skip_offset(data) {
+                        Some(skip) if skip < offset => cx.error(
+                            &layout.name,
+                            data.to_string(),
+                            EncodeErrorKind::PastOffset {
+                                offset: skip,
+                                position: offset,
+                            },
+                        ),
+                        _ => {}
+                    }
+                    (encoded, data.to_string())
+                }
+            };
+            let mut item_bytes = encoded.bytes.clone();
+            let end = address + item_bytes.len() as u64;
+            for fixup in &encoded.fixups {
+                let relocation =
+                    linear(fixup, i, &bases, &assembly.env).and_then(|target| match target {
+                        None => apply(fixup, &mut item_bytes, end, &assembly.env).map(|()| None),
+                        Some((base, value, pc_relative)) => {
+                            let kind = relocation_kind(fixup, fixup.relative || pc_relative)?;
+                            let field = end - encoded.bytes.len() as u64 + fixup.offset as u64;
+                            let start = base as u64 * RELOCATABLE_SPACING;
+                            let mut addend = value.wrapping_sub(start as i64);
+                            if fixup.relative {
+                                addend -= (end - field) as i64;
+                            }
+                            // The value already subtracts a position in
+                            // this section, so the field's own position
+                            // stands in for it.
+                            if pc_relative {
+                                addend += field as i64;
+                            }
+                            Ok(Some(SectionRelocation {
+                                offset: field - layout.address,
+                                kind,
+                                target: match base.checked_sub(names.len()) {
+                                    Some(j) => {
+                                        referenced[j] = true;
+                                        RelocationTarget::Symbol(program.externs[j].value.clone())
+                                    }
+                                    None => RelocationTarget::Section(base),
+                                },
+                                addend,
+                            }))
+                        }
+                    });
+                match relocation {
+                    Ok(relocation) => relocations.extend(relocation),
+                    Err(kind) => cx.error(&layout.name, text.clone(), kind),
+                }
+            }
+            address = end;
+            bytes.extend(item_bytes);
+        }
+        sections.push(RelocatableSection {
+            name: layout.name.clone(),
+            bytes,
+            align,
+            labels,
+            relocations,
+        });
+    }
+
+    if cx.errors.is_empty() {
+        let externs = program
+            .externs
+            .iter()
+            .zip(referenced)
+            .filter(|(_, used)| *used)
+            .map(|(e, _)| e.value.clone())
+            .collect();
+        Ok(Relocatable { sections, externs })
+    } else {
+        Err(cx.errors)
+    }
+}
+
+/// Output sections in the order [`assemble`] lays them out.
+fn section_names(program: &Program) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    let pool = (!program.pool.is_empty()).then_some("rodata");
+    let objects = program.objects.iter().flat_map(|o| &o.sections);
+    let all = program
+        .sections
+        .iter()
+        .map(|s| s.name.as_str())
+        .chain(pool)
+        .chain(objects.map(|s| s.target.as_str()));
+    for name in all {
+        if !names.iter().any(|n| n == name) {
+            names.push(name.to_string());
+        }
+    }
+    names
+}
+
+/// What `fixup` of an item in section `own` depends on: nothing, when the
+/// field can be filled in now, or the base (a section or extern, numbered
+/// as in `bases`) whose address it adds to, with its value as laid out.
+/// A value that also subtracts an address in `own`, as a label-minus-base
+/// offset does, takes a PC-relative relocation; the flag says so.
+fn linear(
+    fixup: &Fixup,
+    own: usize,
+    bases: &HashMap<String, usize>,
+    env: &HashMap<String, ConstExpr>,
+) -> Result<Option<(usize, i64, bool)>, EncodeErrorKind> {
+    // Only what the value mentions, directly or through equs and defines.
+    let mut needed: HashMap<String, ConstExpr> = HashMap::new();
+    let mut pending = Vec::new();
+    fixup
+        .value
+        .visit_symbols(&mut |name| pending.push(name.to_string()));
+    while let Some(name) = pending.pop() {
+        if needed.contains_key(&name) {
+            continue;
+        }
+        let value = env
+            .get(&name)
+            .ok_or_else(|| EncodeErrorKind::UndefinedSymbol(name.clone()))?;
+        value.visit_symbols(&mut |name| pending.push(name.to_string()));
+        needed.insert(name, value.clone());
+    }
+
+    let value = evaluate(&fixup.value, &needed)?;
+    let mut used: Vec<usize> = needed
+        .keys()
+        .filter_map(|n| bases.get(n).copied())
+        .collect();
+    used.sort_unstable();
+    used.dedup();
+
+    // How much the value moves when each base does.
+    let mut moved = Vec::new();
+    for &base in &used {
+        let mut shifted = needed.clone();
+        for (name, value) in &mut shifted {
+            if let (Some(&b), ConstExpr::Int(n)) = (bases.get(name), &value) {
+                if b == base {
+                    *value = ConstExpr::Int(n + 1);
+                }
+            }
+        }
+        let mut slope = evaluate(&fixup.value, &shifted)?.wrapping_sub(value);
+        if fixup.relative && base == own {
+            slope -= 1;
+        }
+        if slope != 0 {
+            moved.push((base, slope));
+        }
+    }
+    if fixup.relative && !used.contains(&own) {
+        moved.push((own, -1));
+    }
+
+    match (fixup.relative, moved.as_slice()) {
+        (_, []) => Ok(None),
+        (false, &[(base, 1)]) => Ok(Some((base, value, false))),
+        (_, &[(a, sa), (b, sb)]) if (a == own && sa == -1) || (b == own && sb == -1) => {
+            let (base, slope) = if a == own { (b, sb) } else { (a, sa) };
+            match slope {
+                1 => Ok(Some((base, value, !fixup.relative))),
+                _ => Err(not_relocatable()),
+            }
+        }
+        _ => Err(not_relocatable()),
+    }
+}
+
+/// The items of `program`, encoded under `options`, whose bytes change
+/// when the whole image moves, with the name of their section: those
+/// holding an address rather than a distance within the image, and those
+/// reaching a symbol of [`EncodeOptions::symbols`], which stays put.
+pub(crate) fn position_dependent(
+    program: &Program,
+    options: &EncodeOptions,
+) -> Result<Vec<(String, String)>, Vec<EncodeError>> {
+    let (assembly, cx) = assemble(program, options);
+    if !cx.errors.is_empty() {
+        return Err(cx.errors);
+    }
+
+    // The image is one base for `linear`, and the symbols outside it are
+    // another.
+    let mut bases: HashMap<String, usize> = options
+        .symbols
+        .keys()
+        .map(|name| (name.clone(), 1))
+        .collect();
+    for item in assembly.layouts.iter().flat_map(|layout| &layout.items) {
+        match item {
+            Item::Label(name) => {
+                bases.insert(name.clone(), 0);
+            }
+            Item::Object { symbols, .. } => {
+                bases.extend(symbols.iter().map(|(name, _)| (name.clone(), 0)));
+            }
+            _ => {}
+        }
+    }
+
+    let mut found = Vec::new();
+    for layout in &assembly.layouts {
+        for item in &layout.items {
+            let (encoded, text) = match item {
+                Item::Code { inst, encoded, .. } => (encoded, inst.to_string()),
+                Item::Data { data, encoded, .. } => (encoded, data.to_string()),
+                Item::Object { name, encoded, .. } => (encoded, format!("object {}", name)),
+                Item::Label(_) | Item::Equ { .. } | Item::Align { .. } => continue,
+            };
+            let moves = encoded
+                .fixups
+                .iter()
+                .any(|fixup| !matches!(linear(fixup, 0, &bases, &assembly.env), Ok(None)));
+            if moves {
+                found.push((layout.name.clone(), text));
+            }
+        }
+    }
+    Ok(found)
+}
+
+fn not_relocatable() -> EncodeErrorKind {
+    unsupported("a value that is not one address plus a constant")
+}
+
+fn relocation_kind(fixup: &Fixup, relative: bool) -> Result<RelocationKind, EncodeErrorKind> {
+    Ok(match (relative, fixup.width, fixup.signed) {
+        (false, 8, _) => RelocationKind::Absolute64,
+        (false, 4, true) => RelocationKind::Absolute32Signed,
+        (false, 4, false) => RelocationKind::Absolute32,
+        (true, 4, _) => RelocationKind::Relative32,
+        (true, 8, _) => RelocationKind::Relative64,
+        (_, width, _) => return Err(unsupported(format_args!("a {}-bit relocation", width * 8))),
+    })
+}
+
+fn flatten(
+    section: &Section,
+    body: &[AsmExpr],
+    program: &Program,
+    scope: &mut String,
+    items: &mut Vec<Item>,
+    cx: &mut Context,
+) {
+    for expr in body {
+        match expr {
+            AsmExpr::Label(label) => {
+                if !label.label.starts_with('.') {
+                    *scope = label.label.clone();
+                }
+                items.push(Item::Label(qualify_label(scope, &label.label)));
+            }
+            AsmExpr::Instruction(inst) => {
+                let long = cx.options.branches == BranchSize::Near;
+                let size = program.defaults_of(section).operand_size;
+                match encode_sized(inst, scope, long, &cx.defines, size) {
+                    Ok(encoded) => items.push(Item::Code {
+                        inst: inst.clone(),
+                        scope: scope.clone(),
+                        long,
+                        encoded,
+                    }),
+                    Err(kind) => cx.error(&section.name, inst.to_string(), kind),
+                }
+            }
+            AsmExpr::Data(data) => {
+                let encoded = encode_data(data, section.endian, cx.pointer_width, scope);
+                items.push(Item::Data {
+                    data: data.clone(),
+                    endian: section.endian,
+                    encoded,
+                });
+            }
+            AsmExpr::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let arm = if cond.eval(&program.config) {
+                    then
+                } else {
+                    otherwise
+                };
+                flatten(section, arm, program, scope, items, cx);
+            }
+            AsmExpr::Block(inner) | AsmExpr::Macro { body: inner, .. } => {
+                flatten(section, inner, program, scope, items, cx)
+            }
+            AsmExpr::Region { name, body } => {
+                let (begin, end) = region_symbols(name);
+                if program.region_symbols {
+                    items.push(Item::Label(begin));
+                }
+                flatten(section, body, program, scope, items, cx);
+                if program.region_symbols {
+                    items.push(Item::Label(end));
+                }
+            }
+            AsmExpr::Raw(text) => match parse_equ(text) {
+                Some(Ok((name, expr))) => items.push(Item::Equ {
+                    name: qualify_label(scope, name),
+                    expr: qualify(expr, scope),
+                }),
+                Some(Err(e)) => cx.error(
+                    &section.name,
+                    text.trim().to_string(),
+                    EncodeErrorKind::Expr(e.to_string()),
+                ),
+                None => cx.error(
+                    &section.name,
+                    text.clone(),
+                    EncodeErrorKind::Unsupported("raw assembly text".to_string()),
+                ),
+            },
+            AsmExpr::Param(name) => cx.error(
+                &section.name,
+                format!("%{}", name),
+                EncodeErrorKind::Unsupported("an unbound parameter".to_string()),
+            ),
+            AsmExpr::Arm64(inst) => cx.error(
+                &section.name,
+                inst.to_string(),
+                EncodeErrorKind::Unsupported("an AArch64 instruction".to_string()),
+            ),
+            AsmExpr::Riscv(inst) => cx.error(
+                &section.name,
+                inst.to_string(),
+                EncodeErrorKind::Unsupported("a RISC-V instruction".to_string()),
+            ),
+        }
+    }
+}
+
+/// The name and value of a `name equ value` line.
+pub(crate) fn parse_equ(text: &str) -> Option<Result<(&str, ConstExpr), ExprError>> {
+    let mut words = text.trim().splitn(3, char::is_whitespace);
+    let name = words.next()?;
+    if words.next()? != "equ" {
+        return None;
+    }
+    Some(ConstExpr::parse(words.next().unwrap_or("")).map(|expr| (name, expr)))
+}
+
+/// `expr` with local label names qualified by `scope`.
+pub(crate) fn qualify(mut expr: ConstExpr, scope: &str) -> ConstExpr {
+    expr.rename_symbols(&|name| {
+        (name.starts_with('.') && !name.starts_with("..")).then(|| qualify_label(scope, name))
+    });
+    expr
+}
+
+/// `expr` with `$` and `$$` replaced by `here` and `start`.
+pub(crate) fn at_position(expr: &ConstExpr, here: u64, start: u64) -> ConstExpr {
+    match expr {
+        ConstExpr::Symbol(name) if name == "$" => ConstExpr::Int(here as i64),
+        ConstExpr::Symbol(name) if name == "$$" => ConstExpr::Int(start as i64),
+        ConstExpr::Int(_) | ConstExpr::Symbol(_) => expr.clone(),
+        ConstExpr::Neg(inner) => -at_position(inner, here, start),
+        ConstExpr::Binary(op, lhs, rhs) => ConstExpr::Binary(
+            *op,
+            Box::new(at_position(lhs, here, start)),
+            Box::new(at_position(rhs, here, start)),
+        ),
+    }
+}
+
+/// Assigns section and label addresses, and gathers everything a fixup
+/// may refer to.
+fn place(mut layouts: Vec<Placement>, cx: &Context) -> Assembly {
+    let options = cx.options;
+    let mut addresses = options.symbols.clone();
+    let mut env = cx.defines.clone();
+    let mut next = options.base;
+    let align = options.section_alignment.max(1);
+
+    for layout in &mut layouts {
+        layout.address = match options.section_bases.get(&layout.name) {
+            Some(&address) => address,
+            None => next.div_ceil(align) * align,
+        };
+        let mut address = layout.address;
+        for item in &mut layout.items {
+            match item {
+                Item::Label(name) => {
+                    addresses.insert(name.clone(), address);
+                }
+                Item::Equ { name, expr } => {
+                    env.insert(name.clone(), at_position(expr, address, layout.address));
+                }
+                Item::Data {
+                    data,
+                    endian,
+                    encoded,
+                } if data.is_positional() => {
+                    encoded.bytes = data.to_bytes_at(*endian, address - layout.address);
+                    address += encoded.bytes.len() as u64;
+                }
+                Item::Align { to, encoded } => {
+                    encoded.bytes = vec![0; (address.next_multiple_of(*to) - address) as usize];
+                    address += encoded.bytes.len() as u64;
+                }
+                Item::Object {
+                    symbols, encoded, ..
+                } => {
+                    for (name, offset) in symbols {
+                        addresses.insert(name.clone(), address + *offset);
+                    }
+                    address += encoded.bytes.len() as u64;
+                }
+                Item::Code { encoded, .. } | Item::Data { encoded, .. } => {
+                    address += encoded.bytes.len() as u64;
+                }
+            }
+        }
+        layout.size = address - layout.address;
+        next = next.max(address);
+    }
+
+    for (name, &address) in &addresses {
+        env.insert(name.clone(), ConstExpr::Int(address as i64));
+    }
+    Assembly {
+        layouts,
+        addresses,
+        env,
+    }
+}
+
+/// Re-encodes short branches whose targets are out of reach in their long
+/// form. Returns whether any changed.
+fn relax(assembly: &mut Assembly, cx: &mut Context) -> bool {
+    if cx.options.branches != BranchSize::Auto {
+        return false;
+    }
+
+    let mut changed = false;
+    for layout in &mut assembly.layouts {
+        let mut address = layout.address;
+        for item in &mut layout.items {
+            let Item::Code {
+                inst,
+                scope,
+                long,
+                encoded,
+            } = item
+            else {
+                address += item.size();
+                continue;
+            };
+            let end = address + encoded.bytes.len() as u64;
+            address = end;
+
+            let reaches = encoded
+                .fixups
+                .iter()
+                .filter(|f| f.field == Field::Branch && f.width == 1)
+                .all(|f| match f.value.eval(&assembly.env) {
+                    Ok(target) => i8::try_from(target.wrapping_sub(end as i64)).is_ok(),
+                    Err(_) => false,
+                });
+            if !reaches && !*long {
+                if let Ok(grown) = encode_instruction(inst, scope, true, &cx.defines) {
+                    if grown.bytes.len() != encoded.bytes.len() {
+                        *long = true;
+                        *encoded = grown;
+                        changed = true;
+                    }
+                }
+            }
+        }
+    }
+    changed
+}
+
+fn evaluate(expr: &ConstExpr, env: &HashMap<String, ConstExpr>) -> Result<i64, EncodeErrorKind> {
+    expr.eval(env).map_err(|e| match e {
+        ExprError::Undefined(name) => EncodeErrorKind::UndefinedSymbol(name),
+        e => EncodeErrorKind::Expr(e.to_string()),
+    })
+}
+
+fn apply(
+    fixup: &Fixup,
+    bytes: &mut [u8],
+    end: u64,
+    env: &HashMap<String, ConstExpr>,
+) -> Result<(), EncodeErrorKind> {
+    let target = evaluate(&fixup.value, env)?;
+    let bits = fixup.width as u32 * 8;
+
+    let value: i128 = if fixup.relative {
+        target.wrapping_sub(end as i64) as i128
+    } else {
+        target as i128
+    };
+    let fits = if fixup.relative || fixup.signed {
+        let limit = 1i128 << (bits - 1);
+        (-limit..limit).contains(&value)
+    } else {
+        bits == 64 || (0..1i128 << bits).contains(&value)
+    };
+
+    if !fits {
+        return Err(match fixup.field {
+            Field::Branch => EncodeErrorKind::BranchOutOfRange {
+                bits,
+                distance: value as i64,
+            },
+            Field::Displacement => EncodeErrorKind::DisplacementOutOfRange {
+                value: value as i64,
+            },
+            Field::Immediate | Field::Data => EncodeErrorKind::ValueOutOfRange { bits, value },
+        });
+    }
+
+    let field = &mut bytes[fixup.offset..fixup.offset + fixup.width as usize];
+    field.copy_from_slice(&(value as u64).to_le_bytes()[..fixup.width as usize]);
+    Ok(())
+}
+
+/// The section offset a [`Data::SkipTo`] advances to.
+fn skip_offset(data: &Data) -> Option<u64> {
+    match data {
+        Data::SkipTo { offset, .. } => Some(*offset),
+        Data::Endian(_, inner) => skip_offset(inner),
+        _ => None,
+    }
+}
+
+pub(crate) fn encode_data(data: &Data, endian: Endian, pointer_width: u32, scope: &str) -> Encoded {
+    match data {
+        Data::Endian(e, inner) => encode_data(inner, *e, pointer_width, scope),
+        Data::Times(count, inner) => {
+            let item = encode_data(inner, endian, pointer_width, scope);
+            let mut out = Encoded::plain(item.bytes.repeat(*count));
+            for copy in 0..*count {
+                out.fixups.extend(item.fixups.iter().map(|fixup| Fixup {
+                    offset: fixup.offset + copy * item.bytes.len(),
+                    ..fixup.clone()
+                }));
+            }
+            out
+        }
+        Data::Address(label) => Encoded {
+            bytes: vec![0; pointer_width as usize],
+            fixups: vec![Fixup {
+                offset: 0,
+                width: pointer_width as u8,
+                relative: false,
+                signed: false,
+                field: Field::Data,
+                value: ConstExpr::sym(&qualify_label(scope, &label.label)),
+            }],
+        },
+        Data::ImageOffset(label) => Encoded {
+            bytes: vec![0; 4],
+            fixups: vec![Fixup {
+                offset: 0,
+                width: 4,
+                relative: false,
+                signed: false,
+                field: Field::Data,
+                value: ConstExpr::sym(&qualify_label(scope, &label.label)),
+            }],
+        },
+        Data::Offset { label, base } => Encoded {
+            bytes: vec![0; 4],
+            fixups: vec![Fixup {
+                offset: 0,
+                width: 4,
+                relative: false,
+                signed: true,
+                field: Field::Data,
+                value: ConstExpr::sym(&qualify_label(scope, &label.label))
+                    - ConstExpr::sym(&qualify_label(scope, &base.label)),
+            }],
+        },
+        Data::USize(v) if pointer_width == 4 => {
+            let mut bytes = (*v as u32).to_le_bytes().to_vec();
+            if endian == Endian::Big {
+                bytes.reverse();
+            }
+            Encoded {
+                bytes,
+                fixups: Vec::new(),
+            }
+        }
+        _ => Encoded {
+            bytes: data.to_bytes_with(endian),
+            fixups: Vec::new(),
+        },
+    }
+}
+
+/// An operand value, known up front or only once the layout is.
+#[derive(Clone)]
+enum Value {
+    Const(i64),
+    Deferred(ConstExpr),
+}
+
+#[derive(Clone)]
+enum Base {
+    Reg(u8),
+    Rip,
+}
+
+#[derive(Clone)]
+struct Mem {
+    base: Base,
+    /// Index register and log2 of its scale.
+    index: Option<(u8, u8)>,
+    disp: Value,
+}
+
+#[derive(Clone)]
+enum Arg {
+    Reg(u8),
+    Mem(Mem),
+    Imm(Value),
+}
+
+/// Operand-size-free instructions with a fixed encoding.
+const FIXED: &[(&str, &[u8])] = &[
+    ("nop", &[0x90]),
+    ("ret", &[0xc3]),
+    ("leave", &[0xc9]),
+    ("hlt", &[0xf4]),
+    ("int3", &[0xcc]),
+    ("cqo", &[0x48, 0x99]),
+    ("cdq", &[0x99]),
+    ("clc", &[0xf8]),
+    ("stc", &[0xf9]),
+    ("cld", &[0xfc]),
+    ("std", &[0xfd]),
+    ("pushfq", &[0x9c]),
+    ("popfq", &[0x9d]),
+    ("pause", &[0xf3, 0x90]),
+    ("syscall", &[0x0f, 0x05]),
+    ("ud2", &[0x0f, 0x0b]),
+    ("cpuid", &[0x0f, 0xa2]),
+    ("rdtsc", &[0x0f, 0x31]),
+    ("rdtscp", &[0x0f, 0x01, 0xf9]),
+    ("swapgs", &[0x0f, 0x01, 0xf8]),
+    ("lfence", &[0x0f, 0xae, 0xe8]),
+    ("mfence", &[0x0f, 0xae, 0xf0]),
+    ("sfence", &[0x0f, 0xae, 0xf8]),
+    ("wbinvd", &[0x0f, 0x09]),
+];
+
+/// `/digit` of the group-1 arithmetic instructions.
+const ARITHMETIC: &[(&str, u8)] = &[
+    ("add", 0),
+    ("or", 1),
+    ("adc", 2),
+    ("sbb", 3),
+    ("and", 4),
+    ("sub", 5),
+    ("xor", 6),
+    ("cmp", 7),
+];
+
+/// `/digit` of the group-2 shifts and rotates.
+const SHIFTS: &[(&str, u8)] = &[
+    ("rol", 0),
+    ("ror", 1),
+    ("rcl", 2),
+    ("rcr", 3),
+    ("shl", 4),
+    ("sal", 4),
+    ("shr", 5),
+    ("sar", 7),
+];
+
+/// `opcode /digit` of single-operand instructions.
+const UNARY: &[(&str, u8, u8)] = &[
+    ("inc", 0xff, 0),
+    ("dec", 0xff, 1),
+    ("not", 0xf7, 2),
+    ("neg", 0xf7, 3),
+    ("mul", 0xf7, 4),
+    ("imul", 0xf7, 5),
+    ("div", 0xf7, 6),
+    ("idiv", 0xf7, 7),
+];
+
+/// `/digit` of the `0f 18` prefetch hints, which take a memory operand.
+const PREFETCH: &[(&str, u8)] = &[
+    ("prefetchnta", 0),
+    ("prefetcht0", 1),
+    ("prefetcht1", 2),
+    ("prefetcht2", 3),
+];
+
+/// `0f` opcode of the register form and `/digit` of the `0f ba` immediate
+/// form of the bit tests.
+const BIT_TESTS: &[(&str, u8, u8)] = &[
+    ("bt", 0xa3, 4),
+    ("bts", 0xab, 5),
+    ("btr", 0xb3, 6),
+    ("btc", 0xbb, 7),
+];
+
+/// `/digit` of the `f3 0f ae` segment-base accesses.
+const SEGMENT_BASES: &[(&str, u8)] = &[
+    ("rdfsbase", 0),
+    ("rdgsbase", 1),
+    ("wrfsbase", 2),
+    ("wrgsbase", 3),
+];
+
+/// Branches that only have an 8-bit form.
+const SHORT_ONLY: &[(&str, u8)] = &[
+    ("loopne", 0xe0),
+    ("loopnz", 0xe0),
+    ("loope", 0xe1),
+    ("loopz", 0xe1),
+    ("loop", 0xe2),
+    ("jrcxz", 0xe3),
+];
+
+/// The condition-code number of a `jcc`/`cmovcc` suffix.
+fn condition(suffix: &str) -> Option<u8> {
+    Some(match suffix {
+        "o" => 0,
+        "no" => 1,
+        "b" | "c" | "nae" => 2,
+        "ae" | "nb" | "nc" => 3,
+        "e" | "z" => 4,
+        "ne" | "nz" => 5,
+        "be" | "na" => 6,
+        "a" | "nbe" => 7,
+        "s" => 8,
+        "ns" => 9,
+        "p" | "pe" => 10,
+        "np" | "po" => 11,
+        "l" | "nge" => 12,
+        "ge" | "nl" => 13,
+        "le" | "ng" => 14,
+        "g" | "nle" => 15,
+        _ => return None,
+    })
+}
+
+fn unsupported(what: impl fmt::Display) -> EncodeErrorKind {
+    EncodeErrorKind::Unsupported(what.to_string())
+}
+
+/// The number of a register operand, a whole register or its low dword.
+fn register(reg: &Amd64Register) -> Result<u8, EncodeErrorKind> {
+    match reg {
+        Amd64Register::Partial(gpr, GprWidth::Dword) => Ok(gpr.index()),
+        _ => address_register(reg),
+    }
+}
+
+/// The number of a base or index register, which is always a whole one.
+fn address_register(reg: &Amd64Register) -> Result<u8, EncodeErrorKind> {
+    let gpr = reg
+        .gpr()
+        .ok_or_else(|| unsupported(format_args!("register {} here", reg)))?;
+    Ok(gpr.index())
+}
+
+fn immediate(
+    imm: &ImmediateValue,
+    scope: &str,
+    defines: &HashMap<String, ConstExpr>,
+) -> Result<Value, EncodeErrorKind> {
+    match imm {
+        ImmediateValue::Label(label) => Ok(Value::Deferred(ConstExpr::sym(&qualify_label(
+            scope,
+            &label.label,
+        )))),
+        ImmediateValue::Bytes(_) => Err(unsupported("a byte-list immediate")),
+        // Names that are not defines may be labels or `equ` constants,
+        // which only have values once the program is laid out.
+        ImmediateValue::Expr(expr) => match expr.eval(defines) {
+            Ok(v) => Ok(Value::Const(v)),
+            Err(ExprError::Undefined(_)) => Ok(Value::Deferred(qualify(expr.clone(), scope))),
+            Err(e) => Err(EncodeErrorKind::Expr(e.to_string())),
+        },
+        _ => Ok(Value::Const(
+            constant(&Operand::Immediate(imm.clone()), defines).unwrap(),
+        )),
+    }
+}
+
+fn argument(
+    operand: &Operand,
+    scope: &str,
+    defines: &HashMap<String, ConstExpr>,
+) -> Result<Arg, EncodeErrorKind> {
+    Ok(match operand {
+        Operand::Register(reg) => Arg::Reg(register(reg)?),
+        Operand::Immediate(imm) => Arg::Imm(immediate(imm, scope, defines)?),
+        Operand::Memory(mem) => {
+            let base = match &mem.base {
+                None if mem.label.is_some() => Base::Rip,
+                None => return Err(unsupported("an absolute address")),
+                Some(Amd64Register::Special(Amd64SpecialRegister::RIP)) => Base::Rip,
+                Some(reg) => Base::Reg(address_register(reg)?),
+            };
+            let index = match &mem.index {
+                None => None,
+                Some(reg) => {
+                    let index = address_register(reg)?;
+                    let scale = match mem.scale {
+                        1 => 0,
+                        2 => 1,
+                        4 => 2,
+                        8 => 3,
+                        n => return Err(unsupported(format_args!("scale {}", n))),
+                    };
+                    if index == Gpr::RSP.index() || matches!(base, Base::Rip) {
+                        return Err(unsupported(format_args!("index register {}", reg)));
+                    }
+                    Some((index, scale))
+                }
+            };
+            let disp = match &mem.label {
+                Some(label) => {
+                    let symbol = ConstExpr::sym(&qualify_label(scope, &label.label));
+                    Value::Deferred(match mem.displacement {
+                        0 => symbol,
+                        d => symbol + d,
+                    })
+                }
+                None => Value::Const(mem.displacement),
+            };
+            Arg::Mem(Mem { base, index, disp })
+        }
+        Operand::Param(name) => return Err(unsupported(format_args!("parameter %{}", name))),
+    })
+}
+
+/// Accumulates one instruction's bytes and fixups.
+#[derive(Default)]
+struct Emitter {
+    out: Encoded,
+}
+
+enum Rm<'a> {
+    Reg(u8),
+    Mem(&'a Mem),
+}
+
+impl Emitter {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.out.bytes.extend_from_slice(bytes);
+    }
+
+    /// `opcode` with the REX prefix its operands need: none, REX, or for
+    /// an APX register REX2, which also carries the top bit of each
+    /// register number and stands in for the `0f` escape of map 1 opcodes.
+    fn prefixed(
+        &mut self,
+        w: bool,
+        r: u8,
+        x: u8,
+        b: u8,
+        opcode: &[u8],
+    ) -> Result<(), EncodeErrorKind> {
+        let low = (w as u8) << 3 | (r >> 3 & 1) << 2 | (x >> 3 & 1) << 1 | (b >> 3 & 1);
+        if r | x | b < 16 {
+            if low != 0 {
+                self.bytes(&[0x40 | low]);
+            }
+            self.bytes(opcode);
+            return Ok(());
+        }
+
+        let (map, opcode) = match opcode {
+            [0x0f, 0x38 | 0x3a, ..] => {
+                return Err(unsupported("an APX register with a three-byte opcode"))
+            }
+            [0x0f, rest @ ..] => (1, rest),
+            _ => (0, opcode),
+        };
+        let high = map << 7 | (r >> 4 & 1) << 6 | (x >> 4 & 1) << 5 | (b >> 4 & 1) << 4;
+        self.bytes(&[0xd5, high | low]);
+        self.bytes(opcode);
+        Ok(())
+    }
+
+    fn fixup(&mut self, width: u8, relative: bool, field: Field, value: &ConstExpr) {
+        self.out.fixups.push(Fixup {
+            offset: self.out.bytes.len(),
+            width,
+            relative,
+            signed: true,
+            field,
+            value: value.clone(),
+        });
+        self.out
+            .bytes
+            .extend(std::iter::repeat_n(0, width as usize));
+    }
+
+    /// An opcode taking a ModRM operand, with the REX prefix it needs.
+    fn op_rm(&mut self, w: bool, opcode: &[u8], reg: u8, rm: &Rm) -> Result<(), EncodeErrorKind> {
+        let (x, b) = match rm {
+            Rm::Reg(r) => (0, *r),
+            Rm::Mem(mem) => (
+                mem.index.map_or(0, |(i, _)| i),
+                match mem.base {
+                    Base::Reg(r) => r,
+                    Base::Rip => 0,
+                },
+            ),
+        };
+        self.prefixed(w, reg, x, b, opcode)?;
+
+        let reg = (reg & 7) << 3;
+        match rm {
+            Rm::Reg(r) => self.bytes(&[0xc0 | reg | (r & 7)]),
+            Rm::Mem(Mem {
+                base: Base::Rip,
+                disp,
+                ..
+            }) => {
+                self.bytes(&[0x05 | reg]);
+                match disp {
+                    Value::Const(d) => self.bytes(&disp32(*d)?.to_le_bytes()),
+                    Value::Deferred(s) => self.fixup(4, true, Field::Displacement, s),
+                }
+            }
+            Rm::Mem(Mem {
+                base: Base::Reg(base),
+                index,
+                disp,
+            }) => {
+                let sib = index.is_some() || base & 7 == 4;
+                let mode = match disp {
+                    Value::Const(0) if base & 7 != 5 => 0,
+                    Value::Const(d) if i8::try_from(*d).is_ok() => 1,
+                    _ => 2,
+                };
+                let rm_bits = if sib { 4 } else { base & 7 };
+                self.bytes(&[mode << 6 | reg | rm_bits]);
+                if sib {
+                    let (index, scale) = index.unwrap_or((4, 0));
+                    self.bytes(&[scale << 6 | (index & 7) << 3 | (base & 7)]);
+                }
+                match (mode, disp) {
+                    (1, Value::Const(d)) => self.bytes(&[*d as i8 as u8]),
+                    (2, Value::Const(d)) => self.bytes(&disp32(*d)?.to_le_bytes()),
+                    (2, Value::Deferred(s)) => self.fixup(4, false, Field::Displacement, s),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A 32-bit immediate, sign-extended by the CPU.
+    fn imm32(&mut self, value: &Value) -> Result<(), EncodeErrorKind> {
+        match value {
+            Value::Const(v) => {
+                let v = i32::try_from(*v).map_err(|_| EncodeErrorKind::ValueOutOfRange {
+                    bits: 32,
+                    value: *v as i128,
+                })?;
+                self.bytes(&v.to_le_bytes());
+            }
+            Value::Deferred(s) => self.fixup(4, false, Field::Immediate, s),
+        }
+        Ok(())
+    }
+
+    /// A branch displacement to a label or, as NASM and GAS read a
+    /// number there, an absolute address. Either way the distance is only
+    /// known once the branch is placed, so it is range-checked and relaxed
+    /// like any other.
+    fn rel(&mut self, width: u8, target: &Value) {
+        match target {
+            Value::Deferred(s) => self.fixup(width, true, Field::Branch, s),
+            Value::Const(v) => self.fixup(width, true, Field::Branch, &ConstExpr::Int(*v)),
+        }
+    }
+}
+
+fn disp32(d: i64) -> Result<i32, EncodeErrorKind> {
+    i32::try_from(d).map_err(|_| EncodeErrorKind::DisplacementOutOfRange { value: d })
+}
+
+fn small(value: &Value) -> Option<i8> {
+    match value {
+        Value::Const(v) => i8::try_from(*v).ok(),
+        Value::Deferred(_) => None,
+    }
+}
+
+fn size_unspecified() -> EncodeErrorKind {
+    unsupported("a memory operand without an operation size")
+}
+
+/// Encodes `inst`, leaving fixups for anything `defines` does not give a
+/// value. `long` picks the 32-bit form of branches to labels.
+pub(crate) fn encode_instruction(
+    inst: &Amd64Instruction,
+    scope: &str,
+    long: bool,
+    defines: &HashMap<String, ConstExpr>,
+) -> Result<Encoded, EncodeErrorKind> {
+    encode_sized(inst, scope, long, defines, None)
+}
+
+/// Encodes `inst` at `address` with every symbol it refers to given a
+/// value by `env`, for the patcher.
+pub(crate) fn encode_placed(
+    inst: &Amd64Instruction,
+    scope: &str,
+    long: bool,
+    address: u64,
+    env: &HashMap<String, ConstExpr>,
+) -> Result<Vec<u8>, EncodeErrorKind> {
+    let mut encoded = encode_instruction(inst, scope, long, env)?;
+    let end = address + encoded.bytes.len() as u64;
+    match encoded.resolve(end, env)? {
+        Some(name) => Err(EncodeErrorKind::UndefinedSymbol(name)),
+        None => Ok(encoded.bytes),
+    }
+}
+
+/// Like [`encode_instruction`], with `size` as the size of a memory
+/// operand nothing else sizes, from the section's
+/// [`Defaults`](crate::Defaults). Operations are 64 or 32 bits wide, as
+/// their register operands or else their memory operand say.
+fn encode_sized(
+    inst: &Amd64Instruction,
+    scope: &str,
+    long: bool,
+    defines: &HashMap<String, ConstExpr>,
+    size: Option<GprWidth>,
+) -> Result<Encoded, EncodeErrorKind> {
+    // A size on the operand itself wins over the section's.
+    let explicit = inst.operands.iter().find_map(|op| match op {
+        Operand::Memory(mem) => mem.size,
+        _ => None,
+    });
+    let size = explicit.or(size);
+    let args = inst
+        .operands
+        .iter()
+        .map(|op| argument(op, scope, defines))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mnemonic = inst.mnemonic.as_str();
+    let mut e = Emitter::default();
+    let bad = || unsupported(format_args!("`{}` with these operands", mnemonic));
+
+    let mut widths = inst.operands.iter().filter_map(|op| match op {
+        Operand::Register(Amd64Register::Partial(_, width)) => Some(*width),
+        Operand::Register(_) => Some(GprWidth::Qword),
+        _ => None,
+    });
+    let width = match widths.next() {
+        Some(first) if widths.all(|width| width == first) => Some(first),
+        Some(_) => return Err(unsupported("registers of different widths")),
+        None => None,
+    };
+    if width.is_some() && explicit.is_some() && width != explicit {
+        return Err(bad());
+    }
+    // REX.W, for a 64-bit operation.
+    let w = width.or(size) != Some(GprWidth::Dword);
+    // The stack and indirect branches only take 64-bit operands.
+    if !w && matches!(mnemonic, "push" | "pop" | "jmp" | "call") {
+        return Err(bad());
+    }
+    let sized = || match size {
+        Some(GprWidth::Qword | GprWidth::Dword) => Ok(()),
+        Some(width) => Err(unsupported(format_args!(
+            "a {}-bit memory operand",
+            width.bits()
+        ))),
+        None => Err(size_unspecified()),
+    };
+
+    if let Some((_, bytes)) = FIXED.iter().find(|(m, _)| *m == mnemonic) {
+        match (mnemonic, args.as_slice()) {
+            ("ret", [Arg::Imm(Value::Const(n))]) => {
+                let n = u16::try_from(*n).map_err(|_| bad())?;
+                e.bytes(&[0xc2]);
+                e.bytes(&n.to_le_bytes());
+            }
+            (_, []) => e.bytes(bytes),
+            _ => return Err(bad()),
+        }
+        return Ok(e.out);
+    }
+
+    if let Some((_, digit)) = ARITHMETIC.iter().find(|(m, _)| *m == mnemonic) {
+        match args.as_slice() {
+            [Arg::Reg(d), Arg::Reg(s)] => e.op_rm(w, &[digit * 8 + 1], *s, &Rm::Reg(*d))?,
+            [Arg::Mem(m), Arg::Reg(s)] => e.op_rm(w, &[digit * 8 + 1], *s, &Rm::Mem(m))?,
+            [Arg::Reg(d), Arg::Mem(m)] => e.op_rm(w, &[digit * 8 + 3], *d, &Rm::Mem(m))?,
+            [dst @ (Arg::Reg(_) | Arg::Mem(_)), Arg::Imm(v)] => {
+                let rm = match dst {
+                    Arg::Reg(d) => Rm::Reg(*d),
+                    Arg::Mem(m) => {
+                        sized()?;
+                        Rm::Mem(m)
+                    }
+                    Arg::Imm(_) => unreachable!(),
+                };
+                match small(v) {
+                    Some(v) => {
+                        e.op_rm(w, &[0x83], *digit, &rm)?;
+                        e.bytes(&[v as u8]);
+                    }
+                    None => {
+                        e.op_rm(w, &[0x81], *digit, &rm)?;
+                        e.imm32(v)?;
+                    }
+                }
+            }
+            _ => return Err(bad()),
+        }
+        return Ok(e.out);
+    }
+
+    if let Some((_, digit)) = SHIFTS.iter().find(|(m, _)| *m == mnemonic) {
+        match args.as_slice() {
+            [dst @ (Arg::Reg(_) | Arg::Mem(_)), Arg::Imm(Value::Const(n))] => {
+                let rm = match dst {
+                    Arg::Reg(d) => Rm::Reg(*d),
+                    Arg::Mem(m) => {
+                        sized()?;
+                        Rm::Mem(m)
+                    }
+                    Arg::Imm(_) => unreachable!(),
+                };
+                if *n == 1 {
+                    e.op_rm(w, &[0xd1], *digit, &rm)?;
+                } else {
+                    let n = u8::try_from(*n).map_err(|_| EncodeErrorKind::ValueOutOfRange {
+                        bits: 8,
+                        value: *n as i128,
+                    })?;
+                    e.op_rm(w, &[0xc1], *digit, &rm)?;
+                    e.bytes(&[n]);
+                }
+            }
+            [Arg::Mem(_), _] => return Err(size_unspecified()),
+            _ => return Err(bad()),
+        }
+        return Ok(e.out);
+    }
+
+    if let Some(&(_, opcode, digit)) = UNARY.iter().find(|(m, ..)| *m == mnemonic) {
+        match args.as_slice() {
+            [Arg::Reg(r)] => {
+                e.op_rm(w, &[opcode], digit, &Rm::Reg(*r))?;
+                return Ok(e.out);
+            }
+            [Arg::Mem(m)] => {
+                sized()?;
+                e.op_rm(w, &[opcode], digit, &Rm::Mem(m))?;
+                return Ok(e.out);
+            }
+            // Two- and three-operand imul are handled below.
+            _ if mnemonic == "imul" => {}
+            _ => return Err(bad()),
+        }
+    }
+
+    if let Some(&(_, digit)) = PREFETCH.iter().find(|(m, _)| *m == mnemonic) {
+        match args.as_slice() {
+            [Arg::Mem(m)] => e.op_rm(false, &[0x0f, 0x18], digit, &Rm::Mem(m))?,
+            _ => return Err(bad()),
+        }
+        return Ok(e.out);
+    }
+
+    if let Some(&(_, opcode, digit)) = BIT_TESTS.iter().find(|(m, ..)| *m == mnemonic) {
+        match args.as_slice() {
+            [Arg::Reg(d), Arg::Reg(s)] => e.op_rm(w, &[0x0f, opcode], *s, &Rm::Reg(*d))?,
+            [Arg::Mem(m), Arg::Reg(s)] => e.op_rm(w, &[0x0f, opcode], *s, &Rm::Mem(m))?,
+            [Arg::Reg(d), Arg::Imm(Value::Const(n))] => {
+                let n = u8::try_from(*n).map_err(|_| EncodeErrorKind::ValueOutOfRange {
+                    bits: 8,
+                    value: *n as i128,
+                })?;
+                e.op_rm(w, &[0x0f, 0xba], digit, &Rm::Reg(*d))?;
+                e.bytes(&[n]);
+            }
+            [Arg::Mem(m), Arg::Imm(Value::Const(n))] => {
+                sized()?;
+                let n = u8::try_from(*n).map_err(|_| EncodeErrorKind::ValueOutOfRange {
+                    bits: 8,
+                    value: *n as i128,
+                })?;
+                e.op_rm(w, &[0x0f, 0xba], digit, &Rm::Mem(m))?;
+                e.bytes(&[n]);
+            }
+            [Arg::Mem(_), Arg::Imm(_)] => return Err(size_unspecified()),
+            _ => return Err(bad()),
+        }
+        return Ok(e.out);
+    }
+
+    if let Some(&(_, digit)) = SEGMENT_BASES.iter().find(|(m, _)| *m == mnemonic) {
+        match args.as_slice() {
+            [Arg::Reg(r)] => {
+                e.bytes(&[0xf3]);
+                e.op_rm(w, &[0x0f, 0xae], digit, &Rm::Reg(*r))?;
+            }
+            _ => return Err(bad()),
+        }
+        return Ok(e.out);
+    }
+
+    if let Some(&(_, opcode)) = SHORT_ONLY.iter().find(|(m, _)| *m == mnemonic) {
+        match args.as_slice() {
+            [Arg::Imm(target)] => {
+                e.bytes(&[opcode]);
+                e.rel(1, target);
+            }
+            _ => return Err(bad()),
+        }
+        return Ok(e.out);
+    }
+
+    match (mnemonic, args.as_slice()) {
+        ("mov", [Arg::Reg(d), Arg::Reg(s)]) => e.op_rm(w, &[0x89], *s, &Rm::Reg(*d))?,
+        ("mov", [Arg::Mem(m), Arg::Reg(s)]) => e.op_rm(w, &[0x89], *s, &Rm::Mem(m))?,
+        ("mov", [Arg::Reg(d), Arg::Mem(m)]) => e.op_rm(w, &[0x8b], *d, &Rm::Mem(m))?,
+        ("mov", [Arg::Reg(d), Arg::Imm(Value::Const(v))]) => {
+            if let Ok(v) = u32::try_from(*v) {
+                // Writing the low half zero-extends, as NASM optimises it.
+                e.prefixed(false, 0, 0, *d, &[0xb8 + (d & 7)])?;
+                e.bytes(&v.to_le_bytes());
+            } else if !w {
+                let v = i32::try_from(*v).map_err(|_| EncodeErrorKind::ValueOutOfRange {
+                    bits: 32,
+                    value: *v as i128,
+                })?;
+                e.prefixed(false, 0, 0, *d, &[0xb8 + (d & 7)])?;
+                e.bytes(&v.to_le_bytes());
+            } else if let Ok(v) = i32::try_from(*v) {
+                e.op_rm(true, &[0xc7], 0, &Rm::Reg(*d))?;
+                e.bytes(&v.to_le_bytes());
+            } else {
+                e.prefixed(true, 0, 0, *d, &[0xb8 + (d & 7)])?;
+                e.bytes(&v.to_le_bytes());
+            }
+        }
+        ("mov", [Arg::Reg(d), Arg::Imm(Value::Deferred(s))]) => {
+            e.prefixed(w, 0, 0, *d, &[0xb8 + (d & 7)])?;
+            let width = if w { 8 } else { 4 };
+            e.out.fixups.push(Fixup {
+                offset: e.out.bytes.len(),
+                width,
+                relative: false,
+                signed: false,
+                field: Field::Immediate,
+                value: s.clone(),
+            });
+            e.bytes(&[0; 8][..width as usize]);
+        }
+        ("mov", [Arg::Mem(m), Arg::Imm(v)]) => {
+            sized()?;
+            e.op_rm(w, &[0xc7], 0, &Rm::Mem(m))?;
+            e.imm32(v)?;
+        }
+        ("clflush", [Arg::Mem(m)]) => e.op_rm(false, &[0x0f, 0xae], 7, &Rm::Mem(m))?,
+        ("clflushopt" | "clwb", [Arg::Mem(m)]) => {
+            let digit = if mnemonic == "clwb" { 6 } else { 7 };
+            e.bytes(&[0x66]);
+            e.op_rm(false, &[0x0f, 0xae], digit, &Rm::Mem(m))?
+        }
+        ("invlpg", [Arg::Mem(m)]) => e.op_rm(false, &[0x0f, 0x01], 7, &Rm::Mem(m))?,
+        ("movnti", [Arg::Mem(m), Arg::Reg(s)]) => e.op_rm(w, &[0x0f, 0xc3], *s, &Rm::Mem(m))?,
+        ("lea", [Arg::Reg(d), Arg::Mem(m)]) => e.op_rm(w, &[0x8d], *d, &Rm::Mem(m))?,
+        ("test", [Arg::Reg(d), Arg::Reg(s)]) => e.op_rm(w, &[0x85], *s, &Rm::Reg(*d))?,
+        ("test", [Arg::Mem(m), Arg::Reg(s)]) | ("test", [Arg::Reg(s), Arg::Mem(m)]) => {
+            e.op_rm(w, &[0x85], *s, &Rm::Mem(m))?
+        }
+        ("test", [Arg::Reg(d), Arg::Imm(v)]) => {
+            e.op_rm(w, &[0xf7], 0, &Rm::Reg(*d))?;
+            e.imm32(v)?;
+        }
+        ("test", [Arg::Mem(m), Arg::Imm(v)]) => {
+            sized()?;
+            e.op_rm(w, &[0xf7], 0, &Rm::Mem(m))?;
+            e.imm32(v)?;
+        }
+        ("xchg", [Arg::Reg(a), Arg::Reg(b)]) => e.op_rm(w, &[0x87], *a, &Rm::Reg(*b))?,
+        ("xchg", [Arg::Reg(r), Arg::Mem(m)]) | ("xchg", [Arg::Mem(m), Arg::Reg(r)]) => {
+            e.op_rm(w, &[0x87], *r, &Rm::Mem(m))?
+        }
+        ("bsf" | "bsr", [Arg::Reg(d), src @ (Arg::Reg(_) | Arg::Mem(_))]) => {
+            let opcode = [0x0f, if mnemonic == "bsf" { 0xbc } else { 0xbd }];
+            match src {
+                Arg::Reg(s) => e.op_rm(w, &opcode, *d, &Rm::Reg(*s))?,
+                Arg::Mem(m) => e.op_rm(w, &opcode, *d, &Rm::Mem(m))?,
+                Arg::Imm(_) => unreachable!(),
+            }
+        }
+        ("imul", [Arg::Reg(d), Arg::Reg(s)]) => e.op_rm(w, &[0x0f, 0xaf], *d, &Rm::Reg(*s))?,
+        ("imul", [Arg::Reg(d), Arg::Mem(m)]) => e.op_rm(w, &[0x0f, 0xaf], *d, &Rm::Mem(m))?,
+        ("imul", [Arg::Reg(d), src @ (Arg::Reg(_) | Arg::Mem(_)), Arg::Imm(v)]) => {
+            let rm = match src {
+                Arg::Reg(s) => Rm::Reg(*s),
+                Arg::Mem(m) => Rm::Mem(m),
+                Arg::Imm(_) => unreachable!(),
+            };
+            match small(v) {
+                Some(v) => {
+                    e.op_rm(w, &[0x6b], *d, &rm)?;
+                    e.bytes(&[v as u8]);
+                }
+                None => {
+                    e.op_rm(w, &[0x69], *d, &rm)?;
+                    e.imm32(v)?;
+                }
+            }
+        }
+        ("push", [Arg::Reg(r)]) => e.prefixed(false, 0, 0, *r, &[0x50 + (r & 7)])?,
+        ("push", [Arg::Imm(v)]) => match small(v) {
+            Some(v) => e.bytes(&[0x6a, v as u8]),
+            None => {
+                e.bytes(&[0x68]);
+                e.imm32(v)?;
+            }
+        },
+        ("pop", [Arg::Reg(r)]) => e.prefixed(false, 0, 0, *r, &[0x58 + (r & 7)])?,
+        // Both default to 64 bits; no REX.W.
+        ("push", [Arg::Mem(m)]) => {
+            sized()?;
+            e.op_rm(false, &[0xff], 6, &Rm::Mem(m))?;
+        }
+        ("pop", [Arg::Mem(m)]) => {
+            sized()?;
+            e.op_rm(false, &[0x8f], 0, &Rm::Mem(m))?;
+        }
+        ("jmp", [Arg::Imm(target)]) => {
+            if long {
+                e.bytes(&[0xe9]);
+                e.rel(4, target);
+            } else {
+                e.bytes(&[0xeb]);
+                e.rel(1, target);
+            }
+        }
+        ("call", [Arg::Imm(target)]) => {
+            e.bytes(&[0xe8]);
+            e.rel(4, target);
+        }
+        ("jmp", [Arg::Reg(r)]) => e.op_rm(false, &[0xff], 4, &Rm::Reg(*r))?,
+        ("jmp", [Arg::Mem(m)]) => e.op_rm(false, &[0xff], 4, &Rm::Mem(m))?,
+        ("call", [Arg::Reg(r)]) => e.op_rm(false, &[0xff], 2, &Rm::Reg(*r))?,
+        ("call", [Arg::Mem(m)]) => e.op_rm(false, &[0xff], 2, &Rm::Mem(m))?,
+        (m, [Arg::Imm(target)]) if m.starts_with('j') && condition(&m[1..]).is_some() => {
+            let cc = condition(&m[1..]).unwrap();
+            if long {
+                e.bytes(&[0x0f, 0x80 + cc]);
+                e.rel(4, target);
+            } else {
+                e.bytes(&[0x70 + cc]);
+                e.rel(1, target);
+            }
+        }
+        (m, [Arg::Reg(d), src]) if m.starts_with("cmov") && condition(&m[4..]).is_some() => {
+            let opcode = [0x0f, 0x40 + condition(&m[4..]).unwrap()];
+            match src {
+                Arg::Reg(s) => e.op_rm(w, &opcode, *d, &Rm::Reg(*s))?,
+                Arg::Mem(mem) => e.op_rm(w, &opcode, *d, &Rm::Mem(mem))?,
+                Arg::Imm(_) => return Err(bad()),
+            }
+        }
+        _ => {
+            return Err(if args.is_empty() || !is_known(mnemonic) {
+                unsupported(format_args!("`{}`", mnemonic))
+            } else {
+                bad()
+            })
+        }
+    }
+
+    Ok(e.out)
+}
+
+/// Whether `mnemonic` has some encoding here, for telling unknown
+/// instructions apart from known ones with unsupported operands.
+fn is_known(mnemonic: &str) -> bool {
+    matches!(
+        mnemonic,
+        "mov"
+            | "movnti"
+            | "lea"
+            | "test"
+            | "xchg"
+            | "bsf"
+            | "bsr"
+            | "imul"
+            | "push"
+            | "pop"
+            | "jmp"
+            | "call"
+            | "clflush"
+            | "clflushopt"
+            | "clwb"
+            | "invlpg"
+    ) || (mnemonic.starts_with('j') && condition(&mnemonic[1..]).is_some())
+        || (mnemonic.starts_with("cmov") && condition(&mnemonic[4..]).is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm_dsl, instr, mnemonic::Amd64Mnemonic, Label};
+
+    /// The bytes of a single text section holding `body`, at address 0.
+    fn bytes(body: Vec<AsmExpr>) -> Vec<u8> {
+        let program = Program::default().with_section(Section::new("text", body));
+        let image = encode(&program, &EncodeOptions::new()).unwrap();
+        image.section("text").unwrap().bytes.clone()
+    }
+
+    fn errors(body: Vec<AsmExpr>) -> Vec<EncodeErrorKind> {
+        let program = Program::default().with_section(Section::new("text", body));
+        let errors = encode(&program, &EncodeOptions::new()).unwrap_err();
+        errors.into_iter().map(|e| e.kind).collect()
+    }
+
+    fn jump(mnemonic: &str, target: i64) -> AsmExpr {
+        AsmExpr::Instruction(Amd64Instruction::new(
+            Amd64Mnemonic::parse(mnemonic),
+            vec![ImmediateValue::I64(target).into()],
+        ))
+    }
+
+    #[test]
+    fn registers_and_addresses() {
+        let code = bytes(asm_dsl! {
+            mov rax, rbx;
+            mov rax, [rbx + rcx*8 + 16];
+            mov r12, [r13];
+            mov rax, [rsp];
+            push r12;
+            pop rbx;
+            syscall;
+            ret;
+        });
+        let expected = [
+            &[0x48, 0x89, 0xd8][..],
+            &[0x48, 0x8b, 0x44, 0xcb, 0x10],
+            &[0x4d, 0x8b, 0x65, 0x00],
+            &[0x48, 0x8b, 0x04, 0x24],
+            &[0x41, 0x54],
+            &[0x5b],
+            &[0x0f, 0x05],
+            &[0xc3],
+        ];
+        assert_eq!(code, expected.concat());
+    }
+
+    /// Checked against the REX2 layout in the APX specification: `d5`,
+    /// then M0 R4 X4 B4 W R3 X3 B3.
+    #[test]
+    fn apx_registers_take_rex2() {
+        let code = bytes(asm_dsl! {
+            mov r16, rax;
+            mov rax, [r17 + r18*4 + 8];
+            push r31;
+            imul r20, r9;
+            mov r25, 5;
+            mov r8, rax;
+        });
+        let expected = [
+            &[0xd5, 0x18, 0x89, 0xc0][..],
+            &[0xd5, 0x38, 0x8b, 0x44, 0x91, 0x08],
+            &[0xd5, 0x11, 0x57],
+            &[0xd5, 0xc9, 0xaf, 0xe1],
+            &[0xd5, 0x11, 0xb9, 0x05, 0x00, 0x00, 0x00],
+            &[0x49, 0x89, 0xc0],
+        ];
+        assert_eq!(code, expected.concat());
+    }
+
+    /// Checked against GNU as.
+    #[test]
+    fn dword_registers_drop_rex_w() {
+        let code = bytes(asm_dsl! {
+            xor eax, eax;
+            xor r8d, r8d;
+            mov eax, 5;
+            mov ecx, -1;
+            mov dword [rdi], 1;
+            add ecx, 1;
+            sub r9d, 1000;
+            lea eax, [rdi + 8];
+            mov eax, [rsp];
+            mov [rbx + rcx*4], r10d;
+            imul edx, esi;
+            cmovne eax, ecx;
+            shl edx, 3;
+            test eax, eax;
+            rdfsbase eax;
+            inc dword [rax];
+        });
+        let expected = [
+            &[0x31, 0xc0][..],
+            &[0x45, 0x31, 0xc0],
+            &[0xb8, 0x05, 0x00, 0x00, 0x00],
+            &[0xb9, 0xff, 0xff, 0xff, 0xff],
+            &[0xc7, 0x07, 0x01, 0x00, 0x00, 0x00],
+            &[0x83, 0xc1, 0x01],
+            &[0x41, 0x81, 0xe9, 0xe8, 0x03, 0x00, 0x00],
+            &[0x8d, 0x47, 0x08],
+            &[0x8b, 0x04, 0x24],
+            &[0x44, 0x89, 0x14, 0x8b],
+            &[0x0f, 0xaf, 0xd6],
+            &[0x0f, 0x45, 0xc1],
+            &[0xc1, 0xe2, 0x03],
+            &[0x85, 0xc0],
+            &[0xf3, 0x0f, 0xae, 0xc0],
+            &[0xff, 0x00],
+        ];
+        assert_eq!(code, expected.concat());
+    }
+
+    #[test]
+    fn dword_operations_must_agree_on_their_width() {
+        let errors = errors(asm_dsl! {
+            mov eax, rbx;
+            mov qword [rdi], eax;
+            mov rax, [eax];
+            push eax;
+            mov ax, 1;
+        });
+        let expected = [
+            unsupported("registers of different widths"),
+            unsupported("`mov` with these operands"),
+            unsupported("register eax here"),
+            unsupported("`push` with these operands"),
+            unsupported("register ax here"),
+        ];
+        assert_eq!(errors, expected);
+    }
+
+    #[test]
+    fn immediates_that_fit_a_byte_take_one() {
+        let code = bytes(asm_dsl! {
+            add rax, 1;
+            add rax, 1000;
+            mov rax, 0x1122334455667788;
+        });
+        let expected = [
+            &[0x48, 0x83, 0xc0, 0x01][..],
+            &[0x48, 0x81, 0xc0, 0xe8, 0x03, 0x00, 0x00],
+            &[0x48, 0xb8, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11],
+        ];
+        assert_eq!(code, expected.concat());
+    }
+
+    #[test]
+    fn rip_relative_addresses_count_from_the_next_instruction() {
+        let code = bytes(asm_dsl! {
+            lea rsi, [rel target];
+            ret;
+            target:
+            ret;
+        });
+        assert_eq!(code, [0x48, 0x8d, 0x35, 0x01, 0x00, 0x00, 0x00, 0xc3, 0xc3]);
+    }
+
+    #[test]
+    fn branches_grow_only_when_their_target_is_out_of_reach() {
+        let near = bytes(vec![
+            instr::jmp(Label::plain("end")),
+            AsmExpr::Label(Label::plain("end")),
+        ]);
+        assert_eq!(near, [0xeb, 0x00]);
+
+        let mut far = vec![instr::jmp(Label::plain("end"))];
+        far.extend((0..200).map(|_| instr::nop()));
+        far.push(AsmExpr::Label(Label::plain("end")));
+        assert_eq!(bytes(far)[..5], [0xe9, 0xc8, 0x00, 0x00, 0x00]);
+    }
+
+    /// A number as a branch target is an address, as NASM and GAS have
+    /// it, not a displacement.
+    #[test]
+    fn constant_branch_targets_are_absolute() {
+        assert_eq!(
+            bytes(vec![jump("jmp", 300)]),
+            [0xe9, 0x27, 0x01, 0x00, 0x00]
+        );
+        assert_eq!(bytes(vec![jump("jmp", 0x10)]), [0xeb, 0x0e]);
+        assert_eq!(
+            bytes(vec![jump("call", 0x1000)]),
+            [0xe8, 0xfb, 0x0f, 0x00, 0x00]
+        );
+        assert_eq!(
+            bytes(vec![jump("je", 0x1000)]),
+            [0x0f, 0x84, 0xfa, 0x0f, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn out_of_range_targets_are_reported() {
+        assert_eq!(
+            errors(vec![jump("loop", 300)]),
+            [EncodeErrorKind::BranchOutOfRange {
+                bits: 8,
+                distance: 298
+            }]
+        );
+        assert_eq!(
+            errors(vec![jump("call", 1 << 40)]),
+            [EncodeErrorKind::BranchOutOfRange {
+                bits: 32,
+                distance: (1 << 40) - 5
+            }]
+        );
+    }
+}
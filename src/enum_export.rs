@@ -0,0 +1,117 @@
+use crate::{pool::ConstPool, AsmExpr, Data, Label};
+
+/// A Rust enum whose discriminants are mirrored as assembly constants.
+///
+/// Implement it by hand for existing enums, or declare the enum through
+/// [`asm_enum!`](crate::asm_enum) to have the variant table generated.
+pub trait AsmEnum {
+    /// Prefix for every exported constant.
+    const NAME: &'static str;
+    /// Every variant's name paired with its discriminant.
+    const VARIANTS: &'static [(&'static str, i64)];
+}
+
+/// Declares an enum with explicit discriminants and implements [`AsmEnum`]
+/// for it.
+#[macro_export]
+macro_rules! asm_enum {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident {
+            $($variant:ident = $value:expr),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis enum $name {
+            $($variant = $value),*
+        }
+
+        impl $crate::enum_export::AsmEnum for $name {
+            const NAME: &'static str = stringify!($name);
+            const VARIANTS: &'static [(&'static str, i64)] =
+                &[$((stringify!($variant), $name::$variant as i64)),*];
+        }
+    };
+}
+
+/// Assembly name of a variant's constant, e.g. `SYSCALL_WRITE`.
+pub fn constant_name<E: AsmEnum>(variant: &str) -> String {
+    format!("{}_{}", E::NAME, variant).to_uppercase()
+}
+
+/// A block of `NAME_VARIANT equ value` lines, one per variant.
+pub fn equ_block<E: AsmEnum>() -> AsmExpr {
+    AsmExpr::Block(
+        E::VARIANTS
+            .iter()
+            .map(|(variant, value)| {
+                AsmExpr::Raw(format!("\t{} equ {}", constant_name::<E>(variant), value))
+            })
+            .collect(),
+    )
+}
+
+/// A `NAME_names` table of `(discriminant, name pointer)` pairs, a
+/// quadword and an address each, with every NUL-terminated variant name
+/// interned in `pool`.
+///
+/// The returned block belongs in a read-only section; the table is
+/// terminated by a zero name pointer so it can be scanned without a length.
+pub fn name_table<E: AsmEnum>(pool: &mut ConstPool) -> AsmExpr {
+    let mut body = vec![AsmExpr::Label(Label::plain(&format!(
+        "{}_names",
+        E::NAME.to_lowercase()
+    )))];
+
+    for (variant, value) in E::VARIANTS {
+        let name = pool.bytes(format!("{}\0", variant).as_bytes());
+        body.push(AsmExpr::Data(Data::Int(*value)));
+        body.push(AsmExpr::Data(Data::Address(name)));
+    }
+    body.push(AsmExpr::Data(Data::Int(0)));
+    body.push(AsmExpr::Data(Data::USize(0)));
+
+    AsmExpr::Block(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        encode::{encode, EncodeOptions},
+        program::Program,
+        Section,
+    };
+
+    asm_enum! {
+        enum Color {
+            Red = 1,
+            Blue = 4,
+        }
+    }
+
+    #[test]
+    fn name_table_points_at_each_name() {
+        let mut program = Program::default();
+        let table = name_table::<Color>(&mut program.pool);
+        let program = program.with_section(Section::new("rodata", vec![table]));
+        let image = encode(&program, &EncodeOptions::new()).unwrap();
+
+        let table = &image.section("rodata").unwrap().bytes[..48];
+        let quad = |i: usize| u64::from_le_bytes(table[i * 8..i * 8 + 8].try_into().unwrap());
+        let name = |address: u64| {
+            let section = image
+                .sections
+                .iter()
+                .find(|s| (s.address..s.address + s.bytes.len() as u64).contains(&address))
+                .unwrap();
+            let start = (address - section.address) as usize;
+            let bytes = &section.bytes[start..];
+            String::from_utf8(bytes[..bytes.iter().position(|&b| b == 0).unwrap()].to_vec())
+                .unwrap()
+        };
+        assert_eq!((quad(0), name(quad(1))), (1, "Red".to_string()));
+        assert_eq!((quad(2), name(quad(3))), (4, "Blue".to_string()));
+        assert_eq!((quad(4), quad(5)), (0, 0));
+    }
+}
@@ -0,0 +1,254 @@
+//! Checking that a pass leaves a program doing what it did.
+//!
+//! [`testgen`](crate::testgen) checks passes against programs it writes
+//! itself; an [`Equivalence`] checks them against any program the
+//! [interpreter](crate::interp) can run. It runs the entry point from a
+//! number of generated register states before and after the pass, and
+//! reports the first state where the two runs differ in exit status,
+//! output or the registers it is told to watch:
+//!
+//! ```
+//! use cataclysm::{equiv::Equivalence, instr, consts::{RAX, RDI}, AsmExpr, Label, Program, Section};
+//!
+//! let program = Program::default().with_section(Section::new(
+//!     "text",
+//!     vec![
+//!         AsmExpr::Label(Label::plain("double")),
+//!         instr::mov(RAX, RDI),
+//!         instr::add(RAX, RDI),
+//!         instr::ret(),
+//!     ],
+//! ));
+//! let check = Equivalence::new("double");
+//! assert_eq!(check.check(&program, |_| {}), Ok(64));
+//!
+//! // Rewriting `add` into `sub` is caught on the first nonzero input.
+//! let divergence = check
+//!     .check(&program, |p| {
+//!         p.sections[0].body_mut()[2] = instr::sub(RAX, RDI);
+//!     })
+//!     .unwrap_err();
+//! assert_ne!(divergence.inputs[0].1, 0);
+//! ```
+
+use std::fmt;
+
+use crate::{
+    interp::{self, InterpError, Outcome},
+    program::Program,
+    register::Gpr,
+    rng::Rng,
+};
+
+/// Values that break arithmetic more often than random ones do.
+const EDGE_VALUES: [u64; 8] = [
+    0,
+    1,
+    2,
+    u64::MAX,
+    i64::MAX as u64,
+    i64::MIN as u64,
+    u32::MAX as u64,
+    1 << 32,
+];
+
+/// How two runs from the same state differ.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DivergenceKind {
+    /// The original program failed from every state, so nothing was
+    /// compared; this is the first failure.
+    NoRuns(InterpError),
+    /// The transformed program fails where the original ran.
+    Invalid(InterpError),
+    /// Both ran, with different status, output or watched registers.
+    Changed { before: Outcome, after: Outcome },
+}
+
+/// The first state a pass changed the behaviour of.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// The registers the runs started with.
+    pub inputs: Vec<(Gpr, u64)>,
+    pub kind: DivergenceKind,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let DivergenceKind::NoRuns(e) = &self.kind {
+            return write!(f, "the original program fails from every state: {}", e);
+        }
+        let inputs: Vec<String> = self
+            .inputs
+            .iter()
+            .map(|(reg, value)| format!("{}={:#x}", reg, value))
+            .collect();
+        write!(f, "from {}: ", inputs.join(" "))?;
+        match &self.kind {
+            DivergenceKind::NoRuns(_) => unreachable!(),
+            DivergenceKind::Invalid(e) => write!(f, "transformed program fails: {}", e),
+            DivergenceKind::Changed { before, after } => {
+                if before.status != after.status {
+                    return write!(f, "status {} became {}", before.status, after.status);
+                }
+                if before.output != after.output {
+                    let at = before
+                        .output
+                        .iter()
+                        .zip(&after.output)
+                        .take_while(|(a, b)| a == b)
+                        .count();
+                    return write!(f, "output differs from byte {}", at);
+                }
+                write!(f, "watched registers changed")
+            }
+        }
+    }
+}
+
+/// Runs of one entry point to compare programs by.
+#[derive(Clone, Debug)]
+pub struct Equivalence {
+    entry: String,
+    inputs: Vec<Gpr>,
+    watched: Vec<Gpr>,
+    states: usize,
+    seed: u64,
+    step_limit: usize,
+}
+
+impl Equivalence {
+    /// Runs from `entry` in 64 states, varying the six System V argument
+    /// registers and watching rax, for at most a million steps each.
+    pub fn new(entry: &str) -> Self {
+        Equivalence {
+            entry: entry.to_string(),
+            inputs: vec![Gpr::RDI, Gpr::RSI, Gpr::RDX, Gpr::RCX, Gpr::R8, Gpr::R9],
+            watched: vec![Gpr::RAX],
+            states: 64,
+            seed: 0,
+            step_limit: 1_000_000,
+        }
+    }
+
+    /// Varies `inputs` instead of the argument registers. The others
+    /// start at zero.
+    pub fn with_inputs(mut self, inputs: &[Gpr]) -> Self {
+        self.inputs = inputs.to_vec();
+        self
+    }
+
+    /// Compares the final values of `watched` instead of rax. Status and
+    /// output are always compared.
+    pub fn with_watched(mut self, watched: &[Gpr]) -> Self {
+        self.watched = watched.to_vec();
+        self
+    }
+
+    pub fn with_states(mut self, states: usize) -> Self {
+        self.states = states;
+        self
+    }
+
+    /// Draws the states from `seed`; the same seed gives the same states.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_step_limit(mut self, step_limit: usize) -> Self {
+        self.step_limit = step_limit;
+        self
+    }
+
+    /// Runs `pass` over a copy of `program` and compares the two. Returns
+    /// how many states were compared.
+    pub fn check(
+        &self,
+        program: &Program,
+        pass: impl FnOnce(&mut Program),
+    ) -> Result<usize, Box<Divergence>> {
+        let mut after = program.clone();
+        pass(&mut after);
+        self.compare(program, &after)
+    }
+
+    /// Compares `before` and `after` from each state. States the original
+    /// fails from, by running out of steps or reading memory an input
+    /// pointed nowhere, are skipped, unless it fails from all of them.
+    /// Returns how many states were compared.
+    pub fn compare(&self, before: &Program, after: &Program) -> Result<usize, Box<Divergence>> {
+        let mut rng = Rng::stream(self.seed, "equiv");
+        let mut compared = 0;
+        let mut first_error = None;
+        for state in 0..self.states {
+            let inputs = self.state(state, &mut rng);
+            let divergence = |kind| {
+                Box::new(Divergence {
+                    inputs: inputs.clone(),
+                    kind,
+                })
+            };
+
+            let expected = match interp::run_with(before, &self.entry, &inputs, self.step_limit) {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                    continue;
+                }
+            };
+            let found = interp::run_with(after, &self.entry, &inputs, self.step_limit)
+                .map_err(|e| divergence(DivergenceKind::Invalid(e)))?;
+            if !self.same(&expected, &found) {
+                return Err(divergence(DivergenceKind::Changed {
+                    before: expected,
+                    after: found,
+                }));
+            }
+            compared += 1;
+        }
+
+        match first_error {
+            Some(e) if compared == 0 => Err(Box::new(Divergence {
+                inputs: Vec::new(),
+                kind: DivergenceKind::NoRuns(e),
+            })),
+            _ => Ok(compared),
+        }
+    }
+
+    /// The inputs for state `index`: all zero first, then each register
+    /// through the edge values in turn, then random mixes of edge values,
+    /// small numbers and arbitrary ones.
+    fn state(&self, index: usize, rng: &mut Rng) -> Vec<(Gpr, u64)> {
+        let edges = index.wrapping_sub(1);
+        let sweep = self.inputs.len() * EDGE_VALUES.len();
+        self.inputs
+            .iter()
+            .enumerate()
+            .map(|(i, &reg)| {
+                let value = match index {
+                    0 => 0,
+                    _ if edges < sweep => match edges / EDGE_VALUES.len() == i {
+                        true => EDGE_VALUES[edges % EDGE_VALUES.len()],
+                        false => 0,
+                    },
+                    _ => match rng.below(3) {
+                        0 => rng.pick(&EDGE_VALUES),
+                        1 => rng.below(256) as u64,
+                        _ => rng.next_u64(),
+                    },
+                };
+                (reg, value)
+            })
+            .collect()
+    }
+
+    fn same(&self, before: &Outcome, after: &Outcome) -> bool {
+        before.status == after.status
+            && before.output == after.output
+            && self.watched.iter().all(|reg| {
+                let i = reg.index() as usize;
+                before.registers[i] == after.registers[i]
+            })
+    }
+}
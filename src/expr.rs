@@ -0,0 +1,342 @@
+use std::{collections::HashMap, error, fmt, ops};
+
+/// An integer expression over literals and named constants, usable
+/// wherever an immediate is expected.
+///
+/// Expressions render in NASM syntax, so unresolved names are left for the
+/// assembler (via the program's `%define` lines), or they can be folded to
+/// literals up front with [`ConstExpr::eval`].
+#[derive(Clone, PartialEq)]
+pub enum ConstExpr {
+    Int(i64),
+    Symbol(String),
+    Neg(Box<ConstExpr>),
+    Binary(BinOp, Box<ConstExpr>, Box<ConstExpr>),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Shl,
+    Shr,
+    And,
+    Or,
+    Xor,
+}
+
+impl BinOp {
+    fn symbol(self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Shl => "<<",
+            BinOp::Shr => ">>",
+            BinOp::And => "&",
+            BinOp::Or => "|",
+            BinOp::Xor => "^",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExprError {
+    Undefined(String),
+    Cycle(String),
+    DivisionByZero,
+    Overflow,
+    /// Text passed to [`ConstExpr::parse`] is not an expression.
+    Syntax(String),
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExprError::Undefined(name) => write!(f, "undefined constant `{}`", name),
+            ExprError::Cycle(name) => {
+                write!(f, "constant `{}` is defined in terms of itself", name)
+            }
+            ExprError::DivisionByZero => write!(f, "division by zero in constant expression"),
+            ExprError::Overflow => write!(f, "constant expression overflows 64 bits"),
+            ExprError::Syntax(text) => write!(f, "cannot parse `{}` as an expression", text),
+        }
+    }
+}
+
+impl error::Error for ExprError {}
+
+impl ConstExpr {
+    pub fn sym(name: &str) -> Self {
+        ConstExpr::Symbol(name.to_string())
+    }
+
+    /// Folds the expression to a literal, looking names up in `defines`.
+    /// Names may refer to other defines, in any order.
+    pub fn eval(&self, defines: &HashMap<String, ConstExpr>) -> Result<i64, ExprError> {
+        self.eval_inner(defines, &mut Vec::new())
+    }
+
+    fn eval_inner<'a>(
+        &'a self,
+        defines: &'a HashMap<String, ConstExpr>,
+        visiting: &mut Vec<&'a str>,
+    ) -> Result<i64, ExprError> {
+        match self {
+            ConstExpr::Int(v) => Ok(*v),
+            ConstExpr::Symbol(name) => {
+                if visiting.contains(&name.as_str()) {
+                    return Err(ExprError::Cycle(name.clone()));
+                }
+                let expr = defines
+                    .get(name)
+                    .ok_or_else(|| ExprError::Undefined(name.clone()))?;
+
+                visiting.push(name);
+                let value = expr.eval_inner(defines, visiting);
+                visiting.pop();
+                value
+            }
+            ConstExpr::Neg(inner) => inner
+                .eval_inner(defines, visiting)?
+                .checked_neg()
+                .ok_or(ExprError::Overflow),
+            ConstExpr::Binary(op, lhs, rhs) => {
+                let l = lhs.eval_inner(defines, visiting)?;
+                let r = rhs.eval_inner(defines, visiting)?;
+                let value = match op {
+                    BinOp::Add => l.checked_add(r),
+                    BinOp::Sub => l.checked_sub(r),
+                    BinOp::Mul => l.checked_mul(r),
+                    BinOp::Div if r == 0 => return Err(ExprError::DivisionByZero),
+                    BinOp::Div => l.checked_div(r),
+                    BinOp::Shl => u32::try_from(r).ok().and_then(|r| l.checked_shl(r)),
+                    BinOp::Shr => u32::try_from(r).ok().and_then(|r| l.checked_shr(r)),
+                    BinOp::And => Some(l & r),
+                    BinOp::Or => Some(l | r),
+                    BinOp::Xor => Some(l ^ r),
+                };
+                value.ok_or(ExprError::Overflow)
+            }
+        }
+    }
+
+    /// Calls `f` with the name of every symbol the expression uses.
+    pub fn visit_symbols(&self, f: &mut dyn FnMut(&str)) {
+        match self {
+            ConstExpr::Int(_) => {}
+            ConstExpr::Symbol(name) => f(name),
+            ConstExpr::Neg(inner) => inner.visit_symbols(f),
+            ConstExpr::Binary(_, lhs, rhs) => {
+                lhs.visit_symbols(f);
+                rhs.visit_symbols(f);
+            }
+        }
+    }
+
+    /// Renames every symbol for which `rename` returns a replacement.
+    pub fn rename_symbols(&mut self, rename: &dyn Fn(&str) -> Option<String>) {
+        match self {
+            ConstExpr::Int(_) => {}
+            ConstExpr::Symbol(name) => {
+                if let Some(new) = rename(name) {
+                    *name = new;
+                }
+            }
+            ConstExpr::Neg(inner) => inner.rename_symbols(rename),
+            ConstExpr::Binary(_, lhs, rhs) => {
+                lhs.rename_symbols(rename);
+                rhs.rename_symbols(rename);
+            }
+        }
+    }
+
+    fn binary(self, op: BinOp, rhs: ConstExpr) -> Self {
+        ConstExpr::Binary(op, Box::new(self), Box::new(rhs))
+    }
+
+    /// Parses a NASM integer expression, such as the right-hand side of an
+    /// `equ`. `$` and `$$` are read as symbols of those names.
+    pub fn parse(text: &str) -> Result<Self, ExprError> {
+        let tokens = tokenize(text).ok_or_else(|| ExprError::Syntax(text.to_string()))?;
+        let mut parser = Parser { tokens, pos: 0 };
+        match parser.expr(0) {
+            Some(expr) if parser.pos == parser.tokens.len() => Ok(expr),
+            _ => Err(ExprError::Syntax(text.to_string())),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Token {
+    Int(i64),
+    Name(String),
+    Op(&'static str),
+}
+
+/// Operators from loosest to tightest binding, as NASM groups them.
+const PRECEDENCE: &[&[(&str, BinOp)]] = &[
+    &[("|", BinOp::Or)],
+    &[("^", BinOp::Xor)],
+    &[("&", BinOp::And)],
+    &[("<<", BinOp::Shl), (">>", BinOp::Shr)],
+    &[("+", BinOp::Add), ("-", BinOp::Sub)],
+    &[("*", BinOp::Mul), ("/", BinOp::Div)],
+];
+
+fn tokenize(text: &str) -> Option<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let c = rest.chars().next()?;
+        let len = if let Some(op) = ["<<", ">>", "|", "^", "&", "+", "-", "*", "/", "(", ")"]
+            .into_iter()
+            .find(|op| rest.starts_with(op))
+        {
+            tokens.push(Token::Op(op));
+            op.len()
+        } else if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            tokens.push(Token::Int(number(&rest[..len])?));
+            len
+        } else if c.is_alphabetic() || "_.?$@".contains(c) {
+            let len = rest
+                .find(|c: char| !c.is_alphanumeric() && !"_.?$@#~".contains(c))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Name(rest[..len].to_string()));
+            len
+        } else {
+            return None;
+        };
+        rest = rest[len..].trim_start();
+    }
+    Some(tokens)
+}
+
+fn number(text: &str) -> Option<i64> {
+    let text = text.replace('_', "");
+    let (digits, radix) = if let Some(hex) = text.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(hex) = text.strip_suffix('h') {
+        (hex, 16)
+    } else if let Some(bin) = text.strip_prefix("0b") {
+        (bin, 2)
+    } else {
+        (text.as_str(), 10)
+    };
+    u64::from_str_radix(digits, radix).ok().map(|v| v as i64)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn eat(&mut self, op: &str) -> bool {
+        let found = matches!(self.tokens.get(self.pos), Some(Token::Op(o)) if *o == op);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    /// Operands joined by operators of precedence `level` or tighter.
+    fn expr(&mut self, level: usize) -> Option<ConstExpr> {
+        let Some(ops) = PRECEDENCE.get(level) else {
+            return self.unary();
+        };
+        let mut lhs = self.expr(level + 1)?;
+        'outer: loop {
+            for &(symbol, op) in *ops {
+                if self.eat(symbol) {
+                    lhs = lhs.binary(op, self.expr(level + 1)?);
+                    continue 'outer;
+                }
+            }
+            return Some(lhs);
+        }
+    }
+
+    fn unary(&mut self) -> Option<ConstExpr> {
+        if self.eat("-") {
+            return Some(-self.unary()?);
+        }
+        if self.eat("+") {
+            return self.unary();
+        }
+        if self.eat("(") {
+            let inner = self.expr(0)?;
+            return self.eat(")").then_some(inner);
+        }
+        let token = self.tokens.get(self.pos)?.clone();
+        self.pos += 1;
+        match token {
+            Token::Int(v) => Some(ConstExpr::Int(v)),
+            Token::Name(name) => Some(ConstExpr::Symbol(name)),
+            Token::Op(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for ConstExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConstExpr::Int(v) => write!(f, "{}", v),
+            ConstExpr::Symbol(name) => write!(f, "{}", name),
+            ConstExpr::Neg(inner) => write!(f, "-({})", inner),
+            ConstExpr::Binary(op, lhs, rhs) => write!(f, "({} {} {})", lhs, op.symbol(), rhs),
+        }
+    }
+}
+
+impl From<i64> for ConstExpr {
+    fn from(value: i64) -> Self {
+        ConstExpr::Int(value)
+    }
+}
+
+impl From<&str> for ConstExpr {
+    fn from(name: &str) -> Self {
+        ConstExpr::sym(name)
+    }
+}
+
+macro_rules! impl_binop {
+    ($($trait:ident, $method:ident => $op:ident;)*) => {
+        $(
+            impl<T: Into<ConstExpr>> ops::$trait<T> for ConstExpr {
+                type Output = ConstExpr;
+
+                fn $method(self, rhs: T) -> ConstExpr {
+                    self.binary(BinOp::$op, rhs.into())
+                }
+            }
+        )*
+    };
+}
+
+impl_binop! {
+    Add, add => Add;
+    Sub, sub => Sub;
+    Mul, mul => Mul;
+    Div, div => Div;
+    Shl, shl => Shl;
+    Shr, shr => Shr;
+    BitAnd, bitand => And;
+    BitOr, bitor => Or;
+    BitXor, bitxor => Xor;
+}
+
+impl ops::Neg for ConstExpr {
+    type Output = ConstExpr;
+
+    fn neg(self) -> ConstExpr {
+        ConstExpr::Neg(Box::new(self))
+    }
+}
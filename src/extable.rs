@@ -0,0 +1,148 @@
+//! Exception tables, for kernel-style code that touches memory which may
+//! not be mapped, such as user buffers, and recovers instead of crashing.
+//!
+//! An instruction marked with [`may_fault`] names a fixup label. [`emit`]
+//! labels each marked instruction and records the pair in an exception
+//! table section: two addresses per entry, the faulting instruction then
+//! the fixup, in program order. A fault handler that finds the faulting
+//! address in the table resumes at the fixup instead.
+
+use std::collections::HashSet;
+
+use crate::{cond::BuildConfig, program::Program, qualify_label, AsmExpr, Data, Label, Section};
+
+/// The section [`emit`] writes the table to.
+pub const SECTION: &str = "ex_table";
+
+/// Marks every instruction in `expr` as one that may fault, resuming at
+/// `fixup` if it does.
+pub fn may_fault(mut expr: AsmExpr, fixup: Label) -> AsmExpr {
+    mark(&mut expr, &fixup);
+    expr
+}
+
+fn mark(expr: &mut AsmExpr, fixup: &Label) {
+    match expr {
+        AsmExpr::Instruction(inst) => inst.fixup = Some(fixup.clone()),
+        _ => {
+            for body in expr.bodies_mut() {
+                for inner in body {
+                    mark(inner, fixup);
+                }
+            }
+        }
+    }
+}
+
+/// One exception table entry, by qualified label name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub instruction: String,
+    pub fixup: String,
+}
+
+struct Walk<'a> {
+    config: &'a BuildConfig,
+    defined: HashSet<String>,
+    scope: String,
+    entries: Vec<Entry>,
+    count: usize,
+}
+
+impl Walk<'_> {
+    fn body(&mut self, body: &mut Vec<AsmExpr>) {
+        let mut i = 0;
+        while i < body.len() {
+            match &mut body[i] {
+                AsmExpr::Label(label) if !label.label.starts_with('.') => {
+                    self.scope = label.label.clone();
+                }
+                AsmExpr::Instruction(inst) => {
+                    if let Some(fixup) = inst.fixup.take() {
+                        let fixup = qualify_label(&self.scope, &fixup.label);
+                        let label = self.label();
+                        self.entries.push(Entry {
+                            instruction: qualify_label(&self.scope, &label.label),
+                            fixup,
+                        });
+                        body.insert(i, AsmExpr::Label(label));
+                        i += 1;
+                    }
+                }
+                AsmExpr::If {
+                    cond,
+                    then,
+                    otherwise,
+                } => {
+                    // Only the emitted arm's labels exist.
+                    let arm = if cond.eval(self.config) {
+                        then
+                    } else {
+                        otherwise
+                    };
+                    self.body(arm);
+                }
+                AsmExpr::Block(inner)
+                | AsmExpr::Region { body: inner, .. }
+                | AsmExpr::Macro { body: inner, .. } => self.body(inner),
+                _ => {}
+            }
+            i += 1;
+        }
+    }
+
+    /// A label for the next faulting instruction: local, so it leaves the
+    /// scope of the labels around it alone, unless there is no scope yet.
+    fn label(&mut self) -> Label {
+        let label = loop {
+            let name = format!(".fault{}", self.count);
+            self.count += 1;
+            let label = match self.scope.is_empty() {
+                true => Label::plain(&format!("extable{}", name)),
+                false => Label::plain(&name),
+            };
+            let qualified = qualify_label(&self.scope, &label.label);
+            if self.defined.insert(qualified) {
+                break label;
+            }
+        };
+        if !label.label.starts_with('.') {
+            self.scope = label.label.clone();
+        }
+        label
+    }
+}
+
+/// Labels every instruction marked by [`may_fault`] and appends its entry
+/// to the [`SECTION`] section, creating it after the others if needed.
+/// The marks are consumed, so running this again only adds instructions
+/// marked since. Returns the new entries.
+pub fn emit(program: &mut Program) -> Vec<Entry> {
+    let mut walk = Walk {
+        config: &program.config,
+        defined: program.defined_symbols(),
+        scope: String::new(),
+        entries: Vec::new(),
+        count: 0,
+    };
+    for section in &mut program.sections {
+        walk.body(section.body_mut());
+    }
+    let entries = walk.entries;
+
+    if entries.is_empty() {
+        return entries;
+    }
+    let data = entries.iter().flat_map(|entry| {
+        [&entry.instruction, &entry.fixup]
+            .map(|name| AsmExpr::Data(Data::Address(Label::plain(name))))
+    });
+    match program.sections.iter_mut().find(|s| s.name == SECTION) {
+        Some(section) => section.body_mut().extend(data),
+        None => {
+            let body = data.collect();
+            program.sections.push(Section::new(SECTION, body));
+        }
+    }
+    entries
+}
@@ -0,0 +1,475 @@
+//! Instructions that need their operands in particular registers.
+//!
+//! x86 shifts only by cl, divides only rdx:rax, leaving the quotient in
+//! rax and the remainder in rdx, and takes system call arguments in
+//! registers of its own choosing. Code that picks registers without
+//! regard to that, as an allocator does, writes these instructions with
+//! every operand explicit, in any general-purpose register:
+//!
+//! - `shl dst, count` and the other shifts and rotates with the count in
+//!   a register other than cl;
+//! - `div quotient, remainder, dividend, divisor`, and the same for
+//!   `idiv`, dividing a 64-bit dividend;
+//! - `syscall result, number, args...`, with up to six arguments passed
+//!   as Linux takes them.
+//!
+//! [`fix_registers`] rewrites each into the real instruction and the
+//! copies that put its operands where it needs them and its results where
+//! they were asked for. Every other register keeps its value: one the
+//! rewrite overwrites while it is live is copied to a dead register and
+//! back, or pushed and popped if none is dead, so code keeping values in
+//! the red zone below rsp should not leave these forms to be fixed.
+//! Liveness is followed to the end of straight-line code, past which
+//! everything is taken to be live, and `ret` is taken to return by the
+//! System V convention:
+//!
+//! ```
+//! use cataclysm::{consts::{RAX, RBX}, fixed::fix_body, instr, AsmExpr};
+//!
+//! let mut body = vec![instr::shl(RAX, RBX), instr::ret()];
+//! assert_eq!(fix_body(&mut body), Ok(1));
+//! let AsmExpr::Block(code) = &body[0] else { panic!() };
+//! let lines: Vec<String> = code.iter().map(|expr| expr.to_string().trim().replace('\t', " ")).collect();
+//! // rcx is dead once the function returns, so needs no saving.
+//! assert_eq!(lines, ["mov rcx, rbx", "shl rax, cl"]);
+//! ```
+
+use std::{error, fmt};
+
+use crate::{
+    callconv::below,
+    consts::{CL, EDX},
+    dataflow::{split_prefix, straight_line_liveness, RegSet},
+    instr::{cqo, lea, mov, pop, push, xchg, xor},
+    mnemonic::Amd64Mnemonic,
+    program::Program,
+    register::{Gpr, GprWidth, RegClass},
+    syscall::ARGUMENT_REGISTERS,
+    Amd64Instruction, Amd64Register, AsmExpr, ImmediateValue, Mem, Operand,
+};
+
+const SHIFTS: &[&str] = &["shl", "sal", "shr", "sar", "rol", "ror", "rcl", "rcr"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FixedError {
+    /// Operand `index`, counting from 1, of `instruction` is of a kind it
+    /// cannot take there, such as an immediate divisor or a 32-bit
+    /// quotient.
+    Operand { instruction: String, index: usize },
+    /// The quotient and remainder of a division are the same register.
+    SameDestination(String),
+    /// A system call with more than six arguments.
+    TooManyArguments(String),
+}
+
+impl fmt::Display for FixedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FixedError::Operand { instruction, index } => write!(
+                f,
+                "`{}` cannot take operand {}",
+                instruction.replace('\t', " "),
+                index
+            ),
+            FixedError::SameDestination(instruction) => write!(
+                f,
+                "`{}` puts its quotient and remainder in the same register",
+                instruction.replace('\t', " ")
+            ),
+            FixedError::TooManyArguments(instruction) => write!(
+                f,
+                "`{}` passes more than {} arguments",
+                instruction.replace('\t', " "),
+                ARGUMENT_REGISTERS.len()
+            ),
+        }
+    }
+}
+
+impl error::Error for FixedError {}
+
+/// Rewrites the fixed-register forms in the program's text sections, in
+/// both arms of conditionals, and returns how many it rewrote.
+pub fn fix_registers(program: &mut Program) -> Result<usize, FixedError> {
+    let mut fixed = 0;
+    for section in program
+        .sections
+        .iter_mut()
+        .filter(|s| s.name.starts_with("text"))
+    {
+        fixed += fix_body(section.body_mut())?;
+    }
+    Ok(fixed)
+}
+
+/// Rewrites the fixed-register forms in `body`, each into a block, and
+/// returns how many it rewrote.
+pub fn fix_body(body: &mut [AsmExpr]) -> Result<usize, FixedError> {
+    let mut fixed = 0;
+    for i in 0..body.len() {
+        let lowering = match &body[i] {
+            AsmExpr::Instruction(inst) => Lowering::of(inst)?,
+            _ => None,
+        };
+        match lowering {
+            Some(lowering) => {
+                let (live, _) = straight_line_liveness(&body[i + 1..]);
+                let mut code = lowering.emit(live);
+                body[i] = match code.len() {
+                    1 => code.remove(0),
+                    _ => AsmExpr::Block(code),
+                };
+                fixed += 1;
+            }
+            None => {
+                for inner in body[i].bodies_mut() {
+                    fixed += fix_body(inner)?;
+                }
+            }
+        }
+    }
+    Ok(fixed)
+}
+
+/// A fixed-register form taken apart.
+struct Lowering {
+    /// The real instruction, whose operands include the fixed registers.
+    inst: Amd64Instruction,
+    /// Operands of `inst` that may be any register but one in `fixed`.
+    free: Vec<usize>,
+    /// The free operand `inst` writes, if any.
+    written: Option<usize>,
+    /// Registers loaded before `inst`, with what, as if all sources were
+    /// read first.
+    inputs: Vec<(Gpr, Operand)>,
+    /// Code between the loads and `inst`, such as `cqo`.
+    setup: Vec<AsmExpr>,
+    /// Registers copied from after `inst`, by the register holding the
+    /// result.
+    outputs: Vec<(Gpr, Gpr)>,
+    /// Registers `inst` reads or writes without their being free operands.
+    fixed: RegSet,
+}
+
+impl Lowering {
+    fn of(inst: &Amd64Instruction) -> Result<Option<Self>, FixedError> {
+        let (_, mnemonic) = split_prefix(inst.mnemonic.as_str());
+        let ops = &inst.operands;
+        let error = |index| FixedError::Operand {
+            instruction: inst.to_string(),
+            index,
+        };
+        let whole = |index: usize| match &ops[index] {
+            Operand::Register(reg) => reg.gpr().ok_or_else(|| error(index + 1)),
+            _ => Err(error(index + 1)),
+        };
+        let source = |index: usize| match &ops[index] {
+            Operand::Register(reg) if reg.gpr().is_none() => Err(error(index + 1)),
+            Operand::Param(_) | Operand::Immediate(ImmediateValue::Bytes(_)) => {
+                Err(error(index + 1))
+            }
+            op => Ok(op.clone()),
+        };
+
+        let lowering = match mnemonic {
+            m if SHIFTS.contains(&m) && ops.len() == 2 => {
+                let Operand::Register(count) = &ops[1] else {
+                    return Ok(None);
+                };
+                if matches!(count, Amd64Register::Partial(Gpr::RCX, GprWidth::Byte)) {
+                    return Ok(None);
+                }
+                let count = count.containing_gpr().ok_or_else(|| error(2))?;
+                if count == Gpr::RSP {
+                    return Err(error(2));
+                }
+                let mut shift = inst.clone();
+                shift.operands[1] = CL.into();
+                match count {
+                    // Any width of rcx shifts by cl.
+                    Gpr::RCX => Lowering::new(shift),
+                    _ => Lowering {
+                        free: vec![0],
+                        written: Some(0),
+                        inputs: vec![(Gpr::RCX, register(count).into())],
+                        fixed: RegSet::of(&[Gpr::RCX]),
+                        ..Lowering::new(shift)
+                    },
+                }
+            }
+            "div" | "idiv" if ops.len() == 4 => {
+                let (quotient, remainder) = (whole(0)?, whole(1)?);
+                if quotient == remainder {
+                    return Err(FixedError::SameDestination(inst.to_string()));
+                }
+                let divisor = match &ops[3] {
+                    Operand::Register(reg) if reg.gpr().is_some() => ops[3].clone(),
+                    Operand::Memory(mem) => mem.clone().with_size(GprWidth::Qword).into(),
+                    _ => return Err(error(4)),
+                };
+                let setup = match mnemonic {
+                    "div" => xor(EDX, EDX),
+                    _ => cqo(),
+                };
+                Lowering {
+                    free: vec![0],
+                    inputs: vec![(Gpr::RAX, source(2)?)],
+                    setup: vec![setup],
+                    outputs: vec![(quotient, Gpr::RAX), (remainder, Gpr::RDX)],
+                    fixed: RegSet::of(&[Gpr::RAX, Gpr::RDX]),
+                    ..Lowering::new(Amd64Instruction::new(inst.kind(), vec![divisor]))
+                }
+            }
+            "syscall" if ops.len() >= 2 => {
+                let result = whole(0)?;
+                let args = ops.len() - 2;
+                if args > ARGUMENT_REGISTERS.len() {
+                    return Err(FixedError::TooManyArguments(inst.to_string()));
+                }
+                let mut inputs = vec![(Gpr::RAX, source(1)?)];
+                for (i, &reg) in ARGUMENT_REGISTERS[..args].iter().enumerate() {
+                    inputs.push((reg, source(i + 2)?));
+                }
+                let mut fixed = RegSet::of(&[Gpr::RAX, Gpr::RCX, Gpr::R11]);
+                fixed.extend(ARGUMENT_REGISTERS[..args].iter().copied());
+                Lowering {
+                    inputs,
+                    outputs: vec![(result, Gpr::RAX)],
+                    fixed,
+                    ..Lowering::new(Amd64Instruction::new(Amd64Mnemonic::Syscall, vec![]))
+                }
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(lowering))
+    }
+
+    fn new(inst: Amd64Instruction) -> Self {
+        Lowering {
+            inst,
+            free: Vec::new(),
+            written: None,
+            inputs: Vec::new(),
+            setup: Vec::new(),
+            outputs: Vec::new(),
+            fixed: RegSet::EMPTY,
+        }
+    }
+
+    /// The code doing what the form did, given the registers live after
+    /// it.
+    fn emit(mut self, live: RegSet) -> Vec<AsmExpr> {
+        // Registers any part of the form names, which no temporary or
+        // save may be.
+        let mut named = self.fixed;
+        named.insert(Gpr::RSP);
+        for op in self
+            .inst
+            .operands
+            .iter()
+            .chain(self.inputs.iter().map(|(_, op)| op))
+        {
+            named = named.union(reads(op));
+        }
+        named.extend(self.outputs.iter().map(|&(dst, _)| dst));
+
+        // Free operands reading a fixed register read a copy instead.
+        for i in self.free.clone() {
+            for reg in reads(&self.inst.operands[i])
+                .intersection(self.fixed)
+                .iter()
+            {
+                let temp = pick(named, live);
+                named.insert(temp);
+                self.inputs.push((temp, register(reg).into()));
+                let operand = &mut self.inst.operands[i];
+                if self.written == Some(i) && reads_directly(operand, reg) {
+                    self.outputs.push((reg, temp));
+                }
+                rename(operand, reg, temp);
+            }
+        }
+
+        let mut writes = self.fixed;
+        writes.extend(self.inputs.iter().map(|&(dst, _)| dst));
+        let mut results = RegSet::EMPTY;
+        results.extend(self.outputs.iter().map(|&(dst, _)| dst));
+        let saved = writes.intersection(live).difference(results);
+
+        let (mut before, mut after) = (Vec::new(), Vec::new());
+        let mut pushed = 0;
+        for reg in saved.iter() {
+            match spares().find(|&gpr| !named.union(live).contains(gpr)) {
+                Some(spare) => {
+                    named.insert(spare);
+                    before.push(mov(register(spare), register(reg)));
+                    after.push(mov(register(reg), register(spare)));
+                }
+                None => {
+                    before.push(push(register(reg)));
+                    after.push(pop(reg));
+                    pushed += 8;
+                }
+            }
+        }
+        after.reverse();
+
+        let inputs = self
+            .inputs
+            .into_iter()
+            .map(|(dst, src)| (dst, below(&src, pushed)))
+            .collect();
+        for op in self.inst.operands.iter_mut() {
+            *op = below(op, pushed);
+        }
+
+        let mut code = before;
+        code.extend(parallel_move(inputs));
+        code.extend(self.setup);
+        code.push(AsmExpr::Instruction(self.inst));
+        code.extend(parallel_move(
+            self.outputs
+                .into_iter()
+                .map(|(dst, src)| (dst, register(src).into()))
+                .collect(),
+        ));
+        code.extend(after);
+        code
+    }
+}
+
+/// The registers a copy may be kept in.
+pub(crate) fn spares() -> impl Iterator<Item = Gpr> {
+    RegClass::GprNoRsp
+        .members()
+        .into_iter()
+        .filter_map(|reg| reg.gpr())
+}
+
+/// A register outside `named`, dead if any is.
+pub(crate) fn pick(named: RegSet, live: RegSet) -> Gpr {
+    spares()
+        .filter(|&gpr| !named.contains(gpr))
+        .min_by_key(|&gpr| live.contains(gpr))
+        .expect("a form names at most twelve registers")
+}
+
+/// Moves each source into its register as if all were read first. A
+/// cycle of registers is broken by swapping two; one through memory, by
+/// pushing a source and popping it into place at the end.
+pub(crate) fn parallel_move(mut pending: Vec<(Gpr, Operand)>) -> Vec<AsmExpr> {
+    let source = |src: &Operand| match src {
+        Operand::Register(reg) => reg.gpr(),
+        _ => None,
+    };
+    let mut code = Vec::new();
+    let mut popped = Vec::new();
+    let mut pushed = 0;
+    loop {
+        pending.retain(|(dst, src)| source(src) != Some(*dst));
+        if pending.is_empty() {
+            break;
+        }
+        let free = (0..pending.len()).find(|&i| {
+            let dst = pending[i].0;
+            pending
+                .iter()
+                .enumerate()
+                .all(|(j, (_, src))| i == j || !reads(src).contains(dst))
+        });
+        if let Some(i) = free {
+            let (dst, src) = pending.remove(i);
+            code.push(load(dst, below(&src, pushed)));
+            continue;
+        }
+        match pending.iter().position(|(_, src)| source(src).is_some()) {
+            Some(i) => {
+                let (dst, src) = pending.remove(i);
+                let src = source(&src).unwrap();
+                code.push(xchg(register(dst), register(src)));
+                for (_, op) in pending.iter_mut() {
+                    swap(op, dst, src);
+                }
+            }
+            None => {
+                let (dst, src) = pending.remove(0);
+                let Operand::Memory(mem) = below(&src, pushed) else {
+                    unreachable!("only memory sources read registers")
+                };
+                code.push(push(mem.with_size(GprWidth::Qword)));
+                popped.push(dst);
+                pushed += 8;
+            }
+        }
+    }
+    code.extend(popped.into_iter().rev().map(pop));
+    code
+}
+
+/// Loads `src` into `dst`, taking a label's address relative to rip.
+pub(crate) fn load(dst: Gpr, src: Operand) -> AsmExpr {
+    match src {
+        Operand::Immediate(ImmediateValue::Label(label)) => lea(dst, Mem::label(label)),
+        src => mov(register(dst), src),
+    }
+}
+
+pub(crate) fn register(gpr: Gpr) -> Amd64Register {
+    Amd64Register::GeneralPurpose(gpr)
+}
+
+/// The registers evaluating `operand` reads, in full or in part.
+pub(crate) fn reads(operand: &Operand) -> RegSet {
+    let mut set = RegSet::EMPTY;
+    match operand {
+        Operand::Register(reg) => set.extend(reg.containing_gpr()),
+        Operand::Memory(mem) => {
+            for reg in mem.registers() {
+                set.extend(reg.containing_gpr());
+            }
+        }
+        _ => {}
+    }
+    set
+}
+
+/// Whether `operand` is all or part of `reg` itself rather than an
+/// address formed from it.
+pub(crate) fn reads_directly(operand: &Operand, reg: Gpr) -> bool {
+    matches!(operand, Operand::Register(r) if r.containing_gpr() == Some(reg))
+}
+
+/// Makes `operand` read `to` wherever it read `from`, at the same width.
+pub(crate) fn rename(operand: &mut Operand, from: Gpr, to: Gpr) {
+    map_registers(operand, |gpr| if gpr == from { to } else { gpr });
+}
+
+/// Makes `operand` read `b` wherever it read `a`, and `a` wherever `b`.
+fn swap(operand: &mut Operand, a: Gpr, b: Gpr) {
+    map_registers(operand, |gpr| match gpr {
+        gpr if gpr == a => b,
+        gpr if gpr == b => a,
+        gpr => gpr,
+    });
+}
+
+pub(crate) fn map_registers(operand: &mut Operand, map: impl Fn(Gpr) -> Gpr) {
+    let apply = |reg: &mut Amd64Register| match *reg {
+        Amd64Register::Partial(gpr, width) => *reg = Amd64Register::Partial(map(gpr), width),
+        _ => {
+            if let Some(gpr) = reg.gpr() {
+                *reg = register(map(gpr));
+            }
+        }
+    };
+    match operand {
+        Operand::Register(reg) => apply(reg),
+        Operand::Memory(mem) => {
+            mem.base
+                .iter_mut()
+                .chain(mem.index.iter_mut())
+                .for_each(apply);
+        }
+        _ => {}
+    }
+}
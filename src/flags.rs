@@ -0,0 +1,367 @@
+//! Per-flag effects of instructions and flag liveness, finer than the
+//! single flags bit of [`dataflow`]: which of the status
+//! flags each instruction reads, computes, forces to a value or leaves
+//! undefined.
+//!
+//! Rewrites that change how flags are produced check against this. `add`
+//! can become `lea` only where none of the flags `add` computes are live
+//! afterwards, and `test` can replace `cmp reg, 0` only where OF and CF,
+//! which `test` clears rather than computes, are not read from it.
+//!
+//! Like `dataflow`, unknown instructions are assumed to read every flag and
+//! to write none.
+
+use std::{collections::HashMap, fmt};
+
+use crate::{cfg::Function, dataflow, Amd64Instruction};
+
+/// A set of EFLAGS bits.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Flags(u8);
+
+const NAMES: [&str; 7] = ["cf", "pf", "af", "zf", "sf", "of", "df"];
+
+impl Flags {
+    pub const EMPTY: Flags = Flags(0);
+    pub const CF: Flags = Flags(1);
+    pub const PF: Flags = Flags(1 << 1);
+    pub const AF: Flags = Flags(1 << 2);
+    pub const ZF: Flags = Flags(1 << 3);
+    pub const SF: Flags = Flags(1 << 4);
+    pub const OF: Flags = Flags(1 << 5);
+    pub const DF: Flags = Flags(1 << 6);
+    /// The six flags arithmetic computes.
+    pub const STATUS: Flags = Flags(0x3f);
+    pub const ALL: Flags = Flags(0x7f);
+
+    pub fn contains(self, other: Flags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersects(self, other: Flags) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn union(self, other: Flags) -> Flags {
+        Flags(self.0 | other.0)
+    }
+
+    pub fn intersection(self, other: Flags) -> Flags {
+        Flags(self.0 & other.0)
+    }
+
+    pub fn difference(self, other: Flags) -> Flags {
+        Flags(self.0 & !other.0)
+    }
+
+    /// Names of the flags in the set, such as `zf`.
+    pub fn names(self) -> impl Iterator<Item = &'static str> {
+        NAMES
+            .into_iter()
+            .enumerate()
+            .filter(move |(i, _)| self.0 & (1 << i) != 0)
+            .map(|(_, name)| name)
+    }
+}
+
+impl fmt::Debug for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.names()).finish()
+    }
+}
+
+/// The flags a condition-code suffix such as `ne` or `be` tests.
+pub fn condition_flags(suffix: &str) -> Option<Flags> {
+    Some(match suffix {
+        "o" | "no" => Flags::OF,
+        "b" | "c" | "nae" | "ae" | "nb" | "nc" => Flags::CF,
+        "e" | "z" | "ne" | "nz" => Flags::ZF,
+        "be" | "na" | "a" | "nbe" => Flags::CF.union(Flags::ZF),
+        "s" | "ns" => Flags::SF,
+        "p" | "pe" | "np" | "po" => Flags::PF,
+        "l" | "nge" | "ge" | "nl" => Flags::SF.union(Flags::OF),
+        "le" | "ng" | "g" | "nle" => Flags::ZF.union(Flags::SF).union(Flags::OF),
+        _ => return None,
+    })
+}
+
+/// What one instruction does to each flag. A flag appears in at most one
+/// of `computes`, `clears`, `sets` and `undefined`; those not in any are
+/// left as they were.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct FlagEffects {
+    pub reads: Flags,
+    /// Flags given a value that depends on the result.
+    pub computes: Flags,
+    /// Flags always left zero.
+    pub clears: Flags,
+    /// Flags always left one.
+    pub sets: Flags,
+    /// Flags left with no defined value.
+    pub undefined: Flags,
+}
+
+impl FlagEffects {
+    /// Every flag whose previous value is lost.
+    pub fn modifies(&self) -> Flags {
+        self.computes
+            .union(self.clears)
+            .union(self.sets)
+            .union(self.undefined)
+    }
+}
+
+/// The count of a shift or rotate, if known. Without a count operand it
+/// shifts by one.
+fn shift_count(inst: &Amd64Instruction) -> Option<i64> {
+    match inst.operands.get(1) {
+        Some(op) => dataflow::constant(op, &HashMap::new()).map(|n| n & 63),
+        None => Some(1),
+    }
+}
+
+/// The flag effects of `inst`.
+pub fn flag_effects(inst: &Amd64Instruction) -> FlagEffects {
+    use Flags as F;
+
+    let computes = |computes: Flags| FlagEffects {
+        computes,
+        ..FlagEffects::default()
+    };
+    // The logic instructions and their relatives: a result-based SF, ZF and
+    // PF, with CF and OF cleared.
+    let logic = FlagEffects {
+        computes: F::SF.union(F::ZF).union(F::PF),
+        clears: F::CF.union(F::OF),
+        undefined: F::AF,
+        ..FlagEffects::default()
+    };
+    let ops = &inst.operands;
+    let mnemonic = match inst.mnemonic.as_str().split_once(' ') {
+        Some((prefix, rest)) if prefix.starts_with("rep") => rest.trim_start(),
+        _ => inst.mnemonic.as_str(),
+    };
+
+    match mnemonic {
+        "add" | "sub" | "cmp" | "neg" | "xadd" | "cmpxchg" => computes(F::STATUS),
+        "adc" | "sbb" => FlagEffects {
+            reads: F::CF,
+            ..computes(F::STATUS)
+        },
+        "inc" | "dec" => computes(F::STATUS.difference(F::CF)),
+        "and" | "or" | "xor" | "test" => logic,
+        "andn" => FlagEffects {
+            undefined: F::AF.union(F::PF),
+            computes: F::SF.union(F::ZF),
+            ..logic
+        },
+        "bextr" => FlagEffects {
+            computes: F::ZF,
+            undefined: F::AF.union(F::PF).union(F::SF),
+            ..logic
+        },
+        "popcnt" => FlagEffects {
+            computes: F::ZF,
+            clears: F::STATUS.difference(F::ZF),
+            ..FlagEffects::default()
+        },
+        "lzcnt" | "tzcnt" => FlagEffects {
+            computes: F::CF.union(F::ZF),
+            undefined: F::OF.union(F::SF).union(F::AF).union(F::PF),
+            ..FlagEffects::default()
+        },
+        "bsf" | "bsr" => FlagEffects {
+            computes: F::ZF,
+            undefined: F::STATUS.difference(F::ZF),
+            ..FlagEffects::default()
+        },
+        "bt" | "bts" | "btr" | "btc" => FlagEffects {
+            computes: F::CF,
+            undefined: F::OF.union(F::SF).union(F::AF).union(F::PF),
+            ..FlagEffects::default()
+        },
+        "mul" | "imul" => FlagEffects {
+            computes: F::CF.union(F::OF),
+            undefined: F::SF.union(F::ZF).union(F::AF).union(F::PF),
+            ..FlagEffects::default()
+        },
+        "div" | "idiv" => FlagEffects {
+            undefined: F::STATUS,
+            ..FlagEffects::default()
+        },
+        "shl" | "sal" | "shr" | "sar" | "rol" | "ror" | "rcl" | "rcr" => {
+            let rotate = matches!(mnemonic, "rol" | "ror" | "rcl" | "rcr");
+            let count = shift_count(inst);
+            let (mut computes, mut undefined) = if rotate {
+                (F::CF, F::EMPTY)
+            } else {
+                (F::CF.union(F::SF).union(F::ZF).union(F::PF), F::AF)
+            };
+            // OF is only defined for a count of one.
+            if count == Some(1) {
+                computes = computes.union(F::OF);
+            } else {
+                undefined = undefined.union(F::OF);
+            }
+            let mut fx = FlagEffects {
+                computes,
+                undefined,
+                ..FlagEffects::default()
+            };
+            if matches!(mnemonic, "rcl" | "rcr") {
+                fx.reads = F::CF;
+            }
+            // A count that may be zero leaves the old flags in place.
+            if count.unwrap_or(0) == 0 {
+                fx.reads = fx.reads.union(fx.modifies());
+            }
+            fx
+        }
+        "clc" => FlagEffects {
+            clears: F::CF,
+            ..FlagEffects::default()
+        },
+        "stc" => FlagEffects {
+            sets: F::CF,
+            ..FlagEffects::default()
+        },
+        "cmc" => FlagEffects {
+            reads: F::CF,
+            ..computes(F::CF)
+        },
+        "cld" => FlagEffects {
+            clears: F::DF,
+            ..FlagEffects::default()
+        },
+        "std" => FlagEffects {
+            sets: F::DF,
+            ..FlagEffects::default()
+        },
+        "lahf" => FlagEffects {
+            reads: F::STATUS.difference(F::OF),
+            ..FlagEffects::default()
+        },
+        "sahf" => computes(F::STATUS.difference(F::OF)),
+        "pushf" | "pushfq" => FlagEffects {
+            reads: F::ALL,
+            ..FlagEffects::default()
+        },
+        "popf" | "popfq" => computes(F::ALL),
+        // Callees may leave any status flag behind, and expect DF clear.
+        "call" => FlagEffects {
+            reads: F::DF,
+            undefined: F::STATUS,
+            ..FlagEffects::default()
+        },
+        "loope" | "loopz" | "loopne" | "loopnz" => FlagEffects {
+            reads: F::ZF,
+            ..FlagEffects::default()
+        },
+        "mov" | "movabs" | "movzx" | "movsx" | "movsxd" | "lea" | "not" | "xchg" | "push"
+        | "pop" | "leave" | "ret" | "jmp" | "loop" | "jrcxz" | "syscall" | "cpuid" | "rdtsc"
+        | "rdtscp" | "cqo" | "nop" | "lfence" | "mfence" | "sfence" | "pause" | "hlt" | "ud2"
+        | "vzeroupper" | "pdep" | "pext" | "rdfsbase" | "rdgsbase" | "prefetcht0"
+        | "prefetcht1" | "prefetcht2" | "prefetchnta" | "movnti" | "movntdq" | "movntps"
+        | "movntpd" | "clflush" | "clflushopt" | "clwb" | "invlpg" | "wbinvd" => {
+            FlagEffects::default()
+        }
+        m if ops.is_empty() && is_string_op(m) => FlagEffects {
+            reads: F::DF,
+            ..match &m[..4] {
+                "cmps" | "scas" => computes(F::STATUS),
+                _ => FlagEffects::default(),
+            }
+        },
+        m => {
+            let condition = m
+                .strip_prefix("cmov")
+                .or_else(|| m.strip_prefix("set"))
+                .or_else(|| m.strip_prefix('j'))
+                .and_then(condition_flags);
+            FlagEffects {
+                reads: condition.unwrap_or(F::ALL),
+                ..FlagEffects::default()
+            }
+        }
+    }
+}
+
+fn is_string_op(mnemonic: &str) -> bool {
+    mnemonic.len() == 5
+        && ["movs", "stos", "lods", "scas", "cmps"].contains(&&mnemonic[..4])
+        && "bwdq".contains(&mnemonic[4..])
+}
+
+/// Flags live before `inst` given those live after it.
+pub fn step_back(inst: &Amd64Instruction, live: Flags) -> Flags {
+    let fx = flag_effects(inst);
+    live.difference(fx.modifies()).union(fx.reads)
+}
+
+/// Flags live on entry to and exit from each block of a function.
+///
+/// Control leaving the function other than by returning keeps every flag
+/// live, as in [`dataflow::Liveness`].
+#[derive(Clone, Debug)]
+pub struct FlagLiveness {
+    pub live_in: Vec<Flags>,
+    pub live_out: Vec<Flags>,
+}
+
+impl FlagLiveness {
+    pub fn compute(function: &Function) -> Self {
+        let n = function.blocks.len();
+        let mut live = FlagLiveness {
+            live_in: vec![Flags::EMPTY; n],
+            live_out: vec![Flags::EMPTY; n],
+        };
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (i, block) in function.blocks.iter().enumerate().rev() {
+                let mut flags = if block.exits {
+                    Flags::ALL
+                } else {
+                    Flags::EMPTY
+                };
+                for &(to, _) in &block.successors {
+                    flags = flags.union(live.live_in[to]);
+                }
+                live.live_out[i] = flags;
+
+                for inst in block.instructions.iter().rev() {
+                    flags = step_back(inst, flags);
+                }
+                if flags != live.live_in[i] {
+                    live.live_in[i] = flags;
+                    changed = true;
+                }
+            }
+        }
+
+        live
+    }
+
+    /// Flags live just after instruction `index` of block `block`.
+    pub fn live_after(&self, function: &Function, block: usize, index: usize) -> Flags {
+        function.blocks[block].instructions[index + 1..]
+            .iter()
+            .rev()
+            .fold(self.live_out[block], |live, inst| step_back(inst, live))
+    }
+
+    /// Whether rewriting instruction `index` of block `block` into one that
+    /// leaves every flag alone, such as `add` into `lea`, keeps every flag
+    /// read later intact.
+    pub fn flags_dead_after(&self, function: &Function, block: usize, index: usize) -> bool {
+        let inst = &function.blocks[block].instructions[index];
+        !self
+            .live_after(function, block, index)
+            .intersects(flag_effects(inst).modifies())
+    }
+}
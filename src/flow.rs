@@ -0,0 +1,214 @@
+//! Structured control flow over labels and jumps.
+//!
+//! A [`FlowBuilder`] collects a function's code and writes the branches
+//! of `if`, `while` and `loop` constructs itself, nested as deeply as the
+//! closures giving their bodies are, with `break` and `continue` jumping
+//! out of or back to the innermost loop. Each construct gets fresh local
+//! labels hashed from the builder's name, so they stay in the scope of
+//! the function the code goes into:
+//!
+//! ```
+//! use cataclysm::{
+//!     consts::{RAX, RDI},
+//!     flow::{Cond, FlowBuilder},
+//!     instr::{self, CondCode},
+//!     interp,
+//!     register::Gpr,
+//!     AsmExpr, Label, Program, Section,
+//! };
+//!
+//! let mut flow = FlowBuilder::new("sum");
+//! flow.emit(instr::xor(RAX, RAX));
+//! flow.loop_(|flow| {
+//!     flow.if_(Cond::cmp(RDI, 0, CondCode::E), |flow| flow.break_(), |_| {});
+//!     flow.emit(instr::add(RAX, RDI));
+//!     flow.emit(instr::dec(RDI));
+//! });
+//! flow.emit(instr::ret());
+//!
+//! let mut body = vec![AsmExpr::Label(Label::plain("sum"))];
+//! body.extend(flow.finish().unwrap());
+//! let program = Program::default().with_section(Section::new("text", body));
+//! let out = interp::run_with(&program, "sum", &[(Gpr::RDI, 10)], 1000).unwrap();
+//! assert_eq!(out.registers[Gpr::RAX.index() as usize], 55);
+//! ```
+
+use std::{error, fmt};
+
+use crate::{
+    instr::{cmp, jcc, jmp, CondCode},
+    AsmExpr, Label, Operand,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlowError {
+    /// A `break` with no loop around it.
+    BreakOutsideLoop,
+    /// A `continue` with no loop around it.
+    ContinueOutsideLoop,
+}
+
+impl fmt::Display for FlowError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FlowError::BreakOutsideLoop => write!(f, "`break` outside a loop"),
+            FlowError::ContinueOutsideLoop => write!(f, "`continue` outside a loop"),
+        }
+    }
+}
+
+impl error::Error for FlowError {}
+
+/// A condition: code setting the flags, and the condition code that holds
+/// on them when the condition does.
+#[derive(Clone)]
+pub struct Cond {
+    test: Vec<AsmExpr>,
+    code: CondCode,
+}
+
+impl Cond {
+    /// `code` holding on the flags as `test` leaves them. An empty `test`
+    /// reads the flags as the code before leaves them.
+    pub fn new(test: Vec<AsmExpr>, code: CondCode) -> Self {
+        Cond { test, code }
+    }
+
+    /// `code` holding for `a` compared with `b`, so that
+    /// `Cond::cmp(RAX, 10, CondCode::L)` holds while rax is below 10.
+    pub fn cmp(a: impl Into<Operand>, b: impl Into<Operand>, code: CondCode) -> Self {
+        Cond::new(vec![cmp(a, b)], code)
+    }
+
+    /// The condition holding exactly when this one does not.
+    pub fn negate(mut self) -> Self {
+        self.code = self.code.negate();
+        self
+    }
+}
+
+/// Code with structured control flow, written out as jumps.
+pub struct FlowBuilder {
+    name: String,
+    code: Vec<AsmExpr>,
+    /// Where `continue` and `break` go, innermost loop last.
+    loops: Vec<(Label, Label)>,
+    constructs: usize,
+    error: Option<FlowError>,
+}
+
+impl FlowBuilder {
+    /// A builder naming its labels after `name`, which no other builder
+    /// putting code in the same function should share.
+    pub fn new(name: &str) -> Self {
+        FlowBuilder {
+            name: name.to_string(),
+            code: Vec::new(),
+            loops: Vec::new(),
+            constructs: 0,
+            error: None,
+        }
+    }
+
+    pub fn emit(&mut self, expr: AsmExpr) {
+        self.code.push(expr);
+    }
+
+    pub fn emit_all(&mut self, code: impl IntoIterator<Item = AsmExpr>) {
+        self.code.extend(code);
+    }
+
+    /// Runs the code `then` writes if `cond` holds and the code
+    /// `otherwise` writes if not.
+    pub fn if_(
+        &mut self,
+        cond: Cond,
+        then: impl FnOnce(&mut Self),
+        otherwise: impl FnOnce(&mut Self),
+    ) {
+        let [other, end] = self.labels(["else", "end"]);
+        self.test(cond, &other);
+        then(self);
+
+        let before = std::mem::take(&mut self.code);
+        otherwise(self);
+        let otherwise = std::mem::replace(&mut self.code, before);
+        if otherwise.is_empty() {
+            self.code.push(AsmExpr::Label(other));
+            return;
+        }
+        self.code.push(jmp(end.clone()));
+        self.code.push(AsmExpr::Label(other));
+        self.code.extend(otherwise);
+        self.code.push(AsmExpr::Label(end));
+    }
+
+    /// Runs the code `body` writes for as long as `cond` holds, testing it
+    /// before every pass. `continue` tests it again.
+    pub fn while_(&mut self, cond: Cond, body: impl FnOnce(&mut Self)) {
+        let [top, end] = self.labels(["while", "end"]);
+        self.code.push(AsmExpr::Label(top.clone()));
+        self.test(cond, &end);
+        self.body(&top, &end, body);
+    }
+
+    /// Runs the code `body` writes until it breaks out.
+    pub fn loop_(&mut self, body: impl FnOnce(&mut Self)) {
+        let [top, end] = self.labels(["loop", "end"]);
+        self.code.push(AsmExpr::Label(top.clone()));
+        self.body(&top, &end, body);
+    }
+
+    /// Jumps past the end of the innermost loop.
+    pub fn break_(&mut self) {
+        match self.loops.last() {
+            Some((_, end)) => self.code.push(jmp(end.clone())),
+            None => {
+                self.error.get_or_insert(FlowError::BreakOutsideLoop);
+            }
+        }
+    }
+
+    /// Jumps to the start of the innermost loop's next pass.
+    pub fn continue_(&mut self) {
+        match self.loops.last() {
+            Some((top, _)) => self.code.push(jmp(top.clone())),
+            None => {
+                self.error.get_or_insert(FlowError::ContinueOutsideLoop);
+            }
+        }
+    }
+
+    /// The code written, or the first `break` or `continue` that had no
+    /// loop to leave.
+    pub fn finish(self) -> Result<Vec<AsmExpr>, FlowError> {
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(self.code),
+        }
+    }
+
+    /// Labels private to the next construct.
+    fn labels<const N: usize>(&mut self, names: [&str; N]) -> [Label; N] {
+        let construct = self.constructs;
+        self.constructs += 1;
+        names.map(|name| {
+            let hashed = Label::hashed(&format!("{}.{}.{}", self.name, name, construct));
+            Label::plain(&format!(".{}", hashed.label))
+        })
+    }
+
+    /// Jumps to `otherwise` unless `cond` holds.
+    fn test(&mut self, cond: Cond, otherwise: &Label) {
+        self.code.extend(cond.test);
+        self.code.push(jcc(cond.code.negate(), otherwise.clone()));
+    }
+
+    fn body(&mut self, top: &Label, end: &Label, body: impl FnOnce(&mut Self)) {
+        self.loops.push((top.clone(), end.clone()));
+        body(self);
+        self.loops.pop();
+        self.code.push(jmp(top.clone()));
+        self.code.push(AsmExpr::Label(end.clone()));
+    }
+}
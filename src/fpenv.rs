@@ -0,0 +1,99 @@
+//! Control of the SSE floating-point environment through MXCSR, and the
+//! AVX upper-state instructions numeric code needs around SSE transitions.
+
+use crate::{
+    consts::RSP,
+    instr::{add, and, mov, or, sub},
+    mnemonic::Amd64Mnemonic,
+    Amd64Instruction, Amd64Register, AsmExpr, Mem, Operand,
+};
+
+/// MXCSR rounding-control field, bits 13-14.
+const ROUNDING_SHIFT: u32 = 13;
+const ROUNDING_MASK: u32 = 0b11 << ROUNDING_SHIFT;
+/// Denormal inputs are treated as zero.
+const DAZ: u32 = 1 << 6;
+/// Denormal results are flushed to zero.
+const FTZ: u32 = 1 << 15;
+
+/// IEEE rounding direction, in MXCSR encoding order.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum RoundingMode {
+    #[default]
+    Nearest,
+    Down,
+    Up,
+    TowardZero,
+}
+
+/// The parts of MXCSR that change floating-point semantics. The default
+/// matches the state a process starts in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct FpEnv {
+    pub rounding: RoundingMode,
+    pub flush_to_zero: bool,
+    pub denormals_are_zero: bool,
+}
+
+impl FpEnv {
+    /// Every MXCSR bit this environment controls.
+    pub const MASK: u32 = ROUNDING_MASK | DAZ | FTZ;
+
+    /// The MXCSR bits selected by this environment, within [`FpEnv::MASK`].
+    pub fn bits(&self) -> u32 {
+        let mut bits = (self.rounding as u32) << ROUNDING_SHIFT;
+        if self.flush_to_zero {
+            bits |= FTZ;
+        }
+        if self.denormals_are_zero {
+            bits |= DAZ;
+        }
+        bits
+    }
+}
+
+fn inst(mnemonic: &str, operands: Vec<Operand>) -> AsmExpr {
+    AsmExpr::Instruction(Amd64Instruction::new(
+        Amd64Mnemonic::parse(mnemonic),
+        operands,
+    ))
+}
+
+/// Loads MXCSR from the 32-bit memory operand `src`.
+pub fn ldmxcsr(src: impl Into<Operand>) -> AsmExpr {
+    inst("ldmxcsr", vec![src.into()])
+}
+
+/// Stores MXCSR to the 32-bit memory operand `dst`.
+pub fn stmxcsr(dst: impl Into<Operand>) -> AsmExpr {
+    inst("stmxcsr", vec![dst.into()])
+}
+
+/// Clears the upper halves of the ymm registers, avoiding the penalty
+/// for mixing AVX and legacy SSE code.
+pub fn vzeroupper() -> AsmExpr {
+    inst("vzeroupper", vec![])
+}
+
+pub fn vzeroall() -> AsmExpr {
+    inst("vzeroall", vec![])
+}
+
+/// Switches MXCSR to `env`, leaving the exception masks and flags alone.
+/// The current value is read through an 8-byte stack temporary and edited
+/// in `scratch`, which is clobbered along with the arithmetic flags.
+pub fn set_fp_env(env: FpEnv, scratch: Amd64Register) -> AsmExpr {
+    let slot = || Mem::base(RSP);
+
+    AsmExpr::Block(vec![
+        sub(RSP, 8),
+        stmxcsr(slot()),
+        mov(scratch.clone(), slot()),
+        // The mask sign-extends, so only the targeted low bits are cleared.
+        and(scratch.clone(), !(FpEnv::MASK as i32)),
+        or(scratch.clone(), env.bits()),
+        mov(slot(), scratch),
+        ldmxcsr(slot()),
+        add(RSP, 8),
+    ])
+}
@@ -0,0 +1,151 @@
+//! Local variables in an rbp frame.
+//!
+//! A [`Frame`] hands out a [`Slot`] for each local a function needs, at
+//! the alignment asked for, and turns a slot into the `[rbp - d]` operand
+//! reaching it; nothing else need know where the slot is. Once the body is
+//! written, [`Frame::function`] puts the prologue before it, reserving the
+//! locals rounded up to keep rsp 16-byte aligned, and tears the frame down
+//! before every `ret`:
+//!
+//! ```
+//! use cataclysm::{frame::Frame, instr, register::Reg64, Label};
+//!
+//! let mut frame = Frame::new();
+//! let count = frame.local(8, 8).unwrap();
+//! let buffer = frame.local(20, 16).unwrap();
+//! let body = vec![
+//!     instr::mov(frame.mem(count), 0),
+//!     instr::lea(Reg64::Rdi, frame.mem(buffer)),
+//!     instr::ret(),
+//! ];
+//! let code = frame.function(Label::plain("f"), body);
+//! let lines: Vec<String> = code.iter().map(|expr| expr.to_string().trim().replace('\t', " ")).collect();
+//! assert_eq!(
+//!     lines,
+//!     [
+//!         "f:",
+//!         "push rbp",
+//!         "mov rbp, rsp",
+//!         "sub rsp, 32",
+//!         "mov qword [rbp - 8], 0",
+//!         "lea rdi, [rbp - 32]",
+//!         "leave",
+//!         "ret",
+//!     ]
+//! );
+//! ```
+
+use std::{error, fmt};
+
+use crate::{
+    consts::{RBP, RSP},
+    instr::{leave, mov, push, ret, sub},
+    register::GprWidth,
+    AsmExpr, Label, Mem,
+};
+
+/// The most a slot can be aligned to: rbp is on a 16-byte boundary once
+/// pushed, as the return address leaves rsp 8 bytes off one.
+const MAX_ALIGNMENT: u32 = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// An alignment that is not a power of two of at most 16 bytes.
+    Alignment(u32),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::Alignment(align) => {
+                write!(f, "locals cannot be aligned to {} bytes", align)
+            }
+        }
+    }
+}
+
+impl error::Error for FrameError {}
+
+/// A local variable in a [`Frame`], by which the frame reaches it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Slot(usize);
+
+/// The locals of one function, laid out down from rbp in the order they
+/// are asked for.
+#[derive(Clone, Debug, Default)]
+pub struct Frame {
+    /// The distance below rbp of each slot's lowest byte, and its size.
+    slots: Vec<(u32, u32)>,
+    /// The bytes below rbp taken so far.
+    depth: u32,
+}
+
+impl Frame {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A slot of `size` bytes whose address is a multiple of `align`.
+    pub fn local(&mut self, size: u32, align: u32) -> Result<Slot, FrameError> {
+        if !align.is_power_of_two() || align > MAX_ALIGNMENT {
+            return Err(FrameError::Alignment(align));
+        }
+        self.depth = (self.depth + size).next_multiple_of(align);
+        self.slots.push((self.depth, size));
+        Ok(Slot(self.slots.len() - 1))
+    }
+
+    /// The operand reaching `slot`, which must be of this frame. Slots of
+    /// 1, 2, 4 or 8 bytes are accessed at their size.
+    pub fn mem(&self, slot: Slot) -> Mem {
+        let (offset, size) = self.slots[slot.0];
+        let mem = Mem::base(RBP).with_displacement(-(offset as i64));
+        match size {
+            1 => mem.with_size(GprWidth::Byte),
+            2 => mem.with_size(GprWidth::Word),
+            4 => mem.with_size(GprWidth::Dword),
+            8 => mem.with_size(GprWidth::Qword),
+            _ => mem,
+        }
+    }
+
+    /// The bytes reserved below rbp, a multiple of 16.
+    pub fn size(&self) -> u32 {
+        self.depth.next_multiple_of(16)
+    }
+
+    /// The code that builds the frame, which must start the function.
+    pub fn prologue(&self) -> Vec<AsmExpr> {
+        let mut code = vec![push(RBP), mov(RBP, RSP)];
+        if self.size() > 0 {
+            code.push(sub(RSP, self.size()));
+        }
+        code
+    }
+
+    /// Code that tears the frame down and returns.
+    pub fn epilogue(&self) -> Vec<AsmExpr> {
+        vec![leave(), ret()]
+    }
+
+    /// `name` labelling the prologue and then `body`, with the frame torn
+    /// down before every `ret` in it, those in blocks and conditionals
+    /// included.
+    pub fn function(&self, name: Label, mut body: Vec<AsmExpr>) -> Vec<AsmExpr> {
+        leave_before_ret(&mut body);
+        let mut code = vec![AsmExpr::Label(name)];
+        code.extend(self.prologue());
+        code.extend(body);
+        code
+    }
+}
+
+fn leave_before_ret(body: &mut Vec<AsmExpr>) {
+    for mut expr in std::mem::take(body) {
+        if matches!(&expr, AsmExpr::Instruction(inst) if inst.mnemonic == "ret") {
+            body.push(leave());
+        }
+        expr.bodies_mut().for_each(leave_before_ret);
+        body.push(expr);
+    }
+}
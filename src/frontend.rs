@@ -0,0 +1,276 @@
+//! The integration point for compilers that use this crate as a backend.
+//!
+//! A [`Frontend`] lowers a unit of its own intermediate representation into
+//! a [`Program`], which can then be optimized, checked, emitted as NASM or
+//! encoded like any other. [`Calc`] is a reference frontend for a tiny
+//! language of integer functions, small enough to read in one sitting:
+//!
+//! ```text
+//! ; One function per line; `;` starts a comment.
+//! square(x) = x * x
+//! mix(a, b) = (a + b) * 3 - (a >> 1)
+//! ```
+
+use std::{collections::HashMap, error, fmt};
+
+use crate::{
+    consts::{RAX, RBP, RCX, RSP},
+    dataflow::SYSV_ARGUMENTS,
+    expr::{BinOp, ConstExpr},
+    instr,
+    register::Reg64,
+    Amd64Register, AsmExpr, Global, Label, Mem, Program, Section,
+};
+
+/// A language that compiles to this crate's [`Program`].
+pub trait Frontend {
+    /// What the frontend compiles at once, such as a parsed module.
+    type Unit;
+    type Error: error::Error;
+
+    /// Adds the code and data for `unit` to `program`.
+    fn lower(&mut self, unit: &Self::Unit, program: &mut Program) -> Result<(), Self::Error>;
+}
+
+/// A new program holding `unit`, lowered by `frontend`.
+pub fn compile<F: Frontend>(frontend: &mut F, unit: &F::Unit) -> Result<Program, F::Error> {
+    let mut program = Program::default();
+    frontend.lower(unit, &mut program)?;
+    Ok(program)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CalcError {
+    /// Line `line` of the source is not a function definition.
+    Syntax { line: usize, message: String },
+    /// Two functions share a name.
+    Duplicate(String),
+    /// A function takes more parameters than fit in argument registers.
+    TooManyParameters(String),
+    /// `function` uses a name that is not one of its parameters.
+    Undefined { function: String, name: String },
+    /// `function` shifts by an amount not known at compile time, or
+    /// outside `0..64`.
+    Shift(String),
+}
+
+impl fmt::Display for CalcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CalcError::Syntax { line, message } => write!(f, "line {}: {}", line, message),
+            CalcError::Duplicate(name) => write!(f, "function `{}` is defined twice", name),
+            CalcError::TooManyParameters(name) => write!(
+                f,
+                "function `{}` takes more than {} parameters",
+                name,
+                SYSV_ARGUMENTS.len()
+            ),
+            CalcError::Undefined { function, name } => {
+                write!(f, "`{}` is not a parameter of `{}`", name, function)
+            }
+            CalcError::Shift(name) => write!(
+                f,
+                "function `{}` shifts by an amount that is not a constant in 0..64",
+                name
+            ),
+        }
+    }
+}
+
+impl error::Error for CalcError {}
+
+/// A function of the [`Calc`] language.
+#[derive(Clone)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: ConstExpr,
+}
+
+impl Function {
+    /// Parses `name(a, b) = expr`. The body uses [`ConstExpr`] syntax.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let (head, body) = text
+            .split_once('=')
+            .ok_or("expected `name(params) = expression`")?;
+        let (name, params) = head
+            .trim()
+            .strip_suffix(')')
+            .and_then(|head| head.split_once('('))
+            .ok_or("expected a parameter list")?;
+        let params: Vec<String> = params
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(str::to_string)
+            .collect();
+        let body = ConstExpr::parse(body.trim()).map_err(|e| e.to_string())?;
+        Ok(Function {
+            name: name.trim().to_string(),
+            params,
+            body,
+        })
+    }
+}
+
+/// Every function of `source`, one per line.
+pub fn parse(source: &str) -> Result<Vec<Function>, CalcError> {
+    let mut functions = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let line = line.split(';').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let function = Function::parse(line).map_err(|message| CalcError::Syntax {
+            line: i + 1,
+            message,
+        })?;
+        functions.push(function);
+    }
+    Ok(functions)
+}
+
+/// The reference frontend: compiles each [`Function`] to an exported
+/// System V function that takes its parameters as 64-bit integers and
+/// returns the body's value in rax.
+///
+/// Operators are those of [`ConstExpr`], wrapping on overflow. `/` is
+/// signed and faults on division by zero, and `>>` is arithmetic.
+/// Subexpressions without parameters are folded at compile time.
+#[derive(Clone)]
+pub struct Calc {
+    section: String,
+}
+
+impl Default for Calc {
+    fn default() -> Self {
+        Calc {
+            section: "text".to_string(),
+        }
+    }
+}
+
+impl Calc {
+    pub fn new() -> Self {
+        Calc::default()
+    }
+
+    /// Puts the code in `section` instead of `text`.
+    pub fn with_section(mut self, section: &str) -> Self {
+        self.section = section.to_string();
+        self
+    }
+
+    fn function(&self, function: &Function) -> Result<Vec<AsmExpr>, CalcError> {
+        if function.params.len() > SYSV_ARGUMENTS.len() {
+            return Err(CalcError::TooManyParameters(function.name.clone()));
+        }
+
+        // Parameters live in the frame, so the argument registers are free
+        // for `idiv` and the operand stack.
+        let mut body = vec![
+            AsmExpr::Label(Label::plain(&function.name)),
+            instr::push(RBP),
+            instr::mov(RBP, RSP),
+        ];
+        body.extend(
+            SYSV_ARGUMENTS[..function.params.len()]
+                .iter()
+                .map(|&r| instr::push(Amd64Register::from(r))),
+        );
+        self.expr(function, &function.body, &mut body)?;
+        body.push(instr::leave());
+        body.push(instr::ret());
+        Ok(body)
+    }
+
+    /// Appends code leaving the value of `expr` in rax.
+    fn expr(
+        &self,
+        function: &Function,
+        expr: &ConstExpr,
+        out: &mut Vec<AsmExpr>,
+    ) -> Result<(), CalcError> {
+        if let Some(value) = folded(expr) {
+            out.push(instr::mov(RAX, value));
+            return Ok(());
+        }
+
+        match expr {
+            ConstExpr::Int(_) => unreachable!("literals are folded above"),
+            ConstExpr::Symbol(name) => {
+                let index = function
+                    .params
+                    .iter()
+                    .position(|p| p == name)
+                    .ok_or_else(|| CalcError::Undefined {
+                        function: function.name.clone(),
+                        name: name.clone(),
+                    })?;
+                let slot = Mem::base(RBP).with_displacement(-8 * (index as i64 + 1));
+                out.push(instr::mov(RAX, slot));
+            }
+            ConstExpr::Neg(inner) => {
+                self.expr(function, inner, out)?;
+                out.push(instr::neg(RAX));
+            }
+            ConstExpr::Binary(op @ (BinOp::Shl | BinOp::Shr), lhs, rhs) => {
+                let count = folded(rhs)
+                    .filter(|n| (0..64).contains(n))
+                    .ok_or_else(|| CalcError::Shift(function.name.clone()))?;
+                self.expr(function, lhs, out)?;
+                out.push(match op {
+                    BinOp::Shl => instr::shl(RAX, count),
+                    _ => instr::sar(RAX, count),
+                });
+            }
+            ConstExpr::Binary(op, lhs, rhs) => {
+                self.expr(function, rhs, out)?;
+                out.push(instr::push(RAX));
+                self.expr(function, lhs, out)?;
+                out.push(instr::pop(Reg64::Rcx));
+                match op {
+                    BinOp::Add => out.push(instr::add(RAX, RCX)),
+                    BinOp::Sub => out.push(instr::sub(RAX, RCX)),
+                    BinOp::Mul => out.push(instr::imul(Reg64::Rax, RCX)),
+                    BinOp::And => out.push(instr::and(RAX, RCX)),
+                    BinOp::Or => out.push(instr::or(RAX, RCX)),
+                    BinOp::Xor => out.push(instr::xor(RAX, RCX)),
+                    BinOp::Div => {
+                        out.push(instr::cqo());
+                        out.push(instr::idiv(RCX));
+                    }
+                    BinOp::Shl | BinOp::Shr => unreachable!("shifts are lowered above"),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The value of `expr` if it has no parameters and evaluates cleanly.
+/// Anything that would fault or overflow is left for run time.
+fn folded(expr: &ConstExpr) -> Option<i64> {
+    expr.eval(&HashMap::new()).ok()
+}
+
+impl Frontend for Calc {
+    type Unit = Vec<Function>;
+    type Error = CalcError;
+
+    fn lower(&mut self, unit: &Self::Unit, program: &mut Program) -> Result<(), CalcError> {
+        let mut body = Vec::new();
+        for (i, function) in unit.iter().enumerate() {
+            if unit[..i].iter().any(|f| f.name == function.name) {
+                return Err(CalcError::Duplicate(function.name.clone()));
+            }
+            body.extend(self.function(function)?);
+        }
+
+        program
+            .globals
+            .extend(unit.iter().map(|f| Global::new(&f.name)));
+        program.sections.push(Section::new(&self.section, body));
+        Ok(())
+    }
+}
@@ -0,0 +1,254 @@
+//! `Arbitrary` implementations for fuzzing the emitters and passes.
+//!
+//! Generated values stay inside what the emitter can render as valid
+//! assembly: labels are identifiers, registers are the baseline sixteen,
+//! instructions use operand forms their mnemonic accepts and floats are
+//! finite. A generated [`Program`] also defines every label it references.
+
+use arbitrary::{Arbitrary, Result, Unstructured};
+
+use crate::{
+    instr::CondCode, mnemonic::Amd64Mnemonic, program::Program, register::Gpr, Amd64Instruction,
+    Amd64Register, AsmExpr, Data, Endian, Global, ImmediateValue, Label, Mem, Operand, Section,
+};
+
+const BINARY: &[Amd64Mnemonic] = &[
+    Amd64Mnemonic::Mov,
+    Amd64Mnemonic::Add,
+    Amd64Mnemonic::Sub,
+    Amd64Mnemonic::And,
+    Amd64Mnemonic::Or,
+    Amd64Mnemonic::Xor,
+    Amd64Mnemonic::Cmp,
+    Amd64Mnemonic::Test,
+];
+const UNARY: &[Amd64Mnemonic] = &[
+    Amd64Mnemonic::Push,
+    Amd64Mnemonic::Inc,
+    Amd64Mnemonic::Dec,
+    Amd64Mnemonic::Neg,
+    Amd64Mnemonic::Not,
+];
+const NULLARY: &[Amd64Mnemonic] = &[Amd64Mnemonic::Nop, Amd64Mnemonic::Cqo, Amd64Mnemonic::Leave];
+const CONDITIONS: &[CondCode] = &[
+    CondCode::O,
+    CondCode::No,
+    CondCode::B,
+    CondCode::Ae,
+    CondCode::E,
+    CondCode::Ne,
+    CondCode::Be,
+    CondCode::A,
+    CondCode::S,
+    CondCode::Ns,
+    CondCode::P,
+    CondCode::Np,
+    CondCode::L,
+    CondCode::Ge,
+    CondCode::Le,
+    CondCode::G,
+];
+
+/// Longest generated identifier, body or program, keeping inputs cheap.
+const MAX_NAME: usize = 12;
+const MAX_BODY: usize = 32;
+
+fn identifier(u: &mut Unstructured) -> Result<String> {
+    const FIRST: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ_";
+    const REST: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ_0123456789";
+
+    let len = u.int_in_range(0..=MAX_NAME - 1)?;
+    let mut name = String::from(*u.choose(FIRST)? as char);
+    for _ in 0..len {
+        name.push(*u.choose(REST)? as char);
+    }
+    Ok(name)
+}
+
+impl<'a> Arbitrary<'a> for Label {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        // Prefixed so a name can never collide with a register or keyword.
+        Ok(Label::plain(&format!("L{}", identifier(u)?)))
+    }
+}
+
+impl<'a> Arbitrary<'a> for Gpr {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Gpr::new(u.int_in_range(0..=15)?).unwrap())
+    }
+}
+
+impl<'a> Arbitrary<'a> for Amd64Register {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Amd64Register::GeneralPurpose(u.arbitrary()?))
+    }
+}
+
+impl<'a> Arbitrary<'a> for ImmediateValue {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        // Sign-extended 32-bit immediates are accepted by every form below.
+        Ok(ImmediateValue::I64(u.arbitrary::<i32>()?.into()))
+    }
+}
+
+impl<'a> Arbitrary<'a> for Operand {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(match u.int_in_range(0..=2)? {
+            0 => Operand::Register(u.arbitrary()?),
+            1 => Operand::Immediate(u.arbitrary()?),
+            _ => Operand::Memory(Mem::label(u.arbitrary()?)),
+        })
+    }
+}
+
+/// Picks an instruction, drawing label operands from `code` (branch
+/// targets) and `data` (memory operands). Falls back to `nop` when the
+/// form chosen needs a label and none is available.
+fn instruction(u: &mut Unstructured, code: &[Label], data: &[Label]) -> Result<Amd64Instruction> {
+    let mem = |u: &mut Unstructured| -> Result<Option<Operand>> {
+        if data.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Operand::Memory(Mem::label(u.choose(data)?.clone()))))
+    };
+    let reg = |u: &mut Unstructured| -> Result<Operand> { Ok(Operand::Register(u.arbitrary()?)) };
+    let nop = || Amd64Instruction::new(Amd64Mnemonic::Nop, vec![]);
+
+    Ok(match u.int_in_range(0..=4)? {
+        0 => {
+            let mnemonic = u.choose(BINARY)?;
+            let dst = reg(u)?;
+            let src = match u.int_in_range(0..=2)? {
+                0 => reg(u)?,
+                1 if *mnemonic != Amd64Mnemonic::Test => Operand::Immediate(u.arbitrary()?),
+                _ => match mem(u)? {
+                    Some(mem) => mem,
+                    None => reg(u)?,
+                },
+            };
+            Amd64Instruction::new(mnemonic.clone(), vec![dst, src])
+        }
+        1 => Amd64Instruction::new(u.choose(UNARY)?.clone(), vec![reg(u)?]),
+        2 => Amd64Instruction::new(u.choose(NULLARY)?.clone(), vec![]),
+        3 => {
+            let Ok(target) = u.choose(code) else {
+                return Ok(nop());
+            };
+            let mnemonic = match u.int_in_range(0..=2)? {
+                0 => Amd64Mnemonic::Jmp,
+                1 => Amd64Mnemonic::Call,
+                _ => Amd64Mnemonic::Jcc(*u.choose(CONDITIONS)?),
+            };
+            Amd64Instruction::new(mnemonic, vec![Operand::from(target.clone())])
+        }
+        _ => match mem(u)? {
+            Some(mem) => Amd64Instruction::new(Amd64Mnemonic::Mov, vec![mem, reg(u)?]),
+            None => nop(),
+        },
+    })
+}
+
+impl<'a> Arbitrary<'a> for Amd64Instruction {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let labels: Vec<Label> = vec![u.arbitrary()?];
+        instruction(u, &labels, &labels)
+    }
+}
+
+impl<'a> Arbitrary<'a> for Data {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let data = match u.int_in_range(0..=8)? {
+            0 => Data::Int(u.arbitrary()?),
+            1 => Data::UInt(u.arbitrary()?),
+            2 => Data::I128(u.arbitrary()?),
+            3 => Data::U128(u.arbitrary()?),
+            4 => {
+                let v: f64 = u.arbitrary()?;
+                Data::Float(if v.is_finite() { v } else { 0.0 })
+            }
+            5 => Data::Byte(u.arbitrary()?),
+            6 => Data::Word(u.arbitrary()?),
+            7 => Data::Dword(u.arbitrary()?),
+            _ => {
+                let len = u.int_in_range(1..=MAX_BODY)?;
+                Data::Bytes((0..len).map(|_| u.arbitrary()).collect::<Result<_>>()?)
+            }
+        };
+
+        Ok(if u.ratio(1, 8)? {
+            Data::Endian(*u.choose(&[Endian::Little, Endian::Big])?, Box::new(data))
+        } else {
+            data
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for AsmExpr {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(match u.int_in_range(0..=3)? {
+            0 => AsmExpr::Label(u.arbitrary()?),
+            1 => AsmExpr::Data(u.arbitrary()?),
+            2 => {
+                let len = u.int_in_range(0..=4)?;
+                AsmExpr::Block(
+                    (0..len)
+                        .map(|_| Ok(AsmExpr::Instruction(u.arbitrary()?)))
+                        .collect::<Result<_>>()?,
+                )
+            }
+            _ => AsmExpr::Instruction(u.arbitrary()?),
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Section {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let name = *u.choose(&["text", "data", "rodata"])?;
+        let len = u.int_in_range(0..=MAX_BODY)?;
+        let body = (0..len).map(|_| u.arbitrary()).collect::<Result<_>>()?;
+        Ok(Section::new(name, body))
+    }
+}
+
+fn unique_labels(u: &mut Unstructured, prefix: &str) -> Result<Vec<Label>> {
+    let len = u.int_in_range(0..=8)?;
+    Ok((0..len)
+        .map(|i| Label::plain(&format!("{}{}", prefix, i)))
+        .collect())
+}
+
+impl<'a> Arbitrary<'a> for Program {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let code = unique_labels(u, "code")?;
+        let data = unique_labels(u, "data")?;
+
+        let mut text = vec![AsmExpr::Label(Label::plain("_start"))];
+        let mut pending = code.iter();
+        let len = u.int_in_range(0..=MAX_BODY)?;
+        for _ in 0..len {
+            if u.ratio(1, 4)? {
+                if let Some(label) = pending.next() {
+                    text.push(AsmExpr::Label(label.clone()));
+                }
+            }
+            text.push(AsmExpr::Instruction(instruction(u, &code, &data)?));
+        }
+        // Every branch target must be defined somewhere.
+        text.extend(pending.cloned().map(AsmExpr::Label));
+        text.push(AsmExpr::Instruction(Amd64Instruction::new(
+            Amd64Mnemonic::Ret,
+            vec![],
+        )));
+
+        let mut rodata = Vec::new();
+        for label in data {
+            rodata.push(AsmExpr::Label(label));
+            rodata.push(AsmExpr::Data(u.arbitrary()?));
+        }
+
+        Ok(Program::new(
+            vec![Global::new("_start")],
+            vec![Section::new("text", text), Section::new("rodata", rodata)],
+        ))
+    }
+}
@@ -0,0 +1,289 @@
+//! Text output in GNU assembler syntax, for programs targeting an
+//! architecture other than x86-64 or emitted with [`SyntaxFlavor::Gas`].
+//!
+//! Labels, data, blocks, regions and conditionals are written the same
+//! way for every architecture; only instructions, comments and, on macOS,
+//! section names differ. Instructions for another architecture than the
+//! target's become `.error` lines, so the assembler stops there.
+//!
+//! [`SyntaxFlavor::Gas`]: crate::SyntaxFlavor::Gas
+
+use std::fmt;
+
+use crate::{
+    cond::BuildConfig,
+    metadata,
+    program::Program,
+    qualify_label, region_symbols,
+    target::{Arch, Os},
+    AsmExpr, Data, Defaults, Endian, Section,
+};
+
+/// How symbols and page-relative operands are spelled, the non-local
+/// label local ones are scoped to, and the settings of the program and
+/// section being written.
+#[derive(Default)]
+pub(crate) struct Syntax {
+    pub(crate) arch: Arch,
+    pub(crate) os: Os,
+    pub(crate) scope: String,
+    pub(crate) region_symbols: bool,
+    pub(crate) hint_comments: bool,
+    /// Size of a pointer on the target, in bytes.
+    pub(crate) pointer_width: u32,
+    pub(crate) defaults: Defaults,
+}
+
+impl Syntax {
+    /// `name` as the assembler sees it. Local labels are qualified with
+    /// their scope as in NASM, then given the prefix that keeps them out
+    /// of the symbol table.
+    pub(crate) fn symbol(&self, name: &str) -> String {
+        if !name.starts_with('.') || name.starts_with("..") {
+            return name.to_string();
+        }
+        let prefix = match self.os {
+            Os::Linux => ".L",
+            Os::MacOs => "L",
+        };
+        format!("{}{}", prefix, qualify_label(&self.scope, name))
+    }
+
+    /// What starts a comment running to the end of the line.
+    fn comment(&self) -> &'static str {
+        match self.arch {
+            Arch::Arm64 => "//",
+            Arch::Amd64 | Arch::Riscv64 => "#",
+        }
+    }
+}
+
+/// The directive that opens `section`.
+fn section_directive(name: &str, os: Os) -> String {
+    match os {
+        Os::Linux if name.starts_with("text.") => format!(".section .{},\"ax\"", name),
+        Os::Linux => format!(".section .{}", name),
+        // Mach-O linkers split sections at symbols themselves.
+        Os::MacOs => match name {
+            name if name == "text" || name.starts_with("text.") => {
+                ".section __TEXT,__text,regular,pure_instructions".to_string()
+            }
+            "rodata" => ".section __TEXT,__const".to_string(),
+            "bss" => ".section __DATA,__bss".to_string(),
+            name => format!(".section __DATA,__{}", name),
+        },
+    }
+}
+
+/// A `.error` line, making the assembler stop with `message` at a line
+/// that has no spelling for the target.
+fn error_line(f: &mut fmt::Formatter, message: &str) -> fmt::Result {
+    writeln!(f, "\t.error \"{}\"", message.replace('"', "'"))
+}
+
+/// An error line for an instruction of architecture `isa` in a program
+/// for another.
+fn foreign(f: &mut fmt::Formatter, isa: Arch, inst: &str, syntax: &Syntax) -> fmt::Result {
+    error_line(
+        f,
+        &format!(
+            "{} instruction `{}` in a program for {}",
+            isa.name(),
+            inst.replace('\t', " "),
+            syntax.arch.name()
+        ),
+    )
+}
+
+/// The bytes of `text` inside a GAS string literal, with everything but
+/// printable ASCII escaped.
+fn gas_string(text: &str) -> String {
+    let mut out = String::new();
+    for byte in text.bytes() {
+        match byte {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\t' => out.push_str("\\t"),
+            b' '..=b'~' => out.push(byte as char),
+            _ => out.push_str(&format!("\\{:03o}", byte)),
+        }
+    }
+    out
+}
+
+fn fmt_data(f: &mut fmt::Formatter, data: &Data, endian: Endian, syntax: &Syntax) -> fmt::Result {
+    match (data, endian) {
+        (Data::Address(label), _) => match syntax.pointer_width {
+            4 => writeln!(f, "\t.long {}", syntax.symbol(&label.label)),
+            _ => writeln!(f, "\t.quad {}", syntax.symbol(&label.label)),
+        },
+        (Data::Offset { label, base }, _) => writeln!(
+            f,
+            "\t.long {} - {}",
+            syntax.symbol(&label.label),
+            syntax.symbol(&base.label)
+        ),
+        (Data::ImageOffset(label), _) => writeln!(f, "\t.rva {}", syntax.symbol(&label.label)),
+        (Data::Endian(e, inner), _) if inner.labels().is_empty() => fmt_data(f, inner, *e, syntax),
+        (Data::Endian(..), _) => error_line(f, "a byte-swapped address or offset"),
+        (Data::Fill { count, byte }, _) => writeln!(f, "\t.fill {}, 1, {}", count, byte),
+        (Data::Times(count, inner), _) => {
+            writeln!(f, "\t.rept {}", count)?;
+            fmt_data(f, inner, endian, syntax)?;
+            writeln!(f, "\t.endr")
+        }
+        (Data::Reserve { count, size }, _) => {
+            writeln!(f, "\t.skip {}", count * size.bits() as usize / 8)
+        }
+        (Data::Str(text, encoding), _) if encoding.unit_width() == 1 => {
+            let directive = match encoding.is_terminated() {
+                true => ".asciz",
+                false => ".ascii",
+            };
+            writeln!(f, "\t{} \"{}\"", directive, gas_string(text))
+        }
+        (Data::Str(text, encoding), Endian::Little) => {
+            let units = encoding.units(text);
+            if units.is_empty() {
+                return Ok(());
+            }
+            let directive = match encoding.unit_width() {
+                2 => ".short",
+                _ => ".long",
+            };
+            let units: Vec<String> = units.iter().map(|unit| format!("0x{:x}", unit)).collect();
+            writeln!(f, "\t{} {}", directive, units.join(", "))
+        }
+        (Data::Byte(v), _) => writeln!(f, "\t.byte {}", v),
+        (Data::Word(v), Endian::Little) => writeln!(f, "\t.short {}", v),
+        (Data::Dword(v), Endian::Little) => writeln!(f, "\t.long {}", v),
+        (Data::SkipTo { offset, byte }, _) => writeln!(f, "\t.org {}, {}", offset, byte),
+        (Data::Int(v), Endian::Little) => writeln!(f, "\t.quad {}", v),
+        (Data::UInt(v), Endian::Little) => writeln!(f, "\t.quad {}", v),
+        (Data::USize(v), Endian::Little) if syntax.pointer_width == 4 => {
+            writeln!(f, "\t.long {}", v)
+        }
+        (Data::USize(v), Endian::Little) => writeln!(f, "\t.quad {}", v),
+        _ => {
+            let bytes = data.to_bytes_with(endian);
+            if bytes.is_empty() {
+                return Ok(());
+            }
+            let bytes: Vec<String> = bytes.iter().map(|b| format!("0x{:02x}", b)).collect();
+            writeln!(f, "\t.byte {}", bytes.join(", "))
+        }
+    }
+}
+
+fn fmt_body(
+    f: &mut fmt::Formatter,
+    body: &[AsmExpr],
+    section: &Section,
+    config: &BuildConfig,
+    syntax: &mut Syntax,
+) -> fmt::Result {
+    for expr in body {
+        match expr {
+            AsmExpr::Label(label) => {
+                if !label.label.starts_with('.') {
+                    syntax.scope = label.label.clone();
+                }
+                writeln!(f, "{}:", syntax.symbol(&label.label))?;
+            }
+            AsmExpr::Arm64(inst) if syntax.arch == Arch::Arm64 => {
+                write!(f, "\t")?;
+                inst.fmt_in(f, syntax)?;
+                writeln!(f)?;
+            }
+            AsmExpr::Arm64(inst) => foreign(f, Arch::Arm64, &inst.to_string(), syntax)?,
+            AsmExpr::Riscv(inst) if syntax.arch == Arch::Riscv64 => {
+                write!(f, "\t")?;
+                inst.fmt_in(f, syntax)?;
+                writeln!(f)?;
+            }
+            AsmExpr::Riscv(inst) => foreign(f, Arch::Riscv64, &inst.to_string(), syntax)?,
+            AsmExpr::Instruction(inst) if syntax.arch == Arch::Amd64 => {
+                write!(f, "\t")?;
+                inst.fmt_att(f, syntax)?;
+                writeln!(f)?;
+            }
+            AsmExpr::Instruction(inst) => foreign(f, Arch::Amd64, &inst.to_string(), syntax)?,
+            AsmExpr::Data(data) => fmt_data(f, data, section.endian, syntax)?,
+            AsmExpr::Raw(text) => writeln!(f, "{}", text)?,
+            AsmExpr::Param(name) => error_line(f, &format!("unbound parameter %{}", name))?,
+            AsmExpr::Block(inner) | AsmExpr::Macro { body: inner, .. } => {
+                fmt_body(f, inner, section, config, syntax)?
+            }
+            AsmExpr::Region { name, body } => {
+                // `..@` only means something to NASM; the symbols are
+                // written without it, and leave the label scope alone.
+                let (begin, end) = region_symbols(name);
+                writeln!(f, "\t{} begin region {}", syntax.comment(), name)?;
+                if syntax.region_symbols {
+                    writeln!(f, "{}:", begin.trim_start_matches("..@"))?;
+                }
+                fmt_body(f, body, section, config, syntax)?;
+                if syntax.region_symbols {
+                    writeln!(f, "{}:", end.trim_start_matches("..@"))?;
+                }
+                writeln!(f, "\t{} end region {}", syntax.comment(), name)?;
+            }
+            AsmExpr::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let arm = if cond.eval(config) { then } else { otherwise };
+                fmt_body(f, arm, section, config, syntax)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes `program` for the GNU or Apple assembler, as its `Display`
+/// does unless it is x86-64 code written for NASM.
+pub(crate) fn fmt_program(program: &Program, f: &mut fmt::Formatter) -> fmt::Result {
+    let mut syntax = Syntax {
+        arch: program.target.arch,
+        os: program.target.os,
+        scope: String::new(),
+        region_symbols: program.region_symbols,
+        hint_comments: program.hint_comments,
+        pointer_width: program.target.abi.pointer_width(),
+        defaults: program.defaults,
+    };
+
+    if program.metadata_comments {
+        for line in metadata::comments(&program.provenance()).lines() {
+            writeln!(f, "{}{}", syntax.comment(), line.trim_start_matches(';'))?;
+        }
+    }
+    for (name, value) in &program.defines {
+        writeln!(f, ".set {}, {}", name, value)?;
+    }
+    for global in &program.globals {
+        writeln!(f, ".globl {}", global.value)?;
+    }
+    // Undefined symbols are external anyway; Apple's assembler has no
+    // directive to say so.
+    if syntax.os == Os::Linux {
+        for ext in &program.externs {
+            writeln!(f, ".extern {}", ext.value)?;
+        }
+    }
+
+    let pool = (!program.pool.is_empty()).then(|| program.pool.to_section());
+    for section in program.sections.iter().chain(&pool) {
+        writeln!(f, "{}", section_directive(&section.name, syntax.os))?;
+        if section.name.starts_with("text") {
+            writeln!(f, "\t.p2align 2")?;
+        }
+        syntax.scope.clear();
+        syntax.defaults = program.defaults_of(section);
+        fmt_body(f, &section.body, section, &program.config, &mut syntax)?;
+        writeln!(f)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,444 @@
+//! Syntax highlighting of emitted assembly, for reviewing generated
+//! programs.
+//!
+//! Highlighting works on the NASM text itself rather than on the program
+//! tree, so raw lines and anything else the emitter produces are covered
+//! exactly as they will be assembled.
+
+use std::{collections::HashSet, env, io::IsTerminal};
+
+use crate::{is_symbol_char, qualify_label};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TokenKind {
+    Mnemonic,
+    /// Assembler directives and data pseudo-instructions: `section`,
+    /// `global`, `%define`, `dq`, ...
+    Directive,
+    Register,
+    /// Size and addressing keywords such as `qword` and `rel`, and section
+    /// names.
+    Keyword,
+    Number,
+    String,
+    Comment,
+    /// The name a label or `%define` line introduces.
+    Definition,
+    /// A use of a label, define or external symbol.
+    Symbol,
+    /// Whitespace and punctuation.
+    Plain,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    pub text: &'a str,
+}
+
+const PREFIXES: &[&str] = &["rep", "repe", "repz", "repne", "repnz", "lock"];
+
+const DIRECTIVES: &[&str] = &[
+    "global", "extern", "common", "static", "section", "segment", "default", "bits", "cpu", "org",
+    "align", "alignb", "absolute", "times", "incbin", "equ", "db", "dw", "dd", "dq", "dt", "do",
+    "dy", "dz", "resb", "resw", "resd", "resq", "rest", "reso", "resy", "resz",
+];
+
+/// Directives whose arguments are keywords (section names, modes) rather
+/// than symbols.
+const KEYWORD_DIRECTIVES: &[&str] = &["section", "segment", "default", "bits", "cpu"];
+
+const KEYWORDS: &[&str] = &[
+    "byte", "word", "dword", "qword", "tword", "oword", "yword", "zword", "ptr", "rel", "abs",
+    "near", "far", "short", "strict",
+];
+
+/// The number in `word` if it is `prefix`, a register number and one of
+/// `suffixes`, as in `r9d`.
+fn numbered(word: &str, prefix: &str, suffixes: &[&str]) -> Option<u32> {
+    let rest = word.strip_prefix(prefix)?;
+    let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let (number, suffix) = rest.split_at(digits);
+    if number.is_empty() || (number.len() > 1 && number.starts_with('0')) {
+        return None;
+    }
+    if !suffixes.contains(&suffix) {
+        return None;
+    }
+    number.parse().ok()
+}
+
+/// Whether `word` names a register in any size: `rax`, `eax`, `al`,
+/// `r9d`, `r31`, `xmm3`, `tmm0`, ...
+pub fn is_register(word: &str) -> bool {
+    let word = word.to_ascii_lowercase();
+    let vector = |prefix| numbered(&word, prefix, &[""]).is_some_and(|n| n < 32);
+
+    matches!(
+        word.as_str(),
+        "rax"
+            | "rbx"
+            | "rcx"
+            | "rdx"
+            | "rsi"
+            | "rdi"
+            | "rsp"
+            | "rbp"
+            | "rip"
+            | "eax"
+            | "ebx"
+            | "ecx"
+            | "edx"
+            | "esi"
+            | "edi"
+            | "esp"
+            | "ebp"
+            | "eip"
+            | "ax"
+            | "bx"
+            | "cx"
+            | "dx"
+            | "si"
+            | "di"
+            | "sp"
+            | "bp"
+            | "al"
+            | "bl"
+            | "cl"
+            | "dl"
+            | "sil"
+            | "dil"
+            | "spl"
+            | "bpl"
+            | "ah"
+            | "bh"
+            | "ch"
+            | "dh"
+            | "cs"
+            | "ds"
+            | "es"
+            | "fs"
+            | "gs"
+            | "ss"
+    ) || numbered(&word, "r", &["", "d", "w", "b"]).is_some_and(|n| (8..32).contains(&n))
+        || vector("xmm")
+        || vector("ymm")
+        || vector("zmm")
+        || numbered(&word, "tmm", &[""]).is_some_and(|n| n < 8)
+        || numbered(&word, "k", &[""]).is_some_and(|n| n < 8)
+}
+
+/// Where in a statement the tokenizer is.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Position {
+    /// Before the mnemonic or directive, where labels are defined.
+    Start,
+    /// After `%define` and friends, before the name they introduce.
+    DefineName,
+    Operands,
+    KeywordOperands,
+}
+
+fn end_of(line: &str, start: usize, more: impl Fn(char) -> bool) -> usize {
+    line[start..]
+        .char_indices()
+        .find(|&(_, c)| !more(c))
+        .map_or(line.len(), |(i, _)| start + i)
+}
+
+fn number_end(line: &str, start: usize) -> usize {
+    let hex = line[start..].starts_with("0x") || line[start..].starts_with("0X");
+    let mut end = start;
+    let mut prev = '0';
+    for c in line[start..].chars() {
+        let exponent_sign = !hex && matches!(c, '+' | '-') && matches!(prev, 'e' | 'E');
+        if !(c.is_ascii_alphanumeric() || c == '_' || c == '.' || exponent_sign) {
+            break;
+        }
+        end += c.len_utf8();
+        prev = c;
+    }
+    end
+}
+
+/// Splits one line of emitted assembly into classified tokens, which
+/// concatenate back to the line.
+pub fn tokenize(line: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut position = Position::Start;
+    let mut i = 0;
+
+    while let Some(c) = line[i..].chars().next() {
+        let start = i;
+        let kind = if c == ';' {
+            i = line.len();
+            TokenKind::Comment
+        } else if matches!(c, '"' | '\'' | '`') {
+            i = line[i + 1..].find(c).map_or(line.len(), |end| i + end + 2);
+            TokenKind::String
+        } else if line[i..].starts_with("__?") {
+            // NASM's special float constants, `__?QNaN?__` and friends.
+            i = line[i + 3..]
+                .find("?__")
+                .map_or(line.len(), |end| i + end + 6);
+            TokenKind::Number
+        } else if c.is_ascii_digit() {
+            i = number_end(line, i);
+            TokenKind::Number
+        } else if c == '%' && position == Position::Start {
+            i = end_of(line, i + 1, is_symbol_char);
+            if matches!(&line[start..i], "%define" | "%xdefine" | "%assign") {
+                position = Position::DefineName;
+            } else {
+                position = Position::Operands;
+            }
+            TokenKind::Directive
+        } else if is_symbol_char(c) {
+            i = end_of(line, i, is_symbol_char);
+            let word = &line[start..i];
+            let lower = word.to_ascii_lowercase();
+            match position {
+                Position::Start if line[i..].trim_start().starts_with(':') => TokenKind::Definition,
+                Position::Start if PREFIXES.contains(&lower.as_str()) => TokenKind::Mnemonic,
+                Position::Start if DIRECTIVES.contains(&lower.as_str()) => {
+                    position = if KEYWORD_DIRECTIVES.contains(&lower.as_str()) {
+                        Position::KeywordOperands
+                    } else {
+                        Position::Operands
+                    };
+                    TokenKind::Directive
+                }
+                Position::Start => {
+                    position = Position::Operands;
+                    TokenKind::Mnemonic
+                }
+                Position::DefineName => {
+                    position = Position::Operands;
+                    TokenKind::Definition
+                }
+                Position::KeywordOperands => TokenKind::Keyword,
+                Position::Operands if is_register(word) => TokenKind::Register,
+                Position::Operands if KEYWORDS.contains(&lower.as_str()) => TokenKind::Keyword,
+                Position::Operands => TokenKind::Symbol,
+            }
+        } else {
+            i += c.len_utf8();
+            TokenKind::Plain
+        };
+
+        match tokens.last_mut() {
+            Some(Token {
+                kind: TokenKind::Plain,
+                text,
+            }) if kind == TokenKind::Plain => *text = &line[start - text.len()..i],
+            _ => tokens.push(Token {
+                kind,
+                text: &line[start..i],
+            }),
+        }
+    }
+
+    tokens
+}
+
+/// `text` with HTML's special characters escaped.
+fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// An HTML id for symbol `name`. Characters that are not safe in a URL
+/// fragment are written as `-` and their hex code.
+fn anchor(name: &str) -> String {
+    let mut id = String::from("sym-");
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+            id.push(c);
+        } else {
+            id.push_str(&format!("-{:x}-", c as u32));
+        }
+    }
+    id
+}
+
+const STYLE: &str = "\
+body { background: #fdfdfd; color: #222; }
+pre { font-family: monospace; line-height: 1.3; }
+a { color: inherit; text-decoration: none; }
+a:hover { text-decoration: underline; }
+:target { background: #fff3b0; }
+.mnemonic { color: #0b5394; font-weight: bold; }
+.directive { color: #7a1fa2; }
+.register { color: #b45f06; }
+.keyword { color: #7a1fa2; font-style: italic; }
+.number { color: #38761d; }
+.string { color: #a61c00; }
+.comment { color: #888; font-style: italic; }
+.definition { color: #000; font-weight: bold; }
+.symbol { color: #134f5c; }
+.symbol.external { color: #666; font-style: italic; }
+";
+
+fn css_class(kind: TokenKind) -> Option<&'static str> {
+    Some(match kind {
+        TokenKind::Mnemonic => "mnemonic",
+        TokenKind::Directive => "directive",
+        TokenKind::Register => "register",
+        TokenKind::Keyword => "keyword",
+        TokenKind::Number => "number",
+        TokenKind::String => "string",
+        TokenKind::Comment => "comment",
+        TokenKind::Definition => "definition",
+        TokenKind::Symbol => "symbol",
+        TokenKind::Plain => return None,
+    })
+}
+
+/// Renders emitted assembly as a standalone HTML page. Each label and
+/// define becomes an anchor and each reference to one a link to it, with
+/// local `.labels` resolved under their enclosing label as NASM does.
+pub fn html(text: &str) -> String {
+    let lines: Vec<_> = text.lines().map(tokenize).collect();
+
+    let mut defined = HashSet::new();
+    let mut externs = HashSet::new();
+    let mut scope = String::new();
+    for tokens in &lines {
+        let mut words = tokens.iter().filter(|t| t.kind != TokenKind::Plain);
+        match words.next() {
+            Some(first) if first.text == "extern" => {
+                externs.extend(words.map(|t| t.text.to_string()));
+            }
+            _ => {}
+        }
+        for token in tokens.iter().filter(|t| t.kind == TokenKind::Definition) {
+            if !token.text.starts_with('.') {
+                scope = token.text.to_string();
+            }
+            defined.insert(qualify_label(&scope, token.text));
+        }
+    }
+
+    let mut out = String::from(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Assembly listing</title>\n<style>\n",
+    );
+    out.push_str(STYLE);
+    out.push_str("</style>\n</head>\n<body>\n<pre>\n");
+
+    let mut scope = String::new();
+    for tokens in &lines {
+        for token in tokens {
+            let text = escape_html(token.text);
+            match token.kind {
+                TokenKind::Plain => out.push_str(&text),
+                TokenKind::Definition => {
+                    if !token.text.starts_with('.') {
+                        scope = token.text.to_string();
+                    }
+                    let id = anchor(&qualify_label(&scope, token.text));
+                    out.push_str(&format!(
+                        "<a class=\"definition\" id=\"{}\" href=\"#{}\">{}</a>",
+                        id, id, text
+                    ));
+                }
+                TokenKind::Symbol => {
+                    let name = qualify_label(&scope, token.text);
+                    if defined.contains(&name) {
+                        out.push_str(&format!(
+                            "<a class=\"symbol\" href=\"#{}\">{}</a>",
+                            anchor(&name),
+                            text
+                        ));
+                    } else if externs.contains(&name) {
+                        out.push_str(&format!("<span class=\"symbol external\">{}</span>", text));
+                    } else {
+                        out.push_str(&format!("<span class=\"symbol\">{}</span>", text));
+                    }
+                }
+                kind => {
+                    out.push_str(&format!(
+                        "<span class=\"{}\">{}</span>",
+                        css_class(kind).unwrap(),
+                        text
+                    ));
+                }
+            }
+        }
+        out.push('\n');
+    }
+
+    out.push_str("</pre>\n</body>\n</html>\n");
+    out
+}
+
+/// When [`ansi`] colouring is applied.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ColorMode {
+    /// Colour only when writing to a terminal and `NO_COLOR` is unset.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorMode {
+    /// The mode a `--color=` style argument names: `auto`, `always` or
+    /// `never`.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "auto" => Some(ColorMode::Auto),
+            "always" => Some(ColorMode::Always),
+            "never" => Some(ColorMode::Never),
+            _ => None,
+        }
+    }
+
+    /// Whether output to `stream` should be coloured.
+    pub fn enabled_for(self, stream: &impl IsTerminal) -> bool {
+        match self {
+            ColorMode::Auto => stream.is_terminal() && env::var_os("NO_COLOR").is_none(),
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+        }
+    }
+}
+
+fn sgr(kind: TokenKind) -> Option<&'static str> {
+    Some(match kind {
+        TokenKind::Mnemonic => "1;34",
+        TokenKind::Directive => "35",
+        TokenKind::Register => "33",
+        TokenKind::Keyword => "3;35",
+        TokenKind::Number => "32",
+        TokenKind::String => "31",
+        TokenKind::Comment => "2",
+        TokenKind::Definition => "1",
+        TokenKind::Symbol => "36",
+        TokenKind::Plain => return None,
+    })
+}
+
+/// Emitted assembly with ANSI escape sequences colouring mnemonics,
+/// registers, immediates and labels.
+pub fn ansi(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() * 2);
+    for line in text.lines() {
+        for token in tokenize(line) {
+            match sgr(token.kind) {
+                Some(code) => out.push_str(&format!("\x1b[{}m{}\x1b[0m", code, token.text)),
+                None => out.push_str(token.text),
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
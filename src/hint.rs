@@ -0,0 +1,328 @@
+//! Expected branch directions, and the block-ordering pass that uses them.
+//!
+//! A conditional branch can carry a [`BranchHint`] saying which way it
+//! usually goes, so a frontend with profile data can pass it down.
+//! [`order_blocks`] then lays each function out so the expected path falls
+//! through and blocks only unlikely paths reach move to its end, inverting
+//! branches and adding jumps so the program still means the same thing.
+
+use std::{collections::HashSet, fmt, sync::Arc};
+
+use crate::{
+    instr::{jmp, CondCode},
+    mnemonic::Amd64Mnemonic,
+    program::Program,
+    qualify_label, Amd64Instruction, AsmExpr, ImmediateValue, Label, Operand,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BranchHint {
+    /// The branch is usually taken.
+    Likely,
+    /// The branch usually falls through.
+    Unlikely,
+}
+
+impl BranchHint {
+    pub fn invert(self) -> Self {
+        match self {
+            BranchHint::Likely => BranchHint::Unlikely,
+            BranchHint::Unlikely => BranchHint::Likely,
+        }
+    }
+}
+
+impl fmt::Display for BranchHint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BranchHint::Likely => write!(f, "likely"),
+            BranchHint::Unlikely => write!(f, "unlikely"),
+        }
+    }
+}
+
+/// Instructions control never passes the end of.
+pub(crate) const NO_FALLTHROUGH: &[&str] =
+    &["jmp", "ret", "iretq", "sysret", "sysretq", "hlt", "ud2"];
+
+fn ends_block(inst: &Amd64Instruction) -> bool {
+    inst.mnemonic.as_str().starts_with('j') || NO_FALLTHROUGH.contains(&inst.mnemonic.as_str())
+}
+
+fn direct_target(inst: &Amd64Instruction) -> Option<&str> {
+    match inst.operands.first() {
+        Some(Operand::Immediate(ImmediateValue::Label(label))) => Some(&label.label),
+        _ => None,
+    }
+}
+
+/// A section body as plain labels and instructions, or `None` if it holds
+/// anything the pass cannot move safely: data, raw text, parameters or
+/// conditionals.
+fn flatten(body: &[AsmExpr], out: &mut Vec<AsmExpr>) -> Option<()> {
+    for expr in body {
+        match expr {
+            AsmExpr::Label(_) | AsmExpr::Instruction(_) => out.push(expr.clone()),
+            AsmExpr::Block(inner)
+            | AsmExpr::Region { body: inner, .. }
+            | AsmExpr::Macro { body: inner, .. } => flatten(inner, out)?,
+            _ => return None,
+        }
+    }
+    Some(())
+}
+
+#[derive(Default)]
+struct Block {
+    labels: Vec<Label>,
+    instructions: Vec<Amd64Instruction>,
+}
+
+impl Block {
+    fn falls_through(&self) -> bool {
+        self.instructions
+            .last()
+            .is_none_or(|inst| !NO_FALLTHROUGH.contains(&inst.mnemonic.as_str()))
+    }
+
+    /// The condition and target of a closing conditional branch.
+    fn branch(&self) -> Option<(CondCode, &str)> {
+        let inst = self.instructions.last()?;
+        let cond = CondCode::from_suffix(inst.mnemonic.as_str().strip_prefix('j')?)?;
+        Some((cond, direct_target(inst)?))
+    }
+}
+
+/// One function's worth of code: a non-local label and everything up to
+/// the next, so moving blocks within it leaves local labels in scope.
+struct Region {
+    scope: String,
+    blocks: Vec<Block>,
+}
+
+fn split(items: Vec<AsmExpr>) -> (Vec<AsmExpr>, Vec<Region>) {
+    let mut prologue = Vec::new();
+    let mut regions: Vec<Region> = Vec::new();
+    for item in items {
+        let region = match (&item, regions.last_mut()) {
+            (AsmExpr::Label(label), _) if !label.label.starts_with('.') => {
+                regions.push(Region {
+                    scope: label.label.clone(),
+                    blocks: vec![Block::default()],
+                });
+                regions.last_mut().unwrap()
+            }
+            (_, Some(region)) => region,
+            (_, None) => {
+                prologue.push(item);
+                continue;
+            }
+        };
+        let current = region.blocks.last_mut().unwrap();
+        match item {
+            AsmExpr::Label(label) => {
+                if !current.instructions.is_empty() {
+                    region.blocks.push(Block::default());
+                }
+                region.blocks.last_mut().unwrap().labels.push(label);
+            }
+            AsmExpr::Instruction(inst) => {
+                let end = ends_block(&inst);
+                current.instructions.push(inst);
+                if end {
+                    region.blocks.push(Block::default());
+                }
+            }
+            _ => unreachable!("flattened"),
+        }
+    }
+    for region in &mut regions {
+        if region.blocks.len() > 1 {
+            let last = region.blocks.last().unwrap();
+            if last.labels.is_empty() && last.instructions.is_empty() {
+                region.blocks.pop();
+            }
+        }
+    }
+    (prologue, regions)
+}
+
+impl Region {
+    fn find(&self, target: &str) -> Option<usize> {
+        let target = qualify_label(&self.scope, target);
+        self.blocks.iter().position(|b| {
+            b.labels
+                .iter()
+                .any(|l| qualify_label(&self.scope, &l.label) == target)
+        })
+    }
+
+    /// The new block order: every hinted branch's expected successor right
+    /// after it, and the blocks only unexpected paths reach last.
+    fn order(&self) -> Vec<usize> {
+        let count = self.blocks.len();
+        let mut hot = vec![None; count];
+        let mut cold = vec![false; count];
+        for (i, block) in self.blocks.iter().enumerate() {
+            let hint = block.instructions.last().and_then(|inst| inst.hint);
+            let (Some(hint), Some((_, target))) = (hint, block.branch()) else {
+                continue;
+            };
+            let (Some(taken), true) = (self.find(target), i + 1 < count) else {
+                continue;
+            };
+            let (expected, unexpected) = match hint {
+                // A likely backward branch is a loop, already laid out with
+                // its body falling into the latch; what follows is its exit.
+                BranchHint::Likely if taken <= i => continue,
+                BranchHint::Likely => (taken, i + 1),
+                BranchHint::Unlikely => (i + 1, taken),
+            };
+            hot[i] = Some(expected);
+            cold[unexpected] = true;
+        }
+        for &h in hot.iter().flatten() {
+            cold[h] = false;
+        }
+        cold[0] = false;
+
+        let mut placed = vec![false; count];
+        let mut order = Vec::with_capacity(count);
+        for start in 0..count {
+            let mut next = Some(start);
+            while let Some(i) = next {
+                if placed[i] || cold[i] {
+                    break;
+                }
+                placed[i] = true;
+                order.push(i);
+                next = hot[i];
+            }
+        }
+        order.extend((0..count).filter(|&i| !placed[i]));
+        order
+    }
+
+    /// The label of block `index`, adding a fresh local one if it has none.
+    fn label(&mut self, index: usize, taken: &mut HashSet<String>) -> Label {
+        if let Some(label) = self.blocks[index].labels.first() {
+            return label.clone();
+        }
+        let label = (0..)
+            .map(|n| Label::plain(&format!(".fall{}", n)))
+            .find(|l| !taken.contains(&qualify_label(&self.scope, &l.label)))
+            .unwrap();
+        taken.insert(qualify_label(&self.scope, &label.label));
+        self.blocks[index].labels.push(label.clone());
+        label
+    }
+
+    /// Rewrites the region in `order`, or leaves it alone and returns false
+    /// if the original last block falls out of the region with nowhere to
+    /// jump back to.
+    fn reorder(&mut self, order: &[usize], after: Option<&Label>) -> bool {
+        let count = self.blocks.len();
+        if order.iter().enumerate().all(|(p, &i)| p == i) {
+            return false;
+        }
+        if after.is_none()
+            && self.blocks[count - 1].falls_through()
+            && order[count - 1] != count - 1
+        {
+            return false;
+        }
+
+        let mut taken: HashSet<String> = self
+            .blocks
+            .iter()
+            .flat_map(|b| &b.labels)
+            .map(|l| qualify_label(&self.scope, &l.label))
+            .collect();
+        for (p, &i) in order.iter().enumerate() {
+            let new_next = order.get(p + 1).copied();
+            if self.blocks[i].falls_through() {
+                if new_next == Some(i + 1) {
+                    continue;
+                }
+                let natural = match i + 1 < count {
+                    true => self.label(i + 1, &mut taken),
+                    false => after.cloned().unwrap(),
+                };
+                let inverted = self.blocks[i]
+                    .branch()
+                    .filter(|(_, target)| new_next.is_some() && self.find(target) == new_next)
+                    .map(|(cond, _)| cond.negate());
+                let block = &mut self.blocks[i];
+                match inverted {
+                    Some(cond) => {
+                        let inst = block.instructions.last_mut().unwrap();
+                        inst.mnemonic = Amd64Mnemonic::Jcc(cond);
+                        inst.operands = vec![Operand::from(natural)];
+                        inst.hint = inst.hint.map(BranchHint::invert);
+                    }
+                    None => {
+                        if let AsmExpr::Instruction(inst) = jmp(natural) {
+                            block.instructions.push(inst);
+                        }
+                    }
+                }
+            } else if let Some(last) = self.blocks[i].instructions.last() {
+                let target = direct_target(last).and_then(|t| self.find(t));
+                if last.mnemonic == "jmp" && target.is_some() && target == new_next {
+                    self.blocks[i].instructions.pop();
+                }
+            }
+        }
+
+        let mut blocks: Vec<Option<Block>> = self.blocks.drain(..).map(Some).collect();
+        self.blocks = order.iter().map(|&i| blocks[i].take().unwrap()).collect();
+        true
+    }
+}
+
+/// Reorders the blocks of every function in the text sections to follow
+/// the branch hints, and returns how many functions changed. Sections
+/// holding anything besides labels and instructions are left as they are,
+/// as are sections without hints; the rest lose their block structure.
+pub fn order_blocks(program: &mut Program) -> usize {
+    let mut changed = 0;
+    for section in program
+        .sections
+        .iter_mut()
+        .filter(|s| s.name.starts_with("text"))
+    {
+        let mut items = Vec::new();
+        if flatten(&section.body, &mut items).is_none() {
+            continue;
+        }
+        let hinted = items
+            .iter()
+            .any(|item| matches!(item, AsmExpr::Instruction(inst) if inst.hint.is_some()));
+        if !hinted {
+            continue;
+        }
+
+        let (mut body, mut regions) = split(items);
+        let heads: Vec<Label> = regions
+            .iter()
+            .map(|r| r.blocks[0].labels[0].clone())
+            .collect();
+        let mut rewritten = false;
+        for (n, region) in regions.iter_mut().enumerate() {
+            let order = region.order();
+            if region.reorder(&order, heads.get(n + 1)) {
+                changed += 1;
+                rewritten = true;
+            }
+        }
+        if !rewritten {
+            continue;
+        }
+        for block in regions.into_iter().flat_map(|r| r.blocks) {
+            body.extend(block.labels.into_iter().map(AsmExpr::Label));
+            body.extend(block.instructions.into_iter().map(AsmExpr::Instruction));
+        }
+        section.body = Arc::new(body);
+    }
+    changed
+}
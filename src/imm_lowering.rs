@@ -0,0 +1,179 @@
+use crate::{
+    pool::ConstPool, program::Program, Amd64Instruction, Amd64Register, AsmExpr, ImmediateValue,
+    Mem, Operand,
+};
+
+/// Which integer immediates get moved out of the instruction stream and
+/// into the constant pool.
+#[derive(Clone, Copy)]
+pub enum LoweringThreshold {
+    /// Only values that cannot be encoded as a sign-extended 32-bit
+    /// immediate, i.e. the ones that would otherwise need a 10-byte `movabs`.
+    Imm64,
+    /// Any value that needs more than the given number of bits as a signed
+    /// immediate.
+    Bits(u32),
+}
+
+impl LoweringThreshold {
+    fn needs_lowering(self, value: i64) -> bool {
+        let bits = match self {
+            LoweringThreshold::Imm64 => 32,
+            LoweringThreshold::Bits(n) => n.clamp(1, 64),
+        };
+
+        if bits >= 64 {
+            return false;
+        }
+
+        let min = -(1i64 << (bits - 1));
+        let max = (1i64 << (bits - 1)) - 1;
+        value < min || value > max
+    }
+}
+
+/// Instructions whose immediate source can be replaced by a memory source of
+/// the same width without changing their meaning.
+const MEMORY_SOURCE_MNEMONICS: &[&str] =
+    &["mov", "add", "adc", "sub", "sbb", "and", "or", "xor", "cmp"];
+
+/// Scalar SSE instructions that take a memory source, without the `s` or
+/// `d` that says their precision or the `v` of their AVX forms.
+const SCALAR_FLOAT_MNEMONICS: &[&str] = &[
+    "movs", "adds", "subs", "muls", "divs", "mins", "maxs", "sqrts", "comis", "ucomis",
+];
+
+/// The width in bits of the scalar a floating-point `mnemonic` works on,
+/// if it is one that can read it from memory.
+fn scalar_float_width(mnemonic: &str) -> Option<u32> {
+    let mnemonic = mnemonic.strip_prefix('v').unwrap_or(mnemonic);
+    let (stem, width) = match mnemonic.as_bytes().last()? {
+        b's' => (&mnemonic[..mnemonic.len() - 1], 32),
+        b'd' => (&mnemonic[..mnemonic.len() - 1], 64),
+        _ => return None,
+    };
+    SCALAR_FLOAT_MNEMONICS.contains(&stem).then_some(width)
+}
+
+/// Points the floating-point immediate source of a scalar SSE instruction
+/// at its value in `pool`, rounded to the precision the instruction reads.
+fn lower_float(inst: &mut Amd64Instruction, pool: &mut ConstPool) -> bool {
+    let Some(width) = scalar_float_width(inst.mnemonic.as_str()) else {
+        return false;
+    };
+    let [Operand::Register(Amd64Register::Vector(_)), .., src] = inst.operands.as_mut_slice()
+    else {
+        return false;
+    };
+    let Operand::Immediate(ImmediateValue::F64(value)) = *src else {
+        return false;
+    };
+
+    let label = match width {
+        32 => pool.f32(value as f32),
+        _ => pool.f64(value),
+    };
+    *src = Operand::Memory(Mem::label(label));
+    true
+}
+
+/// Rewrites `op reg, imm` instructions whose immediate exceeds `threshold`
+/// into `op reg, [rel K]`, where `K` is the immediate interned in the
+/// program's constant pool.
+///
+/// Only two-operand forms with a register destination are rewritten, since
+/// those are the ones x86 can express with a memory source. Label
+/// immediates are addresses rather than constants and are left untouched.
+/// A floating-point immediate is its bit pattern to these, a constant like
+/// any other.
+///
+/// Scalar SSE and AVX instructions have no immediate form at all, so their
+/// floating-point immediates are always moved to the pool, whatever the
+/// threshold, as `f32`s for the single-precision instructions. Returns the
+/// number of instructions rewritten.
+///
+/// ```
+/// use cataclysm::{
+///     consts::{RAX, XMM0, XMM1},
+///     imm_lowering::{lower_large_immediates, LoweringThreshold},
+///     instr,
+///     mnemonic::Amd64Mnemonic,
+///     Amd64Instruction, AsmExpr, ImmediateValue, Operand, Program, Section,
+/// };
+///
+/// let half = || ImmediateValue::F64(0.5);
+/// let sse = |mnemonic, operands: Vec<Operand>| {
+///     AsmExpr::Instruction(Amd64Instruction::new(Amd64Mnemonic::parse(mnemonic), operands))
+/// };
+/// let body = vec![
+///     AsmExpr::Label("f".into()),
+///     sse("mulsd", vec![XMM0.into(), half().into()]),
+///     sse("vaddss", vec![XMM0.into(), XMM1.into(), half().into()]),
+///     instr::mov(RAX, half()),
+///     instr::ret(),
+/// ];
+/// let mut program = Program::default().with_section(Section::new("text", body));
+///
+/// assert_eq!(lower_large_immediates(&mut program, LoweringThreshold::Imm64), 3);
+/// let text = program.to_string();
+/// assert!(text.contains("mulsd\txmm0, [rel L_"), "{}", text);
+/// assert!(text.contains("vaddss\txmm0, xmm1, [rel L_"), "{}", text);
+/// assert!(text.contains("mov\trax, [rel L_"), "{}", text);
+/// // The double, which `mov` shares, and the single it rounds to.
+/// assert_eq!(program.pool.len(), 2);
+/// ```
+pub fn lower_large_immediates(program: &mut Program, threshold: LoweringThreshold) -> usize {
+    let mut count = 0;
+
+    for section in program.sections.iter_mut() {
+        count += lower_block(section.body_mut(), &mut program.pool, threshold);
+    }
+
+    count
+}
+
+fn lower_block(body: &mut [AsmExpr], pool: &mut ConstPool, threshold: LoweringThreshold) -> usize {
+    let mut count = 0;
+
+    for expr in body.iter_mut() {
+        let inst = match expr {
+            AsmExpr::Instruction(inst) => inst,
+            _ => {
+                for inner in expr.bodies_mut() {
+                    count += lower_block(inner, pool, threshold);
+                }
+                continue;
+            }
+        };
+
+        if lower_float(inst, pool) {
+            count += 1;
+            continue;
+        }
+
+        if !MEMORY_SOURCE_MNEMONICS.contains(&inst.mnemonic.as_str()) {
+            continue;
+        }
+
+        let [Operand::Register(_), src] = inst.operands.as_mut_slice() else {
+            continue;
+        };
+
+        let value = match src {
+            Operand::Immediate(ImmediateValue::I64(v)) => *v,
+            Operand::Immediate(ImmediateValue::U64(v)) => *v as i64,
+            Operand::Immediate(ImmediateValue::USize(v)) => *v as i64,
+            Operand::Immediate(ImmediateValue::F64(v)) => v.to_bits() as i64,
+            _ => continue,
+        };
+
+        if !threshold.needs_lowering(value) {
+            continue;
+        }
+
+        *src = Operand::Memory(Mem::label(pool.i64(value)));
+        count += 1;
+    }
+
+    count
+}
@@ -0,0 +1,57 @@
+//! One-line instruction construction: `insn!(mov, reg(RAX), imm(1))`.
+//!
+//! Each operand is written as `kind(args)`, which dispatches to the
+//! shorthand macro of the same name, so the shorthands can also be used on
+//! their own wherever an `Operand` is needed.
+
+/// Builds an `AsmExpr::Instruction` from a mnemonic and shorthand operands.
+#[macro_export]
+macro_rules! insn {
+    ($mnemonic:ident $(, $kind:ident ( $($arg:tt)* ))* $(,)?) => {
+        $crate::AsmExpr::Instruction($crate::Amd64Instruction::new(
+            $crate::mnemonic::Amd64Mnemonic::parse(stringify!($mnemonic)),
+            vec![$($crate::$kind!($($arg)*)),*],
+        ))
+    };
+}
+
+/// Register operand: `reg!(RAX)`.
+#[macro_export]
+macro_rules! reg {
+    ($reg:ident) => {
+        $crate::Operand::Register($crate::consts::$reg)
+    };
+}
+
+/// Integer immediate operand: `imm!(60)`.
+#[macro_export]
+macro_rules! imm {
+    ($value:expr) => {
+        $crate::Operand::Immediate($crate::ImmediateValue::I64(($value) as i64))
+    };
+}
+
+/// Label-address immediate operand: `label!("loop_top")` or
+/// `label!(some_label)`.
+#[macro_export]
+macro_rules! label {
+    ($label:expr) => {
+        $crate::Operand::Immediate($crate::ImmediateValue::Label($crate::Label::from($label)))
+    };
+}
+
+/// RIP-relative memory operand: `rel!(message)`.
+#[macro_export]
+macro_rules! rel {
+    ($label:expr) => {
+        $crate::Operand::Memory($crate::Mem::label($crate::Label::from($label)))
+    };
+}
+
+/// Any value convertible into an operand: `op!(count)`.
+#[macro_export]
+macro_rules! op {
+    ($value:expr) => {
+        $crate::Operand::from($value)
+    };
+}
@@ -0,0 +1,368 @@
+//! Typed constructors for common instructions, sitting between raw
+//! `Amd64Instruction::new` calls and the `asm_dsl!` macro.
+//!
+//! Operands that may be any kind accept `impl Into<Operand>`; operands the
+//! instruction constrains (a register destination, a memory source, a
+//! branch target) take the corresponding type.
+
+use std::fmt;
+
+use crate::{
+    hint::BranchHint,
+    mnemonic::Amd64Mnemonic,
+    register::{Register64, Xmm},
+    Amd64Instruction, Amd64Register, AsmExpr, Label, Mem, Operand,
+};
+
+/// x86 condition codes, as used by `jcc` and `cmovcc`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CondCode {
+    O,
+    No,
+    B,
+    Ae,
+    E,
+    Ne,
+    Be,
+    A,
+    S,
+    Ns,
+    P,
+    Np,
+    L,
+    Ge,
+    Le,
+    G,
+}
+
+impl CondCode {
+    /// The condition a `j` or `cmov` suffix such as `ne` or `z` names.
+    pub fn from_suffix(suffix: &str) -> Option<Self> {
+        Some(match suffix {
+            "o" => CondCode::O,
+            "no" => CondCode::No,
+            "b" | "c" | "nae" => CondCode::B,
+            "ae" | "nc" | "nb" => CondCode::Ae,
+            "e" | "z" => CondCode::E,
+            "ne" | "nz" => CondCode::Ne,
+            "be" | "na" => CondCode::Be,
+            "a" | "nbe" => CondCode::A,
+            "s" => CondCode::S,
+            "ns" => CondCode::Ns,
+            "p" | "pe" => CondCode::P,
+            "np" | "po" => CondCode::Np,
+            "l" | "nge" => CondCode::L,
+            "ge" | "nl" => CondCode::Ge,
+            "le" | "ng" => CondCode::Le,
+            "g" | "nle" => CondCode::G,
+            _ => return None,
+        })
+    }
+
+    /// The condition that holds exactly when `self` does not.
+    pub fn negate(self) -> Self {
+        match self {
+            CondCode::O => CondCode::No,
+            CondCode::No => CondCode::O,
+            CondCode::B => CondCode::Ae,
+            CondCode::Ae => CondCode::B,
+            CondCode::E => CondCode::Ne,
+            CondCode::Ne => CondCode::E,
+            CondCode::Be => CondCode::A,
+            CondCode::A => CondCode::Be,
+            CondCode::S => CondCode::Ns,
+            CondCode::Ns => CondCode::S,
+            CondCode::P => CondCode::Np,
+            CondCode::Np => CondCode::P,
+            CondCode::L => CondCode::Ge,
+            CondCode::Ge => CondCode::L,
+            CondCode::Le => CondCode::G,
+            CondCode::G => CondCode::Le,
+        }
+    }
+}
+
+impl fmt::Display for CondCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let suffix = match self {
+            CondCode::O => "o",
+            CondCode::No => "no",
+            CondCode::B => "b",
+            CondCode::Ae => "ae",
+            CondCode::E => "e",
+            CondCode::Ne => "ne",
+            CondCode::Be => "be",
+            CondCode::A => "a",
+            CondCode::S => "s",
+            CondCode::Ns => "ns",
+            CondCode::P => "p",
+            CondCode::Np => "np",
+            CondCode::L => "l",
+            CondCode::Ge => "ge",
+            CondCode::Le => "le",
+            CondCode::G => "g",
+        };
+        write!(f, "{}", suffix)
+    }
+}
+
+fn inst(mnemonic: Amd64Mnemonic, operands: Vec<Operand>) -> AsmExpr {
+    AsmExpr::Instruction(Amd64Instruction::new(mnemonic, operands))
+}
+
+macro_rules! binary {
+    ($($name:ident => $mnemonic:ident),* $(,)?) => {
+        $(
+            pub fn $name(dst: impl Into<Operand>, src: impl Into<Operand>) -> AsmExpr {
+                inst(Amd64Mnemonic::$mnemonic, vec![dst.into(), src.into()])
+            }
+        )*
+    };
+}
+
+macro_rules! unary {
+    ($($name:ident => $mnemonic:ident),* $(,)?) => {
+        $(
+            pub fn $name(operand: impl Into<Operand>) -> AsmExpr {
+                inst(Amd64Mnemonic::$mnemonic, vec![operand.into()])
+            }
+        )*
+    };
+}
+
+macro_rules! nullary {
+    ($($name:ident => $mnemonic:ident),* $(,)?) => {
+        $(
+            pub fn $name() -> AsmExpr {
+                inst(Amd64Mnemonic::$mnemonic, vec![])
+            }
+        )*
+    };
+}
+
+binary! {
+    mov => Mov,
+    add => Add,
+    adc => Adc,
+    sub => Sub,
+    sbb => Sbb,
+    and => And,
+    or => Or,
+    xor => Xor,
+    cmp => Cmp,
+    test => Test,
+    xchg => Xchg,
+    shl => Shl,
+    shr => Shr,
+    sar => Sar,
+    rol => Rol,
+    ror => Ror,
+}
+
+unary! {
+    push => Push,
+    inc => Inc,
+    dec => Dec,
+    neg => Neg,
+    not => Not,
+    mul => Mul,
+    div => Div,
+    idiv => Idiv,
+}
+
+nullary! {
+    ret => Ret,
+    syscall => Syscall,
+    nop => Nop,
+    leave => Leave,
+    cqo => Cqo,
+}
+
+pub fn pop(dst: impl Register64) -> AsmExpr {
+    inst(Amd64Mnemonic::Pop, vec![Operand::Register(dst.into())])
+}
+
+pub fn lea(dst: impl Register64, mem: Mem) -> AsmExpr {
+    inst(
+        Amd64Mnemonic::Lea,
+        vec![Operand::Register(dst.into()), Operand::Memory(mem)],
+    )
+}
+
+/// Two-operand `imul dst, src`.
+pub fn imul(dst: impl Register64, src: impl Into<Operand>) -> AsmExpr {
+    inst(
+        Amd64Mnemonic::Imul,
+        vec![Operand::Register(dst.into()), src.into()],
+    )
+}
+
+pub fn jmp(target: Label) -> AsmExpr {
+    inst(Amd64Mnemonic::Jmp, vec![Operand::from(target)])
+}
+
+pub fn call(target: Label) -> AsmExpr {
+    inst(Amd64Mnemonic::Call, vec![Operand::from(target)])
+}
+
+pub fn jcc(cond: CondCode, target: Label) -> AsmExpr {
+    inst(Amd64Mnemonic::Jcc(cond), vec![Operand::from(target)])
+}
+
+/// A conditional branch expected to be taken.
+pub fn jcc_likely(cond: CondCode, target: Label) -> AsmExpr {
+    hinted(cond, target, BranchHint::Likely)
+}
+
+/// A conditional branch expected to fall through.
+pub fn jcc_unlikely(cond: CondCode, target: Label) -> AsmExpr {
+    hinted(cond, target, BranchHint::Unlikely)
+}
+
+fn hinted(cond: CondCode, target: Label, hint: BranchHint) -> AsmExpr {
+    let inst = Amd64Instruction::new(Amd64Mnemonic::Jcc(cond), vec![Operand::from(target)]);
+    AsmExpr::Instruction(inst.with_hint(hint))
+}
+
+pub fn cmovcc(cond: CondCode, dst: impl Register64, src: impl Into<Operand>) -> AsmExpr {
+    inst(
+        Amd64Mnemonic::Cmovcc(cond),
+        vec![Operand::Register(dst.into()), src.into()],
+    )
+}
+
+/// Reads the fs segment base (the user thread pointer) into `dst`.
+/// Requires [`CpuFeature::Fsgsbase`](crate::target::CpuFeature::Fsgsbase).
+pub fn rdfsbase(dst: impl Register64) -> AsmExpr {
+    inst(Amd64Mnemonic::Rdfsbase, vec![Operand::Register(dst.into())])
+}
+
+/// Sets the fs segment base from `src`.
+/// Requires [`CpuFeature::Fsgsbase`](crate::target::CpuFeature::Fsgsbase).
+pub fn wrfsbase(src: impl Register64) -> AsmExpr {
+    inst(Amd64Mnemonic::Wrfsbase, vec![Operand::Register(src.into())])
+}
+
+/// Reads the gs segment base into `dst`.
+/// Requires [`CpuFeature::Fsgsbase`](crate::target::CpuFeature::Fsgsbase).
+pub fn rdgsbase(dst: impl Register64) -> AsmExpr {
+    inst(Amd64Mnemonic::Rdgsbase, vec![Operand::Register(dst.into())])
+}
+
+/// Sets the gs segment base from `src`.
+/// Requires [`CpuFeature::Fsgsbase`](crate::target::CpuFeature::Fsgsbase).
+pub fn wrgsbase(src: impl Register64) -> AsmExpr {
+    inst(Amd64Mnemonic::Wrgsbase, vec![Operand::Register(src.into())])
+}
+
+/// Exchanges the gs base with the kernel gs base MSR. Privileged, so only
+/// meaningful in kernel entry and exit paths.
+pub fn swapgs() -> AsmExpr {
+    inst(Amd64Mnemonic::Swapgs, vec![])
+}
+
+/// Fetches the cache line holding `addr` into every cache level, for data
+/// about to be used.
+pub fn prefetcht0(addr: Mem) -> AsmExpr {
+    inst(Amd64Mnemonic::Prefetcht0, vec![addr.into()])
+}
+
+/// Fetches the cache line holding `addr` into L2 and outward.
+pub fn prefetcht1(addr: Mem) -> AsmExpr {
+    inst(Amd64Mnemonic::Prefetcht1, vec![addr.into()])
+}
+
+/// Fetches the cache line holding `addr` into L3 and outward.
+pub fn prefetcht2(addr: Mem) -> AsmExpr {
+    inst(Amd64Mnemonic::Prefetcht2, vec![addr.into()])
+}
+
+/// Fetches the cache line holding `addr` close to the core while keeping
+/// it out of the outer caches as far as possible, for data read once.
+pub fn prefetchnta(addr: Mem) -> AsmExpr {
+    inst(Amd64Mnemonic::Prefetchnta, vec![addr.into()])
+}
+
+/// Stores the general-purpose register `src` to `dst` around the caches,
+/// through a write-combining buffer.
+pub fn movnti(dst: Mem, src: impl Register64) -> AsmExpr {
+    inst(
+        Amd64Mnemonic::Movnti,
+        vec![dst.into(), Operand::Register(src.into())],
+    )
+}
+
+/// Non-temporal store of a vector of integers; `dst` must be 16-byte
+/// aligned.
+pub fn movntdq(dst: Mem, src: Xmm) -> AsmExpr {
+    inst(
+        Amd64Mnemonic::Movntdq,
+        vec![dst.into(), Amd64Register::Vector(src).into()],
+    )
+}
+
+/// Non-temporal store of four packed singles; `dst` must be 16-byte
+/// aligned.
+pub fn movntps(dst: Mem, src: Xmm) -> AsmExpr {
+    inst(
+        Amd64Mnemonic::Movntps,
+        vec![dst.into(), Amd64Register::Vector(src).into()],
+    )
+}
+
+/// Non-temporal store of two packed doubles; `dst` must be 16-byte
+/// aligned.
+pub fn movntpd(dst: Mem, src: Xmm) -> AsmExpr {
+    inst(
+        Amd64Mnemonic::Movntpd,
+        vec![dst.into(), Amd64Register::Vector(src).into()],
+    )
+}
+
+/// Orders every earlier store, non-temporal ones included, before any
+/// later one.
+pub fn sfence() -> AsmExpr {
+    inst(Amd64Mnemonic::Sfence, vec![])
+}
+
+/// `stores` followed by the `sfence` that makes non-temporal stores
+/// visible to other cores in order before anything stored afterwards, such
+/// as a flag announcing the data is ready.
+pub fn streaming(stores: Vec<AsmExpr>) -> AsmExpr {
+    let mut body = stores;
+    body.push(sfence());
+    AsmExpr::Block(body)
+}
+
+/// Writes the cache line holding `addr` back to memory if dirty and
+/// evicts it from every cache, ordered with other `clflush`es and stores.
+pub fn clflush(addr: Mem) -> AsmExpr {
+    inst(Amd64Mnemonic::Clflush, vec![addr.into()])
+}
+
+/// Like [`clflush`], but only ordered by fences, so a run of flushes can
+/// overlap; follow them with [`sfence`].
+/// Requires [`CpuFeature::Clflushopt`](crate::target::CpuFeature::Clflushopt).
+pub fn clflushopt(addr: Mem) -> AsmExpr {
+    inst(Amd64Mnemonic::Clflushopt, vec![addr.into()])
+}
+
+/// Writes the cache line holding `addr` back to memory if dirty, possibly
+/// keeping it cached: the usual way to make a store to persistent memory
+/// durable, followed by [`sfence`].
+/// Requires [`CpuFeature::Clwb`](crate::target::CpuFeature::Clwb).
+pub fn clwb(addr: Mem) -> AsmExpr {
+    inst(Amd64Mnemonic::Clwb, vec![addr.into()])
+}
+
+/// Drops the TLB entries for the page holding `addr`, after its page table
+/// entry changes. Privileged: ring 0 only.
+pub fn invlpg(addr: Mem) -> AsmExpr {
+    inst(Amd64Mnemonic::Invlpg, vec![addr.into()])
+}
+
+/// Writes back every dirty line of every cache and invalidates them all.
+/// Privileged: ring 0 only, and very slow.
+pub fn wbinvd() -> AsmExpr {
+    inst(Amd64Mnemonic::Wbinvd, vec![])
+}
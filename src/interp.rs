@@ -0,0 +1,789 @@
+//! A reference interpreter for the integer subset of the AST, used to
+//! check that transformations preserve what a program does.
+//!
+//! Data sections and the constant pool are laid out one after another
+//! from [`DATA_BASE`], the stack grows down from [`STACK_TOP`], and every
+//! memory access is 64 bits wide. So is every register access but those
+//! of `mov` and the bitwise instructions between 32-bit registers and
+//! immediates, which zero the upper half as the CPU does. The program runs
+//! until it calls `exit`, returns from its entry point, or runs out of steps.
+//! Output written to stdout or stderr is captured rather than printed.
+//!
+//! Local labels belong to the non-local label before them, as in NASM, so
+//! functions can each have their own `.loop`:
+//!
+//! ```
+//! use cataclysm::{
+//!     consts::{RAX, RDI},
+//!     instr::{self, CondCode},
+//!     interp,
+//!     register::Gpr,
+//!     AsmExpr, Label, Program, Section,
+//! };
+//!
+//! let program = Program::default().with_section(Section::new(
+//!     "text",
+//!     vec![
+//!         AsmExpr::Label(Label::plain("triangle")),
+//!         instr::xor(RAX, RAX),
+//!         AsmExpr::Label(Label::plain(".loop")),
+//!         instr::add(RAX, RDI),
+//!         instr::dec(RDI),
+//!         instr::jcc(CondCode::Ne, Label::plain(".loop")),
+//!         instr::ret(),
+//!         AsmExpr::Label(Label::plain("double")),
+//!         instr::mov(RAX, RDI),
+//!         AsmExpr::Label(Label::plain(".loop")),
+//!         instr::add(RAX, RAX),
+//!         instr::ret(),
+//!     ],
+//! ));
+//! let out = interp::run_with(&program, "triangle", &[(Gpr::RDI, 4)], 100).unwrap();
+//! assert_eq!(out.status, 10);
+//! let out = interp::run_with(&program, "double", &[(Gpr::RDI, 4)], 100).unwrap();
+//! assert_eq!(out.status, 8);
+//! ```
+
+use std::{collections::HashMap, error, fmt};
+
+use crate::{
+    encode::qualify,
+    expr::ConstExpr,
+    program::Program,
+    qualify_label,
+    register::{Gpr, GprWidth},
+    Amd64Instruction, Amd64Register, AsmExpr, ImmediateValue, Label, Mem, Operand, Section,
+};
+
+pub const DATA_BASE: u64 = 0x1000_0000;
+pub const STACK_TOP: u64 = 0x7fff_0000;
+const STACK_SIZE: u64 = 0x1_0000;
+/// Code addresses are instruction indices offset from here, so return
+/// addresses and code labels can be told apart from data.
+const CODE_BASE: u64 = 0x40_0000;
+
+const SYS_WRITE: u64 = 1;
+const SYS_EXIT: u64 = 60;
+const SYS_EXIT_GROUP: u64 = 231;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InterpError {
+    /// A label used but defined nowhere in the program.
+    UndefinedLabel(String),
+    /// The entry point label does not name an instruction.
+    NoEntry(String),
+    /// An instruction or operand outside the interpreted subset.
+    Unsupported(String),
+    /// A constant expression that could not be evaluated.
+    Expr(String),
+    /// A memory access outside the data and stack regions.
+    BadAddress(u64),
+    /// A jump or return to something other than an instruction.
+    BadTarget(u64),
+    /// Division by zero or a quotient that does not fit.
+    DivideError,
+    StepLimit,
+}
+
+impl fmt::Display for InterpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InterpError::UndefinedLabel(name) => write!(f, "label `{}` is not defined", name),
+            InterpError::NoEntry(name) => {
+                write!(f, "entry point `{}` is not in a text section", name)
+            }
+            InterpError::Unsupported(what) => {
+                write!(f, "`{}` is not supported by the interpreter", what)
+            }
+            InterpError::Expr(message) => write!(f, "{}", message),
+            InterpError::BadAddress(addr) => write!(f, "access to unmapped address {:#x}", addr),
+            InterpError::BadTarget(addr) => {
+                write!(f, "control transfer to non-code address {:#x}", addr)
+            }
+            InterpError::DivideError => write!(f, "division error"),
+            InterpError::StepLimit => write!(f, "step limit exceeded"),
+        }
+    }
+}
+
+impl error::Error for InterpError {}
+
+/// What a finished run did, as far as the outside world can see.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    /// The status passed to `exit`, or rax when the entry point returned.
+    pub status: u64,
+    /// Bytes written to stdout and stderr, in order.
+    pub output: Vec<u8>,
+    /// The general-purpose registers when the run ended, indexed by
+    /// [`Gpr::index`].
+    pub registers: Vec<u64>,
+    pub steps: usize,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Flags {
+    zero: bool,
+    sign: bool,
+    carry: bool,
+    overflow: bool,
+    parity: bool,
+}
+
+impl Flags {
+    fn logic(result: u64) -> Self {
+        Flags {
+            zero: result == 0,
+            sign: (result as i64) < 0,
+            carry: false,
+            overflow: false,
+            parity: (result as u8).count_ones().is_multiple_of(2),
+        }
+    }
+}
+
+/// Runs `program` from the label `entry` for at most `step_limit`
+/// instructions.
+pub fn run(program: &Program, entry: &str, step_limit: usize) -> Result<Outcome, InterpError> {
+    run_with(program, entry, &[], step_limit)
+}
+
+/// [`run`], with the registers in `registers` set to the given values
+/// first, as a caller would pass arguments. rsp always starts at
+/// [`STACK_TOP`], so a value for it is ignored.
+pub fn run_with(
+    program: &Program,
+    entry: &str,
+    registers: &[(Gpr, u64)],
+    step_limit: usize,
+) -> Result<Outcome, InterpError> {
+    let mut machine = Machine::new(program)?;
+    for &(reg, value) in registers {
+        if reg != Gpr::RSP {
+            machine.regs[reg.index() as usize] = value;
+        }
+    }
+    machine.run(entry, step_limit)
+}
+
+struct Machine {
+    code: Vec<Amd64Instruction>,
+    symbols: HashMap<String, u64>,
+    /// The non-local label local ones are laid out under.
+    scope: String,
+    defines: HashMap<String, ConstExpr>,
+    data: Vec<u8>,
+    stack: Vec<u8>,
+    regs: [u64; 32],
+    flags: Flags,
+    output: Vec<u8>,
+}
+
+impl Machine {
+    fn new(program: &Program) -> Result<Self, InterpError> {
+        let mut machine = Machine {
+            code: Vec::new(),
+            symbols: HashMap::new(),
+            scope: String::new(),
+            defines: program.define_map(),
+            data: Vec::new(),
+            stack: vec![0; STACK_SIZE as usize],
+            regs: [0; 32],
+            flags: Flags::default(),
+            output: Vec::new(),
+        };
+
+        let pool = program.pool.to_section();
+        let sections: Vec<&Section> = program.sections.iter().chain([&pool]).collect();
+
+        // Labels first, so data can hold addresses of code and vice versa.
+        let mut pending = Vec::new();
+        for section in &sections {
+            let text = section.name.starts_with("text");
+            let start = machine.data.len();
+            machine.layout(&section.body, section, text, start, program, &mut pending);
+        }
+        for (offset, label, base) in pending {
+            let addr = machine.symbol(&label)?;
+            match base {
+                Some(base) => {
+                    let distance = addr.wrapping_sub(machine.symbol(&base)?) as i32;
+                    machine.data[offset..offset + 4].copy_from_slice(&distance.to_le_bytes());
+                }
+                None => machine.data[offset..offset + 8].copy_from_slice(&addr.to_le_bytes()),
+            }
+        }
+
+        machine.regs[4] = STACK_TOP;
+        Ok(machine)
+    }
+
+    /// Places the labels, instructions and data of `body`, with every local
+    /// label, where defined and where used, qualified by the non-local
+    /// label before it as NASM does.
+    fn layout(
+        &mut self,
+        body: &[AsmExpr],
+        section: &Section,
+        text: bool,
+        start: usize,
+        program: &Program,
+        pending: &mut Vec<(usize, String, Option<String>)>,
+    ) {
+        for expr in body {
+            match expr {
+                AsmExpr::Label(label) => {
+                    if !label.label.starts_with('.') {
+                        self.scope = label.label.clone();
+                    }
+                    let addr = if text {
+                        CODE_BASE + self.code.len() as u64
+                    } else {
+                        DATA_BASE + self.data.len() as u64
+                    };
+                    let name = qualify_label(&self.scope, &label.label);
+                    self.symbols.insert(name, addr);
+                }
+                AsmExpr::Instruction(inst) if text => self.code.push(qualified(inst, &self.scope)),
+                AsmExpr::Data(data) if !text => {
+                    let name = |label: &Label| qualify_label(&self.scope, &label.label);
+                    match data.labels()[..] {
+                        [label] => pending.push((self.data.len(), name(label), None)),
+                        [label, base] => {
+                            pending.push((self.data.len(), name(label), Some(name(base))))
+                        }
+                        _ => {}
+                    }
+                    let offset = (self.data.len() - start) as u64;
+                    self.data.extend(data.to_bytes_at(section.endian, offset));
+                }
+                AsmExpr::If {
+                    cond,
+                    then,
+                    otherwise,
+                } => {
+                    let arm = if cond.eval(&program.config) {
+                        then
+                    } else {
+                        otherwise
+                    };
+                    self.layout(arm, section, text, start, program, pending);
+                }
+                AsmExpr::Block(inner)
+                | AsmExpr::Region { body: inner, .. }
+                | AsmExpr::Macro { body: inner, .. } => {
+                    self.layout(inner, section, text, start, program, pending)
+                }
+                // Raw lines, parameters and misplaced items take no space
+                // the interpreter knows about.
+                _ => {}
+            }
+        }
+    }
+
+    fn symbol(&self, name: &str) -> Result<u64, InterpError> {
+        self.symbols
+            .get(name)
+            .copied()
+            .ok_or_else(|| InterpError::UndefinedLabel(name.to_string()))
+    }
+
+    fn run(mut self, entry: &str, step_limit: usize) -> Result<Outcome, InterpError> {
+        let mut pc = match self.symbols.get(entry) {
+            Some(&addr) if (CODE_BASE..DATA_BASE).contains(&addr) => (addr - CODE_BASE) as usize,
+            _ => return Err(InterpError::NoEntry(entry.to_string())),
+        };
+
+        for steps in 0..step_limit {
+            if pc >= self.code.len() {
+                return Err(InterpError::BadTarget(CODE_BASE + pc as u64));
+            }
+            let inst = self.code[pc].clone();
+            pc += 1;
+
+            match self.step(&inst, pc)? {
+                Step::Next => {}
+                Step::Jump(target) => pc = self.code_index(target)?,
+                Step::Halt(status) => {
+                    return Ok(Outcome {
+                        status,
+                        output: self.output,
+                        registers: self.regs.to_vec(),
+                        steps: steps + 1,
+                    })
+                }
+            }
+        }
+
+        Err(InterpError::StepLimit)
+    }
+
+    fn code_index(&self, addr: u64) -> Result<usize, InterpError> {
+        if addr >= CODE_BASE && addr - CODE_BASE < self.code.len() as u64 {
+            Ok((addr - CODE_BASE) as usize)
+        } else {
+            Err(InterpError::BadTarget(addr))
+        }
+    }
+
+    fn register(reg: &Amd64Register) -> Result<usize, InterpError> {
+        reg.gpr()
+            .map(|gpr| gpr.index() as usize)
+            .ok_or_else(|| InterpError::Unsupported(reg.to_string()))
+    }
+
+    fn immediate(&self, imm: &ImmediateValue) -> Result<u64, InterpError> {
+        Ok(match imm {
+            ImmediateValue::I64(v) => *v as u64,
+            ImmediateValue::U64(v) => *v,
+            ImmediateValue::USize(v) => *v as u64,
+            ImmediateValue::F64(v) => v.to_bits(),
+            ImmediateValue::Label(label) => self.symbol(&label.label)?,
+            ImmediateValue::Expr(expr) => {
+                expr.eval(&self.defines)
+                    .map_err(|e| InterpError::Expr(e.to_string()))? as u64
+            }
+            ImmediateValue::Bytes(_) => return Err(InterpError::Unsupported(imm.to_string())),
+        })
+    }
+
+    fn address(&self, operand: &Operand) -> Result<u64, InterpError> {
+        match operand {
+            Operand::Memory(mem) => {
+                let mut addr = match &mem.label {
+                    Some(label) => self.symbol(&label.label)?,
+                    None => 0,
+                };
+                // A label reached through rip is simply its address.
+                match &mem.base {
+                    Some(_) if mem.label.is_some() && mem.is_rip_relative() => {}
+                    Some(base) => addr = addr.wrapping_add(self.regs[Self::register(base)?]),
+                    None => {}
+                }
+                if let Some(index) = &mem.index {
+                    let index = self.regs[Self::register(index)?];
+                    addr = addr.wrapping_add(index.wrapping_mul(mem.scale as u64));
+                }
+                Ok(addr.wrapping_add(mem.displacement as u64))
+            }
+            _ => Err(InterpError::Unsupported(operand.to_string())),
+        }
+    }
+
+    fn memory(&mut self, addr: u64, len: u64) -> Result<&mut [u8], InterpError> {
+        let (region, base) = if addr >= DATA_BASE && addr < DATA_BASE + self.data.len() as u64 {
+            (&mut self.data, DATA_BASE)
+        } else {
+            (&mut self.stack, STACK_TOP - STACK_SIZE)
+        };
+        let start = addr.wrapping_sub(base);
+        match start.checked_add(len) {
+            Some(end) if end <= region.len() as u64 => {
+                Ok(&mut region[start as usize..end as usize])
+            }
+            _ => Err(InterpError::BadAddress(addr)),
+        }
+    }
+
+    fn load(&mut self, addr: u64) -> Result<u64, InterpError> {
+        let bytes = self.memory(addr, 8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn store(&mut self, addr: u64, value: u64) -> Result<(), InterpError> {
+        self.memory(addr, 8)?.copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    fn read(&mut self, operand: &Operand) -> Result<u64, InterpError> {
+        match operand {
+            Operand::Register(Amd64Register::Partial(gpr, GprWidth::Dword)) => {
+                Ok(self.regs[gpr.index() as usize] as u32 as u64)
+            }
+            Operand::Register(reg) => Ok(self.regs[Self::register(reg)?]),
+            Operand::Immediate(imm) => self.immediate(imm),
+            _ => {
+                let addr = self.address(operand)?;
+                self.load(addr)
+            }
+        }
+    }
+
+    fn write(&mut self, operand: &Operand, value: u64) -> Result<(), InterpError> {
+        match operand {
+            Operand::Register(Amd64Register::Partial(gpr, GprWidth::Dword)) => {
+                self.regs[gpr.index() as usize] = value as u32 as u64;
+                Ok(())
+            }
+            Operand::Register(reg) => {
+                self.regs[Self::register(reg)?] = value;
+                Ok(())
+            }
+            Operand::Immediate(_) | Operand::Param(_) => {
+                Err(InterpError::Unsupported(operand.to_string()))
+            }
+            _ => {
+                let addr = self.address(operand)?;
+                self.store(addr, value)
+            }
+        }
+    }
+
+    fn push(&mut self, value: u64) -> Result<(), InterpError> {
+        self.regs[4] = self.regs[4].wrapping_sub(8);
+        self.store(self.regs[4], value)
+    }
+
+    fn pop(&mut self) -> Result<u64, InterpError> {
+        let value = self.load(self.regs[4])?;
+        self.regs[4] = self.regs[4].wrapping_add(8);
+        Ok(value)
+    }
+
+    fn condition(&self, suffix: &str) -> Option<bool> {
+        let f = self.flags;
+        Some(match suffix {
+            "o" => f.overflow,
+            "no" => !f.overflow,
+            "b" | "c" | "nae" => f.carry,
+            "ae" | "nc" | "nb" => !f.carry,
+            "e" | "z" => f.zero,
+            "ne" | "nz" => !f.zero,
+            "be" | "na" => f.carry || f.zero,
+            "a" | "nbe" => !f.carry && !f.zero,
+            "s" => f.sign,
+            "ns" => !f.sign,
+            "p" | "pe" => f.parity,
+            "np" | "po" => !f.parity,
+            "l" | "nge" => f.sign != f.overflow,
+            "ge" | "nl" => f.sign == f.overflow,
+            "le" | "ng" => f.zero || f.sign != f.overflow,
+            "g" | "nle" => !f.zero && f.sign == f.overflow,
+            _ => return None,
+        })
+    }
+
+    fn step(&mut self, inst: &Amd64Instruction, next: usize) -> Result<Step, InterpError> {
+        let ops = &inst.operands;
+        let unsupported = || InterpError::Unsupported(inst.to_string());
+        let op = |i: usize| ops.get(i).ok_or_else(unsupported);
+        let dword = ops.iter().any(|op| {
+            matches!(
+                op,
+                Operand::Register(Amd64Register::Partial(_, GprWidth::Dword))
+            )
+        });
+        if dword
+            && (!matches!(
+                inst.mnemonic.as_str(),
+                "mov" | "and" | "or" | "xor" | "test"
+            ) || ops.iter().any(|op| matches!(op, Operand::Memory(_))))
+        {
+            return Err(unsupported());
+        }
+
+        match (inst.mnemonic.as_str(), ops.len()) {
+            ("mov", 2) => {
+                let value = self.read(op(1)?)?;
+                self.write(op(0)?, value)?;
+            }
+            ("lea", 2) => {
+                let addr = self.address(op(1)?)?;
+                self.write(op(0)?, addr)?;
+            }
+            ("xchg", 2) => {
+                let (a, b) = (self.read(op(0)?)?, self.read(op(1)?)?);
+                self.write(op(0)?, b)?;
+                self.write(op(1)?, a)?;
+            }
+            ("add" | "adc" | "sub" | "sbb" | "cmp", 2) => {
+                let (a, b) = (self.read(op(0)?)?, self.read(op(1)?)?);
+                let carry_in = matches!(inst.mnemonic.as_str(), "adc" | "sbb") && self.flags.carry;
+                let subtract = matches!(inst.mnemonic.as_str(), "sub" | "sbb" | "cmp");
+                let (result, carry, overflow) = if subtract {
+                    let (r1, c1) = a.overflowing_sub(b);
+                    let (r, c2) = r1.overflowing_sub(carry_in as u64);
+                    let overflow = ((a ^ b) & (a ^ r)) >> 63 == 1;
+                    (r, c1 || c2, overflow)
+                } else {
+                    let (r1, c1) = a.overflowing_add(b);
+                    let (r, c2) = r1.overflowing_add(carry_in as u64);
+                    let overflow = (!(a ^ b) & (a ^ r)) >> 63 == 1;
+                    (r, c1 || c2, overflow)
+                };
+                self.flags = Flags {
+                    carry,
+                    overflow,
+                    ..Flags::logic(result)
+                };
+                if inst.mnemonic != "cmp" {
+                    self.write(op(0)?, result)?;
+                }
+            }
+            ("and" | "or" | "xor" | "test", 2) => {
+                let (a, b) = (self.read(op(0)?)?, self.read(op(1)?)?);
+                let result = match inst.mnemonic.as_str() {
+                    "or" => a | b,
+                    "xor" => a ^ b,
+                    _ => a & b,
+                };
+                // The flags of a 32-bit result are those of it sign-extended.
+                self.flags = if dword {
+                    Flags::logic(result as i32 as u64)
+                } else {
+                    Flags::logic(result)
+                };
+                if inst.mnemonic != "test" {
+                    self.write(op(0)?, result)?;
+                }
+            }
+            ("inc" | "dec", 1) => {
+                let a = self.read(op(0)?)?;
+                let result = if inst.mnemonic == "inc" {
+                    a.wrapping_add(1)
+                } else {
+                    a.wrapping_sub(1)
+                };
+                let overflow = if inst.mnemonic == "inc" {
+                    a == i64::MAX as u64
+                } else {
+                    a == i64::MIN as u64
+                };
+                // inc and dec leave the carry flag alone.
+                self.flags = Flags {
+                    carry: self.flags.carry,
+                    overflow,
+                    ..Flags::logic(result)
+                };
+                self.write(op(0)?, result)?;
+            }
+            ("neg", 1) => {
+                let a = self.read(op(0)?)?;
+                let result = a.wrapping_neg();
+                self.flags = Flags {
+                    carry: a != 0,
+                    overflow: a == i64::MIN as u64,
+                    ..Flags::logic(result)
+                };
+                self.write(op(0)?, result)?;
+            }
+            ("not", 1) => {
+                let a = self.read(op(0)?)?;
+                self.write(op(0)?, !a)?;
+            }
+            ("shl" | "shr" | "sar" | "rol" | "ror", 2) => {
+                let a = self.read(op(0)?)?;
+                let count = (self.read(op(1)?)? & 63) as u32;
+                if count == 0 {
+                    return Ok(Step::Next);
+                }
+                let (result, carry) = match inst.mnemonic.as_str() {
+                    "shl" => (a << count, (a >> (64 - count)) & 1 == 1),
+                    "shr" => (a >> count, (a >> (count - 1)) & 1 == 1),
+                    "sar" => (
+                        ((a as i64) >> count) as u64,
+                        ((a as i64) >> (count - 1)) & 1 == 1,
+                    ),
+                    "rol" => (a.rotate_left(count), a.rotate_left(count) & 1 == 1),
+                    _ => (a.rotate_right(count), a.rotate_right(count) >> 63 == 1),
+                };
+                if matches!(inst.mnemonic.as_str(), "rol" | "ror") {
+                    self.flags.carry = carry;
+                } else {
+                    self.flags = Flags {
+                        carry,
+                        ..Flags::logic(result)
+                    };
+                }
+                self.write(op(0)?, result)?;
+            }
+            ("imul", 2) => {
+                let (a, b) = (self.read(op(0)?)? as i64, self.read(op(1)?)? as i64);
+                let (result, overflow) = a.overflowing_mul(b);
+                self.flags = Flags {
+                    carry: overflow,
+                    overflow,
+                    ..Flags::logic(result as u64)
+                };
+                self.write(op(0)?, result as u64)?;
+            }
+            ("mul" | "imul" | "div" | "idiv", 1) => {
+                let src = self.read(op(0)?)?;
+                let (rax, rdx) = (self.regs[0], self.regs[2]);
+                let (lo, hi) = match inst.mnemonic.as_str() {
+                    "mul" => {
+                        let wide = rax as u128 * src as u128;
+                        let hi = (wide >> 64) as u64;
+                        self.flags.carry = hi != 0;
+                        self.flags.overflow = hi != 0;
+                        (wide as u64, hi)
+                    }
+                    "imul" => {
+                        let wide = rax as i64 as i128 * src as i64 as i128;
+                        let fits = i64::try_from(wide).is_ok();
+                        self.flags.carry = !fits;
+                        self.flags.overflow = !fits;
+                        (wide as u64, (wide >> 64) as u64)
+                    }
+                    "div" => {
+                        let dividend = (rdx as u128) << 64 | rax as u128;
+                        let quotient = dividend
+                            .checked_div(src as u128)
+                            .filter(|q| *q <= u64::MAX as u128)
+                            .ok_or(InterpError::DivideError)?;
+                        (quotient as u64, (dividend % src as u128) as u64)
+                    }
+                    _ => {
+                        let dividend = ((rdx as u128) << 64 | rax as u128) as i128;
+                        let quotient = dividend
+                            .checked_div(src as i64 as i128)
+                            .filter(|q| i64::try_from(*q).is_ok())
+                            .ok_or(InterpError::DivideError)?;
+                        let remainder = dividend % src as i64 as i128;
+                        (quotient as u64, remainder as u64)
+                    }
+                };
+                self.regs[0] = lo;
+                self.regs[2] = hi;
+            }
+            ("cqo", 0) => {
+                self.regs[2] = if (self.regs[0] as i64) < 0 {
+                    u64::MAX
+                } else {
+                    0
+                }
+            }
+            ("push", 1) => {
+                let value = self.read(op(0)?)?;
+                self.push(value)?;
+            }
+            ("pop", 1) => {
+                let value = self.pop()?;
+                self.write(op(0)?, value)?;
+            }
+            ("leave", 0) => {
+                self.regs[4] = self.regs[5];
+                self.regs[5] = self.pop()?;
+            }
+            ("nop", 0) => {}
+            ("jmp", 1) => return Ok(Step::Jump(self.read(op(0)?)?)),
+            ("call", 1) => {
+                let target = self.read(op(0)?)?;
+                self.push(CODE_BASE + next as u64)?;
+                return Ok(Step::Jump(target));
+            }
+            ("ret", 0) => {
+                if self.regs[4] == STACK_TOP {
+                    return Ok(Step::Halt(self.regs[0]));
+                }
+                return Ok(Step::Jump(self.pop()?));
+            }
+            ("syscall", 0) => return self.syscall(),
+            (m, 1) if m.starts_with('j') => {
+                let taken = self.condition(&m[1..]).ok_or_else(unsupported)?;
+                if taken {
+                    return Ok(Step::Jump(self.read(op(0)?)?));
+                }
+            }
+            (m, 2) if m.starts_with("cmov") => {
+                let taken = self.condition(&m[4..]).ok_or_else(unsupported)?;
+                if taken {
+                    let value = self.read(op(1)?)?;
+                    self.write(op(0)?, value)?;
+                }
+            }
+            _ => return Err(unsupported()),
+        }
+
+        Ok(Step::Next)
+    }
+
+    fn syscall(&mut self) -> Result<Step, InterpError> {
+        // The kernel clobbers rcx and r11.
+        let nr = self.regs[0];
+        self.regs[1] = 0;
+        self.regs[11] = 0;
+
+        match nr {
+            SYS_WRITE => {
+                let (fd, buf, len) = (self.regs[7], self.regs[6], self.regs[2]);
+                if fd != 1 && fd != 2 {
+                    return Err(InterpError::Unsupported(format!("write to fd {}", fd)));
+                }
+                let bytes = self.memory(buf, len)?.to_vec();
+                self.output.extend(bytes);
+                self.regs[0] = len;
+                Ok(Step::Next)
+            }
+            SYS_EXIT | SYS_EXIT_GROUP => Ok(Step::Halt(self.regs[7])),
+            _ => Err(InterpError::Unsupported(format!("syscall {}", nr))),
+        }
+    }
+}
+
+/// `inst` with the local labels it refers to qualified by `scope`.
+fn qualified(inst: &Amd64Instruction, scope: &str) -> Amd64Instruction {
+    let mut inst = inst.clone();
+    for operand in &mut inst.operands {
+        match operand {
+            Operand::Immediate(ImmediateValue::Label(label)) => {
+                label.label = qualify_label(scope, &label.label);
+            }
+            Operand::Immediate(ImmediateValue::Expr(expr)) => *expr = qualify(expr.clone(), scope),
+            Operand::Memory(Mem {
+                label: Some(label), ..
+            }) => label.label = qualify_label(scope, &label.label),
+            _ => {}
+        }
+    }
+    inst
+}
+
+enum Step {
+    Next,
+    Jump(u64),
+    Halt(u64),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm_dsl;
+
+    fn run_body(body: Vec<AsmExpr>) -> Result<Outcome, InterpError> {
+        let mut text = vec![AsmExpr::Label(Label::plain("f"))];
+        text.extend(body);
+        let program = Program::default().with_section(Section::new("text", text));
+        run_with(&program, "f", &[(Gpr::RDI, u64::MAX)], 100)
+    }
+
+    #[test]
+    fn dword_writes_zero_the_upper_half() {
+        let out = run_body(asm_dsl! {
+            mov rax, rdi;
+            xor eax, eax;
+            mov rcx, rdi;
+            mov ecx, 5;
+            mov rdx, rdi;
+            or edx, 0;
+            mov rsi, 0;
+            mov r8, 1;
+            cmovs rsi, r8;
+            ret;
+        })
+        .unwrap();
+        assert_eq!(&out.registers[..3], [0, 5, 0xffff_ffff]);
+        // The sign of a 32-bit result is its bit 31.
+        assert_eq!(out.registers[Gpr::RSI.index() as usize], 1);
+    }
+
+    #[test]
+    fn dword_arithmetic_is_unsupported() {
+        let err = run_body(asm_dsl! {
+            add eax, 1;
+            ret;
+        })
+        .unwrap_err();
+        assert!(matches!(err, InterpError::Unsupported(_)));
+    }
+}
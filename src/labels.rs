@@ -0,0 +1,234 @@
+//! Keeping label names straight.
+//!
+//! A [`LabelTable`] hands out labels by name and refuses to define one
+//! twice, catching the mistake where the label is made rather than where
+//! the assembler trips over it. [`check`] looks at a finished program
+//! however its labels were made, and finds every symbol it uses without
+//! defining:
+//!
+//! ```
+//! use cataclysm::{instr, labels::LabelError, AsmExpr, Program, Section};
+//!
+//! let mut program = Program::default();
+//! let entry = program.labels.define("entry").unwrap();
+//! let done = program.labels.reference("done");
+//! assert!(program.labels.define("entry").is_err());
+//!
+//! program.sections.push(Section::new(
+//!     "text",
+//!     vec![AsmExpr::Label(entry), instr::jmp(done)],
+//! ));
+//! let [LabelError::Undefined { name, section, item }] = &program.check_labels()[..] else {
+//!     panic!();
+//! };
+//! assert_eq!((name.as_str(), section.as_str(), item.as_str()), ("done", "text", "jmp\tdone"));
+//! ```
+
+use std::{
+    collections::{BTreeSet, HashSet},
+    error, fmt,
+};
+
+use crate::{program::Program, qualify_label, region_symbols, symbol_words, AsmExpr, Label};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LabelError {
+    /// The label is defined twice; local labels are named with their
+    /// scope.
+    Duplicate(String),
+    /// `name` is used by `item` in `section` but defined nowhere.
+    Undefined {
+        name: String,
+        section: String,
+        item: String,
+    },
+}
+
+impl fmt::Display for LabelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LabelError::Duplicate(name) => write!(f, "label `{}` is defined twice", name),
+            LabelError::Undefined {
+                name,
+                section,
+                item,
+            } => write!(
+                f,
+                "`{}` is not defined, but `{}` in section {} uses it",
+                name,
+                item.replace('\t', " "),
+                section
+            ),
+        }
+    }
+}
+
+impl error::Error for LabelError {}
+
+/// The labels a program builder has made, each name kept once.
+///
+/// Local labels are scoped to the non-local label defined before them, as
+/// NASM scopes them, so `.loop` may be defined once under every function.
+#[derive(Debug, Clone, Default)]
+pub struct LabelTable {
+    defined: BTreeSet<String>,
+    referenced: BTreeSet<String>,
+    scope: String,
+}
+
+impl LabelTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A label to define at `name`, unless that has been done already.
+    pub fn define(&mut self, name: &str) -> Result<Label, LabelError> {
+        let qualified = qualify_label(&self.scope, name);
+        if !self.defined.insert(qualified.clone()) {
+            return Err(LabelError::Duplicate(qualified));
+        }
+        if !name.starts_with('.') {
+            self.scope = name.to_string();
+        }
+        Ok(Label::plain(name))
+    }
+
+    /// A label referring to `name`, which may be defined before or after.
+    pub fn reference(&mut self, name: &str) -> Label {
+        self.referenced.insert(qualify_label(&self.scope, name));
+        Label::plain(name)
+    }
+
+    pub fn is_defined(&self, name: &str) -> bool {
+        self.defined.contains(name)
+    }
+
+    /// Names referred to through the table but not defined through it,
+    /// sorted. These may still be defined some other way, as externs are.
+    pub fn undefined(&self) -> impl Iterator<Item = &str> {
+        self.referenced
+            .difference(&self.defined)
+            .map(String::as_str)
+    }
+}
+
+/// Every label `program` defines twice, then every use of a symbol it
+/// defines nowhere, in program order. Only the arms of conditionals that
+/// are emitted count. Symbols on `Raw` lines are not checked, as nothing
+/// tells them apart from mnemonics and registers, though `equ` lines
+/// define their names.
+pub fn check(program: &Program) -> Vec<LabelError> {
+    let pool = (!program.pool.is_empty()).then(|| program.pool.to_section());
+    let mut walk = Walk {
+        program,
+        defined: HashSet::new(),
+        uses: Vec::new(),
+        errors: Vec::new(),
+        scope: String::new(),
+        section: "",
+    };
+    for section in program.sections.iter().chain(&pool) {
+        walk.section = &section.name;
+        walk.body(&section.body);
+    }
+
+    let mut known = walk.defined;
+    known.extend(["$".to_string(), "$$".to_string()]);
+    known.extend(program.defines.iter().map(|(name, _)| name.clone()));
+    known.extend(program.externs.iter().map(|ext| ext.value.clone()));
+    for object in &program.objects {
+        for section in &object.sections {
+            known.extend(section.symbols.iter().map(|(name, _)| name.clone()));
+        }
+    }
+
+    let mut errors = walk.errors;
+    errors.extend(
+        walk.uses.into_iter().filter(
+            |err| !matches!(err, LabelError::Undefined { name, .. } if known.contains(name)),
+        ),
+    );
+    errors
+}
+
+struct Walk<'a> {
+    program: &'a Program,
+    defined: HashSet<String>,
+    /// Every reference, as the error it is if the symbol turns out to be
+    /// undefined.
+    uses: Vec<LabelError>,
+    errors: Vec<LabelError>,
+    scope: String,
+    section: &'a str,
+}
+
+impl Walk<'_> {
+    fn define(&mut self, name: String) {
+        if self.defined.contains(&name) {
+            self.errors.push(LabelError::Duplicate(name));
+        } else {
+            self.defined.insert(name);
+        }
+    }
+
+    fn body(&mut self, body: &[AsmExpr]) {
+        for expr in body {
+            match expr {
+                AsmExpr::Label(label) => {
+                    let name = qualify_label(&self.scope, &label.label);
+                    if !label.label.starts_with('.') {
+                        self.scope = label.label.clone();
+                    }
+                    self.define(name);
+                }
+                AsmExpr::Raw(text) => {
+                    let mut words = symbol_words(text);
+                    if let (Some(name), Some("equ")) = (words.next(), words.next()) {
+                        self.define(name.to_string());
+                    }
+                }
+                AsmExpr::Param(_) => {}
+                AsmExpr::Block(body) | AsmExpr::Macro { body, .. } => self.body(body),
+                AsmExpr::Region { name, body } => {
+                    let (begin, end) = region_symbols(name);
+                    if self.program.region_symbols {
+                        self.define(begin);
+                    }
+                    self.body(body);
+                    if self.program.region_symbols {
+                        self.define(end);
+                    }
+                }
+                AsmExpr::If {
+                    cond,
+                    then,
+                    otherwise,
+                } => {
+                    let arm = if cond.eval(&self.program.config) {
+                        then
+                    } else {
+                        otherwise
+                    };
+                    self.body(arm);
+                }
+                AsmExpr::Instruction(_)
+                | AsmExpr::Arm64(_)
+                | AsmExpr::Riscv(_)
+                | AsmExpr::Data(_) => {
+                    let item = expr.to_string().trim().to_string();
+                    let mut names = Vec::new();
+                    AsmExpr::visit_references(std::slice::from_ref(expr), &mut |name| {
+                        names.push(qualify_label(&self.scope, name));
+                    });
+                    for name in names {
+                        self.uses.push(LabelError::Undefined {
+                            name,
+                            section: self.section.to_string(),
+                            item: item.clone(),
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
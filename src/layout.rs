@@ -0,0 +1,98 @@
+//! The program as laid out for encoding: where every label, instruction
+//! and data item lands, for listings, maps and anything else that needs
+//! offsets without the bytes themselves.
+//!
+//! Layout takes two passes. The first sizes every item and places it,
+//! growing branches that cannot reach their targets; the second resolves
+//! what depends on the result, such as `len equ $ - name` constants. See
+//! [`encode::layout`](crate::encode::layout).
+
+use std::{collections::HashMap, fmt};
+
+use crate::{Amd64Instruction, Data};
+
+#[derive(Clone)]
+pub enum EntryKind {
+    Label(String),
+    Instruction(Amd64Instruction),
+    Data(Data),
+    Equ {
+        name: String,
+        value: i64,
+    },
+    /// A section of the linked object of this name.
+    Object(String),
+}
+
+/// One item of a section and where it lands.
+#[derive(Clone)]
+pub struct Entry {
+    /// Bytes from the start of the section.
+    pub offset: u64,
+    pub size: u64,
+    pub kind: EntryKind,
+}
+
+#[derive(Clone)]
+pub struct SectionLayout {
+    pub name: String,
+    pub address: u64,
+    pub size: u64,
+    pub entries: Vec<Entry>,
+}
+
+impl SectionLayout {
+    /// The first entry at or covering `offset`.
+    pub fn entry_at(&self, offset: u64) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .find(|e| e.offset == offset || (e.offset..e.offset + e.size).contains(&offset))
+    }
+}
+
+/// Every section of a program with its entries, and the address of every
+/// label. Sections sharing a name are laid out as one.
+#[derive(Clone)]
+pub struct Layout {
+    pub sections: Vec<SectionLayout>,
+    pub symbols: HashMap<String, u64>,
+}
+
+impl Layout {
+    pub fn section(&self, name: &str) -> Option<&SectionLayout> {
+        self.sections.iter().find(|s| s.name == name)
+    }
+}
+
+/// Renders the layout as the program's text annotated with the offset
+/// and size of each line.
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for section in &self.sections {
+            writeln!(
+                f,
+                "section .{}\t; {:#x}, {} bytes",
+                section.name, section.address, section.size
+            )?;
+            for entry in &section.entries {
+                write!(f, "{:08X}  ", entry.offset)?;
+                match &entry.kind {
+                    EntryKind::Label(name) => writeln!(f, "    {}:", name)?,
+                    EntryKind::Instruction(inst) => writeln!(
+                        f,
+                        "{:>2}    {}",
+                        entry.size,
+                        inst.to_string().replace('\t', " ")
+                    )?,
+                    EntryKind::Data(data) => writeln!(f, "{:>2}    {}", entry.size, data)?,
+                    EntryKind::Object(name) => {
+                        writeln!(f, "{:>2}    ; object {}", entry.size, name)?
+                    }
+                    EntryKind::Equ { name, value } => writeln!(f, "    {} equ {}", name, value)?,
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
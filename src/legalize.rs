@@ -0,0 +1,352 @@
+//! Rewriting instructions x86 cannot encode as written into ones it can.
+//!
+//! Most x86 arithmetic overwrites its first source, while code lowered
+//! from three-address form names a destination apart from both sources.
+//! [`two_address`] accepts that form, `add dst, a, b` and the like for the
+//! binary arithmetic, logic and shift instructions and `neg dst, a` for
+//! the unary ones, with `dst` a register, and rewrites each into the
+//! two-address instructions doing the same. No `mov` is added where the
+//! destination is already a source of a commutative operation, and an
+//! addition whose flags are dead becomes a single `lea`:
+//!
+//! ```
+//! use cataclysm::{consts::{RAX, RBX, RCX}, instr, legalize::two_address_body, mnemonic::Amd64Mnemonic, AsmExpr, Amd64Instruction};
+//!
+//! let three = |mnemonic, operands| AsmExpr::Instruction(Amd64Instruction::new(mnemonic, operands));
+//! let mut body = vec![
+//!     three(Amd64Mnemonic::Add, vec![RAX.into(), RBX.into(), RCX.into()]),
+//!     three(Amd64Mnemonic::Xor, vec![RCX.into(), RBX.into(), RCX.into()]),
+//!     instr::ret(),
+//! ];
+//! assert_eq!(two_address_body(&mut body), Ok(2));
+//! let lines: Vec<String> = body.iter().map(|expr| expr.to_string().trim().replace('\t', " ")).collect();
+//! assert_eq!(lines, ["lea rax, [rbx + rcx]", "xor rcx, rbx", "ret"]);
+//! ```
+//!
+//! A destination that is also the second source of a subtraction or
+//! shift is computed in a spare register, dead if one is and pushed and
+//! popped around otherwise. Liveness is followed as
+//! [`fixed`](crate::fixed) follows it.
+//!
+//! Immediates are sign-extended from at most 32 bits, save what `mov`
+//! puts in a register. [`immediates`] loads any wider constant a 64-bit
+//! operation takes into a fresh virtual register and has the operation
+//! read that instead, so `add rax, 0x123456789` becomes
+//! `mov v0, 0x123456789` and `add rax, v0`. The virtual registers come
+//! from the same [`VRegs`] as the function's own, and the
+//! [allocator](crate::regalloc::allocate) then finds each a scratch
+//! register wherever one is free.
+
+use std::{collections::HashMap, error, fmt};
+
+use crate::{
+    callconv::below,
+    dataflow::{constant, split_prefix, straight_line_liveness, RegSet},
+    fixed::{pick, reads, register, rename},
+    instr::{add, imul, mov, neg, pop, push},
+    mnemonic::Amd64Mnemonic,
+    program::Program,
+    regalloc::VRegs,
+    register::{Gpr, GprWidth},
+    Amd64Instruction, Amd64Register, AsmExpr, Mem, Operand,
+};
+
+/// Binary instructions, and whether their sources commute.
+const BINARY: &[(&str, bool)] = &[
+    ("add", true),
+    ("adc", true),
+    ("and", true),
+    ("or", true),
+    ("xor", true),
+    ("imul", true),
+    ("sub", false),
+    ("sbb", false),
+    ("shl", false),
+    ("sal", false),
+    ("shr", false),
+    ("sar", false),
+    ("rol", false),
+    ("ror", false),
+];
+
+const UNARY: &[&str] = &["neg", "not", "inc", "dec"];
+
+/// Instructions taking a 32-bit immediate as their last operand and with a
+/// form reading a register in its place.
+const IMMEDIATE: &[&str] = &[
+    "add", "adc", "sub", "sbb", "and", "or", "xor", "cmp", "test", "imul",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LegalizeError {
+    /// A three-address instruction whose destination is not a
+    /// general-purpose register.
+    Destination(String),
+}
+
+impl fmt::Display for LegalizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LegalizeError::Destination(instruction) => write!(
+                f,
+                "`{}` must write a general-purpose register",
+                instruction.replace('\t', " ")
+            ),
+        }
+    }
+}
+
+impl error::Error for LegalizeError {}
+
+/// Rewrites the three-address instructions in the program's text
+/// sections, in both arms of conditionals, and returns how many it
+/// rewrote.
+pub fn two_address(program: &mut Program) -> Result<usize, LegalizeError> {
+    let mut rewritten = 0;
+    for section in program
+        .sections
+        .iter_mut()
+        .filter(|s| s.name.starts_with("text"))
+    {
+        rewritten += two_address_body(section.body_mut())?;
+    }
+    Ok(rewritten)
+}
+
+/// Rewrites the three-address instructions in `body`, each into one
+/// instruction or a block, and returns how many it rewrote.
+pub fn two_address_body(body: &mut [AsmExpr]) -> Result<usize, LegalizeError> {
+    let mut rewritten = 0;
+    for i in 0..body.len() {
+        let AsmExpr::Instruction(inst) = &body[i] else {
+            for inner in body[i].bodies_mut() {
+                rewritten += two_address_body(inner)?;
+            }
+            continue;
+        };
+        let Some(form) = ThreeAddress::of(inst)? else {
+            continue;
+        };
+        let (live, flags) = straight_line_liveness(&body[i + 1..]);
+        let mut code = form.lower(live, flags);
+        body[i] = match code.len() {
+            1 => code.remove(0),
+            _ => AsmExpr::Block(code),
+        };
+        rewritten += 1;
+    }
+    Ok(rewritten)
+}
+
+/// Rewrites the instructions in the program's text sections whose
+/// immediates do not fit their encodings, taking scratch registers from
+/// `vregs`, and returns how many it rewrote.
+pub fn immediates(program: &mut Program, vregs: &mut VRegs) -> usize {
+    let mut rewritten = 0;
+    for section in program
+        .sections
+        .iter_mut()
+        .filter(|s| s.name.starts_with("text"))
+    {
+        rewritten += immediates_body(section.body_mut(), vregs);
+    }
+    rewritten
+}
+
+/// Rewrites the instructions in `body` whose immediates do not fit their
+/// encodings, each into a block, and returns how many it rewrote.
+pub fn immediates_body(body: &mut [AsmExpr], vregs: &mut VRegs) -> usize {
+    let mut rewritten = 0;
+    for expr in body.iter_mut() {
+        let AsmExpr::Instruction(inst) = expr else {
+            for inner in expr.bodies_mut() {
+                rewritten += immediates_body(inner, vregs);
+            }
+            continue;
+        };
+        if let Some(code) = load_immediate(inst, vregs) {
+            *expr = AsmExpr::Block(code);
+            rewritten += 1;
+        }
+    }
+    rewritten
+}
+
+/// `inst` reading its immediate from a fresh register, if the immediate
+/// is too wide for it. Immediates of narrower operations are left for
+/// the assembler to reject, as they fit no register form either.
+fn load_immediate(inst: &Amd64Instruction, vregs: &mut VRegs) -> Option<Vec<AsmExpr>> {
+    let (_, mnemonic) = split_prefix(inst.mnemonic.as_str());
+    let ops = &inst.operands;
+    let imm = ops.last()?;
+    if i32::try_from(constant(imm, &HashMap::new())?).is_ok() {
+        return None;
+    }
+    let quad = |operand: &Operand| match operand {
+        Operand::Register(reg) => reg.containing_gpr().is_some() && reg.width() == Some(64),
+        Operand::Memory(mem) => mem.size == Some(GprWidth::Qword),
+        _ => false,
+    };
+    let wide = match (mnemonic, ops.len()) {
+        ("push", 1) => true,
+        ("mov", 2) => matches!(ops[0], Operand::Memory(_)) && quad(&ops[0]),
+        ("imul", 3) => quad(&ops[0]),
+        (m, 2) => IMMEDIATE.contains(&m) && quad(&ops[0]),
+        _ => false,
+    };
+    if !wide {
+        return None;
+    }
+
+    let scratch = vregs.fresh();
+    let load = mov(scratch, imm.clone());
+    if ops.len() == 3 {
+        // `imul dst, src, imm` has no form with a register for `imm`, so
+        // the product is built in the scratch register.
+        return Some(vec![
+            load,
+            imul(scratch, ops[1].clone()),
+            mov(ops[0].clone(), scratch),
+        ]);
+    }
+    let mut rewritten = inst.clone();
+    *rewritten.operands.last_mut()? = scratch.into();
+    Some(vec![load, AsmExpr::Instruction(rewritten)])
+}
+
+/// `dst = a op b`, or `dst = op a` when `b` is `None`.
+struct ThreeAddress {
+    mnemonic: Amd64Mnemonic,
+    dst: Amd64Register,
+    a: Operand,
+    b: Option<Operand>,
+    commutes: bool,
+}
+
+impl ThreeAddress {
+    fn of(inst: &Amd64Instruction) -> Result<Option<Self>, LegalizeError> {
+        let (_, mnemonic) = split_prefix(inst.mnemonic.as_str());
+        let ops = &inst.operands;
+        let commutes = match (ops.len(), mnemonic) {
+            // `imul dst, src, imm` is an instruction of its own.
+            (3, "imul") if matches!(ops[2], Operand::Immediate(_)) => return Ok(None),
+            (3, m) => match BINARY.iter().find(|&&(name, _)| name == m) {
+                Some(&(_, commutes)) => commutes,
+                None => return Ok(None),
+            },
+            (2, m) if UNARY.contains(&m) => false,
+            _ => return Ok(None),
+        };
+        let dst = match &ops[0] {
+            Operand::Register(reg) if reg.containing_gpr().is_some() => reg.clone(),
+            _ => return Err(LegalizeError::Destination(inst.to_string())),
+        };
+        Ok(Some(ThreeAddress {
+            mnemonic: inst.mnemonic.clone(),
+            dst,
+            a: ops[1].clone(),
+            b: ops.get(2).cloned(),
+            commutes,
+        }))
+    }
+
+    /// The two-address code, given the registers and flags live after it.
+    fn lower(self, live: RegSet, flags: bool) -> Vec<AsmExpr> {
+        let dst = Operand::Register(self.dst.clone());
+        let gpr = self.dst.containing_gpr().unwrap();
+        let op = |dst: &Operand, src: Option<&Operand>| {
+            let mut operands = vec![dst.clone()];
+            operands.extend(src.cloned());
+            AsmExpr::Instruction(Amd64Instruction::new(self.mnemonic.clone(), operands))
+        };
+        let copy = |dst: &Operand, src: &Operand| mov(dst.clone(), src.clone());
+
+        let Some(b) = &self.b else {
+            if same_register(&dst, &self.a) {
+                return vec![op(&dst, None)];
+            }
+            return vec![copy(&dst, &self.a), op(&dst, None)];
+        };
+        if same_register(&dst, &self.a) {
+            return vec![op(&dst, Some(b))];
+        }
+        if self.commutes && same_register(&dst, b) {
+            return vec![op(&dst, Some(&self.a))];
+        }
+        if !reads(b).contains(gpr) {
+            if !flags {
+                if let Some(address) = self.address(b) {
+                    let lea = Amd64Instruction::new(Amd64Mnemonic::Lea, vec![dst, address.into()]);
+                    return vec![AsmExpr::Instruction(lea)];
+                }
+            }
+            return vec![copy(&dst, &self.a), op(&dst, Some(b))];
+        }
+        // `a - dst` as `-dst + a`, where the flags are not wanted.
+        if !flags && self.mnemonic == "sub" && same_register(&dst, b) {
+            return vec![neg(dst.clone()), add(dst, self.a.clone())];
+        }
+
+        // The second source reads the destination, so the result is built
+        // elsewhere.
+        let mut named = reads(&self.a).union(reads(b));
+        named.insert(gpr);
+        named.insert(Gpr::RSP);
+        let spare = pick(named, live);
+        let mut temp = dst.clone();
+        rename(&mut temp, gpr, spare);
+        let saved = live.contains(spare);
+        let lowered = if saved { 8 } else { 0 };
+        let (a, b) = (below(&self.a, lowered), below(b, lowered));
+
+        let mut code = Vec::new();
+        if saved {
+            code.push(push(register(spare)));
+        }
+        code.extend([copy(&temp, &a), op(&temp, Some(&b)), copy(&dst, &temp)]);
+        if saved {
+            code.push(pop(spare));
+        }
+        code
+    }
+
+    /// `a + b` or `a - b` as an address, for a 64-bit `lea` of two
+    /// registers or a register and a 32-bit displacement.
+    fn address(&self, b: &Operand) -> Option<Mem> {
+        let Operand::Register(a) = &self.a else {
+            return None;
+        };
+        if self.dst.gpr().is_none() || a.gpr().is_none() {
+            return None;
+        }
+        let base = Mem::base(a.clone());
+        // Wrapping is fine: the address is computed modulo 2^64 too.
+        let displacement = |value: i64| i32::try_from(value).ok().map(i64::from);
+        match (self.mnemonic.as_str(), b) {
+            ("add", Operand::Register(index)) if index.gpr().is_some_and(|g| g != Gpr::RSP) => {
+                Some(base.with_index(index.clone(), 1))
+            }
+            ("add", Operand::Immediate(_)) => {
+                displacement(constant(b, &HashMap::new())?).map(|d| base.with_displacement(d))
+            }
+            ("sub", Operand::Immediate(_)) => {
+                let value = constant(b, &HashMap::new())?.checked_neg()?;
+                displacement(value).map(|d| base.with_displacement(d))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Whether `a` and `b` are the same general-purpose register at the same
+/// width.
+fn same_register(a: &Operand, b: &Operand) -> bool {
+    match (a, b) {
+        (Operand::Register(a), Operand::Register(b)) => {
+            a.containing_gpr().is_some()
+                && a.containing_gpr() == b.containing_gpr()
+                && a.width() == b.width()
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,1740 @@
+//! An x86-64 macro-assembler for compilers and JITs, producing NASM
+//! source or machine code.
+//!
+//! Code is an [`AsmExpr`] tree: instructions, data, labels and blocks,
+//! grouped into [`Section`]s of a [`Program`] together with the symbols it
+//! exports and imports. [`asm_dsl!`] writes instructions in NASM syntax,
+//! and [`instr`] has a builder for each instruction. A program renders to
+//! NASM with its `Display` implementation, or to bytes with
+//! [`Program::encode`].
+//!
+//! ```
+//! use cataclysm::{asm_dsl, Program, Section};
+//!
+//! let program = Program::default()
+//!     .with_global("_start")
+//!     .with_define("SYS_EXIT", 60)
+//!     .with_section(Section::new(
+//!         "text",
+//!         asm_dsl! {
+//!             _start:
+//!             mov rax, {cataclysm::ConstExpr::sym("SYS_EXIT")};
+//!             xor rdi, rdi;
+//!             syscall;
+//!         },
+//!     ));
+//! assert!(program.to_string().contains("global _start"));
+//! ```
+
+// Lets `asm_dsl!` expansions name items as `::cataclysm::...`.
+extern crate self as cataclysm;
+
+pub mod abi;
+pub mod alignment;
+pub mod amx;
+pub mod arm64;
+pub mod array;
+mod att;
+pub mod bench;
+pub mod bitfield;
+pub mod bitmanip;
+pub mod blob;
+pub mod callconv;
+pub mod cfg;
+pub mod cond;
+pub mod consts;
+pub mod crypto;
+pub mod dataflow;
+pub mod dedup;
+pub mod describe;
+pub mod elf;
+pub mod encode;
+pub mod enum_export;
+pub mod equiv;
+pub mod expr;
+pub mod extable;
+pub mod fixed;
+pub mod flags;
+pub mod flow;
+#[cfg(feature = "arbitrary")]
+pub mod fuzz;
+pub mod fpenv;
+pub mod frame;
+pub mod frontend;
+mod gnu;
+pub mod highlight;
+pub mod hint;
+pub mod imm_lowering;
+pub mod insn;
+pub mod interp;
+pub mod instr;
+pub mod labels;
+pub mod layout;
+pub mod legalize;
+pub mod lint;
+#[cfg(feature = "llvm")]
+pub mod llvm;
+pub mod macros;
+pub mod metadata;
+pub mod mnemonic;
+pub mod object;
+pub mod optimize;
+pub mod patch;
+pub mod policy;
+pub mod pool;
+pub mod probe;
+pub mod program;
+pub mod refgraph;
+pub mod regalloc;
+pub mod register;
+pub mod riscv;
+pub mod rng;
+pub mod sections;
+pub mod simd;
+pub mod sizing;
+pub mod spill;
+pub mod stack;
+pub mod startup;
+pub mod stats;
+pub mod stream;
+pub mod strength;
+pub mod syscall;
+pub mod table;
+pub mod target;
+pub mod template;
+pub mod testgen;
+pub mod timing;
+pub mod unwind;
+pub mod vdso;
+
+use std::{
+    cell::RefCell,
+    collections::{hash_map::DefaultHasher, HashMap},
+    error,
+    fmt,
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use array::Array;
+/// Memory operands take every form a [`Mem`] can describe:
+///
+/// ```
+/// use cataclysm::{asm_dsl, Label};
+///
+/// let table = Label::plain("table");
+/// let code = asm_dsl! {
+///     mov rax, [rdi];
+///     mov rax, [rbp - 8];
+///     mov rax, [rsp + 0x10];
+///     lea rax, [rdi + rcx*8 + 16];
+///     lea rax, [rdi + rsi];
+///     mov rax, [rbx + {table} + rcx*8];
+///     mov rax, qword [8*rcx + rdx - 4];
+///     mov eax, dword [rel table + 4];
+///     mov rax, [0x1000];
+/// };
+/// let lines: Vec<String> = code.iter().map(|expr| expr.to_string().trim().replace('\t', " ")).collect();
+/// assert_eq!(
+///     lines,
+///     [
+///         "mov rax, [rdi]",
+///         "mov rax, [rbp - 8]",
+///         "mov rax, [rsp + 16]",
+///         "lea rax, [rdi + rcx*8 + 16]",
+///         "lea rax, [rdi + rsi]",
+///         "mov rax, [rbx + table + rcx*8]",
+///         "mov rax, qword [rdx + rcx*8 - 4]",
+///         "mov eax, dword [rel table + 4]",
+///         "mov rax, [4096]",
+///     ]
+/// );
+/// ```
+///
+/// and reject at compile time what it cannot, such as a scale other than
+/// 1, 2, 4 or 8:
+///
+/// ```compile_fail
+/// cataclysm::asm_dsl! { mov rax, [rdi + rcx*3]; };
+/// ```
+///
+/// rsp as an index:
+///
+/// ```compile_fail
+/// cataclysm::asm_dsl! { mov rax, [rdi + rsp*2]; };
+/// ```
+///
+/// a register that cannot address memory:
+///
+/// ```compile_fail
+/// cataclysm::asm_dsl! { mov rax, [al]; };
+/// ```
+///
+/// a subtracted register:
+///
+/// ```compile_fail
+/// cataclysm::asm_dsl! { mov rax, [rbp - rcx]; };
+/// ```
+///
+/// two labels:
+///
+/// ```compile_fail
+/// cataclysm::asm_dsl! { mov rax, [first + second]; };
+/// ```
+///
+/// or a register with `rel`:
+///
+/// ```compile_fail
+/// cataclysm::asm_dsl! { mov rax, [rel rax]; };
+/// ```
+pub use cataclysm_macros::asm_dsl;
+use cond::{BuildConfig, Cond};
+pub use expr::ConstExpr;
+use hint::BranchHint;
+use mnemonic::Amd64Mnemonic;
+pub use program::Program;
+use register::{Gpr, GprWidth, Opmask, RegisterError, Tmm, VReg, Xmm, Ymm, Zmm};
+
+/// A symbol name, as defined by [`AsmExpr::Label`] or referenced by an
+/// operand.
+#[derive(Clone)]
+pub struct Label {
+    pub label: String,
+}
+
+/// A symbol exported from the program.
+#[derive(Clone)]
+pub struct Global {
+    pub value: String,
+}
+
+/// A symbol defined outside the program and resolved at link time.
+#[derive(Clone)]
+pub struct Extern {
+    pub value: String,
+}
+
+impl Label {
+    pub fn plain(label: &str) -> Self {
+        Label {
+            label: label.to_string(),
+        }
+    }
+
+    pub fn hashed(label: &str) -> Self {
+        let label = HASHED_LABELS.with(|cache| {
+            if let Some(hashed) = cache.borrow().get(label) {
+                return hashed.clone();
+            }
+
+            let mut hasher = DefaultHasher::new();
+            label.hash(&mut hasher);
+            let hashed = format!("L_{:x}", hasher.finish());
+
+            let mut cache = cache.borrow_mut();
+            if cache.len() >= HASHED_LABEL_CACHE_SIZE {
+                cache.clear();
+            }
+            cache.insert(label.to_string(), hashed.clone());
+            hashed
+        });
+
+        Label { label }
+    }
+
+    /// A hashed label for `name` that no other call in the process
+    /// returns, for code that may be emitted any number of times into one
+    /// program, by copies of one generator as much as by one.
+    pub fn unique(name: &str) -> Self {
+        static INSTANCES: AtomicUsize = AtomicUsize::new(0);
+        let instance = INSTANCES.fetch_add(1, Ordering::Relaxed);
+        Label::hashed(&format!("{}.{}", name, instance))
+    }
+}
+
+/// Entries kept by the [`Label::hashed`] cache before it starts over.
+const HASHED_LABEL_CACHE_SIZE: usize = 1 << 16;
+
+thread_local! {
+    /// `Label::hashed` results by name, as generators hash the same names
+    /// over and over.
+    static HASHED_LABELS: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
+}
+
+impl From<&str> for Label {
+    fn from(label: &str) -> Self {
+        Label::plain(label)
+    }
+}
+
+impl Global {
+    pub fn new(value: &str) -> Self {
+        Global {
+            value: value.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:", self.label)
+    }
+}
+
+impl fmt::Display for Global {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "global {}", self.value)
+    }
+}
+
+impl Extern {
+    pub fn new(value: &str) -> Self {
+        Extern {
+            value: value.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Extern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "extern {}", self.value)
+    }
+}
+
+fn is_symbol_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.'
+}
+
+/// The symbol-like words of a line of raw assembly text.
+fn symbol_words(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !is_symbol_char(c))
+        .filter(|w| !w.is_empty())
+}
+
+/// The full name NASM gives label `name` when it appears under the
+/// non-local label `scope`: `.loop` under `copy` is `copy.loop`.
+fn qualify_label(scope: &str, name: &str) -> String {
+    if name.starts_with('.') && !name.starts_with("..") {
+        format!("{}{}", scope, name)
+    } else {
+        name.to_string()
+    }
+}
+
+/// Rewrites every symbol-like word of `text` for which `rename` returns a
+/// replacement, leaving punctuation and whitespace untouched.
+fn rename_symbols(text: &str, rename: impl Fn(&str) -> Option<String>) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut start = None;
+
+    for (i, c) in text.char_indices() {
+        match (is_symbol_char(c), start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                let word = &text[s..i];
+                out.push_str(&rename(word).unwrap_or_else(|| word.to_string()));
+                out.push(c);
+                start = None;
+            }
+            (false, None) => out.push(c),
+            (true, Some(_)) => {}
+        }
+    }
+    if let Some(s) = start {
+        let word = &text[s..];
+        out.push_str(&rename(word).unwrap_or_else(|| word.to_string()));
+    }
+
+    out
+}
+
+/// A mnemonic, with any prefixes, and its operands. A prefixed mnemonic
+/// such as `rep movsb` is an [`Amd64Mnemonic::Raw`] one.
+#[derive(Clone)]
+pub struct Amd64Instruction {
+    pub mnemonic: Amd64Mnemonic,
+    pub operands: Vec<Operand>,
+    /// Which way a conditional branch is expected to go.
+    pub hint: Option<BranchHint>,
+    /// Where execution resumes if the instruction faults, recorded in the
+    /// exception table.
+    pub fixup: Option<Label>,
+    /// Lints that do not report this instruction, by name.
+    pub allow: Vec<String>,
+}
+
+/// An immediate operand or constant value.
+#[derive(Clone)]
+pub enum ImmediateValue {
+    Label(Label),
+    U64(u64),
+    USize(usize),
+    I64(i64),
+    /// A double whose bit pattern is the operand, as NASM's
+    /// `__?float64?__` gives it. SSE instructions take no immediates, so
+    /// one in vector code needs
+    /// [`lower_large_immediates`](imm_lowering::lower_large_immediates) to
+    /// move it into memory before the program assembles.
+    F64(f64),
+    Bytes(&'static [u8]),
+    Expr(ConstExpr),
+}
+
+impl fmt::Display for ImmediateValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImmediateValue::U64(n) => write!(f, "{}", n),
+            ImmediateValue::I64(n) => write!(f, "{}", n),
+            ImmediateValue::USize(n) => write!(f, "{}", n),
+            ImmediateValue::F64(v) => write!(f, "__?float64?__({})", float_literal(*v)),
+            ImmediateValue::Expr(e) => write!(f, "{}", e),
+            ImmediateValue::Label(s) => {
+                write!(f, "{}", s.label)
+            }
+            ImmediateValue::Bytes(b) => {
+                
+                for (i, &byte) in b.iter().enumerate() {
+                    let formatted_byte = format!("0x{:02X}", byte);
+                
+                    if i == b.len() - 1 {
+                        write!(f, "{}", formatted_byte).unwrap();
+                    } else {
+                        write!(f, "{}, ", formatted_byte).unwrap();
+                    }
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+/// An instruction operand.
+#[derive(Clone)]
+pub enum Operand {
+    Register(Amd64Register),
+    Immediate(ImmediateValue),
+    Memory(Mem),
+    /// Placeholder for a macro or template parameter.
+    Param(String),
+}
+
+impl Operand {
+    /// Size in bits of a register operand, or of a memory operand given
+    /// one; otherwise the instruction decides.
+    pub fn width(&self) -> Option<u32> {
+        match self {
+            Operand::Register(reg) => reg.width(),
+            Operand::Memory(mem) => mem.size.map(GprWidth::bits),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Register(reg) => write!(f, "{}", reg),
+            Operand::Immediate(imm) => write!(f, "{}", imm),
+            Operand::Param(name) => write!(f, "%{}", name),
+            Operand::Memory(mem) => write!(f, "{}", mem),
+        }
+    }
+}
+
+impl From<Amd64Register> for Operand {
+    fn from(reg: Amd64Register) -> Self {
+        Operand::Register(reg)
+    }
+}
+
+impl From<ImmediateValue> for Operand {
+    fn from(imm: ImmediateValue) -> Self {
+        Operand::Immediate(imm)
+    }
+}
+
+impl From<i64> for Operand {
+    fn from(value: i64) -> Self {
+        Operand::Immediate(ImmediateValue::I64(value))
+    }
+}
+
+// Plain integer literals default to `i32`, so these keep `mov(RAX, 1)`
+// working without a suffix.
+impl From<i32> for Operand {
+    fn from(value: i32) -> Self {
+        Operand::Immediate(ImmediateValue::I64(value.into()))
+    }
+}
+
+impl From<u32> for Operand {
+    fn from(value: u32) -> Self {
+        Operand::Immediate(ImmediateValue::I64(value.into()))
+    }
+}
+
+impl From<u64> for Operand {
+    fn from(value: u64) -> Self {
+        Operand::Immediate(ImmediateValue::U64(value))
+    }
+}
+
+impl From<usize> for Operand {
+    fn from(value: usize) -> Self {
+        Operand::Immediate(ImmediateValue::USize(value))
+    }
+}
+
+impl From<ConstExpr> for Operand {
+    fn from(expr: ConstExpr) -> Self {
+        Operand::Immediate(ImmediateValue::Expr(expr))
+    }
+}
+
+impl From<Label> for Operand {
+    fn from(label: Label) -> Self {
+        Operand::Immediate(ImmediateValue::Label(label))
+    }
+}
+
+impl From<Mem> for Operand {
+    fn from(mem: Mem) -> Self {
+        Operand::Memory(mem)
+    }
+}
+
+/// A register outside the general-purpose file. Every general-purpose
+/// register is an [`Amd64Register::GeneralPurpose`], whatever constant or
+/// name it came from.
+#[derive(Clone)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Amd64SpecialRegister {
+    RIP,
+}
+
+impl fmt::Display for Amd64SpecialRegister {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Amd64SpecialRegister::RIP => write!(f, "rip"),
+        }
+    }
+}
+
+/// Any register an operand can name.
+#[derive(Clone)]
+pub enum Amd64Register {
+    GeneralPurpose(Gpr),
+    /// The instruction pointer, which only an address can name.
+    Special(Amd64SpecialRegister),
+    /// An AMX tile register.
+    Tile(Tmm),
+    /// An SSE vector register.
+    Vector(Xmm),
+    /// A 256-bit AVX vector register.
+    Ymm(Ymm),
+    /// A 512-bit AVX-512 vector register.
+    Zmm(Zmm),
+    /// The low 8, 16 or 32 bits of a general-purpose register, such as
+    /// `eax` or `r9b`.
+    Partial(Gpr, GprWidth),
+    /// An AVX-512 opmask register.
+    Mask(Opmask),
+    /// The low 8, 16, 32 or all 64 bits of a virtual register, for
+    /// [`regalloc`] to replace with a general-purpose register.
+    Virtual(VReg, GprWidth),
+}
+
+impl Amd64Register {
+    /// General-purpose register by architectural number, rejecting indices
+    /// past r15.
+    pub fn general_purpose(index: u32) -> Result<Self, RegisterError> {
+        Gpr::new(index).map(Amd64Register::GeneralPurpose)
+    }
+
+    /// The general-purpose register this names in full. Only `rip`, tiles,
+    /// vector, opmask, partial and virtual registers have none.
+    pub fn gpr(&self) -> Option<Gpr> {
+        match self {
+            Amd64Register::GeneralPurpose(gpr) => Some(*gpr),
+            Amd64Register::Special(_)
+            | Amd64Register::Tile(_)
+            | Amd64Register::Vector(_)
+            | Amd64Register::Ymm(_)
+            | Amd64Register::Zmm(_)
+            | Amd64Register::Partial(..)
+            | Amd64Register::Mask(_)
+            | Amd64Register::Virtual(..) => None,
+        }
+    }
+
+    /// The general-purpose register this names all or part of. Analyses
+    /// that track whole registers should use [`Amd64Register::gpr`],
+    /// which has none for partial registers.
+    pub fn containing_gpr(&self) -> Option<Gpr> {
+        match self {
+            Amd64Register::Partial(gpr, _) => Some(*gpr),
+            _ => self.gpr(),
+        }
+    }
+
+    /// Size in bits of what the register holds. Tiles are configured at
+    /// run time, so have none.
+    pub fn width(&self) -> Option<u32> {
+        match self {
+            Amd64Register::GeneralPurpose(_) | Amd64Register::Special(_) => Some(64),
+            Amd64Register::Partial(_, width) | Amd64Register::Virtual(_, width) => {
+                Some(width.bits())
+            }
+            Amd64Register::Vector(_) => Some(128),
+            Amd64Register::Ymm(_) => Some(256),
+            Amd64Register::Zmm(_) => Some(512),
+            Amd64Register::Mask(_) => Some(64),
+            Amd64Register::Tile(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Amd64Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Amd64Register::GeneralPurpose(reg) => write!(f, "{}", reg),
+            Amd64Register::Special(reg) => write!(f, "{}", reg),
+            Amd64Register::Tile(reg) => write!(f, "{}", reg),
+            Amd64Register::Vector(reg) => write!(f, "{}", reg),
+            Amd64Register::Ymm(reg) => write!(f, "{}", reg),
+            Amd64Register::Zmm(reg) => write!(f, "{}", reg),
+            Amd64Register::Mask(reg) => write!(f, "{}", reg),
+            Amd64Register::Partial(reg, width) => write!(f, "{}", reg.name_at(*width)),
+            Amd64Register::Virtual(reg, width) => write!(f, "{}", reg.name_at(*width)),
+            // Add more cases for other register types (e.g., SIMD, FP) as needed
+        }
+    }
+}
+
+/// A memory operand, `[base + index*scale + label + disp]`.
+///
+/// With a label and no base register the address is RIP-relative, which
+/// is how data in the program is usually reached; with neither it is the
+/// absolute address `disp`. Registers and scale are checked by the
+/// builder methods, which panic on an address x86-64 cannot form;
+/// [`Mem::validate`] checks one built field by field.
+///
+/// ```
+/// use cataclysm::{consts::{RBX, RCX}, register::GprWidth, Label, Mem};
+///
+/// let mem = Mem::base(RBX).with_index(RCX, 8).with_displacement(-16);
+/// assert_eq!(mem.to_string(), "[rbx + rcx*8 - 16]");
+/// let mem = Mem::label(Label::plain("counts")).with_size(GprWidth::Dword);
+/// assert_eq!(mem.to_string(), "dword [rel counts]");
+/// ```
+#[derive(Clone)]
+pub struct Mem {
+    pub base: Option<Amd64Register>,
+    pub index: Option<Amd64Register>,
+    pub scale: u32,
+    pub label: Option<Label>,
+    pub displacement: i64,
+    /// The size of the access, for instructions nothing else sizes.
+    pub size: Option<GprWidth>,
+}
+
+/// Why a [`Mem`] is not an address x86-64 can form.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemError {
+    /// The register cannot be a base: not a 64- or 32-bit general-purpose
+    /// register, or rip.
+    Base(String),
+    /// The register cannot be an index: rsp, rip, or not a 64- or 32-bit
+    /// general-purpose register.
+    Index(String),
+    Scale(u32),
+    /// rip-relative addresses take no index.
+    RipIndex,
+    /// The displacement does not fit in 32 bits.
+    Displacement(i64),
+}
+
+impl fmt::Display for MemError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemError::Base(reg) => write!(f, "{} cannot be a base register", reg),
+            MemError::Index(reg) => write!(f, "{} cannot be an index register", reg),
+            MemError::Scale(scale) => write!(f, "scale {} is not 1, 2, 4 or 8", scale),
+            MemError::RipIndex => write!(f, "a rip-relative address cannot have an index"),
+            MemError::Displacement(d) => write!(f, "displacement {} does not fit in 32 bits", d),
+        }
+    }
+}
+
+impl error::Error for MemError {}
+
+/// Whether `reg` can appear in an address at all: the whole of a
+/// general-purpose register, or its low 32 bits.
+fn addressing(reg: &Amd64Register) -> bool {
+    match reg {
+        Amd64Register::Partial(_, GprWidth::Dword) => true,
+        Amd64Register::Virtual(_, width) => matches!(width, GprWidth::Dword | GprWidth::Qword),
+        _ => reg.gpr().is_some(),
+    }
+}
+
+fn is_rip(reg: &Amd64Register) -> bool {
+    matches!(reg, Amd64Register::Special(Amd64SpecialRegister::RIP))
+}
+
+impl Mem {
+    /// `[base]`, to be extended with an index or displacement.
+    ///
+    /// # Panics
+    ///
+    /// If `base` cannot be a base register.
+    pub fn base(base: Amd64Register) -> Self {
+        Mem {
+            base: None,
+            index: None,
+            scale: 1,
+            label: None,
+            displacement: 0,
+            size: None,
+        }
+        .with_base(base)
+    }
+
+    /// `[rel label]`, to be given a base register if it is not reached
+    /// RIP-relative, or a displacement.
+    pub fn label(label: Label) -> Self {
+        Mem {
+            base: None,
+            index: None,
+            scale: 1,
+            label: Some(label),
+            displacement: 0,
+            size: None,
+        }
+    }
+
+    /// # Panics
+    ///
+    /// If `base` cannot be a base register.
+    pub fn with_base(mut self, base: Amd64Register) -> Self {
+        self.base = Some(base);
+        self.checked()
+    }
+
+    /// # Panics
+    ///
+    /// If `index` cannot be an index register, `scale` is not 1, 2, 4 or
+    /// 8, or the address is rip-relative.
+    pub fn with_index(mut self, index: Amd64Register, scale: u32) -> Self {
+        self.index = Some(index);
+        self.scale = scale;
+        self.checked()
+    }
+
+    /// # Panics
+    ///
+    /// If `displacement` does not fit in 32 bits and is not an absolute
+    /// address.
+    pub fn with_displacement(mut self, displacement: i64) -> Self {
+        self.displacement = displacement;
+        self.checked()
+    }
+
+    pub fn with_size(mut self, size: GprWidth) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    /// Whether the address is relative to the next instruction, through
+    /// a label without a base register or through rip.
+    pub fn is_rip_relative(&self) -> bool {
+        match &self.base {
+            None => self.label.is_some(),
+            Some(base) => is_rip(base),
+        }
+    }
+
+    /// The registers the address reads, base first.
+    pub fn registers(&self) -> impl Iterator<Item = &Amd64Register> {
+        self.base.iter().chain(&self.index)
+    }
+
+    pub fn validate(&self) -> Result<(), MemError> {
+        if let Some(base) = &self.base {
+            if !addressing(base) && !is_rip(base) {
+                return Err(MemError::Base(base.to_string()));
+            }
+        }
+        if let Some(index) = &self.index {
+            if !addressing(index) || index.containing_gpr() == Some(Gpr::RSP) {
+                return Err(MemError::Index(index.to_string()));
+            }
+            if self.is_rip_relative() {
+                return Err(MemError::RipIndex);
+            }
+        }
+        if !matches!(self.scale, 1 | 2 | 4 | 8) {
+            return Err(MemError::Scale(self.scale));
+        }
+        let absolute = self.base.is_none() && self.label.is_none() && self.index.is_none();
+        if !absolute && i32::try_from(self.displacement).is_err() {
+            return Err(MemError::Displacement(self.displacement));
+        }
+        Ok(())
+    }
+
+    fn checked(self) -> Self {
+        if let Err(e) = self.validate() {
+            panic!("invalid memory operand {}: {}", self, e);
+        }
+        self
+    }
+
+    /// Writes the operand, leaving `rel` off a RIP-relative label when the
+    /// section's `default rel` makes it implied.
+    fn fmt_in(&self, f: &mut fmt::Formatter, default_rel: bool) -> fmt::Result {
+        if let Some(size) = self.size {
+            write!(f, "{} ", size.keyword())?;
+        }
+        let mut terms = Vec::new();
+        match (&self.base, &self.label) {
+            (None, Some(label)) if !default_rel => terms.push(format!("rel {}", label.label)),
+            (base, label) => {
+                terms.extend(base.iter().map(|base| base.to_string()));
+                terms.extend(label.iter().map(|label| label.label.clone()));
+            }
+        }
+        if let Some(index) = &self.index {
+            terms.push(match self.scale {
+                1 => index.to_string(),
+                scale => format!("{}*{}", index, scale),
+            });
+        }
+        write!(f, "[{}", terms.join(" + "))?;
+        match self.displacement {
+            d if terms.is_empty() => write!(f, "{}", d)?,
+            0 => {}
+            d if d < 0 => write!(f, " - {}", d.unsigned_abs())?,
+            d => write!(f, " + {}", d)?,
+        }
+        write!(f, "]")
+    }
+}
+
+impl fmt::Display for Mem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_in(f, false)
+    }
+}
+
+impl Amd64Instruction {
+    /// An instruction whose operands are not checked; see
+    /// [`Amd64Instruction::typed`] for one that is. The mnemonic is typed,
+    /// so a misspelt one is written out as a [`Amd64Mnemonic::Raw`] on
+    /// purpose or not at all:
+    ///
+    /// ```compile_fail
+    /// cataclysm::Amd64Instruction::new("mvo", vec![]);
+    /// ```
+    pub fn new(mnemonic: Amd64Mnemonic, operands: Vec<Operand>) -> Self {
+        Amd64Instruction {
+            mnemonic,
+            operands,
+            hint: None,
+            fixup: None,
+            allow: Vec::new(),
+        }
+    }
+
+    pub fn with_hint(self, hint: BranchHint) -> Self {
+        Amd64Instruction {
+            hint: Some(hint),
+            ..self
+        }
+    }
+
+    /// Keeps lint `name` from reporting this instruction, for code that
+    /// does something unusual on purpose.
+    pub fn with_allow(mut self, name: &str) -> Self {
+        self.allow.push(name.to_string());
+        self
+    }
+}
+
+impl fmt::Display for Amd64Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_in(f, &EmitContext::default())
+    }
+}
+
+impl Amd64Instruction {
+    fn fmt_in(&self, f: &mut fmt::Formatter, ctx: &EmitContext) -> fmt::Result {
+        write!(f, "{}", self.mnemonic)?;
+
+        if !self.operands.is_empty() {
+            let sized = ctx
+                .defaults
+                .operand_size
+                .and_then(|width| Some((self.unsized_memory()?, width)));
+            write!(f, "\t")?;
+            for (index, operand) in self.operands.iter().enumerate() {
+                if index > 0 {
+                    write!(f, ", ")?;
+                }
+                match sized {
+                    Some((i, width)) if i == index => write!(f, "{} ", width.keyword())?,
+                    _ => {}
+                }
+                match operand {
+                    Operand::Memory(mem) => mem.fmt_in(f, ctx.defaults.rel)?,
+                    _ => write!(f, "{}", operand)?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A data item, emitted as `db`, `dq` and the like.
+#[derive(Clone)]
+pub enum Data {
+    /// A byte, word or doubleword, emitted with `db`, `dw` or `dd`.
+    Byte(u8),
+    Word(u16),
+    Dword(u32),
+    /// Quadwords, emitted with `dq`.
+    Int(i64),
+    UInt(u64),
+    /// An x87 80-bit extended-precision float, emitted with `dt`. Every
+    /// `f64` converts to one exactly.
+    Tword(f64),
+    /// A 128-bit value, emitted as two quadwords, low half first.
+    I128(i128),
+    U128(u128),
+    USize(usize),
+    Float(f64),
+    Bytes(Vec<u8>),
+    /// Text, written as a string literal with NASM's quoting and escapes.
+    Str(String, Encoding),
+    /// An item emitted in a fixed byte order regardless of its section.
+    Endian(Endian, Box<Data>),
+    /// The address of a label, as wide as a pointer on the target.
+    Address(Label),
+    /// The signed 32-bit distance from `base` to `label`, which needs no
+    /// relocation when both are in one section, or a PC-relative one when
+    /// `base` is in the item's own section.
+    Offset { label: Label, base: Label },
+    /// The unsigned 32-bit offset of `label` from the start of the loaded
+    /// image, as Windows' unwind and exception tables hold addresses. In
+    /// an image encoded here, that is the label's address.
+    ImageOffset(Label),
+    /// Numbers of one type, emitted with a single directive.
+    Array(Array),
+    /// `count` copies of `byte`.
+    Fill { count: usize, byte: u8 },
+    /// `count` copies of an item, emitted with `times`.
+    Times(usize, Box<Data>),
+    /// Room for `count` values of `size`, emitted with `resb` and the
+    /// like. In a `bss` section it takes no space in the object file;
+    /// anywhere else it is zeros.
+    Reserve { count: usize, size: GprWidth },
+    /// Copies of `byte` up to `offset` bytes from the start of the section,
+    /// for fields at fixed positions. How many depends on where the item
+    /// lands, so it has no bytes of its own; see [`Data::to_bytes_at`].
+    SkipTo { offset: u64, byte: u8 },
+}
+
+/// Byte order used when a multi-byte data item is laid out in memory.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub enum Endian {
+    #[default]
+    Little,
+    Big,
+}
+
+/// How the text of a [`Data::Str`] is laid out, and whether a zero code
+/// unit follows it. Wide strings are UTF-16 on Windows and UTF-32 on
+/// Linux and macOS, as `wchar_t` is.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    Utf8,
+    /// UTF-8 followed by a zero byte, as C strings are stored.
+    Utf8Z,
+    /// UTF-16 code units in the item's byte order.
+    Utf16,
+    Utf16Z,
+    Utf32,
+    Utf32Z,
+}
+
+impl Encoding {
+    /// Size of one code unit in bytes.
+    pub fn unit_width(self) -> usize {
+        match self {
+            Encoding::Utf8 | Encoding::Utf8Z => 1,
+            Encoding::Utf16 | Encoding::Utf16Z => 2,
+            Encoding::Utf32 | Encoding::Utf32Z => 4,
+        }
+    }
+
+    pub fn is_terminated(self) -> bool {
+        matches!(self, Encoding::Utf8Z | Encoding::Utf16Z | Encoding::Utf32Z)
+    }
+
+    /// The same encoding followed by a zero code unit.
+    pub fn terminated(self) -> Self {
+        match self {
+            Encoding::Utf8 | Encoding::Utf8Z => Encoding::Utf8Z,
+            Encoding::Utf16 | Encoding::Utf16Z => Encoding::Utf16Z,
+            Encoding::Utf32 | Encoding::Utf32Z => Encoding::Utf32Z,
+        }
+    }
+
+    /// The code units of `text`, and the terminator if there is one.
+    pub fn units(self, text: &str) -> Vec<u32> {
+        let mut units: Vec<u32> = match self.unit_width() {
+            1 => text.bytes().map(u32::from).collect(),
+            2 => text.encode_utf16().map(u32::from).collect(),
+            _ => text.chars().map(u32::from).collect(),
+        };
+        if self.is_terminated() {
+            units.push(0);
+        }
+        units
+    }
+}
+
+/// `text` as a NASM string: single-quoted if it can be, otherwise
+/// backquoted with C-style escapes, which NASM converts from UTF-8 when
+/// the string is wrapped in `__?utf16?__` or `__?utf32?__`.
+fn nasm_string(text: &str) -> String {
+    use std::fmt::Write as _;
+
+    if text.chars().all(|c| matches!(c, ' '..='~') && c != '\'' && c != '`') {
+        return format!("'{}'", text);
+    }
+    let mut out = String::from("`");
+    for c in text.chars() {
+        match c {
+            '`' => out.push_str("\\`"),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            ' '..='~' => out.push(c),
+            '\0'..='\x7f' => write!(out, "\\x{:02x}", c as u32).unwrap(),
+            '\u{80}'..='\u{ffff}' => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            _ => write!(out, "\\U{:08x}", c as u32).unwrap(),
+        }
+    }
+    out.push('`');
+    out
+}
+
+/// The assembler dialect a program targeting x86-64 is written in. Other
+/// architectures are always written for the GNU assembler.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum SyntaxFlavor {
+    /// NASM's Intel syntax and directives.
+    #[default]
+    Nasm,
+    /// The GNU assembler's AT&T syntax and directives.
+    Gas,
+}
+
+/// Qualifiers that would otherwise be repeated on every instruction,
+/// set for a whole program or one section.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct Defaults {
+    /// Whether the section is emitted under `default rel`, which writes
+    /// label operands as `[msg]` rather than `[rel msg]`.
+    pub rel: bool,
+    /// The size of memory operands that nothing else sizes, such as the
+    /// destination of `mov [rbx], 5`, written before them as `qword` and
+    /// the like.
+    pub operand_size: Option<GprWidth>,
+    /// Whether [`sizing::check`] reports memory operands that nothing,
+    /// not even `operand_size`, sizes.
+    pub strict_sizing: bool,
+}
+
+impl Defaults {
+    pub fn new() -> Self {
+        Defaults::default()
+    }
+
+    pub fn with_rel(mut self) -> Self {
+        self.rel = true;
+        self
+    }
+
+    pub fn with_operand_size(mut self, width: GprWidth) -> Self {
+        self.operand_size = Some(width);
+        self
+    }
+
+    pub fn with_strict_sizing(mut self) -> Self {
+        self.strict_sizing = true;
+        self
+    }
+}
+
+/// One line or group of lines of a section.
+#[derive(Clone)]
+pub enum AsmExpr {
+    Data(Data),
+    Instruction(Amd64Instruction),
+    /// An AArch64 instruction, emitted when the program targets
+    /// [`Arch::Arm64`](target::Arch::Arm64).
+    Arm64(arm64::Arm64Instruction),
+    /// A RISC-V instruction, emitted when the program targets
+    /// [`Arch::Riscv64`](target::Arch::Riscv64).
+    Riscv(riscv::RiscvInstruction),
+    Block(Vec<AsmExpr>),
+    Label(Label),
+    Raw(String),
+    /// Placeholder for a data item or label bound by a macro or template.
+    Param(String),
+    /// Conditional assembly: only the arm selected by evaluating `cond`
+    /// against the program's [`BuildConfig`] is emitted.
+    If {
+        cond: Cond,
+        then: Vec<AsmExpr>,
+        otherwise: Vec<AsmExpr>,
+    },
+    /// A named group of related lines, such as the code one generator
+    /// phase produced. The body is emitted between `; begin region` and
+    /// `; end region` comments and, with
+    /// [`Program::region_symbols`](program::Program::region_symbols),
+    /// between symbols a profiler can attribute addresses to.
+    Region { name: String, body: Vec<AsmExpr> },
+    /// A call of a macro, made with [`MacroDef::call`](macros::MacroDef::call),
+    /// and its expansion. Everything but NASM output with
+    /// [`Program::macro_directives`](program::Program::macro_directives)
+    /// set sees only the expansion.
+    Macro {
+        call: macros::MacroCall,
+        body: Vec<AsmExpr>,
+    },
+}
+
+/// The symbols marking where region `name` begins and ends. NASM keeps
+/// `..@` labels out of local label scoping, so the region's body sees the
+/// same scope as the lines around it.
+pub fn region_symbols(name: &str) -> (String, String) {
+    (
+        format!("..@region.{}.begin", name),
+        format!("..@region.{}.end", name),
+    )
+}
+
+impl fmt::Display for Data {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Data::Endian(..) | Data::Address(_) | Data::Offset { .. } | Data::ImageOffset(_) => {
+                self.fmt_in(f, &EmitContext::default())
+            }
+            Data::Float(v) => write!(f, "dq {}", float_literal(*v)),
+            Data::Tword(v) => write!(f, "dt {}", float_literal(*v)),
+            Data::Byte(v) => write!(f, "db {}", v),
+            Data::Word(v) => write!(f, "dw {}", v),
+            Data::Dword(v) => write!(f, "dd {}", v),
+            Data::Int(v) => write!(f, "dq {}", v),
+            Data::UInt(v) => write!(f, "dq {}", v),
+            Data::I128(v) => write_quad_pair(f, *v as u128),
+            Data::U128(v) => write_quad_pair(f, *v),
+            Data::USize(v) => write!(f, "dq {}", v),
+            Data::Array(array) => write!(f, "{}", array),
+            Data::Fill { count, byte } => write!(f, "times {} db 0x{:02X}", count, byte),
+            Data::Times(count, item) => write!(f, "times {} {}", count, item),
+            Data::Reserve { count, size } => {
+                let suffix = match size {
+                    GprWidth::Byte => 'b',
+                    GprWidth::Word => 'w',
+                    GprWidth::Dword => 'd',
+                    GprWidth::Qword => 'q',
+                };
+                write!(f, "res{} {}", suffix, count)
+            }
+            Data::SkipTo { offset, byte } => {
+                write!(f, "times {} - ($ - $$) db 0x{:02X}", offset, byte)
+            }
+            Data::Str(text, encoding) => {
+                let (directive, wrapper) = match encoding.unit_width() {
+                    1 => ("db", None),
+                    2 => ("dw", Some("__?utf16?__")),
+                    _ => ("dd", Some("__?utf32?__")),
+                };
+                let mut parts = Vec::new();
+                if !text.is_empty() {
+                    let quoted = nasm_string(text);
+                    parts.push(match wrapper {
+                        Some(wrapper) => format!("{}({})", wrapper, quoted),
+                        None => quoted,
+                    });
+                }
+                if encoding.is_terminated() {
+                    parts.push("0".to_string());
+                }
+                match parts.is_empty() {
+                    true => write!(f, "times 0 db 0"),
+                    false => write!(f, "{} {}", directive, parts.join(", ")),
+                }
+            }
+            Data::Bytes(v) => {
+                let formatted_bytes = v
+                    .iter()
+                    .map(|&byte| format!("0x{:02X}", byte))
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                write!(f, "db {}", formatted_bytes)
+            }
+        }
+    }
+}
+
+/// NASM has no 128-bit data directive, so wide values are split into
+/// quadwords in memory order.
+fn write_quad_pair(f: &mut fmt::Formatter, v: u128) -> fmt::Result {
+    write!(f, "dq 0x{:016X}, 0x{:016X}", v as u64, (v >> 64) as u64)
+}
+
+/// `v` in the x87 80-bit format, little-endian: a 64-bit significand with
+/// an explicit integer bit, then the sign and a 15-bit exponent.
+fn extended_bytes(v: f64) -> [u8; 10] {
+    let bits = v.to_bits();
+    let sign = (bits >> 63) as u16;
+    let exponent = ((bits >> 52) & 0x7ff) as u16;
+    let fraction = bits & ((1 << 52) - 1);
+    let (exponent, significand) = match exponent {
+        0 if fraction == 0 => (0, 0),
+        // Subnormal doubles are normal here; shift the integer bit up.
+        0 => {
+            let shift = fraction.leading_zeros();
+            (15372 - shift as u16, fraction << shift)
+        }
+        0x7ff => (0x7fff, 1 << 63 | fraction << 11),
+        e => (e + (16383 - 1023), 1 << 63 | fraction << 11),
+    };
+    let mut out = [0; 10];
+    out[..8].copy_from_slice(&significand.to_le_bytes());
+    out[8..].copy_from_slice(&(sign << 15 | exponent).to_le_bytes());
+    out
+}
+
+/// `v` as a NASM floating-point constant. NASM reads anything without a
+/// period as an integer, so `1.0` must not be printed as `1`. Printing
+/// `f32`s as themselves keeps them at their shortest round-tripping form.
+fn float_literal<F: Into<f64> + fmt::Debug + Copy>(value: F) -> String {
+    let v: f64 = value.into();
+    if v.is_nan() {
+        return "__?QNaN?__".to_string();
+    }
+    if v.is_infinite() {
+        let sign = if v < 0.0 { "-" } else { "" };
+        return format!("{}__?Infinity?__", sign);
+    }
+
+    let text = format!("{:?}", value);
+    if text.contains('.') {
+        return text;
+    }
+    match text.find('e') {
+        Some(e) => format!("{}.0{}", &text[..e], &text[e..]),
+        None => format!("{}.0", text),
+    }
+}
+
+impl Data {
+    pub fn big_endian(self) -> Data {
+        Data::Endian(Endian::Big, Box::new(self))
+    }
+
+    pub fn little_endian(self) -> Data {
+        Data::Endian(Endian::Little, Box::new(self))
+    }
+
+    pub fn zeros(count: usize) -> Data {
+        Data::Fill { count, byte: 0 }
+    }
+
+    /// The bytes of `text` followed by a zero, as C strings are stored.
+    pub fn asciiz(text: &str) -> Data {
+        Data::Str(text.to_string(), Encoding::Utf8Z)
+    }
+
+    /// The little-endian bytes this item occupies once assembled.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_bytes_with(Endian::Little)
+    }
+
+    /// The bytes this item occupies once assembled, laying out multi-byte
+    /// values in `endian` order unless the item overrides it.
+    pub fn to_bytes_with(&self, endian: Endian) -> Vec<u8> {
+        let mut bytes = match self {
+            Data::Byte(v) => vec![*v],
+            Data::Word(v) => v.to_le_bytes().to_vec(),
+            Data::Dword(v) => v.to_le_bytes().to_vec(),
+            Data::Int(v) => v.to_le_bytes().to_vec(),
+            Data::UInt(v) => v.to_le_bytes().to_vec(),
+            Data::I128(v) => v.to_le_bytes().to_vec(),
+            Data::U128(v) => v.to_le_bytes().to_vec(),
+            Data::USize(v) => (*v as u64).to_le_bytes().to_vec(),
+            Data::Float(v) => v.to_le_bytes().to_vec(),
+            Data::Tword(v) => extended_bytes(*v).to_vec(),
+            Data::Bytes(v) => return v.clone(),
+            // Each code unit is in `endian` order, not the whole string.
+            Data::Str(text, encoding) => {
+                let width = encoding.unit_width();
+                let mut bytes = Vec::new();
+                for unit in encoding.units(text) {
+                    let unit = match endian {
+                        Endian::Little => unit.to_le_bytes(),
+                        Endian::Big => (unit << (32 - 8 * width)).to_be_bytes(),
+                    };
+                    bytes.extend(&unit[..width]);
+                }
+                return bytes;
+            }
+            Data::Array(array) => return array.to_bytes_with(endian),
+            Data::Fill { count, byte } => return vec![*byte; *count],
+            Data::Times(count, item) => return item.to_bytes_with(endian).repeat(*count),
+            Data::Reserve { count, size } => return vec![0; count * size.bits() as usize / 8],
+            Data::SkipTo { .. } => return Vec::new(),
+            Data::Endian(e, inner) => return inner.to_bytes_with(*e),
+            // Filled in by the linker; see `Data::labels`.
+            Data::Address(_) => vec![0; 8],
+            Data::Offset { .. } | Data::ImageOffset(_) => vec![0; 4],
+        };
+
+        if endian == Endian::Big {
+            bytes.reverse();
+        }
+
+        bytes
+    }
+
+    /// The bytes this item occupies when placed `offset` bytes into its
+    /// section. A [`Data::SkipTo`] already past its offset takes none.
+    pub fn to_bytes_at(&self, endian: Endian, offset: u64) -> Vec<u8> {
+        match self {
+            Data::SkipTo { offset: to, byte } => vec![*byte; to.saturating_sub(offset) as usize],
+            Data::Endian(e, inner) => inner.to_bytes_at(*e, offset),
+            _ => self.to_bytes_with(endian),
+        }
+    }
+
+    /// Whether the item's size depends on where it is placed.
+    pub fn is_positional(&self) -> bool {
+        match self {
+            Data::SkipTo { .. } => true,
+            Data::Endian(_, inner) => inner.is_positional(),
+            _ => false,
+        }
+    }
+
+    /// The label whose address this item holds, if any. Such items only
+    /// have placeholder bytes until link time.
+    pub fn address_label(&self) -> Option<&Label> {
+        match self {
+            Data::Address(label) => Some(label),
+            Data::Endian(_, inner) => inner.address_label(),
+            _ => None,
+        }
+    }
+
+    pub fn address_label_mut(&mut self) -> Option<&mut Label> {
+        match self {
+            Data::Address(label) => Some(label),
+            Data::Endian(_, inner) => inner.address_label_mut(),
+            _ => None,
+        }
+    }
+
+    /// Every label the item's value depends on: an address's label, or an
+    /// offset's label and base.
+    pub fn labels(&self) -> Vec<&Label> {
+        match self {
+            Data::Address(label) | Data::ImageOffset(label) => vec![label],
+            Data::Offset { label, base } => vec![label, base],
+            Data::Endian(_, inner) | Data::Times(_, inner) => inner.labels(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn labels_mut(&mut self) -> Vec<&mut Label> {
+        match self {
+            Data::Address(label) | Data::ImageOffset(label) => vec![label],
+            Data::Offset { label, base } => vec![label, base],
+            Data::Endian(_, inner) | Data::Times(_, inner) => inner.labels_mut(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// NASM only lays out `dq` and friends little-endian, so big-endian
+    /// items are spelled out byte by byte. Addresses are always emitted in
+    /// the target's native order.
+    fn fmt_in(&self, f: &mut fmt::Formatter, ctx: &EmitContext) -> fmt::Result {
+        match (self, ctx.endian) {
+            (Data::Endian(e, inner), _) => inner.fmt_in(f, &ctx.with_endian(*e)),
+            (Data::Address(label), _) => match ctx.pointer_width {
+                4 => write!(f, "dd {}", label.label),
+                _ => write!(f, "dq {}", label.label),
+            },
+            (Data::Offset { label, base }, _) => write!(f, "dd {} - {}", label.label, base.label),
+            (Data::ImageOffset(label), _) => write!(f, "dd {} wrt ..imagebase", label.label),
+            (Data::USize(v), endian) if ctx.pointer_width == 4 => match endian {
+                Endian::Little => write!(f, "dd {}", v),
+                Endian::Big => write!(f, "{}", Data::Bytes((*v as u32).to_be_bytes().to_vec())),
+            },
+            (Data::Times(count, item), _) => {
+                write!(f, "times {} ", count)?;
+                item.fmt_in(f, ctx)
+            }
+            (Data::Str(_, encoding), _) if encoding.unit_width() == 1 => write!(f, "{}", self),
+            (Data::Bytes(_) | Data::Fill { .. } | Data::SkipTo { .. } | Data::Reserve { .. }, _)
+            | (_, Endian::Little) => write!(f, "{}", self),
+            (_, Endian::Big) => write!(f, "{}", Data::Bytes(self.to_bytes_with(Endian::Big))),
+        }
+    }
+}
+
+impl fmt::Display for AsmExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_in(f, &EmitContext::default())
+    }
+}
+
+/// Settings inherited from the enclosing section and program while
+/// rendering an expression.
+#[derive(Clone, Copy)]
+struct EmitContext<'a> {
+    endian: Endian,
+    defaults: Defaults,
+    config: Option<&'a BuildConfig>,
+    /// Size of a pointer on the target, in bytes.
+    pointer_width: u32,
+    /// Whether hinted branches are followed by a comment naming the hint.
+    hint_comments: bool,
+    /// Whether regions define their begin and end symbols.
+    region_symbols: bool,
+    /// Macros defined with `%macro`, whose calls are written as calls.
+    macros: &'a [macros::MacroDef],
+}
+
+impl Default for EmitContext<'_> {
+    fn default() -> Self {
+        EmitContext {
+            endian: Endian::Little,
+            defaults: Defaults::default(),
+            config: None,
+            pointer_width: 8,
+            hint_comments: false,
+            region_symbols: false,
+            macros: &[],
+        }
+    }
+}
+
+impl EmitContext<'_> {
+    fn with_endian(&self, endian: Endian) -> Self {
+        EmitContext { endian, ..*self }
+    }
+}
+
+impl AsmExpr {
+    fn fmt_in(&self, f: &mut fmt::Formatter, ctx: &EmitContext) -> fmt::Result {
+        match self {
+            AsmExpr::Data(data) => {
+                write!(f, "\t\t")?;
+                data.fmt_in(f, ctx)
+            }
+            AsmExpr::Instruction(inst) => {
+                write!(f, "\t\t")?;
+                inst.fmt_in(f, ctx)?;
+                match inst.hint {
+                    Some(hint) if ctx.hint_comments => write!(f, "\t; {}", hint),
+                    _ => Ok(()),
+                }
+            }
+            AsmExpr::Arm64(inst) => write!(
+                f,
+                "%error AArch64 instruction `{}` in an x86-64 program",
+                inst.to_string().replace('\t', " ")
+            ),
+            AsmExpr::Riscv(inst) => write!(
+                f,
+                "%error RISC-V instruction `{}` in an x86-64 program",
+                inst.to_string().replace('\t', " ")
+            ),
+            AsmExpr::Label(lbl) => write!(f, "\t{}", lbl),
+            AsmExpr::Raw(str) => write!(f, "{}", str),
+            AsmExpr::Param(name) => write!(f, "\t\t%{}", name),
+            AsmExpr::Macro { call, .. } if ctx.macros.iter().any(|m| m.name == call.name) => {
+                write!(f, "\t\t{}", call)
+            }
+            AsmExpr::Block(lines) | AsmExpr::Macro { body: lines, .. } => {
+                for line in lines {
+                    line.fmt_in(f, ctx)?;
+                    writeln!(f)?;
+                }
+                Ok(())
+            }
+            AsmExpr::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let taken = match ctx.config {
+                    Some(config) => cond.eval(config),
+                    None => cond.eval(&BuildConfig::default()),
+                };
+                for line in if taken { then } else { otherwise } {
+                    line.fmt_in(f, ctx)?;
+                    writeln!(f)?;
+                }
+                Ok(())
+            }
+            AsmExpr::Region { name, body } => {
+                let (begin, end) = region_symbols(name);
+                writeln!(f, "\t; begin region {}", name)?;
+                if ctx.region_symbols {
+                    writeln!(f, "\t{}:", begin)?;
+                }
+                for line in body {
+                    line.fmt_in(f, ctx)?;
+                    writeln!(f)?;
+                }
+                if ctx.region_symbols {
+                    writeln!(f, "\t{}:", end)?;
+                }
+                write!(f, "\t; end region {}", name)
+            }
+        }
+    }
+
+    /// The nested expression lists of a container node: a block's or
+    /// region's body, or both arms of a conditional.
+    pub fn bodies(&self) -> impl Iterator<Item = &Vec<AsmExpr>> {
+        let (first, second) = match self {
+            AsmExpr::Block(body) | AsmExpr::Region { body, .. } | AsmExpr::Macro { body, .. } => {
+                (Some(body), None)
+            }
+            AsmExpr::If {
+                then, otherwise, ..
+            } => (Some(then), Some(otherwise)),
+            _ => (None, None),
+        };
+        first.into_iter().chain(second)
+    }
+
+    pub fn bodies_mut(&mut self) -> impl Iterator<Item = &mut Vec<AsmExpr>> {
+        let (first, second) = match self {
+            AsmExpr::Block(body) | AsmExpr::Region { body, .. } | AsmExpr::Macro { body, .. } => {
+                (Some(body), None)
+            }
+            AsmExpr::If {
+                then, otherwise, ..
+            } => (Some(then), Some(otherwise)),
+            _ => (None, None),
+        };
+        first.into_iter().chain(second)
+    }
+
+    /// Renames label definitions and every reference to them: label and
+    /// memory operands, expression symbols and words of `Raw` lines.
+    pub fn rename_labels(body: &mut [AsmExpr], rename: &dyn Fn(&str) -> Option<String>) {
+        let apply = |label: &mut Label| {
+            if let Some(new) = rename(&label.label) {
+                label.label = new;
+            }
+        };
+
+        for expr in body.iter_mut() {
+            match expr {
+                AsmExpr::Label(label) => apply(label),
+                AsmExpr::Data(data) => data.labels_mut().into_iter().for_each(apply),
+                AsmExpr::Raw(text) => *text = rename_symbols(text, rename),
+                AsmExpr::Instruction(inst) => {
+                    for operand in inst.operands.iter_mut() {
+                        match operand {
+                            Operand::Immediate(ImmediateValue::Label(l)) => apply(l),
+                            Operand::Immediate(ImmediateValue::Expr(e)) => e.rename_symbols(rename),
+                            Operand::Memory(Mem {
+                                label: Some(label), ..
+                            }) => apply(label),
+                            _ => {}
+                        }
+                    }
+                }
+                AsmExpr::Arm64(inst) => {
+                    inst.operands
+                        .iter_mut()
+                        .filter_map(|operand| operand.label_mut())
+                        .for_each(apply);
+                }
+                AsmExpr::Riscv(inst) => {
+                    inst.operands
+                        .iter_mut()
+                        .filter_map(|operand| operand.label_mut())
+                        .for_each(apply);
+                }
+                _ => {
+                    for inner in expr.bodies_mut() {
+                        AsmExpr::rename_labels(inner, rename);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Calls `f` with every symbol `body` refers to, in the places
+    /// [`AsmExpr::rename_labels`] would rename. Label definitions are not
+    /// references, and both arms of conditionals are visited.
+    pub fn visit_references(body: &[AsmExpr], f: &mut dyn FnMut(&str)) {
+        for expr in body {
+            match expr {
+                AsmExpr::Data(data) => {
+                    for label in data.labels() {
+                        f(&label.label);
+                    }
+                }
+                AsmExpr::Raw(text) => symbol_words(text).for_each(&mut *f),
+                AsmExpr::Instruction(inst) => {
+                    for operand in &inst.operands {
+                        match operand {
+                            Operand::Immediate(ImmediateValue::Label(l)) => f(&l.label),
+                            Operand::Immediate(ImmediateValue::Expr(e)) => e.visit_symbols(f),
+                            Operand::Memory(Mem {
+                                label: Some(label), ..
+                            }) => f(&label.label),
+                            _ => {}
+                        }
+                    }
+                }
+                AsmExpr::Arm64(inst) => {
+                    for label in inst.operands.iter().filter_map(|operand| operand.label()) {
+                        f(&label.label);
+                    }
+                }
+                AsmExpr::Riscv(inst) => {
+                    for label in inst.operands.iter().filter_map(|operand| operand.label()) {
+                        f(&label.label);
+                    }
+                }
+                _ => {
+                    for inner in expr.bodies() {
+                        AsmExpr::visit_references(inner, f);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Replaces every conditional with a block holding the arm selected by
+    /// `config`, so later passes see exactly what will be emitted.
+    pub fn resolve_conditions(body: &mut [AsmExpr], config: &BuildConfig) {
+        for expr in body.iter_mut() {
+            if let AsmExpr::If {
+                cond,
+                then,
+                otherwise,
+            } = expr
+            {
+                let taken = if cond.eval(config) { then } else { otherwise };
+                *expr = AsmExpr::Block(std::mem::take(taken));
+            }
+            for inner in expr.bodies_mut() {
+                AsmExpr::resolve_conditions(inner, config);
+            }
+        }
+    }
+}
+
+/// A named section and its contents.
+#[derive(Clone)]
+pub struct Section {
+    pub name: String,
+    /// Shared between clones until one of them changes it, so snapshots of
+    /// a program only copy the sections a pass rewrites.
+    pub body: Arc<Vec<AsmExpr>>,
+    /// Default byte order for multi-byte data items in this section.
+    pub endian: Endian,
+    /// Replaces the program's [`Defaults`] for this section when set.
+    pub defaults: Option<Defaults>,
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_with(f, &EmitContext::default())
+    }
+}
+
+impl Section {
+    fn fmt_with(&self, f: &mut fmt::Formatter, parent: &EmitContext) -> fmt::Result {
+        // NASM gives ELF sections it does not know no execute permission
+        // and no alignment.
+        if self.name.starts_with("text.") {
+            writeln!(
+                f,
+                "section .{} progbits alloc exec nowrite align=16",
+                self.name
+            )?;
+        } else {
+            writeln!(f, "section .{}", self.name)?;
+        }
+
+        // `default rel` lasts until the next `default`, so a section that
+        // differs from the program puts the program's setting back after.
+        let defaults = self.defaults.unwrap_or(parent.defaults);
+        let switch = defaults.rel != parent.defaults.rel;
+        let rel = |rel| if rel { "default rel" } else { "default abs" };
+        if switch {
+            writeln!(f, "{}", rel(defaults.rel))?;
+        }
+        let ctx = EmitContext {
+            defaults,
+            ..parent.with_endian(self.endian)
+        };
+        for line in self.body.iter() {
+            line.fmt_in(f, &ctx)?;
+            writeln!(f)?;
+        }
+        if switch {
+            writeln!(f, "{}", rel(parent.defaults.rel))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Section {
+    pub fn new(name: &str, body: Vec<AsmExpr>) -> Self {
+        Section {
+            name: name.to_string(),
+            body: Arc::new(body),
+            endian: Endian::Little,
+            defaults: None,
+        }
+    }
+
+    pub fn with_endian(mut self, endian: Endian) -> Self {
+        self.endian = endian;
+        self
+    }
+
+    pub fn with_defaults(mut self, defaults: Defaults) -> Self {
+        self.defaults = Some(defaults);
+        self
+    }
+
+    /// The body for changing, copied first if a snapshot shares it.
+    pub fn body_mut(&mut self) -> &mut Vec<AsmExpr> {
+        Arc::make_mut(&mut self.body)
+    }
+}
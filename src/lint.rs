@@ -0,0 +1,452 @@
+//! Lints that flag suspicious generated code.
+//!
+//! A lint looks at one function of the program's control-flow graph at a
+//! time and reports what it finds through its [`LintContext`]. [`BUILTIN`]
+//! lists the lints that ship with the crate; callers can pass their own to
+//! [`run`] alongside them.
+//!
+//! Each lint reports at the level the program's [`LintLevels`] give it,
+//! a warning unless set otherwise. A lint can be raised to an error,
+//! lowered back or allowed, which keeps it from running at all; a single
+//! instruction can allow a lint with [`Amd64Instruction::with_allow`].
+
+use std::fmt;
+
+use crate::{
+    cfg::{Cfg, Function},
+    dataflow::{self, operand_registers, Liveness, RegSet, SYSCALL_CLOBBERS},
+    program::Program,
+    register::Gpr,
+    Amd64Instruction, Operand,
+};
+
+/// How a lint's findings are reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+pub enum Level {
+    /// Not reported; the lint does not run.
+    Allow,
+    #[default]
+    Warn,
+    /// Reported, and meant to stop the build.
+    Deny,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Level::Allow => "allowed",
+            Level::Warn => "warning",
+            Level::Deny => "error",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The level of each lint, by name, for lints not left at their default
+/// of [`Level::Warn`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LintLevels {
+    levels: Vec<(String, Level)>,
+}
+
+impl LintLevels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, lint: &str, level: Level) {
+        match self.levels.iter_mut().find(|(name, _)| name == lint) {
+            Some(slot) => slot.1 = level,
+            None => self.levels.push((lint.to_string(), level)),
+        }
+    }
+
+    pub fn with(mut self, lint: &str, level: Level) -> Self {
+        self.set(lint, level);
+        self
+    }
+
+    pub fn level(&self, lint: &str) -> Level {
+        self.levels
+            .iter()
+            .find(|(name, _)| name == lint)
+            .map_or(Level::Warn, |(_, level)| *level)
+    }
+
+    /// Each lint given a level, and the level, in the order they were set.
+    pub fn entries(&self) -> &[(String, Level)] {
+        &self.levels
+    }
+
+    /// Names given a level that are not among `lints`, most likely typos.
+    pub fn unknown<'a>(&'a self, lints: &[Lint]) -> Vec<&'a str> {
+        self.levels
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .filter(|name| !lints.iter().any(|lint| lint.name == *name))
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub lint: &'static str,
+    pub level: Level,
+    pub function: String,
+    /// The offending instruction as emitted, when the finding is about one.
+    pub instruction: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}: ", self.level, self.function)?;
+        if let Some(inst) = &self.instruction {
+            write!(f, "`{}`: ", inst.replace('\t', " "))?;
+        }
+        write!(f, "{} [{}]", self.message, self.lint)
+    }
+}
+
+/// What a lint gets to look at: one function, with the liveness already
+/// computed for it.
+pub struct LintContext<'a> {
+    pub program: &'a Program,
+    pub function: &'a Function,
+    pub liveness: &'a Liveness,
+    lint: &'static str,
+    level: Level,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl LintContext<'_> {
+    /// Records a finding, unless `inst` allows the lint.
+    pub fn report(&mut self, inst: Option<&Amd64Instruction>, message: impl Into<String>) {
+        if inst.is_some_and(|inst| inst.allow.iter().any(|name| name == self.lint)) {
+            return;
+        }
+        self.diagnostics.push(Diagnostic {
+            lint: self.lint,
+            level: self.level,
+            function: self.function.name.clone(),
+            instruction: inst.map(|i| i.to_string()),
+            message: message.into(),
+        });
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Lint {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub check: fn(&mut LintContext),
+}
+
+pub const MISSING_RET: Lint = Lint {
+    name: "missing-ret",
+    description: "a function can run past its last instruction without returning",
+    check: missing_ret,
+};
+
+pub const UNUSED_FLAGS: Lint = Lint {
+    name: "unused-flags",
+    description: "a comparison sets flags that nothing reads",
+    check: unused_flags,
+};
+
+pub const DEAD_WRITE: Lint = Lint {
+    name: "dead-write",
+    description: "a register is written and then overwritten before being read",
+    check: dead_write,
+};
+
+pub const SYSCALL_CLOBBER: Lint = Lint {
+    name: "syscall-clobber",
+    description: "rcx or r11 is read after a syscall overwrote it",
+    check: syscall_clobber,
+};
+
+pub const BUILTIN: &[Lint] = &[MISSING_RET, UNUSED_FLAGS, DEAD_WRITE, SYSCALL_CLOBBER];
+
+/// Runs `lints` over every function of `program`, in program order, at
+/// the levels in its [`Program::lint_levels`].
+pub fn run(program: &Program, lints: &[Lint]) -> Vec<Diagnostic> {
+    let cfg = Cfg::build(program);
+    let mut diagnostics = Vec::new();
+
+    for function in &cfg.functions {
+        let liveness = Liveness::compute(function);
+        for lint in lints {
+            let level = program.lint_levels.level(lint.name);
+            if level == Level::Allow {
+                continue;
+            }
+            let mut cx = LintContext {
+                program,
+                function,
+                liveness: &liveness,
+                lint: lint.name,
+                level,
+                diagnostics: Vec::new(),
+            };
+            (lint.check)(&mut cx);
+            diagnostics.append(&mut cx.diagnostics);
+        }
+    }
+
+    diagnostics
+}
+
+/// Whether any of `diagnostics` is an error.
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics.iter().any(|d| d.level == Level::Deny)
+}
+
+fn missing_ret(cx: &mut LintContext) {
+    let function = cx.function;
+    let reachable = function.reachable();
+    let Some(block) = function.blocks.last() else {
+        return;
+    };
+    let last = function.blocks.len() - 1;
+
+    // Only running off the end leaves the last block with the exit flag
+    // and no jump; jumps out of the function end in `jmp` or a branch.
+    let ends_in_jump = block
+        .instructions
+        .last()
+        .is_some_and(|inst| inst.mnemonic == "jmp");
+    if reachable[last] && block.exits && !ends_in_jump {
+        cx.report(
+            block.instructions.last(),
+            "control can run past the end of the function without returning",
+        );
+    }
+}
+
+fn unused_flags(cx: &mut LintContext) {
+    let (function, liveness) = (cx.function, cx.liveness);
+    for (b, block) in function.blocks.iter().enumerate() {
+        let mut flags = liveness.flags_out[b];
+        let mut unread = Vec::new();
+        for inst in block.instructions.iter().rev() {
+            let fx = dataflow::effects(inst);
+            if fx.known && fx.writes_flags && fx.defs.is_empty() && !fx.reads_flags && !flags {
+                unread.push(inst);
+            }
+            flags = fx.reads_flags || (flags && !fx.writes_flags);
+        }
+        for inst in unread.into_iter().rev() {
+            cx.report(Some(inst), "sets flags that are never read");
+        }
+    }
+}
+
+/// The register `inst` writes and does nothing else to, if any: a plain
+/// computation into a register operand.
+fn sole_write(inst: &Amd64Instruction) -> Option<Gpr> {
+    let fx = dataflow::effects(inst);
+    let dst = match inst.operands.first() {
+        Some(Operand::Register(reg)) => reg.gpr()?,
+        _ => return None,
+    };
+    let mut defs = fx.defs.iter();
+    (fx.known && defs.next() == Some(dst) && defs.next().is_none() && dst != Gpr::RSP)
+        .then_some(dst)
+}
+
+fn dead_write(cx: &mut LintContext) {
+    let function = cx.function;
+    for block in &function.blocks {
+        let insts = &block.instructions;
+        for (i, inst) in insts.iter().enumerate() {
+            let Some(reg) = sole_write(inst) else {
+                continue;
+            };
+            for later in &insts[i + 1..] {
+                let fx = dataflow::effects(later);
+                if fx.uses.contains(reg) {
+                    break;
+                }
+                // Calls clobber registers too, but a callee may well read
+                // what was left there; only count explicit overwrites.
+                if fx.defs.contains(reg) && operand_registers(later).contains(reg) {
+                    cx.report(
+                        Some(inst),
+                        format!(
+                            "the value written to {} is overwritten by `{}` before being read",
+                            reg,
+                            later.to_string().replace('\t', " ")
+                        ),
+                    );
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn syscall_clobber(cx: &mut LintContext) {
+    let function = cx.function;
+    let clobbered = RegSet::of(&SYSCALL_CLOBBERS[1..]);
+
+    // Forward: registers on some path from a syscall without being set
+    // since.
+    let transfer = |mut state: RegSet, inst: &Amd64Instruction| {
+        state = state.difference(dataflow::effects(inst).defs);
+        if inst.mnemonic == "syscall" {
+            state = state.union(clobbered);
+        }
+        state
+    };
+
+    let n = function.blocks.len();
+    let mut state_in = vec![RegSet::EMPTY; n];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, block) in function.blocks.iter().enumerate() {
+            let out = block.instructions.iter().fold(state_in[i], transfer);
+            for &(to, _) in &block.successors {
+                let merged = state_in[to].union(out);
+                if merged != state_in[to] {
+                    state_in[to] = merged;
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    for (i, block) in function.blocks.iter().enumerate() {
+        let mut state = state_in[i];
+        for inst in &block.instructions {
+            let stale = operand_registers(inst)
+                .intersection(dataflow::effects(inst).uses)
+                .intersection(state);
+            for reg in stale.iter() {
+                cx.report(
+                    Some(inst),
+                    format!("reads {}, which an earlier syscall overwrote", reg),
+                );
+            }
+            state = transfer(state, inst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{asm_dsl, AsmExpr, Label, Section};
+
+    fn function(body: Vec<AsmExpr>) -> Program {
+        let text = [vec![AsmExpr::Label(Label::plain("f"))], body].concat();
+        Program::default().with_section(Section::new("text", text))
+    }
+
+    /// Each finding as its lint and the instruction it is about.
+    fn findings(program: &Program) -> Vec<(&'static str, String)> {
+        program
+            .lint()
+            .into_iter()
+            .map(|d| {
+                let inst = d.instruction.unwrap_or_default();
+                (d.lint, inst.trim().replace('\t', " "))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn functions_must_return() {
+        let program = function(asm_dsl! { mov rax, 1; });
+        assert_eq!(
+            findings(&program),
+            [("missing-ret", "mov rax, 1".to_string())]
+        );
+        let program = function(asm_dsl! { mov rax, 1; ret; });
+        assert_eq!(findings(&program), []);
+    }
+
+    #[test]
+    fn comparisons_must_be_read() {
+        let program = function(asm_dsl! {
+            cmp rdi, 1;
+            cmp rdi, 2;
+            je done;
+            done:
+            ret;
+        });
+        assert_eq!(
+            findings(&program),
+            [("unused-flags", "cmp rdi, 1".to_string())]
+        );
+    }
+
+    #[test]
+    fn writes_must_be_read_before_the_next() {
+        let program = function(asm_dsl! {
+            mov rax, 1;
+            mov rax, 2;
+            ret;
+        });
+        assert_eq!(
+            findings(&program),
+            [("dead-write", "mov rax, 1".to_string())]
+        );
+    }
+
+    #[test]
+    fn syscalls_clobber_rcx_and_r11() {
+        let program = function(asm_dsl! {
+            mov rcx, 1;
+            syscall;
+            mov rax, rcx;
+            ret;
+        });
+        assert_eq!(
+            findings(&program),
+            [("syscall-clobber", "mov rax, rcx".to_string())]
+        );
+    }
+
+    #[test]
+    fn levels_decide_how_findings_are_reported() {
+        let program = function(asm_dsl! { mov rax, 1; });
+        assert!(!has_errors(&program.lint()));
+
+        let denied = program.clone().with_lint_level("missing-ret", Level::Deny);
+        let diagnostics = denied.lint();
+        assert!(has_errors(&diagnostics));
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "error: f: `mov rax, 1`: control can run past the end of the function \
+             without returning [missing-ret]"
+        );
+
+        let allowed = program.with_lint_level("missing-ret", Level::Allow);
+        assert_eq!(findings(&allowed), []);
+    }
+
+    #[test]
+    fn instructions_can_allow_a_lint() {
+        let mut program = function(asm_dsl! { mov rax, 1; mov rax, 2; ret; });
+        let AsmExpr::Instruction(first) = &mut Arc::make_mut(&mut program.sections[0].body)[1]
+        else {
+            unreachable!();
+        };
+        *first = first.clone().with_allow("dead-write");
+        assert_eq!(findings(&program), []);
+    }
+
+    #[test]
+    fn unknown_lint_names_are_found() {
+        let levels = LintLevels::new()
+            .with("dead-write", Level::Deny)
+            .with("dead-writes", Level::Allow);
+        assert_eq!(levels.unknown(BUILTIN), ["dead-writes"]);
+        assert_eq!(levels.level("dead-write"), Level::Deny);
+        assert_eq!(levels.level("unused-flags"), Level::Warn);
+    }
+}
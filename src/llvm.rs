@@ -0,0 +1,820 @@
+//! Import of LLVM textual IR, so an existing compiler can try this crate as
+//! its backend by handing over the `.ll` it already produces.
+//!
+//! Only a small subset is understood: functions over `i64` values, with
+//! `i1` for comparisons and branches. That covers integer arithmetic and
+//! bitwise instructions, `icmp`, `select`, `phi`, `call`, `br` and `ret`.
+//! Memory instructions and other types are reported as unsupported rather
+//! than guessed at. Shift amounts must be constants. Functions that are
+//! only declared become externs.
+//!
+//! Every SSA value gets a stack slot, which keeps the lowering simple
+//! enough to check against the IR by eye; run the optimization passes over
+//! the result for anything faster.
+
+use std::{collections::HashMap, error, fmt};
+
+use crate::{
+    consts::{RAX, RBP, RCX, RDX, RSP},
+    dataflow::SYSV_ARGUMENTS,
+    frontend::Frontend,
+    instr::{self, CondCode},
+    register::Reg64,
+    Amd64Register, AsmExpr, Extern, Global, Label, Mem, Program, Section,
+};
+
+/// Something in the IR outside the supported subset, or malformed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl error::Error for ImportError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Value {
+    Local(String),
+    Const(i64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    And,
+    Or,
+    Xor,
+    SDiv,
+    UDiv,
+    SRem,
+    URem,
+    Shl,
+    LShr,
+    AShr,
+}
+
+#[derive(Clone, Debug)]
+enum Inst {
+    Binary {
+        dst: String,
+        op: BinaryOp,
+        lhs: Value,
+        rhs: Value,
+    },
+    Icmp {
+        dst: String,
+        cond: CondCode,
+        lhs: Value,
+        rhs: Value,
+    },
+    Select {
+        dst: String,
+        cond: Value,
+        then: Value,
+        otherwise: Value,
+    },
+    Phi {
+        dst: String,
+        incoming: Vec<(Value, String)>,
+    },
+    Call {
+        dst: Option<String>,
+        callee: String,
+        args: Vec<Value>,
+    },
+    Br(String),
+    CondBr {
+        cond: Value,
+        then: String,
+        otherwise: String,
+    },
+    Ret(Option<Value>),
+}
+
+#[derive(Clone, Debug)]
+struct Block {
+    name: String,
+    /// Each instruction with the line it came from.
+    insts: Vec<(usize, Inst)>,
+}
+
+#[derive(Clone, Debug)]
+struct Function {
+    name: String,
+    line: usize,
+    params: Vec<String>,
+    blocks: Vec<Block>,
+}
+
+/// A parsed IR module: its function definitions and declarations.
+#[derive(Clone, Debug, Default)]
+pub struct Module {
+    functions: Vec<Function>,
+    declarations: Vec<String>,
+}
+
+impl Module {
+    /// Names of the functions the module defines, in order.
+    pub fn functions(&self) -> impl Iterator<Item = &str> {
+        self.functions.iter().map(|f| f.name.as_str())
+    }
+}
+
+/// Parses the supported subset of LLVM IR. Top-level lines other than
+/// `define` and `declare`, such as the target triple, attribute groups and
+/// metadata, are skipped.
+pub fn parse(source: &str) -> Result<Module, ImportError> {
+    let mut module = Module::default();
+    let mut current: Option<Function> = None;
+
+    for (i, line) in source.lines().enumerate() {
+        let number = i + 1;
+        let error = |message: String| ImportError {
+            line: number,
+            message,
+        };
+        let line = line.split(';').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let Some(function) = &mut current else {
+            if line.starts_with("define ") {
+                current = Some(header(line).map_err(error)?);
+                current.as_mut().unwrap().line = number;
+            } else if line.starts_with("declare ") {
+                module.declarations.push(callee(line).map_err(error)?.0);
+            }
+            continue;
+        };
+
+        if line == "}" {
+            module.functions.extend(current.take());
+        } else if let Some(label) = line.strip_suffix(':') {
+            function.blocks.push(Block {
+                name: label.trim_matches('"').to_string(),
+                insts: Vec::new(),
+            });
+        } else {
+            if function.blocks.is_empty() {
+                // An unnamed entry block takes the next number after the
+                // unnamed parameters.
+                let unnamed = function
+                    .params
+                    .iter()
+                    .filter(|p| p.bytes().all(|b| b.is_ascii_digit()))
+                    .count();
+                function.blocks.push(Block {
+                    name: unnamed.to_string(),
+                    insts: Vec::new(),
+                });
+            }
+            let inst = instruction(line).map_err(error)?;
+            function
+                .blocks
+                .last_mut()
+                .unwrap()
+                .insts
+                .push((number, inst));
+        }
+    }
+
+    match current {
+        Some(function) => Err(ImportError {
+            line: function.line,
+            message: format!("function @{} is not closed", function.name),
+        }),
+        None => Ok(module),
+    }
+}
+
+/// The name and argument text of the `@name(...)` in `text`.
+fn callee(text: &str) -> Result<(String, &str), String> {
+    let start = text.find('@').ok_or("expected a function name")?;
+    let rest = &text[start + 1..];
+    let open = rest.find('(').ok_or("expected an argument list")?;
+    let close = rest.rfind(')').ok_or("expected `)`")?;
+    Ok((
+        rest[..open].trim_matches('"').to_string(),
+        &rest[open + 1..close],
+    ))
+}
+
+/// The type and value of `i64 noundef %x`.
+fn typed_value(text: &str) -> Result<Value, String> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let (Some(ty), Some(value)) = (words.first(), words.last()) else {
+        return Err(format!("expected a typed value, found `{}`", text));
+    };
+    check_type(ty)?;
+    value_of(value)
+}
+
+fn check_type(ty: &str) -> Result<(), String> {
+    match ty {
+        "i64" | "i1" => Ok(()),
+        _ => Err(format!("unsupported type `{}`", ty)),
+    }
+}
+
+fn value_of(text: &str) -> Result<Value, String> {
+    match text {
+        "true" => Ok(Value::Const(1)),
+        "false" => Ok(Value::Const(0)),
+        _ => match text.strip_prefix('%') {
+            Some(name) => Ok(Value::Local(name.trim_matches('"').to_string())),
+            None => text
+                .parse()
+                .map(Value::Const)
+                .map_err(|_| format!("unsupported value `{}`", text)),
+        },
+    }
+}
+
+fn local(text: &str) -> Result<String, String> {
+    match value_of(text.trim())? {
+        Value::Local(name) => Ok(name),
+        Value::Const(_) => Err(format!("expected a local, found `{}`", text.trim())),
+    }
+}
+
+fn label(text: &str) -> Result<String, String> {
+    let name = text
+        .trim()
+        .strip_prefix("label")
+        .ok_or_else(|| format!("expected a label, found `{}`", text.trim()))?;
+    local(name)
+}
+
+fn header(line: &str) -> Result<Function, String> {
+    let (name, params) = callee(line)?;
+    let before = &line[..line.find('@').unwrap()];
+    match before.split_whitespace().last() {
+        Some("void") => {}
+        Some(ty) => check_type(ty)?,
+        None => return Err("expected a return type".to_string()),
+    }
+    let params = params
+        .split(',')
+        .filter(|p| !p.trim().is_empty())
+        .map(|p| {
+            let words: Vec<&str> = p.split_whitespace().collect();
+            check_type(words[0])?;
+            local(words.last().unwrap())
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Function {
+        name,
+        line: 0,
+        params,
+        blocks: Vec::new(),
+    })
+}
+
+/// The words of `text` after `opcode`, with flags that do not change the
+/// result, such as `nsw`, dropped.
+fn operands<'a>(text: &'a str, opcode: &str) -> Vec<&'a str> {
+    text[opcode.len()..]
+        .split_whitespace()
+        .filter(|w| !matches!(*w, "nuw" | "nsw" | "exact" | "disjoint"))
+        .collect()
+}
+
+/// `ty a, b` as two values.
+fn pair(words: &[&str]) -> Result<(Value, Value), String> {
+    let (ty, rest) = words.split_first().ok_or("expected operands")?;
+    check_type(ty)?;
+    let rest = rest.join(" ");
+    let (lhs, rhs) = rest.split_once(',').ok_or("expected two operands")?;
+    Ok((value_of(lhs.trim())?, value_of(rhs.trim())?))
+}
+
+fn instruction(line: &str) -> Result<Inst, String> {
+    let (dst, text) = match line.split_once(" = ") {
+        Some((dst, text)) => (Some(local(dst)?), text.trim()),
+        None => (None, line),
+    };
+    let text = text.strip_prefix("tail ").unwrap_or(text);
+    let opcode = text.split_whitespace().next().unwrap_or_default();
+    let dst_or = |what: &str| dst.clone().ok_or(format!("`{}` needs a result", what));
+
+    let binary = match opcode {
+        "add" => Some(BinaryOp::Add),
+        "sub" => Some(BinaryOp::Sub),
+        "mul" => Some(BinaryOp::Mul),
+        "and" => Some(BinaryOp::And),
+        "or" => Some(BinaryOp::Or),
+        "xor" => Some(BinaryOp::Xor),
+        "sdiv" => Some(BinaryOp::SDiv),
+        "udiv" => Some(BinaryOp::UDiv),
+        "srem" => Some(BinaryOp::SRem),
+        "urem" => Some(BinaryOp::URem),
+        "shl" => Some(BinaryOp::Shl),
+        "lshr" => Some(BinaryOp::LShr),
+        "ashr" => Some(BinaryOp::AShr),
+        _ => None,
+    };
+    if let Some(op) = binary {
+        let (lhs, rhs) = pair(&operands(text, opcode))?;
+        return Ok(Inst::Binary {
+            dst: dst_or(opcode)?,
+            op,
+            lhs,
+            rhs,
+        });
+    }
+
+    Ok(match opcode {
+        "icmp" => {
+            let words = operands(text, opcode);
+            let (predicate, rest) = words.split_first().ok_or("expected a predicate")?;
+            let cond = match *predicate {
+                "eq" => CondCode::E,
+                "ne" => CondCode::Ne,
+                "slt" => CondCode::L,
+                "sle" => CondCode::Le,
+                "sgt" => CondCode::G,
+                "sge" => CondCode::Ge,
+                "ult" => CondCode::B,
+                "ule" => CondCode::Be,
+                "ugt" => CondCode::A,
+                "uge" => CondCode::Ae,
+                p => return Err(format!("unsupported predicate `{}`", p)),
+            };
+            let (lhs, rhs) = pair(rest)?;
+            Inst::Icmp {
+                dst: dst_or(opcode)?,
+                cond,
+                lhs,
+                rhs,
+            }
+        }
+        "select" => {
+            let parts: Vec<&str> = text[opcode.len()..].split(',').collect();
+            let [cond, then, otherwise] = parts[..] else {
+                return Err("expected three operands".to_string());
+            };
+            Inst::Select {
+                dst: dst_or(opcode)?,
+                cond: typed_value(cond)?,
+                then: typed_value(then)?,
+                otherwise: typed_value(otherwise)?,
+            }
+        }
+        "phi" => {
+            let rest = text[opcode.len()..].trim();
+            let (ty, rest) = rest.split_once(' ').ok_or("expected a type")?;
+            check_type(ty)?;
+            let incoming = rest
+                .split(']')
+                .filter_map(|edge| edge.split_once('['))
+                .map(|(_, edge)| {
+                    let (value, block) =
+                        edge.split_once(',').ok_or("expected `[ value, %block ]`")?;
+                    Ok((value_of(value.trim())?, local(block)?))
+                })
+                .collect::<Result<_, String>>()?;
+            Inst::Phi {
+                dst: dst_or(opcode)?,
+                incoming,
+            }
+        }
+        "call" => {
+            let (name, args) = callee(text)?;
+            let before = &text[..text.find('@').unwrap()];
+            if before.split_whitespace().last() != Some("void") && dst.is_none() {
+                // A call whose result is ignored is fine; one with an
+                // unsupported return type is not.
+                check_type(before.split_whitespace().last().unwrap_or_default())?;
+            }
+            let args = args
+                .split(',')
+                .filter(|a| !a.trim().is_empty())
+                .map(typed_value)
+                .collect::<Result<_, _>>()?;
+            Inst::Call {
+                dst,
+                callee: name,
+                args,
+            }
+        }
+        "br" => {
+            let parts: Vec<&str> = text[opcode.len()..].split(',').collect();
+            match parts[..] {
+                [target] => Inst::Br(label(target)?),
+                [cond, then, otherwise] => Inst::CondBr {
+                    cond: typed_value(cond)?,
+                    then: label(then)?,
+                    otherwise: label(otherwise)?,
+                },
+                _ => return Err("expected a branch target".to_string()),
+            }
+        }
+        "ret" => match text[opcode.len()..].trim() {
+            "void" => Inst::Ret(None),
+            value => Inst::Ret(Some(typed_value(value)?)),
+        },
+        _ => return Err(format!("unsupported instruction `{}`", opcode)),
+    })
+}
+
+/// Imports LLVM IR as a [`Frontend`], each defined function becoming an
+/// exported System V function.
+///
+/// ```
+/// use cataclysm::{frontend, interp, llvm, register::Gpr};
+///
+/// // `sum` adds up the numbers below `n`. `swap` passes `a` and `b`
+/// // round `n` times, through phis that each read the other's old value,
+/// // so it returns `b` after an odd number and `a` after an even one.
+/// let source = r#"
+/// define i64 @sum(i64 %n) {
+/// entry:
+///   br label %loop
+/// loop:
+///   %i = phi i64 [ 0, %entry ], [ %next, %loop ]
+///   %acc = phi i64 [ 0, %entry ], [ %total, %loop ]
+///   %total = add i64 %acc, %i
+///   %next = add i64 %i, 1
+///   %done = icmp eq i64 %next, %n
+///   br i1 %done, label %exit, label %loop
+/// exit:
+///   ret i64 %total
+/// }
+///
+/// define i64 @swap(i64 %a, i64 %b, i64 %n) {
+/// entry:
+///   br label %loop
+/// loop:
+///   %x = phi i64 [ %a, %entry ], [ %y, %loop ]
+///   %y = phi i64 [ %b, %entry ], [ %x, %loop ]
+///   %i = phi i64 [ 0, %entry ], [ %next, %loop ]
+///   %next = add i64 %i, 1
+///   %done = icmp eq i64 %next, %n
+///   br i1 %done, label %exit, label %loop
+/// exit:
+///   ret i64 %y
+/// }
+/// "#;
+/// let module = llvm::parse(source).unwrap();
+/// let program = frontend::compile(&mut llvm::LlvmIr::new(), &module).unwrap();
+///
+/// let sum = interp::run_with(&program, "sum", &[(Gpr::RDI, 5)], 10_000).unwrap();
+/// assert_eq!(sum.status, 10);
+/// for (n, expected) in [(1, 7), (2, 3), (3, 7)] {
+///     let args = [(Gpr::RDI, 3), (Gpr::RSI, 7), (Gpr::RDX, n)];
+///     let out = interp::run_with(&program, "swap", &args, 10_000).unwrap();
+///     assert_eq!(out.status, expected, "{} swaps", n);
+/// }
+/// ```
+#[derive(Clone)]
+pub struct LlvmIr {
+    section: String,
+}
+
+impl Default for LlvmIr {
+    fn default() -> Self {
+        LlvmIr {
+            section: "text".to_string(),
+        }
+    }
+}
+
+/// Lowering state for one function.
+struct Lowering<'a> {
+    function: &'a Function,
+    slots: HashMap<&'a str, i64>,
+    edges: usize,
+    out: Vec<AsmExpr>,
+}
+
+impl LlvmIr {
+    pub fn new() -> Self {
+        LlvmIr::default()
+    }
+
+    /// Puts the code in `section` instead of `text`.
+    pub fn with_section(mut self, section: &str) -> Self {
+        self.section = section.to_string();
+        self
+    }
+}
+
+impl<'a> Lowering<'a> {
+    fn new(function: &'a Function) -> Result<Self, ImportError> {
+        if function.params.len() > SYSV_ARGUMENTS.len() {
+            return Err(ImportError {
+                line: function.line,
+                message: format!(
+                    "@{} takes more than {} parameters",
+                    function.name,
+                    SYSV_ARGUMENTS.len()
+                ),
+            });
+        }
+
+        let mut slots = HashMap::new();
+        let results = function
+            .blocks
+            .iter()
+            .flat_map(|b| &b.insts)
+            .filter_map(|(_, inst)| match inst {
+                Inst::Binary { dst, .. }
+                | Inst::Icmp { dst, .. }
+                | Inst::Select { dst, .. }
+                | Inst::Phi { dst, .. }
+                | Inst::Call { dst: Some(dst), .. } => Some(dst.as_str()),
+                _ => None,
+            });
+        for name in function.params.iter().map(String::as_str).chain(results) {
+            let next = -8 * (slots.len() as i64 + 1);
+            slots.entry(name).or_insert(next);
+        }
+        Ok(Lowering {
+            function,
+            slots,
+            edges: 0,
+            out: Vec::new(),
+        })
+    }
+
+    fn slot(&self, name: &str, line: usize) -> Result<Mem, ImportError> {
+        match self.slots.get(name) {
+            Some(&offset) => Ok(Mem::base(RBP).with_displacement(offset)),
+            None => Err(ImportError {
+                line,
+                message: format!("`%{}` is never defined", name),
+            }),
+        }
+    }
+
+    fn load(&mut self, reg: Amd64Register, value: &Value, line: usize) -> Result<(), ImportError> {
+        let inst = match value {
+            Value::Const(v) => instr::mov(reg, *v),
+            Value::Local(name) => instr::mov(reg, self.slot(name, line)?),
+        };
+        self.out.push(inst);
+        Ok(())
+    }
+
+    fn store(&mut self, name: &str, reg: Amd64Register, line: usize) -> Result<(), ImportError> {
+        let slot = self.slot(name, line)?;
+        self.out.push(instr::mov(slot, reg));
+        Ok(())
+    }
+
+    fn block_label(&self, block: &str) -> Label {
+        Label::plain(&format!(".bb_{}", block))
+    }
+
+    fn lower(mut self) -> Result<Vec<AsmExpr>, ImportError> {
+        let function = self.function;
+        let frame = (self.slots.len() * 8).next_multiple_of(16) as i64;
+        self.out.extend([
+            AsmExpr::Label(Label::plain(&function.name)),
+            instr::push(RBP),
+            instr::mov(RBP, RSP),
+            instr::sub(RSP, frame),
+        ]);
+        for (param, reg) in function.params.iter().zip(SYSV_ARGUMENTS) {
+            self.store(param, reg.into(), function.line)?;
+        }
+
+        for block in &function.blocks {
+            self.out.push(AsmExpr::Label(self.block_label(&block.name)));
+            for (line, inst) in &block.insts {
+                self.inst(&block.name, inst, *line)?;
+            }
+        }
+        Ok(self.out)
+    }
+
+    fn inst(&mut self, block: &str, inst: &Inst, line: usize) -> Result<(), ImportError> {
+        match inst {
+            Inst::Binary { dst, op, lhs, rhs } => {
+                let result = self.binary(*op, lhs, rhs, line)?;
+                self.store(dst, result, line)?;
+            }
+            Inst::Icmp {
+                dst,
+                cond,
+                lhs,
+                rhs,
+            } => {
+                self.load(RAX, lhs, line)?;
+                self.load(RCX, rhs, line)?;
+                self.out.extend([
+                    instr::cmp(RAX, RCX),
+                    instr::mov(RAX, 0),
+                    instr::mov(RCX, 1),
+                    instr::cmovcc(*cond, Reg64::Rax, RCX),
+                ]);
+                self.store(dst, RAX, line)?;
+            }
+            Inst::Select {
+                dst,
+                cond,
+                then,
+                otherwise,
+            } => {
+                self.load(RAX, otherwise, line)?;
+                self.load(RCX, then, line)?;
+                self.load(RDX, cond, line)?;
+                self.out.extend([
+                    instr::test(RDX, RDX),
+                    instr::cmovcc(CondCode::Ne, Reg64::Rax, RCX),
+                ]);
+                self.store(dst, RAX, line)?;
+            }
+            // Filled in by each predecessor on its way in.
+            Inst::Phi { .. } => {}
+            Inst::Call { dst, callee, args } => {
+                if args.len() > SYSV_ARGUMENTS.len() {
+                    return Err(ImportError {
+                        line,
+                        message: format!(
+                            "calls with more than {} arguments are unsupported",
+                            SYSV_ARGUMENTS.len()
+                        ),
+                    });
+                }
+                for (arg, reg) in args.iter().zip(SYSV_ARGUMENTS) {
+                    self.load(reg.into(), arg, line)?;
+                }
+                self.out.push(instr::call(Label::plain(callee)));
+                if let Some(dst) = dst {
+                    self.store(dst, RAX, line)?;
+                }
+            }
+            Inst::Br(target) => {
+                self.edge(block, target, line)?;
+                self.out.push(instr::jmp(self.block_label(target)));
+            }
+            Inst::CondBr {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let taken = Label::plain(&format!(".edge{}", self.edges));
+                self.edges += 1;
+                self.load(RAX, cond, line)?;
+                self.out.extend([
+                    instr::test(RAX, RAX),
+                    instr::jcc(CondCode::Ne, taken.clone()),
+                ]);
+                self.edge(block, otherwise, line)?;
+                self.out.push(instr::jmp(self.block_label(otherwise)));
+                self.out.push(AsmExpr::Label(taken));
+                self.edge(block, then, line)?;
+                self.out.push(instr::jmp(self.block_label(then)));
+            }
+            Inst::Ret(value) => {
+                if let Some(value) = value {
+                    self.load(RAX, value, line)?;
+                }
+                self.out.extend([instr::leave(), instr::ret()]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Computes `lhs op rhs`, returning the register holding the result.
+    fn binary(
+        &mut self,
+        op: BinaryOp,
+        lhs: &Value,
+        rhs: &Value,
+        line: usize,
+    ) -> Result<Amd64Register, ImportError> {
+        if matches!(op, BinaryOp::Shl | BinaryOp::LShr | BinaryOp::AShr) {
+            let count = match rhs {
+                Value::Const(n) if (0..64).contains(n) => *n,
+                _ => {
+                    return Err(ImportError {
+                        line,
+                        message: "shift amounts must be constants in 0..64".to_string(),
+                    })
+                }
+            };
+            self.load(RAX, lhs, line)?;
+            self.out.push(match op {
+                BinaryOp::Shl => instr::shl(RAX, count),
+                BinaryOp::LShr => instr::shr(RAX, count),
+                _ => instr::sar(RAX, count),
+            });
+            return Ok(RAX);
+        }
+
+        self.load(RAX, lhs, line)?;
+        self.load(RCX, rhs, line)?;
+        Ok(match op {
+            BinaryOp::Add => {
+                self.out.push(instr::add(RAX, RCX));
+                RAX
+            }
+            BinaryOp::Sub => {
+                self.out.push(instr::sub(RAX, RCX));
+                RAX
+            }
+            BinaryOp::Mul => {
+                self.out.push(instr::imul(Reg64::Rax, RCX));
+                RAX
+            }
+            BinaryOp::And => {
+                self.out.push(instr::and(RAX, RCX));
+                RAX
+            }
+            BinaryOp::Or => {
+                self.out.push(instr::or(RAX, RCX));
+                RAX
+            }
+            BinaryOp::Xor => {
+                self.out.push(instr::xor(RAX, RCX));
+                RAX
+            }
+            BinaryOp::SDiv | BinaryOp::SRem => {
+                self.out.extend([instr::cqo(), instr::idiv(RCX)]);
+                if op == BinaryOp::SDiv {
+                    RAX
+                } else {
+                    RDX
+                }
+            }
+            BinaryOp::UDiv | BinaryOp::URem => {
+                self.out.extend([instr::xor(RDX, RDX), instr::div(RCX)]);
+                if op == BinaryOp::UDiv {
+                    RAX
+                } else {
+                    RDX
+                }
+            }
+            BinaryOp::Shl | BinaryOp::LShr | BinaryOp::AShr => {
+                unreachable!("shifts are lowered above")
+            }
+        })
+    }
+
+    /// Sets the `phi`s of `to` for arriving from `from`. Every incoming
+    /// value is read before any is written, since a `phi` may read another
+    /// of the same block.
+    fn edge(&mut self, from: &str, to: &str, line: usize) -> Result<(), ImportError> {
+        let Some(target) = self.function.blocks.iter().find(|b| b.name == to) else {
+            return Err(ImportError {
+                line,
+                message: format!("no block `%{}`", to),
+            });
+        };
+        let mut phis = Vec::new();
+        for (phi_line, inst) in &target.insts {
+            let Inst::Phi { dst, incoming } = inst else {
+                continue;
+            };
+            let Some((value, _)) = incoming.iter().find(|(_, block)| block == from) else {
+                return Err(ImportError {
+                    line: *phi_line,
+                    message: format!("`%{}` has no value for `%{}`", dst, from),
+                });
+            };
+            phis.push((dst, value, *phi_line));
+        }
+
+        for (_, value, line) in &phis {
+            self.load(RAX, value, *line)?;
+            self.out.push(instr::push(RAX));
+        }
+        for (dst, _, line) in phis.iter().rev() {
+            self.out.push(instr::pop(Reg64::Rax));
+            self.store(dst, RAX, *line)?;
+        }
+        Ok(())
+    }
+}
+
+impl Frontend for LlvmIr {
+    type Unit = Module;
+    type Error = ImportError;
+
+    fn lower(&mut self, unit: &Module, program: &mut Program) -> Result<(), ImportError> {
+        let mut body = Vec::new();
+        for function in &unit.functions {
+            body.extend(Lowering::new(function)?.lower()?);
+        }
+
+        program
+            .globals
+            .extend(unit.functions.iter().map(|f| Global::new(&f.name)));
+        program
+            .externs
+            .extend(unit.declarations.iter().map(|name| Extern::new(name)));
+        program.sections.push(Section::new(&self.section, body));
+        Ok(())
+    }
+}
@@ -0,0 +1,467 @@
+use std::{error, fmt};
+
+use crate::{expr::ConstExpr, rename_symbols, AsmExpr, Data, ImmediateValue, Label, Operand};
+
+/// Value bound to a macro parameter at instantiation.
+#[derive(Clone)]
+pub enum MacroArg {
+    Operand(Operand),
+    Label(Label),
+    Data(Data),
+}
+
+impl MacroArg {
+    fn as_operand(&self) -> Option<Operand> {
+        match self {
+            MacroArg::Operand(op) => Some(op.clone()),
+            MacroArg::Label(l) => Some(Operand::Immediate(ImmediateValue::Label(l.clone()))),
+            MacroArg::Data(_) => None,
+        }
+    }
+
+    fn as_expr(&self) -> Option<ConstExpr> {
+        match self {
+            MacroArg::Operand(Operand::Immediate(imm)) => match imm {
+                ImmediateValue::I64(v) => Some(ConstExpr::Int(*v)),
+                ImmediateValue::U64(v) => Some(ConstExpr::Int(*v as i64)),
+                ImmediateValue::USize(v) => Some(ConstExpr::Int(*v as i64)),
+                ImmediateValue::F64(v) => Some(ConstExpr::Int(v.to_bits() as i64)),
+                ImmediateValue::Expr(e) => Some(e.clone()),
+                ImmediateValue::Label(l) => Some(ConstExpr::sym(&l.label)),
+                ImmediateValue::Bytes(_) => None,
+            },
+            MacroArg::Label(l) => Some(ConstExpr::sym(&l.label)),
+            _ => None,
+        }
+    }
+
+    /// Text substituted for the parameter inside `Raw` lines.
+    fn text(&self) -> String {
+        match self {
+            MacroArg::Operand(op) => op.to_string(),
+            MacroArg::Label(l) => l.label.clone(),
+            MacroArg::Data(d) => d.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MacroError {
+    ArgCount {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    /// An argument was bound to a parameter used somewhere it cannot go,
+    /// e.g. a data item passed where an operand is expected.
+    ArgKind { name: String, param: String },
+    /// A call of a macro the program does not define.
+    Undefined(String),
+}
+
+impl fmt::Display for MacroError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MacroError::ArgCount {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "macro `{}` takes {} arguments but {} were given",
+                name, expected, found
+            ),
+            MacroError::ArgKind { name, param } => write!(
+                f,
+                "argument for parameter `{}` of macro `{}` has the wrong kind",
+                param, name
+            ),
+            MacroError::Undefined(name) => write!(f, "macro `{}` is not defined", name),
+        }
+    }
+}
+
+impl error::Error for MacroError {}
+
+/// A named, parameterized template of assembly expressions.
+///
+/// Parameters are referenced from the body through `Operand::Param` and
+/// `AsmExpr::Param` placeholders, through labels or expression symbols
+/// spelled like the parameter, and as whole words inside `Raw` lines.
+/// Labels listed in `locals` are private to each instantiation: every
+/// expansion renames them to fresh hashed labels so a macro can be used any
+/// number of times without its internal labels colliding, by clones of the
+/// definition as much as by the definition itself.
+///
+/// [`MacroDef::call`] keeps the expansion together with the call, which
+/// NASM output can write as a call of a `%macro` instead; see
+/// [`Program::macro_directives`](crate::program::Program::macro_directives).
+#[derive(Clone)]
+pub struct MacroDef {
+    pub name: String,
+    pub params: Vec<String>,
+    pub locals: Vec<String>,
+    pub body: Vec<AsmExpr>,
+}
+
+impl MacroDef {
+    pub fn new(name: &str, params: &[&str], locals: &[&str], body: Vec<AsmExpr>) -> Self {
+        MacroDef {
+            name: name.to_string(),
+            params: params.iter().map(|p| p.to_string()).collect(),
+            locals: locals.iter().map(|l| l.to_string()).collect(),
+            body,
+        }
+    }
+
+    /// Instantiates the macro with one argument per parameter, in order.
+    pub fn expand(&self, args: &[MacroArg]) -> Result<Vec<AsmExpr>, MacroError> {
+        if args.len() != self.params.len() {
+            return Err(MacroError::ArgCount {
+                name: self.name.clone(),
+                expected: self.params.len(),
+                found: args.len(),
+            });
+        }
+
+        let locals = self
+            .locals
+            .iter()
+            .map(|l| (l.as_str(), Label::unique(&format!("{}.{}", self.name, l))))
+            .collect();
+
+        let expansion = Expansion {
+            name: &self.name,
+            params: &self.params,
+            args,
+            locals,
+            textual: false,
+        };
+        expansion.body(&self.body)
+    }
+
+    /// Instantiates the macro as [`MacroDef::expand`] does, keeping the
+    /// call to write in its place.
+    pub fn call(&self, args: &[MacroArg]) -> Result<AsmExpr, MacroError> {
+        Ok(AsmExpr::Macro {
+            call: MacroCall {
+                name: self.name.clone(),
+                args: args.to_vec(),
+            },
+            body: self.expand(args)?,
+        })
+    }
+
+    /// The body of the NASM `%macro` defining this macro: parameters
+    /// become `%1`, `%2` and so on, and locals `%%` labels, which NASM
+    /// makes unique to each call.
+    pub(crate) fn directive_body(&self) -> Vec<AsmExpr> {
+        let args: Vec<MacroArg> = (1..=self.params.len())
+            .map(|i| MacroArg::Label(Label::plain(&format!("%{}", i))))
+            .collect();
+        let locals = self
+            .locals
+            .iter()
+            .map(|l| {
+                let renamed = Label::plain(&format!("%%{}", l.trim_start_matches('.')));
+                (l.as_str(), renamed)
+            })
+            .collect();
+        let expansion = Expansion {
+            name: &self.name,
+            params: &self.params,
+            args: &args,
+            locals,
+            textual: true,
+        };
+        // A label argument fits anywhere a parameter can be used.
+        expansion
+            .body(&self.body)
+            .expect("parameter text is accepted everywhere")
+    }
+}
+
+/// A use of a macro, by name, with the arguments it was expanded with.
+#[derive(Clone)]
+pub struct MacroCall {
+    pub name: String,
+    pub args: Vec<MacroArg>,
+}
+
+/// The call as a NASM macro call line, without indentation. Arguments
+/// with commas in them are wrapped in braces, as NASM requires.
+impl fmt::Display for MacroCall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        for (i, arg) in self.args.iter().enumerate() {
+            let text = arg.text();
+            let sep = if i == 0 { " " } else { ", " };
+            match text.contains(',') {
+                true => write!(f, "{}{{{}}}", sep, text)?,
+                false => write!(f, "{}{}", sep, text)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Substitutes `args` for `params` throughout `body`, exactly as a macro
+/// without local labels would be expanded.
+pub(crate) fn substitute(
+    name: &str,
+    params: &[String],
+    args: &[MacroArg],
+    body: &[AsmExpr],
+) -> Result<Vec<AsmExpr>, MacroError> {
+    let expansion = Expansion {
+        name,
+        params,
+        args,
+        locals: Vec::new(),
+        textual: false,
+    };
+    expansion.body(body)
+}
+
+struct Expansion<'a> {
+    name: &'a str,
+    params: &'a [String],
+    args: &'a [MacroArg],
+    locals: Vec<(&'a str, Label)>,
+    /// Whether parameters stand for NASM macro parameters rather than
+    /// values, so data placeholders become the parameter's text.
+    textual: bool,
+}
+
+impl Expansion<'_> {
+    fn arg(&self, name: &str) -> Option<&MacroArg> {
+        self.params
+            .iter()
+            .position(|p| p == name)
+            .map(|i| &self.args[i])
+    }
+
+    fn wrong_kind(&self, param: &str) -> MacroError {
+        MacroError::ArgKind {
+            name: self.name.to_string(),
+            param: param.to_string(),
+        }
+    }
+
+    fn label(&self, label: &Label) -> Result<Label, MacroError> {
+        if let Some((_, renamed)) = self.locals.iter().find(|(l, _)| *l == label.label) {
+            return Ok(renamed.clone());
+        }
+
+        match self.arg(&label.label) {
+            Some(MacroArg::Label(l)) => Ok(l.clone()),
+            Some(_) => Err(self.wrong_kind(&label.label)),
+            None => Ok(label.clone()),
+        }
+    }
+
+    fn expr(&self, expr: &ConstExpr) -> Result<ConstExpr, MacroError> {
+        Ok(match expr {
+            ConstExpr::Int(_) => expr.clone(),
+            ConstExpr::Symbol(name) => {
+                if let Some((_, renamed)) = self.locals.iter().find(|(l, _)| l == name) {
+                    ConstExpr::sym(&renamed.label)
+                } else if let Some(arg) = self.arg(name) {
+                    arg.as_expr().ok_or_else(|| self.wrong_kind(name))?
+                } else {
+                    expr.clone()
+                }
+            }
+            ConstExpr::Neg(inner) => ConstExpr::Neg(Box::new(self.expr(inner)?)),
+            ConstExpr::Binary(op, lhs, rhs) => {
+                ConstExpr::Binary(*op, Box::new(self.expr(lhs)?), Box::new(self.expr(rhs)?))
+            }
+        })
+    }
+
+    fn operand(&self, operand: &Operand) -> Result<Operand, MacroError> {
+        Ok(match operand {
+            Operand::Param(name) => match self.arg(name) {
+                Some(arg) => arg.as_operand().ok_or_else(|| self.wrong_kind(name))?,
+                None => operand.clone(),
+            },
+            Operand::Immediate(ImmediateValue::Label(l)) => {
+                Operand::Immediate(ImmediateValue::Label(self.label(l)?))
+            }
+            Operand::Immediate(ImmediateValue::Expr(e)) => {
+                Operand::Immediate(ImmediateValue::Expr(self.expr(e)?))
+            }
+            Operand::Memory(mem) => {
+                let mut mem = mem.clone();
+                if let Some(label) = &mem.label {
+                    mem.label = Some(self.label(label)?);
+                }
+                Operand::Memory(mem)
+            }
+            _ => operand.clone(),
+        })
+    }
+
+    /// An argument of a call nested in the body, which may pass on one of
+    /// this macro's arguments whatever its kind.
+    fn arg_in_call(&self, arg: &MacroArg) -> Result<MacroArg, MacroError> {
+        Ok(match arg {
+            MacroArg::Operand(op) => MacroArg::Operand(self.operand(op)?),
+            MacroArg::Label(l) if self.locals.iter().any(|(local, _)| *local == l.label) => {
+                MacroArg::Label(self.label(l)?)
+            }
+            MacroArg::Label(l) => self.arg(&l.label).cloned().unwrap_or_else(|| arg.clone()),
+            MacroArg::Data(data) => {
+                let mut data = data.clone();
+                for label in data.labels_mut() {
+                    *label = self.label(label)?;
+                }
+                MacroArg::Data(data)
+            }
+        })
+    }
+
+    fn raw(&self, text: &str) -> String {
+        rename_symbols(text, |word| {
+            if let Some((_, renamed)) = self.locals.iter().find(|(l, _)| *l == word) {
+                Some(renamed.label.clone())
+            } else {
+                self.arg(word).map(MacroArg::text)
+            }
+        })
+    }
+
+    fn body(&self, body: &[AsmExpr]) -> Result<Vec<AsmExpr>, MacroError> {
+        body.iter().map(|expr| self.node(expr)).collect()
+    }
+
+    fn node(&self, expr: &AsmExpr) -> Result<AsmExpr, MacroError> {
+        Ok(match expr {
+            AsmExpr::Label(l) => AsmExpr::Label(self.label(l)?),
+            AsmExpr::Instruction(inst) => {
+                let mut inst = inst.clone();
+                inst.operands = inst
+                    .operands
+                    .iter()
+                    .map(|op| self.operand(op))
+                    .collect::<Result<_, _>>()?;
+                AsmExpr::Instruction(inst)
+            }
+            AsmExpr::Arm64(inst) => {
+                let mut inst = inst.clone();
+                for label in inst.operands.iter_mut().filter_map(|op| op.label_mut()) {
+                    *label = self.label(label)?;
+                }
+                AsmExpr::Arm64(inst)
+            }
+            AsmExpr::Riscv(inst) => {
+                let mut inst = inst.clone();
+                for label in inst.operands.iter_mut().filter_map(|op| op.label_mut()) {
+                    *label = self.label(label)?;
+                }
+                AsmExpr::Riscv(inst)
+            }
+            AsmExpr::Raw(text) => AsmExpr::Raw(self.raw(text)),
+            AsmExpr::Param(name) => match self.arg(name) {
+                Some(arg) if self.textual => AsmExpr::Raw(format!("\t\t{}", arg.text())),
+                Some(MacroArg::Data(d)) => AsmExpr::Data(d.clone()),
+                Some(MacroArg::Label(l)) => AsmExpr::Label(l.clone()),
+                Some(MacroArg::Operand(_)) => return Err(self.wrong_kind(name)),
+                None => expr.clone(),
+            },
+            AsmExpr::Block(inner) => AsmExpr::Block(self.body(inner)?),
+            AsmExpr::Region { name, body } => AsmExpr::Region {
+                name: name.clone(),
+                body: self.body(body)?,
+            },
+            AsmExpr::Macro { call, body } => AsmExpr::Macro {
+                call: MacroCall {
+                    name: call.name.clone(),
+                    args: call
+                        .args
+                        .iter()
+                        .map(|arg| self.arg_in_call(arg))
+                        .collect::<Result<_, _>>()?,
+                },
+                body: self.body(body)?,
+            },
+            AsmExpr::If {
+                cond,
+                then,
+                otherwise,
+            } => AsmExpr::If {
+                cond: cond.clone(),
+                then: self.body(then)?,
+                otherwise: self.body(otherwise)?,
+            },
+            AsmExpr::Data(data) => {
+                let mut data = data.clone();
+                for label in data.labels_mut() {
+                    *label = self.label(label)?;
+                }
+                AsmExpr::Data(data)
+            }
+        })
+    }
+}
+
+/// The stock replacement for the old `datastring!` macro: defines `name`
+/// over the bytes passed as `text` and sets `len` to their length.
+pub fn datastring() -> MacroDef {
+    MacroDef::new(
+        "datastring",
+        &["name", "text", "len"],
+        &[],
+        vec![
+            AsmExpr::Label(Label::plain("name")),
+            AsmExpr::Param("text".to_string()),
+            AsmExpr::Raw("\tlen equ $ - name".to_string()),
+        ],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{consts::RAX, instr, program::Program, Section};
+
+    /// A loop counting rax down to zero.
+    fn countdown() -> MacroDef {
+        MacroDef::new(
+            "countdown",
+            &[],
+            &[".again"],
+            vec![
+                AsmExpr::Label(Label::plain(".again")),
+                instr::dec(RAX),
+                instr::jcc(instr::CondCode::Ne, Label::plain(".again")),
+            ],
+        )
+    }
+
+    #[test]
+    fn clones_expand_to_their_own_labels() {
+        let def = countdown();
+        let copy = def.clone();
+        let mut body = vec![AsmExpr::Label(Label::plain("f"))];
+        for macro_def in [&def, &copy, &def, &copy.clone()] {
+            body.push(macro_def.call(&[]).unwrap());
+        }
+        body.push(instr::ret());
+
+        let program = Program::default().with_section(Section::new("text", body));
+        assert!(program.check_labels().is_empty());
+    }
+
+    #[test]
+    fn arguments_must_match_the_parameters() {
+        assert!(matches!(
+            countdown().expand(&[MacroArg::Label(Label::plain("x"))]),
+            Err(MacroError::ArgCount {
+                expected: 0,
+                found: 1,
+                ..
+            })
+        ));
+    }
+}
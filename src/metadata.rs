@@ -0,0 +1,113 @@
+//! Provenance metadata: where a generated program came from.
+//!
+//! [`Metadata`] is a list of key-value pairs kept on the program, such as
+//! the generator, its version and when it ran. On top of those,
+//! [`Program::provenance`](crate::program::Program::provenance) adds the
+//! seed and target the program was built with. The result can be queried
+//! directly, emitted as comments at the top of the text output, or stored
+//! in the binary as an ELF note section for tools to find later.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{AsmExpr, Data, Section};
+
+/// Name of the section [`note_section`] builds.
+pub const NOTE_SECTION: &str = "note.cataclysm";
+/// Owner name in the note header.
+const NOTE_NAME: &[u8] = b"cataclysm\0";
+/// Note type of the provenance note.
+const NOTE_TYPE: u32 = 1;
+
+/// Key-value pairs in insertion order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metadata {
+    entries: Vec<(String, String)>,
+}
+
+impl Metadata {
+    pub fn new() -> Self {
+        Metadata::default()
+    }
+
+    /// Sets `key`, replacing its value if already present.
+    pub fn set(&mut self, key: &str, value: impl ToString) {
+        let value = value.to_string();
+        match self.entries.iter_mut().find(|(k, _)| k == key) {
+            Some(entry) => entry.1 = value,
+            None => self.entries.push((key.to_string(), value)),
+        }
+    }
+
+    pub fn with(mut self, key: &str, value: impl ToString) -> Self {
+        self.set(key, value);
+        self
+    }
+
+    /// Records this crate as the generator, with its version.
+    pub fn with_generator(self) -> Self {
+        self.with("generator", env!("CARGO_PKG_NAME"))
+            .with("version", env!("CARGO_PKG_VERSION"))
+    }
+
+    /// Records the current time, in seconds since the Unix epoch. Leave it
+    /// out for byte-for-byte reproducible output.
+    pub fn with_timestamp(self) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.with("timestamp", now)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let index = self.entries.iter().position(|(k, _)| k == key)?;
+        Some(self.entries.remove(index).1)
+    }
+
+    pub fn entries(&self) -> &[(String, String)] {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Comment lines, one `; key: value` per entry.
+pub fn comments(entries: &[(String, String)]) -> String {
+    entries
+        .iter()
+        .map(|(k, v)| format!("; {}: {}\n", k, v.replace('\n', " ")))
+        .collect()
+}
+
+fn padded(bytes: &mut Vec<u8>) {
+    bytes.resize(bytes.len().next_multiple_of(4), 0);
+}
+
+/// An ELF note section holding `entries` as `key=value` strings, each
+/// NUL-terminated, owned by `cataclysm`.
+pub fn note_section(entries: &[(String, String)]) -> Section {
+    let mut desc = Vec::new();
+    for (k, v) in entries {
+        desc.extend(format!("{}={}", k, v).bytes());
+        desc.push(0);
+    }
+
+    let mut note = Vec::new();
+    note.extend((NOTE_NAME.len() as u32).to_le_bytes());
+    note.extend((desc.len() as u32).to_le_bytes());
+    note.extend(NOTE_TYPE.to_le_bytes());
+    note.extend(NOTE_NAME);
+    padded(&mut note);
+    note.extend(desc);
+    padded(&mut note);
+
+    Section::new(NOTE_SECTION, vec![AsmExpr::Data(Data::Bytes(note))])
+}
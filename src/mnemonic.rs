@@ -0,0 +1,703 @@
+//! Typed mnemonics, so a misspelt instruction is caught where it is built
+//! rather than by the assembler.
+//!
+//! [`Amd64Mnemonic`] covers the common integer, control-flow and SSE
+//! instructions and the AVX and AVX-512 vector forms, each with the
+//! number of operands it takes and the [`Constraint`] on each: the
+//! [register class](crate::register::RegClass) it is drawn from, or the
+//! one register it must be, as a shift count must be `cl`.
+//! [`Amd64Instruction::typed`] checks the count, that vector registers
+//! suit the encoding and that each register meets its constraint, up
+//! front, and [`check`] reports unknown mnemonics and unsuitable operands
+//! across a whole program.
+//! Every [`Amd64Instruction`] carries one: the [`instr`](crate::instr)
+//! builders name theirs, and mnemonics read as text go through
+//! [`Amd64Mnemonic::parse`].
+//! Anything else can still be written with [`Amd64Mnemonic::Raw`], whose
+//! operands are never checked; list such mnemonics in `check`'s allow-list
+//! so they are not reported as unknown.
+
+use std::{error, fmt, str::FromStr};
+
+use crate::{
+    instr::CondCode,
+    program::Program,
+    register::{Gpr, GprWidth, RegClass},
+    Amd64Instruction, Amd64Register, AsmExpr, Operand,
+};
+
+macro_rules! mnemonics {
+    ($($variant:ident => $name:literal, $min:literal..=$max:literal;)*) => {
+        /// An instruction mnemonic, without prefixes.
+        #[derive(Clone, Debug, PartialEq, Eq)]
+        pub enum Amd64Mnemonic {
+            $($variant,)*
+            /// `j` with a condition.
+            Jcc(CondCode),
+            /// `cmov` with a condition.
+            Cmovcc(CondCode),
+            /// `set` with a condition.
+            Setcc(CondCode),
+            /// Any other mnemonic, or one with prefixes, emitted as written
+            /// and never checked.
+            Raw(String),
+        }
+
+        impl Amd64Mnemonic {
+            fn fixed(name: &str) -> Option<Self> {
+                match name {
+                    $($name => Some(Amd64Mnemonic::$variant),)*
+                    _ => None,
+                }
+            }
+
+            /// The mnemonic as it is written out.
+            pub fn as_str(&self) -> &str {
+                match self {
+                    $(Amd64Mnemonic::$variant => $name,)*
+                    Amd64Mnemonic::Jcc(cc) => JCC[*cc as usize],
+                    Amd64Mnemonic::Cmovcc(cc) => CMOVCC[*cc as usize],
+                    Amd64Mnemonic::Setcc(cc) => SETCC[*cc as usize],
+                    Amd64Mnemonic::Raw(name) => name,
+                }
+            }
+
+            /// The fewest and most operands the instruction takes, or
+            /// `None` for [`Amd64Mnemonic::Raw`].
+            pub fn arity(&self) -> Option<(usize, usize)> {
+                match self {
+                    $(Amd64Mnemonic::$variant => Some(($min, $max)),)*
+                    Amd64Mnemonic::Jcc(_) | Amd64Mnemonic::Setcc(_) => Some((1, 1)),
+                    Amd64Mnemonic::Cmovcc(_) => Some((2, 2)),
+                    Amd64Mnemonic::Raw(_) => None,
+                }
+            }
+        }
+
+    };
+}
+
+// The conditional mnemonics, in the order `CondCode` declares its
+// conditions.
+const JCC: [&str; 16] = [
+    "jo", "jno", "jb", "jae", "je", "jne", "jbe", "ja", "js", "jns", "jp", "jnp", "jl", "jge",
+    "jle", "jg",
+];
+const CMOVCC: [&str; 16] = [
+    "cmovo", "cmovno", "cmovb", "cmovae", "cmove", "cmovne", "cmovbe", "cmova", "cmovs", "cmovns",
+    "cmovp", "cmovnp", "cmovl", "cmovge", "cmovle", "cmovg",
+];
+const SETCC: [&str; 16] = [
+    "seto", "setno", "setb", "setae", "sete", "setne", "setbe", "seta", "sets", "setns", "setp",
+    "setnp", "setl", "setge", "setle", "setg",
+];
+
+impl fmt::Display for Amd64Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl PartialEq<str> for Amd64Mnemonic {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for Amd64Mnemonic {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+mnemonics! {
+    // Data movement.
+    Mov => "mov", 2..=2;
+    Movzx => "movzx", 2..=2;
+    Movsx => "movsx", 2..=2;
+    Movsxd => "movsxd", 2..=2;
+    Lea => "lea", 2..=2;
+    Xchg => "xchg", 2..=2;
+    Push => "push", 1..=1;
+    Pop => "pop", 1..=1;
+    Cqo => "cqo", 0..=0;
+    Cdq => "cdq", 0..=0;
+    // Arithmetic and logic.
+    Add => "add", 2..=2;
+    Adc => "adc", 2..=2;
+    Sub => "sub", 2..=2;
+    Sbb => "sbb", 2..=2;
+    And => "and", 2..=2;
+    Or => "or", 2..=2;
+    Xor => "xor", 2..=2;
+    Cmp => "cmp", 2..=2;
+    Test => "test", 2..=2;
+    Not => "not", 1..=1;
+    Neg => "neg", 1..=1;
+    Inc => "inc", 1..=1;
+    Dec => "dec", 1..=1;
+    Mul => "mul", 1..=1;
+    Imul => "imul", 1..=3;
+    Div => "div", 1..=1;
+    Idiv => "idiv", 1..=1;
+    Shl => "shl", 2..=2;
+    Shr => "shr", 2..=2;
+    Sar => "sar", 2..=2;
+    Rol => "rol", 2..=2;
+    Ror => "ror", 2..=2;
+    Bt => "bt", 2..=2;
+    Bsf => "bsf", 2..=2;
+    Bsr => "bsr", 2..=2;
+    // Control flow.
+    Jmp => "jmp", 1..=1;
+    Call => "call", 1..=1;
+    Ret => "ret", 0..=1;
+    Leave => "leave", 0..=0;
+    Syscall => "syscall", 0..=0;
+    Int3 => "int3", 0..=0;
+    Hlt => "hlt", 0..=0;
+    Ud2 => "ud2", 0..=0;
+    Nop => "nop", 0..=1;
+    // Flags and fences.
+    Clc => "clc", 0..=0;
+    Stc => "stc", 0..=0;
+    Cld => "cld", 0..=0;
+    Std => "std", 0..=0;
+    Pause => "pause", 0..=0;
+    Lfence => "lfence", 0..=0;
+    Sfence => "sfence", 0..=0;
+    Mfence => "mfence", 0..=0;
+    // SSE moves.
+    Movd => "movd", 2..=2;
+    Movq => "movq", 2..=2;
+    Movss => "movss", 2..=2;
+    Movsd => "movsd", 2..=2;
+    Movaps => "movaps", 2..=2;
+    Movups => "movups", 2..=2;
+    Movapd => "movapd", 2..=2;
+    Movupd => "movupd", 2..=2;
+    Movdqa => "movdqa", 2..=2;
+    Movdqu => "movdqu", 2..=2;
+    // SSE arithmetic.
+    Addss => "addss", 2..=2;
+    Addsd => "addsd", 2..=2;
+    Addps => "addps", 2..=2;
+    Addpd => "addpd", 2..=2;
+    Subss => "subss", 2..=2;
+    Subsd => "subsd", 2..=2;
+    Subps => "subps", 2..=2;
+    Subpd => "subpd", 2..=2;
+    Mulss => "mulss", 2..=2;
+    Mulsd => "mulsd", 2..=2;
+    Mulps => "mulps", 2..=2;
+    Mulpd => "mulpd", 2..=2;
+    Divss => "divss", 2..=2;
+    Divsd => "divsd", 2..=2;
+    Divps => "divps", 2..=2;
+    Divpd => "divpd", 2..=2;
+    Sqrtss => "sqrtss", 2..=2;
+    Sqrtsd => "sqrtsd", 2..=2;
+    Minss => "minss", 2..=2;
+    Minsd => "minsd", 2..=2;
+    Maxss => "maxss", 2..=2;
+    Maxsd => "maxsd", 2..=2;
+    // SSE logic and comparison.
+    Andps => "andps", 2..=2;
+    Andpd => "andpd", 2..=2;
+    Orps => "orps", 2..=2;
+    Orpd => "orpd", 2..=2;
+    Xorps => "xorps", 2..=2;
+    Xorpd => "xorpd", 2..=2;
+    Pand => "pand", 2..=2;
+    Por => "por", 2..=2;
+    Pxor => "pxor", 2..=2;
+    Paddd => "paddd", 2..=2;
+    Paddq => "paddq", 2..=2;
+    Psubd => "psubd", 2..=2;
+    Psubq => "psubq", 2..=2;
+    Ucomiss => "ucomiss", 2..=2;
+    Ucomisd => "ucomisd", 2..=2;
+    Comiss => "comiss", 2..=2;
+    Comisd => "comisd", 2..=2;
+    // SSE conversion.
+    Cvtsi2ss => "cvtsi2ss", 2..=2;
+    Cvtsi2sd => "cvtsi2sd", 2..=2;
+    Cvttss2si => "cvttss2si", 2..=2;
+    Cvttsd2si => "cvttsd2si", 2..=2;
+    Cvtss2sd => "cvtss2sd", 2..=2;
+    Cvtsd2ss => "cvtsd2ss", 2..=2;
+    // AVX and AVX-512 moves.
+    Vmovaps => "vmovaps", 2..=2;
+    Vmovups => "vmovups", 2..=2;
+    Vmovapd => "vmovapd", 2..=2;
+    Vmovupd => "vmovupd", 2..=2;
+    Vmovdqa => "vmovdqa", 2..=2;
+    Vmovdqu => "vmovdqu", 2..=2;
+    Vmovdqa32 => "vmovdqa32", 2..=2;
+    Vmovdqa64 => "vmovdqa64", 2..=2;
+    Vmovdqu32 => "vmovdqu32", 2..=2;
+    Vmovdqu64 => "vmovdqu64", 2..=2;
+    // AVX and AVX-512 arithmetic and logic.
+    Vaddps => "vaddps", 3..=3;
+    Vaddpd => "vaddpd", 3..=3;
+    Vsubps => "vsubps", 3..=3;
+    Vsubpd => "vsubpd", 3..=3;
+    Vmulps => "vmulps", 3..=3;
+    Vmulpd => "vmulpd", 3..=3;
+    Vdivps => "vdivps", 3..=3;
+    Vdivpd => "vdivpd", 3..=3;
+    Vandps => "vandps", 3..=3;
+    Vandpd => "vandpd", 3..=3;
+    Vorps => "vorps", 3..=3;
+    Vorpd => "vorpd", 3..=3;
+    Vxorps => "vxorps", 3..=3;
+    Vxorpd => "vxorpd", 3..=3;
+    Vpand => "vpand", 3..=3;
+    Vpor => "vpor", 3..=3;
+    Vpxor => "vpxor", 3..=3;
+    Vpandd => "vpandd", 3..=3;
+    Vpandq => "vpandq", 3..=3;
+    Vpord => "vpord", 3..=3;
+    Vporq => "vporq", 3..=3;
+    Vpxord => "vpxord", 3..=3;
+    Vpxorq => "vpxorq", 3..=3;
+    Vpaddd => "vpaddd", 3..=3;
+    Vpaddq => "vpaddq", 3..=3;
+    Vpsubd => "vpsubd", 3..=3;
+    Vpsubq => "vpsubq", 3..=3;
+    Vzeroupper => "vzeroupper", 0..=0;
+    Vzeroall => "vzeroall", 0..=0;
+    // Segment bases.
+    Rdfsbase => "rdfsbase", 1..=1;
+    Wrfsbase => "wrfsbase", 1..=1;
+    Rdgsbase => "rdgsbase", 1..=1;
+    Wrgsbase => "wrgsbase", 1..=1;
+    Swapgs => "swapgs", 0..=0;
+    // Cache control.
+    Prefetcht0 => "prefetcht0", 1..=1;
+    Prefetcht1 => "prefetcht1", 1..=1;
+    Prefetcht2 => "prefetcht2", 1..=1;
+    Prefetchnta => "prefetchnta", 1..=1;
+    Movnti => "movnti", 2..=2;
+    Movntdq => "movntdq", 2..=2;
+    Movntps => "movntps", 2..=2;
+    Movntpd => "movntpd", 2..=2;
+    Clflush => "clflush", 1..=1;
+    Clflushopt => "clflushopt", 1..=1;
+    Clwb => "clwb", 1..=1;
+    Invlpg => "invlpg", 1..=1;
+    Wbinvd => "wbinvd", 0..=0;
+}
+
+impl Amd64Mnemonic {
+    /// The mnemonic named `name`, or [`Amd64Mnemonic::Raw`] if it is not
+    /// one of the known ones.
+    pub fn parse(name: &str) -> Self {
+        let conditional = |prefix: &str| name.strip_prefix(prefix).and_then(CondCode::from_suffix);
+        if let Some(mnemonic) = Amd64Mnemonic::fixed(name) {
+            mnemonic
+        } else if let Some(cc) = conditional("cmov") {
+            Amd64Mnemonic::Cmovcc(cc)
+        } else if let Some(cc) = conditional("set") {
+            Amd64Mnemonic::Setcc(cc)
+        } else if let Some(cc) = conditional("j") {
+            Amd64Mnemonic::Jcc(cc)
+        } else {
+            Amd64Mnemonic::Raw(name.to_string())
+        }
+    }
+
+    pub fn is_raw(&self) -> bool {
+        matches!(self, Amd64Mnemonic::Raw(_))
+    }
+}
+
+/// What register one operand of an instruction may name. Immediates and
+/// memory are left to the encoding.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Constraint {
+    /// Any register the encoding takes.
+    Any,
+    /// A register of the class.
+    Class(RegClass),
+    /// This register at this width and no other, as the count of a shift
+    /// by a register must be `cl`. [`fixed`](crate::fixed) moves a count
+    /// written in another register into place.
+    Fixed(Gpr, GprWidth),
+}
+
+impl Constraint {
+    pub fn allows(&self, reg: &Amd64Register) -> bool {
+        match *self {
+            Constraint::Any => true,
+            Constraint::Class(class) => class.contains(reg),
+            Constraint::Fixed(gpr, width) => {
+                reg.containing_gpr() == Some(gpr) && reg.width() == Some(width.bits())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Constraint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Constraint::Any => write!(f, "any register"),
+            Constraint::Class(class) => write!(f, "{}", class),
+            Constraint::Fixed(gpr, width) => write!(f, "{}", gpr.name_at(*width)),
+        }
+    }
+}
+
+impl Amd64Mnemonic {
+    /// The constraint on each of `count` operands of the instruction.
+    pub fn constraints(&self, count: usize) -> Vec<Constraint> {
+        use Amd64Mnemonic::*;
+        use Constraint::{Any, Class, Fixed};
+
+        let gpr = Class(RegClass::Gpr);
+        let sse = Class(RegClass::Sse);
+        let each = |constraint| vec![constraint; count];
+        let pair = |first, second| {
+            let mut constraints = vec![first, second];
+            constraints.truncate(count);
+            constraints
+        };
+        match self {
+            Shl | Shr | Sar | Rol | Ror => pair(gpr, Fixed(Gpr::RCX, GprWidth::Byte)),
+            Lea => pair(gpr, Any),
+            Cvtsi2ss | Cvtsi2sd => pair(sse, gpr),
+            Cvttss2si | Cvttsd2si => pair(gpr, sse),
+            // Either side may be the general-purpose one.
+            Movd | Movq | Ret | Raw(_) => each(Any),
+            Movss | Movsd | Movaps | Movups | Movapd | Movupd | Movdqa | Movdqu | Addss | Addsd
+            | Addps | Addpd | Subss | Subsd | Subps | Subpd | Mulss | Mulsd | Mulps | Mulpd
+            | Divss | Divsd | Divps | Divpd | Sqrtss | Sqrtsd | Minss | Minsd | Maxss | Maxsd
+            | Andps | Andpd | Orps | Orpd | Xorps | Xorpd | Pand | Por | Pxor | Paddd | Paddq
+            | Psubd | Psubq | Ucomiss | Ucomisd | Comiss | Comisd | Cvtss2sd | Cvtsd2ss
+            | Movntdq | Movntps | Movntpd => each(sse),
+            // Every other known mnemonic starting with `v` is VEX- or
+            // EVEX-encoded and takes vector registers throughout.
+            m if m.to_string().starts_with('v') => each(Class(RegClass::Vector)),
+            _ => each(gpr),
+        }
+    }
+}
+
+impl FromStr for Amd64Mnemonic {
+    type Err = MnemonicErrorKind;
+
+    /// Like [`Amd64Mnemonic::parse`], but rejects unknown mnemonics.
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match Amd64Mnemonic::parse(name) {
+            Amd64Mnemonic::Raw(name) => Err(MnemonicErrorKind::Unknown(name)),
+            mnemonic => Ok(mnemonic),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MnemonicErrorKind {
+    Unknown(String),
+    /// The instruction takes `min` to `max` operands but was given `found`.
+    Arity {
+        min: usize,
+        max: usize,
+        found: usize,
+    },
+    /// A register wider than the instruction's encoding reaches, such as
+    /// ymm0 for a legacy SSE instruction. `encodings` names those that
+    /// reach it.
+    WideVector {
+        register: String,
+        encodings: &'static str,
+    },
+    /// Vector registers of different widths in an instruction that needs
+    /// them to match.
+    MixedVectors {
+        first: String,
+        other: String,
+    },
+    /// Operand `operand`, counting from 1, is a register the instruction
+    /// cannot take there.
+    Constraint {
+        operand: usize,
+        register: String,
+        expected: Constraint,
+    },
+}
+
+impl fmt::Display for MnemonicErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MnemonicErrorKind::Unknown(name) => write!(f, "unknown mnemonic `{}`", name),
+            MnemonicErrorKind::Arity { min, max, found } if min == max => {
+                write!(f, "takes {} operands, not {}", min, found)
+            }
+            MnemonicErrorKind::Arity { min, max, found } => {
+                write!(f, "takes {} to {} operands, not {}", min, max, found)
+            }
+            MnemonicErrorKind::WideVector {
+                register,
+                encodings,
+            } => write!(
+                f,
+                "cannot take {}, which only {} instructions reach",
+                register, encodings
+            ),
+            MnemonicErrorKind::MixedVectors { first, other } => {
+                write!(f, "mixes {} with {}", first, other)
+            }
+            MnemonicErrorKind::Constraint {
+                operand,
+                register,
+                expected,
+            } => write!(
+                f,
+                "operand {} cannot be {}, only {}",
+                operand, register, expected
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MnemonicError {
+    /// The section holding the instruction, when checking a program.
+    pub section: Option<String>,
+    pub instruction: String,
+    pub kind: MnemonicErrorKind,
+}
+
+impl fmt::Display for MnemonicError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "`{}`", self.instruction)?;
+        if let Some(section) = &self.section {
+            write!(f, " in section .{}", section)?;
+        }
+        write!(f, ": {}", self.kind)
+    }
+}
+
+impl error::Error for MnemonicError {}
+
+/// Whether `found` operands suit `mnemonic`.
+fn check_arity(mnemonic: &Amd64Mnemonic, found: usize) -> Result<(), MnemonicErrorKind> {
+    match mnemonic.arity() {
+        Some((min, max)) if !(min..=max).contains(&found) => {
+            Err(MnemonicErrorKind::Arity { min, max, found })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Whether the vector registers among `operands` suit `mnemonic`. Legacy
+/// SSE reaches only xmm registers and VEX also ymm, and the AVX
+/// instructions take registers of a single width.
+fn check_vectors(mnemonic: &Amd64Mnemonic, operands: &[Operand]) -> Result<(), MnemonicErrorKind> {
+    if let Amd64Mnemonic::Raw(_) = mnemonic {
+        return Ok(());
+    }
+    // Every known mnemonic starting with `v` is VEX- or EVEX-encoded.
+    let avx = mnemonic.to_string().starts_with('v');
+    let vex_only = matches!(
+        mnemonic,
+        Amd64Mnemonic::Vmovdqa
+            | Amd64Mnemonic::Vmovdqu
+            | Amd64Mnemonic::Vpand
+            | Amd64Mnemonic::Vpor
+            | Amd64Mnemonic::Vpxor
+    );
+
+    let mut first: Option<&Amd64Register> = None;
+    for operand in operands {
+        let Operand::Register(reg) = operand else {
+            continue;
+        };
+        let encodings = match reg {
+            Amd64Register::Ymm(_) if !avx => "VEX- and EVEX-encoded",
+            Amd64Register::Zmm(_) if !avx || vex_only => "EVEX-encoded",
+            Amd64Register::Vector(_) | Amd64Register::Ymm(_) | Amd64Register::Zmm(_) => {
+                match first {
+                    Some(f) if avx && f.width() != reg.width() => {
+                        return Err(MnemonicErrorKind::MixedVectors {
+                            first: f.to_string(),
+                            other: reg.to_string(),
+                        })
+                    }
+                    Some(_) => {}
+                    None => first = Some(reg),
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        return Err(MnemonicErrorKind::WideVector {
+            register: reg.to_string(),
+            encodings,
+        });
+    }
+    Ok(())
+}
+
+/// Whether `operands` suit `mnemonic` in number and, for vector
+/// registers, width.
+/// Whether each register among `operands` is one `mnemonic` takes in its
+/// place.
+fn check_constraints(
+    mnemonic: &Amd64Mnemonic,
+    operands: &[Operand],
+) -> Result<(), MnemonicErrorKind> {
+    let constraints = mnemonic.constraints(operands.len());
+    for (i, (operand, constraint)) in operands.iter().zip(constraints).enumerate() {
+        match operand {
+            Operand::Register(reg) if !constraint.allows(reg) => {
+                return Err(MnemonicErrorKind::Constraint {
+                    operand: i + 1,
+                    register: reg.to_string(),
+                    expected: constraint,
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn check_operands(mnemonic: &Amd64Mnemonic, operands: &[Operand]) -> Result<(), MnemonicErrorKind> {
+    check_arity(mnemonic, operands.len())?;
+    check_vectors(mnemonic, operands)?;
+    check_constraints(mnemonic, operands)
+}
+
+impl Amd64Instruction {
+    /// An instruction whose operands are checked against `mnemonic`.
+    pub fn typed(mnemonic: Amd64Mnemonic, operands: Vec<Operand>) -> Result<Self, MnemonicError> {
+        let inst = Amd64Instruction::new(mnemonic, operands);
+        match check_operands(&inst.mnemonic, &inst.operands) {
+            Ok(()) => Ok(inst),
+            Err(kind) => Err(MnemonicError {
+                section: None,
+                instruction: inst.to_string(),
+                kind,
+            }),
+        }
+    }
+
+    /// The mnemonic, without prefixes.
+    pub fn kind(&self) -> Amd64Mnemonic {
+        match &self.mnemonic {
+            Amd64Mnemonic::Raw(text) => {
+                Amd64Mnemonic::parse(text.split_whitespace().last().unwrap_or_default())
+            }
+            mnemonic => mnemonic.clone(),
+        }
+    }
+
+    /// What register each operand may be, for an allocator to choose from.
+    pub fn constraints(&self) -> Vec<Constraint> {
+        self.kind().constraints(self.operands.len())
+    }
+}
+
+/// Reports every instruction of `program` with the wrong number of
+/// operands or registers it cannot take, or with a mnemonic that is neither known nor in `allow`, in
+/// both arms of each conditional. `allow` lists the instructions outside
+/// [`Amd64Mnemonic`] the program is expected to use.
+pub fn check(program: &Program, allow: &[&str]) -> Result<(), Vec<MnemonicError>> {
+    let mut errors = Vec::new();
+
+    for section in &program.sections {
+        check_body(&section.body, &section.name, allow, &mut errors);
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn check_body(body: &[AsmExpr], section: &str, allow: &[&str], errors: &mut Vec<MnemonicError>) {
+    for expr in body {
+        match expr {
+            AsmExpr::Instruction(inst) => {
+                let mnemonic = inst.kind();
+                let result = match &mnemonic {
+                    Amd64Mnemonic::Raw(name) if allow.contains(&name.as_str()) => Ok(()),
+                    Amd64Mnemonic::Raw(name) => Err(MnemonicErrorKind::Unknown(name.clone())),
+                    _ => check_operands(&mnemonic, &inst.operands),
+                };
+                if let Err(kind) = result {
+                    errors.push(MnemonicError {
+                        section: Some(section.to_string()),
+                        instruction: inst.to_string(),
+                        kind,
+                    });
+                }
+            }
+            _ => {
+                for inner in expr.bodies() {
+                    check_body(inner, section, allow, errors);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{consts::RAX, instr, register::Reg64};
+
+    const CONDITIONS: [CondCode; 16] = [
+        CondCode::O,
+        CondCode::No,
+        CondCode::B,
+        CondCode::Ae,
+        CondCode::E,
+        CondCode::Ne,
+        CondCode::Be,
+        CondCode::A,
+        CondCode::S,
+        CondCode::Ns,
+        CondCode::P,
+        CondCode::Np,
+        CondCode::L,
+        CondCode::Ge,
+        CondCode::Le,
+        CondCode::G,
+    ];
+
+    #[test]
+    fn conditional_mnemonics_spell_their_condition() {
+        for cc in CONDITIONS {
+            assert_eq!(Amd64Mnemonic::Jcc(cc).as_str(), format!("j{}", cc));
+            assert_eq!(Amd64Mnemonic::Cmovcc(cc).as_str(), format!("cmov{}", cc));
+            assert_eq!(Amd64Mnemonic::Setcc(cc).as_str(), format!("set{}", cc));
+            assert_eq!(
+                Amd64Mnemonic::parse(&format!("j{}", cc)),
+                Amd64Mnemonic::Jcc(cc)
+            );
+        }
+    }
+
+    #[test]
+    fn builders_are_typed() {
+        let AsmExpr::Instruction(inst) = instr::mov(RAX, 1) else {
+            panic!("not an instruction");
+        };
+        assert_eq!(inst.mnemonic, Amd64Mnemonic::Mov);
+        let AsmExpr::Instruction(inst) = instr::rdfsbase(Reg64::Rax) else {
+            panic!("not an instruction");
+        };
+        assert_eq!(inst.mnemonic, Amd64Mnemonic::Rdfsbase);
+    }
+
+    #[test]
+    fn prefixed_mnemonics_are_raw_but_know_their_kind() {
+        let inst = Amd64Instruction::new(Amd64Mnemonic::parse("lock add"), vec![]);
+        assert!(inst.mnemonic.is_raw());
+        assert_eq!(inst.kind(), Amd64Mnemonic::Add);
+        assert_eq!(inst.to_string(), "lock add");
+    }
+}
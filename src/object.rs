@@ -0,0 +1,461 @@
+//! Pre-built code and data linked into a program by the encoder: ELF
+//! relocatable objects, such as hand-written routines assembled elsewhere,
+//! or raw bytes under a symbol name.
+//!
+//! Each allocated section of an object is appended to the program's
+//! section of the same kind, so generated code can call into it and it
+//! can call back out. Only the relocations the encoder can fill in
+//! directly are supported; anything that needs a GOT or PLT is rejected,
+//! so objects should be built with `-fno-pic` or the equivalent.
+//! Objects only take part in encoding, not in the emitted assembly text.
+
+use std::{error, fmt};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ObjectError {
+    /// Not a little-endian 64-bit x86-64 ELF relocatable object.
+    NotRelocatable,
+    /// A header, table or section extends past the end of the file.
+    Truncated,
+    UnsupportedRelocation {
+        section: String,
+        kind: u32,
+    },
+    /// A common symbol, which needs allocating by a full linker.
+    CommonSymbol(String),
+}
+
+impl fmt::Display for ObjectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjectError::NotRelocatable => {
+                write!(f, "not an x86-64 ELF relocatable object")
+            }
+            ObjectError::Truncated => write!(f, "object file is truncated"),
+            ObjectError::UnsupportedRelocation { section, kind } => {
+                write!(f, "unsupported relocation type {} in {}", kind, section)
+            }
+            ObjectError::CommonSymbol(name) => {
+                write!(f, "common symbol `{}` needs -fno-common to be linked", name)
+            }
+        }
+    }
+}
+
+impl error::Error for ObjectError {}
+
+/// How a relocated field is computed from its symbol's address `S`, the
+/// addend `A` and the field's own address `P`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RelocationKind {
+    /// `S + A` in 64 bits.
+    Absolute64,
+    /// `S + A` in 32 bits, zero-extended.
+    Absolute32,
+    /// `S + A` in 32 bits, sign-extended.
+    Absolute32Signed,
+    /// `S + A - P` in 32 bits.
+    Relative32,
+    /// `S + A - P` in 64 bits.
+    Relative64,
+}
+
+impl RelocationKind {
+    /// Size of the relocated field in bytes.
+    pub fn width(self) -> u8 {
+        match self {
+            RelocationKind::Absolute64 | RelocationKind::Relative64 => 8,
+            _ => 4,
+        }
+    }
+
+    pub fn is_relative(self) -> bool {
+        matches!(
+            self,
+            RelocationKind::Relative32 | RelocationKind::Relative64
+        )
+    }
+
+    fn from_elf(kind: u32) -> Option<Self> {
+        Some(match kind {
+            R_X86_64_64 => RelocationKind::Absolute64,
+            R_X86_64_PC32 | R_X86_64_PLT32 => RelocationKind::Relative32,
+            R_X86_64_32 => RelocationKind::Absolute32,
+            R_X86_64_32S => RelocationKind::Absolute32Signed,
+            R_X86_64_PC64 => RelocationKind::Relative64,
+            _ => return None,
+        })
+    }
+
+    pub(crate) fn to_elf(self) -> u32 {
+        match self {
+            RelocationKind::Absolute64 => R_X86_64_64,
+            RelocationKind::Relative32 => R_X86_64_PC32,
+            RelocationKind::Absolute32 => R_X86_64_32,
+            RelocationKind::Absolute32Signed => R_X86_64_32S,
+            RelocationKind::Relative64 => R_X86_64_PC64,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Relocation {
+    /// Offset of the field from the start of its section.
+    pub offset: u64,
+    pub kind: RelocationKind,
+    pub symbol: String,
+    pub addend: i64,
+}
+
+/// One section of an object, destined for the program section `target`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObjectSection {
+    pub target: String,
+    pub bytes: Vec<u8>,
+    pub align: u64,
+    /// Symbols defined in the section, with their offsets.
+    pub symbols: Vec<(String, u64)>,
+    pub relocations: Vec<Relocation>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Object {
+    /// Used to keep the object's local symbols apart from everyone else's.
+    pub name: String,
+    pub sections: Vec<ObjectSection>,
+}
+
+impl Object {
+    /// `bytes` placed in `section` and labelled `symbol`.
+    pub fn raw(section: &str, symbol: &str, bytes: Vec<u8>) -> Self {
+        Object {
+            name: symbol.to_string(),
+            sections: vec![ObjectSection {
+                target: section.to_string(),
+                bytes,
+                align: 1,
+                symbols: vec![(symbol.to_string(), 0)],
+                relocations: Vec::new(),
+            }],
+        }
+    }
+
+    /// Reads an ELF relocatable object. Global symbols keep their names;
+    /// local ones are prefixed with `name:`.
+    pub fn parse(name: &str, file: &[u8]) -> Result<Self, ObjectError> {
+        let elf = Elf { file };
+        if file.get(..4) != Some(b"\x7fELF".as_slice())
+            || file.get(4..6) != Some([2, 1].as_slice())
+            || elf.u16(16)? != ET_REL
+            || elf.u16(18)? != EM_X86_64
+        {
+            return Err(ObjectError::NotRelocatable);
+        }
+
+        let shoff = elf.u64(0x28)? as usize;
+        let headers: Vec<SectionHeader> = (0..elf.u16(0x3c)? as usize)
+            .map(|i| elf.section_header(shoff + i * 64))
+            .collect::<Result<_, _>>()?;
+        let shstrtab = headers
+            .get(elf.u16(0x3e)? as usize)
+            .ok_or(ObjectError::Truncated)?;
+        let section_name = |h: &SectionHeader| elf.string(shstrtab.offset + h.name as u64);
+
+        // Allocated sections become object sections; remember which.
+        let mut index = vec![None; headers.len()];
+        let mut sections = Vec::new();
+        for (i, h) in headers.iter().enumerate() {
+            if h.flags & SHF_ALLOC == 0 || !matches!(h.kind, SHT_PROGBITS | SHT_NOBITS) {
+                continue;
+            }
+            let bytes = match h.kind {
+                SHT_NOBITS => vec![0; h.size as usize],
+                _ => elf.slice(h.offset, h.size)?.to_vec(),
+            };
+            index[i] = Some(sections.len());
+            sections.push(ObjectSection {
+                target: target_section(&section_name(h)?),
+                bytes,
+                align: h.align.max(1),
+                symbols: vec![(format!("{}:{}", name, section_name(h)?), 0)],
+                relocations: Vec::new(),
+            });
+        }
+
+        let Some(symtab) = headers.iter().find(|h| h.kind == SHT_SYMTAB) else {
+            return Ok(Object {
+                name: name.to_string(),
+                sections,
+            });
+        };
+        let strtab = headers
+            .get(symtab.link as usize)
+            .ok_or(ObjectError::Truncated)?;
+
+        // Symbol names as relocations refer to them.
+        let mut symbols = Vec::new();
+        for i in 0..symtab.size / 24 {
+            let at = (symtab.offset + i * 24) as usize;
+            let info = *file.get(at + 4).ok_or(ObjectError::Truncated)?;
+            let shndx = elf.u16(at + 6)?;
+            let value = elf.u64(at + 8)?;
+            let local = info >> 4 == STB_LOCAL;
+            let symbol = match info & 0xf {
+                STT_SECTION => match headers.get(shndx as usize) {
+                    Some(h) => format!("{}:{}", name, section_name(h)?),
+                    None => String::new(),
+                },
+                _ => {
+                    let base = elf.string(strtab.offset + elf.u32(at)? as u64)?;
+                    if local && !base.is_empty() {
+                        format!("{}:{}", name, base)
+                    } else {
+                        base
+                    }
+                }
+            };
+
+            if shndx == SHN_COMMON {
+                return Err(ObjectError::CommonSymbol(symbol));
+            }
+            let defines = info & 0xf != STT_SECTION && info & 0xf != STT_FILE;
+            if let Some(Some(s)) = index.get(shndx as usize) {
+                if defines && !symbol.is_empty() {
+                    sections[*s].symbols.push((symbol.clone(), value));
+                }
+            }
+            symbols.push(symbol);
+        }
+
+        for h in headers.iter().filter(|h| h.kind == SHT_RELA) {
+            let Some(Some(s)) = index.get(h.info as usize) else {
+                continue;
+            };
+            for i in 0..h.size / 24 {
+                let at = (h.offset + i * 24) as usize;
+                let info = elf.u64(at + 8)?;
+                let kind = RelocationKind::from_elf(info as u32).ok_or_else(|| {
+                    ObjectError::UnsupportedRelocation {
+                        section: section_name(h).unwrap_or_default(),
+                        kind: info as u32,
+                    }
+                })?;
+                sections[*s].relocations.push(Relocation {
+                    offset: elf.u64(at)?,
+                    kind,
+                    symbol: symbols
+                        .get((info >> 32) as usize)
+                        .cloned()
+                        .ok_or(ObjectError::Truncated)?,
+                    addend: elf.u64(at + 16)? as i64,
+                });
+            }
+        }
+
+        Ok(Object {
+            name: name.to_string(),
+            sections,
+        })
+    }
+}
+
+/// The program section an ELF section's contents belong in.
+fn target_section(name: &str) -> String {
+    let name = name.trim_start_matches('.');
+    for kind in ["text", "rodata", "data", "bss"] {
+        if name == kind || name.starts_with(&format!("{}.", kind)) {
+            return kind.to_string();
+        }
+    }
+    name.to_string()
+}
+
+pub(crate) const ET_REL: u16 = 1;
+pub(crate) const EM_X86_64: u16 = 62;
+pub(crate) const SHT_PROGBITS: u32 = 1;
+pub(crate) const SHT_SYMTAB: u32 = 2;
+pub(crate) const SHT_RELA: u32 = 4;
+pub(crate) const SHT_NOBITS: u32 = 8;
+pub(crate) const SHF_ALLOC: u64 = 2;
+pub(crate) const STB_LOCAL: u8 = 0;
+pub(crate) const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+const SHN_COMMON: u16 = 0xfff2;
+const R_X86_64_64: u32 = 1;
+const R_X86_64_PC32: u32 = 2;
+const R_X86_64_PLT32: u32 = 4;
+const R_X86_64_32: u32 = 10;
+const R_X86_64_32S: u32 = 11;
+const R_X86_64_PC64: u32 = 24;
+
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+}
+
+/// Bounds-checked little-endian reads from an ELF file.
+struct Elf<'a> {
+    file: &'a [u8],
+}
+
+impl<'a> Elf<'a> {
+    fn slice(&self, offset: u64, len: u64) -> Result<&'a [u8], ObjectError> {
+        let start = usize::try_from(offset).map_err(|_| ObjectError::Truncated)?;
+        let end = start
+            .checked_add(len as usize)
+            .ok_or(ObjectError::Truncated)?;
+        self.file.get(start..end).ok_or(ObjectError::Truncated)
+    }
+
+    fn bytes<const N: usize>(&self, offset: usize) -> Result<[u8; N], ObjectError> {
+        Ok(self.slice(offset as u64, N as u64)?.try_into().unwrap())
+    }
+
+    fn u16(&self, offset: usize) -> Result<u16, ObjectError> {
+        self.bytes(offset).map(u16::from_le_bytes)
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32, ObjectError> {
+        self.bytes(offset).map(u32::from_le_bytes)
+    }
+
+    fn u64(&self, offset: usize) -> Result<u64, ObjectError> {
+        self.bytes(offset).map(u64::from_le_bytes)
+    }
+
+    fn string(&self, offset: u64) -> Result<String, ObjectError> {
+        let start = usize::try_from(offset).map_err(|_| ObjectError::Truncated)?;
+        let rest = self.file.get(start..).ok_or(ObjectError::Truncated)?;
+        let len = rest
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or(ObjectError::Truncated)?;
+        Ok(String::from_utf8_lossy(&rest[..len]).into_owned())
+    }
+
+    fn section_header(&self, at: usize) -> Result<SectionHeader, ObjectError> {
+        Ok(SectionHeader {
+            name: self.u32(at)?,
+            kind: self.u32(at + 4)?,
+            flags: self.u64(at + 8)?,
+            offset: self.u64(at + 24)?,
+            size: self.u64(at + 32)?,
+            link: self.u32(at + 40)?,
+            info: self.u32(at + 44)?,
+            align: self.u64(at + 48)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm_dsl, encode::EncodeOptions, program::Program, AsmExpr, Data, Label, Section};
+
+    /// An object as the crate writes it: a global `helper` that loads the
+    /// address of a local `table`.
+    fn helper() -> Vec<u8> {
+        let text = [
+            vec![AsmExpr::Label(Label::plain("helper"))],
+            asm_dsl! { lea rax, [rel table]; ret; },
+        ]
+        .concat();
+        let data = vec![
+            AsmExpr::Label(Label::plain("table")),
+            AsmExpr::Data(Data::UInt(7)),
+        ];
+        Program::default()
+            .with_global("helper")
+            .with_section(Section::new("text", text))
+            .with_section(Section::new("data", data))
+            .to_object()
+            .unwrap()
+    }
+
+    #[test]
+    fn sections_symbols_and_relocations_are_read() {
+        let object = Object::parse("lib", &helper()).unwrap();
+        let [text, data] = object.sections.as_slice() else {
+            panic!("{:?}", object.sections);
+        };
+        assert_eq!(
+            (text.target.as_str(), data.target.as_str()),
+            ("text", "data")
+        );
+        assert_eq!(data.bytes, 7u64.to_le_bytes());
+        assert_eq!(
+            text.symbols,
+            [("lib:.text".to_string(), 0), ("helper".to_string(), 0)]
+        );
+        assert_eq!(
+            data.symbols,
+            [("lib:.data".to_string(), 0), ("lib:table".to_string(), 0)]
+        );
+        assert_eq!(
+            text.relocations,
+            [Relocation {
+                offset: 3,
+                kind: RelocationKind::Relative32,
+                symbol: "lib:.data".to_string(),
+                addend: -4,
+            }]
+        );
+    }
+
+    #[test]
+    fn linked_objects_can_be_called_into() {
+        let mut program = Program::default().with_section(Section::new(
+            "text",
+            asm_dsl! {
+                call helper;
+                ret;
+            },
+        ));
+        program.link(Object::parse("lib", &helper()).unwrap());
+        let image = program.encode(&EncodeOptions::new()).unwrap();
+        let helper = image.symbol("helper").unwrap();
+        let text = &image.section("text").unwrap().bytes;
+        let rel = i32::from_le_bytes(text[1..5].try_into().unwrap());
+        assert_eq!(5 + rel as i64, helper as i64);
+        // The object's own reference to its data is filled in too.
+        let table = image.symbol("lib:table").unwrap();
+        let at = helper as usize + 3;
+        let disp = i32::from_le_bytes(text[at..at + 4].try_into().unwrap());
+        assert_eq!(at as i64 + 4 + disp as i64, table as i64);
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        assert_eq!(
+            Object::parse("lib", b"#!/bin/sh\n"),
+            Err(ObjectError::NotRelocatable)
+        );
+        let file = helper();
+        assert_eq!(
+            Object::parse("lib", &file[..file.len() - 16]),
+            Err(ObjectError::Truncated)
+        );
+    }
+
+    #[test]
+    fn sections_go_where_their_kind_does() {
+        assert_eq!(target_section(".text.unlikely"), "text");
+        assert_eq!(target_section(".rodata.str1.1"), "rodata");
+        assert_eq!(target_section(".bss"), "bss");
+        assert_eq!(target_section(".textual"), "textual");
+        assert_eq!(target_section(".init_array"), "init_array");
+    }
+
+    #[test]
+    fn raw_bytes_are_one_labelled_section() {
+        let object = Object::raw("rodata", "blob", vec![1, 2, 3]);
+        assert_eq!(object.sections[0].target, "rodata");
+        assert_eq!(object.sections[0].symbols, [("blob".to_string(), 0)]);
+    }
+}
@@ -0,0 +1,197 @@
+//! Peephole rewrites of instruction sequences.
+//!
+//! [`optimize`] looks at each instruction of the text sections with those
+//! around it and replaces it with something shorter doing the same, or
+//! drops it if it does nothing. How far it goes is an [`OptLevel`]: the
+//! first level only removes instructions, moves of a register to itself and
+//! jumps to the label right after them; the second also rewrites ones that
+//! set the flags differently where nothing reads the flags after, zeroing
+//! a register with `xor` and adding or subtracting one with `inc` and
+//! `dec`:
+//!
+//! ```
+//! use cataclysm::{consts::{RAX, RCX}, instr, optimize::{optimize_body, OptLevel}, AsmExpr, Label};
+//!
+//! let mut body = vec![
+//!     instr::mov(RAX, RAX),
+//!     instr::jmp(Label::plain(".done")),
+//!     AsmExpr::Label(Label::plain(".done")),
+//!     instr::add(RCX, 1),
+//!     instr::mov(RAX, 0),
+//!     instr::ret(),
+//! ];
+//! assert_eq!(optimize_body(&mut body, OptLevel::O2), 4);
+//! let lines: Vec<String> = body.iter().map(|expr| expr.to_string().trim().replace('\t', " ")).collect();
+//! assert_eq!(lines, [".done:", "inc rcx", "xor eax, eax", "ret"]);
+//! ```
+//!
+//! What it writes encodes, and runs the same under the
+//! [interpreter](crate::interp):
+//!
+//! ```
+//! use cataclysm::{
+//!     consts::{RAX, RDI},
+//!     encode::EncodeOptions,
+//!     instr, interp,
+//!     optimize::{optimize, OptLevel},
+//!     register::Gpr,
+//!     AsmExpr, Label, Program, Section,
+//! };
+//!
+//! let body = vec![
+//!     AsmExpr::Label(Label::plain("f")),
+//!     instr::mov(RAX, 0),
+//!     instr::add(RAX, RDI),
+//!     instr::sub(RAX, 1),
+//!     instr::ret(),
+//! ];
+//! let mut program = Program::default().with_section(Section::new("text", body));
+//! let before = interp::run_with(&program, "f", &[(Gpr::RDI, 5)], 10).unwrap();
+//! assert_eq!(optimize(&mut program, OptLevel::O2), 2);
+//! let after = interp::run_with(&program, "f", &[(Gpr::RDI, 5)], 10).unwrap();
+//! assert_eq!((before.status, after.status), (4, 4));
+//! program.encode(&EncodeOptions::new()).unwrap();
+//! ```
+//!
+//! The flags count as dead only where the straight-line code after the
+//! instruction overwrites them before any label or jump, or returns, so no
+//! rewrite needs the function's control flow.
+
+use std::collections::HashMap;
+
+use crate::{
+    dataflow::{constant, split_prefix, straight_line_liveness},
+    mnemonic::Amd64Mnemonic,
+    program::Program,
+    register::GprWidth,
+    Amd64Instruction, Amd64Register, AsmExpr, ImmediateValue, Operand,
+};
+
+/// How much [`optimize`] rewrites.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum OptLevel {
+    /// Nothing.
+    O0,
+    /// Removes instructions that do nothing.
+    #[default]
+    O1,
+    /// Also rewrites instructions into shorter ones that set the flags
+    /// differently, where the flags are dead.
+    O2,
+}
+
+/// Rewrites the instructions of the program's text sections, in both arms
+/// of conditionals, and returns how many it rewrote or removed.
+pub fn optimize(program: &mut Program, level: OptLevel) -> usize {
+    let mut rewritten = 0;
+    for section in program
+        .sections
+        .iter_mut()
+        .filter(|s| s.name.starts_with("text"))
+    {
+        rewritten += optimize_body(section.body_mut(), level);
+    }
+    rewritten
+}
+
+/// Rewrites the instructions of `body` and returns how many it rewrote or
+/// removed.
+pub fn optimize_body(body: &mut Vec<AsmExpr>, level: OptLevel) -> usize {
+    if level == OptLevel::O0 {
+        return 0;
+    }
+    let mut rewritten = 0;
+    let mut i = 0;
+    while i < body.len() {
+        let AsmExpr::Instruction(inst) = &body[i] else {
+            for inner in body[i].bodies_mut() {
+                rewritten += optimize_body(inner, level);
+            }
+            i += 1;
+            continue;
+        };
+        if inst.fixup.is_some() {
+            i += 1;
+            continue;
+        }
+        if is_nop_move(inst) || jumps_to_next(inst, &body[i + 1..]) {
+            body.remove(i);
+            rewritten += 1;
+            continue;
+        }
+        if level >= OptLevel::O2 && !straight_line_liveness(&body[i + 1..]).1 {
+            if let Some(shorter) = shorter(inst) {
+                body[i] = AsmExpr::Instruction(shorter);
+                rewritten += 1;
+            }
+        }
+        i += 1;
+    }
+    rewritten
+}
+
+/// Whether `inst` moves a register to itself without zero-extending it.
+fn is_nop_move(inst: &Amd64Instruction) -> bool {
+    match (inst.mnemonic.as_str(), inst.operands.as_slice()) {
+        ("mov", [Operand::Register(dst), Operand::Register(src)]) => {
+            dst.containing_gpr().is_some()
+                && dst.containing_gpr() == src.containing_gpr()
+                && dst.width() == src.width()
+                // Writing a 32-bit register clears the upper half.
+                && dst.width() != Some(32)
+        }
+        _ => false,
+    }
+}
+
+/// Whether `inst` is a jump to one of the labels starting `rest`, so that
+/// control gets there the same without it.
+fn jumps_to_next(inst: &Amd64Instruction, rest: &[AsmExpr]) -> bool {
+    if !inst.mnemonic.as_str().starts_with('j') {
+        return false;
+    }
+    let Some(Operand::Immediate(ImmediateValue::Label(target))) = inst.operands.first() else {
+        return false;
+    };
+    let local = target.label.starts_with('.') && !target.label.starts_with("..");
+    // A local label after a non-local one is in another scope.
+    let mut same_scope = true;
+    for expr in rest {
+        let AsmExpr::Label(label) = expr else {
+            return false;
+        };
+        if label.label == target.label && (same_scope || !local) {
+            return true;
+        }
+        if !label.label.starts_with('.') || label.label.starts_with("..") {
+            same_scope = false;
+        }
+    }
+    false
+}
+
+/// A shorter instruction doing what `inst` does but for the flags.
+fn shorter(inst: &Amd64Instruction) -> Option<Amd64Instruction> {
+    let (prefix, mnemonic) = split_prefix(inst.mnemonic.as_str());
+    if prefix.is_some() {
+        return None;
+    }
+    let [Operand::Register(dst), src] = inst.operands.as_slice() else {
+        return None;
+    };
+    let gpr = dst.containing_gpr()?;
+    let (mnemonic, operands) = match (mnemonic, constant(src, &HashMap::new())?) {
+        // The 32-bit form clears the upper half too, and is shorter still.
+        ("mov", 0) if matches!(dst.width(), Some(32 | 64)) => {
+            let dword = Operand::Register(Amd64Register::Partial(gpr, GprWidth::Dword));
+            (Amd64Mnemonic::Xor, vec![dword.clone(), dword])
+        }
+        ("add", 1) | ("sub", -1) => (Amd64Mnemonic::Inc, vec![Operand::Register(dst.clone())]),
+        ("sub", 1) | ("add", -1) => (Amd64Mnemonic::Dec, vec![Operand::Register(dst.clone())]),
+        _ => return None,
+    };
+    let mut shorter = inst.clone();
+    shorter.mnemonic = mnemonic;
+    shorter.operands = operands;
+    Some(shorter)
+}
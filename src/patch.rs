@@ -0,0 +1,273 @@
+//! Patching encoded code in place, for loaders and JITs that build code
+//! once and specialize its constants, or swap an instruction, before each
+//! use.
+//!
+//! A [`Patcher`] keeps the encoded image together with its layout, so a
+//! patch can be checked against the item it lands in: an immediate must
+//! fall inside one instruction or data item, and a replacement
+//! instruction must fit where the old one was. A shorter replacement is
+//! padded with `nop`s; nothing after it moves.
+//!
+//! ```
+//! use cataclysm::{asm_dsl, encode::EncodeOptions, Program, Section};
+//!
+//! let program = Program::default().with_section(Section::new(
+//!     "text",
+//!     asm_dsl! {
+//!         get:
+//!         mov rax, 0x11223344;
+//!         ret;
+//!     },
+//! ));
+//! let mut patcher = program.patcher(&EncodeOptions::default()).unwrap();
+//! // The encoder picks the five-byte `mov eax` form, whose immediate
+//! // follows the opcode.
+//! patcher.patch_imm32_at("get", 1, 42).unwrap();
+//! assert_eq!(patcher.image().sections[0].bytes, [0xb8, 42, 0, 0, 0, 0xc3]);
+//! ```
+
+use std::{collections::HashMap, error, fmt};
+
+use crate::{
+    encode::{self, EncodeError, EncodeErrorKind, EncodeOptions, Image},
+    expr::ConstExpr,
+    layout::{EntryKind, Layout},
+    program::Program,
+    AsmExpr,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchError {
+    UndefinedSymbol(String),
+    /// The bytes to patch are not all inside one instruction or data item.
+    OutOfBounds {
+        symbol: String,
+        offset: u64,
+    },
+    /// An immediate patch that fits neither a signed nor an unsigned
+    /// 32-bit field.
+    ValueOutOfRange(i64),
+    /// There are fewer than `index + 1` instructions after `label` in its
+    /// section.
+    NoInstruction {
+        label: String,
+        index: usize,
+    },
+    /// A replacement that is not an instruction.
+    NotAnInstruction(String),
+    /// A replacement `found` bytes long, where `available` are free.
+    TooLong {
+        available: u64,
+        found: u64,
+    },
+    Encode(EncodeErrorKind),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::UndefinedSymbol(name) => write!(f, "undefined symbol `{}`", name),
+            PatchError::OutOfBounds { symbol, offset } => write!(
+                f,
+                "{} + {} is not inside one instruction or data item",
+                symbol, offset
+            ),
+            PatchError::ValueOutOfRange(value) => {
+                write!(f, "value {:#x} does not fit in 32 bits", value)
+            }
+            PatchError::NoInstruction { label, index } => {
+                write!(f, "there is no instruction {} after `{}`", index, label)
+            }
+            PatchError::NotAnInstruction(expr) => {
+                write!(
+                    f,
+                    "`{}` is not an instruction",
+                    expr.trim().replace('\t', " ")
+                )
+            }
+            PatchError::TooLong { available, found } => write!(
+                f,
+                "the replacement is {} bytes long, where {} are free",
+                found, available
+            ),
+            PatchError::Encode(kind) => write!(f, "{}", kind),
+        }
+    }
+}
+
+impl error::Error for PatchError {}
+
+impl From<EncodeErrorKind> for PatchError {
+    fn from(kind: EncodeErrorKind) -> Self {
+        PatchError::Encode(kind)
+    }
+}
+
+/// An encoded program open for patching.
+#[derive(Clone)]
+pub struct Patcher {
+    image: Image,
+    layout: Layout,
+    /// Symbol addresses, defines and equs, for encoding replacements.
+    env: HashMap<String, ConstExpr>,
+}
+
+impl Patcher {
+    /// Encodes `program` as [`Program::encode`] would, keeping its layout.
+    pub fn new(program: &Program, options: &EncodeOptions) -> Result<Self, Vec<EncodeError>> {
+        let image = encode::encode(program, options)?;
+        let layout = encode::layout(program, options)?;
+        let mut env = program.define_map();
+        for section in &layout.sections {
+            for entry in &section.entries {
+                if let EntryKind::Equ { name, value } = &entry.kind {
+                    env.insert(name.clone(), ConstExpr::Int(*value));
+                }
+            }
+        }
+        for (name, &address) in &layout.symbols {
+            env.insert(name.clone(), ConstExpr::Int(address as i64));
+        }
+        Ok(Patcher { image, layout, env })
+    }
+
+    pub fn image(&self) -> &Image {
+        &self.image
+    }
+
+    pub fn into_image(self) -> Image {
+        self.image
+    }
+
+    /// Overwrites the 32-bit little-endian field `offset` bytes past
+    /// `symbol` with `value`, which may be signed or unsigned.
+    pub fn patch_imm32_at(
+        &mut self,
+        symbol: &str,
+        offset: u64,
+        value: i64,
+    ) -> Result<(), PatchError> {
+        if !(i32::MIN as i64..=u32::MAX as i64).contains(&value) {
+            return Err(PatchError::ValueOutOfRange(value));
+        }
+        let address = self
+            .layout
+            .symbols
+            .get(symbol)
+            .ok_or_else(|| PatchError::UndefinedSymbol(symbol.to_string()))?
+            + offset;
+        let out_of_bounds = || PatchError::OutOfBounds {
+            symbol: symbol.to_string(),
+            offset,
+        };
+
+        // The field must lie inside one item with bytes of its own.
+        let inside = self.layout.sections.iter().any(|section| {
+            section.entries.iter().any(|entry| {
+                let start = section.address + entry.offset;
+                matches!(entry.kind, EntryKind::Instruction(_) | EntryKind::Data(_))
+                    && start <= address
+                    && address + 4 <= start + entry.size
+            })
+        });
+        if !inside {
+            return Err(out_of_bounds());
+        }
+        let field = self.bytes_at(address, 4).ok_or_else(out_of_bounds)?;
+        field.copy_from_slice(&(value as u32).to_le_bytes());
+        Ok(())
+    }
+
+    /// Replaces the instruction `index` places after `label` in its
+    /// section, counting instructions only, with `expr`. A local `label`
+    /// is named with its scope, as in `f.loop`. Labels the replacement
+    /// refers to resolve as in the encoded program, and local ones within
+    /// the scope of the non-local label before it. Memory operands need a
+    /// size of their own if the instruction does not give them one;
+    /// section defaults do not apply.
+    pub fn replace_instruction(
+        &mut self,
+        label: &str,
+        index: usize,
+        expr: &AsmExpr,
+    ) -> Result<(), PatchError> {
+        let AsmExpr::Instruction(inst) = expr else {
+            return Err(PatchError::NotAnInstruction(expr.to_string()));
+        };
+        let no_instruction = || PatchError::NoInstruction {
+            label: label.to_string(),
+            index,
+        };
+
+        let (s, e) = self
+            .layout
+            .sections
+            .iter()
+            .enumerate()
+            .find_map(|(s, section)| {
+                let at = section.entries.iter().position(
+                    |entry| matches!(&entry.kind, EntryKind::Label(name) if name == label),
+                )?;
+                let (e, _) = section
+                    .entries
+                    .iter()
+                    .enumerate()
+                    .skip(at)
+                    .filter(|(_, entry)| matches!(entry.kind, EntryKind::Instruction(_)))
+                    .nth(index)?;
+                Some((s, e))
+            })
+            .ok_or_else(no_instruction)?;
+
+        let section = &self.layout.sections[s];
+        let entry = &section.entries[e];
+        let address = section.address + entry.offset;
+        let scope = section.entries[..e]
+            .iter()
+            .rev()
+            .find_map(|entry| match &entry.kind {
+                EntryKind::Label(name) if !name.starts_with('.') => {
+                    Some(name.split('.').next().unwrap_or(name).to_string())
+                }
+                _ => None,
+            })
+            .unwrap_or_default();
+
+        // The short form of a branch may fit where the long one does not.
+        let available = entry.size;
+        let bytes = [false, true]
+            .into_iter()
+            .map(|long| encode::encode_placed(inst, &scope, long, address, &self.env))
+            .reduce(|short, long| match short {
+                Ok(bytes) if bytes.len() as u64 <= available => Ok(bytes),
+                _ => long,
+            })
+            .unwrap()?;
+        if bytes.len() as u64 > available {
+            return Err(PatchError::TooLong {
+                available,
+                found: bytes.len() as u64,
+            });
+        }
+
+        let field = self
+            .bytes_at(address, available)
+            .ok_or_else(no_instruction)?;
+        field.fill(NOP);
+        field[..bytes.len()].copy_from_slice(&bytes);
+        self.layout.sections[s].entries[e].kind = EntryKind::Instruction(inst.clone());
+        Ok(())
+    }
+
+    /// The `len` image bytes at `address`, if one section holds them all.
+    fn bytes_at(&mut self, address: u64, len: u64) -> Option<&mut [u8]> {
+        let section = self.image.sections.iter_mut().find(|section| {
+            section.address <= address
+                && address + len <= section.address + section.bytes.len() as u64
+        })?;
+        let start = (address - section.address) as usize;
+        Some(&mut section.bytes[start..start + len as usize])
+    }
+}
+
+const NOP: u8 = 0x90;
@@ -0,0 +1,173 @@
+//! Instruction policies for embedders that must never emit certain
+//! instructions, such as a sandboxed JIT that may not make system calls.
+//!
+//! A [`Policy`] combines an optional allow-list of mnemonics, a deny-list
+//! of mnemonics and prefixes, and callbacks for anything more involved.
+//! Code can be checked as it is built, with [`Policy::admit`], or as a
+//! whole program before it is emitted, with [`check`].
+
+use std::{collections::BTreeSet, error, fmt, rc::Rc};
+
+use crate::{program::Program, target::required_cpl, Amd64Instruction, AsmExpr};
+
+/// A callback that rejects an instruction by returning why.
+type Hook = Rc<dyn Fn(&Amd64Instruction) -> Result<(), String>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyError {
+    /// The section holding the instruction, when checking a program.
+    pub section: Option<String>,
+    pub instruction: String,
+    pub reason: String,
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "`{}`", self.instruction)?;
+        if let Some(section) = &self.section {
+            write!(f, " in section .{}", section)?;
+        }
+        write!(f, " is not allowed: {}", self.reason)
+    }
+}
+
+impl error::Error for PolicyError {}
+
+/// Which instructions code may contain. The default allows everything.
+#[derive(Clone, Default)]
+pub struct Policy {
+    allowed: Option<BTreeSet<String>>,
+    denied: BTreeSet<String>,
+    denied_prefixes: BTreeSet<String>,
+    deny_privileged: bool,
+    deny_raw: bool,
+    hooks: Vec<Hook>,
+}
+
+impl Policy {
+    pub fn new() -> Self {
+        Policy::default()
+    }
+
+    /// Allows only the listed mnemonics, prefixes aside. Calling this again
+    /// extends the list.
+    pub fn allow_only<'a>(mut self, mnemonics: impl IntoIterator<Item = &'a str>) -> Self {
+        self.allowed
+            .get_or_insert_with(BTreeSet::new)
+            .extend(mnemonics.into_iter().map(str::to_string));
+        self
+    }
+
+    /// Rejects `mnemonic`, even if allow-listed.
+    pub fn deny(mut self, mnemonic: &str) -> Self {
+        self.denied.insert(mnemonic.to_string());
+        self
+    }
+
+    /// Rejects any instruction carrying `prefix`, such as `lock` or `rep`.
+    pub fn deny_prefix(mut self, prefix: &str) -> Self {
+        self.denied_prefixes.insert(prefix.to_string());
+        self
+    }
+
+    /// Rejects instructions that only run in ring 0.
+    pub fn deny_privileged(mut self) -> Self {
+        self.deny_privileged = true;
+        self
+    }
+
+    /// Rejects raw text, whose instructions cannot be checked.
+    pub fn deny_raw(mut self) -> Self {
+        self.deny_raw = true;
+        self
+    }
+
+    /// Runs `hook` on every instruction, which rejects it by returning the
+    /// reason.
+    pub fn with_hook(
+        mut self,
+        hook: impl Fn(&Amd64Instruction) -> Result<(), String> + 'static,
+    ) -> Self {
+        self.hooks.push(Rc::new(hook));
+        self
+    }
+
+    /// Why `inst` breaks the policy, if it does.
+    pub fn violation(&self, inst: &Amd64Instruction) -> Option<String> {
+        let mut prefixes: Vec<&str> = inst.mnemonic.as_str().split_whitespace().collect();
+        let mnemonic = prefixes.pop().unwrap_or_default();
+
+        if let Some(prefix) = prefixes.iter().find(|p| self.denied_prefixes.contains(**p)) {
+            return Some(format!("prefix `{}` is denied", prefix));
+        }
+        if self.denied.contains(mnemonic) {
+            return Some(format!("`{}` is denied", mnemonic));
+        }
+        if let Some(allowed) = &self.allowed {
+            if !allowed.contains(mnemonic) {
+                return Some(format!("`{}` is not on the allow-list", mnemonic));
+            }
+        }
+        if self.deny_privileged && required_cpl(mnemonic) == 0 {
+            return Some("privileged instructions are denied".to_string());
+        }
+        self.hooks.iter().find_map(|hook| hook(inst).err())
+    }
+
+    /// Checks every instruction in `expr`, in both arms of conditionals,
+    /// returning it unchanged if all pass. For use while building code.
+    pub fn admit(&self, expr: AsmExpr) -> Result<AsmExpr, PolicyError> {
+        let mut errors = Vec::new();
+        self.check_expr(&expr, None, &mut errors);
+        match errors.into_iter().next() {
+            Some(error) => Err(error),
+            None => Ok(expr),
+        }
+    }
+
+    fn check_expr(&self, expr: &AsmExpr, section: Option<&str>, errors: &mut Vec<PolicyError>) {
+        let error = |instruction: String, reason: String| PolicyError {
+            section: section.map(str::to_string),
+            instruction,
+            reason,
+        };
+        match expr {
+            AsmExpr::Instruction(inst) => {
+                if let Some(reason) = self.violation(inst) {
+                    errors.push(error(inst.to_string(), reason));
+                }
+            }
+            AsmExpr::Raw(text) if self.deny_raw => {
+                errors.push(error(
+                    text.trim().to_string(),
+                    "raw text is denied".to_string(),
+                ));
+            }
+            _ => {
+                for body in expr.bodies() {
+                    for inner in body {
+                        self.check_expr(inner, section, errors);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Reports every instruction of `program` that breaks `policy`, in both
+/// arms of each conditional.
+pub fn check(program: &Program, policy: &Policy) -> Result<(), Vec<PolicyError>> {
+    let mut errors = Vec::new();
+
+    for section in &program.sections {
+        for expr in section.body.iter() {
+            policy.check_expr(expr, Some(&section.name), &mut errors);
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
@@ -0,0 +1,174 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt,
+    hash::{Hash, Hasher},
+};
+
+use crate::{alignment::natural, AsmExpr, Data, Label, Section};
+
+/// Content-addressed pool of read-only constants.
+///
+/// Every constant is keyed by the bytes it assembles to, so asking for the
+/// same string or number twice hands back the same label and the value is
+/// emitted exactly once, in `.rodata`.
+#[derive(Clone, Default)]
+pub struct ConstPool {
+    entries: Vec<(Label, Data)>,
+    /// Keyed by assembled bytes and, for addresses and offsets, the labels
+    /// referenced.
+    index: HashMap<(Vec<u8>, Vec<String>), usize>,
+}
+
+impl ConstPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn string(&mut self, value: &str) -> Label {
+        self.insert(Data::Bytes(value.as_bytes().to_vec()))
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) -> Label {
+        self.insert(Data::Bytes(value.to_vec()))
+    }
+
+    pub fn f64(&mut self, value: f64) -> Label {
+        self.insert(Data::Float(value))
+    }
+
+    pub fn f32(&mut self, value: f32) -> Label {
+        self.insert(Data::Dword(value.to_bits()))
+    }
+
+    pub fn u64(&mut self, value: u64) -> Label {
+        self.insert(Data::UInt(value))
+    }
+
+    pub fn i64(&mut self, value: i64) -> Label {
+        self.insert(Data::Int(value))
+    }
+
+    /// Interns an arbitrary data item, returning the label of the existing
+    /// entry when one with identical bytes is already present.
+    pub fn insert(&mut self, data: Data) -> Label {
+        let key = (
+            data.to_bytes(),
+            data.labels().into_iter().map(|l| l.label.clone()).collect(),
+        );
+
+        if let Some(&i) = self.index.get(&key) {
+            return self.entries[i].0.clone();
+        }
+
+        let label = content_label(&key);
+        self.index.insert(key, self.entries.len());
+        self.entries.push((label.clone(), data));
+        label
+    }
+
+    /// Interns every constant of `other`. Labels are derived from content,
+    /// so references into either pool stay valid.
+    pub fn merge(&mut self, other: &ConstPool) {
+        for (_, data) in &other.entries {
+            self.insert(data.clone());
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Renders the pool as a standalone `.rodata` section.
+    ///
+    /// Entries are laid out by their natural alignment, largest first,
+    /// with padding wherever one's size would leave the next off its own,
+    /// so each is aligned as long as the section starts at a multiple of
+    /// the largest. Interning order does not matter to anything that
+    /// refers to an entry by its label.
+    pub fn to_section(&self) -> Section {
+        let mut entries: Vec<(u64, &Label, &Data)> = self
+            .entries
+            .iter()
+            .map(|(label, data)| {
+                let size = data.to_bytes().len() as u64;
+                (natural(data, size).max(1), label, data)
+            })
+            .collect();
+        entries.sort_by_key(|&(alignment, ..)| std::cmp::Reverse(alignment));
+
+        let mut body = Vec::with_capacity(self.entries.len() * 2);
+        let mut offset = 0u64;
+        for (alignment, label, data) in entries {
+            let padding = offset.next_multiple_of(alignment) - offset;
+            if padding > 0 {
+                body.push(AsmExpr::Data(Data::Fill {
+                    count: padding as usize,
+                    byte: 0,
+                }));
+            }
+            body.push(AsmExpr::Label(label.clone()));
+            body.push(AsmExpr::Data(data.clone()));
+            offset += padding + data.to_bytes().len() as u64;
+        }
+
+        Section::new("rodata", body)
+    }
+}
+
+impl fmt::Display for ConstPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_section())
+    }
+}
+
+fn content_label(key: &(Vec<u8>, Vec<String>)) -> Label {
+    let mut hasher = DefaultHasher::new();
+    key.0.hash(&mut hasher);
+    for label in &key.1 {
+        label.hash(&mut hasher);
+    }
+
+    Label {
+        label: format!("L_{:x}", hasher.finish()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encode::EncodeOptions, program::Program};
+
+    #[test]
+    fn entries_are_laid_out_aligned() {
+        let mut program = Program::default();
+        program.pool.string("abc");
+        let double = program.pool.f64(1.5);
+        program.pool.insert(Data::Tword(2.5));
+        program.pool.bytes(&[1]);
+        program.pool.insert(Data::Word(7));
+        program.pool.f32(0.5);
+        program.pool.i64(-1);
+
+        let misaligned = program.check_alignment(&EncodeOptions::new()).unwrap();
+        assert!(misaligned.is_empty(), "{:?}", misaligned);
+
+        let layout = program.layout(&EncodeOptions::new()).unwrap();
+        assert_eq!(layout.symbols[&double.label] % 8, 0);
+    }
+
+    #[test]
+    fn equal_constants_share_an_entry() {
+        let mut pool = ConstPool::new();
+        let a = pool.i64(5);
+        pool.string("five");
+        let b = pool.i64(5);
+        assert_eq!(a.label, b.label);
+        assert_eq!(pool.len(), 2);
+        // Bytes decide, not the type they were interned as.
+        assert_eq!(pool.u64(5).label, a.label);
+    }
+}
@@ -0,0 +1,170 @@
+//! Stack probes for frames larger than a page.
+//!
+//! A stack grows into the page below it only when that page is touched,
+//! and past a guard page the next access faults or, on a system that
+//! does not keep a gap below the stack, lands in unrelated memory. A
+//! frame allocated with one `sub rsp, N` larger than a page can step over
+//! the guard page entirely, so [`insert_stack_probes`] touches each page
+//! of such a frame from the top down before allocating it. Windows
+//! requires this of every function, which its C runtime does with
+//! `__chkstk`; Linux needs it under stack-clash protection, where GCC
+//! and Clang probe inline.
+//!
+//! The allocation itself stays a single `sub rsp, N` at the end of the
+//! probe, so [`check_stack_balance`](crate::stack::check_stack_balance)
+//! and frame compaction still recognize the frame.
+
+use std::collections::HashMap;
+
+use crate::{
+    consts::{R11, RAX, RSP},
+    dataflow::constant,
+    expr::ConstExpr,
+    instr::{call, cmp, jcc, mov, or, sub, xor, CondCode},
+    program::Program,
+    register::{Gpr, GprWidth},
+    startup::Startup,
+    AsmExpr, Extern, Label, Mem, Operand,
+};
+
+/// The smallest page on every supported target.
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Frames of up to this many pages are probed without a loop.
+const UNROLLED_PAGES: u64 = 4;
+
+/// How pages are probed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProbeStyle {
+    /// `mov rax, N` and a call to the C runtime's `__chkstk`, as MSVC
+    /// emits for Windows. `__chkstk` is declared extern.
+    Chkstk,
+    /// An `or` of zero into each page, as GCC's `-fstack-clash-protection`
+    /// emits, unrolled for small frames and in a loop over r11 otherwise.
+    /// The flags and r11, which neither C calling convention passes
+    /// arguments in, are clobbered.
+    Inline,
+}
+
+impl ProbeStyle {
+    /// `Chkstk` for programs started as Windows programs, `Inline`
+    /// otherwise.
+    pub fn for_program(program: &Program) -> Self {
+        match program.startup() {
+            Startup::Windows { .. } => ProbeStyle::Chkstk,
+            _ => ProbeStyle::Inline,
+        }
+    }
+}
+
+/// A frame allocation that was probed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StackProbe {
+    /// The non-local label the allocation follows.
+    pub function: String,
+    pub size: u64,
+}
+
+/// Probes every constant `sub rsp, N` in the program's text sections with
+/// `N` over a page, in both arms of conditionals, and returns those it
+/// probed. Allocations whose size is not a constant are left alone.
+pub fn insert_stack_probes(program: &mut Program, style: ProbeStyle) -> Vec<StackProbe> {
+    let mut probes = Vec::new();
+    let mut pass = Pass {
+        defines: program.define_map(),
+        style,
+        function: String::new(),
+        loops: 0,
+        probes: &mut probes,
+    };
+    for section in program
+        .sections
+        .iter_mut()
+        .filter(|s| s.name.starts_with("text"))
+    {
+        pass.body(section.body_mut());
+    }
+
+    let declared = program.externs.iter().any(|e| e.value == CHKSTK);
+    if style == ProbeStyle::Chkstk && !probes.is_empty() && !declared {
+        program.externs.push(Extern::new(CHKSTK));
+    }
+    probes
+}
+
+const CHKSTK: &str = "__chkstk";
+
+struct Pass<'a> {
+    defines: HashMap<String, ConstExpr>,
+    style: ProbeStyle,
+    function: String,
+    /// Probe loops emitted so far, to give each its own label.
+    loops: usize,
+    probes: &'a mut Vec<StackProbe>,
+}
+
+impl Pass<'_> {
+    fn body(&mut self, body: &mut [AsmExpr]) {
+        for expr in body.iter_mut() {
+            match expr {
+                AsmExpr::Label(label) if !label.label.starts_with('.') => {
+                    self.function = label.label.clone();
+                }
+                AsmExpr::Instruction(inst) => {
+                    let Some(size) = self.allocation(&inst.operands) else {
+                        continue;
+                    };
+                    if inst.mnemonic != "sub" || size <= PAGE_SIZE {
+                        continue;
+                    }
+                    let mut probe = self.probe(size);
+                    probe.push(expr.clone());
+                    *expr = AsmExpr::Block(probe);
+                    self.probes.push(StackProbe {
+                        function: self.function.clone(),
+                        size,
+                    });
+                }
+                _ => {
+                    for inner in expr.bodies_mut() {
+                        self.body(inner);
+                    }
+                }
+            }
+        }
+    }
+
+    /// `N` if `operands` are `rsp, N` with `N` a positive constant.
+    fn allocation(&self, operands: &[Operand]) -> Option<u64> {
+        match operands {
+            [Operand::Register(reg), size] if reg.gpr() == Some(Gpr::RSP) => {
+                u64::try_from(constant(size, &self.defines)?).ok()
+            }
+            _ => None,
+        }
+    }
+
+    /// Touches every whole page of `size` bytes below rsp, top down.
+    fn probe(&mut self, size: u64) -> Vec<AsmExpr> {
+        let pages = size / PAGE_SIZE;
+        let touch = |mem: Mem| or(mem.with_size(GprWidth::Qword), 0);
+        match self.style {
+            ProbeStyle::Chkstk => vec![mov(RAX, size), call(Label::plain(CHKSTK))],
+            ProbeStyle::Inline if pages <= UNROLLED_PAGES => (1..=pages)
+                .map(|page| touch(Mem::base(RSP).with_displacement(-((page * PAGE_SIZE) as i64))))
+                .collect(),
+            ProbeStyle::Inline => {
+                let top = Label::plain(&format!(".stack_probe{}", self.loops));
+                self.loops += 1;
+                vec![
+                    xor(R11, R11),
+                    AsmExpr::Label(top.clone()),
+                    sub(R11, PAGE_SIZE),
+                    touch(Mem::base(RSP).with_index(R11, 1)),
+                    cmp(R11, -((pages * PAGE_SIZE) as i64)),
+                    jcc(CondCode::Ne, top),
+                ]
+            }
+        }
+    }
+}
@@ -0,0 +1,793 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error, fmt, io,
+    sync::Arc,
+};
+
+use crate::{
+    alignment::{self, Misalignment},
+    blob::{self, Blob, BlobError},
+    cond::BuildConfig,
+    elf,
+    encode::{self, EncodeError, EncodeOptions, Image},
+    expr::{ConstExpr, ExprError},
+    gnu,
+    highlight::{self, ColorMode},
+    labels::{self, LabelError, LabelTable},
+    layout::Layout,
+    lint::{self, Diagnostic, Level, LintLevels},
+    macros::{MacroArg, MacroDef, MacroError},
+    metadata::{self, Metadata},
+    object::Object,
+    patch::Patcher,
+    pool::ConstPool,
+    rng::Rng,
+    startup::Startup,
+    stats::{self, ProgramStats},
+    symbol_words,
+    target::{Arch, Target},
+    AsmExpr, Defaults, EmitContext, Extern, Global, ImmediateValue, Operand, Section, SyntaxFlavor,
+};
+
+/// A complete assembly program: the symbolic constants and exported
+/// symbols, followed by every section in the order they were added, and
+/// finally the constant pool.
+#[derive(Clone)]
+pub struct Program {
+    pub globals: Vec<Global>,
+    pub externs: Vec<Extern>,
+    pub sections: Vec<Section>,
+    pub pool: ConstPool,
+    pub defines: Vec<(String, ConstExpr)>,
+    /// Settings that decide which arm of each conditional is emitted.
+    pub config: BuildConfig,
+    pub target: Target,
+    /// Pre-built code and data linked in when the program is encoded.
+    pub objects: Vec<Object>,
+    /// Whether the text output marks hinted branches `; likely` or
+    /// `; unlikely`.
+    pub hint_comments: bool,
+    /// Whether regions define symbols at their begin and end, in the text
+    /// output and the encoded image; see [`region_symbols`](crate::region_symbols).
+    pub region_symbols: bool,
+    /// Addressing and operand-size defaults for sections that set none.
+    pub defaults: Defaults,
+    /// Whether x86-64 code is written for NASM or the GNU assembler.
+    pub syntax: SyntaxFlavor,
+    /// Seeds every randomized pass; see [`Program::rng`].
+    pub seed: Option<u64>,
+    /// Where the program came from; see [`Program::provenance`].
+    pub metadata: Metadata,
+    /// Whether the text output starts with the provenance as comments.
+    pub metadata_comments: bool,
+    /// The level each lint reports at.
+    pub lint_levels: LintLevels,
+    /// Labels made through the program, for catching duplicates as they
+    /// are defined.
+    pub labels: LabelTable,
+    /// Macros the program calls by name with [`Program::call_macro`].
+    pub macros: Vec<MacroDef>,
+    /// Whether NASM output defines `macros` with `%macro` and writes their
+    /// calls as calls, rather than writing out each expansion.
+    pub macro_directives: bool,
+    /// Set with [`Program::with_startup`], which also adds what it needs.
+    startup: Startup,
+    /// The result of the last successful [`Program::encode`].
+    image: Option<Image>,
+}
+
+impl Program {
+    pub fn new(globals: Vec<Global>, sections: Vec<Section>) -> Self {
+        Program {
+            globals,
+            externs: Vec::new(),
+            sections,
+            pool: ConstPool::new(),
+            defines: Vec::new(),
+            config: BuildConfig::new(),
+            target: Target::x86_64(),
+            objects: Vec::new(),
+            hint_comments: false,
+            region_symbols: false,
+            defaults: Defaults::default(),
+            syntax: SyntaxFlavor::Nasm,
+            seed: None,
+            metadata: Metadata::new(),
+            metadata_comments: false,
+            lint_levels: LintLevels::new(),
+            labels: LabelTable::new(),
+            macros: Vec::new(),
+            macro_directives: false,
+            startup: Startup::default(),
+            image: None,
+        }
+    }
+
+    /// Names a constant usable in [`ImmediateValue::Expr`] operands.
+    /// Redefining a name replaces its previous value.
+    pub fn define(&mut self, name: &str, value: impl Into<ConstExpr>) {
+        let value = value.into();
+        match self.defines.iter_mut().find(|(n, _)| n == name) {
+            Some(slot) => slot.1 = value,
+            None => self.defines.push((name.to_string(), value)),
+        }
+    }
+
+    /// Exports `label`.
+    pub fn with_global(mut self, label: &str) -> Self {
+        self.globals.push(Global::new(label));
+        self
+    }
+
+    /// Declares `label` as defined outside the program.
+    pub fn with_extern(mut self, label: &str) -> Self {
+        self.externs.push(Extern::new(label));
+        self
+    }
+
+    /// Defines `def` for [`Program::call_macro`], replacing any macro of
+    /// the same name.
+    pub fn with_macro(mut self, def: MacroDef) -> Self {
+        self.macros.retain(|m| m.name != def.name);
+        self.macros.push(def);
+        self
+    }
+
+    /// A call of the program's macro `name`; see [`MacroDef::call`].
+    pub fn call_macro(&self, name: &str, args: &[MacroArg]) -> Result<AsmExpr, MacroError> {
+        self.macros
+            .iter()
+            .find(|m| m.name == name)
+            .ok_or_else(|| MacroError::Undefined(name.to_string()))?
+            .call(args)
+    }
+
+    /// Appends `section` after those already added.
+    pub fn with_section(mut self, section: Section) -> Self {
+        self.sections.push(section);
+        self
+    }
+
+    pub fn with_define(mut self, name: &str, value: impl Into<ConstExpr>) -> Self {
+        self.define(name, value);
+        self
+    }
+
+    pub fn with_target(mut self, target: Target) -> Self {
+        self.target = target;
+        self
+    }
+
+    pub fn with_defaults(mut self, defaults: Defaults) -> Self {
+        self.defaults = defaults;
+        self
+    }
+
+    pub fn with_syntax(mut self, syntax: SyntaxFlavor) -> Self {
+        self.syntax = syntax;
+        self
+    }
+
+    /// Reports lint `name` at `level`, or not at all for [`Level::Allow`].
+    pub fn with_lint_level(mut self, name: &str, level: Level) -> Self {
+        self.lint_levels.set(name, level);
+        self
+    }
+
+    /// Starts the program as `startup` says: exports its entry symbol and,
+    /// if it has glue for the target, appends that in a text section and
+    /// declares what it calls. Set the target first.
+    pub fn with_startup(mut self, startup: Startup) -> Self {
+        if !self.is_global(startup.entry()) {
+            self.globals.push(Global::new(startup.entry()));
+        }
+        for name in startup.externs() {
+            if !self.externs.iter().any(|ext| ext.value == *name) {
+                self.externs.push(Extern::new(name));
+            }
+        }
+        self.sections.extend(startup.glue(&self.target));
+        self.startup = startup;
+        self
+    }
+
+    /// How execution reaches the program; freestanding from `_start`
+    /// unless set otherwise.
+    pub fn startup(&self) -> &Startup {
+        &self.startup
+    }
+
+    /// The defaults `section` is emitted and checked under: its own if it
+    /// sets any, and the program's otherwise.
+    pub fn defaults_of(&self, section: &Section) -> Defaults {
+        section.defaults.unwrap_or(self.defaults)
+    }
+
+    /// The generator for the randomized pass `name`, one stream of the
+    /// program's seed. If the program has no seed yet, a fresh one is
+    /// chosen and recorded, so the output can be reproduced later.
+    pub fn rng(&mut self, name: &str) -> Rng {
+        let seed = *self.seed.get_or_insert_with(Rng::fresh_seed);
+        Rng::stream(seed, name)
+    }
+
+    /// The metadata entries, followed by the seed and target unless the
+    /// metadata already sets them.
+    pub fn provenance(&self) -> Vec<(String, String)> {
+        let mut entries = self.metadata.clone();
+        if let (Some(seed), None) = (self.seed, entries.get("seed")) {
+            entries.set("seed", seed);
+        }
+        if entries.get("target").is_none() {
+            entries.set("target", self.target.name());
+        }
+        entries.entries().to_vec()
+    }
+
+    /// The provenance as an ELF note section, for adding to the program.
+    pub fn metadata_note(&self) -> Section {
+        metadata::note_section(&self.provenance())
+    }
+
+    /// A copy to compare against or roll back to after running passes.
+    /// Section bodies are shared until either copy changes them, so taking
+    /// one costs little more than copying the section list, and a snapshot
+    /// can be handed to another thread:
+    ///
+    /// ```
+    /// use cataclysm::{instr, Program, Section};
+    ///
+    /// let program = Program::default().with_section(Section::new("text", vec![instr::ret()]));
+    /// let before = program.snapshot();
+    /// let text = std::thread::spawn(move || before.to_string()).join().unwrap();
+    /// assert_eq!(text, program.to_string());
+    /// ```
+    pub fn snapshot(&self) -> Program {
+        self.clone()
+    }
+
+    /// The sections that are new or have been written to since `before`
+    /// was taken with [`Program::snapshot`]. A section a pass opened for
+    /// writing counts even if the pass left it as it was.
+    pub fn changed_sections(&self, before: &Program) -> Vec<&str> {
+        self.sections
+            .iter()
+            .filter(|section| {
+                !before
+                    .sections
+                    .iter()
+                    .any(|old| old.name == section.name && Arc::ptr_eq(&old.body, &section.body))
+            })
+            .map(|section| section.name.as_str())
+            .collect()
+    }
+
+    /// Evaluates a defined constant.
+    pub fn constant(&self, name: &str) -> Result<i64, ExprError> {
+        ConstExpr::sym(name).eval(&self.define_map())
+    }
+
+    /// Folds every expression operand to a literal, so the output no longer
+    /// depends on the emitted `%define` lines. Returns the number of
+    /// operands rewritten.
+    pub fn resolve_defines(&mut self) -> Result<usize, ExprError> {
+        let defines = self.define_map();
+        let mut count = 0;
+
+        for section in self.sections.iter_mut() {
+            count += resolve_block(section.body_mut(), &defines)?;
+        }
+
+        Ok(count)
+    }
+
+    /// Replaces every conditional with the arm selected by the program's
+    /// configuration.
+    pub fn resolve_conditions(&mut self) {
+        for section in self.sections.iter_mut() {
+            AsmExpr::resolve_conditions(section.body_mut(), &self.config);
+        }
+    }
+
+    /// Every symbol defined by the program's sections: labels and `equ`
+    /// constants. NASM local labels (`.name`) are scoped to their parent
+    /// label and are not included.
+    pub fn defined_symbols(&self) -> HashSet<String> {
+        let mut out = HashSet::new();
+        for section in &self.sections {
+            collect_definitions(&section.body, &mut out);
+        }
+        out
+    }
+
+    /// Stitches `other` into this program.
+    ///
+    /// Sections are appended to the first section of the same name (and
+    /// byte order), exported symbols are combined, and externs that either
+    /// program defines are dropped. With a `prefix`, every label `other`
+    /// defines without exporting it is renamed `prefix` + name first, so
+    /// independently generated modules cannot collide on internal labels.
+    /// Macros and lint levels are combined the same way as constants: one
+    /// defined by both programs must be defined the same, and a lint both
+    /// set must be set to the same level. Metadata keys `self` lacks are
+    /// taken from `other`, and `self`'s value wins where both have one. A
+    /// startup set by only one program is kept; both setting different
+    /// ones is an error.
+    /// Any symbol still defined by both programs, or any of the conflicts
+    /// above, is an error and leaves `self` untouched. The build
+    /// configuration, target and output settings of `self` are kept.
+    pub fn merge(&mut self, mut other: Program, prefix: Option<&str>) -> Result<(), MergeError> {
+        let mut other_defs = other.defined_symbols();
+
+        if let Some(prefix) = prefix {
+            let exported: HashSet<&str> = other.globals.iter().map(|g| g.value.as_str()).collect();
+            let local: HashSet<String> = other_defs
+                .iter()
+                .filter(|name| !exported.contains(name.as_str()))
+                .cloned()
+                .collect();
+
+            let rename = |name: &str| local.contains(name).then(|| format!("{}{}", prefix, name));
+            for section in other.sections.iter_mut() {
+                AsmExpr::rename_labels(section.body_mut(), &rename);
+            }
+            other_defs = other.defined_symbols();
+        }
+
+        let self_defs = self.defined_symbols();
+        if let Some(name) = self_defs.intersection(&other_defs).min() {
+            return Err(MergeError::DuplicateSymbol(name.clone()));
+        }
+
+        for (name, value) in &other.defines {
+            if let Some((_, existing)) = self.defines.iter().find(|(n, _)| n == name) {
+                if existing != value {
+                    return Err(MergeError::ConflictingDefine(name.clone()));
+                }
+            }
+        }
+
+        for def in &other.macros {
+            if let Some(existing) = self.macros.iter().find(|m| m.name == def.name) {
+                if !same_macro(existing, def) {
+                    return Err(MergeError::ConflictingMacro(def.name.clone()));
+                }
+            }
+        }
+
+        for (lint, level) in other.lint_levels.entries() {
+            if let Some((_, existing)) = self.lint_levels.entries().iter().find(|(n, _)| n == lint)
+            {
+                if existing != level {
+                    return Err(MergeError::ConflictingLintLevel(lint.clone()));
+                }
+            }
+        }
+
+        let default = Startup::default();
+        let startup = match (&self.startup, other.startup) {
+            (_, theirs) if theirs == default => None,
+            (ours, theirs) if *ours == default || *ours == theirs => Some(theirs),
+            _ => return Err(MergeError::ConflictingStartup),
+        };
+
+        for (name, value) in other.defines {
+            self.define(&name, value);
+        }
+
+        for def in other.macros {
+            if !self.macros.iter().any(|m| m.name == def.name) {
+                self.macros.push(def);
+            }
+        }
+
+        for (lint, level) in other.lint_levels.entries() {
+            self.lint_levels.set(lint, *level);
+        }
+
+        for (key, value) in other.metadata.entries() {
+            if self.metadata.get(key).is_none() {
+                self.metadata.set(key, value);
+            }
+        }
+
+        if let Some(startup) = startup {
+            self.startup = startup;
+        }
+
+        for global in other.globals {
+            if !self.is_global(&global.value) {
+                self.globals.push(global);
+            }
+        }
+
+        self.pool.merge(&other.pool);
+        self.objects.extend(other.objects);
+
+        let inherited = other.defaults;
+        for mut section in other.sections {
+            // Keep the defaults the section had in `other`.
+            let defaults = section.defaults.unwrap_or(inherited);
+            section.defaults = (defaults != self.defaults).then_some(defaults);
+            let target = self.sections.iter_mut().find(|s| {
+                s.name == section.name
+                    && s.endian == section.endian
+                    && s.defaults == section.defaults
+            });
+            match target {
+                Some(target) => target.body_mut().extend(Arc::unwrap_or_clone(section.body)),
+                None => self.sections.push(section),
+            }
+        }
+
+        let defined: HashSet<String> = self_defs.into_iter().chain(other_defs).collect();
+        let mut externs = std::mem::take(&mut self.externs);
+        externs.extend(other.externs);
+        for ext in externs {
+            let seen = self.externs.iter().any(|e| e.value == ext.value);
+            if !seen && !defined.contains(&ext.value) {
+                self.externs.push(ext);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn define_map(&self) -> HashMap<String, ConstExpr> {
+        self.defines.iter().cloned().collect()
+    }
+
+    /// Labels defined twice and uses of undefined symbols; see
+    /// [`labels::check`].
+    pub fn check_labels(&self) -> Vec<LabelError> {
+        labels::check(self)
+    }
+
+    /// The program as text, as its `Display` writes it, if every symbol it
+    /// uses is defined once.
+    pub fn render(&self) -> Result<String, Vec<LabelError>> {
+        let errors = self.check_labels();
+        if errors.is_empty() {
+            Ok(self.to_string())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Instruction, label and data counts for the program as emitted.
+    pub fn stats(&self) -> ProgramStats {
+        stats::collect(self)
+    }
+
+    /// Findings of the built-in lints at the levels in
+    /// [`Program::lint_levels`], in program order.
+    pub fn lint(&self) -> Vec<Diagnostic> {
+        lint::run(self, lint::BUILTIN)
+    }
+
+    /// Links `object` into the program when it is encoded. Its global
+    /// symbols can be called or referenced like labels.
+    pub fn link(&mut self, object: Object) {
+        self.objects.push(object);
+    }
+
+    /// Encodes the program to machine code and keeps the result, so that
+    /// addresses can be looked up until the next call.
+    pub fn encode(&mut self, options: &EncodeOptions) -> Result<&Image, Vec<EncodeError>> {
+        self.image = None;
+        let image = encode::encode(self, options)?;
+        Ok(self.image.insert(image))
+    }
+
+    /// The program as an ELF64 relocatable object, ready for `ld`.
+    pub fn to_object(&self) -> Result<Vec<u8>, Vec<EncodeError>> {
+        elf::write(self)
+    }
+
+    /// Data items placed off their natural alignment; see
+    /// [`alignment::check`].
+    pub fn check_alignment(
+        &self,
+        options: &EncodeOptions,
+    ) -> Result<Vec<Misalignment>, Vec<EncodeError>> {
+        alignment::check(self, options)
+    }
+
+    /// Where every label, instruction and data item would be placed by
+    /// [`Program::encode`].
+    pub fn layout(&self, options: &EncodeOptions) -> Result<Layout, Vec<EncodeError>> {
+        encode::layout(self, options)
+    }
+
+    /// The program as one position-independent buffer entered at `entry`.
+    pub fn blob(&self, entry: &str, options: &EncodeOptions) -> Result<Blob, Vec<BlobError>> {
+        blob::blob(self, entry, options)
+    }
+
+    /// The program encoded and open for patching.
+    pub fn patcher(&self, options: &EncodeOptions) -> Result<Patcher, Vec<EncodeError>> {
+        Patcher::new(self, options)
+    }
+
+    /// The last successful encoding.
+    pub fn image(&self) -> Option<&Image> {
+        self.image.as_ref()
+    }
+
+    /// Where `label` was placed by the last encoding. Local labels are
+    /// looked up by their qualified name, such as `func.loop`.
+    pub fn address_of(&self, label: &str) -> Option<u64> {
+        self.image.as_ref()?.symbol(label)
+    }
+
+    /// The address of `section` in the last encoding.
+    pub fn section_base(&self, section: &str) -> Option<u64> {
+        Some(self.image.as_ref()?.section(section)?.address)
+    }
+
+    /// The encoded size of `section` in bytes.
+    pub fn section_size(&self, section: &str) -> Option<u64> {
+        Some(self.image.as_ref()?.section(section)?.bytes.len() as u64)
+    }
+
+    /// The emitted assembly as a highlighted, cross-linked HTML page.
+    pub fn to_html(&self) -> String {
+        highlight::html(&self.to_string())
+    }
+
+    /// Prints the program to stdout, colourised according to `color`.
+    pub fn print(&self, color: ColorMode) {
+        let stdout = io::stdout();
+        if color.enabled_for(&stdout) {
+            print!("{}", highlight::ansi(&self.to_string()));
+        } else {
+            print!("{}", self);
+        }
+    }
+
+    pub fn is_global(&self, label: &str) -> bool {
+        self.globals.iter().any(|g| g.value == label)
+    }
+}
+
+impl Default for Program {
+    /// An empty program, to be filled in with the `with_*` methods.
+    fn default() -> Self {
+        Program::new(Vec::new(), Vec::new())
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.target.arch != Arch::Amd64 || self.syntax == SyntaxFlavor::Gas {
+            return gnu::fmt_program(self, f);
+        }
+
+        if self.metadata_comments {
+            write!(f, "{}", metadata::comments(&self.provenance()))?;
+        }
+
+        for (name, value) in &self.defines {
+            writeln!(f, "%define {} {}", name, value)?;
+        }
+
+        for global in &self.globals {
+            writeln!(f, "{}", global)?;
+        }
+
+        for ext in &self.externs {
+            writeln!(f, "{}", ext)?;
+        }
+
+        if self.defaults.rel {
+            writeln!(f, "default rel")?;
+        }
+
+        let mut ctx = EmitContext {
+            config: Some(&self.config),
+            pointer_width: self.target.abi.pointer_width(),
+            hint_comments: self.hint_comments,
+            region_symbols: self.region_symbols,
+            defaults: self.defaults,
+            ..EmitContext::default()
+        };
+        if self.macro_directives {
+            for def in &self.macros {
+                writeln!(f, "%macro {} {}", def.name, def.params.len())?;
+                for line in def.directive_body() {
+                    line.fmt_in(f, &ctx)?;
+                    writeln!(f)?;
+                }
+                writeln!(f, "%endmacro")?;
+            }
+            ctx.macros = &self.macros;
+        }
+        for section in &self.sections {
+            section.fmt_with(f, &ctx)?;
+            writeln!(f)?;
+        }
+
+        if !self.pool.is_empty() {
+            writeln!(f, "{}", self.pool)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn resolve_block(
+    body: &mut [AsmExpr],
+    defines: &HashMap<String, ConstExpr>,
+) -> Result<usize, ExprError> {
+    let mut count = 0;
+
+    for expr in body.iter_mut() {
+        match expr {
+            AsmExpr::Instruction(inst) => {
+                for operand in inst.operands.iter_mut() {
+                    let Operand::Immediate(imm) = operand else {
+                        continue;
+                    };
+                    if let ImmediateValue::Expr(e) = imm {
+                        let value = e.eval(defines)?;
+                        *imm = ImmediateValue::I64(value);
+                        count += 1;
+                    }
+                }
+            }
+            _ => {
+                for inner in expr.bodies_mut() {
+                    count += resolve_block(inner, defines)?;
+                }
+            }
+        }
+    }
+
+    Ok(count)
+}
+
+fn collect_definitions(body: &[AsmExpr], out: &mut HashSet<String>) {
+    for expr in body {
+        match expr {
+            AsmExpr::Label(label) if !label.label.starts_with('.') => {
+                out.insert(label.label.clone());
+            }
+            AsmExpr::Raw(text) => {
+                let mut words = symbol_words(text);
+                if let (Some(name), Some("equ")) = (words.next(), words.next()) {
+                    out.insert(name.to_string());
+                }
+            }
+            _ => {
+                for inner in expr.bodies() {
+                    collect_definitions(inner, out);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeError {
+    /// Both programs define the symbol.
+    DuplicateSymbol(String),
+    /// Both programs define the constant, with different values.
+    ConflictingDefine(String),
+    /// Both programs define the macro, differently.
+    ConflictingMacro(String),
+    /// Both programs set the lint, to different levels.
+    ConflictingLintLevel(String),
+    /// The programs start in different ways.
+    ConflictingStartup,
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MergeError::DuplicateSymbol(name) => {
+                write!(f, "symbol `{}` is defined by both programs", name)
+            }
+            MergeError::ConflictingDefine(name) => {
+                write!(
+                    f,
+                    "constant `{}` is defined differently by both programs",
+                    name
+                )
+            }
+            MergeError::ConflictingMacro(name) => {
+                write!(
+                    f,
+                    "macro `{}` is defined differently by both programs",
+                    name
+                )
+            }
+            MergeError::ConflictingLintLevel(name) => {
+                write!(
+                    f,
+                    "lint `{}` is set to different levels by both programs",
+                    name
+                )
+            }
+            MergeError::ConflictingStartup => write!(f, "the programs start differently"),
+        }
+    }
+}
+
+impl error::Error for MergeError {}
+
+/// Whether `a` and `b` take the same parameters and expand to the same
+/// code.
+fn same_macro(a: &MacroDef, b: &MacroDef) -> bool {
+    let text = |body: &[AsmExpr]| body.iter().map(|expr| expr.to_string()).collect::<Vec<_>>();
+    a.params == b.params && a.locals == b.locals && text(&a.body) == text(&b.body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{consts::RDI, instr, lint::Level, Label};
+
+    fn module(name: &str) -> Program {
+        Program::default()
+            .with_global(name)
+            .with_section(Section::new(
+                "text",
+                vec![AsmExpr::Label(Label::plain(name)), instr::ret()],
+            ))
+    }
+
+    fn exit(code: i64) -> MacroDef {
+        MacroDef::new("exit", &[], &[], vec![instr::mov(RDI, code)])
+    }
+
+    #[test]
+    fn settings_of_both_programs_are_kept() {
+        let mut first = module("f").with_lint_level("stack-balance", Level::Deny);
+        first.metadata.set("source", "first.bf");
+        let mut second = module("g")
+            .with_macro(exit(0))
+            .with_lint_level("abi", Level::Allow)
+            .with_startup(Startup::Hosted);
+        second.metadata.set("source", "second.bf");
+        second.metadata.set("author", "someone");
+
+        first.merge(second, None).unwrap();
+        assert_eq!(first.macros.len(), 1);
+        assert_eq!(first.lint_levels.level("stack-balance"), Level::Deny);
+        assert_eq!(first.lint_levels.level("abi"), Level::Allow);
+        assert_eq!(first.metadata.get("source"), Some("first.bf"));
+        assert_eq!(first.metadata.get("author"), Some("someone"));
+        assert_eq!(first.startup(), &Startup::Hosted);
+    }
+
+    #[test]
+    fn settings_that_disagree_are_conflicts() {
+        let mut first = module("f").with_macro(exit(0));
+        let same = module("g").with_macro(exit(0));
+        first.merge(same, None).unwrap();
+        assert_eq!(first.macros.len(), 1);
+
+        let other = module("h").with_macro(exit(1));
+        assert_eq!(
+            first.merge(other, None),
+            Err(MergeError::ConflictingMacro("exit".to_string()))
+        );
+
+        let mut first = module("f").with_lint_level("abi", Level::Deny);
+        let other = module("g").with_lint_level("abi", Level::Allow);
+        assert_eq!(
+            first.merge(other, None),
+            Err(MergeError::ConflictingLintLevel("abi".to_string()))
+        );
+        assert_eq!(first.sections[0].body.len(), 2);
+
+        let mut first = module("f").with_startup(Startup::Hosted);
+        let other = module("g").with_startup(Startup::Freestanding {
+            entry: "g".to_string(),
+        });
+        assert_eq!(
+            first.merge(other, None),
+            Err(MergeError::ConflictingStartup)
+        );
+    }
+}
@@ -0,0 +1,148 @@
+//! Which symbols refer to which, for spotting unexpected coupling in large
+//! generated programs.
+//!
+//! Each non-local label owns the code or data that follows it, up to the
+//! next one; a reference made anywhere in that range is an edge from the
+//! owner. Local (`.`-prefixed) labels belong to their owner and do not get
+//! nodes of their own.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+};
+
+use crate::{program::Program, AsmExpr};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SymbolKind {
+    /// Defined in a text section.
+    Code,
+    /// Defined in any other section, including the constant pool.
+    Data,
+    /// Declared `extern`.
+    External,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RefGraph {
+    pub symbols: BTreeMap<String, SymbolKind>,
+    /// `(from, to)` pairs, one per distinct reference.
+    pub edges: BTreeSet<(String, String)>,
+}
+
+impl RefGraph {
+    /// Symbols `name` refers to.
+    pub fn references<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.edges
+            .iter()
+            .filter(move |(from, _)| from == name)
+            .map(|(_, to)| to.as_str())
+    }
+
+    /// Symbols that refer to `name`.
+    pub fn referrers<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.edges
+            .iter()
+            .filter(move |(_, to)| to == name)
+            .map(|(from, _)| from.as_str())
+    }
+
+    /// The graph in Graphviz DOT syntax, code as boxes, data as ellipses
+    /// and externals dashed.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph references {\n");
+        for (name, kind) in &self.symbols {
+            let style = match kind {
+                SymbolKind::Code => "shape=box",
+                SymbolKind::Data => "shape=ellipse",
+                SymbolKind::External => "shape=box, style=dashed",
+            };
+            writeln!(dot, "  {} [{}];", quote(name), style).unwrap();
+        }
+        for (from, to) in &self.edges {
+            writeln!(dot, "  {} -> {};", quote(from), quote(to)).unwrap();
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Builds the reference graph of `program`, counting both arms of
+/// conditionals.
+pub fn reference_graph(program: &Program) -> RefGraph {
+    let mut graph = RefGraph::default();
+    let pool = program.pool.to_section();
+    let sections: Vec<_> = program.sections.iter().chain([&pool]).collect();
+
+    for section in &sections {
+        let kind = if section.name.starts_with("text") {
+            SymbolKind::Code
+        } else {
+            SymbolKind::Data
+        };
+        collect_symbols(&section.body, kind, &mut graph);
+    }
+    for ext in &program.externs {
+        graph
+            .symbols
+            .entry(ext.value.clone())
+            .or_insert(SymbolKind::External);
+    }
+
+    for section in &sections {
+        let mut owner = None;
+        collect_edges(&section.body, &mut owner, &mut graph);
+    }
+
+    graph
+}
+
+fn collect_symbols(body: &[AsmExpr], kind: SymbolKind, graph: &mut RefGraph) {
+    for expr in body {
+        match expr {
+            AsmExpr::Label(label) if !label.label.starts_with('.') => {
+                graph.symbols.insert(label.label.clone(), kind);
+            }
+            _ => {
+                for inner in expr.bodies() {
+                    collect_symbols(inner, kind, graph);
+                }
+            }
+        }
+    }
+}
+
+fn collect_edges(body: &[AsmExpr], owner: &mut Option<String>, graph: &mut RefGraph) {
+    for expr in body {
+        match expr {
+            AsmExpr::Label(label) if !label.label.starts_with('.') => {
+                *owner = Some(label.label.clone());
+            }
+            AsmExpr::Label(_) => {}
+            AsmExpr::Block(_)
+            | AsmExpr::Region { .. }
+            | AsmExpr::Macro { .. }
+            | AsmExpr::If { .. } => {
+                for inner in expr.bodies() {
+                    collect_edges(inner, owner, graph);
+                }
+            }
+            _ => {
+                let Some(from) = owner.as_ref() else {
+                    continue;
+                };
+                let mut edges = Vec::new();
+                AsmExpr::visit_references(std::slice::from_ref(expr), &mut |name| {
+                    if name != from && graph.symbols.contains_key(name) {
+                        edges.push((from.clone(), name.to_string()));
+                    }
+                });
+                graph.edges.extend(edges);
+            }
+        }
+    }
+}
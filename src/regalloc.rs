@@ -0,0 +1,1066 @@
+//! Giving virtual registers general-purpose ones.
+//!
+//! A frontend can write a function over as many [`VReg`]s as it likes,
+//! alongside the physical registers its conventions fix, such as the
+//! arguments in rdi and rsi and the result in rax. [`allocate_function`]
+//! then gives each virtual register a general-purpose register by linear
+//! scan: a virtual register is live over one interval of the function's
+//! instructions, from its first definition to its last use and stretched
+//! over any loop it is live around, and the intervals are given registers
+//! in order of their starts. A register goes only to an interval it is
+//! free throughout, so one holding an argument still to be read, one a
+//! `call` clobbers and one kept for the caller are passed over;
+//! callee-saved registers are used only where the function has saved them
+//! itself. A `mov` from or to a register the interval could have is
+//! given that register, and disappears.
+//!
+//! Operands an instruction fixes, such as a shift count in cl or the
+//! dividend in rax, are written as the physical register, and moved to and
+//! from virtual ones; which physical registers are live is judged as
+//! [`dataflow`](crate::dataflow) judges it, with a `call` reading all six
+//! System V argument registers.
+//!
+//! ```
+//! use cataclysm::{consts::{RAX, RDI, RSI}, instr, regalloc::{allocate_function, VRegs}, AsmExpr, Label};
+//!
+//! let mut vregs = VRegs::new();
+//! let (sum, product) = (vregs.fresh(), vregs.fresh());
+//! let mut body = vec![
+//!     AsmExpr::Label(Label::plain("f")),
+//!     instr::mov(sum, RDI),
+//!     instr::add(sum, RSI),
+//!     instr::mov(product, sum),
+//!     instr::imul(product, RSI),
+//!     instr::mov(RAX, product),
+//!     instr::ret(),
+//! ];
+//! let allocation = allocate_function(&mut body).unwrap();
+//! assert!(allocation.spilled.is_empty());
+//! let lines: Vec<String> = body.iter().map(|expr| expr.to_string().trim().replace('\t', " ")).collect();
+//! assert_eq!(lines, ["f:", "add rdi, rsi", "imul rdi, rsi", "mov rax, rdi", "ret"]);
+//! ```
+//!
+//! When the registers run out, the interval ending last is spilled to an
+//! 8-byte stack slot: each instruction using it loads it into a fresh
+//! virtual register first and stores that back after, and allocation
+//! starts over. The slots are reserved below the return address on entry
+//! and released before each return and tail jump, so every rsp-relative
+//! operand of the function moves up by the size of the area. That needs
+//! the function to move rsp only by pushing, popping and adding or
+//! subtracting constants, whatever path reaches an instruction.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    error, fmt,
+};
+
+use crate::{
+    dataflow::{constant, effects, implicit, operand_registers, split_prefix, step_back, RegSet},
+    instr::{lea, mov},
+    program::Program,
+    qualify_label,
+    register::{Gpr, GprWidth, VReg},
+    Amd64Instruction, Amd64Register, AsmExpr, ImmediateValue, Label, Mem, Operand,
+};
+
+impl From<VReg> for Amd64Register {
+    fn from(reg: VReg) -> Self {
+        reg.at(GprWidth::Qword)
+    }
+}
+
+impl From<VReg> for Operand {
+    fn from(reg: VReg) -> Self {
+        Operand::Register(reg.into())
+    }
+}
+
+/// Hands out virtual registers, numbered from zero.
+#[derive(Clone, Debug, Default)]
+pub struct VRegs {
+    next: u32,
+}
+
+impl VRegs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn fresh(&mut self) -> VReg {
+        let reg = VReg::new(self.next);
+        self.next += 1;
+        reg
+    }
+}
+
+/// The registers given out by default, caller-saved ones first.
+const REGISTERS: [Gpr; 15] = [
+    Gpr::RAX,
+    Gpr::RCX,
+    Gpr::RDX,
+    Gpr::RSI,
+    Gpr::RDI,
+    Gpr::R8,
+    Gpr::R9,
+    Gpr::R10,
+    Gpr::R11,
+    Gpr::RBX,
+    Gpr::RBP,
+    Gpr::R12,
+    Gpr::R13,
+    Gpr::R14,
+    Gpr::R15,
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AllocError {
+    /// An item in a function with virtual registers that is neither a
+    /// label nor an instruction, such as a conditional or a raw line,
+    /// which registers cannot be followed through.
+    Unsupported(String),
+    /// The instruction needs more registers at once than are free around
+    /// it.
+    Pressure(String),
+    /// Spill slots are needed, but the instruction moves rsp by other than
+    /// a constant, is reached with rsp at different depths, or leaves the
+    /// function where the slots cannot be released.
+    Stack(String),
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AllocError::Unsupported(item) => {
+                write!(f, "registers cannot be allocated across `{}`", item)
+            }
+            AllocError::Pressure(instruction) => {
+                write!(f, "`{}` needs more registers than are free", instruction)
+            }
+            AllocError::Stack(instruction) => {
+                write!(f, "spill slots cannot be kept across `{}`", instruction)
+            }
+        }
+    }
+}
+
+impl error::Error for AllocError {}
+
+/// Where one function's virtual registers went.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Allocation {
+    /// The function's first label, or nothing if it has none.
+    pub function: String,
+    /// The register each virtual register kept throughout was given.
+    pub registers: BTreeMap<VReg, Gpr>,
+    /// The others, each kept in an 8-byte stack slot, by the slot's offset
+    /// from the bottom of the spill area.
+    pub spilled: BTreeMap<VReg, u32>,
+    /// The size of the spill area, a multiple of 16 so that the stack
+    /// stays aligned as the function had it.
+    pub frame_size: u32,
+}
+
+/// A linear-scan register allocator.
+#[derive(Clone, Debug)]
+pub struct Allocator {
+    registers: Vec<Gpr>,
+}
+
+impl Default for Allocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Allocator {
+    /// Allocates from every general-purpose register but rsp, the
+    /// caller-saved ones first.
+    pub fn new() -> Self {
+        Allocator {
+            registers: REGISTERS.to_vec(),
+        }
+    }
+
+    /// Allocates only from `registers`, in order of preference. rsp is
+    /// never given out.
+    pub fn with_registers(mut self, registers: &[Gpr]) -> Self {
+        self.registers = registers
+            .iter()
+            .copied()
+            .filter(|&reg| reg != Gpr::RSP)
+            .collect();
+        self
+    }
+
+    /// Allocates each function of the program's text sections that uses
+    /// virtual registers, taking every non-local label to start a
+    /// function, and returns where each function's registers went.
+    pub fn allocate(&self, program: &mut Program) -> Result<Vec<Allocation>, AllocError> {
+        let mut allocations = Vec::new();
+        for section in program
+            .sections
+            .iter_mut()
+            .filter(|s| s.name.starts_with("text"))
+        {
+            let body = section.body_mut();
+            let mut functions: Vec<Vec<AsmExpr>> = vec![Vec::new()];
+            for expr in body.iter() {
+                let starts = matches!(expr, AsmExpr::Label(label) if !label.label.starts_with('.'));
+                if starts && !functions.last().unwrap().is_empty() {
+                    functions.push(Vec::new());
+                }
+                functions.last_mut().unwrap().push(expr.clone());
+            }
+            let mut allocated = Vec::new();
+            for mut function in functions {
+                if has_virtual(&function) {
+                    allocations.push(self.allocate_function(&mut function)?);
+                }
+                allocated.extend(function);
+            }
+            *body = allocated;
+        }
+        Ok(allocations)
+    }
+
+    /// Gives the virtual registers of one function's `body` registers,
+    /// flattening any blocks in it, and returns where they went. A body
+    /// without virtual registers is left as it is.
+    pub fn allocate_function(&self, body: &mut Vec<AsmExpr>) -> Result<Allocation, AllocError> {
+        let function = body
+            .iter()
+            .find_map(|expr| match expr {
+                AsmExpr::Label(label) => Some(label.label.clone()),
+                _ => None,
+            })
+            .unwrap_or_default();
+        let mut allocation = Allocation {
+            function,
+            registers: BTreeMap::new(),
+            spilled: BTreeMap::new(),
+            frame_size: 0,
+        };
+        if !has_virtual(body) {
+            return Ok(allocation);
+        }
+
+        let mut lines = Vec::new();
+        flatten(body, &mut lines)?;
+        let originals: BTreeSet<VReg> = lines
+            .iter()
+            .filter_map(Line::instruction)
+            .flat_map(virtuals)
+            .collect();
+        let mut next = originals.last().map_or(0, |reg| reg.index() + 1);
+        let mut temps = BTreeSet::new();
+        let (assigned, function) = loop {
+            let function = Function::analyse(&lines)?;
+            let (assigned, spilled) = self.scan(&function, &temps)?;
+            if spilled.is_empty() {
+                break (assigned, function);
+            }
+            let depths = function.depths()?;
+            let mut slots = BTreeMap::new();
+            for reg in spilled {
+                let slot = 8 * allocation.spilled.len() as u32;
+                allocation.spilled.insert(reg, slot);
+                slots.insert(reg, slot);
+            }
+            let mut fresh = || {
+                let reg = VReg::new(next);
+                next += 1;
+                temps.insert(reg);
+                reg
+            };
+            lines = spill(lines, &function, &depths, &slots, &mut fresh)?;
+        };
+
+        let frame = (8 * allocation.spilled.len() as u32).next_multiple_of(16);
+        if frame > 0 {
+            lines = reserve(lines, &function, frame)?;
+        }
+        body.clear();
+        for line in lines {
+            match line {
+                Line::Label(label) => body.push(AsmExpr::Label(label)),
+                Line::Instruction { mut inst, .. } => {
+                    if is_copy(&inst, &assigned) {
+                        continue;
+                    }
+                    for operand in &mut inst.operands {
+                        map_virtual(operand, |reg, width| physical(assigned[&reg], width));
+                    }
+                    body.push(AsmExpr::Instruction(inst));
+                }
+            }
+        }
+        allocation.registers = assigned
+            .into_iter()
+            .filter(|(reg, _)| originals.contains(reg))
+            .collect();
+        allocation.frame_size = frame;
+        Ok(allocation)
+    }
+
+    /// One pass of linear scan, giving out registers in order of interval
+    /// start and returning those given and the virtual registers to spill.
+    /// Only `temps`, which live across a single instruction, are never
+    /// spilled.
+    fn scan(
+        &self,
+        function: &Function,
+        temps: &BTreeSet<VReg>,
+    ) -> Result<(BTreeMap<VReg, Gpr>, Vec<VReg>), AllocError> {
+        let mut assigned = BTreeMap::new();
+        let mut spilled = Vec::new();
+        let mut active: Vec<(Interval, Gpr)> = Vec::new();
+        for current in function.intervals() {
+            active.retain(|(interval, _)| interval.end >= current.start);
+            // An interval ending where the current one starts, neither
+            // holding its value across the instruction between, may share.
+            let conflicts = |interval: &Interval| {
+                interval.end > current.start || interval.held_after || current.held_before
+            };
+            let busy = function.busy(&current);
+            let mut taken = busy;
+            for (interval, reg) in &active {
+                if conflicts(interval) {
+                    taken.insert(*reg);
+                }
+            }
+
+            let free: Vec<Gpr> = self
+                .registers
+                .iter()
+                .copied()
+                .filter(|&reg| !taken.contains(reg))
+                .collect();
+            let choice = function
+                .hint(&current, &assigned)
+                .filter(|reg| free.contains(reg))
+                .or(free.first().copied());
+            if let Some(reg) = choice {
+                assigned.insert(current.reg, reg);
+                active.push((current, reg));
+                continue;
+            }
+
+            // Freeing a register means spilling the one interval holding
+            // it; the one ending last frees it for longest.
+            let holders = |reg: Gpr| {
+                active
+                    .iter()
+                    .filter(|(interval, held)| *held == reg && conflicts(interval))
+                    .count()
+            };
+            let victim = active
+                .iter()
+                .enumerate()
+                .filter(|(_, (interval, reg))| {
+                    conflicts(interval)
+                        && !temps.contains(&interval.reg)
+                        && !busy.contains(*reg)
+                        && self.registers.contains(reg)
+                        && holders(*reg) == 1
+                })
+                .max_by_key(|(_, (interval, _))| interval.end)
+                .map(|(i, (interval, _))| (i, interval.end));
+            let spillable = !temps.contains(&current.reg);
+            match victim {
+                Some((i, end)) if end > current.end || !spillable => {
+                    let (interval, reg) = active.remove(i);
+                    assigned.remove(&interval.reg);
+                    spilled.push(interval.reg);
+                    assigned.insert(current.reg, reg);
+                    active.push((current, reg));
+                }
+                _ if spillable => spilled.push(current.reg),
+                _ => {
+                    let inst = &function.insts[current.start];
+                    return Err(AllocError::Pressure(text(inst)));
+                }
+            }
+        }
+        Ok((assigned, spilled))
+    }
+}
+
+/// Allocates the functions of `program`'s text sections from every
+/// general-purpose register but rsp.
+pub fn allocate(program: &mut Program) -> Result<Vec<Allocation>, AllocError> {
+    Allocator::new().allocate(program)
+}
+
+/// Allocates one function's `body` from every general-purpose register but
+/// rsp.
+pub fn allocate_function(body: &mut Vec<AsmExpr>) -> Result<Allocation, AllocError> {
+    Allocator::new().allocate_function(body)
+}
+
+enum Line {
+    Label(Label),
+    /// An instruction, `added` if it is spill code rather than the
+    /// function's own.
+    Instruction {
+        inst: Amd64Instruction,
+        added: bool,
+    },
+}
+
+impl Line {
+    fn instruction(&self) -> Option<&Amd64Instruction> {
+        match self {
+            Line::Instruction { inst, .. } => Some(inst),
+            Line::Label(_) => None,
+        }
+    }
+}
+
+fn flatten(body: &[AsmExpr], lines: &mut Vec<Line>) -> Result<(), AllocError> {
+    for expr in body {
+        match expr {
+            AsmExpr::Label(label) => lines.push(Line::Label(label.clone())),
+            AsmExpr::Instruction(inst) => lines.push(Line::Instruction {
+                inst: inst.clone(),
+                added: false,
+            }),
+            AsmExpr::Block(inner) => flatten(inner, lines)?,
+            _ => return Err(AllocError::Unsupported(expr.to_string().trim().to_string())),
+        }
+    }
+    Ok(())
+}
+
+fn has_virtual(body: &[AsmExpr]) -> bool {
+    body.iter().any(|expr| match expr {
+        AsmExpr::Instruction(inst) => virtuals(inst).next().is_some(),
+        _ => expr.bodies().any(|inner| has_virtual(inner)),
+    })
+}
+
+/// The virtual registers `inst` names, as operands or in addresses, with
+/// repeats.
+fn virtuals(inst: &Amd64Instruction) -> impl Iterator<Item = VReg> + '_ {
+    inst.operands
+        .iter()
+        .flat_map(|operand| match operand {
+            Operand::Register(reg) => vec![reg],
+            Operand::Memory(mem) => mem.registers().collect(),
+            _ => Vec::new(),
+        })
+        .filter_map(|reg| match reg {
+            Amd64Register::Virtual(reg, _) => Some(*reg),
+            _ => None,
+        })
+}
+
+/// Replaces every virtual register in `operand` by `map` of it and the
+/// width it is named at.
+fn map_virtual(operand: &mut Operand, map: impl Fn(VReg, GprWidth) -> Amd64Register) {
+    let apply = |reg: &mut Amd64Register| {
+        if let Amd64Register::Virtual(virt, width) = *reg {
+            *reg = map(virt, width);
+        }
+    };
+    match operand {
+        Operand::Register(reg) => apply(reg),
+        Operand::Memory(mem) => {
+            mem.base
+                .iter_mut()
+                .chain(mem.index.iter_mut())
+                .for_each(apply);
+        }
+        _ => {}
+    }
+}
+
+/// The low `width` of `gpr`.
+fn physical(gpr: Gpr, width: GprWidth) -> Amd64Register {
+    match width {
+        GprWidth::Qword => Amd64Register::GeneralPurpose(gpr),
+        _ => Amd64Register::Partial(gpr, width),
+    }
+}
+
+/// Whether `inst` moves a whole virtual register into the register it was
+/// itself given, or out of it, and so can go.
+fn is_copy(inst: &Amd64Instruction, assigned: &BTreeMap<VReg, Gpr>) -> bool {
+    let whole = |operand: &Operand| match operand {
+        Operand::Register(Amd64Register::Virtual(reg, GprWidth::Qword)) => {
+            Some((Some(assigned[reg]), true))
+        }
+        Operand::Register(reg) => Some((reg.gpr(), false)),
+        _ => None,
+    };
+    match (inst.mnemonic.as_str(), inst.operands.as_slice()) {
+        ("mov", [dst, src]) => match (whole(dst), whole(src)) {
+            (Some((Some(dst), dst_virtual)), Some((Some(src), src_virtual))) => {
+                dst == src && (dst_virtual || src_virtual)
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// How an instruction leaves the straight line.
+enum Control {
+    None,
+    Return,
+    /// A jump to a label, or elsewhere if `None`.
+    Jump(Option<String>),
+    /// A conditional jump, which may also fall through.
+    Branch(Option<String>),
+}
+
+fn control(inst: &Amd64Instruction, scope: &str) -> Control {
+    let target = || match inst.operands.first() {
+        Some(Operand::Immediate(ImmediateValue::Label(label))) => {
+            Some(qualify_label(scope, &label.label))
+        }
+        _ => None,
+    };
+    match split_prefix(inst.mnemonic.as_str()).1 {
+        "ret" => Control::Return,
+        "jmp" => Control::Jump(target()),
+        m if m.starts_with('j') || m.starts_with("loop") => Control::Branch(target()),
+        _ => Control::None,
+    }
+}
+
+/// Instructions `start..end` of a function, run one after another.
+struct Block {
+    start: usize,
+    end: usize,
+    successors: Vec<usize>,
+    /// Whether control may leave the function from the block other than by
+    /// returning.
+    exits: bool,
+    live_in: BTreeSet<VReg>,
+    live_out: BTreeSet<VReg>,
+}
+
+/// What one instruction does with registers.
+struct Info {
+    uses: Vec<VReg>,
+    defs: Vec<VReg>,
+    /// Physical registers live before and after it, and those it writes.
+    before: RegSet,
+    after: RegSet,
+    clobbers: RegSet,
+}
+
+/// The stretch of instructions over which a virtual register is kept.
+struct Interval {
+    reg: VReg,
+    start: usize,
+    end: usize,
+    /// Whether the register holds a value before the instruction it starts
+    /// at, rather than being written first.
+    held_before: bool,
+    /// Whether it holds a value after the instruction it ends at, rather
+    /// than being read last.
+    held_after: bool,
+}
+
+/// A function's instructions, by position, and the blocks they form.
+struct Function {
+    insts: Vec<Amd64Instruction>,
+    /// The line of each instruction.
+    lines: Vec<usize>,
+    info: Vec<Info>,
+    blocks: Vec<Block>,
+    /// The block each instruction is in.
+    block_of: Vec<usize>,
+}
+
+impl Function {
+    fn analyse(lines: &[Line]) -> Result<Self, AllocError> {
+        let mut insts = Vec::new();
+        let mut at = Vec::new();
+        let mut labels = HashMap::new();
+        let mut blocks = vec![(0, Control::None)];
+        let mut scope = String::new();
+        for (i, line) in lines.iter().enumerate() {
+            match line {
+                Line::Label(label) => {
+                    let name = qualify_label(&scope, &label.label);
+                    if !label.label.starts_with('.') {
+                        scope = label.label.clone();
+                    }
+                    if blocks.last().unwrap().0 < insts.len() {
+                        blocks.push((insts.len(), Control::None));
+                    }
+                    labels.insert(name, blocks.len() - 1);
+                }
+                Line::Instruction { inst, .. } => {
+                    insts.push(inst.clone());
+                    at.push(i);
+                    let control = control(inst, &scope);
+                    if !matches!(control, Control::None) {
+                        blocks.last_mut().unwrap().1 = control;
+                        blocks.push((insts.len(), Control::None));
+                    }
+                }
+            }
+        }
+
+        let count = blocks.len();
+        let mut built = Vec::new();
+        let mut block_of = vec![0; insts.len()];
+        for (b, (start, control)) in blocks.iter().enumerate() {
+            let end = blocks.get(b + 1).map_or(insts.len(), |next| next.0);
+            block_of[*start..end].fill(b);
+            let mut block = Block {
+                start: *start,
+                end,
+                successors: Vec::new(),
+                exits: false,
+                live_in: BTreeSet::new(),
+                live_out: BTreeSet::new(),
+            };
+            let leave = |target: &Option<String>, block: &mut Block| match target
+                .as_ref()
+                .and_then(|name| labels.get(name))
+            {
+                Some(&to) => block.successors.push(to),
+                None => block.exits = true,
+            };
+            match control {
+                Control::Return => {}
+                Control::Jump(target) => leave(target, &mut block),
+                Control::Branch(target) => {
+                    leave(target, &mut block);
+                    block.successors.push(b + 1);
+                }
+                Control::None if b + 1 < count => block.successors.push(b + 1),
+                Control::None => block.exits = true,
+            }
+            built.push(block);
+        }
+
+        let mut info = Vec::new();
+        for inst in &insts {
+            let (uses, defs) = virtual_effects(inst)?;
+            info.push(Info {
+                uses,
+                defs,
+                before: RegSet::EMPTY,
+                after: RegSet::EMPTY,
+                clobbers: effects(inst).defs,
+            });
+        }
+        let mut function = Function {
+            insts,
+            lines: at,
+            info,
+            blocks: built,
+            block_of,
+        };
+        function.physical_liveness();
+        function.virtual_liveness();
+        Ok(function)
+    }
+
+    fn physical_liveness(&mut self) {
+        let mut live_in = vec![RegSet::EMPTY; self.blocks.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (b, block) in self.blocks.iter().enumerate().rev() {
+                let mut regs = match block.exits {
+                    true => RegSet::ALL,
+                    false => RegSet::EMPTY,
+                };
+                for &to in &block.successors {
+                    regs = regs.union(live_in[to]);
+                }
+                for p in (block.start..block.end).rev() {
+                    self.info[p].after = regs;
+                    (regs, _) = step_back(&self.insts[p], regs, false);
+                    self.info[p].before = regs;
+                }
+                if regs != live_in[b] {
+                    live_in[b] = regs;
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    fn virtual_liveness(&mut self) {
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for b in (0..self.blocks.len()).rev() {
+                let mut live = BTreeSet::new();
+                for &to in &self.blocks[b].successors {
+                    live.extend(self.blocks[to].live_in.iter().copied());
+                }
+                self.blocks[b].live_out = live.clone();
+                for info in self.info[self.blocks[b].start..self.blocks[b].end]
+                    .iter()
+                    .rev()
+                {
+                    for reg in &info.defs {
+                        live.remove(reg);
+                    }
+                    live.extend(info.uses.iter().copied());
+                }
+                if live != self.blocks[b].live_in {
+                    self.blocks[b].live_in = live;
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    /// Every virtual register's interval, by start.
+    fn intervals(&self) -> Vec<Interval> {
+        let mut ranges: BTreeMap<VReg, (usize, usize)> = BTreeMap::new();
+        let mut touch = |reg: VReg, p: usize| {
+            let range = ranges.entry(reg).or_insert((p, p));
+            *range = (range.0.min(p), range.1.max(p));
+        };
+        for (p, info) in self.info.iter().enumerate() {
+            for &reg in info.uses.iter().chain(&info.defs) {
+                touch(reg, p);
+            }
+        }
+        for block in self.blocks.iter().filter(|b| b.start < b.end) {
+            for &reg in &block.live_in {
+                touch(reg, block.start);
+            }
+            for &reg in &block.live_out {
+                touch(reg, block.end - 1);
+            }
+        }
+
+        let mut intervals: Vec<Interval> = ranges
+            .into_iter()
+            .map(|(reg, (start, end))| {
+                let (first, last) = (&self.info[start], &self.info[end]);
+                let block = &self.blocks[self.block_of[end]];
+                Interval {
+                    reg,
+                    start,
+                    end,
+                    held_before: first.uses.contains(&reg) || !first.defs.contains(&reg),
+                    held_after: last.defs.contains(&reg)
+                        || !last.uses.contains(&reg)
+                        || (end + 1 == block.end && block.live_out.contains(&reg)),
+                }
+            })
+            .collect();
+        intervals.sort_by_key(|interval| (interval.start, interval.end));
+        intervals
+    }
+
+    /// The physical registers live or written while `interval` holds its
+    /// value, which it cannot be given.
+    fn busy(&self, interval: &Interval) -> RegSet {
+        let mut busy = RegSet::of(&[Gpr::RSP]);
+        for p in interval.start..=interval.end {
+            let info = &self.info[p];
+            if p > interval.start || interval.held_before {
+                busy = busy.union(info.before);
+            }
+            if p < interval.end || interval.held_after {
+                busy = busy.union(info.after).union(info.clobbers);
+            }
+        }
+        busy
+    }
+
+    /// The register a `mov` starting or ending `interval` copies from or
+    /// to, which the interval would rather have.
+    fn hint(&self, interval: &Interval, assigned: &BTreeMap<VReg, Gpr>) -> Option<Gpr> {
+        let whole = |operand: &Operand| match operand {
+            Operand::Register(Amd64Register::Virtual(reg, GprWidth::Qword)) => Some(Err(*reg)),
+            Operand::Register(reg) => reg.gpr().map(Ok),
+            _ => None,
+        };
+        let copy = |p: usize| match (self.insts[p].mnemonic.as_str(), &self.insts[p].operands[..]) {
+            ("mov", [dst, src]) => Some((whole(dst)?, whole(src)?)),
+            _ => None,
+        };
+        let ours = Err(interval.reg);
+        match copy(interval.start) {
+            Some((dst, Ok(src))) if dst == ours => return Some(src),
+            Some((dst, Err(src))) if dst == ours => return assigned.get(&src).copied(),
+            _ => {}
+        }
+        match copy(interval.end) {
+            Some((Ok(dst), src)) if src == ours => Some(dst),
+            _ => None,
+        }
+    }
+
+    /// How far rsp is below where it was on entry, before each instruction.
+    fn depths(&self) -> Result<Vec<i64>, AllocError> {
+        let mut depths = vec![0; self.insts.len()];
+        let mut entry: Vec<Option<i64>> = vec![None; self.blocks.len()];
+        entry[0] = Some(0);
+        let mut work = vec![0];
+        while let Some(b) = work.pop() {
+            let block = &self.blocks[b];
+            let mut depth = entry[b].unwrap();
+            for (slot, inst) in depths[block.start..block.end]
+                .iter_mut()
+                .zip(&self.insts[block.start..block.end])
+            {
+                *slot = depth;
+                depth += stack_change(inst)?;
+            }
+            for &to in &block.successors {
+                match entry[to] {
+                    None => {
+                        entry[to] = Some(depth);
+                        work.push(to);
+                    }
+                    Some(known) if known != depth => {
+                        let inst = &self.insts[block.end.max(1) - 1];
+                        return Err(AllocError::Stack(text(inst)));
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        Ok(depths)
+    }
+}
+
+/// The virtual registers `inst` reads and writes, found by standing a
+/// physical register the instruction does not otherwise touch in for each
+/// and asking [`effects`] about it. What an unknown instruction does to
+/// its first operand is assumed to be both.
+fn virtual_effects(inst: &Amd64Instruction) -> Result<(Vec<VReg>, Vec<VReg>), AllocError> {
+    let regs: BTreeSet<VReg> = virtuals(inst).collect();
+    if regs.is_empty() {
+        return Ok((Vec::new(), Vec::new()));
+    }
+    let fx = effects(inst);
+    let fixed = implicit(inst);
+    let named = operand_registers(inst)
+        .union(fx.uses)
+        .union(fx.defs)
+        .union(fixed.uses)
+        .union(fixed.defs);
+    let stand_ins: BTreeMap<VReg, Gpr> = regs
+        .iter()
+        .copied()
+        .zip(REGISTERS.into_iter().filter(|&reg| !named.contains(reg)))
+        .collect();
+    if stand_ins.len() < regs.len() {
+        return Err(AllocError::Pressure(text(inst)));
+    }
+
+    let mut standing = inst.clone();
+    for operand in &mut standing.operands {
+        map_virtual(operand, |reg, width| physical(stand_ins[&reg], width));
+    }
+    let fx = effects(&standing);
+    let mut uses: Vec<VReg> = regs
+        .iter()
+        .copied()
+        .filter(|reg| fx.uses.contains(stand_ins[reg]))
+        .collect();
+    let mut defs: Vec<VReg> = regs
+        .iter()
+        .copied()
+        .filter(|reg| fx.defs.contains(stand_ins[reg]))
+        .collect();
+    if !fx.known {
+        if let Some(Operand::Register(Amd64Register::Virtual(reg, _))) = inst.operands.first() {
+            defs.push(*reg);
+        }
+    }
+    // A register named but neither read nor written is taken to be read.
+    for reg in regs {
+        if !uses.contains(&reg) && !defs.contains(&reg) {
+            uses.push(reg);
+        }
+    }
+    Ok((uses, defs))
+}
+
+/// How many bytes `inst` moves rsp down by.
+fn stack_change(inst: &Amd64Instruction) -> Result<i64, AllocError> {
+    let defines = HashMap::new();
+    let is_rsp = |operand: &Operand| matches!(operand, Operand::Register(reg) if reg.gpr() == Some(Gpr::RSP));
+    let change = match (
+        split_prefix(inst.mnemonic.as_str()).1,
+        inst.operands.as_slice(),
+    ) {
+        ("push", _) => Some(8),
+        ("pop", _) => Some(-8),
+        ("call" | "ret", _) => Some(0),
+        ("sub", [dst, src]) if is_rsp(dst) => constant(src, &defines),
+        ("add", [dst, src]) if is_rsp(dst) => constant(src, &defines).map(|v| -v),
+        ("lea", [dst, Operand::Memory(mem)]) if is_rsp(dst) => {
+            let from_rsp = mem.base.as_ref().and_then(Amd64Register::gpr) == Some(Gpr::RSP);
+            (from_rsp && mem.index.is_none() && mem.label.is_none()).then_some(-mem.displacement)
+        }
+        _ if !effects(inst).defs.contains(Gpr::RSP) => Some(0),
+        _ => None,
+    };
+    change.ok_or_else(|| AllocError::Stack(text(inst)))
+}
+
+/// Rewrites every use of a virtual register in `slots` into a fresh one
+/// loaded from its slot before the instruction and stored back after.
+fn spill(
+    lines: Vec<Line>,
+    function: &Function,
+    depths: &[i64],
+    slots: &BTreeMap<VReg, u32>,
+    fresh: &mut impl FnMut() -> VReg,
+) -> Result<Vec<Line>, AllocError> {
+    let slot = |depth: i64, offset: u32| {
+        Operand::Memory(
+            Mem::base(Amd64Register::GeneralPurpose(Gpr::RSP))
+                .with_displacement(depth + offset as i64),
+        )
+    };
+    let mut out = Vec::with_capacity(lines.len());
+    let mut p = 0;
+    for line in lines {
+        let Line::Instruction { mut inst, added } = line else {
+            out.push(line);
+            continue;
+        };
+        let info = &function.info[p];
+        let (before, after) = (depths[p], depths[p] + stack_change(&inst)?);
+        let mut loads = Vec::new();
+        let mut stores = Vec::new();
+        let regs: BTreeSet<VReg> = virtuals(&inst).collect();
+        for reg in regs {
+            let Some(&offset) = slots.get(&reg) else {
+                continue;
+            };
+            let temp = fresh();
+            for operand in &mut inst.operands {
+                map_virtual(operand, |virt, width| match virt == reg {
+                    true => temp.at(width),
+                    false => virt.at(width),
+                });
+            }
+            if info.uses.contains(&reg) {
+                loads.push(mov(temp, slot(before, offset)));
+            }
+            if info.defs.contains(&reg) {
+                if !matches!(control(&inst, ""), Control::None) {
+                    return Err(AllocError::Stack(text(&inst)));
+                }
+                stores.push(mov(slot(after, offset), temp));
+            }
+        }
+        let added_line = |expr: AsmExpr| match expr {
+            AsmExpr::Instruction(inst) => Line::Instruction { inst, added: true },
+            _ => unreachable!(),
+        };
+        out.extend(loads.into_iter().map(added_line));
+        out.push(Line::Instruction { inst, added });
+        out.extend(stores.into_iter().map(added_line));
+        p += 1;
+    }
+    Ok(out)
+}
+
+/// Reserves `frame` bytes of spill slots on entry and releases them before
+/// each return and tail jump, moving the function's own rsp-relative
+/// operands up past them.
+fn reserve(lines: Vec<Line>, function: &Function, frame: u32) -> Result<Vec<Line>, AllocError> {
+    let named = |p: usize| text(&function.insts[p]);
+    let mut reached = vec![false; function.blocks.len()];
+    let mut work = vec![0];
+    while let Some(b) = work.pop() {
+        if std::mem::replace(&mut reached[b], true) {
+            continue;
+        }
+        let block = &function.blocks[b];
+        if block.successors.contains(&0) {
+            return Err(AllocError::Stack(named(block.end - 1)));
+        }
+        work.extend(&block.successors);
+        // Only an unconditional jump can be preceded by the release.
+        let jumps = block.end > block.start
+            && matches!(
+                control(&function.insts[block.end - 1], ""),
+                Control::Jump(_)
+            );
+        if block.exits && !jumps {
+            let last = block.end.max(1) - 1;
+            return Err(AllocError::Stack(named(last)));
+        }
+    }
+
+    let depths = function.depths()?;
+    let rsp = || Amd64Register::GeneralPurpose(Gpr::RSP);
+    let adjust = |bytes: i64| {
+        let inst = lea(Gpr::RSP, Mem::base(rsp()).with_displacement(bytes));
+        match inst {
+            AsmExpr::Instruction(inst) => Line::Instruction { inst, added: true },
+            _ => unreachable!(),
+        }
+    };
+    let entry = function.lines.first().copied().unwrap_or(lines.len());
+    let mut out = Vec::with_capacity(lines.len() + 2);
+    for (i, line) in lines.into_iter().enumerate() {
+        if i == entry {
+            out.push(adjust(-(frame as i64)));
+        }
+        let Line::Instruction { mut inst, added } = line else {
+            out.push(line);
+            continue;
+        };
+        let p = function.lines.binary_search(&i);
+        if let (Ok(p), false) = (p, added) {
+            shift(&mut inst, depths[p], frame).ok_or_else(|| AllocError::Stack(named(p)))?;
+        }
+        let leaves = match control(&inst, "") {
+            Control::Return => true,
+            Control::Jump(_) => function.blocks[function.block_of[p.unwrap()]].exits,
+            _ => false,
+        };
+        if leaves {
+            out.push(adjust(frame as i64));
+        }
+        out.push(Line::Instruction { inst, added });
+    }
+    Ok(out)
+}
+
+/// Moves the rsp-relative operands of `inst` that reach past the `depth`
+/// bytes the function has pushed up by the `frame` bytes of the spill
+/// area. `None` if rsp is read other than to address memory or be moved
+/// by a constant, as a copy of it would be left pointing elsewhere.
+fn shift(inst: &mut Amd64Instruction, depth: i64, frame: u32) -> Option<()> {
+    let is_rsp = |reg: &Amd64Register| reg.containing_gpr() == Some(Gpr::RSP);
+    let (_, mnemonic) = split_prefix(inst.mnemonic.as_str());
+    if matches!(mnemonic, "add" | "sub" | "lea")
+        && matches!(inst.operands.first(), Some(Operand::Register(reg)) if is_rsp(reg))
+    {
+        return Some(());
+    }
+    for operand in &mut inst.operands {
+        match operand {
+            Operand::Register(reg) if is_rsp(reg) => return None,
+            Operand::Memory(mem) if mem.registers().any(is_rsp) => {
+                if mem.index.is_some() {
+                    return None;
+                }
+                if mem.displacement >= depth {
+                    mem.displacement += frame as i64;
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(())
+}
+
+/// An instruction as an error names it.
+fn text(inst: &Amd64Instruction) -> String {
+    inst.to_string().trim().replace('\t', " ")
+}
@@ -0,0 +1,691 @@
+use std::{error, fmt};
+
+use crate::{Amd64Register, Operand};
+
+/// A general-purpose register by its architectural number (0 = rax,
+/// 1 = rcx, ... 15 = r15), guaranteed to be in range. Numbers 16-31 are
+/// the APX extended registers, which only exist on targets with
+/// [`CpuFeature::Apx`](crate::target::CpuFeature::Apx).
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Gpr(u8);
+
+const GPR_NAMES: [&str; 32] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15", "r16", "r17", "r18", "r19", "r20", "r21", "r22", "r23", "r24", "r25", "r26",
+    "r27", "r28", "r29", "r30", "r31",
+];
+
+/// Number of registers available without APX.
+const LEGACY_GPRS: u32 = 16;
+
+impl Gpr {
+    pub const RAX: Gpr = Gpr(0);
+    pub const RCX: Gpr = Gpr(1);
+    pub const RDX: Gpr = Gpr(2);
+    pub const RBX: Gpr = Gpr(3);
+    pub const RSP: Gpr = Gpr(4);
+    pub const RBP: Gpr = Gpr(5);
+    pub const RSI: Gpr = Gpr(6);
+    pub const RDI: Gpr = Gpr(7);
+    pub const R8: Gpr = Gpr(8);
+    pub const R9: Gpr = Gpr(9);
+    pub const R10: Gpr = Gpr(10);
+    pub const R11: Gpr = Gpr(11);
+    pub const R12: Gpr = Gpr(12);
+    pub const R13: Gpr = Gpr(13);
+    pub const R14: Gpr = Gpr(14);
+    pub const R15: Gpr = Gpr(15);
+    pub const R16: Gpr = Gpr(16);
+    pub const R17: Gpr = Gpr(17);
+    pub const R18: Gpr = Gpr(18);
+    pub const R19: Gpr = Gpr(19);
+    pub const R20: Gpr = Gpr(20);
+    pub const R21: Gpr = Gpr(21);
+    pub const R22: Gpr = Gpr(22);
+    pub const R23: Gpr = Gpr(23);
+    pub const R24: Gpr = Gpr(24);
+    pub const R25: Gpr = Gpr(25);
+    pub const R26: Gpr = Gpr(26);
+    pub const R27: Gpr = Gpr(27);
+    pub const R28: Gpr = Gpr(28);
+    pub const R29: Gpr = Gpr(29);
+    pub const R30: Gpr = Gpr(30);
+    pub const R31: Gpr = Gpr(31);
+
+    /// One of the sixteen baseline registers.
+    pub fn new(index: u32) -> Result<Self, RegisterError> {
+        if index < LEGACY_GPRS {
+            Ok(Gpr(index as u8))
+        } else {
+            Err(RegisterError::InvalidIndex(index))
+        }
+    }
+
+    /// Any register including the APX extended ones, r16-r31.
+    pub fn extended(index: u32) -> Result<Self, RegisterError> {
+        if index < GPR_NAMES.len() as u32 {
+            Ok(Gpr(index as u8))
+        } else {
+            Err(RegisterError::InvalidExtendedIndex(index))
+        }
+    }
+
+    /// Whether this is an APX register, needing REX2 or extended EVEX
+    /// encodings.
+    pub fn is_extended(self) -> bool {
+        self.0 as u32 >= LEGACY_GPRS
+    }
+
+    pub fn index(self) -> u8 {
+        self.0
+    }
+
+    pub fn name(self) -> &'static str {
+        GPR_NAMES[self.0 as usize]
+    }
+}
+
+impl fmt::Display for Gpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// How much of a general-purpose register an operand names.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum GprWidth {
+    /// The low byte: `al`, `sil`, `r8b`.
+    Byte,
+    /// `ax`, `si`, `r8w`.
+    Word,
+    /// `eax`, `esi`, `r8d`. Writing one zeroes the upper half.
+    Dword,
+    /// The whole register.
+    Qword,
+}
+
+impl GprWidth {
+    pub fn bits(self) -> u32 {
+        match self {
+            GprWidth::Byte => 8,
+            GprWidth::Word => 16,
+            GprWidth::Dword => 32,
+            GprWidth::Qword => 64,
+        }
+    }
+
+    /// The NASM size keyword for a memory operand this wide.
+    pub fn keyword(self) -> &'static str {
+        match self {
+            GprWidth::Byte => "byte",
+            GprWidth::Word => "word",
+            GprWidth::Dword => "dword",
+            GprWidth::Qword => "qword",
+        }
+    }
+
+    /// Whether writing this much leaves the rest of the register as it
+    /// was, so the write also depends on the old value.
+    pub fn merges(self) -> bool {
+        matches!(self, GprWidth::Byte | GprWidth::Word)
+    }
+}
+
+/// Names of rax through rdi at 32, 16 and 8 bits, by architectural number.
+const LOW_GPR_NAMES: [[&str; 3]; 8] = [
+    ["eax", "ax", "al"],
+    ["ecx", "cx", "cl"],
+    ["edx", "dx", "dl"],
+    ["ebx", "bx", "bl"],
+    ["esp", "sp", "spl"],
+    ["ebp", "bp", "bpl"],
+    ["esi", "si", "sil"],
+    ["edi", "di", "dil"],
+];
+
+impl Gpr {
+    /// The NASM name of the low `width` of this register.
+    pub fn name_at(self, width: GprWidth) -> String {
+        let column = match width {
+            GprWidth::Qword => return self.name().to_string(),
+            GprWidth::Dword => 0,
+            GprWidth::Word => 1,
+            GprWidth::Byte => 2,
+        };
+        match LOW_GPR_NAMES.get(self.0 as usize) {
+            Some(names) => names[column].to_string(),
+            None => format!("{}{}", self.name(), ["d", "w", "b"][column]),
+        }
+    }
+}
+
+/// Defines a general-purpose register enum of one width, its variants
+/// in architectural order so each one's discriminant is its number.
+macro_rules! width_registers {
+    ($(#[$doc:meta])* $name:ident, $width:expr, [$($variant:ident),* $(,)?]) => {
+        $(#[$doc])*
+        #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+        pub enum $name {
+            $($variant),*
+        }
+
+        impl $name {
+            /// How much of the register every value names.
+            pub const WIDTH: GprWidth = $width;
+
+            /// Every register, by architectural number.
+            pub const ALL: [$name; LEGACY_GPRS as usize] = [$($name::$variant),*];
+
+            /// The register this is part of.
+            pub fn gpr(self) -> Gpr {
+                Gpr(self as u8)
+            }
+
+            /// `gpr` at this width, unless it is an APX register.
+            pub fn of(gpr: Gpr) -> Option<Self> {
+                Self::ALL.get(gpr.index() as usize).copied()
+            }
+
+            /// The whole register.
+            pub fn reg64(self) -> Reg64 {
+                Reg64::ALL[self as usize]
+            }
+
+            /// The low dword of the register.
+            pub fn reg32(self) -> Reg32 {
+                Reg32::ALL[self as usize]
+            }
+
+            /// The low word of the register.
+            pub fn reg16(self) -> Reg16 {
+                Reg16::ALL[self as usize]
+            }
+
+            /// The low byte of the register.
+            pub fn reg8(self) -> Reg8 {
+                Reg8::ALL[self as usize]
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}", self.gpr().name_at(Self::WIDTH))
+            }
+        }
+
+        impl From<$name> for Amd64Register {
+            fn from(reg: $name) -> Self {
+                match $name::WIDTH {
+                    GprWidth::Qword => Amd64Register::GeneralPurpose(reg.gpr()),
+                    width => Amd64Register::Partial(reg.gpr(), width),
+                }
+            }
+        }
+
+        impl From<$name> for Operand {
+            fn from(reg: $name) -> Self {
+                Operand::Register(reg.into())
+            }
+        }
+    };
+}
+
+width_registers!(
+    /// One of the sixteen baseline general-purpose registers in full, its
+    /// width part of its type, as [`Reg32`], [`Reg16`] and [`Reg8`] are
+    /// theirs. Each converts into the [`Amd64Register`] or [`Operand`] it
+    /// names, so it can go wherever an operand does.
+    ///
+    /// ```
+    /// use cataclysm::{
+    ///     instr,
+    ///     register::{GprWidth, Reg32, Reg64, Reg8},
+    ///     Amd64Register, Operand,
+    /// };
+    ///
+    /// assert_eq!(Reg64::R8.to_string(), "r8");
+    /// assert_eq!(Reg8::Dil.to_string(), "dil");
+    /// assert_eq!(Reg64::Rdi.reg8(), Reg8::Dil);
+    /// assert_eq!(Reg32::R15d.reg64(), Reg64::R15);
+    /// assert_eq!(Reg32::WIDTH, GprWidth::Dword);
+    ///
+    /// assert_eq!(Operand::from(Reg32::Eax).width(), Some(32));
+    /// assert_eq!(Amd64Register::from(Reg8::R9b).width(), Some(8));
+    /// assert_eq!(
+    ///     instr::mov(Reg32::Eax, Reg32::R10d).to_string().trim(),
+    ///     "mov\teax, r10d"
+    /// );
+    /// ```
+    Reg64,
+    GprWidth::Qword,
+    [Rax, Rcx, Rdx, Rbx, Rsp, Rbp, Rsi, Rdi, R8, R9, R10, R11, R12, R13, R14, R15]
+);
+
+width_registers!(
+    /// The low dword of a baseline general-purpose register, `eax` to
+    /// `r15d`. Writing one zeroes the rest of the register.
+    Reg32,
+    GprWidth::Dword,
+    [Eax, Ecx, Edx, Ebx, Esp, Ebp, Esi, Edi, R8d, R9d, R10d, R11d, R12d, R13d, R14d, R15d]
+);
+
+width_registers!(
+    /// The low word of a baseline general-purpose register, `ax` to `r15w`.
+    Reg16,
+    GprWidth::Word,
+    [Ax, Cx, Dx, Bx, Sp, Bp, Si, Di, R8w, R9w, R10w, R11w, R12w, R13w, R14w, R15w]
+);
+
+width_registers!(
+    /// The low byte of a baseline general-purpose register, `al` to
+    /// `r15b`.
+    Reg8,
+    GprWidth::Byte,
+    [Al, Cl, Dl, Bl, Spl, Bpl, Sil, Dil, R8b, R9b, R10b, R11b, R12b, R13b, R14b, R15b]
+);
+
+impl From<Gpr> for Amd64Register {
+    fn from(gpr: Gpr) -> Self {
+        Amd64Register::GeneralPurpose(gpr)
+    }
+}
+
+/// A whole general-purpose register, physical ([`Reg64`], [`Gpr`]) or
+/// virtual ([`VReg`]): what the builders in [`instr`](crate::instr) take
+/// where the instruction only accepts a 64-bit register, so a vector,
+/// tile or partial register there is a type error.
+///
+/// ```compile_fail
+/// use cataclysm::{consts::XMM0, instr, Mem, Label};
+///
+/// instr::lea(XMM0, Mem::label(Label::plain("table")));
+/// ```
+pub trait Register64: Into<Amd64Register> {}
+
+impl Register64 for Reg64 {}
+
+impl Register64 for Gpr {}
+
+impl Register64 for VReg {}
+
+/// One of the eight AMX tile registers, tmm0-tmm7.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Tmm(u8);
+
+/// Number of tile registers in the AMX register file.
+pub const TILE_REGISTERS: u32 = 8;
+
+impl Tmm {
+    pub const TMM0: Tmm = Tmm(0);
+    pub const TMM1: Tmm = Tmm(1);
+    pub const TMM2: Tmm = Tmm(2);
+    pub const TMM3: Tmm = Tmm(3);
+    pub const TMM4: Tmm = Tmm(4);
+    pub const TMM5: Tmm = Tmm(5);
+    pub const TMM6: Tmm = Tmm(6);
+    pub const TMM7: Tmm = Tmm(7);
+
+    pub fn new(index: u32) -> Result<Self, RegisterError> {
+        if index < TILE_REGISTERS {
+            Ok(Tmm(index as u8))
+        } else {
+            Err(RegisterError::TileOutOfRange(index))
+        }
+    }
+
+    pub fn index(self) -> u8 {
+        self.0
+    }
+}
+
+impl fmt::Display for Tmm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "tmm{}", self.0)
+    }
+}
+
+/// One of the sixteen SSE vector registers, xmm0-xmm15.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Xmm(u8);
+
+/// Number of xmm registers addressable without EVEX.
+pub const VECTOR_REGISTERS: u32 = 16;
+
+impl Xmm {
+    pub const XMM0: Xmm = Xmm(0);
+    pub const XMM1: Xmm = Xmm(1);
+    pub const XMM2: Xmm = Xmm(2);
+    pub const XMM3: Xmm = Xmm(3);
+    pub const XMM4: Xmm = Xmm(4);
+    pub const XMM5: Xmm = Xmm(5);
+    pub const XMM6: Xmm = Xmm(6);
+    pub const XMM7: Xmm = Xmm(7);
+    pub const XMM8: Xmm = Xmm(8);
+    pub const XMM9: Xmm = Xmm(9);
+    pub const XMM10: Xmm = Xmm(10);
+    pub const XMM11: Xmm = Xmm(11);
+    pub const XMM12: Xmm = Xmm(12);
+    pub const XMM13: Xmm = Xmm(13);
+    pub const XMM14: Xmm = Xmm(14);
+    pub const XMM15: Xmm = Xmm(15);
+
+    pub fn new(index: u32) -> Result<Self, RegisterError> {
+        if index < VECTOR_REGISTERS {
+            Ok(Xmm(index as u8))
+        } else {
+            Err(RegisterError::VectorOutOfRange(index))
+        }
+    }
+
+    pub fn index(self) -> u8 {
+        self.0
+    }
+}
+
+impl fmt::Display for Xmm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "xmm{}", self.0)
+    }
+}
+
+/// One of the sixteen AVX vector registers, ymm0-ymm15, each extending
+/// the xmm register of the same number to 256 bits.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Ymm(u8);
+
+impl Ymm {
+    pub const YMM0: Ymm = Ymm(0);
+    pub const YMM1: Ymm = Ymm(1);
+    pub const YMM2: Ymm = Ymm(2);
+    pub const YMM3: Ymm = Ymm(3);
+    pub const YMM4: Ymm = Ymm(4);
+    pub const YMM5: Ymm = Ymm(5);
+    pub const YMM6: Ymm = Ymm(6);
+    pub const YMM7: Ymm = Ymm(7);
+    pub const YMM8: Ymm = Ymm(8);
+    pub const YMM9: Ymm = Ymm(9);
+    pub const YMM10: Ymm = Ymm(10);
+    pub const YMM11: Ymm = Ymm(11);
+    pub const YMM12: Ymm = Ymm(12);
+    pub const YMM13: Ymm = Ymm(13);
+    pub const YMM14: Ymm = Ymm(14);
+    pub const YMM15: Ymm = Ymm(15);
+
+    pub fn new(index: u32) -> Result<Self, RegisterError> {
+        if index < VECTOR_REGISTERS {
+            Ok(Ymm(index as u8))
+        } else {
+            Err(RegisterError::VectorOutOfRange(index))
+        }
+    }
+
+    pub fn index(self) -> u8 {
+        self.0
+    }
+
+    /// The low 128 bits.
+    pub fn xmm(self) -> Xmm {
+        Xmm(self.0)
+    }
+}
+
+impl fmt::Display for Ymm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ymm{}", self.0)
+    }
+}
+
+/// Number of zmm registers, all of which need EVEX.
+pub const EXTENDED_VECTOR_REGISTERS: u32 = 32;
+
+/// One of the thirty-two AVX-512 vector registers, zmm0-zmm31. The first
+/// sixteen extend the ymm registers of the same number to 512 bits.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Zmm(u8);
+
+impl Zmm {
+    pub const ZMM0: Zmm = Zmm(0);
+    pub const ZMM1: Zmm = Zmm(1);
+    pub const ZMM2: Zmm = Zmm(2);
+    pub const ZMM3: Zmm = Zmm(3);
+    pub const ZMM4: Zmm = Zmm(4);
+    pub const ZMM5: Zmm = Zmm(5);
+    pub const ZMM6: Zmm = Zmm(6);
+    pub const ZMM7: Zmm = Zmm(7);
+    pub const ZMM8: Zmm = Zmm(8);
+    pub const ZMM9: Zmm = Zmm(9);
+    pub const ZMM10: Zmm = Zmm(10);
+    pub const ZMM11: Zmm = Zmm(11);
+    pub const ZMM12: Zmm = Zmm(12);
+    pub const ZMM13: Zmm = Zmm(13);
+    pub const ZMM14: Zmm = Zmm(14);
+    pub const ZMM15: Zmm = Zmm(15);
+    pub const ZMM16: Zmm = Zmm(16);
+    pub const ZMM17: Zmm = Zmm(17);
+    pub const ZMM18: Zmm = Zmm(18);
+    pub const ZMM19: Zmm = Zmm(19);
+    pub const ZMM20: Zmm = Zmm(20);
+    pub const ZMM21: Zmm = Zmm(21);
+    pub const ZMM22: Zmm = Zmm(22);
+    pub const ZMM23: Zmm = Zmm(23);
+    pub const ZMM24: Zmm = Zmm(24);
+    pub const ZMM25: Zmm = Zmm(25);
+    pub const ZMM26: Zmm = Zmm(26);
+    pub const ZMM27: Zmm = Zmm(27);
+    pub const ZMM28: Zmm = Zmm(28);
+    pub const ZMM29: Zmm = Zmm(29);
+    pub const ZMM30: Zmm = Zmm(30);
+    pub const ZMM31: Zmm = Zmm(31);
+
+    pub fn new(index: u32) -> Result<Self, RegisterError> {
+        if index < EXTENDED_VECTOR_REGISTERS {
+            Ok(Zmm(index as u8))
+        } else {
+            Err(RegisterError::ExtendedVectorOutOfRange(index))
+        }
+    }
+
+    pub fn index(self) -> u8 {
+        self.0
+    }
+
+    /// The low 256 bits, for the registers that have a ymm name.
+    pub fn ymm(self) -> Option<Ymm> {
+        (u32::from(self.0) < VECTOR_REGISTERS).then_some(Ymm(self.0))
+    }
+}
+
+impl fmt::Display for Zmm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "zmm{}", self.0)
+    }
+}
+
+/// One of the eight AVX-512 opmask registers, k0-k7. k0 cannot select
+/// elements, as writing it as a mask means no masking.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Opmask(u8);
+
+/// Number of opmask registers.
+pub const MASK_REGISTERS: u32 = 8;
+
+impl Opmask {
+    pub const K0: Opmask = Opmask(0);
+    pub const K1: Opmask = Opmask(1);
+    pub const K2: Opmask = Opmask(2);
+    pub const K3: Opmask = Opmask(3);
+    pub const K4: Opmask = Opmask(4);
+    pub const K5: Opmask = Opmask(5);
+    pub const K6: Opmask = Opmask(6);
+    pub const K7: Opmask = Opmask(7);
+
+    pub fn new(index: u32) -> Result<Self, RegisterError> {
+        if index < MASK_REGISTERS {
+            Ok(Opmask(index as u8))
+        } else {
+            Err(RegisterError::MaskOutOfRange(index))
+        }
+    }
+
+    pub fn index(self) -> u8 {
+        self.0
+    }
+}
+
+impl fmt::Display for Opmask {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "k{}", self.0)
+    }
+}
+
+/// A virtual register: a general-purpose value named by number until
+/// [`regalloc`](crate::regalloc) gives it a register of its own. The
+/// numbers are whoever makes them pleases, as long as one function keeps
+/// them apart.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct VReg(u32);
+
+impl VReg {
+    pub fn new(index: u32) -> Self {
+        VReg(index)
+    }
+
+    pub fn index(self) -> u32 {
+        self.0
+    }
+
+    /// The low `width` of the register, as `eax` is the low dword of rax.
+    pub fn at(self, width: GprWidth) -> Amd64Register {
+        Amd64Register::Virtual(self, width)
+    }
+
+    /// The name of the low `width` of the register, suffixed as r8 is:
+    /// `v3`, `v3d`, `v3w` or `v3b`.
+    pub fn name_at(self, width: GprWidth) -> String {
+        let suffix = match width {
+            GprWidth::Qword => "",
+            GprWidth::Dword => "d",
+            GprWidth::Word => "w",
+            GprWidth::Byte => "b",
+        };
+        format!("v{}{}", self.0, suffix)
+    }
+}
+
+impl fmt::Display for VReg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+/// A set of registers an operand may name, as the instruction tables in
+/// [`mnemonic`](crate::mnemonic) constrain operands and an allocator picks
+/// from them.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum RegClass {
+    /// General-purpose registers, whole or in part, and the virtual
+    /// registers that stand for them.
+    Gpr,
+    /// General-purpose registers other than rsp, which cannot index, and
+    /// virtual registers, which are never given rsp.
+    GprNoRsp,
+    /// xmm registers, which legacy SSE instructions are limited to.
+    Sse,
+    /// xmm, ymm and zmm registers.
+    Vector,
+    /// Opmask registers.
+    Mask,
+}
+
+impl RegClass {
+    pub fn contains(self, reg: &Amd64Register) -> bool {
+        match self {
+            RegClass::Gpr | RegClass::GprNoRsp if matches!(reg, Amd64Register::Virtual(..)) => true,
+            RegClass::Gpr => reg.containing_gpr().is_some(),
+            RegClass::GprNoRsp => reg.containing_gpr().is_some_and(|gpr| gpr != Gpr::RSP),
+            RegClass::Sse => matches!(reg, Amd64Register::Vector(_)),
+            RegClass::Vector => matches!(
+                reg,
+                Amd64Register::Vector(_) | Amd64Register::Ymm(_) | Amd64Register::Zmm(_)
+            ),
+            RegClass::Mask => matches!(reg, Amd64Register::Mask(_)),
+        }
+    }
+
+    /// The registers of the class that every x86-64 processor with the
+    /// class has, whole, by number: the sixteen legacy general-purpose
+    /// registers, the sixteen vector registers by their xmm names and the
+    /// eight opmasks.
+    pub fn members(self) -> Vec<Amd64Register> {
+        match self {
+            RegClass::Gpr | RegClass::GprNoRsp => (0..LEGACY_GPRS)
+                .map(|i| Gpr(i as u8))
+                .filter(|&gpr| self == RegClass::Gpr || gpr != Gpr::RSP)
+                .map(Amd64Register::GeneralPurpose)
+                .collect(),
+            RegClass::Sse | RegClass::Vector => (0..VECTOR_REGISTERS)
+                .map(|i| Amd64Register::Vector(Xmm(i as u8)))
+                .collect(),
+            RegClass::Mask => (0..MASK_REGISTERS)
+                .map(|i| Amd64Register::Mask(Opmask(i as u8)))
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for RegClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            RegClass::Gpr => "a general-purpose register",
+            RegClass::GprNoRsp => "a general-purpose register other than rsp",
+            RegClass::Sse => "an xmm register",
+            RegClass::Vector => "a vector register",
+            RegClass::Mask => "an opmask register",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegisterError {
+    InvalidIndex(u32),
+    InvalidExtendedIndex(u32),
+    TileOutOfRange(u32),
+    VectorOutOfRange(u32),
+    ExtendedVectorOutOfRange(u32),
+    MaskOutOfRange(u32),
+}
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegisterError::InvalidIndex(n) => {
+                write!(
+                    f,
+                    "general-purpose register index {} is out of range (0-15)",
+                    n
+                )
+            }
+            RegisterError::InvalidExtendedIndex(n) => {
+                write!(
+                    f,
+                    "general-purpose register index {} is out of range (0-31)",
+                    n
+                )
+            }
+            RegisterError::TileOutOfRange(n) => {
+                write!(f, "tile register index {} is out of range (0-7)", n)
+            }
+            RegisterError::VectorOutOfRange(n) => {
+                write!(f, "vector register index {} is out of range (0-15)", n)
+            }
+            RegisterError::ExtendedVectorOutOfRange(n) => {
+                write!(f, "vector register index {} is out of range (0-31)", n)
+            }
+            RegisterError::MaskOutOfRange(n) => {
+                write!(f, "opmask register index {} is out of range (0-7)", n)
+            }
+        }
+    }
+}
+
+impl error::Error for RegisterError {}
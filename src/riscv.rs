@@ -0,0 +1,417 @@
+//! RV64I and M-extension instructions, and the pseudo-instructions the
+//! GNU assembler expands, for programs targeting [`Arch::Riscv64`].
+//!
+//! A [`RiscvInstruction`] sits in a section as [`AsmExpr::Riscv`], next to
+//! the same labels, data, blocks and conditionals x86-64 code uses, and is
+//! written in GNU `as` syntax with ABI register names:
+//!
+//! ```text
+//! la      a1, msg
+//! ld      a2, 8(a1)
+//! call    puts
+//! ```
+//!
+//! Like AArch64 code, RISC-V code is only ever emitted as text; the
+//! encoder, interpreter and analyses are x86-64 only and reject or skip it.
+//!
+//! [`Arch::Riscv64`]: crate::target::Arch::Riscv64
+
+use std::fmt;
+
+use crate::{gnu::Syntax, AsmExpr, Label};
+
+/// One of the 32 integer registers, `x0` to `x31`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct RiscvRegister(u8);
+
+impl RiscvRegister {
+    /// `xn`, if there is one.
+    pub fn new(n: u8) -> Option<Self> {
+        (n < 32).then_some(RiscvRegister(n))
+    }
+
+    pub fn index(self) -> u8 {
+        self.0
+    }
+}
+
+/// ABI names, indexed by register number.
+const NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+impl fmt::Display for RiscvRegister {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", NAMES[self.0 as usize])
+    }
+}
+
+pub const ZERO: RiscvRegister = RiscvRegister(0);
+/// The return address `call` writes and `ret` jumps to.
+pub const RA: RiscvRegister = RiscvRegister(1);
+pub const SP: RiscvRegister = RiscvRegister(2);
+pub const GP: RiscvRegister = RiscvRegister(3);
+pub const TP: RiscvRegister = RiscvRegister(4);
+pub const T0: RiscvRegister = RiscvRegister(5);
+pub const T1: RiscvRegister = RiscvRegister(6);
+pub const T2: RiscvRegister = RiscvRegister(7);
+pub const S0: RiscvRegister = RiscvRegister(8);
+/// The frame pointer, s0.
+pub const FP: RiscvRegister = S0;
+pub const S1: RiscvRegister = RiscvRegister(9);
+pub const A0: RiscvRegister = RiscvRegister(10);
+pub const A1: RiscvRegister = RiscvRegister(11);
+pub const A2: RiscvRegister = RiscvRegister(12);
+pub const A3: RiscvRegister = RiscvRegister(13);
+pub const A4: RiscvRegister = RiscvRegister(14);
+pub const A5: RiscvRegister = RiscvRegister(15);
+pub const A6: RiscvRegister = RiscvRegister(16);
+pub const A7: RiscvRegister = RiscvRegister(17);
+pub const S2: RiscvRegister = RiscvRegister(18);
+pub const S3: RiscvRegister = RiscvRegister(19);
+pub const S4: RiscvRegister = RiscvRegister(20);
+pub const S5: RiscvRegister = RiscvRegister(21);
+pub const S6: RiscvRegister = RiscvRegister(22);
+pub const S7: RiscvRegister = RiscvRegister(23);
+pub const S8: RiscvRegister = RiscvRegister(24);
+pub const S9: RiscvRegister = RiscvRegister(25);
+pub const S10: RiscvRegister = RiscvRegister(26);
+pub const S11: RiscvRegister = RiscvRegister(27);
+pub const T3: RiscvRegister = RiscvRegister(28);
+pub const T4: RiscvRegister = RiscvRegister(29);
+pub const T5: RiscvRegister = RiscvRegister(30);
+pub const T6: RiscvRegister = RiscvRegister(31);
+
+#[derive(Clone)]
+pub enum RiscvOperand {
+    Register(RiscvRegister),
+    Immediate(i64),
+    /// `offset(base)`.
+    Memory {
+        base: RiscvRegister,
+        offset: i64,
+    },
+    /// A branch or jump target, or the symbol of a pseudo-instruction.
+    Label(Label),
+    /// `%hi(label)`, the upper 20 bits of a label's address, for `lui`.
+    Hi(Label),
+    /// `%lo(label)`, the lower 12 bits, for the `addi` or load after it.
+    Lo(Label),
+}
+
+impl RiscvOperand {
+    pub fn mem(base: RiscvRegister, offset: i64) -> Self {
+        RiscvOperand::Memory { base, offset }
+    }
+
+    /// The label the operand refers to, if any.
+    pub fn label(&self) -> Option<&Label> {
+        match self {
+            RiscvOperand::Label(l) | RiscvOperand::Hi(l) | RiscvOperand::Lo(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn label_mut(&mut self) -> Option<&mut Label> {
+        match self {
+            RiscvOperand::Label(l) | RiscvOperand::Hi(l) | RiscvOperand::Lo(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    fn fmt_in(&self, f: &mut fmt::Formatter, syntax: &Syntax) -> fmt::Result {
+        match self {
+            RiscvOperand::Register(reg) => write!(f, "{}", reg),
+            RiscvOperand::Immediate(n) => write!(f, "{}", n),
+            RiscvOperand::Memory { base, offset } => write!(f, "{}({})", offset, base),
+            RiscvOperand::Label(label) => write!(f, "{}", syntax.symbol(&label.label)),
+            RiscvOperand::Hi(label) => write!(f, "%hi({})", syntax.symbol(&label.label)),
+            RiscvOperand::Lo(label) => write!(f, "%lo({})", syntax.symbol(&label.label)),
+        }
+    }
+}
+
+impl fmt::Display for RiscvOperand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_in(f, &Syntax::default())
+    }
+}
+
+impl From<RiscvRegister> for RiscvOperand {
+    fn from(reg: RiscvRegister) -> Self {
+        RiscvOperand::Register(reg)
+    }
+}
+
+impl From<i64> for RiscvOperand {
+    fn from(value: i64) -> Self {
+        RiscvOperand::Immediate(value)
+    }
+}
+
+impl From<Label> for RiscvOperand {
+    fn from(label: Label) -> Self {
+        RiscvOperand::Label(label)
+    }
+}
+
+#[derive(Clone)]
+pub struct RiscvInstruction {
+    pub mnemonic: String,
+    pub operands: Vec<RiscvOperand>,
+}
+
+impl RiscvInstruction {
+    pub fn new(mnemonic: &str, operands: Vec<RiscvOperand>) -> Self {
+        RiscvInstruction {
+            mnemonic: mnemonic.to_string(),
+            operands,
+        }
+    }
+
+    pub(crate) fn fmt_in(&self, f: &mut fmt::Formatter, syntax: &Syntax) -> fmt::Result {
+        write!(f, "{}", self.mnemonic)?;
+        for (index, operand) in self.operands.iter().enumerate() {
+            write!(f, "{}", if index == 0 { "\t" } else { ", " })?;
+            operand.fmt_in(f, syntax)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for RiscvInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_in(f, &Syntax::default())
+    }
+}
+
+fn inst(mnemonic: &str, operands: Vec<RiscvOperand>) -> AsmExpr {
+    AsmExpr::Riscv(RiscvInstruction::new(mnemonic, operands))
+}
+
+/// Register-register operations: `dst = a op b`.
+macro_rules! rtype {
+    ($($name:ident => $mnemonic:literal),* $(,)?) => {
+        $(
+            pub fn $name(dst: RiscvRegister, a: RiscvRegister, b: RiscvRegister) -> AsmExpr {
+                inst($mnemonic, vec![dst.into(), a.into(), b.into()])
+            }
+        )*
+    };
+}
+
+/// Register-immediate operations: `dst = src op imm`.
+macro_rules! itype {
+    ($($name:ident => $mnemonic:literal),* $(,)?) => {
+        $(
+            pub fn $name(
+                dst: RiscvRegister,
+                src: RiscvRegister,
+                imm: impl Into<RiscvOperand>,
+            ) -> AsmExpr {
+                inst($mnemonic, vec![dst.into(), src.into(), imm.into()])
+            }
+        )*
+    };
+}
+
+/// Loads into, or stores from, `reg` at `offset(base)`.
+macro_rules! memory {
+    ($($name:ident => $mnemonic:literal),* $(,)?) => {
+        $(
+            pub fn $name(reg: RiscvRegister, base: RiscvRegister, offset: i64) -> AsmExpr {
+                inst($mnemonic, vec![reg.into(), RiscvOperand::mem(base, offset)])
+            }
+        )*
+    };
+}
+
+/// Compare-and-branch: to `target` if `a op b`.
+macro_rules! branch {
+    ($($name:ident => $mnemonic:literal),* $(,)?) => {
+        $(
+            pub fn $name(a: RiscvRegister, b: RiscvRegister, target: Label) -> AsmExpr {
+                inst($mnemonic, vec![a.into(), b.into(), target.into()])
+            }
+        )*
+    };
+}
+
+rtype! {
+    add => "add",
+    sub => "sub",
+    sll => "sll",
+    slt => "slt",
+    sltu => "sltu",
+    xor => "xor",
+    srl => "srl",
+    sra => "sra",
+    or => "or",
+    and => "and",
+    addw => "addw",
+    subw => "subw",
+    sllw => "sllw",
+    srlw => "srlw",
+    sraw => "sraw",
+    mul => "mul",
+    mulh => "mulh",
+    mulhsu => "mulhsu",
+    mulhu => "mulhu",
+    div => "div",
+    divu => "divu",
+    rem => "rem",
+    remu => "remu",
+    mulw => "mulw",
+    divw => "divw",
+    divuw => "divuw",
+    remw => "remw",
+    remuw => "remuw",
+}
+
+itype! {
+    addi => "addi",
+    slti => "slti",
+    sltiu => "sltiu",
+    xori => "xori",
+    ori => "ori",
+    andi => "andi",
+    slli => "slli",
+    srli => "srli",
+    srai => "srai",
+    addiw => "addiw",
+    slliw => "slliw",
+    srliw => "srliw",
+    sraiw => "sraiw",
+}
+
+memory! {
+    ld => "ld",
+    lw => "lw",
+    lwu => "lwu",
+    lh => "lh",
+    lhu => "lhu",
+    lb => "lb",
+    lbu => "lbu",
+    sd => "sd",
+    sw => "sw",
+    sh => "sh",
+    sb => "sb",
+}
+
+branch! {
+    beq => "beq",
+    bne => "bne",
+    blt => "blt",
+    bge => "bge",
+    bltu => "bltu",
+    bgeu => "bgeu",
+}
+
+/// Loads the upper 20 bits of `dst` from `imm`, a number or a
+/// [`RiscvOperand::Hi`].
+pub fn lui(dst: RiscvRegister, imm: impl Into<RiscvOperand>) -> AsmExpr {
+    inst("lui", vec![dst.into(), imm.into()])
+}
+
+pub fn auipc(dst: RiscvRegister, imm: impl Into<RiscvOperand>) -> AsmExpr {
+    inst("auipc", vec![dst.into(), imm.into()])
+}
+
+/// Jumps to `target`, leaving the return address in `link`.
+pub fn jal(link: RiscvRegister, target: Label) -> AsmExpr {
+    inst("jal", vec![link.into(), target.into()])
+}
+
+/// Jumps to `offset(base)`, leaving the return address in `link`.
+pub fn jalr(link: RiscvRegister, base: RiscvRegister, offset: i64) -> AsmExpr {
+    inst("jalr", vec![link.into(), RiscvOperand::mem(base, offset)])
+}
+
+/// A system call, with its number in a7 and arguments from a0.
+pub fn ecall() -> AsmExpr {
+    inst("ecall", vec![])
+}
+
+pub fn ebreak() -> AsmExpr {
+    inst("ebreak", vec![])
+}
+
+/// Orders all earlier memory accesses before all later ones.
+pub fn fence() -> AsmExpr {
+    inst("fence", vec![])
+}
+
+/// `dst = value`, in as many instructions as the assembler needs.
+pub fn li(dst: RiscvRegister, value: i64) -> AsmExpr {
+    inst("li", vec![dst.into(), value.into()])
+}
+
+/// `dst = &label`: `auipc` and `addi` in position-independent code, or
+/// a load from the GOT.
+///
+/// ```
+/// use cataclysm::{program::Program, riscv, target::Target, Label, Section};
+///
+/// let text = Section::new("text", vec![riscv::la(riscv::A0, Label::plain("msg"))]);
+/// let program = Program::new(vec![], vec![text]).with_target(Target::riscv64());
+/// assert!(program.to_string().contains("\tla\ta0, msg\n"));
+/// ```
+pub fn la(dst: RiscvRegister, label: Label) -> AsmExpr {
+    inst("la", vec![dst.into(), label.into()])
+}
+
+/// Calls `target` anywhere within 2 GiB, through `ra`.
+pub fn call(target: Label) -> AsmExpr {
+    inst("call", vec![target.into()])
+}
+
+/// Tail-calls `target` anywhere within 2 GiB, through `t1`.
+pub fn tail(target: Label) -> AsmExpr {
+    inst("tail", vec![target.into()])
+}
+
+pub fn mv(dst: RiscvRegister, src: RiscvRegister) -> AsmExpr {
+    inst("mv", vec![dst.into(), src.into()])
+}
+
+pub fn not(dst: RiscvRegister, src: RiscvRegister) -> AsmExpr {
+    inst("not", vec![dst.into(), src.into()])
+}
+
+pub fn neg(dst: RiscvRegister, src: RiscvRegister) -> AsmExpr {
+    inst("neg", vec![dst.into(), src.into()])
+}
+
+pub fn seqz(dst: RiscvRegister, src: RiscvRegister) -> AsmExpr {
+    inst("seqz", vec![dst.into(), src.into()])
+}
+
+pub fn snez(dst: RiscvRegister, src: RiscvRegister) -> AsmExpr {
+    inst("snez", vec![dst.into(), src.into()])
+}
+
+pub fn beqz(reg: RiscvRegister, target: Label) -> AsmExpr {
+    inst("beqz", vec![reg.into(), target.into()])
+}
+
+pub fn bnez(reg: RiscvRegister, target: Label) -> AsmExpr {
+    inst("bnez", vec![reg.into(), target.into()])
+}
+
+pub fn j(target: Label) -> AsmExpr {
+    inst("j", vec![target.into()])
+}
+
+pub fn jr(target: RiscvRegister) -> AsmExpr {
+    inst("jr", vec![target.into()])
+}
+
+pub fn ret() -> AsmExpr {
+    inst("ret", vec![])
+}
+
+pub fn nop() -> AsmExpr {
+    inst("nop", vec![])
+}
@@ -0,0 +1,76 @@
+//! Seeded, reproducible randomness for passes that vary their output, such
+//! as polymorphic rewriting, nop mixing or register shuffling.
+//!
+//! A program's seed lives in its `seed` field, and each pass draws its own
+//! stream from it with [`Program::rng`](crate::program::Program::rng),
+//! keyed by the pass's name, so adding or reordering passes does not change
+//! what the others produce. The same
+//! seed always gives the same output; the generator is part of the output
+//! format and will not change.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+/// xorshift64*: small, deterministic and dependency-free.
+#[derive(Clone, Debug)]
+pub struct Rng(u64);
+
+/// FNV-1a, stable across platforms and compiler versions, unlike the
+/// standard library's hashers.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    /// A generator for the stream `name` of `seed`, independent of the
+    /// other streams of the same seed.
+    pub fn stream(seed: u64, name: &str) -> Self {
+        Rng::new(seed ^ fnv1a(name.as_bytes()))
+    }
+
+    /// A seed that differs from run to run, for when none was given.
+    pub fn fresh_seed() -> u64 {
+        RandomState::new().build_hasher().finish()
+    }
+
+    /// A child generator for the sub-task `name`, leaving this one's
+    /// sequence as if it had drawn a single number.
+    pub fn fork(&mut self, name: &str) -> Self {
+        Rng::stream(self.next_u64(), name)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// A number in `0..n`. `n` must not be zero.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    pub fn pick<T: Clone>(&mut self, items: &[T]) -> T {
+        items[self.below(items.len())].clone()
+    }
+
+    pub fn chance(&mut self, one_in: usize) -> bool {
+        self.below(one_in) == 0
+    }
+
+    /// Fisher-Yates.
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            items.swap(i, self.below(i + 1));
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! A section for every function.
+//!
+//! [`function_sections`] splits the `text` sections of a program, as
+//! `-ffunction-sections` does, so that each function starts a section of
+//! its own named `text.` and the function's name. A linker can then drop
+//! the functions nothing refers to, as `ld --gc-sections` does, and order
+//! the rest as it likes:
+//!
+//! ```
+//! use cataclysm::{instr, sections::function_sections, AsmExpr, Label, Program, Section};
+//!
+//! let mut program = Program::default().with_section(Section::new(
+//!     "text",
+//!     vec![
+//!         AsmExpr::Label(Label::plain("main")),
+//!         instr::call(Label::plain("helper")),
+//!         instr::ret(),
+//!         AsmExpr::Label(Label::plain("helper")),
+//!         instr::ret(),
+//!     ],
+//! ));
+//! assert_eq!(function_sections(&mut program), 2);
+//! let names: Vec<&str> = program.sections.iter().map(|s| s.name.as_str()).collect();
+//! assert_eq!(names, ["text.main", "text.helper"]);
+//! assert!(program.to_string().contains("section .text.helper progbits alloc exec nowrite align=16"));
+//! ```
+//!
+//! A function is everything from a non-local label at the top level of
+//! the section to the next one. Where control could run on from one into
+//! the next, since what comes before the label does not jump away or
+//! return, the two stay together; so do labels naming the same code.
+
+use std::sync::Arc;
+
+use crate::{hint::NO_FALLTHROUGH, program::Program, AsmExpr, Section};
+
+/// Splits every section named `text` into one section per function, in
+/// place, and returns how many function sections it made. Anything before
+/// the first function keeps the section it was in.
+pub fn function_sections(program: &mut Program) -> usize {
+    let mut made = 0;
+    let mut sections = Vec::with_capacity(program.sections.len());
+    for section in std::mem::take(&mut program.sections) {
+        if section.name != "text" {
+            sections.push(section);
+            continue;
+        }
+        let piece = |name: String, body: Vec<AsmExpr>| Section {
+            name,
+            body: Arc::new(body),
+            ..section.clone()
+        };
+        let mut name = section.name.clone();
+        let mut body = Vec::new();
+        for expr in section.body.iter() {
+            if let AsmExpr::Label(label) = expr {
+                let local = label.label.starts_with('.') && !label.label.starts_with("..");
+                if !local && !falls_through(&body) {
+                    if !body.is_empty() {
+                        sections.push(piece(name, std::mem::take(&mut body)));
+                    }
+                    name = format!("text.{}", label.label);
+                    made += 1;
+                }
+            }
+            body.push(expr.clone());
+        }
+        if !body.is_empty() {
+            sections.push(piece(name, body));
+        }
+    }
+    program.sections = sections;
+    made
+}
+
+/// Whether control may run past the end of `body`: unless it ends with an
+/// instruction that jumps away or returns, or with data, which it cannot
+/// reach. An empty body starts a section, so nothing runs into it.
+fn falls_through(body: &[AsmExpr]) -> bool {
+    last_falls_through(body).unwrap_or(false)
+}
+
+/// Whether control may run past the last item of `body` there is, looking
+/// through blocks and regions.
+fn last_falls_through(body: &[AsmExpr]) -> Option<bool> {
+    for expr in body.iter().rev() {
+        match expr {
+            AsmExpr::Instruction(inst) => {
+                return Some(!NO_FALLTHROUGH.contains(&inst.mnemonic.as_str()));
+            }
+            AsmExpr::Data(_) => return Some(false),
+            AsmExpr::Block(inner) | AsmExpr::Region { body: inner, .. } => {
+                if let Some(falls) = last_falls_through(inner) {
+                    return Some(falls);
+                }
+            }
+            _ => return Some(true),
+        }
+    }
+    None
+}
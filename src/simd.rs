@@ -0,0 +1,247 @@
+//! Typed constructors for SSE and AVX moves and packed arithmetic.
+//!
+//! Each constructor is generic over the register width it operates on, so
+//! the operands have to agree: `vaddps` takes three ymm operands or three
+//! zmm operands, never a mix, and the legacy SSE forms take only xmm
+//! registers. Operands that may come from memory accept a
+//! [`VectorOperand`]; the rest are registers.
+//!
+//! ```
+//! use cataclysm::{register::Ymm, simd, Mem, consts::RSI};
+//!
+//! let load = simd::vmovups(Ymm::YMM0, Mem::base(RSI));
+//! let add = simd::vaddps(Ymm::YMM1, Ymm::YMM1, Ymm::YMM0);
+//! assert_eq!(add.to_string(), "\t\tvaddps\tymm1, ymm1, ymm0");
+//! # let _ = load;
+//! ```
+
+use crate::{
+    mnemonic::Amd64Mnemonic,
+    register::{Xmm, Ymm, Zmm},
+    Amd64Instruction, Amd64Register, AsmExpr, Mem, Operand,
+};
+
+/// An xmm, ymm or zmm register.
+pub trait VectorRegister: Copy + Into<Amd64Register> {}
+
+/// The registers VEX can encode, which the AVX integer logic and the
+/// `vmovdqa` family are limited to; their zmm forms are the EVEX
+/// `vpandd`, `vmovdqa32` and so on.
+pub trait VexRegister: VectorRegister {}
+
+impl VectorRegister for Xmm {}
+
+impl VectorRegister for Ymm {}
+
+impl VectorRegister for Zmm {}
+
+impl VexRegister for Xmm {}
+
+impl VexRegister for Ymm {}
+
+impl From<Xmm> for Amd64Register {
+    fn from(reg: Xmm) -> Self {
+        Amd64Register::Vector(reg)
+    }
+}
+
+impl From<Ymm> for Amd64Register {
+    fn from(reg: Ymm) -> Self {
+        Amd64Register::Ymm(reg)
+    }
+}
+
+impl From<Zmm> for Amd64Register {
+    fn from(reg: Zmm) -> Self {
+        Amd64Register::Zmm(reg)
+    }
+}
+
+/// A vector register of type `V`, or memory holding a value as wide.
+#[derive(Clone)]
+pub enum VectorOperand<V> {
+    Register(V),
+    Memory(Mem),
+}
+
+impl<V: VectorRegister> From<VectorOperand<V>> for Operand {
+    fn from(operand: VectorOperand<V>) -> Self {
+        match operand {
+            VectorOperand::Register(reg) => Operand::Register(reg.into()),
+            VectorOperand::Memory(mem) => Operand::Memory(mem),
+        }
+    }
+}
+
+macro_rules! vector_operand {
+    ($($reg:ty),*) => {
+        $(
+            impl From<$reg> for VectorOperand<$reg> {
+                fn from(reg: $reg) -> Self {
+                    VectorOperand::Register(reg)
+                }
+            }
+
+            impl From<$reg> for Operand {
+                fn from(reg: $reg) -> Self {
+                    Operand::Register(reg.into())
+                }
+            }
+        )*
+    };
+}
+
+vector_operand!(Xmm, Ymm, Zmm);
+
+impl<V> From<Mem> for VectorOperand<V> {
+    fn from(mem: Mem) -> Self {
+        VectorOperand::Memory(mem)
+    }
+}
+
+fn inst(mnemonic: &str, operands: Vec<Operand>) -> AsmExpr {
+    AsmExpr::Instruction(Amd64Instruction::new(
+        Amd64Mnemonic::parse(mnemonic),
+        operands,
+    ))
+}
+
+fn reg(reg: impl Into<Amd64Register>) -> Operand {
+    Operand::Register(reg.into())
+}
+
+/// Moves between registers and memory; one side must be a register.
+macro_rules! moves {
+    ($bound:ident: $($name:ident => $mnemonic:literal),* $(,)?) => {
+        $(
+            pub fn $name<V: $bound>(
+                dst: impl Into<VectorOperand<V>>,
+                src: impl Into<VectorOperand<V>>,
+            ) -> AsmExpr {
+                inst($mnemonic, vec![dst.into().into(), src.into().into()])
+            }
+        )*
+    };
+}
+
+/// Legacy SSE arithmetic, which overwrites its first source.
+macro_rules! sse {
+    ($($name:ident => $mnemonic:literal),* $(,)?) => {
+        $(
+            pub fn $name(dst: Xmm, src: impl Into<VectorOperand<Xmm>>) -> AsmExpr {
+                inst($mnemonic, vec![reg(dst), src.into().into()])
+            }
+        )*
+    };
+}
+
+/// Non-destructive AVX arithmetic: `dst = a op b`.
+macro_rules! avx {
+    ($bound:ident: $($name:ident => $mnemonic:literal),* $(,)?) => {
+        $(
+            pub fn $name<V: $bound>(dst: V, a: V, b: impl Into<VectorOperand<V>>) -> AsmExpr {
+                inst($mnemonic, vec![reg(dst), reg(a), b.into().into()])
+            }
+        )*
+    };
+}
+
+/// Legacy moves, limited to xmm registers.
+macro_rules! sse_moves {
+    ($($name:ident => $mnemonic:literal),* $(,)?) => {
+        $(
+            pub fn $name(
+                dst: impl Into<VectorOperand<Xmm>>,
+                src: impl Into<VectorOperand<Xmm>>,
+            ) -> AsmExpr {
+                inst($mnemonic, vec![dst.into().into(), src.into().into()])
+            }
+        )*
+    };
+}
+
+sse_moves! {
+    movaps => "movaps",
+    movups => "movups",
+    movapd => "movapd",
+    movupd => "movupd",
+    movdqa => "movdqa",
+    movdqu => "movdqu",
+}
+
+sse! {
+    addps => "addps",
+    addpd => "addpd",
+    subps => "subps",
+    subpd => "subpd",
+    mulps => "mulps",
+    mulpd => "mulpd",
+    divps => "divps",
+    divpd => "divpd",
+    andps => "andps",
+    andpd => "andpd",
+    orps => "orps",
+    orpd => "orpd",
+    xorps => "xorps",
+    xorpd => "xorpd",
+    pand => "pand",
+    por => "por",
+    pxor => "pxor",
+    paddd => "paddd",
+    paddq => "paddq",
+    psubd => "psubd",
+    psubq => "psubq",
+}
+
+moves! {
+    VectorRegister:
+    vmovaps => "vmovaps",
+    vmovups => "vmovups",
+    vmovapd => "vmovapd",
+    vmovupd => "vmovupd",
+    vmovdqa32 => "vmovdqa32",
+    vmovdqa64 => "vmovdqa64",
+    vmovdqu32 => "vmovdqu32",
+    vmovdqu64 => "vmovdqu64",
+}
+
+moves! {
+    VexRegister:
+    vmovdqa => "vmovdqa",
+    vmovdqu => "vmovdqu",
+}
+
+avx! {
+    VectorRegister:
+    vaddps => "vaddps",
+    vaddpd => "vaddpd",
+    vsubps => "vsubps",
+    vsubpd => "vsubpd",
+    vmulps => "vmulps",
+    vmulpd => "vmulpd",
+    vdivps => "vdivps",
+    vdivpd => "vdivpd",
+    vandps => "vandps",
+    vandpd => "vandpd",
+    vorps => "vorps",
+    vorpd => "vorpd",
+    vxorps => "vxorps",
+    vxorpd => "vxorpd",
+    vpaddd => "vpaddd",
+    vpaddq => "vpaddq",
+    vpsubd => "vpsubd",
+    vpsubq => "vpsubq",
+    vpandd => "vpandd",
+    vpandq => "vpandq",
+    vpord => "vpord",
+    vporq => "vporq",
+    vpxord => "vpxord",
+    vpxorq => "vpxorq",
+}
+
+avx! {
+    VexRegister:
+    vpand => "vpand",
+    vpor => "vpor",
+    vpxor => "vpxor",
+}
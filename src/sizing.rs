@@ -0,0 +1,106 @@
+//! Memory operands whose size the instruction leaves open.
+//!
+//! In `mov [rbx], rax` the register says how much memory is written, but
+//! in `mov [rbx], 5` or `inc [rbx]` only a size keyword does. The
+//! text output writes the keyword from the section's
+//! [`Defaults::operand_size`](crate::Defaults::operand_size), and
+//! [`check`] reports the operands left unsized in sections that ask for
+//! [`strict_sizing`](crate::Defaults::strict_sizing).
+
+use std::{error, fmt};
+
+use crate::{program::Program, Amd64Instruction, AsmExpr, Operand};
+
+/// Instructions that read or write a memory operand of any size, so that
+/// without a register to match, the operand needs a size keyword.
+const SIZED: &[&str] = &[
+    "mov", "add", "or", "adc", "sbb", "and", "sub", "xor", "cmp", "test", "inc", "dec", "neg",
+    "not", "mul", "imul", "div", "idiv", "bt", "bts", "btr", "btc",
+];
+
+/// Shifts and rotates, whose count in `cl` says nothing about the size of
+/// what is shifted.
+const SHIFTS: &[&str] = &["shl", "sal", "shr", "sar", "rol", "ror", "rcl", "rcr"];
+
+impl Amd64Instruction {
+    /// The index of the memory operand nothing else gives a size, if any.
+    pub fn unsized_memory(&self) -> Option<usize> {
+        let mnemonic = self.mnemonic.as_str().split_whitespace().last()?;
+        let shift = SHIFTS.contains(&mnemonic);
+        if !shift && !SIZED.contains(&mnemonic) {
+            return None;
+        }
+        let mut memory = None;
+        for (i, operand) in self.operands.iter().enumerate() {
+            match operand {
+                Operand::Memory(mem) if mem.size.is_some() => return None,
+                Operand::Memory(_) => memory = Some(i),
+                Operand::Register(_) if shift && i == 1 => {}
+                Operand::Register(_) | Operand::Param(_) => return None,
+                Operand::Immediate(_) => {}
+            }
+        }
+        memory
+    }
+}
+
+/// An instruction [`check`] found with an unsized memory operand.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizingError {
+    pub section: String,
+    /// The instruction, as emitted.
+    pub instruction: String,
+}
+
+impl fmt::Display for SizingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "`{}` in section .{}: nothing gives the memory operand a size",
+            self.instruction.replace('\t', " "),
+            self.section
+        )
+    }
+}
+
+impl error::Error for SizingError {}
+
+/// Reports every unsized memory operand, in both arms of each conditional,
+/// in the sections whose defaults ask for strict sizing but give no
+/// operand size.
+pub fn check(program: &Program) -> Result<(), Vec<SizingError>> {
+    let mut errors = Vec::new();
+
+    for section in &program.sections {
+        let defaults = program.defaults_of(section);
+        if defaults.strict_sizing && defaults.operand_size.is_none() {
+            check_body(&section.body, &section.name, &mut errors);
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn check_body(body: &[AsmExpr], section: &str, errors: &mut Vec<SizingError>) {
+    for expr in body {
+        match expr {
+            AsmExpr::Instruction(inst) => {
+                if inst.unsized_memory().is_some() {
+                    errors.push(SizingError {
+                        section: section.to_string(),
+                        instruction: inst.to_string(),
+                    });
+                }
+            }
+            _ => {
+                for inner in expr.bodies() {
+                    check_body(inner, section, errors);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,730 @@
+//! Spill-slot coalescing: frame slots whose contents are never needed at
+//! the same time share one, and the frame shrinks to fit.
+//!
+//! A function qualifies if it sets up an `rbp` frame with a constant
+//! `sub rsp, N` and reaches its locals only as 8-byte `[rbp - d]` operands
+//! at multiples of eight. Anything that could see the frame as memory
+//! rather than as slots, such as taking the address of a slot, an
+//! `rsp`-relative operand or a vector access, leaves the function as it
+//! is. Slots are live from a store to their last load, as computed over
+//! the function's control-flow graph under the build configuration.
+//!
+//! Slots holding nothing but a constant or a label address need not be
+//! slots at all: [`rematerialize`] recomputes the value at each reload.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::{
+    cfg::{Cfg, Function},
+    cond::BuildConfig,
+    dataflow::{self, constant},
+    expr::ConstExpr,
+    mnemonic::Amd64Mnemonic,
+    program::Program,
+    qualify_label,
+    register::{Gpr, GprWidth},
+    Amd64Instruction, Amd64Register, AsmExpr, ImmediateValue, Label, Mem, Operand,
+};
+
+/// How one function's frame was compacted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FrameCompaction {
+    pub function: String,
+    pub old_size: u64,
+    pub new_size: u64,
+    /// The new displacement from rbp of each slot, by old displacement.
+    pub slots: BTreeMap<i64, i64>,
+}
+
+/// Instructions whose memory operand is exactly as wide as their register
+/// operand, or eight bytes with an immediate or on its own.
+const SLOT_MNEMONICS: &[&str] = &[
+    "mov", "add", "adc", "sub", "sbb", "and", "or", "xor", "cmp", "test", "xchg", "imul", "push",
+    "pop", "inc", "dec", "neg", "not", "mul", "div", "idiv",
+];
+
+/// The frame slots one instruction reads and the one it overwrites.
+#[derive(Default)]
+struct Access {
+    uses: Vec<i64>,
+    def: Option<i64>,
+}
+
+/// The frame size of a function with an `rbp` frame: the `N` of its one
+/// `sub rsp, N` after `mov rbp, rsp`.
+fn frame_size(function: &Function, defines: &HashMap<String, ConstExpr>) -> Option<u64> {
+    let entry = &function.blocks.first()?.instructions;
+    let frame = entry.iter().position(|inst| {
+        inst.mnemonic == "mov"
+            && matches!(inst.operands.as_slice(), [Operand::Register(d), Operand::Register(s)]
+                if d.gpr() == Some(Gpr::RBP) && s.gpr() == Some(Gpr::RSP))
+    })?;
+    entry[frame + 1..]
+        .iter()
+        .find_map(|inst| match inst.operands.as_slice() {
+            [Operand::Register(r), n] if inst.mnemonic == "sub" && r.gpr() == Some(Gpr::RSP) => {
+                constant(n, defines).and_then(|n| u64::try_from(n).ok())
+            }
+            _ => None,
+        })
+}
+
+/// The slots `inst` touches, or `None` if it uses the frame in a way the
+/// pass cannot follow.
+fn access(inst: &Amd64Instruction, size: u64) -> Option<Access> {
+    let mut access = Access::default();
+    let mut touches_frame = false;
+    for (i, op) in inst.operands.iter().enumerate() {
+        let Operand::Memory(mem) = op else {
+            continue;
+        };
+        let base = mem.base.as_ref().and_then(Amd64Register::gpr);
+        let index = mem.index.as_ref().and_then(Amd64Register::gpr);
+        if base == Some(Gpr::RSP) || index == Some(Gpr::RSP) || index == Some(Gpr::RBP) {
+            return None;
+        }
+        if base != Some(Gpr::RBP) {
+            continue;
+        }
+        // Arguments and the return address above the frame stay put.
+        let d = mem.displacement;
+        if d >= 0 {
+            continue;
+        }
+        if mem.index.is_some() || d % 8 != 0 || d.unsigned_abs() > size {
+            return None;
+        }
+        touches_frame = true;
+        if i == 0 && inst.mnemonic == "mov" {
+            access.def = Some(d);
+        } else {
+            access.uses.push(d);
+        }
+    }
+    if !touches_frame {
+        return Some(access);
+    }
+
+    // The access must be exactly one slot wide.
+    let sized = inst.operands.iter().all(|op| match op {
+        Operand::Memory(mem) => mem.size.is_none_or(|size| size == GprWidth::Qword),
+        Operand::Immediate(_) => true,
+        Operand::Register(r) => r.gpr().is_some(),
+        _ => false,
+    });
+    (sized && SLOT_MNEMONICS.contains(&inst.mnemonic.as_str())).then_some(access)
+}
+
+/// Instructions that change rsp in ways other than the frame's own `add`
+/// and `sub`, `push`, `pop`, `call` and `ret`, or copy it.
+fn moves_rsp(inst: &Amd64Instruction, old: u64, defines: &HashMap<String, ConstExpr>) -> bool {
+    let is_rsp = |op: &Operand| matches!(op, Operand::Register(r) if r.gpr() == Some(Gpr::RSP));
+    match (inst.mnemonic.as_str(), inst.operands.as_slice()) {
+        ("sub" | "add", [dst, n]) if is_rsp(dst) => {
+            constant(n, defines).and_then(|n| u64::try_from(n).ok()) != Some(old)
+        }
+        // `mov rbp, rsp` and `mov rsp, rbp` bracket the frame.
+        ("mov", [dst, src]) => {
+            let rbp =
+                |op: &Operand| matches!(op, Operand::Register(r) if r.gpr() == Some(Gpr::RBP));
+            (is_rsp(dst) && !rbp(src)) || (is_rsp(src) && !rbp(dst))
+        }
+        ("push" | "pop" | "call" | "ret" | "leave", _) => false,
+        _ => inst.operands.iter().any(is_rsp),
+    }
+}
+
+/// The slots of a qualifying function, how each instruction touches them
+/// and which are live where.
+struct Frame {
+    size: u64,
+    /// Per block, per instruction.
+    accesses: Vec<Vec<Access>>,
+    slots: BTreeSet<i64>,
+    live_in: Vec<BTreeSet<i64>>,
+    live_out: Vec<BTreeSet<i64>>,
+}
+
+fn step(live: &mut BTreeSet<i64>, access: &Access) {
+    if let Some(d) = access.def {
+        live.remove(&d);
+    }
+    live.extend(&access.uses);
+}
+
+/// Analyzes the frame of `function`, or `None` if it does not qualify.
+fn frame(function: &Function, defines: &HashMap<String, ConstExpr>) -> Option<Frame> {
+    let size = frame_size(function, defines)?;
+    let mut accesses = Vec::new();
+    for block in &function.blocks {
+        let mut block_accesses = Vec::new();
+        for inst in &block.instructions {
+            if moves_rsp(inst, size, defines) {
+                return None;
+            }
+            block_accesses.push(access(inst, size)?);
+        }
+        accesses.push(block_accesses);
+    }
+    let slots: BTreeSet<i64> = accesses
+        .iter()
+        .flatten()
+        .flat_map(|a| a.uses.iter().copied().chain(a.def))
+        .collect();
+
+    // Slot liveness, backwards over the blocks until it settles. Control
+    // leaving the function some other way than returning keeps every slot.
+    let n = function.blocks.len();
+    let mut live_in = vec![BTreeSet::new(); n];
+    let mut live_out = vec![BTreeSet::new(); n];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, block) in function.blocks.iter().enumerate().rev() {
+            let mut live = if block.exits {
+                slots.clone()
+            } else {
+                BTreeSet::new()
+            };
+            for &(to, _) in &block.successors {
+                live.extend(&live_in[to]);
+            }
+            live_out[i] = live.clone();
+            for access in accesses[i].iter().rev() {
+                step(&mut live, access);
+            }
+            if live != live_in[i] {
+                live_in[i] = live;
+                changed = true;
+            }
+        }
+    }
+
+    Some(Frame {
+        size,
+        accesses,
+        slots,
+        live_in,
+        live_out,
+    })
+}
+
+/// Coalesces the slots of one function, or `None` if it does not qualify
+/// or nothing would shrink.
+fn coalesce(function: &Function, defines: &HashMap<String, ConstExpr>) -> Option<FrameCompaction> {
+    let Frame {
+        size: old_size,
+        accesses,
+        slots,
+        live_in,
+        live_out,
+    } = frame(function, defines)?;
+    let n = function.blocks.len();
+
+    // A slot written while another is live must not share with it. Slots
+    // read before any write hold whatever was there, so those live on
+    // entry all conflict as well.
+    let mut conflicts: HashMap<i64, BTreeSet<i64>> = HashMap::new();
+    let mut conflict = |a: i64, b: i64| {
+        if a != b {
+            conflicts.entry(a).or_default().insert(b);
+            conflicts.entry(b).or_default().insert(a);
+        }
+    };
+    for i in 0..n {
+        let mut live = live_out[i].clone();
+        for access in accesses[i].iter().rev() {
+            if let Some(d) = access.def {
+                for &other in &live {
+                    conflict(d, other);
+                }
+            }
+            step(&mut live, access);
+        }
+    }
+    for &a in &live_in[0] {
+        for &b in &live_in[0] {
+            conflict(a, b);
+        }
+    }
+
+    // Give each slot, nearest rbp first, the lowest new slot none of its
+    // conflicts has.
+    let mut assigned: BTreeMap<i64, i64> = BTreeMap::new();
+    for &slot in slots.iter().rev() {
+        let taken: BTreeSet<i64> = conflicts
+            .get(&slot)
+            .into_iter()
+            .flatten()
+            .filter_map(|other| assigned.get(other).copied())
+            .collect();
+        let new = (1..).map(|k| -8 * k).find(|d| !taken.contains(d)).unwrap();
+        assigned.insert(slot, new);
+    }
+
+    // Keep rsp's alignment modulo 16 as it was.
+    let used = assigned
+        .values()
+        .map(|d| d.unsigned_abs())
+        .max()
+        .unwrap_or(0);
+    let new_size = used + (old_size.wrapping_sub(used) % 16);
+    if new_size >= old_size {
+        return None;
+    }
+    Some(FrameCompaction {
+        function: function.name.clone(),
+        old_size,
+        new_size,
+        slots: assigned,
+    })
+}
+
+/// Coalesces the spill slots of every function that qualifies and shrinks
+/// its frame, returning what changed.
+pub fn compact_frames(program: &mut Program) -> Vec<FrameCompaction> {
+    let defines = program.define_map();
+    let cfg = Cfg::build(program);
+    let compactions: Vec<FrameCompaction> = cfg
+        .functions
+        .iter()
+        .filter_map(|f| coalesce(f, &defines))
+        .collect();
+    if compactions.is_empty() {
+        return compactions;
+    }
+
+    edit_functions(
+        program,
+        &cfg,
+        |name| compactions.iter().find(|c| c.function == name),
+        |compaction, inst| {
+            let resizes = matches!(inst.mnemonic.as_str(), "sub" | "add")
+                && matches!(&inst.operands[..], [Operand::Register(r), _] if r.gpr() == Some(Gpr::RSP));
+            for op in inst.operands.iter_mut() {
+                match op {
+                    Operand::Memory(mem)
+                        if mem.base.as_ref().and_then(Amd64Register::gpr) == Some(Gpr::RBP) =>
+                    {
+                        if let Some(&new) = compaction.slots.get(&mem.displacement) {
+                            mem.displacement = new;
+                        }
+                    }
+                    Operand::Immediate(_)
+                        if resizes
+                            && constant(op, &defines) == Some(compaction.old_size as i64) =>
+                    {
+                        *op = Operand::Immediate(ImmediateValue::U64(compaction.new_size));
+                    }
+                    _ => {}
+                }
+            }
+            true
+        },
+    );
+    compactions
+}
+
+/// A value cheap enough to compute again wherever it is needed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Remat {
+    Constant(i64),
+    /// The address of a label, taken with `lea reg, [rel label]`.
+    Address(String),
+}
+
+/// A slot whose reloads were replaced by recomputing its value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rematerialization {
+    pub function: String,
+    pub slot: i64,
+    pub value: Remat,
+    /// How many reloads were rewritten.
+    pub reloads: usize,
+}
+
+/// What a store to a slot writes, if it is a value worth recomputing.
+fn stored_value(
+    inst: &Amd64Instruction,
+    known: &HashMap<Gpr, Remat>,
+    defines: &HashMap<String, ConstExpr>,
+) -> Option<Remat> {
+    match &inst.operands[1] {
+        Operand::Register(r) => known.get(&r.gpr()?).cloned(),
+        op => constant(op, defines).map(Remat::Constant),
+    }
+}
+
+/// Whether `inst` is `mov [rbp + slot], src`, or `mov reg, [rbp + slot]`.
+fn slot_move(inst: &Amd64Instruction, slot: i64) -> Option<bool> {
+    let at = |op: &Operand| matches!(op, Operand::Memory(m) if m.displacement == slot);
+    match inst.operands.as_slice() {
+        [dst, _] if inst.mnemonic == "mov" && at(dst) => Some(true),
+        [Operand::Register(r), src] if inst.mnemonic == "mov" && at(src) && r.gpr().is_some() => {
+            Some(false)
+        }
+        _ => None,
+    }
+}
+
+/// The stores to a slot, each with the value it writes if that is cheap,
+/// and the number of reloads.
+#[derive(Default)]
+struct SlotMoves {
+    stores: Vec<Option<Remat>>,
+    reloads: usize,
+}
+
+/// The slots of one function that only ever hold one cheap value.
+fn remat_slots(
+    function: &Function,
+    defines: &HashMap<String, ConstExpr>,
+) -> Vec<Rematerialization> {
+    let Some(frame) = frame(function, defines) else {
+        return Vec::new();
+    };
+    // Code elsewhere could read the frame after an exit.
+    if function.blocks.iter().any(|b| b.exits) {
+        return Vec::new();
+    }
+
+    // Per slot, `None` once something other than a plain store or reload
+    // touches it. Register values are only followed within a block.
+    let mut found: BTreeMap<i64, Option<SlotMoves>> = frame
+        .slots
+        .iter()
+        .map(|&s| (s, Some(SlotMoves::default())))
+        .collect();
+    for (block, accesses) in function.blocks.iter().zip(&frame.accesses) {
+        let mut known: HashMap<Gpr, Remat> = HashMap::new();
+        for (inst, access) in block.instructions.iter().zip(accesses) {
+            for slot in access.uses.iter().chain(&access.def) {
+                let entry = found.get_mut(slot).unwrap();
+                match (slot_move(inst, *slot), entry.as_mut()) {
+                    (Some(true), Some(moves)) => {
+                        moves.stores.push(stored_value(inst, &known, defines))
+                    }
+                    (Some(false), Some(moves)) => moves.reloads += 1,
+                    _ => *entry = None,
+                }
+            }
+
+            let value = match (inst.mnemonic.as_str(), inst.operands.as_slice()) {
+                ("mov", [Operand::Register(_), src]) => constant(src, defines).map(Remat::Constant),
+                ("lea", [Operand::Register(_), Operand::Memory(mem)]) => match mem {
+                    Mem {
+                        base: None,
+                        index: None,
+                        label: Some(label),
+                        displacement: 0,
+                        ..
+                    } if !label.label.starts_with('.') => Some(Remat::Address(label.label.clone())),
+                    _ => None,
+                },
+                _ => None,
+            };
+            for reg in dataflow::effects(inst).defs.iter() {
+                known.remove(&reg);
+            }
+            if let (Some(value), [Operand::Register(r), _]) = (value, inst.operands.as_slice()) {
+                known.extend(r.gpr().map(|g| (g, value)));
+            }
+        }
+    }
+
+    found
+        .into_iter()
+        .filter(|(slot, _)| !frame.live_in[0].contains(slot))
+        .filter_map(|(slot, entry)| {
+            let SlotMoves { stores, reloads } = entry?;
+            let first = stores.first()?.clone()?;
+            stores
+                .iter()
+                .all(|s| s.as_ref() == Some(&first))
+                .then(|| Rematerialization {
+                    function: function.name.clone(),
+                    slot,
+                    value: first,
+                    reloads,
+                })
+        })
+        .collect()
+}
+
+/// Replaces the reloads of every spill slot that only ever holds one
+/// constant or label address with an instruction that recomputes it, and
+/// drops the stores. Run [`compact_frames`] afterwards to reclaim the
+/// slots.
+pub fn rematerialize(program: &mut Program) -> Vec<Rematerialization> {
+    let defines = program.define_map();
+    let cfg = Cfg::build(program);
+    let remats: Vec<Rematerialization> = cfg
+        .functions
+        .iter()
+        .flat_map(|f| remat_slots(f, &defines))
+        .collect();
+    if remats.is_empty() {
+        return remats;
+    }
+
+    let plans: HashMap<&str, HashMap<i64, &Remat>> =
+        remats.iter().fold(HashMap::new(), |mut plans, r| {
+            plans
+                .entry(r.function.as_str())
+                .or_default()
+                .insert(r.slot, &r.value);
+            plans
+        });
+    edit_functions(
+        program,
+        &cfg,
+        |name| plans.get(name),
+        |plan, inst| {
+            let Some((store, value)) = plan
+                .iter()
+                .find_map(|(&slot, value)| slot_move(inst, slot).map(|store| (store, value)))
+            else {
+                return true;
+            };
+            if store {
+                return false;
+            }
+            let dst = inst.operands[0].clone();
+            *inst = match value {
+                Remat::Constant(n) => {
+                    Amd64Instruction::new(Amd64Mnemonic::Mov, vec![dst, (*n).into()])
+                }
+                Remat::Address(label) => Amd64Instruction::new(
+                    Amd64Mnemonic::Lea,
+                    vec![dst, Operand::Memory(Mem::label(Label::plain(label)))],
+                ),
+            };
+            true
+        },
+    );
+    remats
+}
+
+/// Applies `edit` to every instruction of each function `plan` returns
+/// something for, walking the text sections as the control-flow graph was
+/// built from them. Instructions `edit` returns false for are removed.
+fn edit_functions<'a, T: 'a>(
+    program: &mut Program,
+    cfg: &Cfg,
+    plan: impl Fn(&str) -> Option<&'a T>,
+    edit: impl Fn(&T, &mut Amd64Instruction) -> bool,
+) {
+    let starts: HashMap<&str, Option<&T>> = cfg
+        .functions
+        .iter()
+        .map(|f| (f.name.as_str(), plan(&f.name)))
+        .collect();
+    let mut walk = Walk {
+        starts,
+        current: None,
+        scope: String::new(),
+        edit: &edit,
+    };
+    let config = program.config.clone();
+    for section in program
+        .sections
+        .iter_mut()
+        .filter(|s| s.name.starts_with("text"))
+    {
+        walk.body(section.body_mut(), &config);
+    }
+}
+
+struct Walk<'a, T> {
+    starts: HashMap<&'a str, Option<&'a T>>,
+    current: Option<&'a T>,
+    scope: String,
+    edit: &'a dyn Fn(&T, &mut Amd64Instruction) -> bool,
+}
+
+impl<T> Walk<'_, T> {
+    fn body(&mut self, body: &mut Vec<AsmExpr>, config: &BuildConfig) {
+        body.retain_mut(|expr| {
+            match expr {
+                AsmExpr::Label(label) => {
+                    if !label.label.starts_with('.') {
+                        self.scope = label.label.clone();
+                    }
+                    if let Some(&start) = self
+                        .starts
+                        .get(qualify_label(&self.scope, &label.label).as_str())
+                    {
+                        self.current = start;
+                    }
+                }
+                AsmExpr::Instruction(inst) => {
+                    if let Some(plan) = self.current {
+                        return (self.edit)(plan, inst);
+                    }
+                }
+                AsmExpr::If {
+                    cond,
+                    then,
+                    otherwise,
+                } => {
+                    let arm = if cond.eval(config) { then } else { otherwise };
+                    self.body(arm, config);
+                }
+                AsmExpr::Block(inner)
+                | AsmExpr::Region { body: inner, .. }
+                | AsmExpr::Macro { body: inner, .. } => self.body(inner, config),
+                _ => {}
+            }
+            true
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        asm_dsl,
+        consts::{RBP, RSP},
+        instr::{leave, mov, push, ret, sub},
+        Section,
+    };
+
+    /// A global `f` with `body` between an rbp frame's setup, with `size` bytes of locals,
+    /// and its teardown.
+    fn function(size: u32, body: Vec<AsmExpr>) -> Program {
+        let text = [
+            vec![AsmExpr::Label(Label::plain("f"))],
+            vec![push(RBP), mov(RBP, RSP), sub(RSP, size)],
+            body,
+            vec![leave(), ret()],
+        ]
+        .concat();
+        Program::default()
+            .with_global("f")
+            .with_section(Section::new("text", text))
+    }
+
+    fn lines(program: &Program) -> Vec<String> {
+        program.sections[0]
+            .body
+            .iter()
+            .map(|expr| expr.to_string().trim().replace('\t', " "))
+            .collect()
+    }
+
+    #[test]
+    fn slots_never_live_together_share() {
+        let mut program = function(
+            32,
+            asm_dsl! {
+                mov [rbp - 8], rdi;
+                mov rax, [rbp - 8];
+                mov [rbp - 24], rsi;
+                add rax, [rbp - 24];
+            },
+        );
+        let compactions = compact_frames(&mut program);
+        assert_eq!(
+            compactions,
+            [FrameCompaction {
+                function: "f".to_string(),
+                old_size: 32,
+                new_size: 16,
+                slots: BTreeMap::from([(-24, -8), (-8, -8)]),
+            }]
+        );
+        assert_eq!(
+            lines(&program)[3..8],
+            [
+                "sub rsp, 16",
+                "mov [rbp - 8], rdi",
+                "mov rax, [rbp - 8]",
+                "mov [rbp - 8], rsi",
+                "add rax, [rbp - 8]",
+            ]
+        );
+    }
+
+    #[test]
+    fn slots_live_together_stay_apart() {
+        let mut program = function(
+            32,
+            asm_dsl! {
+                mov [rbp - 8], rdi;
+                mov [rbp - 24], rsi;
+                mov rax, [rbp - 8];
+                add rax, [rbp - 24];
+            },
+        );
+        let compactions = compact_frames(&mut program);
+        assert_eq!(compactions[0].slots, BTreeMap::from([(-24, -16), (-8, -8)]));
+        assert_eq!(compactions[0].new_size, 16);
+    }
+
+    #[test]
+    fn frames_seen_as_memory_are_left_alone() {
+        let body = asm_dsl! {
+            mov [rbp - 8], rdi;
+            mov rax, [rbp - 8];
+            lea rdi, [rbp - 24];
+        };
+        let mut program = function(32, body);
+        assert_eq!(compact_frames(&mut program), []);
+    }
+
+    #[test]
+    fn constant_slots_are_recomputed_at_each_reload() {
+        let mut program = function(
+            16,
+            asm_dsl! {
+                mov rax, 5;
+                mov [rbp - 8], rax;
+                mov rcx, [rbp - 8];
+                mov rdx, [rbp - 8];
+            },
+        );
+        assert_eq!(
+            rematerialize(&mut program),
+            [Rematerialization {
+                function: "f".to_string(),
+                slot: -8,
+                value: Remat::Constant(5),
+                reloads: 2,
+            }]
+        );
+        assert_eq!(
+            lines(&program)[4..7],
+            ["mov rax, 5", "mov rcx, 5", "mov rdx, 5"]
+        );
+    }
+
+    #[test]
+    fn address_slots_are_reloaded_with_lea() {
+        let mut program = function(
+            16,
+            asm_dsl! {
+                lea rax, [rel table];
+                mov [rbp - 8], rax;
+                mov rsi, [rbp - 8];
+            },
+        );
+        let remats = rematerialize(&mut program);
+        assert_eq!(remats[0].value, Remat::Address("table".to_string()));
+        assert_eq!(lines(&program)[5], "lea rsi, [rel table]");
+    }
+
+    #[test]
+    fn slots_stored_different_values_stay() {
+        let mut program = function(
+            16,
+            asm_dsl! {
+                mov [rbp - 8], 1;
+                mov rcx, [rbp - 8];
+                mov [rbp - 8], rdi;
+                mov rdx, [rbp - 8];
+            },
+        );
+        assert_eq!(rematerialize(&mut program), []);
+    }
+}
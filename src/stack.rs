@@ -0,0 +1,311 @@
+//! Stack-balance verification.
+//!
+//! The stack depth is followed along every control-flow path of each
+//! function through `push`, `pop`, constant `add`/`sub` of rsp and `rbp`
+//! frames torn down by `leave` or `mov rsp, rbp`. Every `ret` must be
+//! reached with nothing left on the stack, and paths that meet must agree
+//! on the depth. Paths along which rsp changes in a way that cannot be
+//! followed statically are not checked past that point.
+
+use std::{collections::HashMap, error, fmt};
+
+use crate::{
+    cfg::{BasicBlock, Cfg, Function},
+    dataflow::{self, constant},
+    expr::ConstExpr,
+    program::Program,
+    register::Gpr,
+    Amd64Instruction, Operand,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StackError {
+    /// Two paths reach the same block with different depths.
+    Inconsistent {
+        function: String,
+        block: String,
+        depths: (i64, i64),
+    },
+    /// A `ret` is reached with `depth` bytes still pushed, or with
+    /// `-depth` bytes of the caller's frame popped.
+    Unbalanced {
+        function: String,
+        block: String,
+        depth: i64,
+    },
+}
+
+impl fmt::Display for StackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StackError::Inconsistent {
+                function,
+                block,
+                depths: (a, b),
+            } => write!(
+                f,
+                "{}: paths reach {} with stack depths {} and {}",
+                function, block, a, b
+            ),
+            StackError::Unbalanced {
+                function,
+                block,
+                depth,
+            } if *depth > 0 => write!(
+                f,
+                "{}: returns from {} with {} bytes left on the stack",
+                function, block, depth
+            ),
+            StackError::Unbalanced {
+                function,
+                block,
+                depth,
+            } => write!(
+                f,
+                "{}: returns from {} having popped {} bytes too many",
+                function, block, -depth
+            ),
+        }
+    }
+}
+
+impl error::Error for StackError {}
+
+/// The stack as far as it can be followed: bytes pushed since function
+/// entry, and the depth `rbp` was set to, if it holds a frame pointer.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Depth {
+    rsp: i64,
+    rbp: Option<i64>,
+}
+
+/// Confirms every function of `program` returns with a balanced stack
+/// along all paths.
+pub fn check_stack_balance(program: &Program) -> Result<(), Vec<StackError>> {
+    let defines = program.define_map();
+    let mut errors = Vec::new();
+
+    for function in &Cfg::build(program).functions {
+        check_function(function, &defines, &mut errors);
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn block_name(function: &Function, index: usize) -> String {
+    match function.blocks[index].labels.first() {
+        Some(label) => label.clone(),
+        None => format!("block {} of {}", index, function.name),
+    }
+}
+
+fn check_function(
+    function: &Function,
+    defines: &HashMap<String, ConstExpr>,
+    errors: &mut Vec<StackError>,
+) {
+    let blocks = &function.blocks;
+    let mut depth_in: Vec<Option<Depth>> = vec![None; blocks.len()];
+    let mut conflicts: Vec<Option<(i64, i64)>> = vec![None; blocks.len()];
+    depth_in[0] = Some(Depth { rsp: 0, rbp: None });
+
+    // The first path to reach a block fixes its depth; later ones can only
+    // conflict with it or forget its frame pointer, so this terminates.
+    let mut work = vec![0];
+    while let Some(i) = work.pop() {
+        let Some(out) = depth_in[i].and_then(|d| block_out(&blocks[i], d, defines)) else {
+            continue;
+        };
+        for &(to, _) in &blocks[i].successors {
+            match depth_in[to] {
+                None => {
+                    depth_in[to] = Some(out);
+                    work.push(to);
+                }
+                Some(old) if old.rsp != out.rsp => {
+                    conflicts[to].get_or_insert((old.rsp, out.rsp));
+                }
+                Some(old) if old.rbp != out.rbp && old.rbp.is_some() => {
+                    depth_in[to] = Some(Depth { rbp: None, ..old });
+                    work.push(to);
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    for (i, conflict) in conflicts.into_iter().enumerate() {
+        if let Some(depths) = conflict {
+            errors.push(StackError::Inconsistent {
+                function: function.name.clone(),
+                block: block_name(function, i),
+                depths,
+            });
+        }
+    }
+
+    for (i, block) in blocks.iter().enumerate() {
+        let returns = block
+            .instructions
+            .last()
+            .is_some_and(|inst| inst.mnemonic == "ret");
+        let before_ret = &block.instructions[..block.instructions.len().saturating_sub(1)];
+        let depth = depth_in[i].and_then(|d| {
+            before_ret
+                .iter()
+                .try_fold(d, |d, inst| step(inst, d, defines))
+        });
+        if let (true, Some(depth)) = (returns, depth) {
+            if depth.rsp != 0 {
+                errors.push(StackError::Unbalanced {
+                    function: function.name.clone(),
+                    block: block_name(function, i),
+                    depth: depth.rsp,
+                });
+            }
+        }
+    }
+}
+
+fn block_out(
+    block: &BasicBlock,
+    depth: Depth,
+    defines: &HashMap<String, ConstExpr>,
+) -> Option<Depth> {
+    block
+        .instructions
+        .iter()
+        .try_fold(depth, |d, inst| step(inst, d, defines))
+}
+
+fn is_register(operand: Option<&Operand>, gpr: Gpr) -> bool {
+    matches!(operand, Some(Operand::Register(reg)) if reg.gpr() == Some(gpr))
+}
+
+/// The depth after `inst`, or `None` if it moves rsp unpredictably.
+fn step(
+    inst: &Amd64Instruction,
+    mut depth: Depth,
+    defines: &HashMap<String, ConstExpr>,
+) -> Option<Depth> {
+    let ops = &inst.operands;
+    let on_rsp = is_register(ops.first(), Gpr::RSP);
+    let on_rbp = is_register(ops.first(), Gpr::RBP);
+
+    match inst.mnemonic.as_str() {
+        "push" | "pushfq" => depth.rsp += 8,
+        "pop" | "popfq" => {
+            depth.rsp -= 8;
+            if on_rbp {
+                depth.rbp = None;
+            }
+        }
+        "sub" if on_rsp => depth.rsp += constant(ops.get(1)?, defines)?,
+        "add" if on_rsp => depth.rsp -= constant(ops.get(1)?, defines)?,
+        "mov" if on_rsp && is_register(ops.get(1), Gpr::RBP) => depth.rsp = depth.rbp?,
+        "mov" if on_rbp && is_register(ops.get(1), Gpr::RSP) => depth.rbp = Some(depth.rsp),
+        "leave" => {
+            depth.rsp = depth.rbp? - 8;
+            depth.rbp = None;
+        }
+        // Calls pop what they push, and the callee is checked on its own.
+        "call" => {}
+        _ => {
+            let defs = dataflow::effects(inst).defs;
+            if defs.contains(Gpr::RSP) {
+                return None;
+            }
+            if defs.contains(Gpr::RBP) {
+                depth.rbp = None;
+            }
+        }
+    }
+
+    Some(depth)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm_dsl, AsmExpr, Label, Section};
+
+    fn check(body: Vec<AsmExpr>) -> Result<(), Vec<StackError>> {
+        let text = [vec![AsmExpr::Label(Label::plain("f"))], body].concat();
+        check_stack_balance(&Program::default().with_section(Section::new("text", text)))
+    }
+
+    #[test]
+    fn pushes_pops_and_frames_balance() {
+        assert_eq!(
+            check(asm_dsl! { push rbx; sub rsp, 8; add rsp, 8; pop rbx; ret; }),
+            Ok(())
+        );
+        let framed = asm_dsl! {
+            push rbp;
+            mov rbp, rsp;
+            sub rsp, 32;
+            push rax;
+            leave;
+            ret;
+        };
+        assert_eq!(check(framed), Ok(()));
+        let restored = asm_dsl! {
+            push rbp;
+            mov rbp, rsp;
+            sub rsp, 32;
+            mov rsp, rbp;
+            pop rbp;
+            ret;
+        };
+        assert_eq!(check(restored), Ok(()));
+    }
+
+    #[test]
+    fn returns_must_leave_the_stack_as_found() {
+        let errors = check(asm_dsl! { push rax; ret; }).unwrap_err();
+        assert_eq!(
+            errors,
+            [StackError::Unbalanced {
+                function: "f".to_string(),
+                block: "f".to_string(),
+                depth: 8,
+            }]
+        );
+        let errors = check(asm_dsl! { pop rax; ret; }).unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "f: returns from f having popped 8 bytes too many"
+        );
+    }
+
+    #[test]
+    fn paths_must_agree_on_the_depth() {
+        let errors = check(asm_dsl! {
+            test rdi, rdi;
+            je skip;
+            push rax;
+            skip:
+            pop rax;
+            ret;
+        })
+        .unwrap_err();
+        assert_eq!(
+            errors[0],
+            StackError::Inconsistent {
+                function: "f".to_string(),
+                block: "skip".to_string(),
+                depths: (0, 8),
+            }
+        );
+    }
+
+    #[test]
+    fn untracked_changes_to_rsp_end_the_check() {
+        assert_eq!(check(asm_dsl! { push rax; and rsp, -16; ret; }), Ok(()));
+        assert_eq!(check(asm_dsl! { push rax; sub rsp, rdi; ret; }), Ok(()));
+    }
+}
@@ -0,0 +1,188 @@
+//! Where a program starts, and the glue that runs before its own code.
+//!
+//! A freestanding program defines its entry symbol itself, `_start` unless
+//! the linker is told otherwise, and must end with an exit system call as
+//! there is nothing to return to. A hosted one defines `main` and leaves
+//! the rest to the C runtime it is linked with. In between, the program
+//! can be written as an ordinary function returning its exit status and
+//! have the glue that calls it generated, for Linux or Windows:
+//!
+//! ```
+//! use cataclysm::{program::Program, startup::Startup};
+//!
+//! let program = Program::default().with_startup(Startup::linux("run"));
+//! assert_eq!(program.startup().entry(), "_start");
+//! assert!(program.is_global("_start"));
+//! ```
+
+use crate::{
+    arm64,
+    consts::{RAX, RBP, RCX, RDI, RSP},
+    instr::{call, lea, mov, sub, syscall, xor},
+    register::Reg64,
+    riscv,
+    target::{Arch, Target},
+    AsmExpr, Label, Mem, Section,
+};
+
+/// Linux's `exit` system call on x86-64.
+const EXIT: u64 = 60;
+/// Linux's `exit` system call on AArch64 and RISC-V, which share the
+/// generic numbering.
+const EXIT_GENERIC: i64 = 93;
+
+/// How execution reaches the program, set with
+/// [`Program::with_startup`](crate::program::Program::with_startup).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Startup {
+    /// The program defines `entry` and never returns from it.
+    Freestanding { entry: String },
+    /// The C runtime calls `main`, whose return value is the exit status.
+    Hosted,
+    /// Generated `_start` glue calls `function` with argc and argv, as a C
+    /// `main` would get them, and exits with its return value.
+    Linux { function: String },
+    /// Generated `mainCRTStartup` glue calls `function` and passes its
+    /// return value to `ExitProcess`, as the C runtime would for `main`.
+    Windows { function: String },
+}
+
+impl Startup {
+    /// Freestanding from `_start`, what `ld` enters by default.
+    pub fn start() -> Self {
+        Startup::Freestanding {
+            entry: "_start".to_string(),
+        }
+    }
+
+    /// Freestanding from `entry`, for linking with `-e entry`.
+    pub fn custom(entry: &str) -> Self {
+        Startup::Freestanding {
+            entry: entry.to_string(),
+        }
+    }
+
+    pub fn linux(function: &str) -> Self {
+        Startup::Linux {
+            function: function.to_string(),
+        }
+    }
+
+    pub fn windows(function: &str) -> Self {
+        Startup::Windows {
+            function: function.to_string(),
+        }
+    }
+
+    /// The symbol execution starts at, which the program exports.
+    pub fn entry(&self) -> &str {
+        match self {
+            Startup::Freestanding { entry } => entry,
+            Startup::Hosted => "main",
+            Startup::Linux { .. } => "_start",
+            Startup::Windows { .. } => "mainCRTStartup",
+        }
+    }
+
+    /// The function the glue calls, if there is glue.
+    pub fn function(&self) -> Option<&str> {
+        match self {
+            Startup::Linux { function } | Startup::Windows { function } => Some(function),
+            Startup::Freestanding { .. } | Startup::Hosted => None,
+        }
+    }
+
+    /// Symbols the glue calls that the program does not define.
+    pub fn externs(&self) -> &'static [&'static str] {
+        match self {
+            Startup::Windows { .. } => &["ExitProcess"],
+            _ => &[],
+        }
+    }
+
+    /// A text section holding the glue for `target`, if there is any.
+    /// There is none for Windows on RISC-V, which Windows does not run on.
+    pub fn glue(&self, target: &Target) -> Option<Section> {
+        let body = match (self, target.arch) {
+            (Startup::Linux { function }, Arch::Amd64) => linux_amd64(function, target),
+            (Startup::Linux { function }, Arch::Arm64) => linux_arm64(function),
+            (Startup::Linux { function }, Arch::Riscv64) => linux_riscv64(function),
+            (Startup::Windows { function }, Arch::Amd64) => windows_amd64(function),
+            (Startup::Windows { function }, Arch::Arm64) => windows_arm64(function),
+            (Startup::Windows { .. }, Arch::Riscv64)
+            | (Startup::Freestanding { .. } | Startup::Hosted, _) => return None,
+        };
+        let mut glue = vec![AsmExpr::Label(Label::plain(self.entry()))];
+        glue.extend(body);
+        Some(Section::new("text", glue))
+    }
+}
+
+impl Default for Startup {
+    fn default() -> Self {
+        Startup::start()
+    }
+}
+
+/// The kernel leaves argc at the stack pointer with argv right above it,
+/// and the stack aligned as a call expects it before the return address
+/// is pushed. argc is an int, so only edi matters even where argv starts
+/// four bytes up. rbp is cleared to end frame-pointer walks here.
+fn linux_amd64(function: &str, target: &Target) -> Vec<AsmExpr> {
+    let argv = Mem::base(RSP).with_displacement(target.abi.pointer_width() as i64);
+    vec![
+        xor(RBP, RBP),
+        mov(RDI, Mem::base(RSP)),
+        lea(Reg64::Rsi, argv),
+        call(Label::plain(function)),
+        mov(RDI, RAX),
+        mov(RAX, target.abi.syscall_number(EXIT)),
+        syscall(),
+    ]
+}
+
+fn linux_arm64(function: &str) -> Vec<AsmExpr> {
+    use arm64::{add, bl, ldr, mov, svc, Arm64Operand, FP, LR, SP, X0, X1, X8, XZR};
+    vec![
+        mov(FP, XZR),
+        mov(LR, XZR),
+        ldr(X0, Arm64Operand::mem(SP, 0)),
+        add(X1, SP, 8),
+        bl(Label::plain(function)),
+        mov(X8, EXIT_GENERIC),
+        svc(0),
+    ]
+}
+
+fn linux_riscv64(function: &str) -> Vec<AsmExpr> {
+    use riscv::{addi, call, ecall, ld, li, mv, A0, A1, A7, FP, RA, SP, ZERO};
+    vec![
+        mv(FP, ZERO),
+        mv(RA, ZERO),
+        ld(A0, SP, 0),
+        addi(A1, SP, 8),
+        call(Label::plain(function)),
+        li(A7, EXIT_GENERIC),
+        ecall(),
+    ]
+}
+
+/// 32 bytes of shadow space for the callee plus 8 to realign the stack,
+/// which the loader enters with a return address pushed.
+fn windows_amd64(function: &str) -> Vec<AsmExpr> {
+    vec![
+        sub(RSP, 40),
+        call(Label::plain(function)),
+        mov(RCX, RAX),
+        call(Label::plain("ExitProcess")),
+    ]
+}
+
+/// The return value is already in w0, where `ExitProcess` takes its
+/// argument, and AArch64 has no shadow space.
+fn windows_arm64(function: &str) -> Vec<AsmExpr> {
+    vec![
+        arm64::bl(Label::plain(function)),
+        arm64::bl(Label::plain("ExitProcess")),
+    ]
+}
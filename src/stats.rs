@@ -0,0 +1,163 @@
+//! Size and composition reports for programs, for tracking how generated
+//! output grows across versions.
+
+use std::{collections::BTreeMap, fmt};
+
+use crate::{cond::BuildConfig, program::Program, AsmExpr, Section};
+
+/// Counts for one section, as emitted under the program's build
+/// configuration.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SectionStats {
+    pub name: String,
+    pub instructions: usize,
+    pub labels: usize,
+    pub data_items: usize,
+    /// Assembled size of the section's data items. Instruction encodings
+    /// are not included.
+    pub data_bytes: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProgramStats {
+    /// Occurrences of each mnemonic across all sections.
+    pub mnemonics: BTreeMap<String, usize>,
+    pub sections: Vec<SectionStats>,
+    pub globals: usize,
+    pub externs: usize,
+    pub defines: usize,
+    pub pool_entries: usize,
+    pub pool_bytes: usize,
+}
+
+impl ProgramStats {
+    pub fn instructions(&self) -> usize {
+        self.sections.iter().map(|s| s.instructions).sum()
+    }
+
+    pub fn labels(&self) -> usize {
+        self.sections.iter().map(|s| s.labels).sum()
+    }
+
+    /// Data bytes in every section, including the constant pool.
+    pub fn data_bytes(&self) -> usize {
+        self.sections.iter().map(|s| s.data_bytes).sum::<usize>() + self.pool_bytes
+    }
+}
+
+pub(crate) fn collect(program: &Program) -> ProgramStats {
+    let mut stats = ProgramStats {
+        globals: program.globals.len(),
+        externs: program.externs.len(),
+        defines: program.defines.len(),
+        pool_entries: program.pool.len(),
+        ..ProgramStats::default()
+    };
+
+    for section in &program.sections {
+        let mut section_stats = SectionStats {
+            name: section.name.clone(),
+            ..SectionStats::default()
+        };
+        count(
+            &section.body,
+            section,
+            &program.config,
+            &mut section_stats,
+            &mut stats.mnemonics,
+        );
+        stats.sections.push(section_stats);
+    }
+
+    let pool = program.pool.to_section();
+    let mut pool_stats = SectionStats::default();
+    count(
+        &pool.body,
+        &pool,
+        &program.config,
+        &mut pool_stats,
+        &mut stats.mnemonics,
+    );
+    stats.pool_bytes = pool_stats.data_bytes;
+
+    stats
+}
+
+fn count(
+    body: &[AsmExpr],
+    section: &Section,
+    config: &BuildConfig,
+    stats: &mut SectionStats,
+    mnemonics: &mut BTreeMap<String, usize>,
+) {
+    for expr in body {
+        match expr {
+            AsmExpr::Instruction(inst) => {
+                stats.instructions += 1;
+                *mnemonics.entry(inst.mnemonic.to_string()).or_default() += 1;
+            }
+            AsmExpr::Arm64(inst) => {
+                stats.instructions += 1;
+                *mnemonics.entry(inst.mnemonic.to_string()).or_default() += 1;
+            }
+            AsmExpr::Riscv(inst) => {
+                stats.instructions += 1;
+                *mnemonics.entry(inst.mnemonic.to_string()).or_default() += 1;
+            }
+            AsmExpr::Label(_) => stats.labels += 1,
+            AsmExpr::Data(data) => {
+                stats.data_items += 1;
+                stats.data_bytes += data.to_bytes_with(section.endian).len();
+            }
+            AsmExpr::Block(inner)
+            | AsmExpr::Region { body: inner, .. }
+            | AsmExpr::Macro { body: inner, .. } => count(inner, section, config, stats, mnemonics),
+            AsmExpr::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let arm = if cond.eval(config) { then } else { otherwise };
+                count(arm, section, config, stats, mnemonics);
+            }
+            AsmExpr::Raw(_) | AsmExpr::Param(_) => {}
+        }
+    }
+}
+
+impl fmt::Display for ProgramStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} instructions, {} labels, {} data bytes",
+            self.instructions(),
+            self.labels(),
+            self.data_bytes()
+        )?;
+        writeln!(
+            f,
+            "{} globals, {} externs, {} defines, {} pool constants ({} bytes)",
+            self.globals, self.externs, self.defines, self.pool_entries, self.pool_bytes
+        )?;
+
+        writeln!(f, "\nsection        insns   labels    items    bytes")?;
+        for s in &self.sections {
+            writeln!(
+                f,
+                "{:<12} {:>7} {:>8} {:>8} {:>8}",
+                s.name, s.instructions, s.labels, s.data_items, s.data_bytes
+            )?;
+        }
+
+        if !self.mnemonics.is_empty() {
+            writeln!(f, "\nmnemonic        count")?;
+            let mut by_count: Vec<_> = self.mnemonics.iter().collect();
+            by_count.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+            for (mnemonic, n) in by_count {
+                writeln!(f, "{:<12} {:>8}", mnemonic, n)?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,650 @@
+//! Append-only construction for programs too large to hold as a whole.
+//!
+//! A [`StreamingAssembler`] writes each expression out as soon as it is
+//! pushed instead of building a [`Program`](crate::program::Program). As
+//! text it needs no state beyond the current section. As machine code it
+//! keeps the address of every label, and buffers output only from the
+//! first item still waiting on a forward reference; the rest goes straight
+//! to the writer.
+//!
+//! The binary output is the sections laid out as [`encode`] would, one
+//! after another and padded to [`EncodeOptions::section_alignment`],
+//! written as one flat image. Unlike [`encode`], a section cannot be
+//! reopened once another has started, and branches to labels not yet
+//! defined always take their 32-bit form, since how far away the label
+//! lands is unknown when the branch is written.
+//!
+//! [`encode`]: crate::encode::encode
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    error, fmt,
+    io::{self, Write},
+};
+
+use crate::{
+    cond::BuildConfig,
+    encode::{
+        at_position, encode_data, encode_instruction, parse_equ, qualify, BranchSize, EncodeError,
+        EncodeErrorKind, EncodeOptions, Encoded,
+    },
+    expr::ConstExpr,
+    qualify_label,
+    target::Target,
+    AsmExpr, EmitContext, Endian, Global,
+};
+
+#[derive(Debug)]
+pub enum StreamError {
+    Io(io::Error),
+    Encode(Vec<EncodeError>),
+    /// Binary output lays sections out in the order they are opened, so a
+    /// section cannot be continued once another has started.
+    Reopened(String),
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StreamError::Io(e) => write!(f, "{}", e),
+            StreamError::Encode(errors) => {
+                for (i, e) in errors.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{}", e)?;
+                }
+                Ok(())
+            }
+            StreamError::Reopened(section) => write!(
+                f,
+                "section .{} cannot be reopened in a binary stream",
+                section
+            ),
+        }
+    }
+}
+
+impl error::Error for StreamError {}
+
+impl From<io::Error> for StreamError {
+    fn from(e: io::Error) -> Self {
+        StreamError::Io(e)
+    }
+}
+
+/// An encoded item not yet written, because it or an item before it still
+/// waits on a symbol.
+struct Chunk {
+    end: u64,
+    encoded: Encoded,
+    section: String,
+    item: String,
+}
+
+/// Machine-code output state.
+struct Binary {
+    options: EncodeOptions,
+    /// Defines, `equ` constants and the address of every label so far.
+    env: HashMap<String, ConstExpr>,
+    addresses: HashMap<String, u64>,
+    address: u64,
+    /// Address of the open section.
+    section_start: u64,
+    opened: HashSet<String>,
+    /// Items from the first unresolved one on, the first being number
+    /// `first` of the stream.
+    pending: VecDeque<Chunk>,
+    first: usize,
+    /// Numbers of the pending items waiting on each symbol.
+    waiting: HashMap<String, Vec<usize>>,
+}
+
+enum Output {
+    Text,
+    Binary(Box<Binary>),
+}
+
+/// Writes a program to `W` as it is built, as NASM text or as machine
+/// code.
+pub struct StreamingAssembler<W: Write> {
+    out: W,
+    output: Output,
+    config: BuildConfig,
+    pointer_width: u32,
+    section: Option<String>,
+    /// Default byte order of the open section.
+    endian: Endian,
+    scope: String,
+}
+
+impl<W: Write> StreamingAssembler<W> {
+    /// Writes the program as assembly text.
+    pub fn text(out: W) -> Self {
+        StreamingAssembler::new(out, Output::Text)
+    }
+
+    /// Writes the program as machine code laid out as `options` asks.
+    pub fn binary(out: W, options: EncodeOptions) -> Self {
+        let env = options
+            .symbols
+            .iter()
+            .map(|(name, &address)| (name.clone(), ConstExpr::Int(address as i64)))
+            .collect();
+        let binary = Binary {
+            address: options.base,
+            section_start: options.base,
+            addresses: options.symbols.clone(),
+            options,
+            env,
+            opened: HashSet::new(),
+            pending: VecDeque::new(),
+            first: 0,
+            waiting: HashMap::new(),
+        };
+        StreamingAssembler::new(out, Output::Binary(Box::new(binary)))
+    }
+
+    fn new(out: W, output: Output) -> Self {
+        StreamingAssembler {
+            out,
+            output,
+            config: BuildConfig::default(),
+            pointer_width: Target::default().abi.pointer_width(),
+            section: None,
+            endian: Endian::Little,
+            scope: String::new(),
+        }
+    }
+
+    pub fn with_target(mut self, target: &Target) -> Self {
+        self.pointer_width = target.abi.pointer_width();
+        self
+    }
+
+    /// The configuration `If` expressions are resolved against.
+    pub fn with_config(mut self, config: BuildConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// The address of `label`, once it has been placed. Always `None` for
+    /// text output.
+    pub fn symbol(&self, label: &str) -> Option<u64> {
+        match &self.output {
+            Output::Text => None,
+            Output::Binary(binary) => binary.addresses.get(label).copied(),
+        }
+    }
+
+    /// Defines a constant, which later code and any still waiting on it
+    /// may use.
+    pub fn define(&mut self, name: &str, value: impl Into<ConstExpr>) -> Result<(), StreamError> {
+        let value = value.into();
+        match &mut self.output {
+            Output::Text => writeln!(self.out, "%define {} {}", name, value)?,
+            Output::Binary(binary) => {
+                binary.env.insert(name.to_string(), value);
+                binary.wake(name)?;
+                binary.flush(&mut self.out)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Exports `label`. Binary output has no symbol table, so this only
+    /// affects text.
+    pub fn global(&mut self, label: &str) -> Result<(), StreamError> {
+        if let Output::Text = self.output {
+            writeln!(self.out, "{}", Global::new(label))?;
+        }
+        Ok(())
+    }
+
+    pub fn section(&mut self, name: &str) -> Result<(), StreamError> {
+        self.section_with_endian(name, Endian::Little)
+    }
+
+    /// Starts or continues section `name`, whose multi-byte data items
+    /// default to `endian`.
+    pub fn section_with_endian(&mut self, name: &str, endian: Endian) -> Result<(), StreamError> {
+        match &mut self.output {
+            Output::Text => {
+                if self.section.is_some() {
+                    writeln!(self.out)?;
+                }
+                writeln!(self.out, "section .{}", name)?;
+            }
+            Output::Binary(binary) => {
+                if self.section.as_deref() != Some(name) {
+                    if !binary.opened.insert(name.to_string()) {
+                        return Err(StreamError::Reopened(name.to_string()));
+                    }
+                    if self.section.is_some() {
+                        let align = binary.options.section_alignment.max(1);
+                        let padding = binary.address.next_multiple_of(align) - binary.address;
+                        binary.append(
+                            &mut self.out,
+                            Encoded::plain(vec![0; padding as usize]),
+                            name,
+                            "alignment padding".to_string(),
+                        )?;
+                    }
+                    binary.section_start = binary.address;
+                }
+            }
+        }
+        self.section = Some(name.to_string());
+        self.endian = endian;
+        Ok(())
+    }
+
+    /// Appends `expr` to the current section, opening `text` if none is.
+    pub fn push(&mut self, expr: &AsmExpr) -> Result<(), StreamError> {
+        if self.section.is_none() {
+            self.section("text")?;
+        }
+        match &self.output {
+            Output::Text => {
+                let ctx = EmitContext {
+                    endian: self.endian,
+                    config: Some(&self.config),
+                    pointer_width: self.pointer_width,
+                    ..EmitContext::default()
+                };
+                writeln!(self.out, "{}", Line(expr, &ctx))?;
+                Ok(())
+            }
+            Output::Binary(_) => self.assemble(expr),
+        }
+    }
+
+    pub fn extend<'a>(
+        &mut self,
+        exprs: impl IntoIterator<Item = &'a AsmExpr>,
+    ) -> Result<(), StreamError> {
+        exprs.into_iter().try_for_each(|expr| self.push(expr))
+    }
+
+    fn assemble(&mut self, expr: &AsmExpr) -> Result<(), StreamError> {
+        let Output::Binary(binary) = &mut self.output else {
+            unreachable!("only binary output is assembled");
+        };
+        let section = self.section.clone().unwrap_or_default();
+        let error = |item: String, kind: EncodeErrorKind| {
+            StreamError::Encode(vec![EncodeError {
+                section: section.clone(),
+                item,
+                kind,
+            }])
+        };
+
+        match expr {
+            AsmExpr::Label(label) => {
+                if !label.label.starts_with('.') {
+                    self.scope = label.label.clone();
+                }
+                let name = qualify_label(&self.scope, &label.label);
+                binary.addresses.insert(name.clone(), binary.address);
+                binary
+                    .env
+                    .insert(name.clone(), ConstExpr::Int(binary.address as i64));
+                binary.wake(&name)?;
+                binary.flush(&mut self.out)?;
+            }
+            AsmExpr::Instruction(inst) => {
+                let long = binary.options.branches == BranchSize::Near;
+                let encoded = encode_instruction(inst, &self.scope, long, &binary.env)
+                    .map_err(|kind| error(inst.to_string(), kind))?;
+
+                // Try the short form first, and grow it if the target is
+                // out of reach or not yet known.
+                let end = binary.address + encoded.bytes.len() as u64;
+                let mut resolved = encoded.clone();
+                let outcome = resolved.resolve(end, &binary.env);
+                let grow = binary.options.branches == BranchSize::Auto
+                    && match &outcome {
+                        Ok(Some(_)) => resolved.has_short_branch(),
+                        Err(EncodeErrorKind::BranchOutOfRange { bits: 8, .. }) => true,
+                        _ => false,
+                    };
+                let encoded = if grow {
+                    encode_instruction(inst, &self.scope, true, &binary.env)
+                        .map_err(|kind| error(inst.to_string(), kind))?
+                } else {
+                    outcome.map_err(|kind| error(inst.to_string(), kind))?;
+                    encoded
+                };
+                binary.append(&mut self.out, encoded, &section, inst.to_string())?;
+            }
+            AsmExpr::Data(data) => {
+                let encoded = if data.is_positional() {
+                    let offset = binary.address - binary.section_start;
+                    Encoded::plain(data.to_bytes_at(self.endian, offset))
+                } else {
+                    encode_data(data, self.endian, self.pointer_width, &self.scope)
+                };
+                binary.append(&mut self.out, encoded, &section, data.to_string())?;
+            }
+            AsmExpr::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let arm = if cond.eval(&self.config) {
+                    then
+                } else {
+                    otherwise
+                };
+                self.extend(arm)?;
+            }
+            AsmExpr::Block(inner)
+            | AsmExpr::Region { body: inner, .. }
+            | AsmExpr::Macro { body: inner, .. } => self.extend(inner)?,
+            AsmExpr::Raw(text) => match parse_equ(text) {
+                Some(Ok((name, value))) => {
+                    let name = qualify_label(&self.scope, name);
+                    let value = at_position(
+                        &qualify(value, &self.scope),
+                        binary.address,
+                        binary.section_start,
+                    );
+                    binary.env.insert(name.clone(), value);
+                    binary.wake(&name)?;
+                    binary.flush(&mut self.out)?;
+                }
+                Some(Err(e)) => {
+                    return Err(error(
+                        text.trim().to_string(),
+                        EncodeErrorKind::Expr(e.to_string()),
+                    ))
+                }
+                None => {
+                    return Err(error(
+                        text.clone(),
+                        EncodeErrorKind::Unsupported("raw assembly text".to_string()),
+                    ))
+                }
+            },
+            AsmExpr::Arm64(inst) => {
+                return Err(error(
+                    inst.to_string(),
+                    EncodeErrorKind::Unsupported("an AArch64 instruction".to_string()),
+                ))
+            }
+            AsmExpr::Riscv(inst) => {
+                return Err(error(
+                    inst.to_string(),
+                    EncodeErrorKind::Unsupported("a RISC-V instruction".to_string()),
+                ))
+            }
+            AsmExpr::Param(name) => {
+                return Err(error(
+                    format!("%{}", name),
+                    EncodeErrorKind::Unsupported("an unbound parameter".to_string()),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Ends the stream, reporting every reference still unresolved, and
+    /// returns the writer.
+    pub fn finish(mut self) -> Result<W, StreamError> {
+        if let Output::Binary(binary) = &self.output {
+            let mut errors: Vec<(usize, EncodeError)> = binary
+                .waiting
+                .iter()
+                .flat_map(|(symbol, items)| {
+                    items.iter().map(|&n| {
+                        let chunk = &binary.pending[n - binary.first];
+                        let error = EncodeError {
+                            section: chunk.section.clone(),
+                            item: chunk.item.clone(),
+                            kind: EncodeErrorKind::UndefinedSymbol(symbol.clone()),
+                        };
+                        (n, error)
+                    })
+                })
+                .collect();
+            if !errors.is_empty() {
+                errors.sort_by_key(|(n, _)| *n);
+                return Err(StreamError::Encode(
+                    errors.into_iter().map(|(_, e)| e).collect(),
+                ));
+            }
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+impl Binary {
+    /// Places an item at the current address, writing it out at once if
+    /// nothing before it is pending.
+    fn append(
+        &mut self,
+        out: &mut impl Write,
+        mut encoded: Encoded,
+        section: &str,
+        item: String,
+    ) -> Result<(), StreamError> {
+        let end = self.address + encoded.bytes.len() as u64;
+        self.address = end;
+
+        let waiting = encoded.resolve(end, &self.env).map_err(|kind| {
+            StreamError::Encode(vec![EncodeError {
+                section: section.to_string(),
+                item: item.clone(),
+                kind,
+            }])
+        })?;
+        if waiting.is_none() && self.pending.is_empty() {
+            out.write_all(&encoded.bytes)?;
+            return Ok(());
+        }
+
+        if let Some(symbol) = waiting {
+            let n = self.first + self.pending.len();
+            self.waiting.entry(symbol).or_default().push(n);
+        }
+        self.pending.push_back(Chunk {
+            end,
+            encoded,
+            section: section.to_string(),
+            item,
+        });
+        Ok(())
+    }
+
+    /// Retries the items waiting on `symbol`, now that it has a value.
+    fn wake(&mut self, symbol: &str) -> Result<(), StreamError> {
+        for n in self.waiting.remove(symbol).unwrap_or_default() {
+            let chunk = &mut self.pending[n - self.first];
+            match chunk.encoded.resolve(chunk.end, &self.env) {
+                Ok(None) => {}
+                Ok(Some(next)) => self.waiting.entry(next).or_default().push(n),
+                Err(kind) => {
+                    return Err(StreamError::Encode(vec![EncodeError {
+                        section: chunk.section.clone(),
+                        item: chunk.item.clone(),
+                        kind,
+                    }]))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes out the pending items that no longer wait on anything, up to
+    /// the first that does.
+    fn flush(&mut self, out: &mut impl Write) -> io::Result<()> {
+        while let Some(chunk) = self.pending.front() {
+            if !chunk.encoded.is_resolved() {
+                break;
+            }
+            out.write_all(&chunk.encoded.bytes)?;
+            self.pending.pop_front();
+            self.first += 1;
+        }
+        Ok(())
+    }
+}
+
+/// An expression rendered in the stream's context.
+struct Line<'a>(&'a AsmExpr, &'a EmitContext<'a>);
+
+impl fmt::Display for Line<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt_in(f, self.1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{asm_dsl, encode::encode, program::Program, Data, Label, Section};
+
+    /// A writer whose contents can be read while the stream still owns it.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Shared {
+        fn len(&self) -> usize {
+            self.0.borrow().len()
+        }
+    }
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn binary(body: &[AsmExpr]) -> Result<Vec<u8>, StreamError> {
+        let mut stream = StreamingAssembler::binary(Vec::new(), EncodeOptions::new());
+        stream.extend(body)?;
+        stream.finish()
+    }
+
+    #[test]
+    fn text_is_written_line_by_line() {
+        let mut stream = StreamingAssembler::text(Vec::new());
+        stream.global("main").unwrap();
+        stream.section("text").unwrap();
+        stream.push(&AsmExpr::Label(Label::plain("main"))).unwrap();
+        stream.extend(&asm_dsl! { ret; }).unwrap();
+        stream.section("data").unwrap();
+        stream.push(&AsmExpr::Data(Data::UInt(7))).unwrap();
+        let text = String::from_utf8(stream.finish().unwrap()).unwrap();
+        assert_eq!(
+            text,
+            "global main\nsection .text\n\tmain:\n\t\tret\n\nsection .data\n\t\tdq 7\n"
+        );
+    }
+
+    /// Without forward branches, which the stream cannot shorten, the
+    /// stream writes what [`encode`] lays out.
+    #[test]
+    fn binary_output_matches_the_encoder() {
+        let text = [
+            vec![
+                AsmExpr::Label(Label::plain("start")),
+                AsmExpr::Label(Label::plain(".loop")),
+            ],
+            asm_dsl! {
+                mov rax, [rel value];
+                dec rax;
+                jne .loop;
+                ret;
+            },
+        ]
+        .concat();
+        let data = vec![
+            AsmExpr::Label(Label::plain("value")),
+            AsmExpr::Data(Data::UInt(3)),
+        ];
+
+        let mut stream = StreamingAssembler::binary(Vec::new(), EncodeOptions::new());
+        stream.section("text").unwrap();
+        stream.extend(&text).unwrap();
+        stream.section("data").unwrap();
+        stream.extend(&data).unwrap();
+        let value = stream.symbol("value");
+        let streamed = stream.finish().unwrap();
+
+        let program = Program::default()
+            .with_section(Section::new("text", text))
+            .with_section(Section::new("data", data));
+        let image = encode(&program, &EncodeOptions::new()).unwrap();
+        let (text, data) = (
+            image.section("text").unwrap(),
+            image.section("data").unwrap(),
+        );
+        let mut expected = text.bytes.clone();
+        expected.resize((data.address - text.address) as usize, 0);
+        expected.extend(&data.bytes);
+        assert_eq!(streamed, expected);
+        assert_eq!(value, image.symbol("value"));
+    }
+
+    #[test]
+    fn forward_branches_take_their_long_form() {
+        let mut body = asm_dsl! { jmp done; nop; };
+        body.push(AsmExpr::Label(Label::plain("done")));
+        assert_eq!(binary(&body).unwrap(), [0xe9, 1, 0, 0, 0, 0x90]);
+    }
+
+    #[test]
+    fn output_is_held_back_only_from_the_first_unresolved_item() {
+        let out = Shared::default();
+        let mut stream = StreamingAssembler::binary(out.clone(), EncodeOptions::new());
+        stream.extend(&asm_dsl! { nop; }).unwrap();
+        assert_eq!(out.len(), 1);
+        stream.extend(&asm_dsl! { jmp done; nop; }).unwrap();
+        assert_eq!(out.len(), 1);
+        stream.push(&AsmExpr::Label(Label::plain("done"))).unwrap();
+        assert_eq!(out.len(), 7);
+        stream.extend(&asm_dsl! { ret; }).unwrap();
+        assert_eq!(out.len(), 8);
+        stream.finish().unwrap();
+    }
+
+    #[test]
+    fn defines_resolve_earlier_references() {
+        let mut stream = StreamingAssembler::binary(Vec::new(), EncodeOptions::new());
+        stream.extend(&asm_dsl! { mov rax, [rel LIMIT]; }).unwrap();
+        stream.define("LIMIT", 0x10).unwrap();
+        let bytes = stream.finish().unwrap();
+        // The displacement counts from the end of the 7-byte instruction.
+        assert_eq!(bytes, [0x48, 0x8b, 0x05, 0x09, 0, 0, 0]);
+    }
+
+    #[test]
+    fn sections_cannot_be_reopened() {
+        let mut stream = StreamingAssembler::binary(Vec::new(), EncodeOptions::new());
+        stream.section("text").unwrap();
+        stream.section("data").unwrap();
+        let err = stream.section("text").unwrap_err();
+        assert!(matches!(err, StreamError::Reopened(ref s) if s == "text"));
+    }
+
+    #[test]
+    fn unresolved_references_are_reported_at_the_end() {
+        let Err(StreamError::Encode(errors)) = binary(&asm_dsl! { call missing; ret; }) else {
+            panic!("the stream finished");
+        };
+        let kinds: Vec<_> = errors.into_iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            [EncodeErrorKind::UndefinedSymbol("missing".to_string())]
+        );
+    }
+}
@@ -0,0 +1,361 @@
+//! Multiplication and division by constants without `imul` and `div`
+//! where something cheaper does the job: shifts, `lea` and adds for
+//! multiplication, and a multiply by a fixed-point reciprocal for division,
+//! as compilers do.
+//!
+//! Every sequence computes the full 64-bit result of the operation it
+//! replaces. Division sequences use `mul` or `imul`, so they clobber rax
+//! and rdx, and need a scratch register besides.
+
+use std::{error, fmt};
+
+use crate::{
+    consts::{RAX, RDX, RSP},
+    instr::{add, inc, mov, neg, sar, sbb, shl, shr, sub, xor},
+    mnemonic::Amd64Mnemonic,
+    Amd64Instruction, Amd64Register, AsmExpr, Mem, Operand,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StrengthError {
+    DivideByZero,
+    /// The scratch register is rax, rdx or one of the operands.
+    ScratchConflict,
+}
+
+impl fmt::Display for StrengthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StrengthError::DivideByZero => write!(f, "division by zero"),
+            StrengthError::ScratchConflict => {
+                write!(f, "scratch register overlaps rax, rdx or an operand")
+            }
+        }
+    }
+}
+
+impl error::Error for StrengthError {}
+
+fn same(a: &Amd64Register, b: &Amd64Register) -> bool {
+    a.gpr().is_some() && a.gpr() == b.gpr()
+}
+
+fn copy(dst: &Amd64Register, src: &Amd64Register) -> Vec<AsmExpr> {
+    if same(dst, src) {
+        Vec::new()
+    } else {
+        vec![mov(dst.clone(), src.clone())]
+    }
+}
+
+/// `lea dst, [base + index*scale]`.
+fn lea(dst: &Amd64Register, base: &Amd64Register, index: &Amd64Register, scale: u32) -> AsmExpr {
+    let mem = Mem::base(base.clone()).with_index(index.clone(), scale);
+    AsmExpr::Instruction(Amd64Instruction::new(
+        Amd64Mnemonic::Lea,
+        vec![dst.clone().into(), mem.into()],
+    ))
+}
+
+/// Two-operand `imul dst, src`.
+fn imul(dst: &Amd64Register, src: impl Into<Operand>) -> AsmExpr {
+    AsmExpr::Instruction(Amd64Instruction::new(
+        Amd64Mnemonic::Imul,
+        vec![dst.clone().into(), src.into()],
+    ))
+}
+
+/// The `lea` scale for multiplying by 3, 5 or 9.
+fn lea_factor(c: u64) -> Option<u32> {
+    matches!(c, 3 | 5 | 9).then(|| c as u32 - 1)
+}
+
+/// `dst = src * c` for positive `c`, in the fewest instructions a pattern
+/// covers, or `None` to fall back to `imul`.
+fn mul_positive(
+    dst: &Amd64Register,
+    src: &Amd64Register,
+    c: u64,
+    scratch: &Amd64Register,
+) -> Option<Vec<AsmExpr>> {
+    let k = c.trailing_zeros();
+    let odd = c >> k;
+    let shift = |body: &mut Vec<AsmExpr>| {
+        if k > 0 {
+            body.push(shl(dst.clone(), k));
+        }
+    };
+    // An index of rsp cannot be encoded.
+    let indexable = !same(src, &RSP) && !same(dst, &RSP);
+
+    // 2^k, 3·2^k, 5·2^k, 9·2^k.
+    if odd == 1 {
+        let mut body = copy(dst, src);
+        shift(&mut body);
+        return Some(body);
+    }
+    // Up to two lea factors: 3, 5, 9, 15, 25, 27, 45, 81.
+    let scales = match lea_factor(odd) {
+        Some(scale) => Some(vec![scale]),
+        None => [3, 5, 9].into_iter().find_map(|a| {
+            let b = lea_factor(odd / a).filter(|_| odd.is_multiple_of(a))?;
+            Some(vec![a as u32 - 1, b])
+        }),
+    };
+    if let (Some(scales), true) = (scales, indexable) {
+        let mut body = Vec::new();
+        for (i, scale) in scales.into_iter().enumerate() {
+            let from = if i == 0 { src } else { dst };
+            body.push(lea(dst, from, from, scale));
+        }
+        shift(&mut body);
+        return Some(body);
+    }
+    // 2^n ± 1 times 2^k, keeping the original in scratch if dst is src.
+    if (odd + 1).is_power_of_two() || (odd - 1).is_power_of_two() {
+        let plus = (odd - 1).is_power_of_two();
+        let n = if plus { odd - 1 } else { odd + 1 }.trailing_zeros();
+        let (mut body, original) = if same(dst, src) {
+            (vec![mov(scratch.clone(), src.clone())], scratch)
+        } else {
+            (vec![mov(dst.clone(), src.clone())], src)
+        };
+        body.push(shl(dst.clone(), n));
+        body.push(if plus {
+            add(dst.clone(), original.clone())
+        } else {
+            sub(dst.clone(), original.clone())
+        });
+        shift(&mut body);
+        return Some(body);
+    }
+    None
+}
+
+/// `dst = src * c`, wrapping as `imul` does. `scratch` is only written by
+/// sequences that need the original value after overwriting `dst`, or to
+/// hold a constant too wide for `imul`; it may be neither operand.
+pub fn mul_const(
+    dst: Amd64Register,
+    src: Amd64Register,
+    c: i64,
+    scratch: Amd64Register,
+) -> AsmExpr {
+    let body = match c {
+        0 => vec![xor(dst.clone(), dst.clone())],
+        _ => match mul_positive(&dst, &src, c.unsigned_abs(), &scratch) {
+            Some(mut body) => {
+                if c < 0 {
+                    body.push(neg(dst.clone()));
+                }
+                body
+            }
+            None => {
+                let mut body = copy(&dst, &src);
+                match i32::try_from(c) {
+                    Ok(c) => body.push(imul(&dst, c)),
+                    Err(_) => {
+                        body.push(mov(scratch.clone(), c));
+                        body.push(imul(&dst, scratch));
+                    }
+                }
+                body
+            }
+        },
+    };
+    AsmExpr::Block(body)
+}
+
+/// Checks a division's registers: scratch must survive `mul` and not
+/// clobber an operand.
+fn check_scratch(
+    dst: &Amd64Register,
+    src: &Amd64Register,
+    scratch: &Amd64Register,
+) -> Result<(), StrengthError> {
+    if [&RAX, &RDX, dst, src].iter().any(|r| same(r, scratch)) {
+        return Err(StrengthError::ScratchConflict);
+    }
+    Ok(())
+}
+
+/// The high half of `src * magic` into rdx, returning where the dividend
+/// can still be read afterwards.
+fn mul_high(
+    src: &Amd64Register,
+    magic: u64,
+    signed: bool,
+    scratch: &Amd64Register,
+    body: &mut Vec<AsmExpr>,
+) -> Amd64Register {
+    let x = if same(src, &RAX) || same(src, &RDX) {
+        body.push(mov(scratch.clone(), src.clone()));
+        scratch.clone()
+    } else {
+        src.clone()
+    };
+    body.push(mov(RAX, magic));
+    let mnemonic = if signed {
+        Amd64Mnemonic::Imul
+    } else {
+        Amd64Mnemonic::Mul
+    };
+    body.push(AsmExpr::Instruction(Amd64Instruction::new(
+        mnemonic,
+        vec![Operand::from(x.clone())],
+    )));
+    x
+}
+
+/// `dst = src / d` as unsigned integers, rounding down.
+pub fn udiv_const(
+    dst: Amd64Register,
+    src: Amd64Register,
+    d: u64,
+    scratch: Amd64Register,
+) -> Result<AsmExpr, StrengthError> {
+    if d == 0 {
+        return Err(StrengthError::DivideByZero);
+    }
+    check_scratch(&dst, &src, &scratch)?;
+
+    let mut body = Vec::new();
+    if d.is_power_of_two() {
+        body.extend(copy(&dst, &src));
+        if d > 1 {
+            body.push(shr(dst.clone(), d.trailing_zeros()));
+        }
+        return Ok(AsmExpr::Block(body));
+    }
+    if d > 1 << 63 {
+        // The quotient is 1 if src >= d and 0 otherwise: 1 minus the
+        // borrow of src - d.
+        body.push(mov(scratch.clone(), d));
+        body.extend(copy(&dst, &src));
+        body.push(sub(dst.clone(), scratch));
+        body.push(sbb(dst.clone(), dst.clone()));
+        body.push(inc(dst.clone()));
+        return Ok(AsmExpr::Block(body));
+    }
+
+    // Granlund and Montgomery: with l = floor(log2 d), a 64-bit magic m
+    // gives src / d = mulhi(src, m) >> l when the rounding error is small
+    // enough, and otherwise a 65-bit one, whose top bit is folded in with
+    // an add and halving that cannot overflow.
+    let l = 63 - d.leading_zeros();
+    let wide = 1u128 << (64 + l);
+    let (m, rem) = ((wide / d as u128) as u64, (wide % d as u128) as u64);
+    if d - rem < 1 << l {
+        mul_high(&src, m + 1, false, &scratch, &mut body);
+        if l > 0 {
+            body.push(shr(RDX, l));
+        }
+    } else {
+        let mut m = m.wrapping_add(m);
+        let twice = rem.wrapping_add(rem);
+        if twice >= d || twice < rem {
+            m = m.wrapping_add(1);
+        }
+        let x = mul_high(&src, m.wrapping_add(1), false, &scratch, &mut body);
+        // rdx = ((x - t) >> 1 + t) >> l, with t the high half.
+        body.push(mov(RAX, x));
+        body.push(sub(RAX, RDX));
+        body.push(shr(RAX, 1u32));
+        body.push(add(RDX, RAX));
+        if l > 0 {
+            body.push(shr(RDX, l));
+        }
+    }
+    body.extend(copy(&dst, &RDX));
+    Ok(AsmExpr::Block(body))
+}
+
+/// `dst = src / d` as signed integers, rounding toward zero like `idiv`.
+pub fn sdiv_const(
+    dst: Amd64Register,
+    src: Amd64Register,
+    d: i64,
+    scratch: Amd64Register,
+) -> Result<AsmExpr, StrengthError> {
+    if d == 0 {
+        return Err(StrengthError::DivideByZero);
+    }
+    check_scratch(&dst, &src, &scratch)?;
+
+    let mut body = Vec::new();
+    let abs = d.unsigned_abs();
+    if abs.is_power_of_two() {
+        // Bias negative dividends by |d| - 1 so the shift rounds toward
+        // zero.
+        let k = abs.trailing_zeros();
+        if k > 0 {
+            let (t, x) = if same(&dst, &src) {
+                (scratch.clone(), src.clone())
+            } else {
+                (dst.clone(), src.clone())
+            };
+            body.push(mov(t.clone(), x.clone()));
+            body.push(sar(t.clone(), 63u32));
+            body.push(shr(t.clone(), 64 - k));
+            body.push(add(t.clone(), x));
+            body.push(sar(t.clone(), k));
+            body.extend(copy(&dst, &t));
+        } else {
+            body.extend(copy(&dst, &src));
+        }
+        if d < 0 {
+            body.push(neg(dst.clone()));
+        }
+        return Ok(AsmExpr::Block(body));
+    }
+
+    let (magic, shift) = signed_magic(d);
+    let x = mul_high(&src, magic as u64, true, &scratch, &mut body);
+    if d > 0 && magic < 0 {
+        body.push(add(RDX, x.clone()));
+    } else if d < 0 && magic > 0 {
+        body.push(sub(RDX, x.clone()));
+    }
+    if shift > 0 {
+        body.push(sar(RDX, shift));
+    }
+    // Add one for negative quotients, which the shift rounded down.
+    body.push(mov(RAX, RDX));
+    body.push(shr(RAX, 63u32));
+    body.push(add(RDX, RAX));
+    body.extend(copy(&dst, &RDX));
+    Ok(AsmExpr::Block(body))
+}
+
+/// The magic multiplier and shift for signed division by `d`, which must
+/// not be 0, 1 or -1 (Hacker's Delight, figure 10-1).
+fn signed_magic(d: i64) -> (i64, u32) {
+    const TWO63: u64 = 1 << 63;
+    let ad = d.unsigned_abs();
+    let t = TWO63 + ((d as u64) >> 63);
+    let anc = t - 1 - t % ad;
+    let mut p = 63;
+    let (mut q1, mut r1) = (TWO63 / anc, TWO63 % anc);
+    let (mut q2, mut r2) = (TWO63 / ad, TWO63 % ad);
+    loop {
+        p += 1;
+        q1 = q1.wrapping_mul(2);
+        r1 = r1.wrapping_mul(2);
+        if r1 >= anc {
+            q1 = q1.wrapping_add(1);
+            r1 = r1.wrapping_sub(anc);
+        }
+        q2 = q2.wrapping_mul(2);
+        r2 = r2.wrapping_mul(2);
+        if r2 >= ad {
+            q2 = q2.wrapping_add(1);
+            r2 = r2.wrapping_sub(ad);
+        }
+        let delta = ad - r2;
+        if !(q1 < delta || (q1 == delta && r1 == 0)) {
+            break;
+        }
+    }
+    let magic = q2.wrapping_add(1) as i64;
+    (if d < 0 { magic.wrapping_neg() } else { magic }, p - 64)
+}
@@ -0,0 +1,295 @@
+//! System calls made directly with `syscall`.
+//!
+//! A [`Syscall`] names one of the common Unix calls, numbered for each
+//! target that has it, and [`emit_syscall`] makes one on x86-64 Linux,
+//! loading the number and each argument into its register:
+//!
+//! ```
+//! use cataclysm::{consts::RSI, syscall::{emit_syscall, Syscall}};
+//!
+//! let code = emit_syscall(Syscall::Write, &[1.into(), RSI.into(), 5.into()]).unwrap();
+//! let lines: Vec<String> = code.iter().map(|line| line.to_string().trim().replace('\t', " ")).collect();
+//! assert_eq!(lines, ["mov rax, 1", "mov rdi, 1", "mov rdx, 5", "syscall"]);
+//! ```
+//!
+//! Windows renumbers its system calls between builds, so its stubs, one
+//! small function per native call for code that cannot or should not go
+//! through ntdll, take their numbers from a [`SyscallTable`] the caller
+//! builds or loads for the version it targets. Each stub follows the
+//! ntdll convention: the first argument moves from rcx to r10, because
+//! `syscall` overwrites rcx with the return address, and the call number
+//! goes in eax.
+
+use std::{error, fmt};
+
+use crate::{
+    consts::{R10, RAX, RCX},
+    fixed::parallel_move,
+    instr::{mov, ret, syscall},
+    register::Gpr,
+    target::{Arch, Os, Target},
+    AsmExpr, Global, ImmediateValue, Label, Operand,
+};
+
+/// The registers Linux and macOS take system call arguments in, in order.
+pub const ARGUMENT_REGISTERS: [Gpr; 6] = [Gpr::RDI, Gpr::RSI, Gpr::RDX, Gpr::R10, Gpr::R8, Gpr::R9];
+
+/// The class macOS on x86-64 adds to the number of a BSD call.
+const MACOS_BSD_CLASS: u64 = 0x200_0000;
+
+macro_rules! syscalls {
+    ($($variant:ident => $name:literal, $args:literal, $amd64:literal, $generic:literal, $macos:expr;)*) => {
+        /// A Unix system call.
+        #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+        pub enum Syscall {
+            $($variant,)*
+        }
+
+        impl Syscall {
+            /// The C library's name for the call.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Syscall::$variant => $name,)*
+                }
+            }
+
+            /// How many arguments the call takes.
+            pub fn arguments(self) -> usize {
+                match self {
+                    $(Syscall::$variant => $args,)*
+                }
+            }
+
+            /// The call's number on x86-64 Linux, on the architectures using
+            /// Linux's generic table, and in macOS's BSD class, if it has one.
+            fn numbers(self) -> (u64, u64, Option<u64>) {
+                match self {
+                    $(Syscall::$variant => ($amd64, $generic, $macos),)*
+                }
+            }
+        }
+    };
+}
+
+syscalls! {
+    Read => "read", 3, 0, 63, Some(3);
+    Write => "write", 3, 1, 64, Some(4);
+    Openat => "openat", 4, 257, 56, Some(463);
+    Close => "close", 1, 3, 57, Some(6);
+    Lseek => "lseek", 3, 8, 62, Some(199);
+    Mmap => "mmap", 6, 9, 222, Some(197);
+    Mprotect => "mprotect", 3, 10, 226, Some(74);
+    Munmap => "munmap", 2, 11, 215, Some(73);
+    Brk => "brk", 1, 12, 214, None;
+    Nanosleep => "nanosleep", 2, 35, 101, None;
+    Getpid => "getpid", 0, 39, 172, Some(20);
+    Kill => "kill", 2, 62, 129, Some(37);
+    Exit => "exit", 1, 60, 93, Some(1);
+    ExitGroup => "exit_group", 1, 231, 94, None;
+}
+
+impl Syscall {
+    /// The number that makes the call on `target`, as loaded into the
+    /// register that selects it: rax on x86-64, with the x32 bit set under
+    /// that ABI, x8 on Linux's other architectures and x16 on macOS.
+    pub fn number(self, target: &Target) -> Option<u64> {
+        let (amd64, generic, macos) = self.numbers();
+        match (target.os, target.arch) {
+            (Os::Linux, Arch::Amd64) => Some(target.abi.syscall_number(amd64)),
+            (Os::Linux, Arch::Arm64 | Arch::Riscv64) => Some(generic),
+            (Os::MacOs, Arch::Amd64) => macos.map(|n| n | MACOS_BSD_CLASS),
+            (Os::MacOs, _) => macos,
+        }
+    }
+
+    /// The code making the call on `target`, which must be x86-64, with
+    /// `args` read as if all were read before any was passed. The result
+    /// is left in rax; rcx, r11 and the argument registers are clobbered.
+    pub fn emit(self, target: &Target, args: &[Operand]) -> Result<Vec<AsmExpr>, SyscallError> {
+        if args.len() != self.arguments() {
+            return Err(SyscallError::Arguments {
+                call: self,
+                found: args.len(),
+            });
+        }
+        let number = self
+            .number(target)
+            .filter(|_| target.arch == Arch::Amd64)
+            .ok_or_else(|| SyscallError::Unsupported {
+                call: self,
+                target: target.name(),
+            })?;
+        let mut moves = vec![(Gpr::RAX, Operand::from(number))];
+        for (index, (&reg, arg)) in ARGUMENT_REGISTERS.iter().zip(args).enumerate() {
+            let passable = match arg {
+                Operand::Register(reg) => reg.gpr().is_some(),
+                Operand::Memory(_) => true,
+                Operand::Immediate(imm) => !matches!(imm, ImmediateValue::Bytes(_)),
+                Operand::Param(_) => false,
+            };
+            if !passable {
+                return Err(SyscallError::Argument {
+                    index,
+                    operand: arg.to_string(),
+                });
+            }
+            moves.push((reg, arg.clone()));
+        }
+        let mut code = parallel_move(moves);
+        code.push(syscall());
+        Ok(code)
+    }
+}
+
+impl fmt::Display for Syscall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyscallError {
+    /// The call was given `found` arguments rather than the number it
+    /// takes.
+    Arguments { call: Syscall, found: usize },
+    /// The target has no such call, or is not one calls are emitted for.
+    Unsupported { call: Syscall, target: String },
+    /// Argument `index`, counting from 0, is not a 64-bit general-purpose
+    /// register, memory or a plain immediate.
+    Argument { index: usize, operand: String },
+}
+
+impl fmt::Display for SyscallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyscallError::Arguments { call, found } => write!(
+                f,
+                "`{}` takes {} arguments, not {}",
+                call,
+                call.arguments(),
+                found
+            ),
+            SyscallError::Unsupported { call, target } => {
+                write!(f, "`{}` cannot be called on {}", call, target)
+            }
+            SyscallError::Argument { index, operand } => {
+                write!(f, "argument {} (`{}`) cannot be passed", index, operand)
+            }
+        }
+    }
+}
+
+impl error::Error for SyscallError {}
+
+/// The code making `call` on x86-64 Linux; see [`Syscall::emit`].
+pub fn emit_syscall(call: Syscall, args: &[Operand]) -> Result<Vec<AsmExpr>, SyscallError> {
+    call.emit(&Target::x86_64(), args)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyscallTableError {
+    /// One-based line number in the table text.
+    pub line: usize,
+    pub text: String,
+}
+
+impl fmt::Display for SyscallTableError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "line {}: expected `name number`, found `{}`",
+            self.line, self.text
+        )
+    }
+}
+
+impl error::Error for SyscallTableError {}
+
+/// System-call numbers by function name, such as `NtClose`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SyscallTable {
+    entries: Vec<(String, u32)>,
+}
+
+impl SyscallTable {
+    pub fn new() -> Self {
+        SyscallTable::default()
+    }
+
+    /// Adds or renumbers `name`.
+    pub fn with(mut self, name: &str, number: u32) -> Self {
+        match self.entries.iter_mut().find(|(n, _)| n == name) {
+            Some(entry) => entry.1 = number,
+            None => self.entries.push((name.to_string(), number)),
+        }
+        self
+    }
+
+    /// Reads a table of `name number` lines, with numbers in decimal or
+    /// `0x` hex. Blank lines and text after `#` or `;` are ignored.
+    pub fn parse(text: &str) -> Result<Self, SyscallTableError> {
+        let mut table = SyscallTable::new();
+        for (i, line) in text.lines().enumerate() {
+            let content = line.split(['#', ';']).next().unwrap_or_default().trim();
+            if content.is_empty() {
+                continue;
+            }
+            let error = || SyscallTableError {
+                line: i + 1,
+                text: line.trim().to_string(),
+            };
+            let mut fields = content.split_whitespace();
+            let (Some(name), Some(number), None) = (fields.next(), fields.next(), fields.next())
+            else {
+                return Err(error());
+            };
+            let number = match number.strip_prefix("0x").or(number.strip_prefix("0X")) {
+                Some(hex) => u32::from_str_radix(hex, 16),
+                None => number.parse(),
+            }
+            .map_err(|_| error())?;
+            table = table.with(name, number);
+        }
+        Ok(table)
+    }
+
+    pub fn number(&self, name: &str) -> Option<u32> {
+        self.entries
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|&(_, number)| number)
+    }
+
+    pub fn entries(&self) -> &[(String, u32)] {
+        &self.entries
+    }
+
+    /// A stub for every entry, in table order.
+    pub fn stubs(&self) -> Vec<AsmExpr> {
+        self.entries
+            .iter()
+            .flat_map(|(name, number)| windows_stub(name, *number))
+            .collect()
+    }
+
+    /// Exports every stub.
+    pub fn globals(&self) -> Vec<Global> {
+        self.entries
+            .iter()
+            .map(|(name, _)| Global::new(name))
+            .collect()
+    }
+}
+
+/// A function `name` that makes system call `number` with the caller's
+/// arguments. The number is loaded through rax, which leaves eax as
+/// `mov eax, number` would and clears the upper half.
+pub fn windows_stub(name: &str, number: u32) -> Vec<AsmExpr> {
+    vec![
+        AsmExpr::Label(Label::plain(name)),
+        mov(R10, RCX),
+        mov(RAX, number),
+        syscall(),
+        ret(),
+    ]
+}
@@ -0,0 +1,63 @@
+//! Tables of label addresses and label offsets, for vtables, dispatch
+//! tables and the like.
+//!
+//! An [`addresses`] table holds one pointer per entry and needs an
+//! absolute relocation for each when linked. An [`offsets`] table holds
+//! each entry's 32-bit signed distance from the table itself, which needs
+//! no relocation when the targets are in the same section and a
+//! PC-relative one otherwise, so it also works in position-independent
+//! code. A dispatch through one adds the entry back to the table's
+//! address:
+//!
+//! ```text
+//!         lea     rdx, [rel table]
+//!         movsxd  rax, dword [rdx + rcx*4]
+//!         add     rax, rdx
+//!         jmp     rax
+//! ```
+
+use crate::{AsmExpr, Data, Label};
+
+/// `name`, followed by the address of each of `targets`.
+pub fn addresses(name: &str, targets: &[Label]) -> AsmExpr {
+    let mut body = vec![AsmExpr::Label(Label::plain(name))];
+    body.extend(
+        targets
+            .iter()
+            .map(|target| AsmExpr::Data(Data::Address(target.clone()))),
+    );
+    AsmExpr::Block(body)
+}
+
+/// `name`, followed by the distance from `name` to each of `targets`.
+///
+/// ```
+/// use cataclysm::{table, Label};
+///
+/// let table = table::offsets("ops", &[Label::plain("op_add"), Label::plain("op_sub")]);
+/// assert_eq!(table.to_string(), "\tops:\n\t\tdd op_add - ops\n\t\tdd op_sub - ops\n");
+/// ```
+pub fn offsets(name: &str, targets: &[Label]) -> AsmExpr {
+    let mut body = vec![AsmExpr::Label(Label::plain(name))];
+    if let AsmExpr::Block(entries) = offsets_from(Label::plain(name), targets) {
+        body.extend(entries);
+    }
+    AsmExpr::Block(body)
+}
+
+/// The distance from `base` to each of `targets`, for tables measured from
+/// somewhere other than their own start, such as the function they serve.
+/// `base` must be in the same section as the table or as every target.
+pub fn offsets_from(base: Label, targets: &[Label]) -> AsmExpr {
+    AsmExpr::Block(
+        targets
+            .iter()
+            .map(|target| {
+                AsmExpr::Data(Data::Offset {
+                    label: target.clone(),
+                    base: base.clone(),
+                })
+            })
+            .collect(),
+    )
+}
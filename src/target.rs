@@ -0,0 +1,571 @@
+use std::{collections::BTreeSet, error, fmt};
+
+use crate::{
+    dataflow, program::Program, Amd64Instruction, Amd64Register, AsmExpr, Data, ImmediateValue,
+    Operand,
+};
+
+/// Optional instruction-set extensions a target may provide.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum CpuFeature {
+    /// Advanced Performance Extensions: r16-r31 and REX2 encodings.
+    Apx,
+    /// User-mode access to the fs and gs segment bases.
+    Fsgsbase,
+    /// 256-bit vector registers and VEX-encoded instructions.
+    Avx,
+    /// The AVX-512 foundation: zmm registers and EVEX-encoded instructions.
+    Avx512f,
+    /// Bit Manipulation Instruction Set 1: andn, bextr, tzcnt.
+    Bmi1,
+    /// Bit Manipulation Instruction Set 2: pdep, pext.
+    Bmi2,
+    /// Leading-zero count.
+    Lzcnt,
+    /// Population count.
+    Popcnt,
+    /// AES round instructions.
+    Aes,
+    /// SHA-1 and SHA-256 message and round instructions.
+    Sha,
+    /// SSE4.2, which provides crc32.
+    Sse42,
+    /// Carry-less multiplication.
+    Pclmulqdq,
+    /// Timestamp-counter read that waits for earlier instructions.
+    Rdtscp,
+    /// AMX tile registers, configuration, loads and stores.
+    AmxTile,
+    /// AMX bfloat16 tile dot products.
+    AmxBf16,
+    /// AMX 8-bit integer tile dot products.
+    AmxInt8,
+    /// Weakly ordered cache-line flush.
+    Clflushopt,
+    /// Cache-line write-back that may keep the line cached.
+    Clwb,
+}
+
+impl fmt::Display for CpuFeature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            CpuFeature::Apx => "apx",
+            CpuFeature::Fsgsbase => "fsgsbase",
+            CpuFeature::Avx => "avx",
+            CpuFeature::Avx512f => "avx512f",
+            CpuFeature::Bmi1 => "bmi1",
+            CpuFeature::Bmi2 => "bmi2",
+            CpuFeature::Lzcnt => "lzcnt",
+            CpuFeature::Popcnt => "popcnt",
+            CpuFeature::Aes => "aes",
+            CpuFeature::Sha => "sha",
+            CpuFeature::Sse42 => "sse4.2",
+            CpuFeature::Pclmulqdq => "pclmulqdq",
+            CpuFeature::Rdtscp => "rdtscp",
+            CpuFeature::AmxTile => "amx-tile",
+            CpuFeature::AmxBf16 => "amx-bf16",
+            CpuFeature::AmxInt8 => "amx-int8",
+            CpuFeature::Clflushopt => "clflushopt",
+            CpuFeature::Clwb => "clwb",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The calling and data-layout convention a program follows.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum Abi {
+    /// The standard LP64 System V ABI.
+    #[default]
+    SystemV,
+    /// 64-bit instructions with 32-bit pointers (ILP32).
+    X32,
+}
+
+/// Bit the kernel uses to tell x32 system calls apart from native ones.
+const X32_SYSCALL_BIT: u64 = 0x4000_0000;
+
+impl Abi {
+    /// Size of a pointer in bytes.
+    pub fn pointer_width(self) -> u32 {
+        match self {
+            Abi::SystemV => 8,
+            Abi::X32 => 4,
+        }
+    }
+
+    /// The number to load into `rax` to invoke system call `nr`.
+    pub fn syscall_number(self, nr: u64) -> u64 {
+        match self {
+            Abi::SystemV => nr,
+            Abi::X32 => nr | X32_SYSCALL_BIT,
+        }
+    }
+}
+
+/// The privilege level a program is declared to run at.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum Mode {
+    /// Undeclared: privileged instructions are not checked.
+    #[default]
+    Any,
+    /// Ring 3, as ordinary processes run.
+    User,
+    /// Ring 0.
+    Kernel,
+}
+
+/// The instruction set a program is written in.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum Arch {
+    /// x86-64, emitted as NASM and encodable to machine code.
+    #[default]
+    Amd64,
+    /// AArch64, emitted for the GNU or Apple assembler; see
+    /// [`arm64`](crate::arm64).
+    Arm64,
+    /// RV64IM, emitted for the GNU assembler; see [`riscv`](crate::riscv).
+    Riscv64,
+}
+
+impl Arch {
+    pub fn name(self) -> &'static str {
+        match self {
+            Arch::Amd64 => "x86_64",
+            Arch::Arm64 => "aarch64",
+            Arch::Riscv64 => "riscv64",
+        }
+    }
+}
+
+/// The operating system a program is assembled for, where that changes
+/// the assembly syntax.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum Os {
+    #[default]
+    Linux,
+    /// macOS, whose assembler has Mach-O section names and its own
+    /// spelling of page-relative addresses.
+    MacOs,
+}
+
+/// The machine a program is generated for.
+#[derive(Clone, Default)]
+pub struct Target {
+    pub arch: Arch,
+    pub os: Os,
+    pub features: BTreeSet<CpuFeature>,
+    pub abi: Abi,
+    pub mode: Mode,
+}
+
+impl Target {
+    /// Baseline x86-64 with no optional extensions.
+    pub fn x86_64() -> Self {
+        Self::default()
+    }
+
+    /// AArch64 on `os`.
+    pub fn arm64(os: Os) -> Self {
+        Target {
+            arch: Arch::Arm64,
+            os,
+            ..Self::default()
+        }
+    }
+
+    /// 64-bit RISC-V on Linux.
+    pub fn riscv64() -> Self {
+        Target {
+            arch: Arch::Riscv64,
+            ..Self::default()
+        }
+    }
+
+    /// Baseline x86-64 running the x32 ABI.
+    pub fn x32() -> Self {
+        Target {
+            abi: Abi::X32,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_feature(mut self, feature: CpuFeature) -> Self {
+        self.features.insert(feature);
+        self
+    }
+
+    pub fn has(&self, feature: CpuFeature) -> bool {
+        self.features.contains(&feature)
+    }
+
+    /// A short description such as `x86_64-x32+avx+bmi2` or
+    /// `aarch64-macos`.
+    pub fn name(&self) -> String {
+        let mut name = String::from(self.arch.name());
+        if self.os == Os::MacOs {
+            name.push_str("-macos");
+        }
+        if self.abi == Abi::X32 {
+            name.push_str("-x32");
+        }
+        for feature in &self.features {
+            name.push_str(&format!("+{}", feature));
+        }
+        name
+    }
+
+    pub fn with_mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+}
+
+/// The extension that introduced `mnemonic`, if it is not baseline.
+fn mnemonic_feature(mnemonic: &str) -> Option<CpuFeature> {
+    match mnemonic {
+        "rdfsbase" | "wrfsbase" | "rdgsbase" | "wrgsbase" => Some(CpuFeature::Fsgsbase),
+        "ldtilecfg" | "sttilecfg" | "tileloadd" | "tileloaddt1" | "tilestored" | "tilezero"
+        | "tilerelease" => Some(CpuFeature::AmxTile),
+        "vmovdqa32" | "vmovdqa64" | "vmovdqu32" | "vmovdqu64" | "vpandd" | "vpandq" | "vpord"
+        | "vporq" | "vpxord" | "vpxorq" => Some(CpuFeature::Avx512f),
+        "vmovaps" | "vmovups" | "vmovapd" | "vmovupd" | "vmovdqa" | "vmovdqu" | "vaddps"
+        | "vaddpd" | "vsubps" | "vsubpd" | "vmulps" | "vmulpd" | "vdivps" | "vdivpd" | "vandps"
+        | "vandpd" | "vorps" | "vorpd" | "vxorps" | "vxorpd" | "vpand" | "vpor" | "vpxor"
+        | "vpaddd" | "vpaddq" | "vpsubd" | "vpsubq" | "vzeroupper" | "vzeroall" => {
+            Some(CpuFeature::Avx)
+        }
+        "andn" | "bextr" | "tzcnt" => Some(CpuFeature::Bmi1),
+        "pdep" | "pext" => Some(CpuFeature::Bmi2),
+        "lzcnt" => Some(CpuFeature::Lzcnt),
+        "popcnt" => Some(CpuFeature::Popcnt),
+        "aesenc" | "aesenclast" | "aesdec" | "aesdeclast" | "aesimc" | "aeskeygenassist" => {
+            Some(CpuFeature::Aes)
+        }
+        "sha1rnds4" | "sha1nexte" | "sha1msg1" | "sha1msg2" | "sha256rnds2" | "sha256msg1"
+        | "sha256msg2" => Some(CpuFeature::Sha),
+        "crc32" => Some(CpuFeature::Sse42),
+        "pclmulqdq" => Some(CpuFeature::Pclmulqdq),
+        "rdtscp" => Some(CpuFeature::Rdtscp),
+        "tdpbf16ps" => Some(CpuFeature::AmxBf16),
+        "tdpbssd" | "tdpbsud" | "tdpbusd" | "tdpbuud" => Some(CpuFeature::AmxInt8),
+        "clflushopt" => Some(CpuFeature::Clflushopt),
+        "clwb" => Some(CpuFeature::Clwb),
+        _ => None,
+    }
+}
+
+/// Every extension `inst` needs, judging by its mnemonic and operands.
+pub fn required_features(inst: &Amd64Instruction) -> BTreeSet<CpuFeature> {
+    let mut features: BTreeSet<_> = mnemonic_feature(inst.mnemonic.as_str())
+        .into_iter()
+        .collect();
+
+    for operand in &inst.operands {
+        let regs = match operand {
+            Operand::Register(reg) => vec![reg],
+            Operand::Memory(m) => m.registers().collect(),
+            _ => continue,
+        };
+        for reg in regs {
+            match reg {
+                // By number, so `r16d` counts as much as `r16` does.
+                _ if reg.containing_gpr().is_some_and(|gpr| gpr.is_extended()) => {
+                    features.insert(CpuFeature::Apx);
+                }
+                Amd64Register::Ymm(_) => {
+                    features.insert(CpuFeature::Avx);
+                }
+                Amd64Register::Zmm(_) => {
+                    features.insert(CpuFeature::Avx512f);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    features
+}
+
+/// An instruction that needs an extension the target does not have.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeatureError {
+    pub section: String,
+    pub instruction: String,
+    pub missing: CpuFeature,
+}
+
+impl fmt::Display for FeatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "`{}` in section .{} requires the `{}` feature, which the target lacks",
+            self.instruction, self.section, self.missing
+        )
+    }
+}
+
+impl error::Error for FeatureError {}
+
+/// Checks every instruction of `program` against its target, reporting
+/// each one that relies on a missing extension.
+pub fn check_features(program: &Program) -> Result<(), Vec<FeatureError>> {
+    let mut errors = Vec::new();
+
+    for section in &program.sections {
+        check_block(&section.body, &section.name, &program.target, &mut errors);
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn check_block(body: &[AsmExpr], section: &str, target: &Target, errors: &mut Vec<FeatureError>) {
+    for_each_instruction(body, &mut |inst| {
+        for missing in required_features(inst)
+            .into_iter()
+            .filter(|f| !target.has(*f))
+        {
+            errors.push(FeatureError {
+                section: section.to_string(),
+                instruction: inst.to_string(),
+                missing,
+            });
+        }
+    });
+}
+
+/// Every instruction in `body`, in both arms of each conditional.
+fn for_each_instruction(body: &[AsmExpr], f: &mut impl FnMut(&Amd64Instruction)) {
+    for expr in body {
+        if let AsmExpr::Instruction(inst) = expr {
+            f(inst);
+        }
+        for inner in expr.bodies() {
+            for_each_instruction(inner, f);
+        }
+    }
+}
+
+/// The least privileged level that may run `mnemonic`: 0 for instructions
+/// that fault with #GP outside ring 0, 3 for everything else. Port I/O and
+/// `cli`/`sti` count as ring 0, as without an I/O privilege level granted
+/// by the kernel they fault too.
+pub fn required_cpl(mnemonic: &str) -> u8 {
+    match dataflow::split_prefix(mnemonic).1 {
+        "hlt" | "rdmsr" | "wrmsr" | "invlpg" | "invlpga" | "invpcid" | "invd" | "wbinvd"
+        | "lgdt" | "lidt" | "lldt" | "ltr" | "lmsw" | "clts" | "swapgs" | "sysret" | "sysretq"
+        | "sysexit" | "xsetbv" | "vmxon" | "vmxoff" | "vmlaunch" | "vmresume" | "cli" | "sti"
+        | "in" | "out" | "insb" | "insw" | "insd" | "outsb" | "outsw" | "outsd" => 0,
+        _ => 3,
+    }
+}
+
+/// A privileged instruction in a program declared to run in user mode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrivilegeError {
+    pub section: String,
+    pub instruction: String,
+}
+
+impl fmt::Display for PrivilegeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "`{}` in section .{} only runs in ring 0, but the target is user mode",
+            self.instruction, self.section
+        )
+    }
+}
+
+impl error::Error for PrivilegeError {}
+
+/// Reports every instruction that would raise #GP when the target is
+/// declared [`Mode::User`]. Other modes pass everything.
+pub fn check_privilege(program: &Program) -> Result<(), Vec<PrivilegeError>> {
+    let mut errors = Vec::new();
+    if program.target.mode != Mode::User {
+        return Ok(());
+    }
+
+    for section in &program.sections {
+        for_each_instruction(&section.body, &mut |inst| {
+            if required_cpl(inst.mnemonic.as_str()) == 0 {
+                errors.push(PrivilegeError {
+                    section: section.name.clone(),
+                    instruction: inst.to_string(),
+                });
+            }
+        });
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// A value used as an address that cannot be represented in a 32-bit
+/// pointer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressError {
+    pub section: String,
+    /// The offending instruction or data item, as emitted.
+    pub item: String,
+    pub value: u64,
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "address {:#x} in `{}` in section .{} does not fit the x32 ABI's 32-bit pointers",
+            self.value, self.item, self.section
+        )
+    }
+}
+
+impl error::Error for AddressError {}
+
+/// Confirms every absolute address in `program` fits in 32 bits when the
+/// target uses the x32 ABI: numeric branch targets, pointer-sized
+/// immediates, pointer-sized data and memory operands with neither a base
+/// register nor a label. A displacement from a base or label cannot reach
+/// past 4 GiB either, so one that needs more than 32 bits is reported too.
+/// Other ABIs always pass.
+pub fn check_addresses(program: &Program) -> Result<(), Vec<AddressError>> {
+    let mut errors = Vec::new();
+
+    if program.target.abi == Abi::X32 {
+        for section in &program.sections {
+            check_address_block(&section.body, &section.name, &mut errors);
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn is_branch(mnemonic: &str) -> bool {
+    mnemonic == "call" || mnemonic.starts_with('j')
+}
+
+/// The value `operand` would be used as an address with, if it is a
+/// numeric one.
+fn address_operand(inst: &Amd64Instruction, operand: &Operand) -> Option<u64> {
+    match operand {
+        Operand::Immediate(ImmediateValue::USize(v)) => Some(*v as u64),
+        Operand::Immediate(ImmediateValue::U64(v)) if is_branch(inst.mnemonic.as_str()) => Some(*v),
+        Operand::Immediate(ImmediateValue::I64(v)) if is_branch(inst.mnemonic.as_str()) => {
+            Some(*v as u64)
+        }
+        // The displacement is the address, or the start of the table an
+        // index reads, sign-extended as a negative one is.
+        Operand::Memory(mem) if mem.base.is_none() && mem.label.is_none() => {
+            Some(mem.displacement as u64)
+        }
+        Operand::Memory(mem) if mem.displacement.unsigned_abs() > u32::MAX as u64 => {
+            Some(mem.displacement as u64)
+        }
+        _ => None,
+    }
+}
+
+fn check_address_block(body: &[AsmExpr], section: &str, errors: &mut Vec<AddressError>) {
+    for expr in body {
+        let (item, values) = match expr {
+            AsmExpr::Instruction(inst) => (
+                inst.to_string(),
+                inst.operands
+                    .iter()
+                    .filter_map(|op| address_operand(inst, op))
+                    .collect(),
+            ),
+            AsmExpr::Data(data @ Data::USize(v)) => (data.to_string(), vec![*v as u64]),
+            _ => (String::new(), Vec::new()),
+        };
+        for value in values.into_iter().filter(|v| *v > u32::MAX as u64) {
+            errors.push(AddressError {
+                section: section.to_string(),
+                item: item.clone(),
+                value,
+            });
+        }
+        for inner in expr.bodies() {
+            check_address_block(inner, section, errors);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        consts::{R16, RAX},
+        instr,
+        register::{Gpr, GprWidth},
+        Mem, Section,
+    };
+
+    #[test]
+    fn apx_registers_need_apx_at_any_width() {
+        let r17d = Amd64Register::Partial(Gpr::R17, GprWidth::Dword);
+        let uses = [
+            instr::mov(R16, RAX),
+            instr::mov(r17d.clone(), 1u32),
+            instr::mov(RAX, Mem::base(RAX).with_index(r17d, 4)),
+        ];
+        for expr in uses {
+            let AsmExpr::Instruction(inst) = expr else {
+                unreachable!()
+            };
+            assert!(
+                required_features(&inst).contains(&CpuFeature::Apx),
+                "{}",
+                inst
+            );
+        }
+        let AsmExpr::Instruction(plain) = instr::mov(RAX, 1u32) else {
+            unreachable!()
+        };
+        assert!(required_features(&plain).is_empty());
+    }
+
+    #[test]
+    fn x32_memory_operands_stay_below_4_gib() {
+        // Literals, as the builders stop a base from taking a displacement
+        // past 32 bits.
+        let based = |displacement| Mem {
+            displacement,
+            ..Mem::base(RAX)
+        };
+        let absolute = |displacement| Mem {
+            base: None,
+            ..based(displacement)
+        };
+        let body = vec![
+            instr::mov(RAX, absolute(0x1_0000_0000)),
+            instr::mov(RAX, absolute(-8)),
+            instr::mov(RAX, based(0x1_0000_0000)),
+            instr::mov(RAX, absolute(0xffff_fff0)),
+            instr::mov(RAX, based(-8)),
+            instr::mov(RAX, Mem::label("table".into()).with_displacement(16)),
+        ];
+        let program = Program::default()
+            .with_target(Target::x32())
+            .with_section(Section::new("text", body.clone()));
+        let errors = check_addresses(&program).unwrap_err();
+        let values: Vec<u64> = errors.iter().map(|e| e.value).collect();
+        assert_eq!(values, [0x1_0000_0000, -8i64 as u64, 0x1_0000_0000]);
+
+        let native = Program::default().with_section(Section::new("text", body));
+        assert!(check_addresses(&native).is_ok());
+    }
+}
@@ -0,0 +1,270 @@
+use std::{error, fmt, sync::Arc};
+
+use crate::{
+    macros::{self, MacroArg, MacroError},
+    program::Program,
+    Data, ImmediateValue, Label, Operand,
+};
+
+/// What a template placeholder may be bound to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaceholderKind {
+    Immediate,
+    Label,
+    Bytes,
+}
+
+/// Value bound to a placeholder when instantiating a [`ProgramTemplate`].
+#[derive(Clone)]
+pub enum Binding {
+    Immediate(ImmediateValue),
+    Label(Label),
+    Bytes(Vec<u8>),
+}
+
+impl Binding {
+    pub fn kind(&self) -> PlaceholderKind {
+        match self {
+            Binding::Immediate(_) => PlaceholderKind::Immediate,
+            Binding::Label(_) => PlaceholderKind::Label,
+            Binding::Bytes(_) => PlaceholderKind::Bytes,
+        }
+    }
+
+    fn into_arg(self) -> MacroArg {
+        match self {
+            Binding::Immediate(imm) => MacroArg::Operand(Operand::Immediate(imm)),
+            Binding::Label(label) => MacroArg::Label(label),
+            Binding::Bytes(bytes) => MacroArg::Data(Data::Bytes(bytes)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateError {
+    Missing(String),
+    Unknown(String),
+    /// A placeholder bound more than once.
+    Duplicate(String),
+    Kind {
+        name: String,
+        expected: PlaceholderKind,
+        found: PlaceholderKind,
+    },
+    Substitution(MacroError),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemplateError::Missing(name) => write!(f, "placeholder `{}` is not bound", name),
+            TemplateError::Unknown(name) => write!(f, "template has no placeholder `{}`", name),
+            TemplateError::Duplicate(name) => {
+                write!(f, "placeholder `{}` is bound more than once", name)
+            }
+            TemplateError::Kind {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "placeholder `{}` expects {:?} but was bound to {:?}",
+                name, expected, found
+            ),
+            TemplateError::Substitution(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl error::Error for TemplateError {}
+
+impl From<MacroError> for TemplateError {
+    fn from(err: MacroError) -> Self {
+        TemplateError::Substitution(err)
+    }
+}
+
+/// A prebuilt program with typed holes, instantiated as often as needed
+/// without re-running the code that built it — e.g. a trampoline whose
+/// target address and argument count differ per use.
+///
+/// Placeholders are written like macro parameters: `Operand::Param` for
+/// immediates, a label spelled like the placeholder for labels, and
+/// `AsmExpr::Param` for byte blobs.
+#[derive(Clone)]
+pub struct ProgramTemplate {
+    program: Program,
+    placeholders: Vec<(String, PlaceholderKind)>,
+}
+
+impl ProgramTemplate {
+    pub fn new(program: Program) -> Self {
+        ProgramTemplate {
+            program,
+            placeholders: Vec::new(),
+        }
+    }
+
+    pub fn placeholder(mut self, name: &str, kind: PlaceholderKind) -> Self {
+        self.placeholders.push((name.to_string(), kind));
+        self
+    }
+
+    pub fn placeholders(&self) -> &[(String, PlaceholderKind)] {
+        &self.placeholders
+    }
+
+    /// Produces a concrete program with every placeholder replaced. Each
+    /// placeholder must be bound exactly once, to a value of its kind.
+    pub fn instantiate(&self, bindings: &[(&str, Binding)]) -> Result<Program, TemplateError> {
+        if let Some((name, _)) = bindings
+            .iter()
+            .find(|(name, _)| !self.placeholders.iter().any(|(p, _)| p == name))
+        {
+            return Err(TemplateError::Unknown(name.to_string()));
+        }
+        if let Some((name, _)) = bindings.iter().enumerate().find_map(|(i, binding)| {
+            let bound = bindings[..i].iter().any(|(name, _)| *name == binding.0);
+            bound.then_some(binding)
+        }) {
+            return Err(TemplateError::Duplicate(name.to_string()));
+        }
+
+        let mut params = Vec::with_capacity(self.placeholders.len());
+        let mut args = Vec::with_capacity(self.placeholders.len());
+        for (name, kind) in &self.placeholders {
+            let (_, binding) = bindings
+                .iter()
+                .find(|(n, _)| n == name)
+                .ok_or_else(|| TemplateError::Missing(name.clone()))?;
+
+            if binding.kind() != *kind {
+                return Err(TemplateError::Kind {
+                    name: name.clone(),
+                    expected: *kind,
+                    found: binding.kind(),
+                });
+            }
+
+            params.push(name.clone());
+            args.push(binding.clone().into_arg());
+        }
+
+        let mut program = self.program.clone();
+        for section in program.sections.iter_mut() {
+            section.body = Arc::new(macros::substitute(
+                "template",
+                &params,
+                &args,
+                &section.body,
+            )?);
+        }
+
+        Ok(program)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{consts::RAX, instr, mnemonic::Amd64Mnemonic, Amd64Instruction, AsmExpr, Section};
+
+    /// Loads `value`, jumps to `target`, and carries `payload` as data.
+    fn trampoline() -> ProgramTemplate {
+        let text = vec![
+            AsmExpr::Instruction(Amd64Instruction::new(
+                Amd64Mnemonic::Mov,
+                vec![RAX.into(), Operand::Param("value".to_string())],
+            )),
+            instr::jmp(Label::plain("target")),
+        ];
+        let data = vec![AsmExpr::Param("payload".to_string())];
+        let program = Program::default()
+            .with_section(Section::new("text", text))
+            .with_section(Section::new("data", data));
+        ProgramTemplate::new(program)
+            .placeholder("value", PlaceholderKind::Immediate)
+            .placeholder("target", PlaceholderKind::Label)
+            .placeholder("payload", PlaceholderKind::Bytes)
+    }
+
+    fn lines(program: &Program) -> Vec<String> {
+        program
+            .sections
+            .iter()
+            .flat_map(|section| section.body.iter())
+            .map(|expr| expr.to_string().trim().replace('\t', " "))
+            .collect()
+    }
+
+    fn bindings(value: i64, target: &str) -> [(&'static str, Binding); 3] {
+        [
+            ("value", Binding::Immediate(ImmediateValue::I64(value))),
+            ("target", Binding::Label(Label::plain(target))),
+            ("payload", Binding::Bytes(vec![1, 2])),
+        ]
+    }
+
+    #[test]
+    fn every_placeholder_is_replaced() {
+        let template = trampoline();
+        let first = template.instantiate(&bindings(7, "first")).unwrap();
+        let second = template.instantiate(&bindings(9, "second")).unwrap();
+        assert_eq!(lines(&first), ["mov rax, 7", "jmp first", "db 0x01, 0x02"]);
+        assert_eq!(
+            lines(&second),
+            ["mov rax, 9", "jmp second", "db 0x01, 0x02"]
+        );
+    }
+
+    #[test]
+    fn bindings_must_match_the_placeholders() {
+        let template = trampoline();
+        let [value, target, payload] = bindings(7, "first");
+        assert_eq!(
+            template.instantiate(&[value.clone(), target.clone()]).err(),
+            Some(TemplateError::Missing("payload".to_string()))
+        );
+
+        let extra = ("other", Binding::Bytes(Vec::new()));
+        assert_eq!(
+            template
+                .instantiate(&[value.clone(), target.clone(), payload.clone(), extra])
+                .err(),
+            Some(TemplateError::Unknown("other".to_string()))
+        );
+
+        let again = ("value", Binding::Immediate(ImmediateValue::I64(8)));
+        assert_eq!(
+            template
+                .instantiate(&[value.clone(), target.clone(), payload.clone(), again])
+                .err(),
+            Some(TemplateError::Duplicate("value".to_string()))
+        );
+
+        let label = ("target", Binding::Immediate(ImmediateValue::I64(0)));
+        assert_eq!(
+            template.instantiate(&[value, label, payload]).err(),
+            Some(TemplateError::Kind {
+                name: "target".to_string(),
+                expected: PlaceholderKind::Label,
+                found: PlaceholderKind::Immediate,
+            })
+        );
+    }
+
+    #[test]
+    fn placeholders_are_used_as_their_kind() {
+        let program = Program::default().with_section(Section::new(
+            "text",
+            vec![instr::jmp(Label::plain("target"))],
+        ));
+        let template =
+            ProgramTemplate::new(program).placeholder("target", PlaceholderKind::Immediate);
+        let binding = ("target", Binding::Immediate(ImmediateValue::I64(0)));
+        assert!(matches!(
+            template.instantiate(&[binding]),
+            Err(TemplateError::Substitution(MacroError::ArgKind { .. }))
+        ));
+    }
+}
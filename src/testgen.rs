@@ -0,0 +1,414 @@
+//! Random but well-formed programs for property-testing transformations.
+//!
+//! Every generated program defines each label it uses, keeps its stack
+//! balanced, and terminates: loops are counted, conditional jumps only go
+//! forward and functions only call functions defined before them. It ends
+//! by writing its value registers to stdout and exiting, so the
+//! [interpreter](crate::interp) can compare runs before and after a pass.
+
+use std::{fmt, ops::Range};
+
+use crate::{
+    cond::Cond,
+    consts::{RAX, RDI, RDX},
+    dedup::dedup_data,
+    expr::ConstExpr,
+    fixed::fix_registers,
+    hint::order_blocks,
+    imm_lowering::{lower_large_immediates, LoweringThreshold},
+    instr::{self, CondCode},
+    interp::{self, InterpError, Outcome},
+    legalize::two_address,
+    optimize::{optimize, OptLevel},
+    program::Program,
+    register::Reg64,
+    rng::Rng,
+    sections::function_sections,
+    spill::{compact_frames, rematerialize},
+    AsmExpr, Data, Global, Label, Mem, Section,
+};
+
+/// Registers generated code computes with. The loop counters and the
+/// stack and frame pointers are kept out of reach.
+const VALUE_REGS: [Reg64; 12] = [
+    Reg64::Rax,
+    Reg64::Rbx,
+    Reg64::Rcx,
+    Reg64::Rdx,
+    Reg64::Rsi,
+    Reg64::Rdi,
+    Reg64::R8,
+    Reg64::R9,
+    Reg64::R10,
+    Reg64::R11,
+    Reg64::R12,
+    Reg64::R13,
+];
+const COUNTERS: [Reg64; 2] = [Reg64::R15, Reg64::R14];
+
+const CONDITIONS: [CondCode; 8] = [
+    CondCode::E,
+    CondCode::Ne,
+    CondCode::B,
+    CondCode::Ae,
+    CondCode::L,
+    CondCode::Ge,
+    CondCode::S,
+    CondCode::Be,
+];
+
+/// Steps allowed per run; generated programs finish far sooner.
+const STEP_LIMIT: usize = 1_000_000;
+
+/// Knobs for the size of generated programs.
+#[derive(Clone, Debug)]
+pub struct GenConfig {
+    /// Statements per block, before nesting.
+    pub block_len: usize,
+    /// Deepest nesting of loops, skips and conditionals.
+    pub max_depth: usize,
+    pub functions: usize,
+    /// Read-only data items, some of them duplicates.
+    pub data_items: usize,
+}
+
+impl Default for GenConfig {
+    fn default() -> Self {
+        GenConfig {
+            block_len: 12,
+            max_depth: 2,
+            functions: 3,
+            data_items: 6,
+        }
+    }
+}
+
+struct Generator<'a> {
+    rng: Rng,
+    config: &'a GenConfig,
+    program: Program,
+    data: Vec<Label>,
+    labels: usize,
+    /// Functions callable from the code being generated.
+    callable: usize,
+}
+
+impl Generator<'_> {
+    fn label(&mut self) -> Label {
+        self.labels += 1;
+        Label::plain(&format!("t{}", self.labels))
+    }
+
+    fn reg(&mut self) -> Reg64 {
+        self.rng.pick(&VALUE_REGS)
+    }
+
+    fn imm32(&mut self) -> i32 {
+        match self.rng.below(3) {
+            0 => self.rng.below(16) as i32,
+            1 => -(self.rng.below(16) as i32),
+            _ => self.rng.next_u64() as i32,
+        }
+    }
+
+    fn block(&mut self, depth: usize) -> Vec<AsmExpr> {
+        let len = 1 + self.rng.below(self.config.block_len);
+        let mut body = Vec::new();
+        for _ in 0..len {
+            self.statement(depth, &mut body);
+        }
+        body
+    }
+
+    fn statement(&mut self, depth: usize, body: &mut Vec<AsmExpr>) {
+        let nested = depth < self.config.max_depth;
+        let (a, b) = (self.reg(), self.reg());
+
+        match self.rng.below(if nested { 14 } else { 10 }) {
+            0 => body.push(self.rng.pick(&[
+                instr::add,
+                instr::sub,
+                instr::and,
+                instr::or,
+                instr::xor,
+            ])(a, b)),
+            1 => {
+                let imm = self.imm32();
+                body.push(self.rng.pick(&[
+                    instr::add,
+                    instr::sub,
+                    instr::xor,
+                    instr::cmp,
+                ])(a, imm));
+            }
+            2 => body.push(instr::imul(a, b)),
+            3 => {
+                // Wide enough to exercise immediate lowering.
+                let imm = self.rng.next_u64() | 1 << 40;
+                body.push(instr::mov(a, imm));
+            }
+            4 => {
+                let name = format!("K{}", self.rng.below(4));
+                body.push(instr::mov(a, ConstExpr::sym(&name) * 3 + 1));
+            }
+            5 if !self.data.is_empty() => {
+                let label = self.rng.pick(&self.data);
+                body.push(instr::mov(a, Mem::label(label)));
+            }
+            5 => {
+                let label = self.program.pool.u64(self.rng.next_u64());
+                body.push(instr::mov(a, Mem::label(label)));
+            }
+            6 => {
+                let count = self.rng.below(64) as u32;
+                body.push(self.rng.pick(&[instr::shl, instr::shr, instr::sar])(
+                    a, count,
+                ));
+            }
+            7 => body.push(self.rng.pick(&[
+                instr::neg,
+                instr::not,
+                instr::inc,
+                instr::dec,
+            ])(a)),
+            8 => {
+                body.push(instr::cmp(a, b));
+                let cond = self.rng.pick(&CONDITIONS);
+                body.push(instr::cmovcc(cond, self.reg(), self.reg()));
+            }
+            9 if self.callable > 0 => {
+                let f = self.rng.below(self.callable);
+                body.push(instr::call(Label::plain(&format!("f{}", f))));
+            }
+            9 => body.push(instr::nop()),
+            10 => {
+                body.push(instr::push(a));
+                body.extend(self.block(depth + 1));
+                body.push(instr::pop(b));
+            }
+            11 => {
+                let skip = self.label();
+                body.push(instr::test(a, b));
+                body.push(instr::jcc(self.rng.pick(&CONDITIONS), skip.clone()));
+                body.extend(self.block(depth + 1));
+                body.push(AsmExpr::Label(skip));
+            }
+            // Each nesting level needs a counter of its own, and the caller's
+            // is saved in case this loop is in a function it calls.
+            12 if depth < COUNTERS.len() => {
+                let counter = COUNTERS[depth];
+                let top = self.label();
+                body.push(instr::push(counter));
+                body.push(instr::mov(counter, 1 + self.rng.below(4) as u32));
+                body.push(AsmExpr::Label(top.clone()));
+                body.extend(self.block(depth + 1));
+                body.push(instr::dec(counter));
+                body.push(instr::jcc(CondCode::Ne, top));
+                body.push(instr::pop(counter));
+            }
+            12 => body.push(instr::nop()),
+            _ => {
+                let flag = format!("F{}", self.rng.below(3));
+                body.push(AsmExpr::If {
+                    cond: Cond::set(&flag),
+                    then: self.block(depth + 1),
+                    otherwise: self.block(depth + 1),
+                });
+            }
+        }
+    }
+}
+
+/// A program built from `seed`, with entry point `_start`. The same seed
+/// and configuration always give the same program.
+pub fn generate(seed: u64, config: &GenConfig) -> Program {
+    let mut gen = Generator {
+        rng: Rng::new(seed),
+        config,
+        program: Program::new(vec![Global::new("_start")], Vec::new()),
+        data: Vec::new(),
+        labels: 0,
+        callable: 0,
+    };
+
+    for i in 0..4 {
+        let value = gen.rng.below(1000) as i64;
+        gen.program.define(&format!("K{}", i), value);
+    }
+    for i in 0..3 {
+        if gen.rng.chance(2) {
+            gen.program.config.enable(&format!("F{}", i));
+        }
+    }
+
+    let mut rodata = Vec::new();
+    let mut values = Vec::new();
+    for i in 0..config.data_items {
+        // Draw from a small set so some items repeat.
+        let value = if values.is_empty() || gen.rng.chance(2) {
+            gen.rng.next_u64()
+        } else {
+            gen.rng.pick(&values)
+        };
+        values.push(value);
+        let label = Label::plain(&format!("d{}", i));
+        rodata.push(AsmExpr::Label(label.clone()));
+        rodata.push(AsmExpr::Data(Data::UInt(value)));
+        gen.data.push(label);
+    }
+
+    let mut text = Vec::new();
+    for f in 0..config.functions {
+        text.push(AsmExpr::Label(Label::plain(&format!("f{}", f))));
+        text.extend(gen.block(0));
+        text.push(instr::ret());
+        gen.callable = f + 1;
+    }
+
+    text.push(AsmExpr::Label(Label::plain("_start")));
+    text.extend(gen.block(0));
+
+    let mut data = Vec::new();
+    for (i, reg) in VALUE_REGS.iter().enumerate() {
+        let label = Label::plain(&format!("out{}", i));
+        text.push(instr::mov(Mem::label(label.clone()), *reg));
+        data.push(AsmExpr::Label(label));
+        data.push(AsmExpr::Data(Data::UInt(0)));
+    }
+    text.extend([
+        instr::mov(RAX, 1u32),
+        instr::mov(RDI, 1u32),
+        instr::lea(Reg64::Rsi, Mem::label(Label::plain("out0"))),
+        instr::mov(RDX, VALUE_REGS.len() as u32 * 8),
+        instr::syscall(),
+        instr::mov(RAX, 60u32),
+        instr::xor(RDI, RDI),
+        instr::syscall(),
+    ]);
+
+    gen.program.sections.extend([
+        Section::new("text", text),
+        Section::new("rodata", rodata),
+        Section::new("data", data),
+    ]);
+    gen.program
+}
+
+/// How a pass broke a generated program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FailureKind {
+    /// The original program failed to run, which is a generator bug.
+    Generator(InterpError),
+    /// The transformed program no longer runs.
+    Invalid(InterpError),
+    /// The transformed program runs but does something else.
+    Changed { before: Outcome, after: Outcome },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PassFailure {
+    pub pass: &'static str,
+    pub seed: u64,
+    /// The program as generated, before the pass ran.
+    pub program: String,
+    pub kind: FailureKind,
+}
+
+impl fmt::Display for PassFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}, seed {}: ", self.pass, self.seed)?;
+        match &self.kind {
+            FailureKind::Generator(e) => write!(f, "generated program fails: {}", e),
+            FailureKind::Invalid(e) => write!(f, "transformed program fails: {}", e),
+            FailureKind::Changed { before, after } => write!(
+                f,
+                "behaviour changed (status {} -> {}, {} -> {} output bytes)",
+                before.status,
+                after.status,
+                before.output.len(),
+                after.output.len()
+            ),
+        }
+    }
+}
+
+/// Runs `pass` over the program generated from each seed and checks that
+/// the result still runs and produces the same status and output.
+pub fn check_pass(
+    name: &'static str,
+    seeds: Range<u64>,
+    config: &GenConfig,
+    pass: impl Fn(&mut Program),
+) -> Result<(), Box<PassFailure>> {
+    for seed in seeds {
+        let mut program = generate(seed, config);
+        let failure = |kind| {
+            Box::new(PassFailure {
+                pass: name,
+                seed,
+                program: generate(seed, config).to_string(),
+                kind,
+            })
+        };
+
+        let before = interp::run(&program, "_start", STEP_LIMIT)
+            .map_err(|e| failure(FailureKind::Generator(e)))?;
+        pass(&mut program);
+        let after = interp::run(&program, "_start", STEP_LIMIT)
+            .map_err(|e| failure(FailureKind::Invalid(e)))?;
+
+        if before.status != after.status || before.output != after.output {
+            return Err(failure(FailureKind::Changed { before, after }));
+        }
+    }
+
+    Ok(())
+}
+
+type Pass = (&'static str, fn(&mut Program));
+
+/// [`check_pass`] over every transformation in the crate that takes a
+/// program of physical registers to another, at each level it has.
+pub fn check_builtin_passes(seeds: Range<u64>, config: &GenConfig) -> Result<(), Box<PassFailure>> {
+    let passes: [Pass; 12] = [
+        ("dedup_data", |p| {
+            dedup_data(p);
+        }),
+        ("lower_large_immediates", |p| {
+            lower_large_immediates(p, LoweringThreshold::Bits(32));
+        }),
+        ("resolve_defines", |p| {
+            p.resolve_defines().unwrap();
+        }),
+        ("resolve_conditions", Program::resolve_conditions),
+        ("optimize O1", |p| {
+            optimize(p, OptLevel::O1);
+        }),
+        ("optimize O2", |p| {
+            optimize(p, OptLevel::O2);
+        }),
+        ("two_address", |p| {
+            two_address(p).unwrap();
+        }),
+        ("fix_registers", |p| {
+            fix_registers(p).unwrap();
+        }),
+        ("compact_frames", |p| {
+            compact_frames(p);
+        }),
+        ("rematerialize", |p| {
+            rematerialize(p);
+        }),
+        ("order_blocks", |p| {
+            order_blocks(p);
+        }),
+        ("function_sections", |p| {
+            function_sections(p);
+        }),
+    ];
+
+    for (name, pass) in passes {
+        check_pass(name, seeds.clone(), config, pass)?;
+    }
+    Ok(())
+}
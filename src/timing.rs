@@ -0,0 +1,90 @@
+//! Cycle-count harness for micro-benchmarking generated code on real
+//! hardware.
+
+use crate::{
+    consts::{RAX, RDX},
+    instr::{add, mov, or, shl, sub, xor},
+    mnemonic::Amd64Mnemonic,
+    Amd64Instruction, AsmExpr, Data, Global, Label, Mem,
+};
+
+fn inst(mnemonic: &str) -> AsmExpr {
+    AsmExpr::Instruction(Amd64Instruction::new(
+        Amd64Mnemonic::parse(mnemonic),
+        vec![],
+    ))
+}
+
+fn slot(label: &Label) -> Mem {
+    Mem::label(label.clone())
+}
+
+/// A pair of 8-byte slots, `{name}_start` and `{name}_cycles`, and the
+/// code that times a region into them. Every run of a wrapped region adds
+/// its elapsed timestamp-counter ticks to `{name}_cycles`.
+///
+/// The timing code clobbers rax, rbx, rcx, rdx and the flags, and the
+/// closing `rdtscp` needs [`CpuFeature::Rdtscp`](crate::target::CpuFeature::Rdtscp).
+pub struct TimingHarness {
+    start: Label,
+    cycles: Label,
+}
+
+impl TimingHarness {
+    pub fn new(name: &str) -> Self {
+        TimingHarness {
+            start: Label::plain(&format!("{}_start", name)),
+            cycles: Label::plain(&format!("{}_cycles", name)),
+        }
+    }
+
+    /// The accumulated cycle count.
+    pub fn cycles(&self) -> &Label {
+        &self.cycles
+    }
+
+    /// Exports the cycle count so the host can read it after a run.
+    pub fn global(&self) -> Global {
+        Global::new(&self.cycles.label)
+    }
+
+    /// The slot definitions, for a writable data section.
+    pub fn slots(&self) -> Vec<AsmExpr> {
+        vec![
+            AsmExpr::Label(self.start.clone()),
+            AsmExpr::Data(Data::UInt(0)),
+            AsmExpr::Label(self.cycles.clone()),
+            AsmExpr::Data(Data::UInt(0)),
+        ]
+    }
+
+    /// Zeroes the accumulated count.
+    pub fn reset(&self) -> AsmExpr {
+        AsmExpr::Block(vec![xor(RAX, RAX), mov(slot(&self.cycles), RAX)])
+    }
+
+    /// `region`, bracketed by serialized timestamp reads. `cpuid` keeps
+    /// earlier instructions from leaking into the measurement, and
+    /// `rdtscp` followed by `lfence` keeps later ones out.
+    pub fn wrap(&self, region: Vec<AsmExpr>) -> AsmExpr {
+        let mut body = vec![
+            xor(RAX, RAX),
+            inst("cpuid"),
+            inst("rdtsc"),
+            shl(RDX, 32),
+            or(RAX, RDX),
+            mov(slot(&self.start), RAX),
+        ];
+        body.extend(region);
+        body.extend([
+            inst("rdtscp"),
+            inst("lfence"),
+            shl(RDX, 32),
+            or(RAX, RDX),
+            sub(RAX, slot(&self.start)),
+            add(slot(&self.cycles), RAX),
+        ]);
+
+        AsmExpr::Block(body)
+    }
+}
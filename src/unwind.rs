@@ -0,0 +1,288 @@
+//! Unwind data for Windows x64 functions.
+//!
+//! Windows walks the stack, for exceptions and debuggers alike, using a
+//! table in `.pdata` that maps each function's code to an `UNWIND_INFO`
+//! in `.xdata` describing its prologue. A function that calls anything
+//! or touches a nonvolatile register must have one, or the first
+//! exception thrown through it terminates the process. A [`Prologue`]
+//! describes a frame, writes the code that builds and tears it down, and
+//! the matching unwind data; [`function`] puts the three together:
+//!
+//! ```
+//! use cataclysm::{instr, register::Gpr, unwind::{self, Prologue}, Label, Program};
+//!
+//! let prologue = Prologue::new().with_push(Gpr::RBX).with_allocation(32);
+//! let mut body = vec![instr::call(Label::plain("work"))];
+//! body.extend(prologue.epilogue());
+//!
+//! let mut program = Program::default().with_extern("work");
+//! for section in unwind::function("run", &prologue, body).unwrap() {
+//!     program = program.with_section(section);
+//! }
+//! // Version 1; a 5-byte prologue of two codes, with no frame register.
+//! assert_eq!(prologue.unwind_info().unwrap(), [0x01, 0x05, 0x02, 0x00, 0x05, 0x32, 0x01, 0x30]);
+//! ```
+
+use std::{collections::HashMap, error, fmt};
+
+use crate::{
+    consts::{RBP, RSP},
+    encode::{encode_instruction, EncodeErrorKind},
+    instr::{add, lea, pop, push, ret, sub},
+    register::Gpr,
+    Amd64Register, AsmExpr, Data, Label, Mem, Section,
+};
+
+/// The frame pointer, set by [`Prologue::with_frame_pointer`].
+const FRAME_REGISTER: Gpr = Gpr::RBP;
+
+/// The largest prologue `UNWIND_INFO` can describe, in bytes.
+const MAX_PROLOGUE: usize = 255;
+
+/// The largest frame pointer offset, a multiple of 16.
+const MAX_FRAME_OFFSET: u32 = 240;
+
+// Unwind operations, the low half of an unwind code's second byte.
+const UWOP_PUSH_NONVOL: u8 = 0;
+const UWOP_ALLOC_LARGE: u8 = 1;
+const UWOP_ALLOC_SMALL: u8 = 2;
+const UWOP_SET_FPREG: u8 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnwindError {
+    /// rsp is listed as pushed.
+    PushedRsp,
+    /// The pushes and allocation leave rsp off the 16-byte boundary a
+    /// call needs; `frame` is how far below the return address it ends.
+    Misaligned { frame: u64 },
+    /// The frame pointer is set but rbp's old value is not saved first.
+    UnsavedFramePointer,
+    /// A frame pointer offset that is not a multiple of 16 up to 240, or
+    /// past the allocation.
+    FrameOffset(u32),
+    /// The prologue is this many bytes long.
+    TooLong(usize),
+    Encode(EncodeErrorKind),
+}
+
+impl fmt::Display for UnwindError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UnwindError::PushedRsp => write!(f, "rsp cannot be saved by pushing it"),
+            UnwindError::Misaligned { frame } => write!(
+                f,
+                "a {}-byte frame leaves rsp misaligned for calls",
+                frame
+            ),
+            UnwindError::UnsavedFramePointer => {
+                write!(f, "rbp is set as the frame pointer without being pushed first")
+            }
+            UnwindError::FrameOffset(offset) => write!(
+                f,
+                "frame pointer offset {} is not a multiple of 16 within the allocation, up to {}",
+                offset, MAX_FRAME_OFFSET
+            ),
+            UnwindError::TooLong(len) => write!(
+                f,
+                "the prologue is {} bytes long, more than unwind data can describe",
+                len
+            ),
+            UnwindError::Encode(kind) => write!(f, "{}", kind),
+        }
+    }
+}
+
+impl error::Error for UnwindError {}
+
+/// A function's frame: nonvolatile registers pushed on entry, in order,
+/// then `allocation` bytes of stack, then optionally rbp pointed into it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Prologue {
+    pushes: Vec<Gpr>,
+    allocation: u32,
+    frame_offset: Option<u32>,
+}
+
+impl Prologue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_push(mut self, reg: Gpr) -> Self {
+        self.pushes.push(reg);
+        self
+    }
+
+    pub fn with_allocation(mut self, bytes: u32) -> Self {
+        self.allocation = bytes;
+        self
+    }
+
+    /// Sets rbp to `offset` bytes above rsp once the stack is allocated,
+    /// so the function may move rsp in ways the unwinder cannot follow.
+    /// rbp must be among the pushes.
+    pub fn with_frame_pointer(mut self, offset: u32) -> Self {
+        self.frame_offset = Some(offset);
+        self
+    }
+
+    /// Checks the frame is one Windows can unwind and calls can be made
+    /// from.
+    pub fn validate(&self) -> Result<(), UnwindError> {
+        if self.pushes.contains(&Gpr::RSP) {
+            return Err(UnwindError::PushedRsp);
+        }
+        // The return address is already pushed on entry.
+        let frame = 8 * (self.pushes.len() as u64 + 1) + self.allocation as u64;
+        if !frame.is_multiple_of(16) {
+            return Err(UnwindError::Misaligned { frame });
+        }
+        if let Some(offset) = self.frame_offset {
+            if !self.pushes.contains(&FRAME_REGISTER) {
+                return Err(UnwindError::UnsavedFramePointer);
+            }
+            if !offset.is_multiple_of(16) || offset > MAX_FRAME_OFFSET || offset > self.allocation {
+                return Err(UnwindError::FrameOffset(offset));
+            }
+        }
+        Ok(())
+    }
+
+    /// The code that builds the frame, which must start the function.
+    pub fn instructions(&self) -> Vec<AsmExpr> {
+        let mut code: Vec<AsmExpr> = self
+            .pushes
+            .iter()
+            .map(|&reg| push(Amd64Register::GeneralPurpose(reg)))
+            .collect();
+        if self.allocation > 0 {
+            code.push(sub(RSP, self.allocation));
+        }
+        if let Some(offset) = self.frame_offset {
+            code.push(lea(Gpr::RBP, Mem::base(RSP).with_displacement(offset as i64)));
+        }
+        code
+    }
+
+    /// Code that tears the frame down and returns, in the only shape the
+    /// unwinder recognizes as an epilogue.
+    pub fn epilogue(&self) -> Vec<AsmExpr> {
+        let mut code = Vec::new();
+        match self.frame_offset {
+            Some(offset) => {
+                let above = self.allocation as i64 - offset as i64;
+                code.push(lea(Gpr::RSP, Mem::base(RBP).with_displacement(above)));
+            }
+            None if self.allocation > 0 => code.push(add(RSP, self.allocation)),
+            None => {}
+        }
+        code.extend(self.pushes.iter().rev().map(|&reg| pop(reg)));
+        code.push(ret());
+        code
+    }
+
+    /// The `UNWIND_INFO` describing the prologue, without an exception
+    /// handler.
+    pub fn unwind_info(&self) -> Result<Vec<u8>, UnwindError> {
+        self.validate()?;
+
+        // Each operation is described by where the instruction doing it
+        // ends, and the unwinder undoes them last first.
+        let mut end = 0;
+        let mut groups = Vec::new();
+        for (inst, op) in self.instructions().iter().zip(self.operations()) {
+            let AsmExpr::Instruction(inst) = inst else {
+                unreachable!("prologues are made of instructions");
+            };
+            end += encode_instruction(inst, "", false, &HashMap::new())
+                .map_err(UnwindError::Encode)?
+                .bytes
+                .len();
+            if end > MAX_PROLOGUE {
+                return Err(UnwindError::TooLong(end));
+            }
+            groups.push(op.codes(end as u8));
+        }
+        let mut codes: Vec<u8> = groups.into_iter().rev().flatten().collect();
+        let count = codes.len() / 2;
+        // The array of two-byte codes always has an even length.
+        if !count.is_multiple_of(2) {
+            codes.extend([0, 0]);
+        }
+
+        let frame = match self.frame_offset {
+            Some(offset) => FRAME_REGISTER.index() | ((offset / 16) as u8) << 4,
+            None => 0,
+        };
+        let mut info = vec![1, end as u8, count as u8, frame];
+        info.extend(codes);
+        Ok(info)
+    }
+
+    fn operations(&self) -> impl Iterator<Item = Operation> + '_ {
+        let pushes = self.pushes.iter().map(|&reg| Operation::Push(reg));
+        let allocation = (self.allocation > 0).then_some(Operation::Allocate(self.allocation));
+        let frame = self.frame_offset.map(|_| Operation::SetFrame);
+        pushes.chain(allocation).chain(frame)
+    }
+}
+
+/// One step of a prologue, as the unwinder sees it.
+enum Operation {
+    Push(Gpr),
+    Allocate(u32),
+    SetFrame,
+}
+
+impl Operation {
+    /// The unwind codes for the step, ending `offset` bytes into the
+    /// prologue.
+    fn codes(&self, offset: u8) -> Vec<u8> {
+        let code = |op: u8, info: u8| vec![offset, op | info << 4];
+        match *self {
+            Operation::Push(reg) => code(UWOP_PUSH_NONVOL, reg.index()),
+            Operation::Allocate(size @ 8..=128) => code(UWOP_ALLOC_SMALL, (size / 8 - 1) as u8),
+            Operation::Allocate(size) if size / 8 <= u16::MAX as u32 => {
+                let mut codes = code(UWOP_ALLOC_LARGE, 0);
+                codes.extend(((size / 8) as u16).to_le_bytes());
+                codes
+            }
+            Operation::Allocate(size) => {
+                let mut codes = code(UWOP_ALLOC_LARGE, 1);
+                codes.extend(size.to_le_bytes());
+                codes
+            }
+            Operation::SetFrame => code(UWOP_SET_FPREG, 0),
+        }
+    }
+}
+
+/// `name` as a function with the frame `prologue` describes, followed by
+/// `body`, which should leave through [`Prologue::epilogue`]. Returns the
+/// text section, the unwind information in `xdata` and its `pdata` entry,
+/// which use the labels `name.end` and `name.unwind`.
+pub fn function(
+    name: &str,
+    prologue: &Prologue,
+    body: Vec<AsmExpr>,
+) -> Result<Vec<Section>, UnwindError> {
+    let info = prologue.unwind_info()?;
+    let end = Label::plain(&format!("{}.end", name));
+    let unwind = Label::plain(&format!("{}.unwind", name));
+
+    let mut text = vec![AsmExpr::Label(Label::plain(name))];
+    text.extend(prologue.instructions());
+    text.extend(body);
+    text.push(AsmExpr::Label(end.clone()));
+
+    let xdata = vec![AsmExpr::Label(unwind.clone()), AsmExpr::Data(Data::Bytes(info))];
+    let pdata = [Label::plain(name), end, unwind]
+        .into_iter()
+        .map(|label| AsmExpr::Data(Data::ImageOffset(label)))
+        .collect();
+    Ok(vec![
+        Section::new("text", text),
+        Section::new("xdata", xdata),
+        Section::new("pdata", pdata),
+    ])
+}
@@ -0,0 +1,273 @@
+//! Calls into the Linux vDSO, the small shared object the kernel maps into
+//! every process, so generated code can read clocks without entering the
+//! kernel.
+//!
+//! A [`VdsoCall`] keeps the function's address in an 8-byte slot. The slot
+//! is filled once, either from a pointer the caller already has or by
+//! looking the symbol up at run time: the auxiliary vector gives the vDSO's
+//! base (`AT_SYSINFO_EHDR`), and the function is found by name in its
+//! dynamic symbol table. Calls through an empty slot fall back to the
+//! equivalent system call, so code still works where there is no vDSO.
+
+use crate::{
+    consts::{R10, R11, R8, R9, RAX, RCX, RDI, RDX, RSI, RSP},
+    instr::{add, and, cmp, dec, jcc, jmp, mov, shl, shr, syscall, test, xor, CondCode},
+    mnemonic::Amd64Mnemonic,
+    Amd64Instruction, Amd64Register, AsmExpr, Data, Label, Mem,
+};
+
+/// Auxiliary-vector entry holding the address of the vDSO's ELF header.
+const AT_SYSINFO_EHDR: u32 = 33;
+const SHT_DYNSYM: u32 = 11;
+const SECTION_HEADER_SIZE: i64 = 64;
+const SYMBOL_SIZE: u32 = 24;
+
+fn at(base: Amd64Register, displacement: i64) -> Mem {
+    Mem::base(base).with_displacement(displacement)
+}
+
+/// Zero-extends the low 32 bits of `reg`.
+fn low32(reg: Amd64Register) -> [AsmExpr; 2] {
+    [shl(reg.clone(), 32u32), shr(reg, 32u32)]
+}
+
+/// One vDSO function, such as `__vdso_clock_gettime`, and the system call
+/// that does the same job.
+pub struct VdsoCall {
+    symbol: String,
+    fallback: u64,
+    slot: Label,
+}
+
+impl VdsoCall {
+    /// `symbol`, falling back to system call `fallback` when unresolved.
+    pub fn new(symbol: &str, fallback: u64) -> Self {
+        VdsoCall {
+            symbol: symbol.to_string(),
+            fallback,
+            slot: Label::plain(&format!("{}_ptr", symbol)),
+        }
+    }
+
+    /// `clock_gettime(clock, timespec)`: clock id in rdi, pointer in rsi.
+    pub fn clock_gettime() -> Self {
+        VdsoCall::new("__vdso_clock_gettime", 228)
+    }
+
+    /// `gettimeofday(timeval, timezone)`.
+    pub fn gettimeofday() -> Self {
+        VdsoCall::new("__vdso_gettimeofday", 96)
+    }
+
+    /// `time(tloc)`.
+    pub fn time() -> Self {
+        VdsoCall::new("__vdso_time", 201)
+    }
+
+    /// `getcpu(cpu, node, cache)`.
+    pub fn getcpu() -> Self {
+        VdsoCall::new("__vdso_getcpu", 309)
+    }
+
+    /// The slot holding the function's address, or zero until resolved.
+    pub fn slot(&self) -> &Label {
+        &self.slot
+    }
+
+    /// The slot definition, for a writable data section.
+    pub fn slots(&self) -> Vec<AsmExpr> {
+        vec![
+            AsmExpr::Label(self.slot.clone()),
+            AsmExpr::Data(Data::UInt(0)),
+        ]
+    }
+
+    fn slot_ref(&self) -> Mem {
+        Mem::label(self.slot.clone())
+    }
+
+    /// Labels private to one emitted sequence.
+    fn locals<const N: usize>(&self, names: [&str; N]) -> [Label; N] {
+        names.map(|name| Label::unique(&format!("{}.{}", self.symbol, name)))
+    }
+
+    /// Stores a function address the caller already has.
+    pub fn resolve_from(&self, address: Amd64Register) -> AsmExpr {
+        mov(self.slot_ref(), address)
+    }
+
+    /// Looks the function up through the auxiliary vector at `auxv`,
+    /// leaving the slot zero if there is no vDSO or it lacks the function.
+    ///
+    /// The vDSO is mapped whole, section headers included, and linked at
+    /// zero, so its symbol values are offsets from its base. Clobbers rax,
+    /// rcx, rdx, rsi, rdi, r8 to r11 and the flags.
+    pub fn resolve_from_auxv(&self, auxv: Amd64Register) -> AsmExpr {
+        let [scan, sections, symbols, skip, missing, store] =
+            self.locals(["scan", "sections", "symbols", "skip", "missing", "store"]);
+
+        let mut body = vec![
+            mov(RSI, auxv),
+            // Find AT_SYSINFO_EHDR; the vector ends with a zero type.
+            AsmExpr::Label(scan.clone()),
+            mov(RAX, at(RSI, 0)),
+            test(RAX, RAX),
+            jcc(CondCode::E, missing.clone()),
+            add(RSI, 16u32),
+            cmp(RAX, AT_SYSINFO_EHDR),
+            jcc(CondCode::Ne, scan),
+            mov(RDI, at(RSI, -8)),
+            // Find the dynamic symbol table among the section headers.
+            mov(RDX, at(RDI, 0x28)),
+            add(RDX, RDI),
+            mov(RCX, at(RDI, 0x3c)),
+            and(RCX, 0xffffu32),
+            AsmExpr::Label(sections.clone()),
+            test(RCX, RCX),
+            jcc(CondCode::E, missing.clone()),
+            dec(RCX),
+            mov(RAX, at(RDX, 4)),
+            add(RDX, SECTION_HEADER_SIZE),
+        ];
+        body.extend(low32(RAX));
+        body.extend([
+            cmp(RAX, SHT_DYNSYM),
+            jcc(CondCode::Ne, sections),
+            // r8 walks the symbols up to r9; r10 is their string table.
+            // rdx is one header past the table's.
+            mov(R8, at(RDX, 24 - SECTION_HEADER_SIZE)),
+            add(R8, RDI),
+            mov(R9, at(RDX, 32 - SECTION_HEADER_SIZE)),
+            add(R9, R8),
+            mov(RAX, at(RDX, 40 - SECTION_HEADER_SIZE)),
+        ]);
+        body.extend(low32(RAX));
+        body.extend([
+            shl(RAX, 6u32),
+            mov(RDX, at(RDI, 0x28)),
+            add(RDX, RDI),
+            mov(R10, Mem::base(RDX).with_index(RAX, 1).with_displacement(24)),
+            add(R10, RDI),
+            AsmExpr::Label(symbols.clone()),
+            cmp(R8, R9),
+            jcc(CondCode::Ae, missing.clone()),
+            mov(RAX, at(R8, 0)),
+        ]);
+        body.extend(low32(RAX));
+        body.push(add(RAX, R10));
+        body.extend(self.compare_name(skip.clone()));
+        body.extend([
+            mov(RAX, at(R8, 8)),
+            add(RAX, RDI),
+            jmp(store.clone()),
+            AsmExpr::Label(skip),
+            add(R8, SYMBOL_SIZE),
+            jmp(symbols),
+            AsmExpr::Label(missing),
+            xor(RAX, RAX),
+            AsmExpr::Label(store),
+            mov(self.slot_ref(), RAX),
+        ]);
+
+        AsmExpr::Block(body)
+    }
+
+    /// Compares the string at rax with the symbol name eight bytes at a
+    /// time, terminator included, jumping to `mismatch` if they differ.
+    fn compare_name(&self, mismatch: Label) -> Vec<AsmExpr> {
+        let mut name = self.symbol.clone().into_bytes();
+        name.push(0);
+
+        let mut body = Vec::new();
+        for (i, chunk) in name.chunks(8).enumerate() {
+            let mut bytes = [0; 8];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            // Shift out whatever follows the name in the last chunk.
+            let unused = 64 - 8 * chunk.len() as u32;
+            let expected = u64::from_le_bytes(bytes) << unused;
+
+            body.push(mov(RCX, at(RAX, 8 * i as i64)));
+            if unused > 0 {
+                body.push(shl(RCX, unused));
+            }
+            body.extend([
+                mov(R11, expected),
+                cmp(RCX, R11),
+                jcc(CondCode::Ne, mismatch.clone()),
+            ]);
+        }
+        body
+    }
+
+    /// Calls the function, or makes the fallback system call if the slot
+    /// is empty. Arguments go in rdi, rsi and rdx and the result comes back
+    /// in rax either way. The stack must be 16-byte aligned, as for any
+    /// call, and every caller-saved register may be clobbered.
+    pub fn call(&self) -> AsmExpr {
+        let [fallback, done] = self.locals(["fallback", "done"]);
+        AsmExpr::Block(vec![
+            mov(RAX, self.slot_ref()),
+            test(RAX, RAX),
+            jcc(CondCode::E, fallback.clone()),
+            AsmExpr::Instruction(Amd64Instruction::new(Amd64Mnemonic::Call, vec![RAX.into()])),
+            jmp(done.clone()),
+            AsmExpr::Label(fallback),
+            mov(RAX, self.fallback),
+            syscall(),
+            AsmExpr::Label(done),
+        ])
+    }
+}
+
+/// Points `auxv` at the auxiliary vector, from the stack as the kernel
+/// leaves it at the process entry point: the argument count, the argument
+/// and environment pointers, each list ending in zero, then the vector.
+/// Must run before anything is pushed. Clobbers `scratch`.
+pub fn auxv_from_entry(auxv: Amd64Register, scratch: Amd64Register) -> AsmExpr {
+    let find = Label::unique("auxv_from_entry.find");
+    AsmExpr::Block(vec![
+        mov(auxv.clone(), at(RSP, 0)),
+        AsmExpr::Instruction(Amd64Instruction::new(
+            Amd64Mnemonic::Lea,
+            vec![
+                auxv.clone().into(),
+                Mem::base(RSP)
+                    .with_index(auxv.clone(), 8)
+                    .with_displacement(16)
+                    .into(),
+            ],
+        )),
+        AsmExpr::Label(find.clone()),
+        mov(scratch.clone(), at(auxv.clone(), 0)),
+        add(auxv, 8u32),
+        test(scratch.clone(), scratch),
+        jcc(CondCode::Ne, find),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{consts::RBX, instr, program::Program, Section};
+
+    /// Every sequence's labels are its own, whichever object emitted it.
+    #[test]
+    fn sequences_can_be_emitted_twice() {
+        let clock = VdsoCall::clock_gettime();
+        let body = vec![
+            AsmExpr::Label(Label::plain("_start")),
+            auxv_from_entry(RBX, RCX),
+            auxv_from_entry(RBX, RCX),
+            clock.resolve_from_auxv(RBX),
+            VdsoCall::clock_gettime().resolve_from_auxv(RBX),
+            clock.call(),
+            clock.call(),
+            VdsoCall::clock_gettime().call(),
+            instr::ret(),
+        ];
+        let program = Program::default()
+            .with_section(Section::new("text", body))
+            .with_section(Section::new("data", clock.slots()));
+        assert!(program.check_labels().is_empty());
+    }
+}
@@ -0,0 +1,52 @@
+//! Brainfuck programs compiled by the example's compiler, run in the
+//! interpreter and encoded, end to end through labels, loops, syscalls and
+//! data.
+
+#[path = "../examples/brainfuck/compiler.rs"]
+mod compiler;
+
+use cataclysm::{encode::EncodeOptions, interp};
+
+/// Compiles and runs `source`, checking that it exits with status 0 and
+/// that it encodes, and returns what it printed.
+fn output(source: &str) -> String {
+    let program = compiler::compile(source).unwrap();
+    let outcome = interp::run(&program, "_start", 10_000_000).unwrap();
+    assert_eq!(outcome.status, 0);
+    program.clone().encode(&EncodeOptions::new()).unwrap();
+    String::from_utf8(outcome.output).unwrap()
+}
+
+#[test]
+fn hello() {
+    let source = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+    assert_eq!(output(source), "Hello World!\n");
+}
+
+/// Nested loops and a cell that wraps below zero.
+#[test]
+fn wrap() {
+    assert_eq!(output("-[>+<-----]>--.+.+."), "123");
+}
+
+/// Prints the digits 0 to 9 while counting the first cell down.
+#[test]
+fn digits() {
+    let source = "++++++[>++++++++<-]++++++++++[>.+<-]++++++++++.";
+    assert_eq!(output(source), "0123456789\n");
+}
+
+/// `,` reads end-of-file, so the loop it guards never runs.
+#[test]
+fn input_reads_zero() {
+    assert_eq!(
+        output("+++,[>+++++++[<++++++>-]<.[-]]++++++++[>++++++<-]>."),
+        "0"
+    );
+}
+
+#[test]
+fn unmatched_brackets() {
+    assert_eq!(compiler::compile("+]").err().unwrap(), "unmatched `]`");
+    assert_eq!(compiler::compile("[+").err().unwrap(), "unmatched `[`");
+}
@@ -0,0 +1,23 @@
+//! Every built-in pass, property-tested over generated programs.
+
+use cataclysm::testgen::{check_builtin_passes, GenConfig};
+
+#[test]
+fn builtin_passes_preserve_behaviour() {
+    if let Err(failure) = check_builtin_passes(0..64, &GenConfig::default()) {
+        panic!("{}\n{}", failure, failure.program);
+    }
+}
+
+#[test]
+fn builtin_passes_preserve_behaviour_of_deeper_programs() {
+    let config = GenConfig {
+        block_len: 6,
+        max_depth: 3,
+        functions: 4,
+        data_items: 8,
+    };
+    if let Err(failure) = check_builtin_passes(1000..1032, &config) {
+        panic!("{}\n{}", failure, failure.program);
+    }
+}
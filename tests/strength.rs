@@ -0,0 +1,137 @@
+//! The strength-reduced sequences run under the interpreter against Rust's
+//! own arithmetic, for every register arrangement they treat differently.
+
+use cataclysm::{
+    consts::{RAX, RCX, RDI, RDX, RSI},
+    instr, interp,
+    strength::{mul_const, sdiv_const, udiv_const},
+    Amd64Register, AsmExpr, Label, Program, Section,
+};
+
+/// Dividends and multiplicands: the edges of both ranges, powers of two
+/// and their neighbours, and a spread of others.
+fn values() -> Vec<u64> {
+    let mut values = vec![0, 1, 2, 3, 7, 641, u64::MAX, u64::MAX - 1];
+    values.extend([i64::MIN as u64, i64::MAX as u64, i64::MIN as u64 + 1]);
+    for k in 0..64 {
+        let power = 1u64 << k;
+        values.extend([power, power - 1, power.wrapping_neg()]);
+    }
+    let mut x = 0x9e37_79b9_7f4a_7c15u64;
+    for _ in 0..16 {
+        x = x
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        values.push(x);
+        values.push(x >> (x % 64));
+    }
+    values
+}
+
+/// Powers of two and their neighbours, and the divisors whose magic needs
+/// the add-back or a 65-bit multiplier.
+fn constants() -> Vec<u64> {
+    let mut constants = vec![
+        3, 5, 6, 7, 9, 10, 11, 13, 15, 25, 27, 45, 81, 100, 641, 1000,
+    ];
+    constants.extend([6700417, 274177, 1_000_000_007, 0x5555_5555_5555_5555]);
+    for k in (0..64).step_by(3) {
+        let power = 1u64 << k;
+        constants.extend([power, power - 1, power + 1]);
+    }
+    constants.extend([1 << 62, 1 << 63, (1 << 63) + 1]);
+    constants.extend([u64::MAX, u64::MAX - 1, i64::MAX as u64, i32::MAX as u64 + 1]);
+    constants.retain(|&c| c != 0);
+    constants
+}
+
+/// The constants both ways round, for the signed operations.
+fn constants_i64() -> Vec<i64> {
+    constants()
+        .into_iter()
+        .flat_map(|c| [c as i64, (c as i64).wrapping_neg()])
+        .collect()
+}
+
+/// Runs `code` as a function taking its input in `src` and returns `dst`.
+fn run(code: AsmExpr, src: &Amd64Register, dst: &Amd64Register, x: u64) -> u64 {
+    let body = vec![AsmExpr::Label(Label::plain("f")), code, instr::ret()];
+    let program = Program::default().with_section(Section::new("text", body));
+    let input = src.gpr().unwrap();
+    let out = interp::run_with(&program, "f", &[(input, x)], 1000).unwrap();
+    out.registers[dst.gpr().unwrap().index() as usize]
+}
+
+/// Destination and source pairs: apart, the same, and in rax or rdx,
+/// which the divisions clobber.
+fn arrangements() -> [(Amd64Register, Amd64Register); 4] {
+    [(RSI, RDI), (RDI, RDI), (RAX, RDX), (RDX, RAX)]
+}
+
+#[test]
+fn mul_const_matches_wrapping_mul() {
+    let edges = [0, 1, -1, i64::MIN, i64::MAX, i64::MIN + 1];
+    for c in edges.into_iter().chain(constants_i64()) {
+        for (dst, src) in arrangements() {
+            let code = mul_const(dst.clone(), src.clone(), c, RCX);
+            for x in values() {
+                let expected = (x as i64).wrapping_mul(c) as u64;
+                assert_eq!(run(code.clone(), &src, &dst, x), expected, "{} * {}", x, c);
+            }
+        }
+    }
+}
+
+#[test]
+fn udiv_const_matches_division() {
+    for d in [1].into_iter().chain(constants()) {
+        for (dst, src) in arrangements() {
+            let code = udiv_const(dst.clone(), src.clone(), d, RCX).unwrap();
+            for x in values() {
+                assert_eq!(run(code.clone(), &src, &dst, x), x / d, "{} / {}", x, d);
+            }
+        }
+    }
+}
+
+#[test]
+fn sdiv_const_matches_division() {
+    let divisors = [1, -1, i64::MIN, i64::MAX, i64::MIN + 1];
+    for d in divisors.into_iter().chain(constants_i64()) {
+        for (dst, src) in arrangements() {
+            let code = sdiv_const(dst.clone(), src.clone(), d, RCX).unwrap();
+            for x in values() {
+                let expected = (x as i64).wrapping_div(d) as u64;
+                assert_eq!(
+                    run(code.clone(), &src, &dst, x),
+                    expected,
+                    "{} / {}",
+                    x as i64,
+                    d
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn division_by_zero_and_clobbered_scratch_are_rejected() {
+    use cataclysm::strength::StrengthError;
+
+    assert_eq!(
+        udiv_const(RAX, RDI, 0, RCX).err(),
+        Some(StrengthError::DivideByZero)
+    );
+    assert_eq!(
+        sdiv_const(RAX, RDI, 0, RCX).err(),
+        Some(StrengthError::DivideByZero)
+    );
+    assert_eq!(
+        udiv_const(RAX, RDI, 7, RDX).err(),
+        Some(StrengthError::ScratchConflict)
+    );
+    assert_eq!(
+        sdiv_const(RSI, RDI, 7, RDI).err(),
+        Some(StrengthError::ScratchConflict)
+    );
+}